@@ -0,0 +1,49 @@
+use std::ops::RangeInclusive;
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum AccessKind {
+    Read,
+    Write,
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum AccessSource {
+    Cpu,
+    // The OAM DMA or the VRAM DMA of a Gameboy Color
+    Dma,
+    // Device::read_memory and Device::write_memory
+    Debugger,
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct Access {
+    pub address: u16,
+    pub value: u8,
+    pub kind: AccessKind,
+    pub source: AccessSource,
+}
+
+pub type AccessCallback = Box<dyn FnMut(Access) + Send>;
+
+// Reports the accesses to memory in some address ranges, for tools like memory viewers. The MMU
+// only has one while it is set, so it costs nothing during normal play.
+pub struct AccessHook {
+    ranges: Vec<RangeInclusive<u16>>,
+    callback: AccessCallback,
+}
+
+impl AccessHook {
+    pub fn new(ranges: Vec<RangeInclusive<u16>>, callback: AccessCallback) -> AccessHook {
+        AccessHook { ranges, callback }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ranges.iter().all(|range| range.is_empty())
+    }
+
+    pub fn report(&mut self, access: Access) {
+        if self.ranges.iter().any(|range| range.contains(&access.address)) {
+            (self.callback)(access);
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use crate::apu::sequencer;
+use crate::state::{StateError, StateReader, StateResult, StateWriter};
+
+// The length counter of a channel, which disables the channel when it expires. It is clocked by
+// the length steps of the frame sequencer while it is enabled.
+//
+// Enabling it, or triggering with an expired counter, while the current step of the frame
+// sequencer does not clock the length gives it an extra clock, as if the step that was just
+// executed saw the new state.
+pub struct LengthCounter {
+    enabled: bool,
+    value: u16,
+    max: u16,
+}
+
+impl LengthCounter {
+    // max is 64, or 256 for the wave channel
+    pub fn new(max: u16) -> LengthCounter {
+        LengthCounter {
+            enabled: false,
+            value: 0,
+            max,
+        }
+    }
+
+    pub fn value(&self) -> u16 {
+        self.value
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    // The channel has to be disabled when this becomes true
+    pub fn expired(&self) -> bool {
+        self.value == 0
+    }
+
+    // Loads the counter from the length bits of NRx1, which count up to max
+    pub fn load(&mut self, length: u8) {
+        self.value = self.max - length as u16;
+    }
+
+    // Bit 6 of NRx4
+    pub fn set_enable(&mut self, enable: bool, current_step: u8) {
+        let was_enabled = self.enabled;
+        self.enabled = enable;
+        if !was_enabled && !sequencer::clocks_length(current_step) {
+            self.clock();
+        }
+    }
+
+    // Bit 7 of NRx4, after set_enable was called with bit 6 of the same write
+    pub fn trigger(&mut self, current_step: u8) {
+        if self.value == 0 {
+            self.value = self.max;
+            if !sequencer::clocks_length(current_step) {
+                self.clock();
+            }
+        }
+    }
+
+    // Called with every step the frame sequencer executes
+    pub fn tick(&mut self, step: u8) {
+        if sequencer::clocks_length(step) {
+            self.clock();
+        }
+    }
+
+    fn clock(&mut self) {
+        if self.enabled && self.value > 0 {
+            self.value -= 1;
+        }
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.enabled);
+        w.u16(self.value);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.enabled = r.bool()?;
+        self.value = r.u16()?;
+        if self.value > self.max {
+            return Err(StateError::Corrupt);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::LengthCounter;
+    use crate::apu::TestRng;
+    use crate::apu::sequencer::FrameSequencer;
+
+    // Written from the description in the Pan Docs, without sharing code with LengthCounter
+    struct Reference {
+        enabled: bool,
+        value: u16,
+        max: u16,
+    }
+
+    impl Reference {
+        fn write_nrx4(&mut self, v: u8, next_step: u8) {
+            let first_half = next_step % 2 == 1;
+            let enable = v & 0x40 != 0;
+            if enable && !self.enabled && first_half && self.value != 0 {
+                self.value -= 1;
+            }
+            self.enabled = enable;
+            if v & 0x80 != 0 && self.value == 0 {
+                self.value = if enable && first_half { self.max - 1 } else { self.max };
+            }
+        }
+
+        fn step(&mut self, step: u8) {
+            if [0, 2, 4, 6].contains(&step) && self.enabled && self.value != 0 {
+                self.value -= 1;
+            }
+        }
+    }
+
+    #[test]
+    fn matches_reference() {
+        for &max in [64u16, 256].iter() {
+            let mut rng = TestRng::new(max as u64);
+            for _ in 0 .. 200 {
+                let mut counter = LengthCounter::new(max);
+                let mut reference = Reference { enabled: false, value: 0, max };
+                let mut sequencer = FrameSequencer::new();
+
+                for _ in 0 .. 200 {
+                    match rng.below(4) {
+                        0 => {
+                            let length = (rng.below(max as u64)) as u8;
+                            counter.load(length);
+                            reference.value = max - length as u16;
+                        },
+                        1 => {
+                            let v = rng.next() as u8;
+                            let current_step = sequencer.current_step();
+                            counter.set_enable(v & 0x40 != 0, current_step);
+                            if v & 0x80 != 0 {
+                                counter.trigger(current_step);
+                            }
+                            reference.write_nrx4(v, current_step);
+                        },
+                        _ => {
+                            let step = sequencer.advance();
+                            counter.tick(step);
+                            reference.step(step);
+                        },
+                    }
+
+                    assert!(counter.value() <= max);
+                    assert_eq!(counter.expired(), counter.value() == 0);
+                    assert_eq!((counter.value(), counter.enabled()), (reference.value, reference.enabled));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn extra_clock_on_enable() {
+        let mut counter = LengthCounter::new(64);
+        counter.load(60);
+        // Step 0 clocks the length, so there is no extra clock
+        counter.set_enable(true, 0);
+        assert_eq!(counter.value(), 4);
+
+        let mut counter = LengthCounter::new(64);
+        counter.load(60);
+        counter.set_enable(true, 1);
+        assert_eq!(counter.value(), 3);
+        // Only when it was disabled before
+        counter.set_enable(true, 1);
+        assert_eq!(counter.value(), 3);
+    }
+
+    #[test]
+    fn trigger_reloads_expired_counter() {
+        let mut counter = LengthCounter::new(256);
+        counter.set_enable(true, 0);
+        counter.trigger(0);
+        assert_eq!(counter.value(), 256);
+
+        let mut counter = LengthCounter::new(256);
+        counter.set_enable(true, 0);
+        counter.trigger(3);
+        assert_eq!(counter.value(), 255);
+
+        counter.load(10);
+        counter.trigger(3);
+        assert_eq!(counter.value(), 246);
+    }
+}
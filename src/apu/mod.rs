@@ -0,0 +1,26 @@
+// Parts of the APU that are shared by several channels
+pub mod length;
+pub mod sequencer;
+
+// A small deterministic generator for the randomized tests of the APU
+#[cfg(test)]
+pub struct TestRng(u64);
+
+#[cfg(test)]
+impl TestRng {
+    pub fn new(seed: u64) -> TestRng {
+        TestRng(seed | 1)
+    }
+
+    pub fn next(&mut self) -> u64 {
+        // xorshift64*
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
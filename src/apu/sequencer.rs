@@ -0,0 +1,79 @@
+use crate::state::{StateReader, StateResult, StateWriter};
+
+// The frame sequencer is clocked at 512 Hz and runs through 8 steps:
+//
+//   Step    0    1    2    3    4    5    6    7
+//   Length  x         x         x         x
+//   Sweep             x                   x
+//   Envelope                                   x
+//
+// The current step is the one that is executed next. It is restarted at step 0 when the APU is
+// powered on.
+pub struct FrameSequencer {
+    step: u8,
+}
+
+impl FrameSequencer {
+    pub fn new() -> FrameSequencer {
+        FrameSequencer {
+            step: 0,
+        }
+    }
+
+    pub fn current_step(&self) -> u8 {
+        self.step
+    }
+
+    pub fn reset(&mut self) {
+        self.step = 0;
+    }
+
+    // Moves on to the next step and returns the one that has to be executed now
+    pub fn advance(&mut self) -> u8 {
+        let step = self.step;
+        self.step = (self.step + 1) % 8;
+        step
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.step);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.step = r.u8()? % 8;
+        Ok(())
+    }
+}
+
+pub fn clocks_length(step: u8) -> bool {
+    step % 2 == 0
+}
+
+pub fn clocks_sweep(step: u8) -> bool {
+    step % 4 == 2
+}
+
+pub fn clocks_envelope(step: u8) -> bool {
+    step == 7
+}
+
+#[cfg(test)]
+mod test {
+    use super::{clocks_envelope, clocks_length, clocks_sweep, FrameSequencer};
+
+    #[test]
+    fn steps() {
+        let mut sequencer = FrameSequencer::new();
+        let steps: Vec<u8> = (0 .. 16).map(|_| sequencer.advance()).collect();
+        assert_eq!(steps, [0, 1, 2, 3, 4, 5, 6, 7, 0, 1, 2, 3, 4, 5, 6, 7]);
+
+        let length: Vec<u8> = (0 .. 8).filter(|&s| clocks_length(s)).collect();
+        let sweep: Vec<u8> = (0 .. 8).filter(|&s| clocks_sweep(s)).collect();
+        let envelope: Vec<u8> = (0 .. 8).filter(|&s| clocks_envelope(s)).collect();
+        assert_eq!((length, sweep, envelope), (vec![0, 2, 4, 6], vec![2, 6], vec![7]));
+
+        sequencer.advance();
+        sequencer.reset();
+        assert_eq!(sequencer.current_step(), 0);
+    }
+}
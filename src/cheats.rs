@@ -0,0 +1,209 @@
+use std::fmt;
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum CheatError {
+    // Not 6 or 9 hex digits for Game Genie, or 8 for GameShark
+    InvalidLength,
+    InvalidCharacter(char),
+    // A Game Genie code can only patch the ROM at 0000-7FFF, a GameShark code anything else
+    GameGenieOutsideRom(u16),
+    GameSharkInRom(u16),
+    UnsupportedGameSharkType(u8),
+}
+
+impl fmt::Display for CheatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CheatError::InvalidLength => write!(f, "A Game Genie code has 6 or 9 hex digits, as ABC-DEF or ABC-DEF-GHI, and a GameShark code 8"),
+            CheatError::InvalidCharacter(c) => write!(f, "'{}' is not a hex digit", c),
+            CheatError::GameGenieOutsideRom(a) => write!(f, "The Game Genie code patches {:04X}, which is not in the ROM", a),
+            CheatError::GameSharkInRom(a) => write!(f, "The GameShark code writes to {:04X}, which is in the ROM", a),
+            CheatError::UnsupportedGameSharkType(t) => write!(f, "GameShark codes of type {:02X} are not supported", t),
+        }
+    }
+}
+
+impl std::error::Error for CheatError {}
+
+// Replaces the byte the cartridge returns at an address, in whatever bank is mapped there. With a
+// compare value only a byte that matches it is replaced, which picks one of the banks.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct GameGenieCode {
+    pub address: u16,
+    pub value: u8,
+    pub compare: Option<u8>,
+}
+
+impl GameGenieCode {
+    // ABC-DEF or ABC-DEF-GHI, where AB is the value and FCDE the address with F inverted. GI
+    // rotated right by 2 and XORed with BA is the compare value, H is not used.
+    pub fn parse(code: &str) -> Result<GameGenieCode, CheatError> {
+        let digits = hex_digits(code)?;
+        if digits.len() != 6 && digits.len() != 9 {
+            return Err(CheatError::InvalidLength);
+        }
+
+        let value = digits[0] << 4 | digits[1];
+        let address = ((digits[5] ^ 0xF) as u16) << 12 | (digits[2] as u16) << 8 | (digits[3] as u16) << 4 | digits[4] as u16;
+        if address >= 0x8000 {
+            return Err(CheatError::GameGenieOutsideRom(address));
+        }
+        let compare = match digits.len() {
+            9 => Some((digits[6] << 4 | digits[8]).rotate_right(2) ^ 0xBA),
+            _ => None,
+        };
+        Ok(GameGenieCode { address, value, compare })
+    }
+}
+
+// Writes a byte once per frame, at the start of VBlank
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct GameSharkCode {
+    pub address: u16,
+    pub value: u8,
+    pub target: GameSharkTarget,
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum GameSharkTarget {
+    // Whatever is mapped at the address
+    Mapped,
+    // A bank of the cartridge RAM at A000-BFFF
+    CartridgeRam(usize),
+    // A bank of the work RAM at D000-DFFF of a Gameboy Color
+    WorkRam(usize),
+}
+
+impl GameSharkCode {
+    // ttvvaaaa, with the type tt, the value vv and the address in little endian. Type 00 and 01
+    // write to what is mapped, 80-8F to the cartridge RAM bank in the lower digit and 90-97 to
+    // the work RAM bank in the lower digit.
+    pub fn parse(code: &str) -> Result<GameSharkCode, CheatError> {
+        let digits = hex_digits(code)?;
+        if digits.len() != 8 {
+            return Err(CheatError::InvalidLength);
+        }
+
+        let byte = |i: usize| digits[i] << 4 | digits[i + 1];
+        let address = (byte(6) as u16) << 8 | byte(4) as u16;
+        if address < 0x8000 {
+            return Err(CheatError::GameSharkInRom(address));
+        }
+        let target = match byte(0) {
+            0x00 | 0x01 => GameSharkTarget::Mapped,
+            t @ 0x80 ..= 0x8F if (0xA000 .. 0xC000).contains(&address) => GameSharkTarget::CartridgeRam(t as usize & 0x0F),
+            t @ 0x90 ..= 0x97 if (0xD000 .. 0xE000).contains(&address) => GameSharkTarget::WorkRam((t as usize & 0x07).max(1)),
+            // A bank of other memory is ignored
+            0x80 ..= 0x97 => GameSharkTarget::Mapped,
+            t => return Err(CheatError::UnsupportedGameSharkType(t)),
+        };
+        Ok(GameSharkCode { address, value: byte(2), target })
+    }
+}
+
+// A code with dashes or without
+fn hex_digits(code: &str) -> Result<Vec<u8>, CheatError> {
+    code.trim().chars()
+        .filter(|&c| c != '-')
+        .map(|c| c.to_digit(16).map(|d| d as u8).ok_or(CheatError::InvalidCharacter(c)))
+        .collect()
+}
+
+#[derive(Default)]
+pub struct Cheats {
+    game_genie: Vec<GameGenieCode>,
+    gameshark: Vec<GameSharkCode>,
+}
+
+impl Cheats {
+    pub fn add_game_genie(&mut self, code: GameGenieCode) {
+        if !self.game_genie.contains(&code) {
+            self.game_genie.push(code);
+        }
+    }
+
+    pub fn remove_game_genie(&mut self, code: GameGenieCode) -> bool {
+        let len = self.game_genie.len();
+        self.game_genie.retain(|c| *c != code);
+        self.game_genie.len() != len
+    }
+
+    pub fn add_gameshark(&mut self, code: GameSharkCode) {
+        if !self.gameshark.contains(&code) {
+            self.gameshark.push(code);
+        }
+    }
+
+    pub fn remove_gameshark(&mut self, code: GameSharkCode) -> bool {
+        let len = self.gameshark.len();
+        self.gameshark.retain(|c| *c != code);
+        self.gameshark.len() != len
+    }
+
+    pub fn clear(&mut self) {
+        self.game_genie.clear();
+        self.gameshark.clear();
+    }
+
+    pub fn gameshark(&self) -> &[GameSharkCode] {
+        &self.gameshark
+    }
+
+    // Called with the byte the cartridge returned after banking
+    pub fn patch_rom(&self, address: u16, value: u8) -> u8 {
+        self.game_genie.iter()
+            .find(|c| c.address == address && c.compare.unwrap_or(value) == value)
+            .map_or(value, |c| c.value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CheatError, Cheats, GameGenieCode, GameSharkCode, GameSharkTarget};
+
+    #[test]
+    fn parse_game_genie() {
+        assert_eq!(GameGenieCode::parse("00A-17B-C49"), Ok(GameGenieCode { address: 0x4A17, value: 0x00, compare: Some(0xC8) }));
+        assert_eq!(GameGenieCode::parse("3ED-58F"), Ok(GameGenieCode { address: 0x0D58, value: 0x3E, compare: None }));
+        assert_eq!(GameGenieCode::parse("3ed58f"), GameGenieCode::parse("3ED-58F"));
+        assert_eq!(GameGenieCode::parse("3ED-58"), Err(CheatError::InvalidLength));
+        assert_eq!(GameGenieCode::parse("3ED-58F-0"), Err(CheatError::InvalidLength));
+        assert_eq!(GameGenieCode::parse("3ED-5XF"), Err(CheatError::InvalidCharacter('X')));
+        assert_eq!(GameGenieCode::parse("3ED-587"), Err(CheatError::GameGenieOutsideRom(0x8D58)));
+    }
+
+    #[test]
+    fn parse_gameshark() {
+        assert_eq!(GameSharkCode::parse("010238CD"), Ok(GameSharkCode { address: 0xCD38, value: 0x02, target: GameSharkTarget::Mapped }));
+        assert_eq!(GameSharkCode::parse("826300A0"), Ok(GameSharkCode { address: 0xA000, value: 0x63, target: GameSharkTarget::CartridgeRam(2) }));
+        assert_eq!(GameSharkCode::parse("9163A0D3"), Ok(GameSharkCode { address: 0xD3A0, value: 0x63, target: GameSharkTarget::WorkRam(1) }));
+        assert_eq!(GameSharkCode::parse("9063A0D3").unwrap().target, GameSharkTarget::WorkRam(1));
+        // The bank only applies to its own memory
+        assert_eq!(GameSharkCode::parse("8163A0C3").unwrap().target, GameSharkTarget::Mapped);
+        assert_eq!(GameSharkCode::parse("01FF80FF").unwrap().address, 0xFF80);
+        assert_eq!(GameSharkCode::parse("01FF00E0").unwrap().address, 0xE000);
+        assert_eq!(GameSharkCode::parse("01020040"), Err(CheatError::GameSharkInRom(0x4000)));
+        assert_eq!(GameSharkCode::parse("A1020040"), Err(CheatError::GameSharkInRom(0x4000)));
+        assert_eq!(GameSharkCode::parse("A10238CD"), Err(CheatError::UnsupportedGameSharkType(0xA1)));
+        assert_eq!(GameSharkCode::parse("010238C"), Err(CheatError::InvalidLength));
+        assert_eq!(GameSharkCode::parse("010238CG"), Err(CheatError::InvalidCharacter('G')));
+    }
+
+    #[test]
+    fn patch_rom() {
+        let mut cheats = Cheats::default();
+        let plain = GameGenieCode::parse("3ED-58F").unwrap();
+        let compared = GameGenieCode::parse("00A-17B-C49").unwrap();
+        cheats.add_game_genie(plain);
+        cheats.add_game_genie(compared);
+        assert_eq!(cheats.patch_rom(0x0D58, 0x12), 0x3E);
+        assert_eq!(cheats.patch_rom(0x0D59, 0x12), 0x12);
+        // Only the bank with the compare value is patched
+        assert_eq!(cheats.patch_rom(0x4A17, 0xC8), 0x00);
+        assert_eq!(cheats.patch_rom(0x4A17, 0xC9), 0xC9);
+
+        assert!(cheats.remove_game_genie(plain));
+        assert!(!cheats.remove_game_genie(plain));
+        assert_eq!(cheats.patch_rom(0x0D58, 0x12), 0x12);
+    }
+}
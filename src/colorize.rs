@@ -0,0 +1,243 @@
+// The palette sets of the CGB boot ROM for DMG games, as RGB555 for BGP, OBP0 and OBP1, by their
+// number in the boot ROM. The first is what it gives a game it has no colors for. The twelve that a
+// player picks with a direction and A or B while the logo shows have names, the others are named by
+// their number.
+pub const COMPAT_PALETTES: [(&str, [[u16; 4]; 3]); 51] = [
+    ("dark-green", [[0x7FFF, 0x1BEF, 0x6180, 0x0000], [0x7FFF, 0x421F, 0x1CF2, 0x0000], [0x7FFF, 0x421F, 0x1CF2, 0x0000]]),
+    ("green", [[0x7FFF, 0x03EA, 0x011F, 0x0000], [0x7FFF, 0x03EA, 0x011F, 0x0000], [0x7FFF, 0x03EA, 0x011F, 0x0000]]),
+    ("0x02", [[0x7FFF, 0x027F, 0x001F, 0x0000], [0x7FFF, 0x027F, 0x001F, 0x0000], [0x7FFF, 0x027F, 0x001F, 0x0000]]),
+    ("orange", [[0x7FFF, 0x03FF, 0x001F, 0x0000], [0x7FFF, 0x03FF, 0x001F, 0x0000], [0x7FFF, 0x03FF, 0x001F, 0x0000]]),
+    ("0x04", [[0x7E74, 0x03FF, 0x0180, 0x0000], [0x7E74, 0x03FF, 0x0180, 0x0000], [0x7E74, 0x03FF, 0x0180, 0x0000]]),
+    ("brown", [[0x7FFF, 0x32BF, 0x00D0, 0x0000], [0x7FFF, 0x32BF, 0x00D0, 0x0000], [0x7FFF, 0x32BF, 0x00D0, 0x0000]]),
+    ("inverted", [[0x0000, 0x4200, 0x037F, 0x7FFF], [0x0000, 0x4200, 0x037F, 0x7FFF], [0x0000, 0x4200, 0x037F, 0x7FFF]]),
+    ("grayscale", [[0x7FFF, 0x5294, 0x294A, 0x0000], [0x7FFF, 0x5294, 0x294A, 0x0000], [0x7FFF, 0x5294, 0x294A, 0x0000]]),
+    ("pastel", [[0x53FF, 0x4A5F, 0x7E52, 0x0000], [0x53FF, 0x4A5F, 0x7E52, 0x0000], [0x53FF, 0x4A5F, 0x7E52, 0x0000]]),
+    ("0x09", [[0x7FFF, 0x033F, 0x0193, 0x0000], [0x7FFF, 0x033F, 0x0193, 0x0000], [0x7FFF, 0x033F, 0x0193, 0x0000]]),
+    ("0x0A", [[0x7FFF, 0x42B5, 0x3DC8, 0x0000], [0x7FFF, 0x01DF, 0x0112, 0x0000], [0x7FFF, 0x42B5, 0x3DC8, 0x0000]]),
+    ("0x0B", [[0x7FFF, 0x7E8C, 0x7C00, 0x0000], [0x7FFF, 0x421F, 0x1CF2, 0x0000], [0x7FFF, 0x7E8C, 0x7C00, 0x0000]]),
+    ("0x0C", [[0x7FFF, 0x6E31, 0x454A, 0x0000], [0x7FFF, 0x421F, 0x1CF2, 0x0000], [0x7FFF, 0x6E31, 0x454A, 0x0000]]),
+    ("0x0D", [[0x7FFF, 0x421F, 0x1CF2, 0x0000], [0x7FFF, 0x1BEF, 0x0200, 0x0000], [0x7FFF, 0x421F, 0x1CF2, 0x0000]]),
+    ("0x0E", [[0x7FFF, 0x1BEF, 0x6180, 0x0000], [0x7FFF, 0x421F, 0x1CF2, 0x0000], [0x7FFF, 0x1BEF, 0x6180, 0x0000]]),
+    ("0x0F", [[0x7FFF, 0x7E8C, 0x7C00, 0x0000], [0x7FFF, 0x7E8C, 0x7C00, 0x0000], [0x7FFF, 0x421F, 0x1CF2, 0x0000]]),
+    ("0x10", [[0x7FFF, 0x6E31, 0x454A, 0x0000], [0x7FFF, 0x6E31, 0x454A, 0x0000], [0x231F, 0x035F, 0x00F2, 0x0009]]),
+    ("0x11", [[0x7FFF, 0x42B5, 0x3DC8, 0x0000], [0x7FFF, 0x01DF, 0x0112, 0x0000], [0x7FFF, 0x01DF, 0x0112, 0x0000]]),
+    ("0x12", [[0x7FFF, 0x03EF, 0x01D6, 0x0000], [0x7FFF, 0x421F, 0x1CF2, 0x0000], [0x7FFF, 0x421F, 0x1CF2, 0x0000]]),
+    ("0x13", [[0x7FFF, 0x03EA, 0x011F, 0x0000], [0x7FFF, 0x421F, 0x1CF2, 0x0000], [0x7FFF, 0x421F, 0x1CF2, 0x0000]]),
+    ("0x14", [[0x7FFF, 0x027F, 0x001F, 0x0000], [0x7FFF, 0x421F, 0x1CF2, 0x0000], [0x7FFF, 0x421F, 0x1CF2, 0x0000]]),
+    ("0x15", [[0x7E74, 0x03FF, 0x0180, 0x0000], [0x299F, 0x001A, 0x000C, 0x0000], [0x299F, 0x001A, 0x000C, 0x0000]]),
+    ("0x16", [[0x7ED6, 0x4BFF, 0x2175, 0x0000], [0x0000, 0x7FFF, 0x421F, 0x1CF2], [0x0000, 0x7FFF, 0x421F, 0x1CF2]]),
+    ("0x17", [[0x7FFF, 0x6E31, 0x454A, 0x0000], [0x231F, 0x035F, 0x00F2, 0x0009], [0x231F, 0x035F, 0x00F2, 0x0009]]),
+    ("0x18", [[0x7FFF, 0x6E31, 0x454A, 0x0000], [0x7FFF, 0x421F, 0x1CF2, 0x0000], [0x7FFF, 0x421F, 0x1CF2, 0x0000]]),
+    ("0x19", [[0x7FFF, 0x1BEF, 0x0200, 0x0000], [0x7FFF, 0x421F, 0x1CF2, 0x0000], [0x7FFF, 0x421F, 0x1CF2, 0x0000]]),
+    ("0x1A", [[0x7FFF, 0x32BF, 0x00D0, 0x0000], [0x7FFF, 0x7E8C, 0x7C00, 0x0000], [0x7FFF, 0x7E8C, 0x7C00, 0x0000]]),
+    ("0x1B", [[0x7FFF, 0x32BF, 0x00D0, 0x0000], [0x7FFF, 0x1BEF, 0x0200, 0x0000], [0x7FFF, 0x1BEF, 0x0200, 0x0000]]),
+    ("dark-brown", [[0x639F, 0x4279, 0x15B0, 0x04CB], [0x7FFF, 0x32BF, 0x00D0, 0x0000], [0x7FFF, 0x32BF, 0x00D0, 0x0000]]),
+    ("0x1D", [[0x7FFF, 0x03EA, 0x011F, 0x0000], [0x7FFF, 0x03EA, 0x011F, 0x0000], [0x7FFF, 0x7EEB, 0x001F, 0x7C00]]),
+    ("0x1E", [[0x7FFF, 0x027F, 0x001F, 0x0000], [0x7FFF, 0x027F, 0x001F, 0x0000], [0x7FFF, 0x7EEB, 0x001F, 0x7C00]]),
+    ("0x1F", [[0x7FFF, 0x03FF, 0x001F, 0x0000], [0x7FFF, 0x03FF, 0x001F, 0x0000], [0x7FFF, 0x7EEB, 0x001F, 0x7C00]]),
+    ("0x20", [[0x7FFF, 0x42B5, 0x3DC8, 0x0000], [0x7FFF, 0x01DF, 0x0112, 0x0000], [0x7FFF, 0x7EEB, 0x001F, 0x7C00]]),
+    ("0x21", [[0x4FFF, 0x7ED2, 0x3A4C, 0x1CE0], [0x231F, 0x035F, 0x00F2, 0x0009], [0x7FFF, 0x421F, 0x1CF2, 0x0000]]),
+    ("0x22", [[0x03ED, 0x7FFF, 0x255F, 0x0000], [0x7FFF, 0x7FFF, 0x7E8C, 0x7C00], [0x7FFF, 0x32BF, 0x00D0, 0x0000]]),
+    ("0x23", [[0x036A, 0x021F, 0x03FF, 0x7FFF], [0x7FFF, 0x7FFF, 0x7E8C, 0x7C00], [0x7FFF, 0x421F, 0x1CF2, 0x0000]]),
+    ("0x24", [[0x7E74, 0x03FF, 0x0180, 0x0000], [0x299F, 0x001A, 0x000C, 0x0000], [0x7FFF, 0x7EEB, 0x001F, 0x7C00]]),
+    ("0x25", [[0x67FF, 0x77AC, 0x1A13, 0x2D6B], [0x7FFF, 0x01DF, 0x0112, 0x0000], [0x7FFF, 0x7E8C, 0x7C00, 0x0000]]),
+    ("0x26", [[0x7FFF, 0x7E8C, 0x7C00, 0x0000], [0x7FFF, 0x421F, 0x1CF2, 0x0000], [0x7FFF, 0x3FFF, 0x7E00, 0x001F]]),
+    ("0x27", [[0x7FFF, 0x6E31, 0x454A, 0x0000], [0x231F, 0x035F, 0x00F2, 0x0009], [0x7FFF, 0x7EEB, 0x001F, 0x7C00]]),
+    ("dark-blue", [[0x7FFF, 0x6E31, 0x454A, 0x0000], [0x7FFF, 0x421F, 0x1CF2, 0x0000], [0x7FFF, 0x32BF, 0x00D0, 0x0000]]),
+    ("0x29", [[0x7FFF, 0x1BEF, 0x0200, 0x0000], [0x7FFF, 0x421F, 0x1CF2, 0x0000], [0x7FFF, 0x7E8C, 0x7C00, 0x0000]]),
+    ("0x2A", [[0x7FFF, 0x32BF, 0x00D0, 0x0000], [0x7FFF, 0x7E8C, 0x7C00, 0x0000], [0x7FFF, 0x1BEF, 0x0200, 0x0000]]),
+    ("red", [[0x7FFF, 0x421F, 0x1CF2, 0x0000], [0x7FFF, 0x1BEF, 0x0200, 0x0000], [0x7FFF, 0x7E8C, 0x7C00, 0x0000]]),
+    ("0x2C", [[0x7FFF, 0x421F, 0x1CF2, 0x0000], [0x7FFF, 0x03E0, 0x0206, 0x0120], [0x7FFF, 0x7E8C, 0x7C00, 0x0000]]),
+    ("0x2D", [[0x7FFF, 0x32BF, 0x00D0, 0x0000], [0x7FFF, 0x1BEF, 0x0200, 0x0000], [0x7FFF, 0x7E8C, 0x7C00, 0x0000]]),
+    ("0x2E", [[0x7FFF, 0x7E8C, 0x7C00, 0x0000], [0x03FF, 0x001F, 0x000C, 0x0000], [0x7FFF, 0x1BEF, 0x0200, 0x0000]]),
+    ("0x2F", [[0x7FFF, 0x42B5, 0x3DC8, 0x0000], [0x7FFF, 0x32BF, 0x00D0, 0x0000], [0x7FFF, 0x7E8C, 0x7C00, 0x0000]]),
+    ("blue", [[0x7FFF, 0x7E8C, 0x7C00, 0x0000], [0x7FFF, 0x421F, 0x1CF2, 0x0000], [0x7FFF, 0x1BEF, 0x0200, 0x0000]]),
+    ("yellow", [[0x7FFF, 0x03FF, 0x012F, 0x0000], [0x7FFF, 0x7E8C, 0x7C00, 0x0000], [0x7FFF, 0x1BEF, 0x0200, 0x0000]]),
+    ("0x32", [[0x7FFF, 0x1BEF, 0x6180, 0x0000], [0x7FFF, 0x421F, 0x1CF2, 0x0000], [0x7FFF, 0x7E8C, 0x7C00, 0x0000]]),
+];
+
+const GRAYSCALE: usize = 7;
+
+// Titles by the sum of their 16 bytes, with the set in COMPAT_PALETTES that the boot ROM gives them.
+// The sums of the last 29 are shared by several titles, and are told apart by the fourth letter.
+const TITLE_PALETTES: [(u8, Option<u8>, usize); 94] = [
+    (0x00, None, 0x00),
+    (0x88, None, 0x04), // ALLEY WAY
+    (0x16, None, 0x05), // YAKUMAN
+    (0x36, None, 0x23), // BASEBALL, GAME&WATCH 2
+    (0xD1, None, 0x22), // TENNIS
+    (0xDB, None, 0x03), // TETRIS
+    (0xF2, None, 0x1F), // QIX
+    (0x3C, None, 0x0F), // DR.MARIO
+    (0x8C, None, 0x0A), // RADARMISSION
+    (0x92, None, 0x05), // F1RACE
+    (0x3D, None, 0x13), // YOSSY NO TAMAGO
+    (0x5C, None, 0x24),
+    (0x58, None, 0x07), // X
+    (0xC9, None, 0x25), // MARIOLAND2
+    (0x3E, None, 0x1E), // YOSSY NO COOKIE
+    (0x70, None, 0x2C), // ZELDA
+    (0x1D, None, 0x15), // KIRBY'S PINBALL
+    (0x59, None, 0x20),
+    (0x69, None, 0x1F), // TETRIS FLASH
+    (0x19, None, 0x14), // DONKEY KONG
+    (0x35, None, 0x05), // MARIO'S PICROSS
+    (0xA8, None, 0x21),
+    (0x14, None, 0x0D), // POKEMON RED, GAMEBOYCAMERA G
+    (0xAA, None, 0x0E), // POKEMON GREEN
+    (0x75, None, 0x05), // PICROSS 2
+    (0x95, None, 0x1D), // YOSSY NO PANEPON
+    (0x99, None, 0x05), // KIRAKIRA KIDS
+    (0x34, None, 0x12), // GAMEBOY GALLERY
+    (0x6F, None, 0x09), // POCKETCAMERA
+    (0x15, None, 0x03),
+    (0xFF, None, 0x02), // BALLOON KID
+    (0x97, None, 0x1A), // KINGOFTHEZOO
+    (0x4B, None, 0x19), // DMG FOOTBALL
+    (0x90, None, 0x19), // WORLD CUP
+    (0x17, None, 0x29), // OTHELLO
+    (0x10, None, 0x2A), // SUPER RC PRO-AM
+    (0x39, None, 0x1A), // DYNABLASTER
+    (0xF7, None, 0x2D), // BOY AND BLOB GB2
+    (0xF6, None, 0x2A), // MEGAMAN
+    (0xA2, None, 0x2D), // STAR WARS-NOA
+    (0x49, None, 0x24), // KIRBY DREAM LAND
+    (0x4E, None, 0x26), // WAVERACE
+    (0x43, None, 0x1A),
+    (0x68, None, 0x2A), // LOLO2
+    (0xE0, None, 0x1E), // YOSHI'S COOKIE
+    (0x8B, None, 0x29), // MYSTIC QUEST
+    (0xF0, None, 0x22),
+    (0xCE, None, 0x22), // TOPRANKINGTENNIS
+    (0x0C, None, 0x05), // MANSELL
+    (0x29, None, 0x2A), // MEGAMAN3
+    (0xE8, None, 0x06), // SPACE INVADERS
+    (0xB7, None, 0x05), // GAME&WATCH
+    (0x86, None, 0x21), // DONKEYKONGLAND95
+    (0x9A, None, 0x19), // ASTEROIDS/MISCMD
+    (0x52, None, 0x2A), // STREET FIGHTER 2
+    (0x01, None, 0x2A), // DEFENDER/JOUST
+    (0x9D, None, 0x28), // KILLERINSTINCT95
+    (0x71, None, 0x02), // TETRIS BLAST
+    (0x9C, None, 0x10), // PINOCCHIO
+    (0xBD, None, 0x19),
+    (0x5D, None, 0x2A), // BA.TOSHINDEN
+    (0x6D, None, 0x2A), // NETTOU KOF 95
+    (0x67, None, 0x05),
+    (0x3F, None, 0x00), // TETRIS PLUS
+    (0x6B, None, 0x27), // DONKEYKONGLAND 3
+    (0xB3, Some(b'B'), 0x24),
+    (0x46, Some(b'E'), 0x16), // SUPER MARIOLAND
+    (0x28, Some(b'F'), 0x19), // GOLF
+    (0xA5, Some(b'A'), 0x06), // SOLARSTRIKER
+    (0xC6, Some(b'A'), 0x20), // GBWARS
+    (0xD3, Some(b'R'), 0x0C), // KAERUNOTAMENI
+    (0x27, Some(b'B'), 0x24),
+    (0x61, Some(b'E'), 0x0B), // POKEMON BLUE
+    (0x18, Some(b'K'), 0x27), // DONKEYKONGLAND
+    (0x66, Some(b'E'), 0x12), // GAMEBOY GALLERY2
+    (0x6A, Some(b'K'), 0x27), // DONKEYKONGLAND 2
+    (0xBF, Some(b' '), 0x18), // KID ICARUS
+    (0x0D, Some(b'R'), 0x1F), // TETRIS2
+    (0xF4, Some(b'-'), 0x32),
+    (0xB3, Some(b'U'), 0x11), // MOGURANYA
+    (0x46, Some(b'R'), 0x2E),
+    (0x28, Some(b'A'), 0x06), // GALAGA&GALAXIAN
+    (0xA5, Some(b'R'), 0x1B), // BT2RAGNAROKWORLD
+    (0xC6, Some(b' '), 0x00), // KEN GRIFFEY JR
+    (0xD3, Some(b'I'), 0x2F),
+    (0x27, Some(b'N'), 0x29), // MAGNETIC SOCCER
+    (0x61, Some(b'A'), 0x29), // VEGAS STAKES
+    (0x18, Some(b'I'), 0x00),
+    (0x66, Some(b'L'), 0x00), // MILLI/CENTI/PEDE
+    (0x6A, Some(b'I'), 0x13), // MARIO & YOSHI
+    (0xBF, Some(b'C'), 0x22), // SOCCER
+    (0x0D, Some(b'E'), 0x17), // POKEBOM
+    (0xF4, Some(b' '), 0x12), // G&W GALLERY
+    (0xB3, Some(b'R'), 0x1D), // TETRIS ATTACK
+];
+
+// How a DMG game is colored on a Gameboy Color without a boot ROM
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Colorization {
+    // The set the boot ROM picks from the title
+    #[default]
+    Auto,
+    // An index of COMPAT_PALETTES
+    Palette(usize),
+    // Gray shades, as on a DMG
+    Off,
+}
+
+// The set the boot ROM picks for the header at 0134-014F of the ROM: only games by Nintendo get
+// colors of their own
+fn title_palette(rom: &[u8]) -> usize {
+    let licensee = rom[0x14B];
+    let nintendo = licensee == 0x01 || (licensee == 0x33 && &rom[0x144 .. 0x146] == b"01");
+    if !nintendo { return 0 }
+
+    let checksum = rom[0x134 ..= 0x143].iter().fold(0u8, |sum, &v| sum.wrapping_add(v));
+    TITLE_PALETTES.iter()
+        .find(|&&(sum, letter, _)| sum == checksum && (letter.is_none() || letter == Some(rom[0x137])))
+        .map_or(0, |&(_, _, set)| set)
+}
+
+// The BGP, OBP0 and OBP1 colors for a ROM of at least 0150 bytes
+pub fn compat_palettes(rom: &[u8], colorization: Colorization) -> [[u16; 4]; 3] {
+    let set = match colorization {
+        Colorization::Auto => title_palette(rom),
+        Colorization::Palette(set) => set,
+        Colorization::Off => GRAYSCALE,
+    };
+    COMPAT_PALETTES.get(set).unwrap_or(&COMPAT_PALETTES[0]).1
+}
+
+#[cfg(test)]
+mod test {
+    use super::{compat_palettes, title_palette, Colorization, COMPAT_PALETTES};
+
+    fn rom(title: &[u8], old_licensee: u8, new_licensee: &[u8; 2]) -> Vec<u8> {
+        let mut rom = vec![0; 0x150];
+        rom[0x134 .. 0x134 + title.len()].copy_from_slice(title);
+        rom[0x144 .. 0x146].copy_from_slice(new_licensee);
+        rom[0x14B] = old_licensee;
+        rom
+    }
+
+    fn set_of(title: &[u8]) -> &'static str {
+        COMPAT_PALETTES[title_palette(&rom(title, 0x01, b"\0\0"))].0
+    }
+
+    #[test]
+    fn palettes_by_title() {
+        assert_eq!(set_of(b"POKEMON RED"), "0x0D");
+        assert_eq!(set_of(b"TETRIS"), "orange");
+        assert_eq!(set_of(b"ZELDA"), "0x2C");
+        assert_eq!(set_of(b"DR.MARIO"), "0x0F");
+        assert_eq!(set_of(b"KIRBY DREAM LAND"), "0x24");
+        assert_eq!(set_of(b"MARIOLAND2"), "0x25");
+        assert_eq!(set_of(b"ALLEY WAY"), "0x04");
+        // Titles with the same sum are told apart by the fourth letter
+        assert_eq!(set_of(b"SUPER MARIOLAND"), "0x16");
+        assert_eq!(set_of(b"METROID2"), "0x2E");
+        assert_eq!(set_of(b"TETRIS2"), "0x1F");
+        assert_eq!(set_of(b"POKEBOM"), "0x17");
+        assert_eq!(set_of(b"KID ICARUS"), "0x18");
+        assert_eq!(set_of(b"SOCCER"), "0x22");
+        // The sum of SUPER MARIOLAND with a fourth letter that is not in the table
+        assert_eq!(set_of(b"\0\0\0Z\xEC"), "dark-green");
+        // Only Nintendo games are looked up
+        assert_eq!(COMPAT_PALETTES[title_palette(&rom(b"POKEMON BLUE", 0x33, b"01"))].0, "0x0B");
+        assert_eq!(COMPAT_PALETTES[title_palette(&rom(b"POKEMON RED", 0x33, b"08"))].0, "dark-green");
+        assert_eq!(COMPAT_PALETTES[title_palette(&rom(b"POKEMON RED", 0x08, b"\0\0"))].0, "dark-green");
+        assert_eq!(set_of(b"UNKNOWN"), "dark-green");
+    }
+
+    #[test]
+    fn palette_overrides() {
+        let red = rom(b"POKEMON RED", 0x01, b"\0\0");
+        assert_eq!(compat_palettes(&red, Colorization::Auto), COMPAT_PALETTES[0x0D].1);
+        assert_eq!(compat_palettes(&red, Colorization::Palette(5)), COMPAT_PALETTES[5].1);
+        assert_eq!(compat_palettes(&red, Colorization::Off), [[0x7FFF, 0x5294, 0x294A, 0x0000]; 3]);
+    }
+}
@@ -1,17 +1,58 @@
 use crate::register::CpuFlag::{C, N, H, Z};
 use crate::register::Registers;
 use crate::serial::SerialCallback;
+use crate::sgb::Sgb;
 use crate::mmu::MMU;
 use crate::mbc;
+use crate::debugger::{DebugHooks, DebugStop};
+use crate::disasm::disassemble;
+use crate::profiler::{ProfileEntry, Profiler};
+use crate::state::{StateReader, StateResult, StateWriter};
+use crate::trace::CpuTrace;
+use crate::trap::{SpeedSwitchConditions, TrapMonitor, TrapOptions, TrapReport};
 use crate::StrResult;
 
+// The CPU pauses for this many M-cycles while switching speed
+const SPEED_SWITCH_PAUSE: u32 = 2050;
+
+// The CPU as seen by debuggers and other tools
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct CpuState {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub ime: bool,
+    pub halted: bool,
+}
+
 pub struct CPU<'a> {
-    reg: Registers,
+    pub reg: Registers,
     pub mmu: MMU<'a>,
     halted: bool,
+    halt_bug: bool,
+    stopped: bool,
+    // The address of the illegal opcode that hung the CPU
+    locked: Option<u16>,
+    speed_switch_pause: u32,
+    instr_cycles: u32,
+    instr_ticks: u32,
     ime: bool,
-    setdi: u32,
-    setei: u32,
+    // EI was executed, and IME is set after the next instruction
+    ei_pending: bool,
+    traps: Option<TrapMonitor>,
+    trace: Option<CpuTrace>,
+    trap_report: Option<TrapReport>,
+    debug: Option<Box<DebugHooks>>,
+    debug_stop: Option<DebugStop>,
+    paused: bool,
+    profiler: Option<Box<Profiler>>,
 }
 
 impl<'a> CPU<'a> {
@@ -21,9 +62,21 @@ impl<'a> CPU<'a> {
         Ok(CPU {
             reg: registers,
             halted: false,
+            halt_bug: false,
+            stopped: false,
+            locked: None,
+            speed_switch_pause: 0,
+            instr_cycles: 0,
+            instr_ticks: 0,
             ime: true,
-            setdi: 0,
-            setei: 0,
+            ei_pending: false,
+            traps: None,
+            trace: None,
+            trap_report: None,
+            debug: None,
+            debug_stop: None,
+            paused: false,
+            profiler: None,
             mmu: cpu_mmu,
         })
     }
@@ -34,20 +87,304 @@ impl<'a> CPU<'a> {
         Ok(CPU {
             reg: registers,
             halted: false,
+            halt_bug: false,
+            stopped: false,
+            locked: None,
+            speed_switch_pause: 0,
+            instr_cycles: 0,
+            instr_ticks: 0,
             ime: true,
-            setdi: 0,
-            setei: 0,
+            ei_pending: false,
+            traps: None,
+            trace: None,
+            trap_report: None,
+            debug: None,
+            debug_stop: None,
+            paused: false,
+            profiler: None,
             mmu: cpu_mmu,
         })
     }
 
+    // Starts from the power on state at 0000 in a DMG boot ROM, which hands over to the game at 0100
+    pub fn map_boot_rom(&mut self, boot_rom: Vec<u8>) {
+        self.mmu.map_boot_rom(boot_rom);
+        self.reg = Registers::power_on();
+        self.ime = false;
+    }
+
+    // Runs as a Super Game Boy, from the state after its boot ROM
+    pub fn enable_sgb(&mut self) {
+        self.mmu.sgb = Some(Sgb::new());
+        self.reg = Registers::sgb();
+    }
+
+    pub fn set_traps(&mut self, options: TrapOptions) {
+        self.traps = match options.any_enabled() {
+            true => Some(TrapMonitor::new(options)),
+            false => None,
+        };
+    }
+
+    pub fn trap_options(&self) -> TrapOptions {
+        match self.traps {
+            Some(ref monitor) => monitor.options(),
+            None => TrapOptions::disabled(),
+        }
+    }
+
+    // Returns the report of the trap that paused the CPU, only once
+    pub fn take_trap(&mut self) -> Option<TrapReport> {
+        self.trap_report.take()
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn resume(&mut self) {
+        self.paused = false;
+        let pc = self.reg.pc;
+        if let Some(ref mut debug) = self.debug {
+            debug.resume_at(pc);
+        }
+    }
+
+    // Runs a single instruction or interrupt dispatch, even when paused, and returns the
+    // elapsed ticks like do_cycle. A paused CPU stays paused.
+    pub fn step(&mut self) -> u32 {
+        let paused = self.paused;
+        self.resume();
+        let ticks = self.do_cycle();
+        self.paused |= paused;
+        ticks
+    }
+
+    // Runs until at least the given number of ticks have elapsed, and returns the overshoot
+    pub fn run_cycles(&mut self, ticks: u32) -> u32 {
+        let mut elapsed = 0;
+        while elapsed < ticks {
+            elapsed += self.do_cycle();
+        }
+        elapsed - ticks
+    }
+
+    pub fn ime(&self) -> bool {
+        self.ime
+    }
+
+    pub fn state(&self) -> CpuState {
+        let reg = &self.reg;
+        CpuState {
+            a: reg.a, f: reg.af() as u8, b: reg.b, c: reg.c, d: reg.d, e: reg.e, h: reg.h, l: reg.l,
+            sp: reg.sp, pc: reg.pc,
+            ime: self.ime,
+            halted: self.halted,
+        }
+    }
+
+    // The lower nibble of F does not exist, so it is dropped as with POP AF
+    pub fn set_state(&mut self, state: &CpuState) {
+        self.reg.setaf(((state.a as u16) << 8) | state.f as u16);
+        self.reg.setbc(((state.b as u16) << 8) | state.c as u16);
+        self.reg.setde(((state.d as u16) << 8) | state.e as u16);
+        self.reg.sethl(((state.h as u16) << 8) | state.l as u16);
+        self.reg.sp = state.sp;
+        self.reg.pc = state.pc;
+        self.ime = state.ime;
+        self.halted = state.halted;
+    }
+
+    // Returns the address of the illegal opcode that locked up the CPU
+    pub fn locked(&self) -> Option<u16> {
+        self.locked
+    }
+
+    // The machine, without the debugger, traps, trace and profiler
+    pub fn save_state(&self, w: &mut StateWriter) {
+        let reg = &self.reg;
+        for v in [reg.af(), reg.bc(), reg.de(), reg.hl(), reg.sp, reg.pc] {
+            w.u16(v);
+        }
+        w.bool(self.halted);
+        w.bool(self.halt_bug);
+        w.bool(self.stopped);
+        w.bool(self.locked.is_some());
+        if let Some(address) = self.locked {
+            w.u16(address);
+        }
+        w.u32(self.speed_switch_pause);
+        w.bool(self.ime);
+        w.bool(self.ei_pending);
+        self.mmu.save_state(w);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.reg.setaf(r.u16()?);
+        self.reg.setbc(r.u16()?);
+        self.reg.setde(r.u16()?);
+        self.reg.sethl(r.u16()?);
+        self.reg.sp = r.u16()?;
+        self.reg.pc = r.u16()?;
+        self.halted = r.bool()?;
+        self.halt_bug = r.bool()?;
+        self.stopped = r.bool()?;
+        self.locked = match r.bool()? {
+            true => Some(r.u16()?),
+            false => None,
+        };
+        self.speed_switch_pause = r.u32()?;
+        self.ime = r.bool()?;
+        self.ei_pending = r.bool()?;
+        self.mmu.load_state(r)
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.debug_hooks().add_breakpoint(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) {
+        self.debug_hooks().remove_breakpoint(address);
+        self.drop_empty_debug_hooks();
+    }
+
+    pub fn add_watchpoint(&mut self, address: u16, on_read: bool, on_write: bool) {
+        self.debug_hooks().add_watchpoint(address, on_read, on_write);
+        self.drop_empty_debug_hooks();
+    }
+
+    pub fn remove_watchpoint(&mut self, address: u16) {
+        self.debug_hooks().remove_watchpoint(address);
+        self.drop_empty_debug_hooks();
+    }
+
+    pub fn set_soft_breakpoints(&mut self, enabled: bool) {
+        self.debug_hooks().set_soft_breakpoints(enabled);
+        self.drop_empty_debug_hooks();
+    }
+
+    // The structured steps resume the CPU and pause it again with DebugStop::Reached when done.
+    // Breakpoints and watchpoints still stop them early.
+    pub fn run_to(&mut self, address: u16) {
+        self.debug_hooks().run_to(address);
+        self.resume();
+    }
+
+    // With follow_interrupts, an interrupt during the step stops at its handler. Otherwise the
+    // handler runs until it returns.
+    pub fn step_over(&mut self, follow_interrupts: bool) {
+        let (pc, sp) = (self.reg.pc, self.reg.sp);
+        let opcode = self.mmu.rb(pc);
+        self.debug_hooks().step_over(pc, opcode, sp, follow_interrupts);
+        self.resume();
+    }
+
+    pub fn step_out(&mut self, follow_interrupts: bool) {
+        let sp = self.reg.sp;
+        self.debug_hooks().step_out(sp, follow_interrupts);
+        self.resume();
+    }
+
+    // Returns why the debugger paused the CPU, only once
+    pub fn take_debug_stop(&mut self) -> Option<DebugStop> {
+        self.debug_stop.take()
+    }
+
+    #[cfg(test)]
+    pub fn has_debug_hooks(&self) -> bool {
+        self.debug.is_some()
+    }
+
+    fn debug_hooks(&mut self) -> &mut DebugHooks {
+        self.debug.get_or_insert_with(Default::default)
+    }
+
+    fn drop_empty_debug_hooks(&mut self) {
+        if self.debug.as_ref().is_some_and(|debug| debug.is_empty()) {
+            self.debug = None;
+        }
+    }
+
+    fn pause_on_debug(&mut self, stop: DebugStop) {
+        self.debug_stop = Some(stop);
+        self.paused = true;
+        self.drop_empty_debug_hooks();
+    }
+
     pub fn do_cycle(&mut self) -> u32 {
-        let ticks = self.docycle() * 4;
-        return self.mmu.do_cycle(ticks);
+        // Keep time moving for the frontend, but leave the machine alone
+        if self.paused { return 4; }
+
+        self.instr_cycles = 0;
+        self.instr_ticks = 0;
+        let cycles = self.docycle();
+        debug_assert!(self.instr_cycles <= cycles);
+        // Internal cycles that were not ticked on their own come at the end of the instruction
+        while self.instr_cycles < cycles {
+            self.idle();
+        }
+        // Watchpoints stop the CPU after the instruction that made the access
+        if let Some(stop) = self.debug.as_mut().and_then(|debug| debug.take_hit()) {
+            self.pause_on_debug(stop);
+        }
+        self.instr_ticks
+    }
+
+    // Every memory access takes one M-cycle, during which the rest of the system runs
+    fn tick(&mut self) {
+        self.instr_ticks += self.mmu.do_cycle(4);
+        self.instr_cycles += 1;
+    }
+
+    fn idle(&mut self) {
+        self.tick();
+    }
+
+    fn rb(&mut self, address: u16) -> u8 {
+        let v = self.mmu.cpu_rb(address);
+        if let Some(ref mut debug) = self.debug {
+            debug.read(address, v);
+        }
+        self.tick();
+        v
+    }
+
+    fn rw(&mut self, address: u16) -> u16 {
+        (self.rb(address) as u16) | ((self.rb(address.wrapping_add(1)) as u16) << 8)
+    }
+
+    fn wb(&mut self, address: u16, value: u8) {
+        if let Some(ref mut debug) = self.debug {
+            debug.write(address, value);
+        }
+        self.mmu.cpu_wb(address, value);
+        self.tick();
+    }
+
+    fn ww(&mut self, address: u16, value: u16) {
+        self.wb(address, (value & 0xFF) as u8);
+        self.wb(address.wrapping_add(1), (value >> 8) as u8);
     }
 
     fn docycle(&mut self) -> u32 {
-        self.updateime();
+        if self.speed_switch_pause > 0 {
+            self.speed_switch_pause -= 1;
+            if self.speed_switch_pause == 0 {
+                // DIV is reset again, as it does not run during the pause
+                self.mmu.switch_speed();
+            }
+            return 1;
+        }
+
+        // The rest of the system keeps running, so the screen freezes as on hardware
+        if self.locked.is_some() { return 1; }
+
+        if self.stopped {
+            // Only a held key on a selected row wakes the CPU, even if the joypad interrupt is disabled
+            if self.mmu.keypad.rb() & 0x0F == 0x0F { return 1; }
+            self.stopped = false;
+        }
+
         match self.handleinterrupt() {
             0 => {},
             n => return n,
@@ -55,580 +392,251 @@ impl<'a> CPU<'a> {
 
         if self.halted {
             // Emulate an noop instruction
-            1
+            return 1;
+        }
+
+        // Stop before the instruction, without spending any time on it
+        if self.debug.is_some() {
+            let pc = self.reg.pc;
+            let opcode = self.mmu.rb(pc);
+            let sp = self.reg.sp;
+            if let Some(stop) = self.debug.as_mut().unwrap().before(pc, opcode, sp) {
+                self.pause_on_debug(stop);
+                return 0;
+            }
+        }
+
+        if self.traps.is_some() {
+            self.call_with_traps()
         } else {
+            self.log_trace();
             self.call()
         }
     }
 
+    fn call_with_traps(&mut self) -> u32 {
+        let pc = self.reg.pc;
+        let opcode = self.mmu.rb(pc);
+        let monitor = self.traps.as_mut().unwrap();
+        if let Some(trap) = monitor.before(pc, opcode) {
+            self.pause_on_trap(trap);
+            return 1;
+        }
+        self.log_trace();
+
+        // The conditions have to be taken before STOP clears the request
+        let speed_switch = match opcode {
+            0x10 if self.mmu.speed_switch_requested() => Some(self.speed_switch_conditions()),
+            _ => None,
+        };
+
+        let ticks = self.call();
+        let monitor = self.traps.as_mut().unwrap();
+        if let Some(trap) = monitor.after(pc, opcode, self.reg.pc) {
+            self.pause_on_trap(trap);
+        }
+        else if let Some(trap) = speed_switch.and_then(|conditions| monitor.speed_switch(&conditions)) {
+            self.pause_on_trap(trap);
+        }
+        ticks
+    }
+
+    // LY reads as 90 while tracing when stub_ly is set, as the Gameboy Doctor logs expect
+    pub fn start_trace(&mut self, trace: CpuTrace, stub_ly: bool) {
+        self.trace = Some(trace);
+        self.mmu.set_ly_stub(stub_ly);
+    }
+
+    pub fn stop_trace(&mut self) {
+        if let Some(mut trace) = self.trace.take() {
+            trace.flush();
+        }
+        self.mmu.set_ly_stub(false);
+    }
+
+    // Counts the instructions and cycles spent on every address, see profile_report
+    pub fn start_profile(&mut self) {
+        if self.profiler.is_none() {
+            self.profiler = Some(Box::new(Profiler::new()));
+        }
+    }
+
+    pub fn stop_profile(&mut self) {
+        self.profiler = None;
+    }
+
+    // Empty when not profiling
+    pub fn profile_report(&self, top_n: usize) -> Vec<ProfileEntry> {
+        self.profiler.as_ref().map_or_else(Vec::new, |p| p.report(top_n))
+    }
+
+    // To keep the profile over a reset
+    pub fn take_profiler(&mut self) -> Option<Box<Profiler>> {
+        self.profiler.take()
+    }
+
+    pub fn set_profiler(&mut self, profiler: Option<Box<Profiler>>) {
+        self.profiler = profiler;
+    }
+
+    fn bank_and_pc(&self) -> (usize, u16) {
+        let pc = self.reg.pc;
+        match pc {
+            0x0000 ..= 0x7FFF => (self.mmu.mbc.rombank_at(pc), pc),
+            _ => (0, pc),
+        }
+    }
+
+    fn log_trace(&mut self) {
+        if self.trace.is_none() { return; }
+
+        // Like the trap history, this reads without ticking the system
+        let pc = self.reg.pc;
+        let mut pcmem = [0; 4];
+        for (i, v) in pcmem.iter_mut().enumerate() {
+            *v = self.mmu.rb(pc.wrapping_add(i as u16));
+        }
+        if !self.trace.as_mut().unwrap().log(&self.reg, pcmem) {
+            self.stop_trace();
+        }
+    }
+
+    fn speed_switch_conditions(&mut self) -> SpeedSwitchConditions {
+        let p1 = self.mmu.rb(0xFF00);
+        SpeedSwitchConditions {
+            joypad_selected: p1 & 0x30 != 0x30,
+            key_held: p1 & 0x0F != 0x0F,
+            interrupt_pending: self.mmu.inte & self.mmu.intf & 0x1F != 0,
+            ime: self.ime,
+        }
+    }
+
+    fn pause_on_trap(&mut self, trap: crate::trap::Trap) {
+        let addresses: Vec<u16> = self.traps.as_ref().unwrap().history().collect();
+        let history = addresses.into_iter().map(|pc| {
+            let bytes = [self.mmu.rb(pc), self.mmu.rb(pc.wrapping_add(1)), self.mmu.rb(pc.wrapping_add(2))];
+            (pc, disassemble(&bytes, pc).0)
+        }).collect();
+        self.trap_report = Some(TrapReport { trap, history });
+        self.paused = true;
+    }
+
     fn fetchbyte(&mut self) -> u8 {
-        let b = self.mmu.rb(self.reg.pc);
-        self.reg.pc = self.reg.pc.wrapping_add(1);
+        let b = self.rb(self.reg.pc);
+        match self.halt_bug {
+            true => self.halt_bug = false,
+            false => self.reg.pc = self.reg.pc.wrapping_add(1),
+        }
         b
     }
 
     fn fetchword(&mut self) -> u16 {
-        let w = self.mmu.rw(self.reg.pc);
-        self.reg.pc += 2;
+        let w = self.rw(self.reg.pc);
+        self.reg.pc = self.reg.pc.wrapping_add(2);
         w
     }
 
-    fn updateime(&mut self) {
-        self.setdi = match self.setdi {
-            2 => 1,
-            1 => { self.ime = false; 0 },
-            _ => 0,
-        };
-        self.setei = match self.setei {
-            2 => 1,
-            1 => { self.ime = true; 0 },
-            _ => 0,
-        };
-    }
-
     fn handleinterrupt(&mut self) -> u32 {
         if self.ime == false && self.halted == false { return 0 }
 
-        let triggered = self.mmu.inte & self.mmu.intf;
+        let triggered = self.mmu.inte & self.mmu.intf & 0x1F;
         if triggered == 0 { return 0 }
 
         self.halted = false;
         if self.ime == false { return 0 }
         self.ime = false;
 
-        let n = triggered.trailing_zeros();
-        if n >= 5 { panic!("Invalid interrupt triggered"); }
-        self.mmu.intf &= !(1 << n);
+        // After EI; HALT the interrupt returns to the HALT, which then halts as usual
+        if self.halt_bug {
+            self.halt_bug = false;
+            self.reg.pc = self.reg.pc.wrapping_sub(1);
+        }
+
+        // Two idle cycles, then the high byte of PC is pushed. That push can overwrite IE, so the
+        // interrupt is only chosen after it, and the dispatch jumps to 0000 when none is left.
         let pc = self.reg.pc;
-        self.pushstack(pc);
-        self.reg.pc = 0x0040 | ((n as u16) << 3);
+        self.idle();
+        self.idle();
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        self.wb(self.reg.sp, (pc >> 8) as u8);
+        let triggered = self.mmu.inte & self.mmu.intf & 0x1F;
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        self.wb(self.reg.sp, (pc & 0xFF) as u8);
+
+        self.reg.pc = match triggered {
+            0 => 0x0000,
+            _ => {
+                let n = triggered.trailing_zeros();
+                self.mmu.intf &= !(1 << n);
+                0x0040 | ((n as u16) << 3)
+            },
+        };
+        self.idle();
+        if let Some(ref mut debug) = self.debug {
+            debug.interrupt(pc, self.reg.sp, self.reg.pc);
+        }
+
+        5
+    }
+
+    // With IME off and an interrupt already pending, HALT does not halt. Instead the CPU fails to
+    // increment PC after fetching the next opcode, so the byte after HALT is read twice.
+    fn halt(&mut self) {
+        if !self.ime && self.mmu.inte & self.mmu.intf & 0x1F != 0 {
+            self.halt_bug = true;
+        } else {
+            self.halted = true;
+        }
+    }
 
-        return 4
+    // STOP is followed by a padding byte. With a speed switch requested in KEY1, it switches speed
+    // after a pause. Otherwise it enters the stop mode until a key is pressed.
+    fn stop(&mut self) {
+        self.reg.pc = self.reg.pc.wrapping_add(1);
+        self.mmu.wb(0xFF04, 0);
+        if self.mmu.speed_switch_requested() {
+            self.speed_switch_pause = SPEED_SWITCH_PAUSE;
+        } else {
+            self.stopped = true;
+        }
     }
 
+    // An internal cycle, then the high byte is written first
     fn pushstack(&mut self, value: u16) {
-        self.reg.sp = self.reg.sp.wrapping_sub(2);
-        self.mmu.ww(self.reg.sp, value);
+        self.idle();
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        self.wb(self.reg.sp, (value >> 8) as u8);
+        self.reg.sp = self.reg.sp.wrapping_sub(1);
+        self.wb(self.reg.sp, (value & 0xFF) as u8);
     }
 
     fn popstack(&mut self) -> u16 {
-        let res = self.mmu.rw(self.reg.sp);
-        self.reg.sp += 2;
+        let res = self.rw(self.reg.sp);
+        self.reg.sp = self.reg.sp.wrapping_add(2);
         res
     }
 
     fn call(&mut self) -> u32 {
+        // EI takes effect after the instruction that follows it, unless that is a DI. Until then
+        // no interrupt can come between them, which makes EI; RET and EI; HALT atomic.
+        let enable_ime = self.ei_pending;
+        // The bank has to be taken before the instruction switches it
+        let profile_at = self.profiler.is_some().then(|| self.bank_and_pc());
         let opcode = self.fetchbyte();
-        match opcode {
-            0x00 => { 1 },
-            0x01 => { let v = self.fetchword(); self.reg.setbc(v); 3 },
-            0x02 => { self.mmu.wb(self.reg.bc(), self.reg.a); 2 },
-            0x03 => { self.reg.setbc(self.reg.bc().wrapping_add(1)); 2 },
-            0x04 => { self.reg.b = self.alu_inc(self.reg.b); 1 },
-            0x05 => { self.reg.b = self.alu_dec(self.reg.b); 1 },
-            0x06 => { self.reg.b = self.fetchbyte(); 2 },
-            0x07 => { self.reg.a = self.alu_rlc(self.reg.a); self.reg.flag(Z, false); 1 },
-            0x08 => { let a = self.fetchword(); self.mmu.ww(a, self.reg.sp); 5 },
-            0x09 => { self.alu_add16(self.reg.bc()); 2 },
-            0x0A => { self.reg.a = self.mmu.rb(self.reg.bc()); 2 },
-            0x0B => { self.reg.setbc(self.reg.bc().wrapping_sub(1)); 2 },
-            0x0C => { self.reg.c = self.alu_inc(self.reg.c); 1 },
-            0x0D => { self.reg.c = self.alu_dec(self.reg.c); 1 },
-            0x0E => { self.reg.c = self.fetchbyte(); 2 },
-            0x0F => { self.reg.a = self.alu_rrc(self.reg.a); self.reg.flag(Z, false); 1 },
-            0x10 => { self.mmu.switch_speed(); 1 }, // STOP
-            0x11 => { let v = self.fetchword(); self.reg.setde(v); 3 },
-            0x12 => { self.mmu.wb(self.reg.de(), self.reg.a); 2 },
-            0x13 => { self.reg.setde(self.reg.de().wrapping_add(1)); 2 },
-            0x14 => { self.reg.d = self.alu_inc(self.reg.d); 1 },
-            0x15 => { self.reg.d = self.alu_dec(self.reg.d); 1 },
-            0x16 => { self.reg.d = self.fetchbyte(); 2 },
-            0x17 => { self.reg.a = self.alu_rl(self.reg.a); self.reg.flag(Z, false); 1 },
-            0x18 => { self.cpu_jr(); 3 },
-            0x19 => { self.alu_add16(self.reg.de()); 2 },
-            0x1A => { self.reg.a = self.mmu.rb(self.reg.de()); 2 },
-            0x1B => { self.reg.setde(self.reg.de().wrapping_sub(1)); 2 },
-            0x1C => { self.reg.e = self.alu_inc(self.reg.e); 1 },
-            0x1D => { self.reg.e = self.alu_dec(self.reg.e); 1 },
-            0x1E => { self.reg.e = self.fetchbyte(); 2 },
-            0x1F => { self.reg.a = self.alu_rr(self.reg.a); self.reg.flag(Z, false); 1 },
-            0x20 => { if !self.reg.getflag(Z) { self.cpu_jr(); 3 } else { self.reg.pc += 1; 2 } },
-            0x21 => { let v = self.fetchword(); self.reg.sethl(v); 3 },
-            0x22 => { self.mmu.wb(self.reg.hli(), self.reg.a); 2 },
-            0x23 => { let v = self.reg.hl().wrapping_add(1); self.reg.sethl(v); 2 },
-            0x24 => { self.reg.h = self.alu_inc(self.reg.h); 1 },
-            0x25 => { self.reg.h = self.alu_dec(self.reg.h); 1 },
-            0x26 => { self.reg.h = self.fetchbyte(); 2 },
-            0x27 => { self.alu_daa(); 1 },
-            0x28 => { if self.reg.getflag(Z) { self.cpu_jr(); 3 } else { self.reg.pc += 1; 2  } },
-            0x29 => { let v = self.reg.hl(); self.alu_add16(v); 2 },
-            0x2A => { self.reg.a = self.mmu.rb(self.reg.hli()); 2 },
-            0x2B => { let v = self.reg.hl().wrapping_sub(1); self.reg.sethl(v); 2 },
-            0x2C => { self.reg.l = self.alu_inc(self.reg.l); 1 },
-            0x2D => { self.reg.l = self.alu_dec(self.reg.l); 1 },
-            0x2E => { self.reg.l = self.fetchbyte(); 2 },
-            0x2F => { self.reg.a = !self.reg.a; self.reg.flag(H, true); self.reg.flag(N, true); 1 },
-            0x30 => { if !self.reg.getflag(C) { self.cpu_jr(); 3 } else { self.reg.pc += 1; 2 } },
-            0x31 => { self.reg.sp = self.fetchword(); 3 },
-            0x32 => { self.mmu.wb(self.reg.hld(), self.reg.a); 2 },
-            0x33 => { self.reg.sp = self.reg.sp.wrapping_add(1); 2 },
-            0x34 => { let a = self.reg.hl(); let v = self.mmu.rb(a); let v2 = self.alu_inc(v); self.mmu.wb(a, v2); 3 },
-            0x35 => { let a = self.reg.hl(); let v = self.mmu.rb(a); let v2 = self.alu_dec(v); self.mmu.wb(a, v2); 3 },
-            0x36 => { let v = self.fetchbyte(); self.mmu.wb(self.reg.hl(), v); 3 },
-            0x37 => { self.reg.flag(C, true); self.reg.flag(H, false); self.reg.flag(N, false); 1 },
-            0x38 => { if self.reg.getflag(C) { self.cpu_jr(); 3 } else { self.reg.pc += 1; 2  } },
-            0x39 => { self.alu_add16(self.reg.sp); 2 },
-            0x3A => { self.reg.a = self.mmu.rb(self.reg.hld()); 2 },
-            0x3B => { self.reg.sp = self.reg.sp.wrapping_sub(1); 2 },
-            0x3C => { self.reg.a = self.alu_inc(self.reg.a); 1 },
-            0x3D => { self.reg.a = self.alu_dec(self.reg.a); 1 },
-            0x3E => { self.reg.a = self.fetchbyte(); 2 },
-            0x3F => { let v = !self.reg.getflag(C); self.reg.flag(C, v); self.reg.flag(H, false); self.reg.flag(N, false); 1 },
-            0x40 => { 1 },
-            0x41 => { self.reg.b = self.reg.c; 1 },
-            0x42 => { self.reg.b = self.reg.d; 1 },
-            0x43 => { self.reg.b = self.reg.e; 1 },
-            0x44 => { self.reg.b = self.reg.h; 1 },
-            0x45 => { self.reg.b = self.reg.l; 1 },
-            0x46 => { self.reg.b = self.mmu.rb(self.reg.hl()); 2 },
-            0x47 => { self.reg.b = self.reg.a; 1 },
-            0x48 => { self.reg.c = self.reg.b; 1 },
-            0x49 => { 1 },
-            0x4A => { self.reg.c = self.reg.d; 1 },
-            0x4B => { self.reg.c = self.reg.e; 1 },
-            0x4C => { self.reg.c = self.reg.h; 1 },
-            0x4D => { self.reg.c = self.reg.l; 1 },
-            0x4E => { self.reg.c = self.mmu.rb(self.reg.hl()); 2 },
-            0x4F => { self.reg.c = self.reg.a; 1 },
-            0x50 => { self.reg.d = self.reg.b; 1 },
-            0x51 => { self.reg.d = self.reg.c; 1 },
-            0x52 => { 1 },
-            0x53 => { self.reg.d = self.reg.e; 1 },
-            0x54 => { self.reg.d = self.reg.h; 1 },
-            0x55 => { self.reg.d = self.reg.l; 1 },
-            0x56 => { self.reg.d = self.mmu.rb(self.reg.hl()); 2 },
-            0x57 => { self.reg.d = self.reg.a; 1 },
-            0x58 => { self.reg.e = self.reg.b; 1 },
-            0x59 => { self.reg.e = self.reg.c; 1 },
-            0x5A => { self.reg.e = self.reg.d; 1 },
-            0x5B => { 1 },
-            0x5C => { self.reg.e = self.reg.h; 1 },
-            0x5D => { self.reg.e = self.reg.l; 1 },
-            0x5E => { self.reg.e = self.mmu.rb(self.reg.hl()); 2 },
-            0x5F => { self.reg.e = self.reg.a; 1 },
-            0x60 => { self.reg.h = self.reg.b; 1 },
-            0x61 => { self.reg.h = self.reg.c; 1 },
-            0x62 => { self.reg.h = self.reg.d; 1 },
-            0x63 => { self.reg.h = self.reg.e; 1 },
-            0x64 => { 1 },
-            0x65 => { self.reg.h = self.reg.l; 1 },
-            0x66 => { self.reg.h = self.mmu.rb(self.reg.hl()); 2 },
-            0x67 => { self.reg.h = self.reg.a; 1 },
-            0x68 => { self.reg.l = self.reg.b; 1 },
-            0x69 => { self.reg.l = self.reg.c; 1 },
-            0x6A => { self.reg.l = self.reg.d; 1 },
-            0x6B => { self.reg.l = self.reg.e; 1 },
-            0x6C => { self.reg.l = self.reg.h; 1 },
-            0x6D => { 1 },
-            0x6E => { self.reg.l = self.mmu.rb(self.reg.hl()); 2 },
-            0x6F => { self.reg.l = self.reg.a; 1 },
-            0x70 => { self.mmu.wb(self.reg.hl(), self.reg.b); 2 },
-            0x71 => { self.mmu.wb(self.reg.hl(), self.reg.c); 2 },
-            0x72 => { self.mmu.wb(self.reg.hl(), self.reg.d); 2 },
-            0x73 => { self.mmu.wb(self.reg.hl(), self.reg.e); 2 },
-            0x74 => { self.mmu.wb(self.reg.hl(), self.reg.h); 2 },
-            0x75 => { self.mmu.wb(self.reg.hl(), self.reg.l); 2 },
-            0x76 => { self.halted = true; 1 },
-            0x77 => { self.mmu.wb(self.reg.hl(), self.reg.a); 2 },
-            0x78 => { self.reg.a = self.reg.b; 1 },
-            0x79 => { self.reg.a = self.reg.c; 1 },
-            0x7A => { self.reg.a = self.reg.d; 1 },
-            0x7B => { self.reg.a = self.reg.e; 1 },
-            0x7C => { self.reg.a = self.reg.h; 1 },
-            0x7D => { self.reg.a = self.reg.l; 1 },
-            0x7E => { self.reg.a = self.mmu.rb(self.reg.hl()); 2 },
-            0x7F => { 1 },
-            0x80 => { self.alu_add(self.reg.b, false); 1 },
-            0x81 => { self.alu_add(self.reg.c, false); 1 },
-            0x82 => { self.alu_add(self.reg.d, false); 1 },
-            0x83 => { self.alu_add(self.reg.e, false); 1 },
-            0x84 => { self.alu_add(self.reg.h, false); 1 },
-            0x85 => { self.alu_add(self.reg.l, false); 1 },
-            0x86 => { let v = self.mmu.rb(self.reg.hl()); self.alu_add(v, false); 2 },
-            0x87 => { self.alu_add(self.reg.a, false); 1 },
-            0x88 => { self.alu_add(self.reg.b, true); 1 },
-            0x89 => { self.alu_add(self.reg.c, true); 1 },
-            0x8A => { self.alu_add(self.reg.d, true); 1 },
-            0x8B => { self.alu_add(self.reg.e, true); 1 },
-            0x8C => { self.alu_add(self.reg.h, true); 1 },
-            0x8D => { self.alu_add(self.reg.l, true); 1 },
-            0x8E => { let v = self.mmu.rb(self.reg.hl()); self.alu_add(v, true); 2 },
-            0x8F => { self.alu_add(self.reg.a, true); 1 },
-            0x90 => { self.alu_sub(self.reg.b, false); 1 },
-            0x91 => { self.alu_sub(self.reg.c, false); 1 },
-            0x92 => { self.alu_sub(self.reg.d, false); 1 },
-            0x93 => { self.alu_sub(self.reg.e, false); 1 },
-            0x94 => { self.alu_sub(self.reg.h, false); 1 },
-            0x95 => { self.alu_sub(self.reg.l, false); 1 },
-            0x96 => { let v = self.mmu.rb(self.reg.hl()); self.alu_sub(v, false); 2 },
-            0x97 => { self.alu_sub(self.reg.a, false); 1 },
-            0x98 => { self.alu_sub(self.reg.b, true); 1 },
-            0x99 => { self.alu_sub(self.reg.c, true); 1 },
-            0x9A => { self.alu_sub(self.reg.d, true); 1 },
-            0x9B => { self.alu_sub(self.reg.e, true); 1 },
-            0x9C => { self.alu_sub(self.reg.h, true); 1 },
-            0x9D => { self.alu_sub(self.reg.l, true); 1 },
-            0x9E => { let v = self.mmu.rb(self.reg.hl()); self.alu_sub(v, true); 2 },
-            0x9F => { self.alu_sub(self.reg.a, true); 1 },
-            0xA0 => { self.alu_and(self.reg.b); 1 },
-            0xA1 => { self.alu_and(self.reg.c); 1 },
-            0xA2 => { self.alu_and(self.reg.d); 1 },
-            0xA3 => { self.alu_and(self.reg.e); 1 },
-            0xA4 => { self.alu_and(self.reg.h); 1 },
-            0xA5 => { self.alu_and(self.reg.l); 1 },
-            0xA6 => { let v = self.mmu.rb(self.reg.hl()); self.alu_and(v); 2 },
-            0xA7 => { self.alu_and(self.reg.a); 1 },
-            0xA8 => { self.alu_xor(self.reg.b); 1 },
-            0xA9 => { self.alu_xor(self.reg.c); 1 },
-            0xAA => { self.alu_xor(self.reg.d); 1 },
-            0xAB => { self.alu_xor(self.reg.e); 1 },
-            0xAC => { self.alu_xor(self.reg.h); 1 },
-            0xAD => { self.alu_xor(self.reg.l); 1 },
-            0xAE => { let v = self.mmu.rb(self.reg.hl()); self.alu_xor(v); 2 },
-            0xAF => { self.alu_xor(self.reg.a); 1 },
-            0xB0 => { self.alu_or(self.reg.b); 1 },
-            0xB1 => { self.alu_or(self.reg.c); 1 },
-            0xB2 => { self.alu_or(self.reg.d); 1 },
-            0xB3 => { self.alu_or(self.reg.e); 1 },
-            0xB4 => { self.alu_or(self.reg.h); 1 },
-            0xB5 => { self.alu_or(self.reg.l); 1 },
-            0xB6 => { let v = self.mmu.rb(self.reg.hl()); self.alu_or(v); 2 },
-            0xB7 => { self.alu_or(self.reg.a); 1 },
-            0xB8 => { self.alu_cp(self.reg.b); 1 },
-            0xB9 => { self.alu_cp(self.reg.c); 1 },
-            0xBA => { self.alu_cp(self.reg.d); 1 },
-            0xBB => { self.alu_cp(self.reg.e); 1 },
-            0xBC => { self.alu_cp(self.reg.h); 1 },
-            0xBD => { self.alu_cp(self.reg.l); 1 },
-            0xBE => { let v = self.mmu.rb(self.reg.hl()); self.alu_cp(v); 2 },
-            0xBF => { self.alu_cp(self.reg.a); 1 },
-            0xC0 => { if !self.reg.getflag(Z) { self.reg.pc = self.popstack(); 5 } else { 2 } },
-            0xC1 => { let v = self.popstack(); self.reg.setbc(v); 3 },
-            0xC2 => { if !self.reg.getflag(Z) { self.reg.pc = self.fetchword(); 4 } else { self.reg.pc += 2; 3 } },
-            0xC3 => { self.reg.pc = self.fetchword(); 4 },
-            0xC4 => { if !self.reg.getflag(Z) { self.pushstack(self.reg.pc + 2); self.reg.pc = self.fetchword(); 6 } else { self.reg.pc += 2; 3 } },
-            0xC5 => { self.pushstack(self.reg.bc()); 4 },
-            0xC6 => { let v = self.fetchbyte(); self.alu_add(v, false); 2 },
-            0xC7 => { self.pushstack(self.reg.pc); self.reg.pc = 0x00; 4 },
-            0xC8 => { if self.reg.getflag(Z) { self.reg.pc = self.popstack(); 5 } else { 2 } },
-            0xC9 => { self.reg.pc = self.popstack(); 4 },
-            0xCA => { if self.reg.getflag(Z) { self.reg.pc = self.fetchword(); 4 } else { self.reg.pc += 2; 3 } },
-            0xCB => { self.call_cb() },
-            0xCC => { if self.reg.getflag(Z) { self.pushstack(self.reg.pc + 2); self.reg.pc = self.fetchword(); 6 } else { self.reg.pc += 2; 3 } },
-            0xCD => { self.pushstack(self.reg.pc + 2); self.reg.pc = self.fetchword(); 6 },
-            0xCE => { let v = self.fetchbyte(); self.alu_add(v, true); 2 },
-            0xCF => { self.pushstack(self.reg.pc); self.reg.pc = 0x08; 4 },
-            0xD0 => { if !self.reg.getflag(C) { self.reg.pc = self.popstack(); 5 } else { 2 } },
-            0xD1 => { let v = self.popstack(); self.reg.setde(v); 3 },
-            0xD2 => { if !self.reg.getflag(C) { self.reg.pc = self.fetchword(); 4 } else { self.reg.pc += 2; 3 } },
-            0xD4 => { if !self.reg.getflag(C) { self.pushstack(self.reg.pc + 2); self.reg.pc = self.fetchword(); 6 } else { self.reg.pc += 2; 3 } },
-            0xD5 => { self.pushstack(self.reg.de()); 4 },
-            0xD6 => { let v = self.fetchbyte(); self.alu_sub(v, false); 2 },
-            0xD7 => { self.pushstack(self.reg.pc); self.reg.pc = 0x10; 4 },
-            0xD8 => { if self.reg.getflag(C) { self.reg.pc = self.popstack(); 5 } else { 2 } },
-            0xD9 => { self.reg.pc = self.popstack(); self.setei = 1; 4 },
-            0xDA => { if self.reg.getflag(C) { self.reg.pc = self.fetchword(); 4 } else { self.reg.pc += 2; 3 } },
-            0xDC => { if self.reg.getflag(C) { self.pushstack(self.reg.pc + 2); self.reg.pc = self.fetchword(); 6 } else { self.reg.pc += 2; 3 } },
-            0xDE => { let v = self.fetchbyte(); self.alu_sub(v, true); 2 },
-            0xDF => { self.pushstack(self.reg.pc); self.reg.pc = 0x18; 4 },
-            0xE0 => { let a = 0xFF00 | self.fetchbyte() as u16; self.mmu.wb(a, self.reg.a); 3 },
-            0xE1 => { let v = self.popstack(); self.reg.sethl(v); 3 },
-            0xE2 => { self.mmu.wb(0xFF00 | self.reg.c as u16, self.reg.a); 2 },
-            0xE5 => { self.pushstack(self.reg.hl()); 4 },
-            0xE6 => { let v = self.fetchbyte(); self.alu_and(v); 2 },
-            0xE7 => { self.pushstack(self.reg.pc); self.reg.pc = 0x20; 4 },
-            0xE8 => { self.reg.sp = self.alu_add16imm(self.reg.sp); 4 },
-            0xE9 => { self.reg.pc = self.reg.hl(); 1 },
-            0xEA => { let a = self.fetchword(); self.mmu.wb(a, self.reg.a); 4 },
-            0xEE => { let v = self.fetchbyte(); self.alu_xor(v); 2 },
-            0xEF => { self.pushstack(self.reg.pc); self.reg.pc = 0x28; 4 },
-            0xF0 => { let a = 0xFF00 | self.fetchbyte() as u16; self.reg.a = self.mmu.rb(a); 3 },
-            0xF1 => { let v = self.popstack() & 0xFFF0; self.reg.setaf(v); 3 },
-            0xF2 => { self.reg.a = self.mmu.rb(0xFF00 | self.reg.c as u16); 2 },
-            0xF3 => { self.setdi = 2; 1 },
-            0xF5 => { self.pushstack(self.reg.af()); 4 },
-            0xF6 => { let v = self.fetchbyte(); self.alu_or(v); 2 },
-            0xF7 => { self.pushstack(self.reg.pc); self.reg.pc = 0x30; 4 },
-            0xF8 => { let r = self.alu_add16imm(self.reg.sp); self.reg.sethl(r); 3 },
-            0xF9 => { self.reg.sp = self.reg.hl(); 2 },
-            0xFA => { let a = self.fetchword(); self.reg.a = self.mmu.rb(a); 4 },
-            0xFB => { self.setei = 2; 1 },
-            0xFE => { let v = self.fetchbyte(); self.alu_cp(v); 2 },
-            0xFF => { self.pushstack(self.reg.pc); self.reg.pc = 0x38; 4 },
-            other=> panic!("Instruction {:2X} is not implemented", other),
+        let cycles = (OPCODES[opcode as usize].execute)(self);
+        if let (Some((bank, pc)), Some(profiler)) = (profile_at, self.profiler.as_mut()) {
+            profiler.record(bank, pc, cycles);
         }
+        if enable_ime && self.ei_pending {
+            self.ime = true;
+            self.ei_pending = false;
+        }
+        cycles
     }
 
     fn call_cb(&mut self) -> u32 {
         let opcode = self.fetchbyte();
-        match opcode {
-            0x00 => { self.reg.b = self.alu_rlc(self.reg.b); 2 },
-            0x01 => { self.reg.c = self.alu_rlc(self.reg.c); 2 },
-            0x02 => { self.reg.d = self.alu_rlc(self.reg.d); 2 },
-            0x03 => { self.reg.e = self.alu_rlc(self.reg.e); 2 },
-            0x04 => { self.reg.h = self.alu_rlc(self.reg.h); 2 },
-            0x05 => { self.reg.l = self.alu_rlc(self.reg.l); 2 },
-            0x06 => { let a = self.reg.hl(); let v = self.mmu.rb(a); let v2 = self.alu_rlc(v); self.mmu.wb(a, v2); 4 },
-            0x07 => { self.reg.a = self.alu_rlc(self.reg.a); 2 },
-            0x08 => { self.reg.b = self.alu_rrc(self.reg.b); 2 },
-            0x09 => { self.reg.c = self.alu_rrc(self.reg.c); 2 },
-            0x0A => { self.reg.d = self.alu_rrc(self.reg.d); 2 },
-            0x0B => { self.reg.e = self.alu_rrc(self.reg.e); 2 },
-            0x0C => { self.reg.h = self.alu_rrc(self.reg.h); 2 },
-            0x0D => { self.reg.l = self.alu_rrc(self.reg.l); 2 },
-            0x0E => { let a = self.reg.hl(); let v = self.mmu.rb(a); let v2 = self.alu_rrc(v); self.mmu.wb(a, v2); 4 },
-            0x0F => { self.reg.a = self.alu_rrc(self.reg.a); 2 },
-            0x10 => { self.reg.b = self.alu_rl(self.reg.b); 2 },
-            0x11 => { self.reg.c = self.alu_rl(self.reg.c); 2 },
-            0x12 => { self.reg.d = self.alu_rl(self.reg.d); 2 },
-            0x13 => { self.reg.e = self.alu_rl(self.reg.e); 2 },
-            0x14 => { self.reg.h = self.alu_rl(self.reg.h); 2 },
-            0x15 => { self.reg.l = self.alu_rl(self.reg.l); 2 },
-            0x16 => { let a = self.reg.hl(); let v = self.mmu.rb(a); let v2 = self.alu_rl(v); self.mmu.wb(a, v2); 4 },
-            0x17 => { self.reg.a = self.alu_rl(self.reg.a); 2 },
-            0x18 => { self.reg.b = self.alu_rr(self.reg.b); 2 },
-            0x19 => { self.reg.c = self.alu_rr(self.reg.c); 2 },
-            0x1A => { self.reg.d = self.alu_rr(self.reg.d); 2 },
-            0x1B => { self.reg.e = self.alu_rr(self.reg.e); 2 },
-            0x1C => { self.reg.h = self.alu_rr(self.reg.h); 2 },
-            0x1D => { self.reg.l = self.alu_rr(self.reg.l); 2 },
-            0x1E => { let a = self.reg.hl(); let v = self.mmu.rb(a); let v2 = self.alu_rr(v); self.mmu.wb(a, v2); 4 },
-            0x1F => { self.reg.a = self.alu_rr(self.reg.a); 2 },
-            0x20 => { self.reg.b = self.alu_sla(self.reg.b); 2 },
-            0x21 => { self.reg.c = self.alu_sla(self.reg.c); 2 },
-            0x22 => { self.reg.d = self.alu_sla(self.reg.d); 2 },
-            0x23 => { self.reg.e = self.alu_sla(self.reg.e); 2 },
-            0x24 => { self.reg.h = self.alu_sla(self.reg.h); 2 },
-            0x25 => { self.reg.l = self.alu_sla(self.reg.l); 2 },
-            0x26 => { let a = self.reg.hl(); let v = self.mmu.rb(a); let v2 = self.alu_sla(v); self.mmu.wb(a, v2); 4 },
-            0x27 => { self.reg.a = self.alu_sla(self.reg.a); 2 },
-            0x28 => { self.reg.b = self.alu_sra(self.reg.b); 2 },
-            0x29 => { self.reg.c = self.alu_sra(self.reg.c); 2 },
-            0x2A => { self.reg.d = self.alu_sra(self.reg.d); 2 },
-            0x2B => { self.reg.e = self.alu_sra(self.reg.e); 2 },
-            0x2C => { self.reg.h = self.alu_sra(self.reg.h); 2 },
-            0x2D => { self.reg.l = self.alu_sra(self.reg.l); 2 },
-            0x2E => { let a = self.reg.hl(); let v = self.mmu.rb(a); let v2 = self.alu_sra(v); self.mmu.wb(a, v2); 4 },
-            0x2F => { self.reg.a = self.alu_sra(self.reg.a); 2 },
-            0x30 => { self.reg.b = self.alu_swap(self.reg.b); 2 },
-            0x31 => { self.reg.c = self.alu_swap(self.reg.c); 2 },
-            0x32 => { self.reg.d = self.alu_swap(self.reg.d); 2 },
-            0x33 => { self.reg.e = self.alu_swap(self.reg.e); 2 },
-            0x34 => { self.reg.h = self.alu_swap(self.reg.h); 2 },
-            0x35 => { self.reg.l = self.alu_swap(self.reg.l); 2 },
-            0x36 => { let a = self.reg.hl(); let v = self.mmu.rb(a); let v2 = self.alu_swap(v); self.mmu.wb(a, v2); 4 },
-            0x37 => { self.reg.a = self.alu_swap(self.reg.a); 2 },
-            0x38 => { self.reg.b = self.alu_srl(self.reg.b); 2 },
-            0x39 => { self.reg.c = self.alu_srl(self.reg.c); 2 },
-            0x3A => { self.reg.d = self.alu_srl(self.reg.d); 2 },
-            0x3B => { self.reg.e = self.alu_srl(self.reg.e); 2 },
-            0x3C => { self.reg.h = self.alu_srl(self.reg.h); 2 },
-            0x3D => { self.reg.l = self.alu_srl(self.reg.l); 2 },
-            0x3E => { let a = self.reg.hl(); let v = self.mmu.rb(a); let v2 = self.alu_srl(v); self.mmu.wb(a, v2); 4 },
-            0x3F => { self.reg.a = self.alu_srl(self.reg.a); 2 },
-            0x40 => { self.alu_bit(self.reg.b, 0); 2 },
-            0x41 => { self.alu_bit(self.reg.c, 0); 2 },
-            0x42 => { self.alu_bit(self.reg.d, 0); 2 },
-            0x43 => { self.alu_bit(self.reg.e, 0); 2 },
-            0x44 => { self.alu_bit(self.reg.h, 0); 2 },
-            0x45 => { self.alu_bit(self.reg.l, 0); 2 },
-            0x46 => { let v = self.mmu.rb(self.reg.hl()); self.alu_bit(v, 0); 3 },
-            0x47 => { self.alu_bit(self.reg.a, 0); 2 },
-            0x48 => { self.alu_bit(self.reg.b, 1); 2 },
-            0x49 => { self.alu_bit(self.reg.c, 1); 2 },
-            0x4A => { self.alu_bit(self.reg.d, 1); 2 },
-            0x4B => { self.alu_bit(self.reg.e, 1); 2 },
-            0x4C => { self.alu_bit(self.reg.h, 1); 2 },
-            0x4D => { self.alu_bit(self.reg.l, 1); 2 },
-            0x4E => { let v = self.mmu.rb(self.reg.hl()); self.alu_bit(v, 1); 3 },
-            0x4F => { self.alu_bit(self.reg.a, 1); 2 },
-            0x50 => { self.alu_bit(self.reg.b, 2); 2 },
-            0x51 => { self.alu_bit(self.reg.c, 2); 2 },
-            0x52 => { self.alu_bit(self.reg.d, 2); 2 },
-            0x53 => { self.alu_bit(self.reg.e, 2); 2 },
-            0x54 => { self.alu_bit(self.reg.h, 2); 2 },
-            0x55 => { self.alu_bit(self.reg.l, 2); 2 },
-            0x56 => { let v = self.mmu.rb(self.reg.hl()); self.alu_bit(v, 2); 3 },
-            0x57 => { self.alu_bit(self.reg.a, 2); 2 },
-            0x58 => { self.alu_bit(self.reg.b, 3); 2 },
-            0x59 => { self.alu_bit(self.reg.c, 3); 2 },
-            0x5A => { self.alu_bit(self.reg.d, 3); 2 },
-            0x5B => { self.alu_bit(self.reg.e, 3); 2 },
-            0x5C => { self.alu_bit(self.reg.h, 3); 2 },
-            0x5D => { self.alu_bit(self.reg.l, 3); 2 },
-            0x5E => { let v = self.mmu.rb(self.reg.hl()); self.alu_bit(v, 3); 3 },
-            0x5F => { self.alu_bit(self.reg.a, 3); 2 },
-            0x60 => { self.alu_bit(self.reg.b, 4); 2 },
-            0x61 => { self.alu_bit(self.reg.c, 4); 2 },
-            0x62 => { self.alu_bit(self.reg.d, 4); 2 },
-            0x63 => { self.alu_bit(self.reg.e, 4); 2 },
-            0x64 => { self.alu_bit(self.reg.h, 4); 2 },
-            0x65 => { self.alu_bit(self.reg.l, 4); 2 },
-            0x66 => { let v = self.mmu.rb(self.reg.hl()); self.alu_bit(v, 4); 3 },
-            0x67 => { self.alu_bit(self.reg.a, 4); 2 },
-            0x68 => { self.alu_bit(self.reg.b, 5); 2 },
-            0x69 => { self.alu_bit(self.reg.c, 5); 2 },
-            0x6A => { self.alu_bit(self.reg.d, 5); 2 },
-            0x6B => { self.alu_bit(self.reg.e, 5); 2 },
-            0x6C => { self.alu_bit(self.reg.h, 5); 2 },
-            0x6D => { self.alu_bit(self.reg.l, 5); 2 },
-            0x6E => { let v = self.mmu.rb(self.reg.hl()); self.alu_bit(v, 5); 3 },
-            0x6F => { self.alu_bit(self.reg.a, 5); 2 },
-            0x70 => { self.alu_bit(self.reg.b, 6); 2 },
-            0x71 => { self.alu_bit(self.reg.c, 6); 2 },
-            0x72 => { self.alu_bit(self.reg.d, 6); 2 },
-            0x73 => { self.alu_bit(self.reg.e, 6); 2 },
-            0x74 => { self.alu_bit(self.reg.h, 6); 2 },
-            0x75 => { self.alu_bit(self.reg.l, 6); 2 },
-            0x76 => { let v = self.mmu.rb(self.reg.hl()); self.alu_bit(v, 6); 3 },
-            0x77 => { self.alu_bit(self.reg.a, 6); 2 },
-            0x78 => { self.alu_bit(self.reg.b, 7); 2 },
-            0x79 => { self.alu_bit(self.reg.c, 7); 2 },
-            0x7A => { self.alu_bit(self.reg.d, 7); 2 },
-            0x7B => { self.alu_bit(self.reg.e, 7); 2 },
-            0x7C => { self.alu_bit(self.reg.h, 7); 2 },
-            0x7D => { self.alu_bit(self.reg.l, 7); 2 },
-            0x7E => { let v = self.mmu.rb(self.reg.hl()); self.alu_bit(v, 7); 3 },
-            0x7F => { self.alu_bit(self.reg.a, 7); 2 },
-            0x80 => { self.reg.b = self.reg.b & !(1 << 0); 2 },
-            0x81 => { self.reg.c = self.reg.c & !(1 << 0); 2 },
-            0x82 => { self.reg.d = self.reg.d & !(1 << 0); 2 },
-            0x83 => { self.reg.e = self.reg.e & !(1 << 0); 2 },
-            0x84 => { self.reg.h = self.reg.h & !(1 << 0); 2 },
-            0x85 => { self.reg.l = self.reg.l & !(1 << 0); 2 },
-            0x86 => { let a = self.reg.hl(); let v = self.mmu.rb(a) & !(1 << 0); self.mmu.wb(a, v); 4 },
-            0x87 => { self.reg.a = self.reg.a & !(1 << 0); 2 },
-            0x88 => { self.reg.b = self.reg.b & !(1 << 1); 2 },
-            0x89 => { self.reg.c = self.reg.c & !(1 << 1); 2 },
-            0x8A => { self.reg.d = self.reg.d & !(1 << 1); 2 },
-            0x8B => { self.reg.e = self.reg.e & !(1 << 1); 2 },
-            0x8C => { self.reg.h = self.reg.h & !(1 << 1); 2 },
-            0x8D => { self.reg.l = self.reg.l & !(1 << 1); 2 },
-            0x8E => { let a = self.reg.hl(); let v = self.mmu.rb(a) & !(1 << 1); self.mmu.wb(a, v); 4 },
-            0x8F => { self.reg.a = self.reg.a & !(1 << 1); 2 },
-            0x90 => { self.reg.b = self.reg.b & !(1 << 2); 2 },
-            0x91 => { self.reg.c = self.reg.c & !(1 << 2); 2 },
-            0x92 => { self.reg.d = self.reg.d & !(1 << 2); 2 },
-            0x93 => { self.reg.e = self.reg.e & !(1 << 2); 2 },
-            0x94 => { self.reg.h = self.reg.h & !(1 << 2); 2 },
-            0x95 => { self.reg.l = self.reg.l & !(1 << 2); 2 },
-            0x96 => { let a = self.reg.hl(); let v = self.mmu.rb(a) & !(1 << 2); self.mmu.wb(a, v); 4 },
-            0x97 => { self.reg.a = self.reg.a & !(1 << 2); 2 },
-            0x98 => { self.reg.b = self.reg.b & !(1 << 3); 2 },
-            0x99 => { self.reg.c = self.reg.c & !(1 << 3); 2 },
-            0x9A => { self.reg.d = self.reg.d & !(1 << 3); 2 },
-            0x9B => { self.reg.e = self.reg.e & !(1 << 3); 2 },
-            0x9C => { self.reg.h = self.reg.h & !(1 << 3); 2 },
-            0x9D => { self.reg.l = self.reg.l & !(1 << 3); 2 },
-            0x9E => { let a = self.reg.hl(); let v = self.mmu.rb(a) & !(1 << 3); self.mmu.wb(a, v); 4 },
-            0x9F => { self.reg.a = self.reg.a & !(1 << 3); 2 },
-            0xA0 => { self.reg.b = self.reg.b & !(1 << 4); 2 },
-            0xA1 => { self.reg.c = self.reg.c & !(1 << 4); 2 },
-            0xA2 => { self.reg.d = self.reg.d & !(1 << 4); 2 },
-            0xA3 => { self.reg.e = self.reg.e & !(1 << 4); 2 },
-            0xA4 => { self.reg.h = self.reg.h & !(1 << 4); 2 },
-            0xA5 => { self.reg.l = self.reg.l & !(1 << 4); 2 },
-            0xA6 => { let a = self.reg.hl(); let v = self.mmu.rb(a) & !(1 << 4); self.mmu.wb(a, v); 4 },
-            0xA7 => { self.reg.a = self.reg.a & !(1 << 4); 2 },
-            0xA8 => { self.reg.b = self.reg.b & !(1 << 5); 2 },
-            0xA9 => { self.reg.c = self.reg.c & !(1 << 5); 2 },
-            0xAA => { self.reg.d = self.reg.d & !(1 << 5); 2 },
-            0xAB => { self.reg.e = self.reg.e & !(1 << 5); 2 },
-            0xAC => { self.reg.h = self.reg.h & !(1 << 5); 2 },
-            0xAD => { self.reg.l = self.reg.l & !(1 << 5); 2 },
-            0xAE => { let a = self.reg.hl(); let v = self.mmu.rb(a) & !(1 << 5); self.mmu.wb(a, v); 4 },
-            0xAF => { self.reg.a = self.reg.a & !(1 << 5); 2 },
-            0xB0 => { self.reg.b = self.reg.b & !(1 << 6); 2 },
-            0xB1 => { self.reg.c = self.reg.c & !(1 << 6); 2 },
-            0xB2 => { self.reg.d = self.reg.d & !(1 << 6); 2 },
-            0xB3 => { self.reg.e = self.reg.e & !(1 << 6); 2 },
-            0xB4 => { self.reg.h = self.reg.h & !(1 << 6); 2 },
-            0xB5 => { self.reg.l = self.reg.l & !(1 << 6); 2 },
-            0xB6 => { let a = self.reg.hl(); let v = self.mmu.rb(a) & !(1 << 6); self.mmu.wb(a, v); 4 },
-            0xB7 => { self.reg.a = self.reg.a & !(1 << 6); 2 },
-            0xB8 => { self.reg.b = self.reg.b & !(1 << 7); 2 },
-            0xB9 => { self.reg.c = self.reg.c & !(1 << 7); 2 },
-            0xBA => { self.reg.d = self.reg.d & !(1 << 7); 2 },
-            0xBB => { self.reg.e = self.reg.e & !(1 << 7); 2 },
-            0xBC => { self.reg.h = self.reg.h & !(1 << 7); 2 },
-            0xBD => { self.reg.l = self.reg.l & !(1 << 7); 2 },
-            0xBE => { let a = self.reg.hl(); let v = self.mmu.rb(a) & !(1 << 7); self.mmu.wb(a, v); 4 },
-            0xBF => { self.reg.a = self.reg.a & !(1 << 7); 2 },
-            0xC0 => { self.reg.b = self.reg.b | (1 << 0); 2 },
-            0xC1 => { self.reg.c = self.reg.c | (1 << 0); 2 },
-            0xC2 => { self.reg.d = self.reg.d | (1 << 0); 2 },
-            0xC3 => { self.reg.e = self.reg.e | (1 << 0); 2 },
-            0xC4 => { self.reg.h = self.reg.h | (1 << 0); 2 },
-            0xC5 => { self.reg.l = self.reg.l | (1 << 0); 2 },
-            0xC6 => { let a = self.reg.hl(); let v = self.mmu.rb(a) | (1 << 0); self.mmu.wb(a, v); 4 },
-            0xC7 => { self.reg.a = self.reg.a | (1 << 0); 2 },
-            0xC8 => { self.reg.b = self.reg.b | (1 << 1); 2 },
-            0xC9 => { self.reg.c = self.reg.c | (1 << 1); 2 },
-            0xCA => { self.reg.d = self.reg.d | (1 << 1); 2 },
-            0xCB => { self.reg.e = self.reg.e | (1 << 1); 2 },
-            0xCC => { self.reg.h = self.reg.h | (1 << 1); 2 },
-            0xCD => { self.reg.l = self.reg.l | (1 << 1); 2 },
-            0xCE => { let a = self.reg.hl(); let v = self.mmu.rb(a) | (1 << 1); self.mmu.wb(a, v); 4 },
-            0xCF => { self.reg.a = self.reg.a | (1 << 1); 2 },
-            0xD0 => { self.reg.b = self.reg.b | (1 << 2); 2 },
-            0xD1 => { self.reg.c = self.reg.c | (1 << 2); 2 },
-            0xD2 => { self.reg.d = self.reg.d | (1 << 2); 2 },
-            0xD3 => { self.reg.e = self.reg.e | (1 << 2); 2 },
-            0xD4 => { self.reg.h = self.reg.h | (1 << 2); 2 },
-            0xD5 => { self.reg.l = self.reg.l | (1 << 2); 2 },
-            0xD6 => { let a = self.reg.hl(); let v = self.mmu.rb(a) | (1 << 2); self.mmu.wb(a, v); 4 },
-            0xD7 => { self.reg.a = self.reg.a | (1 << 2); 2 },
-            0xD8 => { self.reg.b = self.reg.b | (1 << 3); 2 },
-            0xD9 => { self.reg.c = self.reg.c | (1 << 3); 2 },
-            0xDA => { self.reg.d = self.reg.d | (1 << 3); 2 },
-            0xDB => { self.reg.e = self.reg.e | (1 << 3); 2 },
-            0xDC => { self.reg.h = self.reg.h | (1 << 3); 2 },
-            0xDD => { self.reg.l = self.reg.l | (1 << 3); 2 },
-            0xDE => { let a = self.reg.hl(); let v = self.mmu.rb(a) | (1 << 3); self.mmu.wb(a, v); 4 },
-            0xDF => { self.reg.a = self.reg.a | (1 << 3); 2 },
-            0xE0 => { self.reg.b = self.reg.b | (1 << 4); 2 },
-            0xE1 => { self.reg.c = self.reg.c | (1 << 4); 2 },
-            0xE2 => { self.reg.d = self.reg.d | (1 << 4); 2 },
-            0xE3 => { self.reg.e = self.reg.e | (1 << 4); 2 },
-            0xE4 => { self.reg.h = self.reg.h | (1 << 4); 2 },
-            0xE5 => { self.reg.l = self.reg.l | (1 << 4); 2 },
-            0xE6 => { let a = self.reg.hl(); let v = self.mmu.rb(a) | (1 << 4); self.mmu.wb(a, v); 4 },
-            0xE7 => { self.reg.a = self.reg.a | (1 << 4); 2 },
-            0xE8 => { self.reg.b = self.reg.b | (1 << 5); 2 },
-            0xE9 => { self.reg.c = self.reg.c | (1 << 5); 2 },
-            0xEA => { self.reg.d = self.reg.d | (1 << 5); 2 },
-            0xEB => { self.reg.e = self.reg.e | (1 << 5); 2 },
-            0xEC => { self.reg.h = self.reg.h | (1 << 5); 2 },
-            0xED => { self.reg.l = self.reg.l | (1 << 5); 2 },
-            0xEE => { let a = self.reg.hl(); let v = self.mmu.rb(a) | (1 << 5); self.mmu.wb(a, v); 4 },
-            0xEF => { self.reg.a = self.reg.a | (1 << 5); 2 },
-            0xF0 => { self.reg.b = self.reg.b | (1 << 6); 2 },
-            0xF1 => { self.reg.c = self.reg.c | (1 << 6); 2 },
-            0xF2 => { self.reg.d = self.reg.d | (1 << 6); 2 },
-            0xF3 => { self.reg.e = self.reg.e | (1 << 6); 2 },
-            0xF4 => { self.reg.h = self.reg.h | (1 << 6); 2 },
-            0xF5 => { self.reg.l = self.reg.l | (1 << 6); 2 },
-            0xF6 => { let a = self.reg.hl(); let v = self.mmu.rb(a) | (1 << 6); self.mmu.wb(a, v); 4 },
-            0xF7 => { self.reg.a = self.reg.a | (1 << 6); 2 },
-            0xF8 => { self.reg.b = self.reg.b | (1 << 7); 2 },
-            0xF9 => { self.reg.c = self.reg.c | (1 << 7); 2 },
-            0xFA => { self.reg.d = self.reg.d | (1 << 7); 2 },
-            0xFB => { self.reg.e = self.reg.e | (1 << 7); 2 },
-            0xFC => { self.reg.h = self.reg.h | (1 << 7); 2 },
-            0xFD => { self.reg.l = self.reg.l | (1 << 7); 2 },
-            0xFE => { let a = self.reg.hl(); let v = self.mmu.rb(a) | (1 << 7); self.mmu.wb(a, v); 4 },
-            0xFF => { self.reg.a = self.reg.a | (1 << 7); 2 },
-        }
+        (CB_OPCODES[opcode as usize].execute)(self)
     }
 
     fn alu_add(&mut self, b: u8, usec: bool) {
@@ -809,16 +817,627 @@ impl<'a> CPU<'a> {
         self.reg.a = a;
     }
 
+    fn cpu_call(&mut self) {
+        let a = self.fetchword();
+        self.pushstack(self.reg.pc);
+        self.reg.pc = a;
+    }
+
     fn cpu_jr(&mut self) {
         let n = self.fetchbyte() as i8;
-        self.reg.pc = ((self.reg.pc as u32 as i32) + (n as i32)) as u16;
+        self.reg.pc = self.reg.pc.wrapping_add(n as u16);
+    }
+
+    // The conditional instructions return whether they were taken
+    fn jr_if(&mut self, taken: bool) -> bool {
+        if taken { self.cpu_jr(); } else { self.reg.pc = self.reg.pc.wrapping_add(1); }
+        taken
     }
+
+    fn jp_if(&mut self, taken: bool) -> bool {
+        if taken { self.reg.pc = self.fetchword(); } else { self.reg.pc = self.reg.pc.wrapping_add(2); }
+        taken
+    }
+
+    fn call_if(&mut self, taken: bool) -> bool {
+        if taken { self.cpu_call(); } else { self.reg.pc = self.reg.pc.wrapping_add(2); }
+        taken
+    }
+
+    fn ret_if(&mut self, taken: bool) -> bool {
+        self.idle();
+        if taken { self.reg.pc = self.popstack(); }
+        taken
+    }
+
+    // The illegal opcodes hang the CPU for good
+    fn lock(&mut self) {
+        self.locked = Some(self.reg.pc.wrapping_sub(1));
+    }
+}
+
+// An entry of the opcode tables. The mnemonic has the operands as {d8}, {d16}, {r8} (signed),
+// {+r8} (signed with a sign), {jr} (the jump target) and {op} (the opcode itself).
+pub(crate) struct Opcode {
+    pub mnemonic: &'static str,
+    pub len: u8,
+    // In M-cycles, when a condition does not hold and when it does
+    pub cycles: u32,
+    pub cycles_taken: u32,
+    execute: fn(&mut CPU) -> u32,
+}
+
+macro_rules! opcode_cycles {
+    (cb) => { (0, 0) };
+    ($cycles:literal) => { ($cycles, $cycles) };
+    ($cycles:literal / $taken:literal) => { ($cycles, $taken) };
 }
 
+// The body of a conditional instruction returns whether it was taken, and the CB prefix returns
+// the cycles of the instruction after it
+macro_rules! opcode_body {
+    ($body:block cb) => { $body };
+    ($body:block $cycles:literal) => {{ $body; $cycles }};
+    ($body:block $cycles:literal / $taken:literal) => { if $body { $taken } else { $cycles } };
+}
+
+// Builds a table of all 256 opcodes, each with its own function. Leaving out or repeating an
+// opcode fails the build.
+macro_rules! opcode_table {
+    ($($op:literal => ($mnemonic:literal, $len:literal, $($cycles:tt)+) |$c:ident| $body:block,)*) => {{
+        const MISSING: Opcode = Opcode {
+            mnemonic: "",
+            len: 0,
+            cycles: 0,
+            cycles_taken: 0,
+            execute: { fn missing(_: &mut CPU) -> u32 { unreachable!() } missing },
+        };
+        let mut table = [MISSING; 256];
+        $(
+            assert!(table[$op].len == 0, "An opcode is listed twice");
+            let (cycles, cycles_taken) = opcode_cycles!($($cycles)+);
+            table[$op] = Opcode {
+                mnemonic: $mnemonic,
+                len: $len,
+                cycles,
+                cycles_taken,
+                execute: { fn execute($c: &mut CPU) -> u32 { opcode_body!($body $($cycles)+) } execute },
+            };
+        )*
+        let mut op = 0;
+        while op < 256 {
+            assert!(table[op].len != 0, "An opcode is missing");
+            op += 1;
+        }
+        table
+    }};
+}
+
+pub(crate) static OPCODES: [Opcode; 256] = opcode_table! {
+    0x00 => ("NOP",                   1, 1) |_c| {},
+    0x01 => ("LD BC,{d16}",           3, 3) |c| { let v = c.fetchword(); c.reg.setbc(v); },
+    0x02 => ("LD (BC),A",             1, 2) |c| { c.wb(c.reg.bc(), c.reg.a); },
+    0x03 => ("INC BC",                1, 2) |c| { c.reg.setbc(c.reg.bc().wrapping_add(1)); },
+    0x04 => ("INC B",                 1, 1) |c| { c.reg.b = c.alu_inc(c.reg.b); },
+    0x05 => ("DEC B",                 1, 1) |c| { c.reg.b = c.alu_dec(c.reg.b); },
+    0x06 => ("LD B,{d8}",             2, 2) |c| { c.reg.b = c.fetchbyte(); },
+    0x07 => ("RLCA",                  1, 1) |c| { c.reg.a = c.alu_rlc(c.reg.a); c.reg.flag(Z, false); },
+    0x08 => ("LD ({d16}),SP",         3, 5) |c| { let a = c.fetchword(); c.ww(a, c.reg.sp); },
+    0x09 => ("ADD HL,BC",             1, 2) |c| { c.alu_add16(c.reg.bc()); },
+    0x0A => ("LD A,(BC)",             1, 2) |c| { c.reg.a = c.rb(c.reg.bc()); },
+    0x0B => ("DEC BC",                1, 2) |c| { c.reg.setbc(c.reg.bc().wrapping_sub(1)); },
+    0x0C => ("INC C",                 1, 1) |c| { c.reg.c = c.alu_inc(c.reg.c); },
+    0x0D => ("DEC C",                 1, 1) |c| { c.reg.c = c.alu_dec(c.reg.c); },
+    0x0E => ("LD C,{d8}",             2, 2) |c| { c.reg.c = c.fetchbyte(); },
+    0x0F => ("RRCA",                  1, 1) |c| { c.reg.a = c.alu_rrc(c.reg.a); c.reg.flag(Z, false); },
+    0x10 => ("STOP",                  2, 1) |c| { c.stop(); },
+    0x11 => ("LD DE,{d16}",           3, 3) |c| { let v = c.fetchword(); c.reg.setde(v); },
+    0x12 => ("LD (DE),A",             1, 2) |c| { c.wb(c.reg.de(), c.reg.a); },
+    0x13 => ("INC DE",                1, 2) |c| { c.reg.setde(c.reg.de().wrapping_add(1)); },
+    0x14 => ("INC D",                 1, 1) |c| { c.reg.d = c.alu_inc(c.reg.d); },
+    0x15 => ("DEC D",                 1, 1) |c| { c.reg.d = c.alu_dec(c.reg.d); },
+    0x16 => ("LD D,{d8}",             2, 2) |c| { c.reg.d = c.fetchbyte(); },
+    0x17 => ("RLA",                   1, 1) |c| { c.reg.a = c.alu_rl(c.reg.a); c.reg.flag(Z, false); },
+    0x18 => ("JR {jr}",               2, 3) |c| { c.cpu_jr(); },
+    0x19 => ("ADD HL,DE",             1, 2) |c| { c.alu_add16(c.reg.de()); },
+    0x1A => ("LD A,(DE)",             1, 2) |c| { c.reg.a = c.rb(c.reg.de()); },
+    0x1B => ("DEC DE",                1, 2) |c| { c.reg.setde(c.reg.de().wrapping_sub(1)); },
+    0x1C => ("INC E",                 1, 1) |c| { c.reg.e = c.alu_inc(c.reg.e); },
+    0x1D => ("DEC E",                 1, 1) |c| { c.reg.e = c.alu_dec(c.reg.e); },
+    0x1E => ("LD E,{d8}",             2, 2) |c| { c.reg.e = c.fetchbyte(); },
+    0x1F => ("RRA",                   1, 1) |c| { c.reg.a = c.alu_rr(c.reg.a); c.reg.flag(Z, false); },
+    0x20 => ("JR NZ,{jr}",            2, 2 / 3) |c| { c.jr_if(!c.reg.getflag(Z)) },
+    0x21 => ("LD HL,{d16}",           3, 3) |c| { let v = c.fetchword(); c.reg.sethl(v); },
+    0x22 => ("LD (HL+),A",            1, 2) |c| { let a = c.reg.hli(); c.wb(a, c.reg.a); },
+    0x23 => ("INC HL",                1, 2) |c| { let v = c.reg.hl().wrapping_add(1); c.reg.sethl(v); },
+    0x24 => ("INC H",                 1, 1) |c| { c.reg.h = c.alu_inc(c.reg.h); },
+    0x25 => ("DEC H",                 1, 1) |c| { c.reg.h = c.alu_dec(c.reg.h); },
+    0x26 => ("LD H,{d8}",             2, 2) |c| { c.reg.h = c.fetchbyte(); },
+    0x27 => ("DAA",                   1, 1) |c| { c.alu_daa(); },
+    0x28 => ("JR Z,{jr}",             2, 2 / 3) |c| { c.jr_if(c.reg.getflag(Z)) },
+    0x29 => ("ADD HL,HL",             1, 2) |c| { let v = c.reg.hl(); c.alu_add16(v); },
+    0x2A => ("LD A,(HL+)",            1, 2) |c| { let a = c.reg.hli(); c.reg.a = c.rb(a); },
+    0x2B => ("DEC HL",                1, 2) |c| { let v = c.reg.hl().wrapping_sub(1); c.reg.sethl(v); },
+    0x2C => ("INC L",                 1, 1) |c| { c.reg.l = c.alu_inc(c.reg.l); },
+    0x2D => ("DEC L",                 1, 1) |c| { c.reg.l = c.alu_dec(c.reg.l); },
+    0x2E => ("LD L,{d8}",             2, 2) |c| { c.reg.l = c.fetchbyte(); },
+    0x2F => ("CPL",                   1, 1) |c| { c.reg.a = !c.reg.a; c.reg.flag(H, true); c.reg.flag(N, true); },
+    0x30 => ("JR NC,{jr}",            2, 2 / 3) |c| { c.jr_if(!c.reg.getflag(C)) },
+    0x31 => ("LD SP,{d16}",           3, 3) |c| { c.reg.sp = c.fetchword(); },
+    0x32 => ("LD (HL-),A",            1, 2) |c| { let a = c.reg.hld(); c.wb(a, c.reg.a); },
+    0x33 => ("INC SP",                1, 2) |c| { c.reg.sp = c.reg.sp.wrapping_add(1); },
+    0x34 => ("INC (HL)",              1, 3) |c| { let a = c.reg.hl(); let v = c.rb(a); let v2 = c.alu_inc(v); c.wb(a, v2); },
+    0x35 => ("DEC (HL)",              1, 3) |c| { let a = c.reg.hl(); let v = c.rb(a); let v2 = c.alu_dec(v); c.wb(a, v2); },
+    0x36 => ("LD (HL),{d8}",          2, 3) |c| { let v = c.fetchbyte(); c.wb(c.reg.hl(), v); },
+    0x37 => ("SCF",                   1, 1) |c| { c.reg.flag(C, true); c.reg.flag(H, false); c.reg.flag(N, false); },
+    0x38 => ("JR C,{jr}",             2, 2 / 3) |c| { c.jr_if(c.reg.getflag(C)) },
+    0x39 => ("ADD HL,SP",             1, 2) |c| { c.alu_add16(c.reg.sp); },
+    0x3A => ("LD A,(HL-)",            1, 2) |c| { let a = c.reg.hld(); c.reg.a = c.rb(a); },
+    0x3B => ("DEC SP",                1, 2) |c| { c.reg.sp = c.reg.sp.wrapping_sub(1); },
+    0x3C => ("INC A",                 1, 1) |c| { c.reg.a = c.alu_inc(c.reg.a); },
+    0x3D => ("DEC A",                 1, 1) |c| { c.reg.a = c.alu_dec(c.reg.a); },
+    0x3E => ("LD A,{d8}",             2, 2) |c| { c.reg.a = c.fetchbyte(); },
+    0x3F => ("CCF",                   1, 1) |c| { let v = !c.reg.getflag(C); c.reg.flag(C, v); c.reg.flag(H, false); c.reg.flag(N, false); },
+    0x40 => ("LD B,B",                1, 1) |_c| {},
+    0x41 => ("LD B,C",                1, 1) |c| { c.reg.b = c.reg.c; },
+    0x42 => ("LD B,D",                1, 1) |c| { c.reg.b = c.reg.d; },
+    0x43 => ("LD B,E",                1, 1) |c| { c.reg.b = c.reg.e; },
+    0x44 => ("LD B,H",                1, 1) |c| { c.reg.b = c.reg.h; },
+    0x45 => ("LD B,L",                1, 1) |c| { c.reg.b = c.reg.l; },
+    0x46 => ("LD B,(HL)",             1, 2) |c| { c.reg.b = c.rb(c.reg.hl()); },
+    0x47 => ("LD B,A",                1, 1) |c| { c.reg.b = c.reg.a; },
+    0x48 => ("LD C,B",                1, 1) |c| { c.reg.c = c.reg.b; },
+    0x49 => ("LD C,C",                1, 1) |_c| {},
+    0x4A => ("LD C,D",                1, 1) |c| { c.reg.c = c.reg.d; },
+    0x4B => ("LD C,E",                1, 1) |c| { c.reg.c = c.reg.e; },
+    0x4C => ("LD C,H",                1, 1) |c| { c.reg.c = c.reg.h; },
+    0x4D => ("LD C,L",                1, 1) |c| { c.reg.c = c.reg.l; },
+    0x4E => ("LD C,(HL)",             1, 2) |c| { c.reg.c = c.rb(c.reg.hl()); },
+    0x4F => ("LD C,A",                1, 1) |c| { c.reg.c = c.reg.a; },
+    0x50 => ("LD D,B",                1, 1) |c| { c.reg.d = c.reg.b; },
+    0x51 => ("LD D,C",                1, 1) |c| { c.reg.d = c.reg.c; },
+    0x52 => ("LD D,D",                1, 1) |_c| {},
+    0x53 => ("LD D,E",                1, 1) |c| { c.reg.d = c.reg.e; },
+    0x54 => ("LD D,H",                1, 1) |c| { c.reg.d = c.reg.h; },
+    0x55 => ("LD D,L",                1, 1) |c| { c.reg.d = c.reg.l; },
+    0x56 => ("LD D,(HL)",             1, 2) |c| { c.reg.d = c.rb(c.reg.hl()); },
+    0x57 => ("LD D,A",                1, 1) |c| { c.reg.d = c.reg.a; },
+    0x58 => ("LD E,B",                1, 1) |c| { c.reg.e = c.reg.b; },
+    0x59 => ("LD E,C",                1, 1) |c| { c.reg.e = c.reg.c; },
+    0x5A => ("LD E,D",                1, 1) |c| { c.reg.e = c.reg.d; },
+    0x5B => ("LD E,E",                1, 1) |_c| {},
+    0x5C => ("LD E,H",                1, 1) |c| { c.reg.e = c.reg.h; },
+    0x5D => ("LD E,L",                1, 1) |c| { c.reg.e = c.reg.l; },
+    0x5E => ("LD E,(HL)",             1, 2) |c| { c.reg.e = c.rb(c.reg.hl()); },
+    0x5F => ("LD E,A",                1, 1) |c| { c.reg.e = c.reg.a; },
+    0x60 => ("LD H,B",                1, 1) |c| { c.reg.h = c.reg.b; },
+    0x61 => ("LD H,C",                1, 1) |c| { c.reg.h = c.reg.c; },
+    0x62 => ("LD H,D",                1, 1) |c| { c.reg.h = c.reg.d; },
+    0x63 => ("LD H,E",                1, 1) |c| { c.reg.h = c.reg.e; },
+    0x64 => ("LD H,H",                1, 1) |_c| {},
+    0x65 => ("LD H,L",                1, 1) |c| { c.reg.h = c.reg.l; },
+    0x66 => ("LD H,(HL)",             1, 2) |c| { c.reg.h = c.rb(c.reg.hl()); },
+    0x67 => ("LD H,A",                1, 1) |c| { c.reg.h = c.reg.a; },
+    0x68 => ("LD L,B",                1, 1) |c| { c.reg.l = c.reg.b; },
+    0x69 => ("LD L,C",                1, 1) |c| { c.reg.l = c.reg.c; },
+    0x6A => ("LD L,D",                1, 1) |c| { c.reg.l = c.reg.d; },
+    0x6B => ("LD L,E",                1, 1) |c| { c.reg.l = c.reg.e; },
+    0x6C => ("LD L,H",                1, 1) |c| { c.reg.l = c.reg.h; },
+    0x6D => ("LD L,L",                1, 1) |_c| {},
+    0x6E => ("LD L,(HL)",             1, 2) |c| { c.reg.l = c.rb(c.reg.hl()); },
+    0x6F => ("LD L,A",                1, 1) |c| { c.reg.l = c.reg.a; },
+    0x70 => ("LD (HL),B",             1, 2) |c| { c.wb(c.reg.hl(), c.reg.b); },
+    0x71 => ("LD (HL),C",             1, 2) |c| { c.wb(c.reg.hl(), c.reg.c); },
+    0x72 => ("LD (HL),D",             1, 2) |c| { c.wb(c.reg.hl(), c.reg.d); },
+    0x73 => ("LD (HL),E",             1, 2) |c| { c.wb(c.reg.hl(), c.reg.e); },
+    0x74 => ("LD (HL),H",             1, 2) |c| { c.wb(c.reg.hl(), c.reg.h); },
+    0x75 => ("LD (HL),L",             1, 2) |c| { c.wb(c.reg.hl(), c.reg.l); },
+    0x76 => ("HALT",                  1, 1) |c| { c.halt(); },
+    0x77 => ("LD (HL),A",             1, 2) |c| { c.wb(c.reg.hl(), c.reg.a); },
+    0x78 => ("LD A,B",                1, 1) |c| { c.reg.a = c.reg.b; },
+    0x79 => ("LD A,C",                1, 1) |c| { c.reg.a = c.reg.c; },
+    0x7A => ("LD A,D",                1, 1) |c| { c.reg.a = c.reg.d; },
+    0x7B => ("LD A,E",                1, 1) |c| { c.reg.a = c.reg.e; },
+    0x7C => ("LD A,H",                1, 1) |c| { c.reg.a = c.reg.h; },
+    0x7D => ("LD A,L",                1, 1) |c| { c.reg.a = c.reg.l; },
+    0x7E => ("LD A,(HL)",             1, 2) |c| { c.reg.a = c.rb(c.reg.hl()); },
+    0x7F => ("LD A,A",                1, 1) |_c| {},
+    0x80 => ("ADD A,B",               1, 1) |c| { c.alu_add(c.reg.b, false); },
+    0x81 => ("ADD A,C",               1, 1) |c| { c.alu_add(c.reg.c, false); },
+    0x82 => ("ADD A,D",               1, 1) |c| { c.alu_add(c.reg.d, false); },
+    0x83 => ("ADD A,E",               1, 1) |c| { c.alu_add(c.reg.e, false); },
+    0x84 => ("ADD A,H",               1, 1) |c| { c.alu_add(c.reg.h, false); },
+    0x85 => ("ADD A,L",               1, 1) |c| { c.alu_add(c.reg.l, false); },
+    0x86 => ("ADD A,(HL)",            1, 2) |c| { let v = c.rb(c.reg.hl()); c.alu_add(v, false); },
+    0x87 => ("ADD A,A",               1, 1) |c| { c.alu_add(c.reg.a, false); },
+    0x88 => ("ADC A,B",               1, 1) |c| { c.alu_add(c.reg.b, true); },
+    0x89 => ("ADC A,C",               1, 1) |c| { c.alu_add(c.reg.c, true); },
+    0x8A => ("ADC A,D",               1, 1) |c| { c.alu_add(c.reg.d, true); },
+    0x8B => ("ADC A,E",               1, 1) |c| { c.alu_add(c.reg.e, true); },
+    0x8C => ("ADC A,H",               1, 1) |c| { c.alu_add(c.reg.h, true); },
+    0x8D => ("ADC A,L",               1, 1) |c| { c.alu_add(c.reg.l, true); },
+    0x8E => ("ADC A,(HL)",            1, 2) |c| { let v = c.rb(c.reg.hl()); c.alu_add(v, true); },
+    0x8F => ("ADC A,A",               1, 1) |c| { c.alu_add(c.reg.a, true); },
+    0x90 => ("SUB B",                 1, 1) |c| { c.alu_sub(c.reg.b, false); },
+    0x91 => ("SUB C",                 1, 1) |c| { c.alu_sub(c.reg.c, false); },
+    0x92 => ("SUB D",                 1, 1) |c| { c.alu_sub(c.reg.d, false); },
+    0x93 => ("SUB E",                 1, 1) |c| { c.alu_sub(c.reg.e, false); },
+    0x94 => ("SUB H",                 1, 1) |c| { c.alu_sub(c.reg.h, false); },
+    0x95 => ("SUB L",                 1, 1) |c| { c.alu_sub(c.reg.l, false); },
+    0x96 => ("SUB (HL)",              1, 2) |c| { let v = c.rb(c.reg.hl()); c.alu_sub(v, false); },
+    0x97 => ("SUB A",                 1, 1) |c| { c.alu_sub(c.reg.a, false); },
+    0x98 => ("SBC A,B",               1, 1) |c| { c.alu_sub(c.reg.b, true); },
+    0x99 => ("SBC A,C",               1, 1) |c| { c.alu_sub(c.reg.c, true); },
+    0x9A => ("SBC A,D",               1, 1) |c| { c.alu_sub(c.reg.d, true); },
+    0x9B => ("SBC A,E",               1, 1) |c| { c.alu_sub(c.reg.e, true); },
+    0x9C => ("SBC A,H",               1, 1) |c| { c.alu_sub(c.reg.h, true); },
+    0x9D => ("SBC A,L",               1, 1) |c| { c.alu_sub(c.reg.l, true); },
+    0x9E => ("SBC A,(HL)",            1, 2) |c| { let v = c.rb(c.reg.hl()); c.alu_sub(v, true); },
+    0x9F => ("SBC A,A",               1, 1) |c| { c.alu_sub(c.reg.a, true); },
+    0xA0 => ("AND B",                 1, 1) |c| { c.alu_and(c.reg.b); },
+    0xA1 => ("AND C",                 1, 1) |c| { c.alu_and(c.reg.c); },
+    0xA2 => ("AND D",                 1, 1) |c| { c.alu_and(c.reg.d); },
+    0xA3 => ("AND E",                 1, 1) |c| { c.alu_and(c.reg.e); },
+    0xA4 => ("AND H",                 1, 1) |c| { c.alu_and(c.reg.h); },
+    0xA5 => ("AND L",                 1, 1) |c| { c.alu_and(c.reg.l); },
+    0xA6 => ("AND (HL)",              1, 2) |c| { let v = c.rb(c.reg.hl()); c.alu_and(v); },
+    0xA7 => ("AND A",                 1, 1) |c| { c.alu_and(c.reg.a); },
+    0xA8 => ("XOR B",                 1, 1) |c| { c.alu_xor(c.reg.b); },
+    0xA9 => ("XOR C",                 1, 1) |c| { c.alu_xor(c.reg.c); },
+    0xAA => ("XOR D",                 1, 1) |c| { c.alu_xor(c.reg.d); },
+    0xAB => ("XOR E",                 1, 1) |c| { c.alu_xor(c.reg.e); },
+    0xAC => ("XOR H",                 1, 1) |c| { c.alu_xor(c.reg.h); },
+    0xAD => ("XOR L",                 1, 1) |c| { c.alu_xor(c.reg.l); },
+    0xAE => ("XOR (HL)",              1, 2) |c| { let v = c.rb(c.reg.hl()); c.alu_xor(v); },
+    0xAF => ("XOR A",                 1, 1) |c| { c.alu_xor(c.reg.a); },
+    0xB0 => ("OR B",                  1, 1) |c| { c.alu_or(c.reg.b); },
+    0xB1 => ("OR C",                  1, 1) |c| { c.alu_or(c.reg.c); },
+    0xB2 => ("OR D",                  1, 1) |c| { c.alu_or(c.reg.d); },
+    0xB3 => ("OR E",                  1, 1) |c| { c.alu_or(c.reg.e); },
+    0xB4 => ("OR H",                  1, 1) |c| { c.alu_or(c.reg.h); },
+    0xB5 => ("OR L",                  1, 1) |c| { c.alu_or(c.reg.l); },
+    0xB6 => ("OR (HL)",               1, 2) |c| { let v = c.rb(c.reg.hl()); c.alu_or(v); },
+    0xB7 => ("OR A",                  1, 1) |c| { c.alu_or(c.reg.a); },
+    0xB8 => ("CP B",                  1, 1) |c| { c.alu_cp(c.reg.b); },
+    0xB9 => ("CP C",                  1, 1) |c| { c.alu_cp(c.reg.c); },
+    0xBA => ("CP D",                  1, 1) |c| { c.alu_cp(c.reg.d); },
+    0xBB => ("CP E",                  1, 1) |c| { c.alu_cp(c.reg.e); },
+    0xBC => ("CP H",                  1, 1) |c| { c.alu_cp(c.reg.h); },
+    0xBD => ("CP L",                  1, 1) |c| { c.alu_cp(c.reg.l); },
+    0xBE => ("CP (HL)",               1, 2) |c| { let v = c.rb(c.reg.hl()); c.alu_cp(v); },
+    0xBF => ("CP A",                  1, 1) |c| { c.alu_cp(c.reg.a); },
+    0xC0 => ("RET NZ",                1, 2 / 5) |c| { c.ret_if(!c.reg.getflag(Z)) },
+    0xC1 => ("POP BC",                1, 3) |c| { let v = c.popstack(); c.reg.setbc(v); },
+    0xC2 => ("JP NZ,{d16}",           3, 3 / 4) |c| { c.jp_if(!c.reg.getflag(Z)) },
+    0xC3 => ("JP {d16}",              3, 4) |c| { c.reg.pc = c.fetchword(); },
+    0xC4 => ("CALL NZ,{d16}",         3, 3 / 6) |c| { c.call_if(!c.reg.getflag(Z)) },
+    0xC5 => ("PUSH BC",               1, 4) |c| { c.pushstack(c.reg.bc()); },
+    0xC6 => ("ADD A,{d8}",            2, 2) |c| { let v = c.fetchbyte(); c.alu_add(v, false); },
+    0xC7 => ("RST $00",               1, 4) |c| { c.pushstack(c.reg.pc); c.reg.pc = 0x00; },
+    0xC8 => ("RET Z",                 1, 2 / 5) |c| { c.ret_if(c.reg.getflag(Z)) },
+    0xC9 => ("RET",                   1, 4) |c| { c.reg.pc = c.popstack(); },
+    0xCA => ("JP Z,{d16}",            3, 3 / 4) |c| { c.jp_if(c.reg.getflag(Z)) },
+    0xCB => ("PREFIX CB",             2, cb) |c| { c.call_cb() },
+    0xCC => ("CALL Z,{d16}",          3, 3 / 6) |c| { c.call_if(c.reg.getflag(Z)) },
+    0xCD => ("CALL {d16}",            3, 6) |c| { c.cpu_call(); },
+    0xCE => ("ADC A,{d8}",            2, 2) |c| { let v = c.fetchbyte(); c.alu_add(v, true); },
+    0xCF => ("RST $08",               1, 4) |c| { c.pushstack(c.reg.pc); c.reg.pc = 0x08; },
+    0xD0 => ("RET NC",                1, 2 / 5) |c| { c.ret_if(!c.reg.getflag(C)) },
+    0xD1 => ("POP DE",                1, 3) |c| { let v = c.popstack(); c.reg.setde(v); },
+    0xD2 => ("JP NC,{d16}",           3, 3 / 4) |c| { c.jp_if(!c.reg.getflag(C)) },
+    0xD3 => ("DB {op}",               1, 1) |c| { c.lock(); },
+    0xD4 => ("CALL NC,{d16}",         3, 3 / 6) |c| { c.call_if(!c.reg.getflag(C)) },
+    0xD5 => ("PUSH DE",               1, 4) |c| { c.pushstack(c.reg.de()); },
+    0xD6 => ("SUB {d8}",              2, 2) |c| { let v = c.fetchbyte(); c.alu_sub(v, false); },
+    0xD7 => ("RST $10",               1, 4) |c| { c.pushstack(c.reg.pc); c.reg.pc = 0x10; },
+    0xD8 => ("RET C",                 1, 2 / 5) |c| { c.ret_if(c.reg.getflag(C)) },
+    0xD9 => ("RETI",                  1, 4) |c| { c.reg.pc = c.popstack(); c.ime = true; },
+    0xDA => ("JP C,{d16}",            3, 3 / 4) |c| { c.jp_if(c.reg.getflag(C)) },
+    0xDB => ("DB {op}",               1, 1) |c| { c.lock(); },
+    0xDC => ("CALL C,{d16}",          3, 3 / 6) |c| { c.call_if(c.reg.getflag(C)) },
+    0xDD => ("DB {op}",               1, 1) |c| { c.lock(); },
+    0xDE => ("SBC A,{d8}",            2, 2) |c| { let v = c.fetchbyte(); c.alu_sub(v, true); },
+    0xDF => ("RST $18",               1, 4) |c| { c.pushstack(c.reg.pc); c.reg.pc = 0x18; },
+    0xE0 => ("LDH ($FF00+{d8}),A",    2, 3) |c| { let a = 0xFF00 | c.fetchbyte() as u16; c.wb(a, c.reg.a); },
+    0xE1 => ("POP HL",                1, 3) |c| { let v = c.popstack(); c.reg.sethl(v); },
+    0xE2 => ("LD ($FF00+C),A",        1, 2) |c| { c.wb(0xFF00 | c.reg.c as u16, c.reg.a); },
+    0xE3 => ("DB {op}",               1, 1) |c| { c.lock(); },
+    0xE4 => ("DB {op}",               1, 1) |c| { c.lock(); },
+    0xE5 => ("PUSH HL",               1, 4) |c| { c.pushstack(c.reg.hl()); },
+    0xE6 => ("AND {d8}",              2, 2) |c| { let v = c.fetchbyte(); c.alu_and(v); },
+    0xE7 => ("RST $20",               1, 4) |c| { c.pushstack(c.reg.pc); c.reg.pc = 0x20; },
+    0xE8 => ("ADD SP,{r8}",           2, 4) |c| { c.reg.sp = c.alu_add16imm(c.reg.sp); },
+    0xE9 => ("JP (HL)",               1, 1) |c| { c.reg.pc = c.reg.hl(); },
+    0xEA => ("LD ({d16}),A",          3, 4) |c| { let a = c.fetchword(); c.wb(a, c.reg.a); },
+    0xEB => ("DB {op}",               1, 1) |c| { c.lock(); },
+    0xEC => ("DB {op}",               1, 1) |c| { c.lock(); },
+    0xED => ("DB {op}",               1, 1) |c| { c.lock(); },
+    0xEE => ("XOR {d8}",              2, 2) |c| { let v = c.fetchbyte(); c.alu_xor(v); },
+    0xEF => ("RST $28",               1, 4) |c| { c.pushstack(c.reg.pc); c.reg.pc = 0x28; },
+    0xF0 => ("LDH A,($FF00+{d8})",    2, 3) |c| { let a = 0xFF00 | c.fetchbyte() as u16; c.reg.a = c.rb(a); },
+    0xF1 => ("POP AF",                1, 3) |c| { let v = c.popstack(); c.reg.setaf(v); },
+    0xF2 => ("LD A,($FF00+C)",        1, 2) |c| { c.reg.a = c.rb(0xFF00 | c.reg.c as u16); },
+    0xF3 => ("DI",                    1, 1) |c| { c.ime = false; c.ei_pending = false; },
+    0xF4 => ("DB {op}",               1, 1) |c| { c.lock(); },
+    0xF5 => ("PUSH AF",               1, 4) |c| { c.pushstack(c.reg.af()); },
+    0xF6 => ("OR {d8}",               2, 2) |c| { let v = c.fetchbyte(); c.alu_or(v); },
+    0xF7 => ("RST $30",               1, 4) |c| { c.pushstack(c.reg.pc); c.reg.pc = 0x30; },
+    0xF8 => ("LD HL,SP{+r8}",         2, 3) |c| { let r = c.alu_add16imm(c.reg.sp); c.reg.sethl(r); },
+    0xF9 => ("LD SP,HL",              1, 2) |c| { c.reg.sp = c.reg.hl(); },
+    0xFA => ("LD A,({d16})",          3, 4) |c| { let a = c.fetchword(); c.reg.a = c.rb(a); },
+    0xFB => ("EI",                    1, 1) |c| { c.ei_pending = true; },
+    0xFC => ("DB {op}",               1, 1) |c| { c.lock(); },
+    0xFD => ("DB {op}",               1, 1) |c| { c.lock(); },
+    0xFE => ("CP {d8}",               2, 2) |c| { let v = c.fetchbyte(); c.alu_cp(v); },
+    0xFF => ("RST $38",               1, 4) |c| { c.pushstack(c.reg.pc); c.reg.pc = 0x38; },
+};
+
+pub(crate) static CB_OPCODES: [Opcode; 256] = opcode_table! {
+    0x00 => ("RLC B",                 2, 2) |c| { c.reg.b = c.alu_rlc(c.reg.b); },
+    0x01 => ("RLC C",                 2, 2) |c| { c.reg.c = c.alu_rlc(c.reg.c); },
+    0x02 => ("RLC D",                 2, 2) |c| { c.reg.d = c.alu_rlc(c.reg.d); },
+    0x03 => ("RLC E",                 2, 2) |c| { c.reg.e = c.alu_rlc(c.reg.e); },
+    0x04 => ("RLC H",                 2, 2) |c| { c.reg.h = c.alu_rlc(c.reg.h); },
+    0x05 => ("RLC L",                 2, 2) |c| { c.reg.l = c.alu_rlc(c.reg.l); },
+    0x06 => ("RLC (HL)",              2, 4) |c| { let a = c.reg.hl(); let v = c.rb(a); let v2 = c.alu_rlc(v); c.wb(a, v2); },
+    0x07 => ("RLC A",                 2, 2) |c| { c.reg.a = c.alu_rlc(c.reg.a); },
+    0x08 => ("RRC B",                 2, 2) |c| { c.reg.b = c.alu_rrc(c.reg.b); },
+    0x09 => ("RRC C",                 2, 2) |c| { c.reg.c = c.alu_rrc(c.reg.c); },
+    0x0A => ("RRC D",                 2, 2) |c| { c.reg.d = c.alu_rrc(c.reg.d); },
+    0x0B => ("RRC E",                 2, 2) |c| { c.reg.e = c.alu_rrc(c.reg.e); },
+    0x0C => ("RRC H",                 2, 2) |c| { c.reg.h = c.alu_rrc(c.reg.h); },
+    0x0D => ("RRC L",                 2, 2) |c| { c.reg.l = c.alu_rrc(c.reg.l); },
+    0x0E => ("RRC (HL)",              2, 4) |c| { let a = c.reg.hl(); let v = c.rb(a); let v2 = c.alu_rrc(v); c.wb(a, v2); },
+    0x0F => ("RRC A",                 2, 2) |c| { c.reg.a = c.alu_rrc(c.reg.a); },
+    0x10 => ("RL B",                  2, 2) |c| { c.reg.b = c.alu_rl(c.reg.b); },
+    0x11 => ("RL C",                  2, 2) |c| { c.reg.c = c.alu_rl(c.reg.c); },
+    0x12 => ("RL D",                  2, 2) |c| { c.reg.d = c.alu_rl(c.reg.d); },
+    0x13 => ("RL E",                  2, 2) |c| { c.reg.e = c.alu_rl(c.reg.e); },
+    0x14 => ("RL H",                  2, 2) |c| { c.reg.h = c.alu_rl(c.reg.h); },
+    0x15 => ("RL L",                  2, 2) |c| { c.reg.l = c.alu_rl(c.reg.l); },
+    0x16 => ("RL (HL)",               2, 4) |c| { let a = c.reg.hl(); let v = c.rb(a); let v2 = c.alu_rl(v); c.wb(a, v2); },
+    0x17 => ("RL A",                  2, 2) |c| { c.reg.a = c.alu_rl(c.reg.a); },
+    0x18 => ("RR B",                  2, 2) |c| { c.reg.b = c.alu_rr(c.reg.b); },
+    0x19 => ("RR C",                  2, 2) |c| { c.reg.c = c.alu_rr(c.reg.c); },
+    0x1A => ("RR D",                  2, 2) |c| { c.reg.d = c.alu_rr(c.reg.d); },
+    0x1B => ("RR E",                  2, 2) |c| { c.reg.e = c.alu_rr(c.reg.e); },
+    0x1C => ("RR H",                  2, 2) |c| { c.reg.h = c.alu_rr(c.reg.h); },
+    0x1D => ("RR L",                  2, 2) |c| { c.reg.l = c.alu_rr(c.reg.l); },
+    0x1E => ("RR (HL)",               2, 4) |c| { let a = c.reg.hl(); let v = c.rb(a); let v2 = c.alu_rr(v); c.wb(a, v2); },
+    0x1F => ("RR A",                  2, 2) |c| { c.reg.a = c.alu_rr(c.reg.a); },
+    0x20 => ("SLA B",                 2, 2) |c| { c.reg.b = c.alu_sla(c.reg.b); },
+    0x21 => ("SLA C",                 2, 2) |c| { c.reg.c = c.alu_sla(c.reg.c); },
+    0x22 => ("SLA D",                 2, 2) |c| { c.reg.d = c.alu_sla(c.reg.d); },
+    0x23 => ("SLA E",                 2, 2) |c| { c.reg.e = c.alu_sla(c.reg.e); },
+    0x24 => ("SLA H",                 2, 2) |c| { c.reg.h = c.alu_sla(c.reg.h); },
+    0x25 => ("SLA L",                 2, 2) |c| { c.reg.l = c.alu_sla(c.reg.l); },
+    0x26 => ("SLA (HL)",              2, 4) |c| { let a = c.reg.hl(); let v = c.rb(a); let v2 = c.alu_sla(v); c.wb(a, v2); },
+    0x27 => ("SLA A",                 2, 2) |c| { c.reg.a = c.alu_sla(c.reg.a); },
+    0x28 => ("SRA B",                 2, 2) |c| { c.reg.b = c.alu_sra(c.reg.b); },
+    0x29 => ("SRA C",                 2, 2) |c| { c.reg.c = c.alu_sra(c.reg.c); },
+    0x2A => ("SRA D",                 2, 2) |c| { c.reg.d = c.alu_sra(c.reg.d); },
+    0x2B => ("SRA E",                 2, 2) |c| { c.reg.e = c.alu_sra(c.reg.e); },
+    0x2C => ("SRA H",                 2, 2) |c| { c.reg.h = c.alu_sra(c.reg.h); },
+    0x2D => ("SRA L",                 2, 2) |c| { c.reg.l = c.alu_sra(c.reg.l); },
+    0x2E => ("SRA (HL)",              2, 4) |c| { let a = c.reg.hl(); let v = c.rb(a); let v2 = c.alu_sra(v); c.wb(a, v2); },
+    0x2F => ("SRA A",                 2, 2) |c| { c.reg.a = c.alu_sra(c.reg.a); },
+    0x30 => ("SWAP B",                2, 2) |c| { c.reg.b = c.alu_swap(c.reg.b); },
+    0x31 => ("SWAP C",                2, 2) |c| { c.reg.c = c.alu_swap(c.reg.c); },
+    0x32 => ("SWAP D",                2, 2) |c| { c.reg.d = c.alu_swap(c.reg.d); },
+    0x33 => ("SWAP E",                2, 2) |c| { c.reg.e = c.alu_swap(c.reg.e); },
+    0x34 => ("SWAP H",                2, 2) |c| { c.reg.h = c.alu_swap(c.reg.h); },
+    0x35 => ("SWAP L",                2, 2) |c| { c.reg.l = c.alu_swap(c.reg.l); },
+    0x36 => ("SWAP (HL)",             2, 4) |c| { let a = c.reg.hl(); let v = c.rb(a); let v2 = c.alu_swap(v); c.wb(a, v2); },
+    0x37 => ("SWAP A",                2, 2) |c| { c.reg.a = c.alu_swap(c.reg.a); },
+    0x38 => ("SRL B",                 2, 2) |c| { c.reg.b = c.alu_srl(c.reg.b); },
+    0x39 => ("SRL C",                 2, 2) |c| { c.reg.c = c.alu_srl(c.reg.c); },
+    0x3A => ("SRL D",                 2, 2) |c| { c.reg.d = c.alu_srl(c.reg.d); },
+    0x3B => ("SRL E",                 2, 2) |c| { c.reg.e = c.alu_srl(c.reg.e); },
+    0x3C => ("SRL H",                 2, 2) |c| { c.reg.h = c.alu_srl(c.reg.h); },
+    0x3D => ("SRL L",                 2, 2) |c| { c.reg.l = c.alu_srl(c.reg.l); },
+    0x3E => ("SRL (HL)",              2, 4) |c| { let a = c.reg.hl(); let v = c.rb(a); let v2 = c.alu_srl(v); c.wb(a, v2); },
+    0x3F => ("SRL A",                 2, 2) |c| { c.reg.a = c.alu_srl(c.reg.a); },
+    0x40 => ("BIT 0,B",               2, 2) |c| { c.alu_bit(c.reg.b, 0); },
+    0x41 => ("BIT 0,C",               2, 2) |c| { c.alu_bit(c.reg.c, 0); },
+    0x42 => ("BIT 0,D",               2, 2) |c| { c.alu_bit(c.reg.d, 0); },
+    0x43 => ("BIT 0,E",               2, 2) |c| { c.alu_bit(c.reg.e, 0); },
+    0x44 => ("BIT 0,H",               2, 2) |c| { c.alu_bit(c.reg.h, 0); },
+    0x45 => ("BIT 0,L",               2, 2) |c| { c.alu_bit(c.reg.l, 0); },
+    0x46 => ("BIT 0,(HL)",            2, 3) |c| { let v = c.rb(c.reg.hl()); c.alu_bit(v, 0); },
+    0x47 => ("BIT 0,A",               2, 2) |c| { c.alu_bit(c.reg.a, 0); },
+    0x48 => ("BIT 1,B",               2, 2) |c| { c.alu_bit(c.reg.b, 1); },
+    0x49 => ("BIT 1,C",               2, 2) |c| { c.alu_bit(c.reg.c, 1); },
+    0x4A => ("BIT 1,D",               2, 2) |c| { c.alu_bit(c.reg.d, 1); },
+    0x4B => ("BIT 1,E",               2, 2) |c| { c.alu_bit(c.reg.e, 1); },
+    0x4C => ("BIT 1,H",               2, 2) |c| { c.alu_bit(c.reg.h, 1); },
+    0x4D => ("BIT 1,L",               2, 2) |c| { c.alu_bit(c.reg.l, 1); },
+    0x4E => ("BIT 1,(HL)",            2, 3) |c| { let v = c.rb(c.reg.hl()); c.alu_bit(v, 1); },
+    0x4F => ("BIT 1,A",               2, 2) |c| { c.alu_bit(c.reg.a, 1); },
+    0x50 => ("BIT 2,B",               2, 2) |c| { c.alu_bit(c.reg.b, 2); },
+    0x51 => ("BIT 2,C",               2, 2) |c| { c.alu_bit(c.reg.c, 2); },
+    0x52 => ("BIT 2,D",               2, 2) |c| { c.alu_bit(c.reg.d, 2); },
+    0x53 => ("BIT 2,E",               2, 2) |c| { c.alu_bit(c.reg.e, 2); },
+    0x54 => ("BIT 2,H",               2, 2) |c| { c.alu_bit(c.reg.h, 2); },
+    0x55 => ("BIT 2,L",               2, 2) |c| { c.alu_bit(c.reg.l, 2); },
+    0x56 => ("BIT 2,(HL)",            2, 3) |c| { let v = c.rb(c.reg.hl()); c.alu_bit(v, 2); },
+    0x57 => ("BIT 2,A",               2, 2) |c| { c.alu_bit(c.reg.a, 2); },
+    0x58 => ("BIT 3,B",               2, 2) |c| { c.alu_bit(c.reg.b, 3); },
+    0x59 => ("BIT 3,C",               2, 2) |c| { c.alu_bit(c.reg.c, 3); },
+    0x5A => ("BIT 3,D",               2, 2) |c| { c.alu_bit(c.reg.d, 3); },
+    0x5B => ("BIT 3,E",               2, 2) |c| { c.alu_bit(c.reg.e, 3); },
+    0x5C => ("BIT 3,H",               2, 2) |c| { c.alu_bit(c.reg.h, 3); },
+    0x5D => ("BIT 3,L",               2, 2) |c| { c.alu_bit(c.reg.l, 3); },
+    0x5E => ("BIT 3,(HL)",            2, 3) |c| { let v = c.rb(c.reg.hl()); c.alu_bit(v, 3); },
+    0x5F => ("BIT 3,A",               2, 2) |c| { c.alu_bit(c.reg.a, 3); },
+    0x60 => ("BIT 4,B",               2, 2) |c| { c.alu_bit(c.reg.b, 4); },
+    0x61 => ("BIT 4,C",               2, 2) |c| { c.alu_bit(c.reg.c, 4); },
+    0x62 => ("BIT 4,D",               2, 2) |c| { c.alu_bit(c.reg.d, 4); },
+    0x63 => ("BIT 4,E",               2, 2) |c| { c.alu_bit(c.reg.e, 4); },
+    0x64 => ("BIT 4,H",               2, 2) |c| { c.alu_bit(c.reg.h, 4); },
+    0x65 => ("BIT 4,L",               2, 2) |c| { c.alu_bit(c.reg.l, 4); },
+    0x66 => ("BIT 4,(HL)",            2, 3) |c| { let v = c.rb(c.reg.hl()); c.alu_bit(v, 4); },
+    0x67 => ("BIT 4,A",               2, 2) |c| { c.alu_bit(c.reg.a, 4); },
+    0x68 => ("BIT 5,B",               2, 2) |c| { c.alu_bit(c.reg.b, 5); },
+    0x69 => ("BIT 5,C",               2, 2) |c| { c.alu_bit(c.reg.c, 5); },
+    0x6A => ("BIT 5,D",               2, 2) |c| { c.alu_bit(c.reg.d, 5); },
+    0x6B => ("BIT 5,E",               2, 2) |c| { c.alu_bit(c.reg.e, 5); },
+    0x6C => ("BIT 5,H",               2, 2) |c| { c.alu_bit(c.reg.h, 5); },
+    0x6D => ("BIT 5,L",               2, 2) |c| { c.alu_bit(c.reg.l, 5); },
+    0x6E => ("BIT 5,(HL)",            2, 3) |c| { let v = c.rb(c.reg.hl()); c.alu_bit(v, 5); },
+    0x6F => ("BIT 5,A",               2, 2) |c| { c.alu_bit(c.reg.a, 5); },
+    0x70 => ("BIT 6,B",               2, 2) |c| { c.alu_bit(c.reg.b, 6); },
+    0x71 => ("BIT 6,C",               2, 2) |c| { c.alu_bit(c.reg.c, 6); },
+    0x72 => ("BIT 6,D",               2, 2) |c| { c.alu_bit(c.reg.d, 6); },
+    0x73 => ("BIT 6,E",               2, 2) |c| { c.alu_bit(c.reg.e, 6); },
+    0x74 => ("BIT 6,H",               2, 2) |c| { c.alu_bit(c.reg.h, 6); },
+    0x75 => ("BIT 6,L",               2, 2) |c| { c.alu_bit(c.reg.l, 6); },
+    0x76 => ("BIT 6,(HL)",            2, 3) |c| { let v = c.rb(c.reg.hl()); c.alu_bit(v, 6); },
+    0x77 => ("BIT 6,A",               2, 2) |c| { c.alu_bit(c.reg.a, 6); },
+    0x78 => ("BIT 7,B",               2, 2) |c| { c.alu_bit(c.reg.b, 7); },
+    0x79 => ("BIT 7,C",               2, 2) |c| { c.alu_bit(c.reg.c, 7); },
+    0x7A => ("BIT 7,D",               2, 2) |c| { c.alu_bit(c.reg.d, 7); },
+    0x7B => ("BIT 7,E",               2, 2) |c| { c.alu_bit(c.reg.e, 7); },
+    0x7C => ("BIT 7,H",               2, 2) |c| { c.alu_bit(c.reg.h, 7); },
+    0x7D => ("BIT 7,L",               2, 2) |c| { c.alu_bit(c.reg.l, 7); },
+    0x7E => ("BIT 7,(HL)",            2, 3) |c| { let v = c.rb(c.reg.hl()); c.alu_bit(v, 7); },
+    0x7F => ("BIT 7,A",               2, 2) |c| { c.alu_bit(c.reg.a, 7); },
+    0x80 => ("RES 0,B",               2, 2) |c| { c.reg.b &= !(1 << 0); },
+    0x81 => ("RES 0,C",               2, 2) |c| { c.reg.c &= !(1 << 0); },
+    0x82 => ("RES 0,D",               2, 2) |c| { c.reg.d &= !(1 << 0); },
+    0x83 => ("RES 0,E",               2, 2) |c| { c.reg.e &= !(1 << 0); },
+    0x84 => ("RES 0,H",               2, 2) |c| { c.reg.h &= !(1 << 0); },
+    0x85 => ("RES 0,L",               2, 2) |c| { c.reg.l &= !(1 << 0); },
+    0x86 => ("RES 0,(HL)",            2, 4) |c| { let a = c.reg.hl(); let v = c.rb(a) & !(1 << 0); c.wb(a, v); },
+    0x87 => ("RES 0,A",               2, 2) |c| { c.reg.a &= !(1 << 0); },
+    0x88 => ("RES 1,B",               2, 2) |c| { c.reg.b &= !(1 << 1); },
+    0x89 => ("RES 1,C",               2, 2) |c| { c.reg.c &= !(1 << 1); },
+    0x8A => ("RES 1,D",               2, 2) |c| { c.reg.d &= !(1 << 1); },
+    0x8B => ("RES 1,E",               2, 2) |c| { c.reg.e &= !(1 << 1); },
+    0x8C => ("RES 1,H",               2, 2) |c| { c.reg.h &= !(1 << 1); },
+    0x8D => ("RES 1,L",               2, 2) |c| { c.reg.l &= !(1 << 1); },
+    0x8E => ("RES 1,(HL)",            2, 4) |c| { let a = c.reg.hl(); let v = c.rb(a) & !(1 << 1); c.wb(a, v); },
+    0x8F => ("RES 1,A",               2, 2) |c| { c.reg.a &= !(1 << 1); },
+    0x90 => ("RES 2,B",               2, 2) |c| { c.reg.b &= !(1 << 2); },
+    0x91 => ("RES 2,C",               2, 2) |c| { c.reg.c &= !(1 << 2); },
+    0x92 => ("RES 2,D",               2, 2) |c| { c.reg.d &= !(1 << 2); },
+    0x93 => ("RES 2,E",               2, 2) |c| { c.reg.e &= !(1 << 2); },
+    0x94 => ("RES 2,H",               2, 2) |c| { c.reg.h &= !(1 << 2); },
+    0x95 => ("RES 2,L",               2, 2) |c| { c.reg.l &= !(1 << 2); },
+    0x96 => ("RES 2,(HL)",            2, 4) |c| { let a = c.reg.hl(); let v = c.rb(a) & !(1 << 2); c.wb(a, v); },
+    0x97 => ("RES 2,A",               2, 2) |c| { c.reg.a &= !(1 << 2); },
+    0x98 => ("RES 3,B",               2, 2) |c| { c.reg.b &= !(1 << 3); },
+    0x99 => ("RES 3,C",               2, 2) |c| { c.reg.c &= !(1 << 3); },
+    0x9A => ("RES 3,D",               2, 2) |c| { c.reg.d &= !(1 << 3); },
+    0x9B => ("RES 3,E",               2, 2) |c| { c.reg.e &= !(1 << 3); },
+    0x9C => ("RES 3,H",               2, 2) |c| { c.reg.h &= !(1 << 3); },
+    0x9D => ("RES 3,L",               2, 2) |c| { c.reg.l &= !(1 << 3); },
+    0x9E => ("RES 3,(HL)",            2, 4) |c| { let a = c.reg.hl(); let v = c.rb(a) & !(1 << 3); c.wb(a, v); },
+    0x9F => ("RES 3,A",               2, 2) |c| { c.reg.a &= !(1 << 3); },
+    0xA0 => ("RES 4,B",               2, 2) |c| { c.reg.b &= !(1 << 4); },
+    0xA1 => ("RES 4,C",               2, 2) |c| { c.reg.c &= !(1 << 4); },
+    0xA2 => ("RES 4,D",               2, 2) |c| { c.reg.d &= !(1 << 4); },
+    0xA3 => ("RES 4,E",               2, 2) |c| { c.reg.e &= !(1 << 4); },
+    0xA4 => ("RES 4,H",               2, 2) |c| { c.reg.h &= !(1 << 4); },
+    0xA5 => ("RES 4,L",               2, 2) |c| { c.reg.l &= !(1 << 4); },
+    0xA6 => ("RES 4,(HL)",            2, 4) |c| { let a = c.reg.hl(); let v = c.rb(a) & !(1 << 4); c.wb(a, v); },
+    0xA7 => ("RES 4,A",               2, 2) |c| { c.reg.a &= !(1 << 4); },
+    0xA8 => ("RES 5,B",               2, 2) |c| { c.reg.b &= !(1 << 5); },
+    0xA9 => ("RES 5,C",               2, 2) |c| { c.reg.c &= !(1 << 5); },
+    0xAA => ("RES 5,D",               2, 2) |c| { c.reg.d &= !(1 << 5); },
+    0xAB => ("RES 5,E",               2, 2) |c| { c.reg.e &= !(1 << 5); },
+    0xAC => ("RES 5,H",               2, 2) |c| { c.reg.h &= !(1 << 5); },
+    0xAD => ("RES 5,L",               2, 2) |c| { c.reg.l &= !(1 << 5); },
+    0xAE => ("RES 5,(HL)",            2, 4) |c| { let a = c.reg.hl(); let v = c.rb(a) & !(1 << 5); c.wb(a, v); },
+    0xAF => ("RES 5,A",               2, 2) |c| { c.reg.a &= !(1 << 5); },
+    0xB0 => ("RES 6,B",               2, 2) |c| { c.reg.b &= !(1 << 6); },
+    0xB1 => ("RES 6,C",               2, 2) |c| { c.reg.c &= !(1 << 6); },
+    0xB2 => ("RES 6,D",               2, 2) |c| { c.reg.d &= !(1 << 6); },
+    0xB3 => ("RES 6,E",               2, 2) |c| { c.reg.e &= !(1 << 6); },
+    0xB4 => ("RES 6,H",               2, 2) |c| { c.reg.h &= !(1 << 6); },
+    0xB5 => ("RES 6,L",               2, 2) |c| { c.reg.l &= !(1 << 6); },
+    0xB6 => ("RES 6,(HL)",            2, 4) |c| { let a = c.reg.hl(); let v = c.rb(a) & !(1 << 6); c.wb(a, v); },
+    0xB7 => ("RES 6,A",               2, 2) |c| { c.reg.a &= !(1 << 6); },
+    0xB8 => ("RES 7,B",               2, 2) |c| { c.reg.b &= !(1 << 7); },
+    0xB9 => ("RES 7,C",               2, 2) |c| { c.reg.c &= !(1 << 7); },
+    0xBA => ("RES 7,D",               2, 2) |c| { c.reg.d &= !(1 << 7); },
+    0xBB => ("RES 7,E",               2, 2) |c| { c.reg.e &= !(1 << 7); },
+    0xBC => ("RES 7,H",               2, 2) |c| { c.reg.h &= !(1 << 7); },
+    0xBD => ("RES 7,L",               2, 2) |c| { c.reg.l &= !(1 << 7); },
+    0xBE => ("RES 7,(HL)",            2, 4) |c| { let a = c.reg.hl(); let v = c.rb(a) & !(1 << 7); c.wb(a, v); },
+    0xBF => ("RES 7,A",               2, 2) |c| { c.reg.a &= !(1 << 7); },
+    0xC0 => ("SET 0,B",               2, 2) |c| { c.reg.b |= 1 << 0; },
+    0xC1 => ("SET 0,C",               2, 2) |c| { c.reg.c |= 1 << 0; },
+    0xC2 => ("SET 0,D",               2, 2) |c| { c.reg.d |= 1 << 0; },
+    0xC3 => ("SET 0,E",               2, 2) |c| { c.reg.e |= 1 << 0; },
+    0xC4 => ("SET 0,H",               2, 2) |c| { c.reg.h |= 1 << 0; },
+    0xC5 => ("SET 0,L",               2, 2) |c| { c.reg.l |= 1 << 0; },
+    0xC6 => ("SET 0,(HL)",            2, 4) |c| { let a = c.reg.hl(); let v = c.rb(a) | (1 << 0); c.wb(a, v); },
+    0xC7 => ("SET 0,A",               2, 2) |c| { c.reg.a |= 1 << 0; },
+    0xC8 => ("SET 1,B",               2, 2) |c| { c.reg.b |= 1 << 1; },
+    0xC9 => ("SET 1,C",               2, 2) |c| { c.reg.c |= 1 << 1; },
+    0xCA => ("SET 1,D",               2, 2) |c| { c.reg.d |= 1 << 1; },
+    0xCB => ("SET 1,E",               2, 2) |c| { c.reg.e |= 1 << 1; },
+    0xCC => ("SET 1,H",               2, 2) |c| { c.reg.h |= 1 << 1; },
+    0xCD => ("SET 1,L",               2, 2) |c| { c.reg.l |= 1 << 1; },
+    0xCE => ("SET 1,(HL)",            2, 4) |c| { let a = c.reg.hl(); let v = c.rb(a) | (1 << 1); c.wb(a, v); },
+    0xCF => ("SET 1,A",               2, 2) |c| { c.reg.a |= 1 << 1; },
+    0xD0 => ("SET 2,B",               2, 2) |c| { c.reg.b |= 1 << 2; },
+    0xD1 => ("SET 2,C",               2, 2) |c| { c.reg.c |= 1 << 2; },
+    0xD2 => ("SET 2,D",               2, 2) |c| { c.reg.d |= 1 << 2; },
+    0xD3 => ("SET 2,E",               2, 2) |c| { c.reg.e |= 1 << 2; },
+    0xD4 => ("SET 2,H",               2, 2) |c| { c.reg.h |= 1 << 2; },
+    0xD5 => ("SET 2,L",               2, 2) |c| { c.reg.l |= 1 << 2; },
+    0xD6 => ("SET 2,(HL)",            2, 4) |c| { let a = c.reg.hl(); let v = c.rb(a) | (1 << 2); c.wb(a, v); },
+    0xD7 => ("SET 2,A",               2, 2) |c| { c.reg.a |= 1 << 2; },
+    0xD8 => ("SET 3,B",               2, 2) |c| { c.reg.b |= 1 << 3; },
+    0xD9 => ("SET 3,C",               2, 2) |c| { c.reg.c |= 1 << 3; },
+    0xDA => ("SET 3,D",               2, 2) |c| { c.reg.d |= 1 << 3; },
+    0xDB => ("SET 3,E",               2, 2) |c| { c.reg.e |= 1 << 3; },
+    0xDC => ("SET 3,H",               2, 2) |c| { c.reg.h |= 1 << 3; },
+    0xDD => ("SET 3,L",               2, 2) |c| { c.reg.l |= 1 << 3; },
+    0xDE => ("SET 3,(HL)",            2, 4) |c| { let a = c.reg.hl(); let v = c.rb(a) | (1 << 3); c.wb(a, v); },
+    0xDF => ("SET 3,A",               2, 2) |c| { c.reg.a |= 1 << 3; },
+    0xE0 => ("SET 4,B",               2, 2) |c| { c.reg.b |= 1 << 4; },
+    0xE1 => ("SET 4,C",               2, 2) |c| { c.reg.c |= 1 << 4; },
+    0xE2 => ("SET 4,D",               2, 2) |c| { c.reg.d |= 1 << 4; },
+    0xE3 => ("SET 4,E",               2, 2) |c| { c.reg.e |= 1 << 4; },
+    0xE4 => ("SET 4,H",               2, 2) |c| { c.reg.h |= 1 << 4; },
+    0xE5 => ("SET 4,L",               2, 2) |c| { c.reg.l |= 1 << 4; },
+    0xE6 => ("SET 4,(HL)",            2, 4) |c| { let a = c.reg.hl(); let v = c.rb(a) | (1 << 4); c.wb(a, v); },
+    0xE7 => ("SET 4,A",               2, 2) |c| { c.reg.a |= 1 << 4; },
+    0xE8 => ("SET 5,B",               2, 2) |c| { c.reg.b |= 1 << 5; },
+    0xE9 => ("SET 5,C",               2, 2) |c| { c.reg.c |= 1 << 5; },
+    0xEA => ("SET 5,D",               2, 2) |c| { c.reg.d |= 1 << 5; },
+    0xEB => ("SET 5,E",               2, 2) |c| { c.reg.e |= 1 << 5; },
+    0xEC => ("SET 5,H",               2, 2) |c| { c.reg.h |= 1 << 5; },
+    0xED => ("SET 5,L",               2, 2) |c| { c.reg.l |= 1 << 5; },
+    0xEE => ("SET 5,(HL)",            2, 4) |c| { let a = c.reg.hl(); let v = c.rb(a) | (1 << 5); c.wb(a, v); },
+    0xEF => ("SET 5,A",               2, 2) |c| { c.reg.a |= 1 << 5; },
+    0xF0 => ("SET 6,B",               2, 2) |c| { c.reg.b |= 1 << 6; },
+    0xF1 => ("SET 6,C",               2, 2) |c| { c.reg.c |= 1 << 6; },
+    0xF2 => ("SET 6,D",               2, 2) |c| { c.reg.d |= 1 << 6; },
+    0xF3 => ("SET 6,E",               2, 2) |c| { c.reg.e |= 1 << 6; },
+    0xF4 => ("SET 6,H",               2, 2) |c| { c.reg.h |= 1 << 6; },
+    0xF5 => ("SET 6,L",               2, 2) |c| { c.reg.l |= 1 << 6; },
+    0xF6 => ("SET 6,(HL)",            2, 4) |c| { let a = c.reg.hl(); let v = c.rb(a) | (1 << 6); c.wb(a, v); },
+    0xF7 => ("SET 6,A",               2, 2) |c| { c.reg.a |= 1 << 6; },
+    0xF8 => ("SET 7,B",               2, 2) |c| { c.reg.b |= 1 << 7; },
+    0xF9 => ("SET 7,C",               2, 2) |c| { c.reg.c |= 1 << 7; },
+    0xFA => ("SET 7,D",               2, 2) |c| { c.reg.d |= 1 << 7; },
+    0xFB => ("SET 7,E",               2, 2) |c| { c.reg.e |= 1 << 7; },
+    0xFC => ("SET 7,H",               2, 2) |c| { c.reg.h |= 1 << 7; },
+    0xFD => ("SET 7,L",               2, 2) |c| { c.reg.l |= 1 << 7; },
+    0xFE => ("SET 7,(HL)",            2, 4) |c| { let a = c.reg.hl(); let v = c.rb(a) | (1 << 7); c.wb(a, v); },
+    0xFF => ("SET 7,A",               2, 2) |c| { c.reg.a |= 1 << 7; },
+};
+
 #[cfg(test)]
 mod test
 {
-    use super::CPU;
+    use super::{CB_OPCODES, CPU, OPCODES};
+    use crate::debugger::DebugStop;
+    use crate::keypad::KeypadKey;
+    use crate::register::CpuFlag::{C, N, H, Z};
     use crate::mbc;
 
     const CPUINSTRS: &'static str = "roms/cpu_instrs.gb";
@@ -834,8 +1453,8 @@ mod test
 
         {
             let serial = |v: u8| { output.push(v); None };
-            let cart = mbc::FileBackedMBC::new(CPUINSTRS.into(), false).unwrap();
-            let mut c = match CPU::new(Box::new(cart), Some(Box::new(serial)))
+            let cart = mbc::get_mbc(std::fs::read(CPUINSTRS).unwrap(), false).unwrap();
+            let mut c = match CPU::new(cart, Some(Box::new(serial)))
             {
                 Err(message) => { panic!("{}", message); },
                 Ok(cpu) => cpu,
@@ -862,8 +1481,8 @@ mod test
 
         {
             let serial = |v| { output.push(v); None };
-            let cart = mbc::FileBackedMBC::new(CPUINSTRS.into(), false).unwrap();
-            let mut c = match CPU::new_cgb(Box::new(cart), Some(Box::new(serial)))
+            let cart = mbc::get_mbc(std::fs::read(CPUINSTRS).unwrap(), false).unwrap();
+            let mut c = match CPU::new_cgb(cart, Some(Box::new(serial)))
             {
                 Err(message) => { panic!("{}", message); },
                 Ok(cpu) => cpu,
@@ -882,4 +1501,796 @@ mod test
         assert!(&*output == CPU_SERIAL, "Serial did not output the expected result");
         assert!(sum_color == GPU_COLOR_CHECKSUM, "GPU did not produce expected graphics");
     }
+
+    fn rom_with_code(code: &[u8]) -> Vec<u8> {
+        let mut romdata = vec![0; 0x8000];
+        romdata[0x0100 .. 0x0100 + code.len()].copy_from_slice(code);
+        romdata
+    }
+
+    // Runs the code at 0100 after setting up a pending timer interrupt. The timer handler
+    // increments C and returns.
+    const PENDING_INTERRUPT_SETUP: [u8; 11] = [
+        0xF3,       // DI
+        0x06, 0x00, // LD B,00
+        0x0E, 0x00, // LD C,00
+        0x3E, 0x04, // LD A,04
+        0xE0, 0xFF, // LDH (FF),A
+        0xE0, 0x0F, // LDH (0F),A
+    ];
+
+    // Returns a CPU that is about to run the code at 010B, with IME off and a timer interrupt
+    // pending. The timer handler increments C and returns.
+    fn cpu_with_pending_interrupt(code: &[u8]) -> CPU<'static> {
+        let mut romdata = rom_with_code(&[&PENDING_INTERRUPT_SETUP[..], code].concat());
+        romdata[0x0050] = 0x0C;
+        romdata[0x0051] = 0xC9;
+
+        let cart = mbc::get_mbc(romdata, true).unwrap();
+        let mut c = CPU::new(cart, None).unwrap();
+        while c.reg.pc < 0x0100 + PENDING_INTERRUPT_SETUP.len() as u16 {
+            c.do_cycle();
+        }
+        c
+    }
+
+    // Runs the code at 0100 after setting up a pending timer interrupt, see cpu_with_pending_interrupt
+    fn run_with_pending_interrupt(code: &[u8], steps: usize) -> CPU<'static> {
+        let mut c = cpu_with_pending_interrupt(code);
+        for _ in 0 .. steps {
+            c.do_cycle();
+        }
+        c
+    }
+
+    #[test]
+    fn pop_af_masks_f() {
+        // LD BC,FFFF; PUSH BC; POP AF; PUSH AF; POP DE
+        let cart = mbc::get_mbc(rom_with_code(&[0x01, 0xFF, 0xFF, 0xC5, 0xF1, 0xF5, 0xD1]), true).unwrap();
+        let mut c = CPU::new(cart, None).unwrap();
+        for _ in 0 .. 3 {
+            c.do_cycle();
+        }
+        assert_eq!(c.reg.af(), 0xFFF0);
+        for _ in 0 .. 2 {
+            c.do_cycle();
+        }
+        assert_eq!(c.reg.de(), 0xFFF0);
+    }
+
+    #[test]
+    fn pc_wraps_around() {
+        let mut romdata = rom_with_code(&[]);
+        romdata[0x0000] = 0x42;
+        let mut c = CPU::new(mbc::get_mbc(romdata, true).unwrap(), None).unwrap();
+        c.ime = false;
+
+        // LD A,42 with the opcode in IE and the operand at 0000
+        c.mmu.wb(0xFFFF, 0x3E);
+        c.reg.pc = 0xFFFF;
+        c.do_cycle();
+        assert_eq!(c.reg.a, 0x42);
+        assert_eq!(c.reg.pc, 0x0001);
+
+        // JR +1 from FFFE ends up at 0001
+        c.mmu.wb(0xFFFE, 0x18);
+        c.mmu.wb(0xFFFF, 0x01);
+        c.reg.pc = 0xFFFE;
+        c.do_cycle();
+        assert_eq!(c.reg.pc, 0x0001);
+
+        // JP NZ not taken at FFFE skips the operand at FFFF-0000
+        c.mmu.wb(0xFFFE, 0xC2);
+        c.reg.flag(Z, true);
+        c.reg.pc = 0xFFFE;
+        c.do_cycle();
+        assert_eq!(c.reg.pc, 0x0001);
+    }
+
+    #[test]
+    fn sp_wraps_around() {
+        // PUSH BC; POP DE
+        let mut c = CPU::new(mbc::get_mbc(rom_with_code(&[0xC5, 0xD1]), true).unwrap(), None).unwrap();
+        c.ime = false;
+        c.reg.setbc(0x1234);
+        c.reg.sp = 0x0001;
+        c.add_watchpoint(0x0000, false, true);
+        c.do_cycle();
+        assert_eq!(c.take_debug_stop(), Some(DebugStop::Write { address: 0x0000, value: 0x12, pc: 0x0100 }));
+        assert_eq!(c.mmu.inte, 0x34);
+        assert_eq!(c.reg.sp, 0xFFFF);
+
+        // The write to ROM was ignored, so the high byte reads back as the ROM byte
+        c.resume();
+        c.do_cycle();
+        assert_eq!(c.reg.de(), 0x0034);
+        assert_eq!(c.reg.sp, 0x0001);
+    }
+
+    #[test]
+    fn illegal_opcode_locks() {
+        // LD A,91; LDH (40),A; DB D3; INC B
+        let cart = mbc::get_mbc(rom_with_code(&[0x3E, 0x91, 0xE0, 0x40, 0xD3, 0x04]), true).unwrap();
+        let mut c = CPU::new(cart, None).unwrap();
+        for _ in 0 .. 3 {
+            c.do_cycle();
+        }
+        assert_eq!(c.locked(), Some(0x0104));
+
+        // Interrupts do not wake it, but the PPU keeps running
+        c.mmu.inte = 0x01;
+        let mut ticks = 0;
+        while ticks < 70224 * 2 {
+            ticks += c.do_cycle();
+        }
+        assert_eq!(c.reg.pc, 0x0105);
+        assert_eq!(c.reg.b, 0);
+        assert!(c.mmu.intf & 0x01 != 0);
+    }
+
+    #[test]
+    fn ei_delay() {
+        // EI; INC B: the interrupt comes after INC B
+        let mut c = cpu_with_pending_interrupt(&[0xFB, 0x04]);
+        c.do_cycle();
+        assert!(!c.ime);
+        c.do_cycle();
+        assert!(c.ime);
+        assert_eq!((c.reg.b, c.reg.c), (1, 0));
+        c.do_cycle();
+        assert_eq!(c.reg.pc, 0x0050);
+    }
+
+    #[test]
+    fn ei_di() {
+        // EI; DI; INC B; JR -3: the DI cancels the EI
+        let c = run_with_pending_interrupt(&[0xFB, 0xF3, 0x04, 0x18, 0xFD], 20);
+        assert!(!c.ime);
+        assert_eq!(c.reg.c, 0);
+    }
+
+    #[test]
+    fn ei_ei() {
+        // EI; EI; INC B: IME is set after the second EI
+        let mut c = cpu_with_pending_interrupt(&[0xFB, 0xFB, 0x04]);
+        c.do_cycle();
+        assert!(!c.ime);
+        c.do_cycle();
+        assert!(c.ime);
+        c.do_cycle();
+        assert_eq!(c.reg.pc, 0x0050);
+        assert_eq!(c.reg.b, 0);
+    }
+
+    #[test]
+    fn ei_halt() {
+        // EI; HALT: the interrupt comes right after the HALT, and returns to it
+        let mut c = cpu_with_pending_interrupt(&[0xFB, 0x76, 0x04]);
+        c.do_cycle();
+        assert!(!c.ime);
+        c.do_cycle();
+        assert!(c.ime);
+        c.do_cycle();
+        assert_eq!(c.reg.pc, 0x0050);
+        assert_eq!(c.mmu.rb(c.reg.sp), 0x0C);
+        assert_eq!(c.mmu.rb(c.reg.sp + 1), 0x01);
+    }
+
+    #[test]
+    fn ei_reti() {
+        // LD HL,0200; PUSH HL; EI; RETI: RETI sets IME at once, so the interrupt comes before 0200
+        let mut c = cpu_with_pending_interrupt(&[0x21, 0x00, 0x02, 0xE5, 0xFB, 0xD9]);
+        for _ in 0 .. 4 {
+            c.do_cycle();
+        }
+        assert!(c.ime);
+        assert_eq!(c.reg.pc, 0x0200);
+        c.do_cycle();
+        assert_eq!(c.reg.pc, 0x0050);
+        assert_eq!(c.mmu.rb(c.reg.sp), 0x00);
+        assert_eq!(c.mmu.rb(c.reg.sp + 1), 0x02);
+    }
+
+    // Runs the code at 010A after enabling only VBlank, with IME off and IF clear. The VBlank
+    // handler increments C and returns.
+    fn run_halt(code: &[u8], cycles: usize) -> CPU<'static> {
+        let setup = [
+            0xF3,             // DI
+            0x01, 0x00, 0x00, // LD BC,0000
+            0x3E, 0x01,       // LD A,01
+            0xE0, 0xFF,       // LDH (FF),A
+            0xAF,             // XOR A
+            0xE0, 0x0F,       // LDH (0F),A
+        ];
+        let mut romdata = rom_with_code(&[&setup[..], code].concat());
+        romdata[0x0040] = 0x0C;
+        romdata[0x0041] = 0xC9;
+
+        let cart = mbc::get_mbc(romdata, true).unwrap();
+        let mut c = CPU::new(cart, None).unwrap();
+        for _ in 0 .. 6 + cycles {
+            c.do_cycle();
+        }
+        c
+    }
+
+    #[test]
+    fn halt_wake() {
+        const FRAME: usize = 70224 / 4;
+
+        // IME off, nothing pending: HALT; INC B; JR -2 sleeps until VBlank, then goes on without
+        // servicing it or clearing IF
+        let c = run_halt(&[0x76, 0x04, 0x18, 0xFE], 1000);
+        assert!(c.halted);
+        let c = run_halt(&[0x76, 0x04, 0x18, 0xFE], FRAME);
+        assert_eq!((c.reg.b, c.reg.c), (1, 0));
+        assert_eq!(c.mmu.intf & 0x01, 0x01);
+
+        // IME off, VBlank pending: LD A,01; LDH (0F),A; HALT; INC B; JR -2 does not halt, and
+        // INC B runs twice because of the halt bug
+        let c = run_halt(&[0x3E, 0x01, 0xE0, 0x0F, 0x76, 0x04, 0x18, 0xFE], 1000);
+        assert!(!c.halted);
+        assert_eq!((c.reg.b, c.reg.c), (2, 0));
+        assert_eq!(c.mmu.intf & 0x01, 0x01);
+
+        // IME on, nothing pending: EI; HALT; INC B; JR -2 sleeps until VBlank, which is serviced
+        let c = run_halt(&[0xFB, 0x76, 0x04, 0x18, 0xFE], 1000);
+        assert!(c.halted);
+        let c = run_halt(&[0xFB, 0x76, 0x04, 0x18, 0xFE], FRAME);
+        assert_eq!((c.reg.b, c.reg.c), (1, 1));
+        assert_eq!(c.mmu.intf & 0x01, 0x00);
+
+        // IME on, VBlank pending: LD A,01; LDH (0F),A; EI; HALT; INC B; JR -2 services it at
+        // once. The handler returns with IME off, so the HALT then sleeps until the next VBlank
+        // and goes on without servicing it.
+        let c = run_halt(&[0x3E, 0x01, 0xE0, 0x0F, 0xFB, 0x76, 0x04, 0x18, 0xFE], 1000);
+        assert!(c.halted);
+        assert_eq!((c.reg.b, c.reg.c), (0, 1));
+        let c = run_halt(&[0x3E, 0x01, 0xE0, 0x0F, 0xFB, 0x76, 0x04, 0x18, 0xFE], FRAME);
+        assert_eq!((c.reg.b, c.reg.c), (1, 1));
+        assert_eq!(c.mmu.intf & 0x01, 0x01);
+    }
+
+    #[test]
+    fn halt_bug() {
+        // HALT; INC B; JR -2: INC B runs twice
+        let c = run_with_pending_interrupt(&[0x76, 0x04, 0x18, 0xFE], 20);
+        assert_eq!((c.reg.b, c.reg.c), (2, 0));
+        assert_eq!(c.reg.pc, 0x010D);
+    }
+
+    #[test]
+    fn halt_bug_nested() {
+        // HALT; HALT; INC B: the second HALT is read over and over
+        let c = run_with_pending_interrupt(&[0x76, 0x76, 0x04], 20);
+        assert_eq!((c.reg.b, c.reg.c), (0, 0));
+        assert_eq!(c.reg.pc, 0x010C);
+
+        // HALT; EI; INC B: EI runs twice, and the interrupt fires after INC B
+        let c = run_with_pending_interrupt(&[0x76, 0xFB, 0x04, 0x18, 0xFE], 20);
+        assert_eq!((c.reg.b, c.reg.c), (1, 1));
+
+        // EI; HALT; INC B: the interrupt returns to the HALT, which halts as nothing is pending
+        let c = run_with_pending_interrupt(&[0xFB, 0x76, 0x04], 20);
+        assert_eq!((c.reg.b, c.reg.c), (0, 1));
+        assert_eq!(c.reg.pc, 0x010D);
+    }
+
+    #[test]
+    fn stop_switches_speed() {
+        // LD A,01; LDH (4D),A; STOP; JR -4
+        let mut romdata = rom_with_code(&[0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00, 0x18, 0xFC]);
+        romdata[0x0143] = 0x80;
+        let cart = mbc::get_mbc(romdata, true).unwrap();
+        let mut c = CPU::new_cgb(cart, None).unwrap();
+        for _ in 0 .. 3 {
+            c.do_cycle();
+        }
+        assert_eq!(c.mmu.rb(0xFF4D), 0x7F);
+
+        // The speed only changes after the pause
+        for _ in 0 .. super::SPEED_SWITCH_PAUSE - 1 {
+            c.do_cycle();
+        }
+        assert_eq!(c.mmu.rb(0xFF4D), 0x7F);
+        assert_eq!(c.reg.pc, 0x0106);
+        c.do_cycle();
+        assert_eq!(c.mmu.rb(0xFF4D), 0xFE);
+        assert_eq!(c.mmu.rb(0xFF04), 0);
+
+        // A STOP without a request stops until a key is pressed
+        for _ in 0 .. 10 {
+            c.do_cycle();
+        }
+        assert_eq!(c.mmu.rb(0xFF4D), 0xFE);
+        assert_eq!(c.reg.pc, 0x0106);
+    }
+
+    #[test]
+    fn stop_waits_for_selected_key() {
+        // LD A,20; LDH (00),A; STOP; INC B; JR -2
+        let code = [0x3E, 0x20, 0xE0, 0x00, 0x10, 0x00, 0x04, 0x18, 0xFE];
+        let cart = mbc::get_mbc(rom_with_code(&code), true).unwrap();
+        let mut c = CPU::new(cart, None).unwrap();
+        c.reg.b = 0;
+        c.mmu.inte = 0;
+        for _ in 0 .. 100 {
+            c.do_cycle();
+        }
+        assert_eq!(c.reg.b, 0);
+
+        // The action buttons are not selected
+        c.mmu.keypad.keydown(KeypadKey::A);
+        for _ in 0 .. 100 {
+            c.do_cycle();
+        }
+        assert_eq!(c.reg.b, 0);
+
+        // Wakes without the joypad interrupt being enabled
+        c.mmu.keypad.keydown(KeypadKey::Right);
+        for _ in 0 .. 100 {
+            c.do_cycle();
+        }
+        assert!(c.reg.b > 0);
+    }
+
+    // Counts the instructions while LY advances by 10 lines and DIV by 10 steps, running NOPs
+    fn instructions_per_10_lines_and_divs(double_speed: bool) -> (u32, u32) {
+        // LD A,01; LDH (4D),A; STOP
+        let mut romdata = rom_with_code(&[0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00]);
+        romdata[0x0143] = 0x80;
+        // Replace STOP with a NOP for single speed
+        if !double_speed {
+            romdata[0x0104] = 0x00;
+        }
+        let cart = mbc::get_mbc(romdata, true).unwrap();
+        let mut c = CPU::new_cgb(cart, None).unwrap();
+        for _ in 0 .. 3 + super::SPEED_SWITCH_PAUSE {
+            c.do_cycle();
+        }
+        assert_eq!(c.mmu.rb(0xFF4D) & 0x80 == 0x80, double_speed);
+
+        let mut count = |address: u16| {
+            let start = c.mmu.rb(address);
+            while c.mmu.rb(address) == start {
+                c.do_cycle();
+            }
+            let start = c.mmu.rb(address);
+            let mut instructions = 0;
+            while c.mmu.rb(address) != start.wrapping_add(10) {
+                c.do_cycle();
+                instructions += 1;
+            }
+            instructions
+        };
+        (count(0xFF44), count(0xFF04))
+    }
+
+    #[test]
+    fn double_speed_timing() {
+        // A line is 456 dots and DIV steps every 256 CPU clocks
+        assert_eq!(instructions_per_10_lines_and_divs(false), (1140, 640));
+        assert_eq!(instructions_per_10_lines_and_divs(true), (2280, 640));
+    }
+
+    // JP <origin>, then at origin DI; LD SP,0000; LD A,04; LDH (FF),A; LD A,<if>; LDH (0F),A; EI;
+    // NOP. The push of the high byte of PC writes the high byte of origin to IE.
+    fn dispatch_with_ie_push(intf: u8, origin: u16) -> (CPU<'static>, u32) {
+        let code = [0xF3, 0x31, 0x00, 0x00, 0x3E, 0x04, 0xE0, 0xFF, 0x3E, intf, 0xE0, 0x0F, 0xFB, 0x00];
+        let mut romdata = rom_with_code(&[0xC3, origin as u8, (origin >> 8) as u8]);
+        romdata[origin as usize .. origin as usize + code.len()].copy_from_slice(&code);
+        let cart = mbc::get_mbc(romdata, true).unwrap();
+        let mut c = CPU::new(cart, None).unwrap();
+        for _ in 0 .. 9 {
+            c.do_cycle();
+        }
+        let ticks = c.do_cycle();
+        (c, ticks)
+    }
+
+    #[test]
+    fn interrupt_dispatch() {
+        // The timer interrupt is dispatched in 5 M-cycles
+        let code = [0xF3, 0x3E, 0x04, 0xE0, 0xFF, 0xE0, 0x0F, 0xFB, 0x00];
+        let cart = mbc::get_mbc(rom_with_code(&code), true).unwrap();
+        let mut c = CPU::new(cart, None).unwrap();
+        for _ in 0 .. 6 {
+            c.do_cycle();
+        }
+        assert_eq!(c.do_cycle(), 20);
+        assert_eq!(c.reg.pc, 0x0050);
+        assert_eq!(c.mmu.intf & 0x1F, 0);
+
+        // IE still enables the timer, which is dispatched as usual
+        let (c, ticks) = dispatch_with_ie_push(0x04, 0x0400);
+        assert_eq!(ticks, 20);
+        assert_eq!(c.reg.pc, 0x0050);
+        assert_eq!(c.mmu.inte, 0x04);
+        assert_eq!(c.mmu.intf & 0x1F, 0);
+
+        // IE no longer enables the timer, so the dispatch is cancelled
+        let (mut c, ticks) = dispatch_with_ie_push(0x04, 0x0300);
+        assert_eq!(ticks, 20);
+        assert_eq!(c.reg.pc, 0x0000);
+        assert_eq!(c.mmu.inte, 0x03);
+        assert_eq!(c.mmu.intf & 0x1F, 0x04);
+        assert_eq!(c.mmu.rb(0xFFFE), 0x0E);
+
+        // IE now enables VBlank instead, which is dispatched
+        let (c, _) = dispatch_with_ie_push(0x05, 0x0300);
+        assert_eq!(c.reg.pc, 0x0040);
+        assert_eq!(c.mmu.intf & 0x1F, 0x04);
+    }
+
+    #[test]
+    fn interrupt_priority() {
+        // Every handler is a RETI, and all five interrupts are pending at once
+        let mut romdata = rom_with_code(&[]);
+        for vector in [0x40, 0x48, 0x50, 0x58, 0x60] {
+            romdata[vector] = 0xD9;
+        }
+        let mut c = CPU::new(mbc::get_mbc(romdata, true).unwrap(), None).unwrap();
+        // With the screen off, nothing raises any more interrupts
+        c.mmu.wb(0xFF40, 0x00);
+        c.mmu.inte = 0x1F;
+        c.mmu.intf = 0x1F;
+
+        for (vector, remaining) in [(0x40, 0x1E), (0x48, 0x1C), (0x50, 0x18), (0x58, 0x10), (0x60, 0x00)] {
+            assert_eq!(c.do_cycle(), 20);
+            assert_eq!(c.reg.pc, vector);
+            assert_eq!(c.mmu.intf & 0x1F, remaining);
+            c.do_cycle();
+            assert_eq!(c.reg.pc, 0x0100);
+            assert_eq!(c.mmu.intf & 0x1F, remaining);
+        }
+        c.do_cycle();
+        assert_eq!(c.reg.pc, 0x0101);
+    }
+
+    fn blank_cpu() -> CPU<'static> {
+        let cart = mbc::get_mbc(rom_with_code(&[]), true).unwrap();
+        CPU::new(cart, None).unwrap()
+    }
+
+    // Runs every instruction with all flags clear and all set, which takes every conditional
+    // one both ways, and compares its duration with the table
+    #[test]
+    fn opcode_table_cycles() {
+        for cb in [false, true] {
+            for op in 0 ..= 255u8 {
+                let code = match cb {
+                    false => [op, 0x00, 0xC0],
+                    true => [0xCB, op, 0x00],
+                };
+                let entry = match cb {
+                    false => &OPCODES[op as usize],
+                    true => &CB_OPCODES[op as usize],
+                };
+                if !cb && op == 0xCB {
+                    continue;
+                }
+                let mut durations = Vec::new();
+                for f in [0x00, 0xF0] {
+                    let cart = mbc::get_mbc(rom_with_code(&code), true).unwrap();
+                    let mut c = CPU::new(cart, None).unwrap();
+                    c.reg.setaf(f);
+                    c.reg.sp = 0xDFF0;
+                    c.reg.sethl(0xC000);
+                    durations.push(c.do_cycle() / 4);
+                }
+                durations.sort();
+                assert_eq!(durations, [entry.cycles, entry.cycles_taken], "opcode {}{:02X}", if cb { "CB " } else { "" }, op);
+            }
+        }
+    }
+
+    // DAA as documented, returning A and C. H is always cleared and Z follows A.
+    fn daa_reference(a: u8, n: bool, h: bool, c: bool) -> (u8, bool) {
+        let low_digit_invalid = a & 0x0F > 0x09;
+        match n {
+            false => {
+                let carry = c || a > 0x99;
+                let adjust = (if carry { 0x60 } else { 0 }) | (if h || low_digit_invalid { 0x06 } else { 0 });
+                (a.wrapping_add(adjust), carry)
+            },
+            true => {
+                let adjust = (if c { 0x60 } else { 0 }) | (if h { 0x06 } else { 0 });
+                (a.wrapping_sub(adjust), c)
+            },
+        }
+    }
+
+    #[test]
+    fn daa_all_inputs() {
+        let mut c = blank_cpu();
+        for a in 0 ..= 0xFF {
+            for flags in 0 .. 8 {
+                let (n, h, carry) = (flags & 4 != 0, flags & 2 != 0, flags & 1 != 0);
+                c.reg.a = a;
+                c.reg.flag(N, n);
+                c.reg.flag(H, h);
+                c.reg.flag(C, carry);
+                c.alu_daa();
+
+                let (expected, expected_carry) = daa_reference(a, n, h, carry);
+                let got = (c.reg.a, c.reg.getflag(Z), c.reg.getflag(N), c.reg.getflag(H), c.reg.getflag(C));
+                assert_eq!(got, (expected, expected == 0, n, false, expected_carry), "A={:02X} N={} H={} C={}", a, n, h, carry);
+            }
+        }
+    }
+
+    #[test]
+    fn daa_decimal_arithmetic() {
+        let bcd = |v: u32| (((v / 10) << 4) | (v % 10)) as u8;
+        let mut c = blank_cpu();
+        for x in 0 .. 100 {
+            for y in 0 .. 100 {
+                for &carry in [false, true].iter() {
+                    let carry_in = carry as u32;
+
+                    c.reg.a = bcd(x);
+                    c.reg.flag(C, carry);
+                    c.alu_add(bcd(y), true);
+                    c.alu_daa();
+                    assert_eq!((c.reg.a, c.reg.getflag(C)), (bcd((x + y + carry_in) % 100), x + y + carry_in >= 100));
+
+                    c.reg.a = bcd(x);
+                    c.reg.flag(C, carry);
+                    c.alu_sub(bcd(y), true);
+                    c.alu_daa();
+                    assert_eq!((c.reg.a, c.reg.getflag(C)), (bcd((x + 200 - y - carry_in) % 100), x < y + carry_in));
+                }
+            }
+        }
+    }
+
+    // Runs NOPs and then LDH A,(44) from WRAM, starting right after LY changed
+    fn read_ly_after_nops(nops: u16) -> (u8, u8) {
+        let mut c = blank_cpu();
+        for i in 0 .. nops {
+            c.mmu.wb(0xC000 + i, 0x00);
+        }
+        c.mmu.wb(0xC000 + nops, 0xF0);
+        c.mmu.wb(0xC001 + nops, 0x44);
+
+        let ly = c.mmu.rb(0xFF44);
+        while c.mmu.rb(0xFF44) == ly {
+            c.do_cycle();
+        }
+        let ly = c.mmu.rb(0xFF44);
+        c.reg.pc = 0xC000;
+        for _ in 0 .. nops + 1 {
+            c.do_cycle();
+        }
+        (ly, c.reg.a)
+    }
+
+    // WRAM at C100 and C200 holds two different patterns
+    fn oamdma_cpu() -> CPU<'static> {
+        let mut c = blank_cpu();
+        for i in 0 .. 0xA0 {
+            c.mmu.wb(0xC100 + i, i as u8);
+            c.mmu.wb(0xC200 + i, 0xFF - i as u8);
+        }
+        c.mmu.wb(0xC000, 0x12);
+        c
+    }
+
+    fn oam(c: &mut CPU) -> Vec<u8> {
+        (0xFE00 .. 0xFEA0).map(|a| c.mmu.rb(a)).collect()
+    }
+
+    #[test]
+    fn oamdma_blocks_bus() {
+        let mut c = oamdma_cpu();
+        let code = [
+            0x3E, 0xC1,       // FF80 LD A,C1
+            0xE0, 0x46,       // FF82 LDH (46),A
+            0xFA, 0x00, 0xC0, // FF84 LD A,(C000)
+            0x47,             // FF87 LD B,A
+            0x3E, 0x28,       // FF88 LD A,28
+            0x3D,             // FF8A DEC A
+            0x20, 0xFD,       // FF8B JR NZ,FF8A
+            0xFA, 0x00, 0xC0, // FF8D LD A,(C000)
+            0x18, 0xFE,       // FF90 JR FF90
+        ];
+        for (i, &b) in code.iter().enumerate() {
+            c.mmu.wb(0xFF80 + i as u16, b);
+        }
+        c.reg.pc = 0xFF80;
+        while c.reg.pc != 0xFF90 {
+            c.do_cycle();
+        }
+        assert_eq!((c.reg.b, c.reg.a), (0xFF, 0x12));
+        assert_eq!(oam(&mut c), (0 .. 0xA0).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn oamdma_timing() {
+        let mut c = oamdma_cpu();
+        c.mmu.wb(0xFF46, 0xC1);
+        c.mmu.do_cycle(4);
+        // The start up M-cycle leaves the bus alone, then 160 M-cycles copy
+        let blocked: Vec<bool> = (0 .. 200).map(|_| {
+            let blocked = c.mmu.cpu_rb(0xC000) == 0xFF;
+            c.mmu.do_cycle(4);
+            blocked
+        }).collect();
+        assert_eq!(blocked.iter().position(|&b| b), Some(1));
+        assert_eq!(blocked.iter().filter(|&&b| b).count(), 160);
+        // HRAM and the I/O registers stay reachable
+        c.mmu.wb(0xFF46, 0xC1);
+        c.mmu.do_cycle(8);
+        c.mmu.cpu_wb(0xFF80, 0x34);
+        c.mmu.cpu_wb(0xC000, 0x56);
+        assert_eq!((c.mmu.cpu_rb(0xFF80), c.mmu.cpu_rb(0xFF47), c.mmu.rb(0xC000)), (0x34, 0xFC, 0x12));
+    }
+
+    #[test]
+    fn oamdma_restart_and_echo() {
+        let mut c = oamdma_cpu();
+        c.mmu.wb(0xFF46, 0xC1);
+        c.mmu.do_cycle(4 * 52);
+        // The first DMA keeps copying until the second one has started
+        c.mmu.wb(0xFF46, 0xE2);
+        c.mmu.do_cycle(4 * 2);
+        assert_eq!(c.mmu.cpu_rb(0xC000), 0xFF);
+        c.mmu.do_cycle(4 * 160);
+        assert_eq!(c.mmu.cpu_rb(0xC000), 0x12);
+        assert_eq!(oam(&mut c), (0 .. 0xA0).map(|i| 0xFF - i).collect::<Vec<u8>>());
+
+        c.mmu.wb(0xFF46, 0xC1);
+        c.mmu.do_cycle(4 * 52);
+        c.mmu.wb(0xFF46, 0xC2);
+        c.mmu.do_cycle(4);
+        let oam = oam(&mut c);
+        assert_eq!((oam[0], oam[50], oam[51]), (0x00, 50, 0xFF - 51));
+    }
+
+    // A CGB game with a different byte at every address of C000-C0FF
+    fn vramdma_cpu() -> CPU<'static> {
+        let mut romdata = rom_with_code(&[]);
+        romdata[0x0143] = 0x80;
+        let mut c = CPU::new_cgb(mbc::get_mbc(romdata, true).unwrap(), None).unwrap();
+        for i in 0 .. 0x100 {
+            c.mmu.wb(0xC000 + i, i as u8 ^ 0x5A);
+        }
+        c
+    }
+
+    fn start_vramdma(c: &mut CPU, src: u16, dst: u16, control: u8) {
+        c.mmu.wb(0xFF51, (src >> 8) as u8);
+        c.mmu.wb(0xFF52, src as u8);
+        c.mmu.wb(0xFF53, (dst >> 8) as u8);
+        c.mmu.wb(0xFF54, dst as u8);
+        c.mmu.wb(0xFF55, control);
+    }
+
+    fn vram(c: &mut CPU, address: u16, len: u16) -> Vec<u8> {
+        (address .. address + len).map(|a| c.mmu.rb(a)).collect()
+    }
+
+    #[test]
+    fn gdma() {
+        let mut c = vramdma_cpu();
+        start_vramdma(&mut c, 0xC000, 0x8100, 0x03);
+        // 8 M-cycles per block, on top of the one that was ticked
+        assert_eq!(c.mmu.do_cycle(4), 4 + 4 * 32);
+        assert_eq!(c.mmu.rb(0xFF55), 0xFF);
+        assert_eq!(vram(&mut c, 0x8100, 0x40), (0 .. 0x40).map(|i| i ^ 0x5A).collect::<Vec<u8>>());
+        assert_eq!(c.mmu.rb(0x8140), 0x00);
+
+        // The destination wraps around, and the next transfer continues after the last one
+        start_vramdma(&mut c, 0xC000, 0x9FF0, 0x00);
+        c.mmu.do_cycle(4);
+        c.mmu.wb(0xFF55, 0x00);
+        c.mmu.do_cycle(4);
+        assert_eq!((c.mmu.rb(0x9FF0), c.mmu.rb(0x8000)), (0x5A, 0x10 ^ 0x5A));
+        assert_eq!(c.mmu.rb(0xFF51), 0xFF);
+
+        // Double speed takes as long, in twice the M-cycles
+        c.mmu.wb(0xFF4D, 0x01);
+        c.mmu.switch_speed();
+        start_vramdma(&mut c, 0xC000, 0x8000, 0x01);
+        assert_eq!(c.mmu.do_cycle(4), 2 + 2 * 32);
+    }
+
+    #[test]
+    fn hdma() {
+        let mut c = vramdma_cpu();
+        start_vramdma(&mut c, 0xC000, 0x8000, 0x82);
+        assert_eq!(c.mmu.rb(0xFF55), 0x02);
+
+        // A block is copied at the start of each HBlank
+        let mut lines = Vec::new();
+        while c.mmu.rb(0xFF55) != 0xFF {
+            let len = c.mmu.rb(0xFF55);
+            c.mmu.do_cycle(4);
+            if c.mmu.rb(0xFF55) != len {
+                lines.push((c.mmu.rb(0xFF44), c.mmu.rb(0xFF41) & 0x03));
+            }
+        }
+        assert_eq!(lines, [(0, 0), (1, 0), (2, 0)]);
+        assert_eq!(vram(&mut c, 0x8000, 0x30), (0 .. 0x30).map(|i| i ^ 0x5A).collect::<Vec<u8>>());
+
+        // Stopping it keeps the remaining length
+        start_vramdma(&mut c, 0xC000, 0x8000, 0x85);
+        while c.mmu.rb(0xFF55) == 0x05 {
+            c.mmu.do_cycle(4);
+        }
+        c.mmu.wb(0xFF55, 0x00);
+        for _ in 0 .. 1000 {
+            c.mmu.do_cycle(4);
+        }
+        assert_eq!(c.mmu.rb(0xFF55), 0x84);
+
+        // With the LCD off the first block is copied right away
+        c.mmu.wb(0xFF40, 0x00);
+        start_vramdma(&mut c, 0xC000, 0x8800, 0x81);
+        c.mmu.do_cycle(4);
+        assert_eq!(c.mmu.rb(0xFF55), 0x00);
+        assert_eq!(c.mmu.rb(0x8800), 0x5A);
+    }
+
+    #[test]
+    fn reads_happen_mid_instruction() {
+        // A line takes 114 M-cycles and the read is in the third M-cycle of LDH A,(n)
+        let (ly, read) = read_ly_after_nops(111);
+        assert_eq!(read, ly);
+        let (ly, read) = read_ly_after_nops(112);
+        assert_eq!(read, ly + 1);
+    }
+
+    struct SharedBuffer(::std::sync::Arc<::std::sync::Mutex<Vec<u8>>>);
+
+    impl ::std::io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> ::std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn trace_log() {
+        let code = [
+            0xF0, 0x44, // LDH A,(44)
+            0x00,       // NOP
+            0x18, 0xFE, // JR -2
+        ];
+        let cart = mbc::get_mbc(rom_with_code(&code), true).unwrap();
+        let mut c = CPU::new(cart, None).unwrap();
+        let buffer = ::std::sync::Arc::new(::std::sync::Mutex::new(Vec::new()));
+        c.start_trace(super::CpuTrace::new(Box::new(SharedBuffer(buffer.clone())), Some(4), false), true);
+        for _ in 0 .. 10 {
+            c.do_cycle();
+        }
+
+        // The trace stops at the limit, and LY reads normally again
+        assert!(c.trace.is_none());
+        assert_ne!(c.mmu.rb(0xFF44), 0x90);
+
+        let log = String::from_utf8(buffer.lock().unwrap().clone()).unwrap();
+        assert_eq!(log,
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:F0,44,00,18\n\
+             A:90 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0102 PCMEM:00,18,FE,00\n\
+             A:90 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0103 PCMEM:18,FE,00,00\n\
+             A:90 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0103 PCMEM:18,FE,00,00\n");
+
+        // With the disassembly, the same lines end with the instruction
+        let cart = mbc::get_mbc(rom_with_code(&code), true).unwrap();
+        let mut c = CPU::new(cart, None).unwrap();
+        let disasm_buffer = ::std::sync::Arc::new(::std::sync::Mutex::new(Vec::new()));
+        c.start_trace(super::CpuTrace::new(Box::new(SharedBuffer(disasm_buffer.clone())), Some(3), true), true);
+        for _ in 0 .. 10 {
+            c.do_cycle();
+        }
+        let disasm_log = String::from_utf8(disasm_buffer.lock().unwrap().clone()).unwrap();
+        assert_eq!(disasm_log,
+            "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:F0,44,00,18 LDH A,($FF00+$44)\n\
+             A:90 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0102 PCMEM:00,18,FE,00 NOP\n\
+             A:90 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0103 PCMEM:18,FE,00,00 JR $0103\n");
+    }
 }
@@ -0,0 +1,468 @@
+use crate::register::Registers;
+use std::fmt;
+
+const LD_B_B: u8 = 0x40;
+
+// Why the debugger stopped the CPU. The CPU always stops on an instruction boundary.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum DebugStop {
+    Breakpoint(u16),
+    // LD B,B, with soft breakpoints enabled
+    SoftBreakpoint(u16),
+    Read { address: u16, value: u8, pc: u16 },
+    Write { address: u16, value: u8, pc: u16 },
+    // The end of a run to an address, a step over or a step out
+    Reached(u16),
+    // An interrupt was dispatched to this handler while stepping
+    Interrupt(u16),
+}
+
+impl fmt::Display for DebugStop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DebugStop::Breakpoint(pc) => write!(f, "Breakpoint at {:04X}", pc),
+            DebugStop::SoftBreakpoint(pc) => write!(f, "LD B,B at {:04X}", pc),
+            DebugStop::Read { address, value, pc } => write!(f, "Address {:04X} read with value {:02X} from PC {:04X}", address, value, pc),
+            DebugStop::Write { address, value, pc } => write!(f, "Address {:04X} written with value {:02X} from PC {:04X}", address, value, pc),
+            DebugStop::Reached(pc) => write!(f, "Stopped at {:04X}", pc),
+            DebugStop::Interrupt(pc) => write!(f, "Interrupt to {:04X}", pc),
+        }
+    }
+}
+
+// The Mooneye test ROMs execute LD B,B when done, with the result in the registers
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum MooneyeOutcome {
+    Pass,
+    Fail,
+    // Neither signature, so the ROM did not finish
+    Unknown,
+}
+
+impl MooneyeOutcome {
+    pub fn from_registers(reg: &Registers) -> MooneyeOutcome {
+        match [reg.b, reg.c, reg.d, reg.e, reg.h, reg.l] {
+            [3, 5, 8, 13, 21, 34] => MooneyeOutcome::Pass,
+            [0x42, 0x42, 0x42, 0x42, 0x42, 0x42] => MooneyeOutcome::Fail,
+            _ => MooneyeOutcome::Unknown,
+        }
+    }
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+enum StepTarget {
+    RunTo(u16),
+    // One instruction
+    Into { executed: bool },
+    // The return address of a CALL or RST, with the stack back at or above where it was
+    Over { pc: u16, sp: u16 },
+    // A RET that took SP above where it was
+    Out { sp: u16, ret_executed: bool },
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+struct Step {
+    target: StepTarget,
+    follow_interrupts: bool,
+    // The PC and SP to return to from the interrupt handler that is being skipped
+    interrupted: Option<(u16, u16)>,
+}
+
+impl Step {
+    // Called on every instruction boundary, returns whether the step is done
+    fn before(&mut self, pc: u16, opcode: u8, sp: u16) -> bool {
+        if let Some((return_pc, return_sp)) = self.interrupted {
+            if pc != return_pc || sp != return_sp {
+                return false;
+            }
+            self.interrupted = None;
+        }
+        match self.target {
+            StepTarget::RunTo(address) => pc == address,
+            StepTarget::Into { executed } => {
+                self.target = StepTarget::Into { executed: true };
+                executed
+            },
+            StepTarget::Over { pc: return_pc, sp: return_sp } => pc == return_pc && sp >= return_sp,
+            StepTarget::Out { sp: entry_sp, ret_executed } => {
+                self.target = StepTarget::Out { sp: entry_sp, ret_executed: is_ret(opcode) };
+                ret_executed && sp > entry_sp
+            },
+        }
+    }
+}
+
+fn is_ret(opcode: u8) -> bool {
+    matches!(opcode, 0xC0 | 0xC8 | 0xC9 | 0xD0 | 0xD8 | 0xD9)
+}
+
+// The length of a CALL or RST, which returns to the instruction after it
+fn call_len(opcode: u8) -> Option<u16> {
+    match opcode {
+        0xC4 | 0xCC | 0xCD | 0xD4 | 0xDC => Some(3),
+        _ if opcode & 0xC7 == 0xC7 => Some(1),
+        _ => None,
+    }
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+struct Watchpoint {
+    address: u16,
+    on_read: bool,
+    on_write: bool,
+}
+
+// Breakpoints and watchpoints. The CPU only has these while any are set, so they cost
+// nothing during normal play.
+#[derive(Default)]
+pub struct DebugHooks {
+    breakpoints: Vec<u16>,
+    watchpoints: Vec<Watchpoint>,
+    soft_breakpoints: bool,
+    // The PC of the instruction that is executing, to report where an access came from
+    instr_pc: u16,
+    // A breakpoint at this PC is passed once, so a resume does not stop at the same place again
+    resume_pc: Option<u16>,
+    step: Option<Step>,
+    hit: Option<DebugStop>,
+}
+
+impl DebugHooks {
+    pub fn add_breakpoint(&mut self, address: u16) {
+        if !self.breakpoints.contains(&address) {
+            self.breakpoints.push(address);
+        }
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) {
+        self.breakpoints.retain(|&a| a != address);
+    }
+
+    // Replaces an existing watchpoint on the same address
+    pub fn add_watchpoint(&mut self, address: u16, on_read: bool, on_write: bool) {
+        self.remove_watchpoint(address);
+        if on_read || on_write {
+            self.watchpoints.push(Watchpoint { address, on_read, on_write });
+        }
+    }
+
+    pub fn remove_watchpoint(&mut self, address: u16) {
+        self.watchpoints.retain(|w| w.address != address);
+    }
+
+    // LD B,B is a no-op, which debuggers and test ROMs use as a breakpoint
+    pub fn set_soft_breakpoints(&mut self, enabled: bool) {
+        self.soft_breakpoints = enabled;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.breakpoints.is_empty() && self.watchpoints.is_empty() && !self.soft_breakpoints && self.step.is_none()
+    }
+
+    // Stops when the PC reaches address, also in an interrupt handler
+    pub fn run_to(&mut self, address: u16) {
+        self.start_step(StepTarget::RunTo(address), false);
+    }
+
+    // Runs a CALL or RST until it returns, or else a single instruction. An interrupt is either
+    // followed, stopping at its handler, or run until it returns.
+    pub fn step_over(&mut self, pc: u16, opcode: u8, sp: u16, follow_interrupts: bool) {
+        let target = match call_len(opcode) {
+            Some(len) => StepTarget::Over { pc: pc.wrapping_add(len), sp },
+            None => StepTarget::Into { executed: false },
+        };
+        self.start_step(target, follow_interrupts);
+    }
+
+    // Runs until a RET returns from the current function
+    pub fn step_out(&mut self, sp: u16, follow_interrupts: bool) {
+        self.start_step(StepTarget::Out { sp, ret_executed: false }, follow_interrupts);
+    }
+
+    fn start_step(&mut self, target: StepTarget, follow_interrupts: bool) {
+        self.step = Some(Step { target, follow_interrupts, interrupted: None });
+    }
+
+    pub fn resume_at(&mut self, pc: u16) {
+        self.resume_pc = Some(pc);
+    }
+
+    // Called before the instruction at pc is executed. Any stop ends a step.
+    pub fn before(&mut self, pc: u16, opcode: u8, sp: u16) -> Option<DebugStop> {
+        self.instr_pc = pc;
+        let resumed = self.resume_pc.take() == Some(pc);
+        let step_done = self.step.as_mut().is_some_and(|step| step.before(pc, opcode, sp));
+        let stop = if resumed {
+            None
+        } else if self.breakpoints.contains(&pc) {
+            Some(DebugStop::Breakpoint(pc))
+        } else if self.soft_breakpoints && opcode == LD_B_B {
+            Some(DebugStop::SoftBreakpoint(pc))
+        } else if step_done {
+            Some(DebugStop::Reached(pc))
+        } else {
+            None
+        };
+        if stop.is_some() {
+            self.step = None;
+        }
+        stop
+    }
+
+    // Called after an interrupt was dispatched from return_pc to handler, with the return address
+    // pushed at sp
+    pub fn interrupt(&mut self, return_pc: u16, sp: u16, handler: u16) {
+        let step = match self.step {
+            Some(ref mut step) if !matches!(step.target, StepTarget::RunTo(_)) => step,
+            _ => return,
+        };
+        if step.follow_interrupts {
+            self.step = None;
+            self.hit.get_or_insert(DebugStop::Interrupt(handler));
+        } else if step.interrupted.is_none() {
+            step.interrupted = Some((return_pc, sp.wrapping_add(2)));
+        }
+    }
+
+    // Only the first access of an instruction is reported
+    pub fn read(&mut self, address: u16, value: u8) {
+        if self.hit.is_none() && self.watchpoints.iter().any(|w| w.on_read && w.address == address) {
+            self.hit = Some(DebugStop::Read { address, value, pc: self.instr_pc });
+        }
+    }
+
+    pub fn write(&mut self, address: u16, value: u8) {
+        if self.hit.is_none() && self.watchpoints.iter().any(|w| w.on_write && w.address == address) {
+            self.hit = Some(DebugStop::Write { address, value, pc: self.instr_pc });
+        }
+    }
+
+    // Returns the watchpoint or interrupt hit since the last call
+    pub fn take_hit(&mut self) -> Option<DebugStop> {
+        let hit = self.hit.take();
+        if hit.is_some() {
+            self.step = None;
+        }
+        hit
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DebugStop, MooneyeOutcome};
+    use crate::cpu::CPU;
+    use crate::mbc;
+
+    // Increments C and stores it to C000 forever
+    const CODE: [u8; 7] = [
+        0x0C,             // 0100 INC C
+        0x79,             // 0101 LD A,C
+        0xEA, 0x00, 0xC0, // 0102 LD (C000),A
+        0x18, 0xF9,       // 0105 JR 0100
+    ];
+
+    fn cpu_with_code() -> CPU<'static> {
+        let mut romdata = vec![0; 0x8000];
+        romdata[0x0100 .. 0x0100 + CODE.len()].copy_from_slice(&CODE);
+        let mut c = CPU::new(mbc::get_mbc(romdata, true).unwrap(), None).unwrap();
+        c.reg.c = 0;
+        c
+    }
+
+    fn run_until_paused(c: &mut CPU) {
+        for _ in 0 .. 100 {
+            c.do_cycle();
+            if c.paused() { return; }
+        }
+        panic!("The CPU did not pause");
+    }
+
+    #[test]
+    fn breakpoint() {
+        let mut c = cpu_with_code();
+        c.add_breakpoint(0x0102);
+        run_until_paused(&mut c);
+        assert_eq!(c.take_debug_stop(), Some(DebugStop::Breakpoint(0x0102)));
+        assert_eq!(c.reg.pc, 0x0102);
+        assert_eq!(c.reg.c, 1);
+
+        // Resuming runs the instruction at the breakpoint, and stops there again on the next pass
+        c.resume();
+        run_until_paused(&mut c);
+        assert_eq!(c.take_debug_stop(), Some(DebugStop::Breakpoint(0x0102)));
+        assert_eq!(c.reg.c, 2);
+
+        c.remove_breakpoint(0x0102);
+        assert!(!c.has_debug_hooks());
+    }
+
+    #[test]
+    fn watchpoint() {
+        let mut c = cpu_with_code();
+        c.add_watchpoint(0xC000, false, true);
+        run_until_paused(&mut c);
+        assert_eq!(c.take_debug_stop(), Some(DebugStop::Write { address: 0xC000, value: 1, pc: 0x0102 }));
+        // The CPU stops after the instruction that wrote
+        assert_eq!(c.reg.pc, 0x0105);
+
+        c.add_watchpoint(0xC000, true, false);
+        c.add_watchpoint(0x0105, true, false);
+        c.resume();
+        run_until_paused(&mut c);
+        assert_eq!(c.take_debug_stop(), Some(DebugStop::Read { address: 0x0105, value: 0x18, pc: 0x0105 }));
+
+        c.remove_watchpoint(0xC000);
+        c.remove_watchpoint(0x0105);
+        assert!(!c.has_debug_hooks());
+    }
+
+    #[test]
+    fn step() {
+        let mut c = cpu_with_code();
+        c.add_breakpoint(0x0100);
+        run_until_paused(&mut c);
+        assert_eq!(c.take_debug_stop(), Some(DebugStop::Breakpoint(0x0100)));
+
+        for pc in [0x0101, 0x0102, 0x0105, 0x0100] {
+            c.step();
+            assert_eq!(c.take_debug_stop(), None);
+            assert!(c.paused());
+            assert_eq!(c.reg.pc, pc);
+        }
+    }
+
+    // A main loop calling a function that calls another. The timer handler increments E.
+    fn cpu_with_calls() -> CPU<'static> {
+        let code: [(u16, &[u8]); 7] = [
+            (0x0050, &[0x1C, 0xD9]),       // INC E; RETI
+            (0x0100, &[0xCD, 0x10, 0x01]), // CALL 0110
+            (0x0103, &[0x0C, 0x18, 0xFA]), // INC C; JR 0100
+            (0x0110, &[0x04]),             // INC B
+            (0x0111, &[0xCD, 0x18, 0x01]), // CALL 0118
+            (0x0114, &[0x04, 0xC9]),       // INC B; RET
+            (0x0118, &[0x14, 0xC9]),       // INC D; RET
+        ];
+        let mut romdata = vec![0; 0x8000];
+        for (address, bytes) in code {
+            romdata[address as usize .. address as usize + bytes.len()].copy_from_slice(bytes);
+        }
+        let mut c = CPU::new(mbc::get_mbc(romdata, true).unwrap(), None).unwrap();
+        c.reg.b = 0;
+        c.reg.c = 0;
+        c.reg.d = 0;
+        c.reg.e = 0;
+        c.mmu.inte = 0x04;
+        c
+    }
+
+    #[test]
+    fn step_over() {
+        let mut c = cpu_with_calls();
+        c.step_over(false);
+        run_until_paused(&mut c);
+        assert_eq!(c.take_debug_stop(), Some(DebugStop::Reached(0x0103)));
+        assert_eq!((c.reg.b, c.reg.d), (2, 1));
+
+        // Anything else is a single step
+        c.step_over(false);
+        run_until_paused(&mut c);
+        assert_eq!(c.take_debug_stop(), Some(DebugStop::Reached(0x0104)));
+        assert_eq!(c.reg.c, 1);
+        assert!(!c.has_debug_hooks());
+
+        // A breakpoint inside the function ends the step there
+        c.reg.pc = 0x0100;
+        c.add_breakpoint(0x0118);
+        c.step_over(false);
+        run_until_paused(&mut c);
+        assert_eq!(c.take_debug_stop(), Some(DebugStop::Breakpoint(0x0118)));
+        c.resume();
+        run_until_paused(&mut c);
+        assert_eq!(c.take_debug_stop(), Some(DebugStop::Breakpoint(0x0118)));
+    }
+
+    #[test]
+    fn run_to_and_step_out() {
+        let mut c = cpu_with_calls();
+        c.run_to(0x0118);
+        run_until_paused(&mut c);
+        assert_eq!(c.take_debug_stop(), Some(DebugStop::Reached(0x0118)));
+
+        c.step_out(false);
+        run_until_paused(&mut c);
+        assert_eq!(c.take_debug_stop(), Some(DebugStop::Reached(0x0114)));
+        assert_eq!(c.reg.d, 1);
+
+        c.step_out(false);
+        run_until_paused(&mut c);
+        assert_eq!(c.take_debug_stop(), Some(DebugStop::Reached(0x0103)));
+        assert_eq!(c.reg.b, 2);
+        assert!(!c.has_debug_hooks());
+    }
+
+    #[test]
+    fn step_with_interrupt() {
+        // Skipped: the handler runs, then the step continues where it was interrupted
+        let mut c = cpu_with_calls();
+        c.reg.pc = 0x0103;
+        c.mmu.intf |= 0x04;
+        c.step_over(false);
+        run_until_paused(&mut c);
+        assert_eq!(c.take_debug_stop(), Some(DebugStop::Reached(0x0104)));
+        assert_eq!((c.reg.c, c.reg.e), (1, 1));
+
+        // Also when it comes during a function that is stepped over
+        c.reg.pc = 0x0100;
+        c.step_over(false);
+        c.step();
+        c.mmu.intf |= 0x04;
+        run_until_paused(&mut c);
+        assert_eq!(c.take_debug_stop(), Some(DebugStop::Reached(0x0103)));
+        assert_eq!((c.reg.b, c.reg.e), (2, 2));
+
+        // Followed: the step ends at the handler
+        c.mmu.intf |= 0x04;
+        c.step_over(true);
+        run_until_paused(&mut c);
+        assert_eq!(c.take_debug_stop(), Some(DebugStop::Interrupt(0x0050)));
+        assert_eq!(c.reg.pc, 0x0050);
+        assert!(!c.has_debug_hooks());
+    }
+
+    #[test]
+    fn soft_breakpoint() {
+        let code = [
+            0x06, 0x03, // LD B,03
+            0x0E, 0x05, // LD C,05
+            0x16, 0x08, // LD D,08
+            0x1E, 0x0D, // LD E,0D
+            0x26, 0x15, // LD H,15
+            0x2E, 0x22, // LD L,22
+            0x40,       // LD B,B
+            0x18, 0xFE, // JR -2
+        ];
+        let mut romdata = vec![0; 0x8000];
+        romdata[0x0100 .. 0x0100 + code.len()].copy_from_slice(&code);
+        let mut c = CPU::new(mbc::get_mbc(romdata, true).unwrap(), None).unwrap();
+
+        // Off by default
+        for _ in 0 .. 20 { c.do_cycle(); }
+        assert!(!c.paused());
+
+        c.reg.pc = 0x0100;
+        c.set_soft_breakpoints(true);
+        run_until_paused(&mut c);
+        assert_eq!(c.take_debug_stop(), Some(DebugStop::SoftBreakpoint(0x010C)));
+        assert_eq!(MooneyeOutcome::from_registers(&c.reg), MooneyeOutcome::Pass);
+
+        c.reg.b = 0x42;
+        assert_eq!(MooneyeOutcome::from_registers(&c.reg), MooneyeOutcome::Unknown);
+        for r in [&mut c.reg.c, &mut c.reg.d, &mut c.reg.e, &mut c.reg.h, &mut c.reg.l] {
+            *r = 0x42;
+        }
+        assert_eq!(MooneyeOutcome::from_registers(&c.reg), MooneyeOutcome::Fail);
+
+        c.set_soft_breakpoints(false);
+        assert!(!c.has_debug_hooks());
+    }
+}
@@ -1,13 +1,106 @@
-use crate::cpu::CPU;
-use crate::gbmode::GbMode;
+use crate::access::{AccessCallback, AccessHook, AccessKind, AccessSource};
+use crate::cheats::{CheatError, GameGenieCode, GameSharkCode};
+use crate::colorize::Colorization;
+use crate::cpu::{CpuState, CPU};
+use crate::debugger::DebugStop;
+use crate::gbmode::{GbMode, GbSpeed};
+use crate::gpu::{ColorCorrection, Layer, Renderer, SpriteDebugInfo};
 use crate::keypad::KeypadKey;
 use crate::printer::GbPrinter;
-use crate::mbc;
+use crate::profiler::ProfileEntry;
+use crate::register::Registers;
+use crate::sgb::{SGB_SCREEN_H, SGB_SCREEN_W};
+use crate::mbc::{self, CameraSource, CartridgeHeader};
+use crate::mmu::{MemoryRegion, BOOT_ROM_SIZE, CGB_BOOT_ROM_SIZE};
 use crate::sound;
+use crate::state::{StateError, StateReader, StateResult, StateWriter, STATE_MAGIC, STATE_VERSION};
+use crate::trace::CpuTrace;
+use crate::trap::{TrapOptions, TrapReport};
 use crate::StrResult;
+use std::sync::{Arc, Mutex};
+use std::ops::RangeInclusive;
+use std::{fmt, fs, io, path};
+
+// How often run_until_serial_match looks at the output, about once per frame
+const SERIAL_MATCH_INTERVAL: u32 = 70224;
 
 pub struct Device {
     cpu: CPU<'static>,
+    romsource: RomSource,
+    serial_capture: Option<Arc<Mutex<Vec<u8>>>>,
+    // Overrides the MBC1 multicart detection, kept for a model switch
+    multicart: Option<bool>,
+    rom_info: CartridgeHeader,
+    // Run on every start in classic or color mode, kept for a reset
+    boot_rom: Option<Vec<u8>>,
+    cgb_boot_rom: Option<Vec<u8>>,
+    colorization: Colorization,
+    // Runs classic games that support it as a Super Game Boy, kept for a reset
+    sgb: bool,
+}
+
+#[derive(Default)]
+pub struct LoadOptions {
+    // Starts as a classic Gameboy instead of a Gameboy Color
+    pub classic: bool,
+    pub skip_checksum: bool,
+    // The battery backed RAM to start with, as returned by export_save_ram
+    pub save_ram: Option<Vec<u8>>,
+    // A 256 byte DMG boot ROM to start with in classic mode, instead of the state after it ran
+    pub boot_rom: Option<Vec<u8>>,
+    // A 2304 byte CGB boot ROM to start with otherwise
+    pub cgb_boot_rom: Option<Vec<u8>>,
+    // The colors of a DMG game on a Gameboy Color without a boot ROM
+    pub colorization: Colorization,
+    // Runs a classic game that supports it as a Super Game Boy
+    pub sgb: bool,
+}
+
+// Why a ROM could not be loaded
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadError {
+    // The ROM or its save file could not be read, or the save file not moved away
+    Io(&'static str),
+    // The header names a cartridge the emulator does not support, a wrong checksum or a model
+    // the game does not run on
+    Header(&'static str),
+    // The save RAM does not have the size of the cartridge RAM
+    SaveRamSize,
+    // A DMG boot ROM that is not 256 bytes, or a CGB boot ROM that is not 2304 bytes
+    BootRomSize { cgb: bool },
+}
+
+impl LoadError {
+    pub fn message(&self) -> &'static str {
+        match *self {
+            LoadError::Io(message) | LoadError::Header(message) => message,
+            LoadError::SaveRamSize => "The save RAM does not have the size of the cartridge RAM",
+            LoadError::BootRomSize { cgb: false } => "The boot ROM must be 256 bytes",
+            LoadError::BootRomSize { cgb: true } => "The CGB boot ROM must be 2304 bytes",
+        }
+    }
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message())
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+// Remembers where the ROM came from, so the machine can be rebuilt on a model switch
+enum RomSource {
+    File(String, bool),
+    Buffer(Vec<u8>, bool),
+}
+
+fn check_boot_rom(boot_rom: &Option<Vec<u8>>, cgb: bool) -> Result<(), LoadError> {
+    let size = if cgb { CGB_BOOT_ROM_SIZE } else { BOOT_ROM_SIZE };
+    match boot_rom {
+        Some(data) if data.len() != size => Err(LoadError::BootRomSize { cgb }),
+        _ => Ok(()),
+    }
 }
 
 fn stdoutprinter(v: u8) -> Option<u8> {
@@ -20,31 +113,445 @@ fn stdoutprinter(v: u8) -> Option<u8> {
 }
 
 impl Device {
-    pub fn new(romname: &str, skip_checksum: bool) -> StrResult<Device> {
-        let cart = mbc::FileBackedMBC::new(romname.into(), skip_checksum)?;
-        CPU::new(Box::new(cart), None).map(|cpu| Device { cpu: cpu })
+    pub fn new(romname: &str, skip_checksum: bool) -> Result<Device, LoadError> {
+        Device::new_from_file(romname, LoadOptions { classic: true, skip_checksum, ..LoadOptions::default() })
     }
 
-    pub fn new_cgb(romname: &str, skip_checksum: bool) -> StrResult<Device> {
-        let cart = mbc::FileBackedMBC::new(romname.into(), skip_checksum)?;
-        CPU::new_cgb(Box::new(cart), None).map(|cpu| Device { cpu: cpu })
+    pub fn new_cgb(romname: &str, skip_checksum: bool) -> Result<Device, LoadError> {
+        Device::new_from_file(romname, LoadOptions { classic: false, skip_checksum, ..LoadOptions::default() })
     }
 
-    pub fn new_from_buffer(romdata: Vec<u8>, skip_checksum: bool) -> StrResult<Device> {
-        let cart = mbc::get_mbc(romdata, skip_checksum)?;
-        CPU::new(cart, None).map(|cpu| Device { cpu: cpu })
+    pub fn new_from_buffer(romdata: Vec<u8>, skip_checksum: bool) -> Result<Device, LoadError> {
+        Device::new_from_bytes(romdata, LoadOptions { classic: true, skip_checksum, ..LoadOptions::default() })
     }
 
-    pub fn new_cgb_from_buffer(romdata: Vec<u8>, skip_checksum: bool) -> StrResult<Device> {
-        let cart = mbc::get_mbc(romdata, skip_checksum)?;
-        CPU::new_cgb(cart, None).map(|cpu| Device { cpu: cpu })
+    pub fn new_cgb_from_buffer(romdata: Vec<u8>, skip_checksum: bool) -> Result<Device, LoadError> {
+        Device::new_from_bytes(romdata, LoadOptions { classic: false, skip_checksum, ..LoadOptions::default() })
+    }
+
+    // Loads a ROM file, with the battery backed RAM saved next to it. The save_ram of the options
+    // replaces the one in the save file.
+    pub fn new_from_file(romname: &str, options: LoadOptions) -> Result<Device, LoadError> {
+        Device::with_options(RomSource::File(romname.into(), options.skip_checksum), options)
+    }
+
+    // Loads a ROM from memory, for hosts without a file system. Nothing is saved to a file, the
+    // host gets the battery backed RAM from export_save_ram. Save RAM of the wrong size is an
+    // error, so that the host does not replace its save with empty RAM.
+    pub fn new_from_bytes(rom: Vec<u8>, options: LoadOptions) -> Result<Device, LoadError> {
+        Device::with_options(RomSource::Buffer(rom, options.skip_checksum), options)
+    }
+
+    fn with_options(romsource: RomSource, options: LoadOptions) -> Result<Device, LoadError> {
+        check_boot_rom(&options.boot_rom, false)?;
+        check_boot_rom(&options.cgb_boot_rom, true)?;
+        let mut cart = romsource.load()?;
+        if let (Some(save_ram), true) = (options.save_ram, cart.is_battery_backed()) {
+            cart.loadram(&save_ram).map_err(|_| LoadError::SaveRamSize)?;
+        }
+        let cpu = match options.classic {
+            true => CPU::new(cart, None),
+            false => CPU::new_cgb(cart, None),
+        }.map_err(LoadError::Header)?;
+        let mut device = Device::with_cpu(cpu, romsource);
+        device.set_colorization(options.colorization);
+        device.boot_rom = options.boot_rom;
+        device.cgb_boot_rom = options.cgb_boot_rom;
+        device.sgb = options.sgb;
+        if device.runs_sgb(options.classic) {
+            device.cpu.enable_sgb();
+        }
+        if let Some(boot_rom) = device.boot_rom_for(options.classic) {
+            device.cpu.map_boot_rom(boot_rom);
+        }
+        Ok(device)
+    }
+
+    fn with_cpu(mut cpu: CPU<'static>, romsource: RomSource) -> Device {
+        cpu.set_traps(TrapOptions::default());
+        // Bank 0 is mapped at 0000 on start up, and a whole header always parses
+        let header: Vec<u8> = (0 .. 0x150).map(|a| cpu.mmu.mbc.readrom(a)).collect();
+        let rom_info = CartridgeHeader::parse(&header).unwrap();
+        Device { cpu, romsource, serial_capture: None, multicart: None, rom_info, boot_rom: None, cgb_boot_rom: None, colorization: Colorization::Auto, sgb: false }
+    }
+
+    // Runs a 256 byte DMG boot ROM, or none, in classic mode from the next reset on
+    pub fn set_boot_rom(&mut self, boot_rom: Option<Vec<u8>>) -> StrResult<()> {
+        check_boot_rom(&boot_rom, false).map_err(|e| e.message())?;
+        self.boot_rom = boot_rom;
+        Ok(())
+    }
+
+    // Runs a 2304 byte CGB boot ROM, or none, in color mode from the next reset on. Without one
+    // a DMG game gets the default colors the boot ROM would give it.
+    pub fn set_cgb_boot_rom(&mut self, boot_rom: Option<Vec<u8>>) -> StrResult<()> {
+        check_boot_rom(&boot_rom, true).map_err(|e| e.message())?;
+        self.cgb_boot_rom = boot_rom;
+        Ok(())
+    }
+
+    // Picks the colors of a DMG game on a Gameboy Color without a boot ROM, kept over a reset
+    pub fn set_colorization(&mut self, colorization: Colorization) {
+        self.colorization = colorization;
+        self.cpu.mmu.set_colorization(colorization);
+    }
+
+    pub fn colorization(&self) -> Colorization {
+        self.colorization
+    }
+
+    // Runs a classic game that supports it as a Super Game Boy from the next reset on
+    pub fn set_sgb(&mut self, sgb: bool) {
+        self.sgb = sgb;
+    }
+
+    // Whether the game runs as a Super Game Boy
+    pub fn is_sgb(&self) -> bool {
+        self.cpu.mmu.sgb.is_some()
+    }
+
+    fn runs_sgb(&self, classic: bool) -> bool {
+        self.sgb && classic && self.rom_info.supports_sgb()
+    }
+
+    fn boot_rom_for(&self, classic: bool) -> Option<Vec<u8>> {
+        match classic {
+            true => self.boot_rom.clone(),
+            false => self.cgb_boot_rom.clone(),
+        }
+    }
+
+    // Whether the boot ROM is still mapped over 0000-00FF
+    pub fn boot_rom_mapped(&self) -> bool {
+        self.cpu.mmu.boot_rom_mapped()
+    }
+
+    // The cartridge header of the loaded ROM
+    pub fn rom_info(&self) -> &CartridgeHeader {
+        &self.rom_info
+    }
+
+    // Maps the ROM as an MBC1 multicart (MBC1M) or a plain MBC1, instead of detecting it from the
+    // ROM. Other cartridges ignore it.
+    pub fn set_mbc1_multicart(&mut self, multicart: bool) {
+        self.multicart = Some(multicart);
+        self.cpu.mmu.mbc.set_multicart(multicart);
+    }
+
+    // A Game Genie code as ABC-DEF or ABC-DEF-GHI, which patches the ROM until it is removed
+    pub fn add_game_genie(&mut self, code: &str) -> Result<(), CheatError> {
+        let code = GameGenieCode::parse(code)?;
+        self.cpu.mmu.cheats.add_game_genie(code);
+        Ok(())
+    }
+
+    // Returns whether the code was active
+    pub fn remove_game_genie(&mut self, code: &str) -> bool {
+        match GameGenieCode::parse(code) {
+            Ok(code) => self.cpu.mmu.cheats.remove_game_genie(code),
+            Err(_) => false,
+        }
+    }
+
+    // A GameShark code as ttvvaaaa, which writes to the memory at every VBlank until it is removed
+    pub fn add_gameshark(&mut self, code: &str) -> Result<(), CheatError> {
+        let code = GameSharkCode::parse(code)?;
+        self.cpu.mmu.cheats.add_gameshark(code);
+        Ok(())
+    }
+
+    pub fn remove_gameshark(&mut self, code: &str) -> bool {
+        match GameSharkCode::parse(code) {
+            Ok(code) => self.cpu.mmu.cheats.remove_gameshark(code),
+            Err(_) => false,
+        }
+    }
+
+    // Either kind of code, told apart by the number of digits
+    pub fn add_cheat(&mut self, code: &str) -> Result<(), CheatError> {
+        match code.chars().filter(|&c| c != '-').count() {
+            8 => self.add_gameshark(code),
+            _ => self.add_game_genie(code),
+        }
+    }
+
+    pub fn clear_cheats(&mut self) {
+        self.cpu.mmu.cheats.clear();
+    }
+
+    pub fn is_classic(&self) -> bool {
+        self.cpu.mmu.gbmode == GbMode::Classic
+    }
+
+    pub fn is_double_speed(&self) -> bool {
+        self.cpu.mmu.gbspeed() == GbSpeed::Double
+    }
+
+    // Performs a hard reset into the requested model. The cartridge RAM, serial device and
+    // audio player of the current machine are carried over to the new one.
+    pub fn switch_model(&mut self, classic: bool) -> StrResult<()> {
+        if classic && self.rom_info.is_cgb_only() {
+            return Err("This game does not work in Classic mode");
+        }
+
+        let mut cart = self.romsource.load().map_err(|e| e.message())?;
+        if cart.is_battery_backed() {
+            cart.loadram(&self.cpu.mmu.mbc.dumpram())?;
+        }
+        let mut cpu = match classic {
+            true => CPU::new(cart, None)?,
+            false => CPU::new_cgb(cart, None)?,
+        };
+
+        if let Some(multicart) = self.multicart {
+            cpu.mmu.mbc.set_multicart(multicart);
+        }
+        cpu.mmu.set_colorization(self.colorization);
+        if self.runs_sgb(classic) {
+            cpu.enable_sgb();
+        }
+        if let Some(boot_rom) = self.boot_rom_for(classic) {
+            cpu.map_boot_rom(boot_rom);
+        }
+        if let Some(source) = self.cpu.mmu.mbc.take_camera_source() {
+            cpu.mmu.mbc.set_camera_source(source);
+        }
+        cpu.set_traps(self.cpu.trap_options());
+        cpu.mmu.gpu.set_renderer(self.cpu.mmu.gpu.renderer());
+        cpu.mmu.gpu.set_dmg_palette(self.cpu.mmu.gpu.dmg_palette());
+        cpu.mmu.gpu.set_frame_blend(self.cpu.mmu.gpu.frame_blend());
+        cpu.mmu.gpu.set_color_correction(self.cpu.mmu.gpu.color_correction());
+        for layer in [Layer::Background, Layer::Window, Layer::Sprites] {
+            cpu.mmu.gpu.set_layer_visible(layer, self.cpu.mmu.gpu.layer_visible(layer));
+        }
+        cpu.mmu.gpu.set_frame_count(self.cpu.mmu.gpu.frame_count());
+        cpu.mmu.cheats = std::mem::take(&mut self.cpu.mmu.cheats);
+        cpu.mmu.set_access_hook(self.cpu.mmu.take_access_hook());
+        cpu.set_profiler(self.cpu.take_profiler());
+        cpu.mmu.serial.set_callback(self.cpu.mmu.serial.take_callback());
+        let sound = self.cpu.mmu.sound.take();
+
+        // Dropping the old machine flushes its save file
+        self.cpu = cpu;
+
+        let dmg_mode = self.cpu.mmu.gbmode == GbMode::Classic;
+        self.cpu.mmu.sound = sound.map(|s| s.reset(dmg_mode));
+        Ok(())
+    }
+
+    // Performs a hard reset, keeping the cartridge RAM, as switch_model
+    pub fn reset(&mut self) -> StrResult<()> {
+        let classic = self.is_classic();
+        self.switch_model(classic)
     }
 
     pub fn do_cycle(&mut self) -> u32 {
         self.cpu.do_cycle()
     }
 
+    pub fn set_traps(&mut self, options: TrapOptions) {
+        self.cpu.set_traps(options);
+    }
+
+    // Returns the report of a trap that paused the emulation, see TrapOptions
+    pub fn take_trap(&mut self) -> Option<TrapReport> {
+        self.cpu.take_trap()
+    }
+
+    // Logs every instruction in the Gameboy Doctor format, optionally stopping after limit instructions.
+    // With stub_ly, LY always reads as 90 so the log can be compared against the reference logs.
+    // With disasm, the disassembled instruction is added to each line.
+    pub fn start_trace(&mut self, out: Box<dyn ::std::io::Write + Send>, limit: Option<u64>, stub_ly: bool, disasm: bool) {
+        self.cpu.start_trace(CpuTrace::new(out, limit, disasm), stub_ly);
+    }
+
+    pub fn stop_trace(&mut self) {
+        self.cpu.stop_trace();
+    }
+
+    // Counts the executed instructions and their cycles per ROM bank and address, which costs
+    // nothing until started. The profile is kept over a reset.
+    pub fn start_profile(&mut self) {
+        self.cpu.start_profile();
+    }
+
+    pub fn stop_profile(&mut self) {
+        self.cpu.stop_profile();
+    }
+
+    // The top_n addresses with the most cycles, the most first
+    pub fn profile_report(&self, top_n: usize) -> Vec<ProfileEntry> {
+        self.cpu.profile_report(top_n)
+    }
+
+    // Breakpoints and watchpoints pause the emulation on an instruction boundary, see take_debug_stop
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.cpu.add_breakpoint(address);
+    }
+
+    pub fn remove_breakpoint(&mut self, address: u16) {
+        self.cpu.remove_breakpoint(address);
+    }
+
+    pub fn add_watchpoint(&mut self, address: u16, on_read: bool, on_write: bool) {
+        self.cpu.add_watchpoint(address, on_read, on_write);
+    }
+
+    pub fn remove_watchpoint(&mut self, address: u16) {
+        self.cpu.remove_watchpoint(address);
+    }
+
+    // Also stops on LD B,B, which the Mooneye test ROMs execute when they are done, see MooneyeOutcome
+    pub fn set_soft_breakpoints(&mut self, enabled: bool) {
+        self.cpu.set_soft_breakpoints(enabled);
+    }
+
+    // Resumes until the PC reaches address, then pauses with DebugStop::Reached
+    pub fn run_to(&mut self, address: u16) {
+        self.cpu.run_to(address);
+    }
+
+    // Resumes until a CALL or RST returns, or for a single instruction otherwise. With
+    // follow_interrupts an interrupt pauses at its handler, else the handler is run through.
+    pub fn step_over(&mut self, follow_interrupts: bool) {
+        self.cpu.step_over(follow_interrupts);
+    }
+
+    // Resumes until a RET returns from the current function
+    pub fn step_out(&mut self, follow_interrupts: bool) {
+        self.cpu.step_out(follow_interrupts);
+    }
+
+    // Runs until the emulation pauses, on a trap or a debugger stop, or until max_ticks have
+    // elapsed. Returns whether it paused.
+    pub fn run_until_paused(&mut self, max_ticks: u64) -> bool {
+        let mut ticks = 0;
+        while !self.cpu.paused() && ticks < max_ticks {
+            ticks += self.cpu.do_cycle() as u64;
+        }
+        self.cpu.paused()
+    }
+
+    // Returns why the debugger paused the emulation
+    pub fn take_debug_stop(&mut self) -> Option<DebugStop> {
+        self.cpu.take_debug_stop()
+    }
+
+    // Runs exactly one instruction or interrupt dispatch, also when paused, and returns the
+    // elapsed ticks. The rest of the system runs along as with do_cycle.
+    pub fn step(&mut self) -> u32 {
+        self.cpu.step()
+    }
+
+    // Runs until at least the given number of ticks have elapsed, and returns the overshoot
+    pub fn run_cycles(&mut self, ticks: u32) -> u32 {
+        self.cpu.run_cycles(ticks)
+    }
+
+    pub fn registers(&self) -> Registers {
+        self.cpu.reg
+    }
+
+    pub fn ime(&self) -> bool {
+        self.cpu.ime()
+    }
+
+    // Returns the address of the illegal opcode that locked up the CPU, until a reset. The rest of
+    // the system keeps running.
+    pub fn cpu_locked(&self) -> Option<u16> {
+        self.cpu.locked()
+    }
+
+    /// Returns the registers and interrupt state of the CPU. Together with `set_cpu_state`,
+    /// `read_memory` and `write_memory` this is enough to build a debugger.
+    ///
+    /// ```
+    /// use rboy::device::Device;
+    ///
+    /// // LD B,03; LD B,B; HALT
+    /// let mut rom = vec![0; 0x8000];
+    /// rom[0x0100 .. 0x0104].copy_from_slice(&[0x06, 0x03, 0x40, 0x76]);
+    /// let mut device = Device::new_from_buffer(rom, true).unwrap();
+    ///
+    /// // Stop on the LD B,B
+    /// device.set_soft_breakpoints(true);
+    /// assert!(device.run_until_paused(1000));
+    /// let mut state = device.cpu_state();
+    /// assert_eq!((state.pc, state.b), (0x0102, 0x03));
+    ///
+    /// // The lower nibble of F does not exist
+    /// state.f = 0xFF;
+    /// device.set_cpu_state(&state);
+    /// assert_eq!(device.cpu_state().f, 0xF0);
+    ///
+    /// device.write_memory(0xC000, 0x42);
+    /// assert_eq!(device.read_memory(0xC000), 0x42);
+    /// ```
+    pub fn cpu_state(&self) -> CpuState {
+        self.cpu.state()
+    }
+
+    // The lower nibble of F is dropped, as with POP AF
+    pub fn set_cpu_state(&mut self, state: &CpuState) {
+        self.cpu.set_state(state);
+    }
+
+    // Reads memory as the CPU sees it, without the access taking any time. Reading an I/O
+    // register has the same side effects as a CPU read, e.g. the APU catches up.
+    pub fn read_memory(&mut self, address: u16) -> u8 {
+        let value = self.cpu.mmu.rb(address);
+        self.cpu.mmu.report_access(address, value, AccessKind::Read, AccessSource::Debugger);
+        value
+    }
+
+    // Copies memory for a memory viewer, up to the end of the address space. Unlike read_memory it
+    // has no side effects and is not reported to the access hook.
+    pub fn read_range(&self, address: u16, len: usize) -> Vec<u8> {
+        let end = (address as usize + len).min(0x10000);
+        (address as usize .. end).map(|a| self.cpu.mmu.peek(a as u16)).collect()
+    }
+
+    // Copies a memory without side effects, for debugging. A bank that this Gameboy does not have
+    // is empty.
+    pub fn dump_memory(&self, region: MemoryRegion) -> Vec<u8> {
+        self.cpu.mmu.dump_region(region)
+    }
+
+    // Replaces a memory with data of its size. Of the full bus only what is memory is written,
+    // without the ROM, the I/O registers and the echo of WRAM. The I/O registers are written as
+    // the CPU writes them, except for FF46 and FF50.
+    pub fn load_memory(&mut self, region: MemoryRegion, data: &[u8]) -> StrResult<()> {
+        self.cpu.mmu.load_region(region, data)
+    }
+
+    // Writes memory as the CPU does, without the access taking any time. Writing an I/O register
+    // has the same side effects as a CPU write: writing DIV resets it, writing FF46 starts an OAM
+    // DMA, and writing to ROM selects a bank.
+    pub fn write_memory(&mut self, address: u16, value: u8) {
+        self.cpu.mmu.wb(address, value);
+        self.cpu.mmu.report_access(address, value, AccessKind::Write, AccessSource::Debugger);
+    }
+
+    // Calls callback on the accesses to memory in the ranges, by the CPU, a DMA or the debugger
+    // functions, until it is removed. It replaces the hook that was set before.
+    pub fn set_access_hook(&mut self, ranges: Vec<RangeInclusive<u16>>, callback: AccessCallback) {
+        self.cpu.mmu.set_access_hook(Some(AccessHook::new(ranges, callback)));
+    }
+
+    pub fn remove_access_hook(&mut self) {
+        self.cpu.mmu.set_access_hook(None);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.cpu.paused()
+    }
+
+    pub fn resume(&mut self) {
+        self.cpu.resume();
+    }
+
     pub fn set_stdout(&mut self, output: bool) {
+        self.serial_capture = None;
         if output {
             self.cpu.mmu.serial.set_callback(Box::new(stdoutprinter));
         }
@@ -54,6 +561,7 @@ impl Device {
     }
 
     pub fn attach_printer(&mut self) {
+        self.serial_capture = None;
         let mut printer = GbPrinter::new();
 
         let printfun = move |v: u8| -> Option<u8> {
@@ -63,27 +571,249 @@ impl Device {
         self.cpu.mmu.serial.set_callback(Box::new(printfun));
     }
 
+    // Collects the bytes sent over the serial port, see serial_output
+    pub fn capture_serial(&mut self) {
+        let capture = Arc::new(Mutex::new(Vec::new()));
+        let sink = capture.clone();
+        self.cpu.mmu.serial.set_callback(Box::new(move |v| {
+            sink.lock().unwrap().push(v);
+            None
+        }));
+        self.serial_capture = Some(capture);
+    }
+
+    pub fn serial_output(&self) -> Vec<u8> {
+        match self.serial_capture {
+            Some(ref capture) => capture.lock().unwrap().clone(),
+            None => Vec::new(),
+        }
+    }
+
+    // Runs until the serial output contains the text, or until max_ticks have elapsed. Returns
+    // whether the text was found. Starts capturing the serial output if that was not done yet.
+    pub fn run_until_serial_match(&mut self, text: &str, max_ticks: u64) -> bool {
+        if self.serial_capture.is_none() {
+            self.capture_serial();
+        }
+        let text = text.as_bytes();
+        let mut ticks = 0;
+        loop {
+            if self.serial_output().windows(text.len()).any(|w| w == text) {
+                return true;
+            }
+            if ticks >= max_ticks {
+                return false;
+            }
+            ticks += (SERIAL_MATCH_INTERVAL + self.run_cycles(SERIAL_MATCH_INTERVAL)) as u64;
+        }
+    }
+
     pub fn check_and_reset_gpu_updated(&mut self) -> bool {
         let result = self.cpu.mmu.gpu.updated;
         self.cpu.mmu.gpu.updated = false;
         result
     }
 
+    // The screen as RGB, with the lines of the frame that is being drawn. Frontends should show
+    // framebuffer instead.
     pub fn get_gpu_data(&self) -> &[u8] {
         &self.cpu.mmu.gpu.data
     }
 
+    // The last completed frame, blended with the frame before when set_frame_blend is on. It is
+    // screen_size pixels, row by row from the top left, of 4 bytes each: red, green, blue and an
+    // alpha of 0xFF. This layout only changes with a new major version.
+    pub fn framebuffer(&self) -> &[u8] {
+        match &self.cpu.mmu.sgb {
+            Some(sgb) => sgb.frame(),
+            None => self.cpu.mmu.gpu.framebuffer(),
+        }
+    }
+
+    // The width and height of framebuffer and screenshot: SCREEN_W by SCREEN_H, or SGB_SCREEN_W by
+    // SGB_SCREEN_H with the border of a Super Game Boy. Frame blending is off in that case.
+    pub fn screen_size(&self) -> (usize, usize) {
+        match self.cpu.mmu.sgb {
+            Some(_) => (SGB_SCREEN_W, SGB_SCREEN_H),
+            None => (crate::gpu::SCREEN_W, crate::gpu::SCREEN_H),
+        }
+    }
+
+    // Counts the frames put in framebuffer, including the screen of a loaded state. A frontend
+    // shows a frame when this changed since the last one it showed.
+    pub fn frame_count(&self) -> u64 {
+        self.cpu.mmu.gpu.frame_count()
+    }
+
+    // Mixes this percentage of the previous frame into each frame, or none with 0. Kept over a
+    // model switch.
+    pub fn set_frame_blend(&mut self, percent: u8) {
+        self.cpu.mmu.gpu.set_frame_blend(percent);
+    }
+
+    pub fn frame_blend(&self) -> u8 {
+        self.cpu.mmu.gpu.frame_blend()
+    }
+
+    // How the colors of color mode and of classic games on a Gameboy Color are shown, from the next
+    // line on. Kept over a model switch.
+    pub fn set_color_correction(&mut self, correction: ColorCorrection) {
+        self.cpu.mmu.gpu.set_color_correction(correction);
+    }
+
+    pub fn color_correction(&self) -> ColorCorrection {
+        self.cpu.mmu.gpu.color_correction()
+    }
+
+    // Hides the BG, the window or the sprites from the next line on, to debug the rendering.
+    // Hidden BG and window pixels are color 0. The registers, timing and sprite selection do not
+    // change, so neither does the game. Kept over a model switch.
+    pub fn set_layer_visible(&mut self, layer: Layer, visible: bool) {
+        self.cpu.mmu.gpu.set_layer_visible(layer, visible);
+    }
+
+    pub fn layer_visible(&self, layer: Layer) -> bool {
+        self.cpu.mmu.gpu.layer_visible(layer)
+    }
+
+    // Stable hash of the last completed frame
+    pub fn frame_hash(&self) -> u64 {
+        self.cpu.mmu.gpu.frame_hash()
+    }
+
+    // CRC32 of every line of the last completed frame, to find where two frames start to differ
+    pub fn scanline_crcs(&self) -> [u32; crate::gpu::SCREEN_H] {
+        self.cpu.mmu.gpu.scanline_crcs()
+    }
+
+    // The last completed frame as RGBA of screen_size, as it is shown: in the DMG palette for
+    // classic games or in the border and palettes of a Super Game Boy, and blended with the frame
+    // before when frame blending is on. Never a frame that is still being drawn.
+    pub fn screenshot(&self) -> Vec<u8> {
+        self.framebuffer().to_vec()
+    }
+
+    // The color number of every pixel of the last completed frame, 0 to 3: the shade in classic
+    // games, so it does not depend on the DMG palette, otherwise the color within its palette.
+    // Frame blending does not apply to these.
+    pub fn screenshot_indices(&self) -> Vec<u8> {
+        self.cpu.mmu.gpu.last_frame_indices().to_vec()
+    }
+
+    // The 40 OAM entries with their attributes, whether they are on the screen or were dropped
+    // from a line with more than 10 sprites, and how they look
+    pub fn debug_sprites(&self) -> Vec<SpriteDebugInfo> {
+        self.cpu.mmu.gpu.debug_sprites()
+    }
+
+    // All 384 tiles of a VRAM bank as RGBA in a neutral gray, TILE_SHEET_W by TILE_SHEET_H pixels
+    // with 16 tiles to a row
+    pub fn render_tiles(&self, bank: usize) -> Vec<u8> {
+        self.cpu.mmu.gpu.render_tiles(bank)
+    }
+
+    // The BG map at 9C00 or 9800 with the tiles at 8000 or 8800, as selected by LCDC bits 3 and 4,
+    // as RGBA of BG_MAP_SIZE by BG_MAP_SIZE pixels. It has the current palettes and the CGB tile
+    // attributes, and the part of the map on the screen outlined in red.
+    pub fn render_bg_map(&self, map_select: bool, tile_data_select: bool) -> Vec<u8> {
+        self.cpu.mmu.gpu.render_bg_map(map_select, tile_data_select)
+    }
+
+    // The pixel FIFO shows register writes in the middle of a line, the default scanline renderer
+    // is faster. Kept over a model switch.
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.cpu.mmu.gpu.set_renderer(renderer);
+    }
+
+    pub fn renderer(&self) -> Renderer {
+        self.cpu.mmu.gpu.renderer()
+    }
+
+    // The RGB colors of the four shades of classic mode, lightest first, as in DMG_PALETTES. They
+    // apply from the next frame on, and are kept over a model switch.
+    pub fn set_dmg_palette(&mut self, palette: [[u8; 3]; 4]) {
+        self.cpu.mmu.gpu.set_dmg_palette(palette);
+    }
+
+    pub fn dmg_palette(&self) -> [[u8; 3]; 4] {
+        self.cpu.mmu.gpu.dmg_palette()
+    }
+
+    // The OAM indices of the at most 10 sprites that the OAM scan of the current line selected,
+    // which are the only ones drawn on it
+    pub fn line_sprites(&self) -> Vec<u8> {
+        self.cpu.mmu.gpu.line_sprites().to_vec()
+    }
+
     pub fn enable_audio(&mut self, player: Box<dyn sound::AudioPlayer>) {
+        self.enable_audio_with_options(player, sound::SoundOptions::default());
+    }
+
+    pub fn enable_audio_with_options(&mut self, player: Box<dyn sound::AudioPlayer>, options: sound::SoundOptions) {
         match self.cpu.mmu.gbmode {
             GbMode::Classic => {
-                self.cpu.mmu.sound = Some(sound::Sound::new_dmg(player));
+                self.cpu.mmu.sound = Some(sound::Sound::new_dmg(player, options));
             },
             GbMode::Color | GbMode::ColorAsClassic => {
-                self.cpu.mmu.sound = Some(sound::Sound::new_cgb(player));
+                self.cpu.mmu.sound = Some(sound::Sound::new_cgb(player, options));
             },
         };
     }
 
+    // Replaces the audio player without resetting the APU, e.g. when the audio device was lost
+    pub fn set_audio_player(&mut self, player: Box<dyn sound::AudioPlayer>) {
+        if let Some(ref mut sound) = self.cpu.mmu.sound {
+            sound.set_player(player);
+        }
+    }
+
+    pub fn audio_latency_ms(&self) -> Option<u32> {
+        self.cpu.mmu.sound.as_ref().map(|s| s.latency_ms())
+    }
+
+    pub fn audio_underrun_count(&self) -> Option<u64> {
+        self.cpu.mmu.sound.as_ref().map(|s| s.underrun_count())
+    }
+
+    pub fn audio_underrun_rate(&self) -> Option<u64> {
+        self.cpu.mmu.sound.as_ref().map(|s| s.underrun_rate())
+    }
+
+    pub fn audio_clip_stats(&self) -> Option<::std::sync::Arc<sound::ClipStats>> {
+        self.cpu.mmu.sound.as_ref().map(|s| s.clip_stats())
+    }
+
+    pub fn audio_debug_state(&mut self) -> Option<sound::ApuDebugState> {
+        self.cpu.mmu.sound.as_mut().map(|s| {
+            s.catch_up();
+            s.debug_state()
+        })
+    }
+
+    pub fn set_audio_sample_tap(&mut self, tap: Option<sound::SampleTap>) {
+        if let Some(ref mut sound) = self.cpu.mmu.sound {
+            sound.set_sample_tap(tap);
+        }
+    }
+
+    pub fn set_audio_mix_tap(&mut self, tap: Option<sound::MixTap>) {
+        if let Some(ref mut sound) = self.cpu.mmu.sound {
+            sound.set_mix_tap(tap);
+        }
+    }
+
+    pub fn set_audio_vin_source(&mut self, source: Option<sound::VinSource>) {
+        if let Some(ref mut sound) = self.cpu.mmu.sound {
+            sound.set_vin_source(source);
+        }
+    }
+
+    pub fn set_audio_speed_multiplier(&mut self, multiplier: f32) {
+        if let Some(ref mut sound) = self.cpu.mmu.sound {
+            sound.set_speed_multiplier(multiplier);
+        }
+    }
+
     pub fn sync_audio(&mut self) {
         if let Some(ref mut sound) = self.cpu.mmu.sound {
             sound.sync();
@@ -110,6 +840,30 @@ impl Device {
         self.cpu.mmu.mbc.dumpram()
     }
 
+    // Whether the cartridge turns its rumble motor on, polled by the frontend for force feedback
+    pub fn rumble_active(&self) -> bool {
+        self.cpu.mmu.mbc.rumble_active()
+    }
+
+    // Sets the tilt of an MBC7 cartridge in g, 0 when level. Other cartridges ignore it.
+    pub fn set_accelerometer(&mut self, x: f32, y: f32) {
+        self.cpu.mmu.mbc.set_accelerometer(x, y);
+    }
+
+    // Sets where a Pocket Camera cartridge takes its pictures from, instead of a test pattern. The
+    // frame is exposed and dithered as the game configures the camera.
+    pub fn set_camera_source(&mut self, source: CameraSource) {
+        self.cpu.mmu.mbc.set_camera_source(source);
+    }
+
+    // The battery backed RAM for the host to persist, if the cartridge has any
+    pub fn export_save_ram(&self) -> Option<Vec<u8>> {
+        match self.cpu.mmu.mbc.is_battery_backed() {
+            true => Some(self.cpu.mmu.mbc.dumpram()),
+            false => None,
+        }
+    }
+
     pub fn ram_is_battery_backed(&self) -> bool {
         self.cpu.mmu.mbc.is_battery_backed()
     }
@@ -117,4 +871,924 @@ impl Device {
     pub fn check_and_reset_ram_updated(&mut self) -> bool {
         self.cpu.mmu.mbc.check_and_reset_ram_updated()
     }
+
+    // Saves the whole machine, including the cartridge RAM and the screen. The debugger, traps,
+    // serial device, audio player and the keys that are held are not part of it.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        w.bytes(STATE_MAGIC);
+        w.u16(STATE_VERSION);
+        w.u8(self.rom_info.header_checksum);
+        w.u16(self.rom_info.global_checksum);
+        self.cpu.save_state(&mut w);
+        w.into_vec()
+    }
+
+    // Restores a state from save_state. A state for another game, or one that is not valid, is
+    // refused and leaves the machine as it was.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let mut r = StateReader::new(state);
+        if r.bytes(STATE_MAGIC.len()) != Ok(&STATE_MAGIC[..]) {
+            return Err(StateError::NotAState);
+        }
+        match r.u16()? {
+            STATE_VERSION => {},
+            version => return Err(StateError::UnsupportedVersion(version)),
+        }
+        if (r.u8()?, r.u16()?) != (self.rom_info.header_checksum, self.rom_info.global_checksum) {
+            return Err(StateError::WrongGame);
+        }
+
+        // A damaged state is only noticed halfway through, so the machine is restored from a backup
+        let mut backup = StateWriter::new();
+        self.cpu.save_state(&mut backup);
+        if let Err(e) = self.load_machine(r) {
+            let backup = backup.into_vec();
+            self.load_machine(StateReader::new(&backup)).expect("Could not restore the machine after a failed load");
+            return Err(e);
+        }
+        self.cpu.mmu.gpu.updated = true;
+        Ok(())
+    }
+
+    // The same as save_state and load_state, with the state in a file. A state that is not valid is
+    // an InvalidData error with the StateError inside.
+    pub fn save_state_to<P: AsRef<path::Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.save_state())
+    }
+
+    pub fn load_state_from<P: AsRef<path::Path>>(&mut self, path: P) -> io::Result<()> {
+        let state = fs::read(path)?;
+        self.load_state(&state).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn load_machine(&mut self, mut r: StateReader) -> StateResult<()> {
+        self.cpu.load_state(&mut r)?;
+        r.finish()
+    }
+}
+
+impl RomSource {
+    fn load(&self) -> Result<Box<dyn mbc::MBC+'static>, LoadError> {
+        match *self {
+            RomSource::File(ref romname, skip_checksum) => {
+                let data = fs::read(romname).map_err(|_| LoadError::Io("Could not read ROM"))?;
+                let mbc = mbc::get_mbc(data, skip_checksum).map_err(LoadError::Header)?;
+                let cart = mbc::FileBackedMBC::with_save_file(romname.into(), mbc).map_err(LoadError::Io)?;
+                Ok(Box::new(cart))
+            },
+            RomSource::Buffer(ref romdata, skip_checksum) => mbc::get_mbc(romdata.clone(), skip_checksum).map_err(LoadError::Header),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ColorCorrection, Colorization, Device, GbMode, Layer, LoadError, LoadOptions, Renderer, SGB_SCREEN_H, SGB_SCREEN_W};
+
+    const CPUINSTRS: &str = "roms/cpu_instrs.gb";
+    // About a minute and a half of emulated time
+    const BLARGG_MAX_TICKS: u64 = 4194304 * 90;
+    const MOONEYE_MAX_TICKS: u64 = 4194304 * 20;
+    // The Mooneye ROMs of the emulated models that do not pass yet, relative to the ROM directory.
+    // Any other ROM that does not pass fails the test, and the ones here that pass are listed.
+    const MOONEYE_EXPECTED_FAILURES: &[&str] = &[];
+
+    fn assert_same_boot_state(a: &mut Device, b: &mut Device) {
+        assert_eq!(a.is_classic(), b.is_classic());
+        assert_eq!(a.cpu.reg.af(), b.cpu.reg.af());
+        assert_eq!(a.cpu.reg.bc(), b.cpu.reg.bc());
+        assert_eq!(a.cpu.reg.de(), b.cpu.reg.de());
+        assert_eq!(a.cpu.reg.hl(), b.cpu.reg.hl());
+        assert_eq!(a.cpu.reg.pc, b.cpu.reg.pc);
+        assert_eq!(a.cpu.reg.sp, b.cpu.reg.sp);
+        for address in (0xFF00 ..= 0xFF7F).chain(0xFFFF ..= 0xFFFF) {
+            assert_eq!(a.cpu.mmu.rb(address), b.cpu.mmu.rb(address), "I/O register {:04X} differs", address);
+        }
+    }
+
+    #[test]
+    fn switch_model_matches_fresh_construction() {
+        let mut device = Device::new(CPUINSTRS, false).unwrap();
+        for _ in 0 .. 10000 {
+            device.do_cycle();
+        }
+
+        device.switch_model(false).unwrap();
+        let mut fresh_cgb = Device::new_cgb(CPUINSTRS, false).unwrap();
+        assert_same_boot_state(&mut device, &mut fresh_cgb);
+
+        for _ in 0 .. 10000 {
+            device.do_cycle();
+        }
+
+        device.switch_model(true).unwrap();
+        let mut fresh_dmg = Device::new(CPUINSTRS, false).unwrap();
+        assert_same_boot_state(&mut device, &mut fresh_dmg);
+    }
+
+    #[test]
+    fn save_ram_from_bytes() {
+        // MBC1 with 8 KiB of battery backed RAM, titled and running JR -2
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100 .. 0x0102].copy_from_slice(&[0x18, 0xFE]);
+        rom[0x0134 .. 0x0138].copy_from_slice(b"SAVE");
+        rom[0x0147] = 0x03;
+        rom[0x0149] = 0x02;
+        let mut save_ram = vec![0; 0x2000];
+        save_ram[0x10] = 0x42;
+
+        let mut device = Device::new_from_bytes(rom.clone(), LoadOptions { skip_checksum: true, save_ram: Some(save_ram), ..LoadOptions::default() }).unwrap();
+        assert!(!device.is_classic());
+        assert_eq!(device.rom_info().title, "SAVE");
+        device.write_memory(0x0000, 0x0A);
+        assert_eq!(device.read_memory(0xA010), 0x42);
+        device.write_memory(0xA011, 0x43);
+        let exported = device.export_save_ram().unwrap();
+        assert_eq!((exported.len(), exported[0x10], exported[0x11]), (0x2000, 0x42, 0x43));
+
+        // The RAM survives a reset, and an unbacked cartridge has nothing to save
+        device.reset().unwrap();
+        assert_eq!(device.export_save_ram().unwrap()[0x11], 0x43);
+        let options = LoadOptions { skip_checksum: true, save_ram: Some(vec![0; 0x8000]), ..LoadOptions::default() };
+        assert_eq!(Device::new_from_bytes(rom.clone(), options).err(), Some(LoadError::SaveRamSize));
+        rom[0x0147] = 0x02;
+        let device = Device::new_from_bytes(rom, LoadOptions { classic: true, skip_checksum: true, save_ram: Some(exported), ..LoadOptions::default() }).unwrap();
+        assert!(device.is_classic());
+        assert_eq!(device.export_save_ram(), None);
+    }
+
+    #[test]
+    fn colorization() {
+        // Color 1 of BG palette 0, which only reads in color mode
+        fn bg_color1(device: &mut Device) -> u16 {
+            let gpu = &mut device.cpu.mmu.gpu;
+            let gbmode = gpu.gbmode;
+            gpu.gbmode = GbMode::Color;
+            gpu.wb(0xFF68, 0x02);
+            let lo = gpu.rb(0xFF69) as u16;
+            gpu.wb(0xFF68, 0x03);
+            let color = lo | (gpu.rb(0xFF69) as u16) << 8;
+            gpu.gbmode = gbmode;
+            color
+        }
+
+        let mut rom = vec![0; 0x8000];
+        rom[0x134 .. 0x13F].copy_from_slice(b"POKEMON RED");
+        rom[0x14B] = 0x01;
+        let mut device = Device::new_cgb_from_buffer(rom.clone(), true).unwrap();
+        assert_eq!(bg_color1(&mut device), 0x421F);
+        device.set_colorization(Colorization::Off);
+        assert_eq!(bg_color1(&mut device), 0x5294);
+        device.reset().unwrap();
+        assert_eq!(bg_color1(&mut device), 0x5294);
+
+        let options = LoadOptions { skip_checksum: true, colorization: Colorization::Palette(5), ..LoadOptions::default() };
+        let mut device = Device::new_from_bytes(rom, options).unwrap();
+        assert_eq!(bg_color1(&mut device), 0x32BF);
+        assert_eq!(device.colorization(), Colorization::Palette(5));
+    }
+
+    #[test]
+    fn hidden_layers_kept_over_reset() {
+        let mut device = Device::new_from_buffer(vec![0; 0x8000], true).unwrap();
+        device.set_layer_visible(Layer::Window, false);
+        device.switch_model(false).unwrap();
+        assert!(!device.layer_visible(Layer::Window));
+        assert!(device.layer_visible(Layer::Background) && device.layer_visible(Layer::Sprites));
+    }
+
+    #[test]
+    fn color_correction_kept_over_reset() {
+        let mut device = Device::new_cgb_from_buffer(vec![0; 0x8000], true).unwrap();
+        assert_eq!(device.color_correction(), ColorCorrection::CgbLcd);
+        device.set_color_correction(ColorCorrection::GbaLcd);
+        device.reset().unwrap();
+        assert_eq!(device.color_correction(), ColorCorrection::GbaLcd);
+    }
+
+    #[test]
+    fn super_game_boy() {
+        // JR -2, with the SGB flag and the new licensee code
+        let mut rom = vec![0; 0x8000];
+        rom[0x100 .. 0x102].copy_from_slice(&[0x18, 0xFE]);
+        rom[0x146] = 0x03;
+        rom[0x14B] = 0x33;
+        let options = LoadOptions { classic: true, skip_checksum: true, sgb: true, ..LoadOptions::default() };
+        let mut device = Device::new_from_bytes(rom.clone(), options).unwrap();
+        assert!(device.is_sgb());
+        assert_eq!(device.screen_size(), (SGB_SCREEN_W, SGB_SCREEN_H));
+        assert_eq!((device.registers().af(), device.registers().bc(), device.registers().hl()), (0x0100, 0x0014, 0xC060));
+        let frame_count = device.frame_count();
+        while device.frame_count() == frame_count {
+            device.do_cycle();
+        }
+        assert_eq!(device.framebuffer().len(), SGB_SCREEN_W * SGB_SCREEN_H * 4);
+        assert_eq!(device.screenshot().len(), SGB_SCREEN_W * SGB_SCREEN_H * 4);
+
+        // Kept over a reset, but not in color mode or without the setting
+        device.reset().unwrap();
+        assert!(device.is_sgb());
+        device.switch_model(false).unwrap();
+        assert!(!device.is_sgb());
+        assert_eq!(device.screen_size(), (crate::gpu::SCREEN_W, crate::gpu::SCREEN_H));
+        device.switch_model(true).unwrap();
+        device.set_sgb(false);
+        assert!(device.is_sgb());
+        device.reset().unwrap();
+        assert!(!device.is_sgb());
+
+        // Games without the new licensee code run as on a DMG
+        rom[0x14B] = 0x01;
+        let options = LoadOptions { classic: true, skip_checksum: true, sgb: true, ..LoadOptions::default() };
+        assert!(!Device::new_from_bytes(rom, options).unwrap().is_sgb());
+    }
+
+    #[test]
+    fn boot_rom() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0000] = 0x11;
+        rom[0x0100 .. 0x0102].copy_from_slice(&[0x18, 0xFE]);
+        // LD A,42; LD (C000),A; NOPs up to LD A,01; LDH (50),A at 00FC
+        let mut boot_rom = vec![0; 0x100];
+        boot_rom[0x00 .. 0x05].copy_from_slice(&[0x3E, 0x42, 0xEA, 0x00, 0xC0]);
+        boot_rom[0xFC .. 0x100].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x50]);
+
+        let options = LoadOptions { classic: true, skip_checksum: true, boot_rom: Some(boot_rom.clone()), ..LoadOptions::default() };
+        let mut device = Device::new_from_bytes(rom.clone(), options).unwrap();
+        assert_eq!((device.cpu_state().pc, device.cpu_state().sp), (0x0000, 0x0000));
+        assert_eq!((device.read_memory(0x0000), device.read_memory(0xFF50), device.read_memory(0xFF40)), (0x3E, 0xFE, 0x00));
+
+        while device.cpu_state().pc != 0x0100 {
+            device.step();
+        }
+        assert!(!device.boot_rom_mapped());
+        assert_eq!((device.read_memory(0x0000), device.read_memory(0xFF50), device.read_memory(0xC000)), (0x11, 0xFF, 0x42));
+        device.write_memory(0xFF50, 0x00);
+        assert_eq!(device.read_memory(0xFF50), 0xFF);
+
+        device.reset().unwrap();
+        assert!(device.boot_rom_mapped());
+        assert_eq!((device.cpu_state().pc, device.read_memory(0x0000)), (0x0000, 0x3E));
+
+        // Without a boot ROM, and in CGB mode, the game starts right away
+        device.set_boot_rom(None).unwrap();
+        device.reset().unwrap();
+        assert_eq!((device.cpu_state().pc, device.read_memory(0x0000)), (0x0100, 0x11));
+        let options = LoadOptions { skip_checksum: true, boot_rom: Some(boot_rom), ..LoadOptions::default() };
+        let device = Device::new_from_bytes(rom, options).unwrap();
+        assert_eq!(device.cpu_state().pc, 0x0100);
+        assert!(!device.boot_rom_mapped());
+        assert!(Device::new_cgb_from_buffer(vec![0; 0x8000], true).unwrap().set_boot_rom(Some(vec![0; 0x900])).is_err());
+        let options = LoadOptions { skip_checksum: true, cgb_boot_rom: Some(vec![0; 0x100]), ..LoadOptions::default() };
+        assert_eq!(Device::new_from_bytes(vec![0; 0x8000], options).err(), Some(LoadError::BootRomSize { cgb: true }));
+    }
+
+    #[test]
+    fn new_from_file() {
+        let dir = std::env::temp_dir().join(format!("rboy_new_from_file_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        // MBC1 with 8 KiB of battery backed RAM and a save next to it
+        let mut rom = vec![0; 0x8000];
+        rom[0x0146] = 0x03;
+        rom[0x0147] = 0x03;
+        rom[0x0149] = 0x02;
+        rom[0x014B] = 0x33;
+        let rompath = dir.join("game.gb");
+        std::fs::write(&rompath, &rom).unwrap();
+        let mut save = vec![0; 0x2000];
+        save[0x10] = 0x42;
+        std::fs::write(dir.join("game.sav"), &save).unwrap();
+        let romname = rompath.to_str().unwrap();
+
+        // Started in the boot ROM and as a Super Game Boy, without a reset
+        let options = LoadOptions { classic: true, skip_checksum: true, boot_rom: Some(vec![0; 0x100]), sgb: true, ..LoadOptions::default() };
+        let mut device = Device::new_from_file(romname, options).unwrap();
+        assert!(device.boot_rom_mapped());
+        assert_eq!(device.cpu_state().pc, 0x0000);
+        assert!(device.is_sgb());
+        device.write_memory(0x0000, 0x0A);
+        assert_eq!(device.read_memory(0xA010), 0x42);
+
+        // The RAM is still saved to the file
+        device.write_memory(0xA011, 0x43);
+        device.write_memory(0x0000, 0x00);
+        drop(device);
+        assert_eq!(std::fs::read(dir.join("game.sav")).unwrap()[0x11], 0x43);
+
+        let options = |classic| LoadOptions { classic, skip_checksum: true, ..LoadOptions::default() };
+        assert_eq!(Device::new_from_file(dir.join("missing.gb").to_str().unwrap(), options(true)).err(), Some(LoadError::Io("Could not read ROM")));
+        rom[0x0147] = 0xFD;
+        std::fs::write(&rompath, &rom).unwrap();
+        assert!(matches!(Device::new_from_file(romname, options(true)), Err(LoadError::Header(_))));
+        let options = LoadOptions { boot_rom: Some(vec![0; 0x900]), ..options(true) };
+        assert_eq!(Device::new_from_file(romname, options).err(), Some(LoadError::BootRomSize { cgb: false }));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    // The color of the top left pixel after BGP is set to E4 and the LCD turned on
+    fn first_pixel(device: &mut Device) -> [u8; 3] {
+        device.write_memory(0xFF47, 0xE4);
+        device.write_memory(0xFF40, 0x91);
+        let mut ticks = 0;
+        while ticks < 70224 * 2 {
+            ticks += device.do_cycle();
+        }
+        [device.get_gpu_data()[0], device.get_gpu_data()[1], device.get_gpu_data()[2]]
+    }
+
+    #[test]
+    fn cgb_boot_rom() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x0100 .. 0x0102].copy_from_slice(&[0x18, 0xFE]);
+        rom[0x0104] = 0xCE;
+        let code = [
+            0x3E, 0x80, 0xE0, 0x68,       // 0200 LD A,80; LDH (68),A
+            0x3E, 0x1F, 0xE0, 0x69,       // 0204 LD A,1F; LDH (69),A
+            0x3E, 0x00, 0xE0, 0x69,       // 0208 LD A,00; LDH (69),A
+            0x3E, 0x04, 0xE0, 0x4C,       // 020C LD A,04; LDH (4C),A
+            0x3E, 0x01, 0xE0, 0x6C,       // 0210 LD A,01; LDH (6C),A
+            0xFA, 0x04, 0x01,             // 0214 LD A,(0104)
+            0xEA, 0x00, 0xC0,             // 0217 LD (C000),A
+            0xC3, 0xFC, 0x00,             // 021A JP 00FC
+        ];
+        let mut boot_rom = vec![0; 0x900];
+        boot_rom[0x00 .. 0x03].copy_from_slice(&[0xC3, 0x00, 0x02]);
+        boot_rom[0xFC .. 0x100].copy_from_slice(&[0x3E, 0x01, 0xE0, 0x50]);
+        boot_rom[0x200 .. 0x200 + code.len()].copy_from_slice(&code);
+
+        let boot = |boot_rom: &[u8]| {
+            let options = LoadOptions { skip_checksum: true, cgb_boot_rom: Some(boot_rom.to_vec()), ..LoadOptions::default() };
+            let mut device = Device::new_from_bytes(rom.clone(), options).unwrap();
+            // The boot ROM runs in color mode, whatever the game is
+            assert_eq!(device.read_memory(0xFF4F), 0xFE);
+            while device.cpu_state().pc != 0x0100 {
+                device.step();
+            }
+            device
+        };
+        let mut device = boot(&boot_rom);
+        assert!(!device.boot_rom_mapped());
+        // The header showed through the gap
+        assert_eq!(device.read_memory(0xC000), 0xCE);
+        // KEY0 selected the DMG mode, and the palette the boot ROM wrote is used
+        assert_eq!((device.read_memory(0xFF4F), device.read_memory(0xFF68)), (0xFF, 0xFF));
+        assert_eq!(first_pixel(&mut device), [201, 0, 46]);
+
+        device.reset().unwrap();
+        assert!(device.boot_rom_mapped());
+        assert_eq!((device.read_memory(0x0000), device.read_memory(0x0104), device.read_memory(0x0200)), (0xC3, 0xCE, 0x3E));
+
+        boot_rom[0x020D] = 0x80;
+        let mut device = boot(&boot_rom);
+        assert_eq!((device.read_memory(0xFF4F), device.read_memory(0xFF6C)), (0xFE, 0xFF));
+
+        // Without the boot ROM a DMG game gets the default colors, starting with white
+        let mut device = Device::new_cgb_from_buffer(rom, true).unwrap();
+        assert_eq!(first_pixel(&mut device), [248, 248, 248]);
+        assert!(device.set_cgb_boot_rom(Some(vec![0; 0x100])).is_err());
+    }
+
+    #[test]
+    fn stepping_matches_free_running() {
+        // A few frames, so the comparison crosses VBlank and its interrupt
+        const TICKS: u32 = 70224 * 3 + 1234;
+
+        let mut running = Device::new(CPUINSTRS, false).unwrap();
+        let overshoot = running.run_cycles(TICKS);
+
+        let mut stepping = Device::new(CPUINSTRS, false).unwrap();
+        let mut elapsed = 0;
+        while elapsed < TICKS {
+            elapsed += stepping.step();
+        }
+        assert_eq!(elapsed - TICKS, overshoot);
+
+        assert_eq!(running.registers().af(), stepping.registers().af());
+        assert_eq!(running.registers().pc, stepping.registers().pc);
+        assert_eq!(running.registers().sp, stepping.registers().sp);
+        assert_eq!(running.ime(), stepping.ime());
+        assert_eq!(crate::gpu::first_differing_scanline(&running.scanline_crcs(), &stepping.scanline_crcs()), None);
+        assert_eq!(running.frame_hash(), stepping.frame_hash());
+        for address in (0xFF00 ..= 0xFF7F).chain(0xFFFF ..= 0xFFFF) {
+            assert_eq!(running.read_memory(address), stepping.read_memory(address), "I/O register {:04X} differs", address);
+        }
+    }
+
+    #[test]
+    fn step_instruction_table() {
+        // Code at 0100, the ticks of the first instruction, and A and F after it
+        let table: &[(&[u8], u32, u8, u8)] = &[
+            (&[0x00], 4, 0x01, 0xB0),             // NOP
+            (&[0x3E, 0x0F], 8, 0x0F, 0xB0),       // LD A,0F
+            (&[0x3C], 4, 0x02, 0x10),             // INC A
+            (&[0xC6, 0xFF], 8, 0x00, 0xB0),       // ADD A,FF
+            (&[0xC3, 0x00, 0x02], 16, 0x01, 0xB0), // JP 0200
+            (&[0xCD, 0x00, 0x02], 24, 0x01, 0xB0), // CALL 0200
+            (&[0xFB], 4, 0x01, 0xB0),             // EI
+        ];
+        for &(code, ticks, a, f) in table {
+            let mut romdata = vec![0; 0x8000];
+            romdata[0x0100 .. 0x0100 + code.len()].copy_from_slice(code);
+            let mut device = Device::new_from_buffer(romdata, true).unwrap();
+            assert_eq!(device.step(), ticks, "ticks of {:02X?}", code);
+            assert_eq!(device.registers().a, a, "A after {:02X?}", code);
+            assert_eq!(device.registers().af() as u8, f, "F after {:02X?}", code);
+        }
+
+        // IME can be followed through DI and EI
+        let mut romdata = vec![0; 0x8000];
+        romdata[0x0100 .. 0x0106].copy_from_slice(&[0xF3, 0x00, 0x00, 0xFB, 0x00, 0x00]); // DI; NOP; NOP; EI; NOP; NOP
+        let mut device = Device::new_from_buffer(romdata, true).unwrap();
+        assert!(device.ime());
+        for _ in 0 .. 3 { device.step(); }
+        assert!(!device.ime());
+        for _ in 0 .. 3 { device.step(); }
+        assert!(device.ime());
+    }
+
+    #[test]
+    fn bgp_change_mid_frame() {
+        // All colors white from line 0, and all colors black from line 72
+        let mut romdata = vec![0; 0x8000];
+        romdata[0x0100 .. 0x0103].copy_from_slice(&[0xC3, 0x50, 0x01]); // JP 0150
+        romdata[0x0150 .. 0x0165].copy_from_slice(&[
+            0xF0, 0x44, 0xFE, 0x00, 0x20, 0xFA, // 0150 LDH A,(44); CP 00; JR NZ,0150
+            0xAF, 0xE0, 0x47,                   // 0156 XOR A; LDH (47),A
+            0xF0, 0x44, 0xFE, 0x48, 0x20, 0xFA, // 0159 LDH A,(44); CP 48; JR NZ,0159
+            0x3E, 0xFF, 0xE0, 0x47,             // 015F LD A,FF; LDH (47),A
+            0x18, 0xEB,                         // 0163 JR 0150
+        ]);
+        for &renderer in [Renderer::Scanline, Renderer::Fifo].iter() {
+            let mut device = Device::new_from_buffer(romdata.clone(), true).unwrap();
+            device.set_renderer(renderer);
+            device.run_cycles(70224 * 3);
+
+            let data = device.get_gpu_data();
+            let row = |y: usize| &data[y * crate::gpu::SCREEN_W * 3 .. (y + 1) * crate::gpu::SCREEN_W * 3];
+            for y in 0 .. crate::gpu::SCREEN_H {
+                let expected = if y < 72 { 255 } else { 0 };
+                assert!(row(y).iter().all(|&v| v == expected), "{:?} line {}", renderer, y);
+            }
+
+            // A model switch keeps the renderer
+            device.reset().unwrap();
+            assert_eq!(device.renderer(), renderer);
+        }
+    }
+
+    #[test]
+    fn screenshot_of_last_frame() {
+        // White from line 0 and black from line 72, as in bgp_change_mid_frame
+        let mut romdata = vec![0; 0x8000];
+        romdata[0x0100 .. 0x0103].copy_from_slice(&[0xC3, 0x50, 0x01]); // JP 0150
+        romdata[0x0150 .. 0x0165].copy_from_slice(&[
+            0xF0, 0x44, 0xFE, 0x00, 0x20, 0xFA, // 0150 LDH A,(44); CP 00; JR NZ,0150
+            0xAF, 0xE0, 0x47,                   // 0156 XOR A; LDH (47),A
+            0xF0, 0x44, 0xFE, 0x48, 0x20, 0xFA, // 0159 LDH A,(44); CP 48; JR NZ,0159
+            0x3E, 0xFF, 0xE0, 0x47,             // 015F LD A,FF; LDH (47),A
+            0x18, 0xEB,                         // 0163 JR 0150
+        ]);
+        let mut device = Device::new_from_buffer(romdata, true).unwrap();
+        let (_, green) = crate::gpu::DMG_PALETTES[1];
+        device.set_dmg_palette(green);
+        device.run_cycles(70224 * 2);
+        // Lines 100 to 119 of the frame being drawn are white
+        while device.read_memory(0xFF44) != 100 {
+            device.do_cycle();
+        }
+        device.write_memory(0xFF47, 0x00);
+        while device.read_memory(0xFF44) != 120 {
+            device.do_cycle();
+        }
+        assert_eq!(device.get_gpu_data()[110 * crate::gpu::SCREEN_W * 3], green[0][0]);
+
+        let rgba = device.screenshot();
+        let indices = device.screenshot_indices();
+        assert_eq!(rgba.len(), crate::gpu::SCREEN_W * crate::gpu::SCREEN_H * 4);
+        assert_eq!(indices.len(), crate::gpu::SCREEN_W * crate::gpu::SCREEN_H);
+        for y in 0 .. crate::gpu::SCREEN_H {
+            let (shade, color) = if y < 72 { (0, green[0]) } else { (3, green[3]) };
+            let line = y * crate::gpu::SCREEN_W;
+            assert!(indices[line .. line + crate::gpu::SCREEN_W].iter().all(|&i| i == shade), "line {}", y);
+            for pixel in rgba[line * 4 .. (line + crate::gpu::SCREEN_W) * 4].chunks(4) {
+                assert_eq!(pixel, [color[0], color[1], color[2], 0xFF], "line {}", y);
+            }
+        }
+        assert!(device.framebuffer() == &rgba[..]);
+
+        // With frame blending, the white lines 100 and on of the frame being drawn are mixed with
+        // the black of the frame before, but their color numbers are not
+        device.set_frame_blend(50);
+        while device.read_memory(0xFF44) != 144 {
+            device.do_cycle();
+        }
+        let rgba = device.screenshot();
+        let pixel = &rgba[110 * crate::gpu::SCREEN_W * 4 ..][.. 4];
+        for (c, (&white, &black)) in green[0].iter().zip(green[3].iter()).enumerate() {
+            assert!(white == black || (pixel[c] < white && pixel[c] > black), "{:?}", pixel);
+        }
+        assert_eq!(device.screenshot_indices()[110 * crate::gpu::SCREEN_W], 0);
+        assert!(device.framebuffer() == &rgba[..]);
+    }
+
+    #[test]
+    fn frame_count() {
+        let mut romdata = vec![0; 0x8000];
+        romdata[0x0100 .. 0x0102].copy_from_slice(&[0x18, 0xFE]); // JR 0100
+        let mut device = Device::new_from_buffer(romdata, true).unwrap();
+        device.run_cycles(70224 * 2);
+        let count = device.frame_count();
+        assert!(count >= 1);
+        device.run_cycles(70224 * 3);
+        assert_eq!(device.frame_count(), count + 3);
+
+        // A loaded state shows its screen, as a new frame
+        let state = device.save_state();
+        device.load_state(&state).unwrap();
+        assert_eq!(device.frame_count(), count + 4);
+
+        device.set_frame_blend(50);
+        device.reset().unwrap();
+        assert_eq!(device.frame_count(), count + 4);
+        assert_eq!(device.framebuffer().len(), crate::gpu::SCREEN_W * crate::gpu::SCREEN_H * 4);
+        device.run_cycles(70224);
+        assert_eq!(device.frame_count(), count + 5);
+    }
+
+    // The models a Mooneye ROM is for are the suffix of its name, as in di_timing-GS or
+    // boot_regs-dmgABC, where G is the DMG and MGB, S the SGB and SGB2, C the CGB and AGB and A the
+    // AGB and AGS. Returns whether the ROM runs in classic mode, or None when it is only for models
+    // that are not emulated. A ROM without a suffix is for all models and runs in classic mode.
+    fn mooneye_model(name: &str) -> Option<bool> {
+        const MODELS: [&str; 14] = ["dmgABC", "dmg0", "mgb", "sgb2", "sgb", "cgbABCDE", "cgb0", "cgb", "agb", "ags", "G", "S", "C", "A"];
+
+        let mut suffix = match name.rsplit_once('-') {
+            Some((_, suffix)) => suffix,
+            None => return Some(true),
+        };
+        let mut models = Vec::new();
+        while !suffix.is_empty() {
+            match MODELS.iter().find(|model| suffix.starts_with(*model)) {
+                Some(model) => {
+                    models.push(*model);
+                    suffix = &suffix[model.len() ..];
+                },
+                None => return Some(true),
+            }
+        }
+        if models.iter().any(|model| ["dmgABC", "G"].contains(model)) {
+            Some(true)
+        } else if models.iter().any(|model| ["cgbABCDE", "cgb", "C"].contains(model)) {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    #[test]
+    fn mooneye_models() {
+        assert_eq!(mooneye_model("add_sp_e_timing"), Some(true));
+        assert_eq!(mooneye_model("di_timing-GS"), Some(true));
+        assert_eq!(mooneye_model("boot_regs-dmgABC"), Some(true));
+        assert_eq!(mooneye_model("boot_div-dmgABCmgb"), Some(true));
+        assert_eq!(mooneye_model("boot_hwio-C"), Some(false));
+        assert_eq!(mooneye_model("boot_regs-cgb"), Some(false));
+        for name in ["boot_div-dmg0", "boot_regs-mgb", "boot_hwio-S", "boot_regs-sgb2", "boot_div-cgb0", "boot_regs-A", "boot_div-cgb0agb"].iter() {
+            assert_eq!(mooneye_model(name), None, "{}", name);
+        }
+    }
+
+    // The Mooneye ROMs are looked up in RBOY_MOONEYE_ROMS, or roms/mooneye by default, including
+    // subdirectories. ROMs for models that are not emulated are skipped.
+    #[test]
+    #[ignore]
+    fn mooneye() {
+        use crate::debugger::{DebugStop, MooneyeOutcome};
+        use crate::trap::TrapOptions;
+
+        fn find_roms(dir: &::std::path::Path, roms: &mut Vec<::std::path::PathBuf>) {
+            let entries = ::std::fs::read_dir(dir).unwrap_or_else(|e| panic!("Could not read {}: {}", dir.display(), e));
+            for path in entries.map(|entry| entry.unwrap().path()) {
+                if path.is_dir() {
+                    find_roms(&path, roms);
+                } else if path.extension().is_some_and(|ext| ext == "gb") {
+                    roms.push(path);
+                }
+            }
+        }
+
+        let dir = ::std::env::var("RBOY_MOONEYE_ROMS").unwrap_or_else(|_| "roms/mooneye".into());
+        let mut roms = Vec::new();
+        find_roms(::std::path::Path::new(&dir), &mut roms);
+        roms.sort();
+
+        let (mut failed, mut fixed, mut skipped) = (Vec::new(), Vec::new(), 0);
+        for path in roms.iter() {
+            let classic = match mooneye_model(&path.file_stem().unwrap().to_string_lossy()) {
+                Some(classic) => classic,
+                None => {
+                    skipped += 1;
+                    continue;
+                },
+            };
+            let mut device = match classic {
+                true => Device::new(&path.to_string_lossy(), true),
+                false => Device::new_cgb(&path.to_string_lossy(), true),
+            }.unwrap();
+            device.set_traps(TrapOptions::disabled());
+            device.set_soft_breakpoints(true);
+
+            let outcome = match device.run_until_paused(MOONEYE_MAX_TICKS) {
+                true => match device.take_debug_stop() {
+                    Some(DebugStop::SoftBreakpoint(_)) => MooneyeOutcome::from_registers(&device.registers()),
+                    _ => MooneyeOutcome::Unknown,
+                },
+                false => MooneyeOutcome::Unknown,
+            };
+            let name = path.strip_prefix(&dir).unwrap_or(path).display().to_string();
+            let expected = MOONEYE_EXPECTED_FAILURES.contains(&name.as_str());
+            match (outcome == MooneyeOutcome::Pass, expected) {
+                (false, _) => failed.push((name, outcome, expected)),
+                (true, true) => fixed.push(name),
+                (true, false) => {},
+            }
+        }
+
+        let run = roms.len() - skipped;
+        println!("Mooneye: {} of {} passed, {} for other models skipped", run - failed.len(), run, skipped);
+        for (name, outcome, expected) in failed.iter() {
+            println!("  {:?}: {}{}", outcome, name, if *expected { " (expected)" } else { "" });
+        }
+        for name in fixed.iter() {
+            println!("  Passes now, remove it from the expected failures: {}", name);
+        }
+        let regressions = failed.iter().filter(|(_, _, expected)| !expected).count();
+        assert!(regressions == 0, "{} of {} Mooneye ROMs did not pass, and are not expected to fail", regressions, run);
+    }
+
+    // The blargg ROMs are looked up in RBOY_TEST_ROMS, or roms by default. Run these with --ignored.
+    fn run_blargg(name: &str) {
+        let dir = ::std::env::var("RBOY_TEST_ROMS").unwrap_or_else(|_| "roms".into());
+        let path = ::std::path::Path::new(&dir).join(name);
+        let mut device = match Device::new(&path.to_string_lossy(), false) {
+            Ok(device) => device,
+            Err(message) => panic!("Could not load {}: {}", path.display(), message),
+        };
+        let passed = device.run_until_serial_match("Passed", BLARGG_MAX_TICKS);
+        assert!(passed, "{} did not pass, serial output:\n{}", name, String::from_utf8_lossy(&device.serial_output()));
+    }
+
+    #[test]
+    #[ignore]
+    fn blargg_cpu_instrs() {
+        run_blargg("cpu_instrs.gb");
+    }
+
+    #[test]
+    #[ignore]
+    fn blargg_instr_timing() {
+        run_blargg("instr_timing.gb");
+    }
+
+    #[test]
+    #[ignore]
+    fn blargg_mem_timing() {
+        run_blargg("mem_timing.gb");
+    }
+
+    #[test]
+    fn serial_match() {
+        let mut device = Device::new(CPUINSTRS, false).unwrap();
+        assert!(device.run_until_serial_match("cpu_instrs", 4194304));
+        assert!(!device.run_until_serial_match("Failed", 100000));
+        assert!(device.serial_output().starts_with(b"cpu_instrs"));
+    }
+
+    #[test]
+    fn save_state_round_trip() {
+        const FRAME: u32 = 70224;
+
+        // Saves at a few points of the blargg run, then runs the same frames from the state twice
+        for &classic in [true, false].iter() {
+            let mut device = match classic {
+                true => Device::new(CPUINSTRS, false),
+                false => Device::new_cgb(CPUINSTRS, false),
+            }.unwrap();
+            for &frames in [1, 20, 90].iter() {
+                device.run_cycles(FRAME * frames);
+                let state = device.save_state();
+                device.run_cycles(FRAME * 10);
+                let (crcs, hash, cpu, after) = (device.scanline_crcs(), device.frame_hash(), device.cpu_state(), device.save_state());
+
+                device.load_state(&state).unwrap();
+                assert!(device.save_state() == state);
+                device.run_cycles(FRAME * 10);
+                assert_eq!(crate::gpu::first_differing_scanline(&device.scanline_crcs(), &crcs), None, "after {} frames", frames);
+                assert_eq!((device.frame_hash(), device.cpu_state()), (hash, cpu));
+                assert!(device.save_state() == after, "the state differs after {} frames", frames);
+
+                // A fresh machine continues the same way
+                let mut fresh = Device::new(CPUINSTRS, false).unwrap();
+                fresh.load_state(&state).unwrap();
+                fresh.run_cycles(FRAME * 10);
+                assert!(fresh.save_state() == after);
+            }
+        }
+    }
+
+    #[test]
+    fn load_state_errors() {
+        use crate::state::StateError;
+
+        let mut device = Device::new(CPUINSTRS, false).unwrap();
+        device.run_cycles(70224 * 5);
+        let state = device.save_state();
+        device.run_cycles(70224);
+        let running = device.save_state();
+
+        assert_eq!(device.load_state(b"not a state"), Err(StateError::NotAState));
+        let mut newer = state.clone();
+        newer[8] += 1;
+        assert_eq!(device.load_state(&newer), Err(StateError::UnsupportedVersion(3)));
+        // States from before the Super Game Boy block was added are refused before reading the machine
+        let mut older = state.clone();
+        older[8 .. 10].copy_from_slice(&1u16.to_le_bytes());
+        assert_eq!(device.load_state(&older), Err(StateError::UnsupportedVersion(1)));
+        let mut other = Device::new_from_buffer(vec![0; 0x8000], true).unwrap();
+        assert_eq!(other.load_state(&state), Err(StateError::WrongGame));
+        assert_eq!(device.load_state(&state[.. state.len() - 1]), Err(StateError::Corrupt));
+        let mut longer = state.clone();
+        longer.push(0);
+        assert_eq!(device.load_state(&longer), Err(StateError::Corrupt));
+        // The failed loads left the machine as it was
+        assert!(device.save_state() == running);
+
+        assert_eq!(device.load_state(&state), Ok(()));
+    }
+
+    #[test]
+    fn state_files() {
+        use crate::state::StateError;
+        use std::fs;
+
+        let dir = std::env::temp_dir().join(format!("rboy_state_files_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("cpu_instrs.ss1");
+
+        let mut device = Device::new(CPUINSTRS, false).unwrap();
+        device.run_cycles(70224 * 5);
+        device.save_state_to(&path).unwrap();
+        let state = device.save_state();
+        device.run_cycles(70224);
+        device.load_state_from(&path).unwrap();
+        assert!(device.save_state() == state);
+
+        let err = device.load_state_from(dir.join("missing.ss1")).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        let mut other = Device::new_from_buffer(vec![0; 0x8000], true).unwrap();
+        let err = other.load_state_from(&path).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert_eq!(err.get_ref().unwrap().downcast_ref::<StateError>(), Some(&StateError::WrongGame));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn game_genie() {
+        use crate::cheats::CheatError;
+
+        // MBC1 with 4 banks, where 4A17 holds C8 in bank 2 only
+        let mut romdata = vec![0; 0x10000];
+        romdata[0x0147] = 0x01;
+        romdata[0x0148] = 0x01;
+        romdata[0x0D58] = 0x11;
+        romdata[0x4A17] = 0x22;
+        romdata[0x8A17] = 0xC8;
+        let mut device = Device::new_from_buffer(romdata, true).unwrap();
+
+        device.add_game_genie("3ED-58F").unwrap();
+        device.add_game_genie("00A-17B-C49").unwrap();
+        assert_eq!(device.add_game_genie("00A-17B-C4"), Err(CheatError::InvalidLength));
+        assert_eq!(device.read_memory(0x0D58), 0x3E);
+        assert_eq!(device.read_memory(0x4A17), 0x22);
+        device.write_memory(0x2000, 0x02);
+        assert_eq!(device.read_memory(0x4A17), 0x00);
+
+        // The codes stay active over a reset, until they are removed
+        device.reset().unwrap();
+        assert_eq!(device.read_memory(0x0D58), 0x3E);
+        assert!(device.remove_game_genie("3ed-58f"));
+        assert!(!device.remove_game_genie("3ED-58F"));
+        assert_eq!(device.read_memory(0x0D58), 0x11);
+        device.clear_cheats();
+        device.write_memory(0x2000, 0x02);
+        assert_eq!(device.read_memory(0x4A17), 0xC8);
+    }
+
+    #[test]
+    fn gameshark() {
+        use crate::cheats::CheatError;
+
+        // MBC5 with 4 banks of RAM
+        let mut romdata = vec![0; 0x8000];
+        romdata[0x0143] = 0x80;
+        romdata[0x0147] = 0x1B;
+        romdata[0x0149] = 0x03;
+        let mut device = Device::new_cgb_from_buffer(romdata, true).unwrap();
+        device.write_memory(0x0000, 0x0A);
+
+        for code in ["0111_00C0", "0122_10E0", "0133_80FF", "8244_00A0", "0155_00B0", "9366_00D0", "0177_01D0"] {
+            device.add_cheat(&code.replace('_', "")).unwrap();
+        }
+        assert_eq!(device.add_cheat("01110040"), Err(CheatError::GameSharkInRom(0x4000)));
+        assert_eq!(device.add_cheat("A11100C0"), Err(CheatError::UnsupportedGameSharkType(0xA1)));
+        // The work RAM starts out random
+        for address in [0xC000, 0xC010, 0xFF80, 0xD000, 0xD001] {
+            device.write_memory(address, 0);
+        }
+        device.write_memory(0xFF70, 0x03);
+        device.write_memory(0xD000, 0);
+        device.write_memory(0xFF70, 0x01);
+        // Nothing is written before VBlank
+        device.run_cycles(100);
+        assert_eq!(device.read_memory(0xC000), 0);
+        device.run_cycles(70224);
+
+        assert_eq!(device.read_memory(0xC000), 0x11);
+        // Echo RAM
+        assert_eq!(device.read_memory(0xC010), 0x22);
+        assert_eq!(device.read_memory(0xFF80), 0x33);
+        assert_eq!(device.read_memory(0xB000), 0x55);
+        assert_eq!(device.read_memory(0xA000), 0);
+        device.write_memory(0x4000, 0x02);
+        assert_eq!(device.read_memory(0xA000), 0x44);
+        assert_eq!(device.read_memory(0xD001), 0x77);
+        assert_eq!(device.read_memory(0xD000), 0);
+        device.write_memory(0xFF70, 0x03);
+        assert_eq!(device.read_memory(0xD000), 0x66);
+
+        // The writes repeat every frame until the code is removed
+        device.write_memory(0xC000, 0x00);
+        device.run_cycles(70224);
+        assert_eq!(device.read_memory(0xC000), 0x11);
+        assert!(device.remove_gameshark("011100C0"));
+        device.write_memory(0xC000, 0x00);
+        device.run_cycles(70224);
+        assert_eq!(device.read_memory(0xC000), 0x00);
+    }
+
+    #[test]
+    fn access_hook() {
+        use crate::access::{Access, AccessKind, AccessSource};
+        use std::sync::{Arc, Mutex};
+
+        let mut romdata = vec![0; 0x8000];
+        romdata[0x0100 .. 0x010D].copy_from_slice(&[
+            0x3E, 0x42,       // LD A,42
+            0xEA, 0x00, 0xC0, // LD (C000),A
+            0xFA, 0x01, 0xC0, // LD A,(C001)
+            0x3E, 0xC0,       // LD A,C0
+            0xE0, 0x46,       // LDH (46),A
+            0x18,             // JR -2
+        ]);
+        romdata[0x010D] = 0xFE;
+        let mut device = Device::new_from_buffer(romdata, true).unwrap();
+        device.write_memory(0xC001, 0x17);
+
+        let accesses = Arc::new(Mutex::new(Vec::new()));
+        let log = accesses.clone();
+        device.set_access_hook(vec![0xC000 ..= 0xC001, 0xFE00 ..= 0xFE00], Box::new(move |access| log.lock().unwrap().push(access)));
+        device.run_cycles(1000);
+        assert_eq!(device.read_memory(0xC000), 0x42);
+        assert_eq!(device.read_range(0xC000, 2), vec![0x42, 0x17]);
+        assert_eq!(device.read_range(0xFFFE, 4).len(), 2);
+
+        let access = |address, value, kind, source| Access { address, value, kind, source };
+        assert_eq!(*accesses.lock().unwrap(), vec![
+            access(0xC000, 0x42, AccessKind::Write, AccessSource::Cpu),
+            access(0xC001, 0x17, AccessKind::Read, AccessSource::Cpu),
+            access(0xC000, 0x42, AccessKind::Read, AccessSource::Dma),
+            access(0xFE00, 0x42, AccessKind::Write, AccessSource::Dma),
+            access(0xC001, 0x17, AccessKind::Read, AccessSource::Dma),
+            access(0xC000, 0x42, AccessKind::Read, AccessSource::Debugger),
+        ]);
+
+        // The hook stays over a reset, until it is removed
+        device.reset().unwrap();
+        device.write_memory(0xC000, 0x01);
+        device.remove_access_hook();
+        device.write_memory(0xC000, 0x02);
+        assert_eq!(accesses.lock().unwrap().last(), Some(&access(0xC000, 0x01, AccessKind::Write, AccessSource::Debugger)));
+    }
+
+    #[test]
+    fn switch_model_refuses_cgb_only() {
+        let mut romdata = vec![0; 0x8000];
+        romdata[0x0143] = 0xC0;
+
+        let mut device = Device::new_cgb_from_buffer(romdata, true).unwrap();
+        assert!(device.switch_model(true).is_err());
+        assert!(!device.is_classic());
+    }
 }
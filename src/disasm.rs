@@ -0,0 +1,164 @@
+use crate::cpu::{CB_OPCODES, OPCODES};
+
+// Disassembles the instruction at the start of bytes, which is located at pc. Returns the text
+// and the length. When bytes ends before the instruction does, it is shown as a single DB.
+pub fn disassemble(bytes: &[u8], pc: u16) -> (String, u8) {
+    let op = match bytes.first() {
+        Some(&op) => op,
+        None => return ("DB".to_owned(), 0),
+    };
+    let (text, len) = decode(op, bytes.get(1).copied().unwrap_or(0), bytes.get(2).copied().unwrap_or(0), pc);
+    match bytes.len() < len as usize {
+        true => (format!("DB ${:02X}", op), 1),
+        false => (text, len),
+    }
+}
+
+// The M-cycles of the instruction at the start of bytes, when a condition does not hold and when
+// it does
+pub fn instruction_cycles(bytes: &[u8]) -> (u32, u32) {
+    let opcode = match bytes {
+        [0xCB, op, ..] => &CB_OPCODES[*op as usize],
+        [op, ..] => &OPCODES[*op as usize],
+        [] => return (0, 0),
+    };
+    (opcode.cycles, opcode.cycles_taken)
+}
+
+fn decode(op: u8, b1: u8, b2: u8, pc: u16) -> (String, u8) {
+    let (opcode, len) = match op {
+        0xCB => (&CB_OPCODES[b1 as usize], 2),
+        _ => (&OPCODES[op as usize], OPCODES[op as usize].len),
+    };
+    let r8 = b1 as i8;
+    let text = opcode.mnemonic
+        .replace("{d8}", &format!("${:02X}", b1))
+        .replace("{d16}", &format!("${:04X}", ((b2 as u16) << 8) | b1 as u16))
+        .replace("{+r8}", &format!("{:+}", r8))
+        .replace("{r8}", &r8.to_string())
+        .replace("{jr}", &format!("${:04X}", pc.wrapping_add(2).wrapping_add(r8 as u16)))
+        .replace("{op}", &format!("${:02X}", op));
+    (text, len)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{disassemble, instruction_cycles};
+
+    // Every opcode followed by 34 12, at 0200
+    const BASE: [(&str, u8); 256] = [
+        ("NOP", 1), ("LD BC,$1234", 3), ("LD (BC),A", 1), ("INC BC", 1),
+        ("INC B", 1), ("DEC B", 1), ("LD B,$34", 2), ("RLCA", 1),
+        ("LD ($1234),SP", 3), ("ADD HL,BC", 1), ("LD A,(BC)", 1), ("DEC BC", 1),
+        ("INC C", 1), ("DEC C", 1), ("LD C,$34", 2), ("RRCA", 1),
+        ("STOP", 2), ("LD DE,$1234", 3), ("LD (DE),A", 1), ("INC DE", 1),
+        ("INC D", 1), ("DEC D", 1), ("LD D,$34", 2), ("RLA", 1),
+        ("JR $0236", 2), ("ADD HL,DE", 1), ("LD A,(DE)", 1), ("DEC DE", 1),
+        ("INC E", 1), ("DEC E", 1), ("LD E,$34", 2), ("RRA", 1),
+        ("JR NZ,$0236", 2), ("LD HL,$1234", 3), ("LD (HL+),A", 1), ("INC HL", 1),
+        ("INC H", 1), ("DEC H", 1), ("LD H,$34", 2), ("DAA", 1),
+        ("JR Z,$0236", 2), ("ADD HL,HL", 1), ("LD A,(HL+)", 1), ("DEC HL", 1),
+        ("INC L", 1), ("DEC L", 1), ("LD L,$34", 2), ("CPL", 1),
+        ("JR NC,$0236", 2), ("LD SP,$1234", 3), ("LD (HL-),A", 1), ("INC SP", 1),
+        ("INC (HL)", 1), ("DEC (HL)", 1), ("LD (HL),$34", 2), ("SCF", 1),
+        ("JR C,$0236", 2), ("ADD HL,SP", 1), ("LD A,(HL-)", 1), ("DEC SP", 1),
+        ("INC A", 1), ("DEC A", 1), ("LD A,$34", 2), ("CCF", 1),
+        ("LD B,B", 1), ("LD B,C", 1), ("LD B,D", 1), ("LD B,E", 1),
+        ("LD B,H", 1), ("LD B,L", 1), ("LD B,(HL)", 1), ("LD B,A", 1),
+        ("LD C,B", 1), ("LD C,C", 1), ("LD C,D", 1), ("LD C,E", 1),
+        ("LD C,H", 1), ("LD C,L", 1), ("LD C,(HL)", 1), ("LD C,A", 1),
+        ("LD D,B", 1), ("LD D,C", 1), ("LD D,D", 1), ("LD D,E", 1),
+        ("LD D,H", 1), ("LD D,L", 1), ("LD D,(HL)", 1), ("LD D,A", 1),
+        ("LD E,B", 1), ("LD E,C", 1), ("LD E,D", 1), ("LD E,E", 1),
+        ("LD E,H", 1), ("LD E,L", 1), ("LD E,(HL)", 1), ("LD E,A", 1),
+        ("LD H,B", 1), ("LD H,C", 1), ("LD H,D", 1), ("LD H,E", 1),
+        ("LD H,H", 1), ("LD H,L", 1), ("LD H,(HL)", 1), ("LD H,A", 1),
+        ("LD L,B", 1), ("LD L,C", 1), ("LD L,D", 1), ("LD L,E", 1),
+        ("LD L,H", 1), ("LD L,L", 1), ("LD L,(HL)", 1), ("LD L,A", 1),
+        ("LD (HL),B", 1), ("LD (HL),C", 1), ("LD (HL),D", 1), ("LD (HL),E", 1),
+        ("LD (HL),H", 1), ("LD (HL),L", 1), ("HALT", 1), ("LD (HL),A", 1),
+        ("LD A,B", 1), ("LD A,C", 1), ("LD A,D", 1), ("LD A,E", 1),
+        ("LD A,H", 1), ("LD A,L", 1), ("LD A,(HL)", 1), ("LD A,A", 1),
+        ("ADD A,B", 1), ("ADD A,C", 1), ("ADD A,D", 1), ("ADD A,E", 1),
+        ("ADD A,H", 1), ("ADD A,L", 1), ("ADD A,(HL)", 1), ("ADD A,A", 1),
+        ("ADC A,B", 1), ("ADC A,C", 1), ("ADC A,D", 1), ("ADC A,E", 1),
+        ("ADC A,H", 1), ("ADC A,L", 1), ("ADC A,(HL)", 1), ("ADC A,A", 1),
+        ("SUB B", 1), ("SUB C", 1), ("SUB D", 1), ("SUB E", 1),
+        ("SUB H", 1), ("SUB L", 1), ("SUB (HL)", 1), ("SUB A", 1),
+        ("SBC A,B", 1), ("SBC A,C", 1), ("SBC A,D", 1), ("SBC A,E", 1),
+        ("SBC A,H", 1), ("SBC A,L", 1), ("SBC A,(HL)", 1), ("SBC A,A", 1),
+        ("AND B", 1), ("AND C", 1), ("AND D", 1), ("AND E", 1),
+        ("AND H", 1), ("AND L", 1), ("AND (HL)", 1), ("AND A", 1),
+        ("XOR B", 1), ("XOR C", 1), ("XOR D", 1), ("XOR E", 1),
+        ("XOR H", 1), ("XOR L", 1), ("XOR (HL)", 1), ("XOR A", 1),
+        ("OR B", 1), ("OR C", 1), ("OR D", 1), ("OR E", 1),
+        ("OR H", 1), ("OR L", 1), ("OR (HL)", 1), ("OR A", 1),
+        ("CP B", 1), ("CP C", 1), ("CP D", 1), ("CP E", 1),
+        ("CP H", 1), ("CP L", 1), ("CP (HL)", 1), ("CP A", 1),
+        ("RET NZ", 1), ("POP BC", 1), ("JP NZ,$1234", 3), ("JP $1234", 3),
+        ("CALL NZ,$1234", 3), ("PUSH BC", 1), ("ADD A,$34", 2), ("RST $00", 1),
+        ("RET Z", 1), ("RET", 1), ("JP Z,$1234", 3), ("SWAP H", 2),
+        ("CALL Z,$1234", 3), ("CALL $1234", 3), ("ADC A,$34", 2), ("RST $08", 1),
+        ("RET NC", 1), ("POP DE", 1), ("JP NC,$1234", 3), ("DB $D3", 1),
+        ("CALL NC,$1234", 3), ("PUSH DE", 1), ("SUB $34", 2), ("RST $10", 1),
+        ("RET C", 1), ("RETI", 1), ("JP C,$1234", 3), ("DB $DB", 1),
+        ("CALL C,$1234", 3), ("DB $DD", 1), ("SBC A,$34", 2), ("RST $18", 1),
+        ("LDH ($FF00+$34),A", 2), ("POP HL", 1), ("LD ($FF00+C),A", 1), ("DB $E3", 1),
+        ("DB $E4", 1), ("PUSH HL", 1), ("AND $34", 2), ("RST $20", 1),
+        ("ADD SP,52", 2), ("JP (HL)", 1), ("LD ($1234),A", 3), ("DB $EB", 1),
+        ("DB $EC", 1), ("DB $ED", 1), ("XOR $34", 2), ("RST $28", 1),
+        ("LDH A,($FF00+$34)", 2), ("POP AF", 1), ("LD A,($FF00+C)", 1), ("DI", 1),
+        ("DB $F4", 1), ("PUSH AF", 1), ("OR $34", 2), ("RST $30", 1),
+        ("LD HL,SP+52", 2), ("LD SP,HL", 1), ("LD A,($1234)", 3), ("EI", 1),
+        ("DB $FC", 1), ("DB $FD", 1), ("CP $34", 2), ("RST $38", 1),
+    ];
+
+    #[test]
+    fn base_opcodes() {
+        for (op, &(text, len)) in BASE.iter().enumerate() {
+            assert_eq!(disassemble(&[op as u8, 0x34, 0x12], 0x0200), (text.to_owned(), len), "opcode {:02X}", op);
+        }
+    }
+
+    #[test]
+    fn cb_opcodes() {
+        let cases: &[(u8, &str)] = &[
+            (0x00, "RLC B"), (0x0E, "RRC (HL)"), (0x11, "RL C"), (0x1F, "RR A"),
+            (0x22, "SLA D"), (0x2D, "SRA L"), (0x37, "SWAP A"), (0x3E, "SRL (HL)"),
+            (0x40, "BIT 0,B"), (0x7C, "BIT 7,H"), (0x46, "BIT 0,(HL)"), (0x5F, "BIT 3,A"),
+            (0x80, "RES 0,B"), (0x96, "RES 2,(HL)"), (0xBF, "RES 7,A"),
+            (0xC0, "SET 0,B"), (0xDE, "SET 3,(HL)"), (0xFF, "SET 7,A"),
+        ];
+        for &(op, text) in cases {
+            assert_eq!(disassemble(&[0xCB, op], 0x0200), (text.to_owned(), 2), "opcode CB {:02X}", op);
+        }
+    }
+
+    #[test]
+    fn operands() {
+        assert_eq!(disassemble(&[0x18, 0xFE], 0x0150), ("JR $0150".to_owned(), 2));
+        assert_eq!(disassemble(&[0x20, 0x80], 0x0000), ("JR NZ,$FF82".to_owned(), 2));
+        assert_eq!(disassemble(&[0xE8, 0xFE], 0x0000), ("ADD SP,-2".to_owned(), 2));
+        assert_eq!(disassemble(&[0xF8, 0x80], 0x0000), ("LD HL,SP-128".to_owned(), 2));
+        assert_eq!(disassemble(&[0xE0, 0x40], 0x0000), ("LDH ($FF00+$40),A".to_owned(), 2));
+        assert_eq!(disassemble(&[0xC3, 0x50, 0x01], 0x0100), ("JP $0150".to_owned(), 3));
+    }
+
+    #[test]
+    fn cycle_counts() {
+        assert_eq!(instruction_cycles(&[0x00]), (1, 1));
+        assert_eq!(instruction_cycles(&[0x20, 0x00]), (2, 3));
+        assert_eq!(instruction_cycles(&[0xC4, 0x00, 0x00]), (3, 6));
+        assert_eq!(instruction_cycles(&[0xCB, 0x46]), (3, 3));
+        assert_eq!(instruction_cycles(&[0xCB, 0x06]), (4, 4));
+        assert_eq!(instruction_cycles(&[]), (0, 0));
+    }
+
+    #[test]
+    fn truncated() {
+        assert_eq!(disassemble(&[0xC3, 0x50], 0x0100), ("DB $C3".to_owned(), 1));
+        assert_eq!(disassemble(&[0xCB], 0x0100), ("DB $CB".to_owned(), 1));
+        assert_eq!(disassemble(&[0x00], 0x0100), ("NOP".to_owned(), 1));
+        assert_eq!(disassemble(&[], 0x0100), ("DB".to_owned(), 0));
+    }
+}
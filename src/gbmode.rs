@@ -1,3 +1,5 @@
+use crate::state::{StateError, StateReader, StateResult, StateWriter};
+
 #[derive(PartialEq, Copy, Clone)]
 pub enum GbMode {
     Classic,
@@ -10,3 +12,32 @@ pub enum GbSpeed {
     Single = 1,
     Double = 2,
 }
+
+impl GbMode {
+    pub fn save_state(self, w: &mut StateWriter) {
+        w.u8(self as u8);
+    }
+
+    pub fn load_state(r: &mut StateReader) -> StateResult<GbMode> {
+        match r.u8()? {
+            0 => Ok(GbMode::Classic),
+            1 => Ok(GbMode::Color),
+            2 => Ok(GbMode::ColorAsClassic),
+            _ => Err(StateError::Corrupt),
+        }
+    }
+}
+
+impl GbSpeed {
+    pub fn save_state(self, w: &mut StateWriter) {
+        w.u8(self as u8);
+    }
+
+    pub fn load_state(r: &mut StateReader) -> StateResult<GbSpeed> {
+        match r.u8()? {
+            1 => Ok(GbSpeed::Single),
+            2 => Ok(GbSpeed::Double),
+            _ => Err(StateError::Corrupt),
+        }
+    }
+}
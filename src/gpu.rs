@@ -1,10 +1,20 @@
 use std::cmp::Ordering;
 use crate::gbmode::GbMode;
+use crate::state::{StateError, StateReader, StateResult, StateWriter};
 
 const VRAM_SIZE: usize = 0x4000;
 const VOAM_SIZE: usize = 0xA0;
 pub const SCREEN_W: usize = 160;
 pub const SCREEN_H: usize = 144;
+const LINE_BYTES: usize = SCREEN_W * 3;
+// The size of render_tiles, 16 by 24 tiles, and of render_bg_map
+pub const TILE_SHEET_W: usize = 128;
+pub const TILE_SHEET_H: usize = 192;
+pub const BG_MAP_SIZE: usize = 256;
+// The outline of the SCX/SCY viewport in render_bg_map
+const VIEWPORT_COLOR: [u8; 3] = [255, 0, 0];
+const LINE153_LY_DOTS: u32 = 4;
+const CRC32_TABLE: [u32; 256] = crc32_table();
 
 #[derive(PartialEq, Copy, Clone)]
 enum PrioType {
@@ -13,6 +23,118 @@ enum PrioType {
     Normal,
 }
 
+// Named colors of the four DMG shades, lightest first. The first is the default.
+pub const DMG_PALETTES: [(&str, [[u8; 3]; 4]); 4] = [
+    ("gray", [[255, 255, 255], [192, 192, 192], [96, 96, 96], [0, 0, 0]]),
+    ("green", [[155, 188, 15], [139, 172, 15], [48, 98, 48], [15, 56, 15]]),
+    ("pocket", [[197, 202, 164], [140, 146, 107], [74, 81, 56], [24, 29, 16]]),
+    ("contrast", [[255, 255, 255], [208, 208, 208], [48, 48, 48], [0, 0, 0]]),
+];
+
+// Draws each line at once at the end of mode 3, or pixel by pixel through the pixel FIFO, which is
+// slower but shows register writes in the middle of a line
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Renderer {
+    Scanline,
+    Fifo,
+}
+
+// How the RGB555 colors of color mode are shown: as they are, or with the channels mixed and darkened
+// like on the LCD of a Gameboy Color or a Gameboy Advance
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default)]
+pub enum ColorCorrection {
+    Raw,
+    #[default]
+    CgbLcd,
+    GbaLcd,
+}
+
+pub const COLOR_CORRECTIONS: [(&str, ColorCorrection); 3] = [
+    ("raw", ColorCorrection::Raw),
+    ("cgb", ColorCorrection::CgbLcd),
+    ("gba", ColorCorrection::GbaLcd),
+];
+
+// The parts of the picture that can be hidden for debugging
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Layer {
+    Background,
+    Window,
+    Sprites,
+}
+
+// An OAM entry as debug_sprites shows it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpriteDebugInfo {
+    pub index: u8,
+    // The OAM coordinates, 16 and 8 more than on the screen
+    pub y: u8,
+    pub x: u8,
+    pub tile: u8,
+    pub flags: u8,
+    pub behind_bg: bool,
+    pub y_flip: bool,
+    pub x_flip: bool,
+    // OBP0 or OBP1, in classic mode
+    pub dmg_palette: u8,
+    // Only used in color mode
+    pub cgb_bank: u8,
+    pub cgb_palette: u8,
+    // 8, or 16 when LCDC selects 8x16 sprites
+    pub height: u8,
+    pub on_screen: bool,
+    // One of the 10 sprites drawn on the last line it was on, rather than dropped
+    pub selected: bool,
+    // The sprite as drawn, 8 by height pixels of RGBA, with color 0 transparent and all of it
+    // transparent while the sprites are hidden
+    pub pixels: Vec<u8>,
+}
+
+// A line of a BG or window tile as the fetcher pushes it
+#[derive(Copy, Clone, Default)]
+struct BgRow {
+    lo: u8,
+    hi: u8,
+    attrs: u8,
+    window: bool,
+}
+
+// A sprite pixel waiting to be mixed, color 0 is transparent
+#[derive(Copy, Clone, Default)]
+struct ObjPixel {
+    colnr: u8,
+    flags: u8,
+    index: u8,
+}
+
+// The state of the pixel FIFO during mode 3
+#[derive(Copy, Clone, Default)]
+struct Fifo {
+    // Set when the FIFO draws the line, instead of the scanline renderer at the end of mode 3
+    line: bool,
+    // Dots of the first fetch, whose tile is thrown away
+    warmup: u8,
+    // The dots into the current fetch, the tile counter, and what the fetch read so far
+    fetch_dots: u8,
+    fetch_x: u16,
+    tilenr: u8,
+    attrs: u8,
+    lo: u8,
+    // A fetched line waiting for the BG FIFO to empty
+    fetched: Option<BgRow>,
+    // The BG FIFO only takes a line when empty, so it holds the pixels left of one line
+    bg: BgRow,
+    bg_left: u8,
+    obj: [ObjPixel; 8],
+    window: bool,
+    // Pixels to drop, SCX % 8 at the start of the line or the window left of the screen
+    discard: u8,
+    lx: usize,
+    // The slot in line_sprites being fetched with the dots spent on it, and the slots done
+    sprite: Option<(usize, u8)>,
+    sprites_done: u16,
+}
+
 pub struct GPU {
     mode: u8,
     modeclock: u32,
@@ -30,18 +152,28 @@ pub struct GPU {
     m0_inte: bool,
     m1_inte: bool,
     m2_inte: bool,
+    // Level of the STAT interrupt line, the interrupt is only requested when it goes high
+    stat_line: bool,
     scy: u8,
     scx: u8,
     winy: u8,
     winx: u8,
+    // Set once LY matched WY while the window was enabled, and kept until the next frame.
+    // Changing WY afterwards does not stop the window.
     wy_trigger: bool,
+    // The internal window line counter. It only advances on lines where the window is drawn,
+    // so disabling and re-enabling the window resumes where it stopped.
     wy_pos: i32,
     palbr: u8,
     pal0r: u8,
     pal1r: u8,
-    palb: [u8; 4],
-    pal0: [u8; 4],
-    pal1: [u8; 4],
+    // The RGB colors of the shades in classic mode
+    dmg_palette: [[u8; 3]; 4],
+    // The percentage of the previous frame mixed into the one shown, 0 for none
+    frame_blend: u8,
+    // RGBA, like frame
+    previous_frame: Vec<u8>,
+    blended_frame: Vec<u8>,
     vram: [u8; VRAM_SIZE],
     voam: [u8; VOAM_SIZE],
     cbgpal_inc: bool,
@@ -50,17 +182,54 @@ pub struct GPU {
     csprit_inc: bool,
     csprit_ind: u8,
     csprit: [[[u8; 3]; 4]; 8],
+    // OPRI, sprites are ordered by their X coordinate as on a DMG
+    opri: bool,
+    // The OAM indices of the sprites that the mode 2 scan found on the line
+    line_sprites: [u8; 10],
+    // For each sprite, whether it was one of the 10 on the last line it was on
+    sprite_selected: [bool; 40],
+    line_sprite_count: usize,
+    // Length of mode 3 on the current line, in dots
+    mode3_len: u32,
+    renderer: Renderer,
+    color_correction: ColorCorrection,
+    // The RGB color of every RGB555 value with the color correction
+    color_table: Vec<[u8; 3]>,
+    // Indexed by Layer. Hidden BG and window pixels are color 0, hidden sprites are not drawn, and
+    // nothing else changes.
+    hidden_layers: [bool; 3],
+    fifo: Fifo,
     vrambank: usize,
     pub data: Vec<u8>,
+    // The color number of every pixel in data: the shade in classic mode, otherwise the number
+    // within its palette
+    indices: Vec<u8>,
+    // The last completed frame as RGBA and its color numbers, so the frame being drawn in data
+    // never shows through
+    frame: Vec<u8>,
+    frame_indices: Vec<u8>,
+    frame_count: u64,
     bgprio: [PrioType; SCREEN_W],
     pub updated: bool,
     pub interrupt: u8,
     pub gbmode: GbMode,
-    hblanking: bool,
+    // Set on entering mode 0, until the MMU takes it for the HDMA
+    hblank_start: bool,
+    // Set while the screen only contains the blank color, so turning off the LCD repeatedly is cheap
+    screen_blank: bool,
+    // Set when the LCD is turned on, until the end of its first frame, which is not shown
+    first_frame: bool,
+    lcd_off_warned: bool,
+    // CRC32 of every line of data, updated as each line is finished
+    line_crcs: [u32; SCREEN_H],
+    // The line CRCs and hash of the last completed frame
+    frame_crcs: [u32; SCREEN_H],
+    frame_hash: u64,
 }
 
 impl GPU {
     pub fn new() -> GPU {
+        let initial_crcs = [crc32(&[0; LINE_BYTES]); SCREEN_H];
         GPU {
             mode: 0,
             modeclock: 0,
@@ -78,6 +247,7 @@ impl GPU {
             m2_inte: false,
             m1_inte: false,
             m0_inte: false,
+            stat_line: false,
             scy: 0,
             scx: 0,
             winy: 0,
@@ -87,12 +257,17 @@ impl GPU {
             palbr: 0,
             pal0r: 0,
             pal1r: 1,
-            palb: [0; 4],
-            pal0: [0; 4],
-            pal1: [0; 4],
+            dmg_palette: DMG_PALETTES[0].1,
+            frame_blend: 0,
+            previous_frame: Vec::new(),
+            blended_frame: Vec::new(),
             vram: [0; VRAM_SIZE],
             voam: [0; VOAM_SIZE],
             data: vec![0; SCREEN_W * SCREEN_H * 3],
+            indices: vec![0; SCREEN_W * SCREEN_H],
+            frame: [0, 0, 0, 0xFF].repeat(SCREEN_W * SCREEN_H),
+            frame_indices: vec![0; SCREEN_W * SCREEN_H],
+            frame_count: 0,
             bgprio: [PrioType::Normal; SCREEN_W],
             updated: false,
             interrupt: 0,
@@ -103,8 +278,24 @@ impl GPU {
             csprit_inc: false,
             csprit_ind: 0,
             csprit: [[[0u8; 3]; 4]; 8],
+            opri: false,
+            line_sprites: [0; 10],
+            sprite_selected: [false; 40],
+            line_sprite_count: 0,
+            mode3_len: 172,
+            renderer: Renderer::Scanline,
+            color_correction: ColorCorrection::default(),
+            color_table: color_table(ColorCorrection::default()),
+            hidden_layers: [false; 3],
+            fifo: Fifo::default(),
             vrambank: 0,
-            hblanking: false,
+            hblank_start: false,
+            screen_blank: false,
+            first_frame: false,
+            lcd_off_warned: false,
+            line_crcs: initial_crcs,
+            frame_crcs: initial_crcs,
+            frame_hash: frame_hash(&initial_crcs),
         }
     }
 
@@ -114,20 +305,31 @@ impl GPU {
 
     pub fn do_cycle(&mut self, ticks: u32) {
         if !self.lcd_on { return }
-        self.hblanking = false;
 
         let mut ticksleft = ticks;
 
         while ticksleft > 0 {
-            let curticks = if ticksleft >= 80 { 80 } else { ticksleft };
+            let curticks = if self.renderer == Renderer::Fifo { 1 } else if ticksleft >= 80 { 80 } else { ticksleft };
+            let line153_ly0 = self.line == 153 && self.modeclock < LINE153_LY_DOTS;
             self.modeclock += curticks;
             ticksleft -= curticks;
 
+            // LY turns 0 early in line 153, which compares it with LYC again
+            if line153_ly0 && self.modeclock >= LINE153_LY_DOTS {
+                self.update_stat_line();
+            }
+
             // Full line takes 114 ticks
             if self.modeclock >= 456 {
                 self.modeclock -= 456;
                 self.line = (self.line + 1) % 154;
-                self.check_interrupt_lyc();
+                self.update_stat_line();
+
+                // The mode 2 source sees the start of line 144 too, just before VBlank
+                if self.line == 144 && self.m2_inte && !self.stat_line {
+                    self.interrupt |= 0x02;
+                    self.stat_line = true;
+                }
 
                 // This is a VBlank line
                 if self.line >= 144 && self.mode != 1 {
@@ -137,50 +339,81 @@ impl GPU {
 
             // This is a normal line
             if self.line < 144 {
-                if self.modeclock <= 80 {
-                    if self.mode != 2 { self.change_mode(2); }
-                } else if self.modeclock <= (80 + 172) { // 252 cycles
-                    if self.mode != 3 { self.change_mode(3); }
-                } else { // the remaining 204
+                if self.modeclock < 80 {
+                    if self.mode != 2 && !(self.first_frame && self.line == 0) { self.change_mode(2); }
+                } else if self.mode == 3 && self.fifo.line && self.fifo.lx < SCREEN_W {
+                    self.fifo_dot();
+                    // HBlank starts on the next dot
+                    if self.fifo.lx == SCREEN_W { self.mode3_len = self.modeclock + 1 - 80; }
+                } else if self.modeclock < 80 + self.mode3_len { // at least 172 dots
+                    if self.mode != 3 {
+                        self.change_mode(3);
+                        if self.fifo.line { self.fifo_dot(); }
+                    }
+                } else { // the rest of the 456
                     if self.mode != 0 { self.change_mode(0); }
                 }
             }
         }
     }
 
-    fn check_interrupt_lyc(&mut self) {
-        if self.lyc_inte && self.line == self.lyc {
+    // LY reads 153 only for the first M-cycle of the last line, and 0 for the rest of it
+    fn ly(&self) -> u8 {
+        if self.line == 153 && self.modeclock >= LINE153_LY_DOTS { 0 } else { self.line }
+    }
+
+    fn stat_level(&self) -> bool {
+        self.lcd_on && (
+            (self.lyc_inte && self.ly() == self.lyc) ||
+            (self.m0_inte && self.mode == 0) ||
+            (self.m1_inte && self.mode == 1) ||
+            (self.m2_inte && self.mode == 2))
+    }
+
+    fn update_stat_line(&mut self) {
+        let level = self.stat_level();
+        if level && !self.stat_line {
             self.interrupt |= 0x02;
         }
+        self.stat_line = level;
     }
 
     fn change_mode(&mut self, mode: u8) {
         self.mode = mode;
 
-        if match self.mode {
+        match self.mode {
             0 => {
                 self.renderscan();
-                self.hblanking = true;
-                self.m0_inte
+                self.hblank_start = true;
             },
             1 => { // Vertical blank
                 self.wy_trigger = false;
                 self.interrupt |= 0x01;
-                self.updated = true;
-                self.m1_inte
+                if self.first_frame {
+                    self.first_frame = false;
+                } else {
+                    self.finish_frame(true);
+                }
+            },
+            2 => {
+                self.scan_oam();
             },
-            2 => self.m2_inte,
             3 => {
-                if self.win_on && self.wy_trigger == false && self.line == self.winy {
+                if self.win_on && !self.wy_trigger && self.line == self.winy {
                     self.wy_trigger = true;
                     self.wy_pos = -1;
                 }
-                false
-            }
-            _ => false,
-        } {
-            self.interrupt |= 0x02;
+                self.mode3_len = self.mode3_length();
+                self.fifo = Fifo {
+                    line: self.renderer == Renderer::Fifo,
+                    warmup: 6,
+                    discard: self.scx % 8,
+                    ..Fifo::default()
+                };
+            },
+            _ => {},
         }
+        self.update_stat_line();
     }
 
     pub fn rb(&self, a: u16) -> u8 {
@@ -203,12 +436,12 @@ impl GPU {
                 (if self.m2_inte { 0x20 } else { 0 }) |
                 (if self.m1_inte { 0x10 } else { 0 }) |
                 (if self.m0_inte { 0x08 } else { 0 }) |
-                (if self.line == self.lyc { 0x04 } else { 0 }) |
+                (if self.ly() == self.lyc { 0x04 } else { 0 }) |
                 self.mode
             },
             0xFF42 => self.scy,
             0xFF43 => self.scx,
-            0xFF44 => self.line,
+            0xFF44 => self.ly(),
             0xFF45 => self.lyc,
             0xFF46 => 0, // Write only
             0xFF47 => self.palbr,
@@ -218,7 +451,7 @@ impl GPU {
             0xFF4B => self.winx,
             0xFF4C => 0xFF,
             0xFF4E => 0xFF,
-            0xFF4F ..= 0xFF6B if self.gbmode != GbMode::Color => { 0xFF },
+            0xFF4F ..= 0xFF6C if self.gbmode != GbMode::Color => { 0xFF },
             0xFF4F => self.vrambank as u8 | 0xFE,
             0xFF68 => { 0x40 | self.cbgpal_ind | (if self.cbgpal_inc { 0x80 } else { 0 }) },
             0xFF69 => {
@@ -240,6 +473,7 @@ impl GPU {
                     ((self.csprit[palnum][colnum][1] & 0x18) >> 3) | (self.csprit[palnum][colnum][2] << 2)
                 }
             },
+            0xFF6C => 0xFE | self.opri as u8,
             _ => 0xFF,
         }
     }
@@ -259,45 +493,61 @@ impl GPU {
             0xFE00 ..= 0xFE9F => self.voam[a as usize - 0xFE00] = v,
             0xFF40 => {
                 let orig_lcd_on = self.lcd_on;
-                self.lcd_on = v & 0x80 == 0x80;
-                self.win_tilemap = if v & 0x40 == 0x40 { 0x9C00 } else { 0x9800 };
-                self.win_on = v & 0x20 == 0x20;
-                self.tilebase = if v & 0x10 == 0x10 { 0x8000 } else { 0x8800 };
-                self.bg_tilemap = if v & 0x08 == 0x08 { 0x9C00 } else { 0x9800 };
-                self.sprite_size = if v & 0x04 == 0x04 { 16 } else { 8 };
-                self.sprite_on = v & 0x02 == 0x02;
-                self.lcdc0 = v & 0x01 == 0x01;
+                self.set_lcdc(v);
                 if orig_lcd_on && !self.lcd_on {
+                    if self.mode != 1 && !self.lcd_off_warned {
+                        eprintln!("Warning: the LCD was turned off outside VBlank, at line {}, which can damage a real Gameboy", self.line);
+                        self.lcd_off_warned = true;
+                    }
                     self.modeclock = 0;
                     self.line = 0;
                     self.mode = 0;
+                    self.stat_line = false;
                     self.wy_trigger = false;
+                    self.first_frame = false;
+                    self.fifo = Fifo::default();
                     self.clear_screen();
                 }
-                if !orig_lcd_on && self.lcd_on { self.change_mode(2); self.modeclock = 4; }
+                if !orig_lcd_on && self.lcd_on {
+                    // Line 0 starts in mode 0 instead of 2, and the screen stays blank for the
+                    // first frame
+                    self.modeclock = 4;
+                    self.first_frame = true;
+                    self.scan_oam();
+                    self.update_stat_line();
+                }
             },
             0xFF41 => {
+                // On the DMG, a write briefly enables every STAT source, which fires a
+                // spurious interrupt during HBlank, VBlank or LY=LYC
+                if self.gbmode == GbMode::Classic {
+                    self.lyc_inte = true;
+                    self.m1_inte = true;
+                    self.m0_inte = true;
+                    self.update_stat_line();
+                }
                 self.lyc_inte = v & 0x40 == 0x40;
                 self.m2_inte = v & 0x20 == 0x20;
                 self.m1_inte = v & 0x10 == 0x10;
                 self.m0_inte = v & 0x08 == 0x08;
+                self.update_stat_line();
             },
             0xFF42 => self.scy = v,
             0xFF43 => self.scx = v,
             0xFF44 => {}, // Read-only
             0xFF45 => {
                 self.lyc = v;
-                self.check_interrupt_lyc();
+                self.update_stat_line();
             },
             0xFF46 => panic!("0xFF46 should be handled by MMU"),
-            0xFF47 => { self.palbr = v; self.update_pal(); },
-            0xFF48 => { self.pal0r = v; self.update_pal(); },
-            0xFF49 => { self.pal1r = v; self.update_pal(); },
+            0xFF47 => self.palbr = v,
+            0xFF48 => self.pal0r = v,
+            0xFF49 => self.pal1r = v,
             0xFF4A => self.winy = v,
             0xFF4B => self.winx = v,
             0xFF4C => {},
             0xFF4E => {},
-            0xFF4F ..= 0xFF6B if self.gbmode != GbMode::Color => {},
+            0xFF4F ..= 0xFF6C if self.gbmode != GbMode::Color => {},
             0xFF4F => self.vrambank = (v & 0x01) as usize,
             0xFF68 => { self.cbgpal_ind = v & 0x3F; self.cbgpal_inc = v & 0x80 == 0x80; },
             0xFF69 => {
@@ -325,68 +575,291 @@ impl GPU {
                 }
                 if self.csprit_inc { self.csprit_ind = (self.csprit_ind + 1) & 0x3F; };
             },
+            0xFF6C => self.opri = v & 0x01 == 0x01,
             _ => panic!("GPU does not handle write {:04X}", a),
         }
     }
 
+    fn set_lcdc(&mut self, v: u8) {
+        self.lcd_on = v & 0x80 == 0x80;
+        self.win_tilemap = if v & 0x40 == 0x40 { 0x9C00 } else { 0x9800 };
+        self.win_on = v & 0x20 == 0x20;
+        self.tilebase = if v & 0x10 == 0x10 { 0x8000 } else { 0x8800 };
+        self.bg_tilemap = if v & 0x08 == 0x08 { 0x9C00 } else { 0x9800 };
+        self.sprite_size = if v & 0x04 == 0x04 { 16 } else { 8 };
+        self.sprite_on = v & 0x02 == 0x02;
+        self.lcdc0 = v & 0x01 == 0x01;
+    }
+
     fn clear_screen(&mut self) {
-        for v in self.data.iter_mut() {
-            *v = 255;
+        if !self.screen_blank {
+            let blank = self.blank_color();
+            for pixel in self.data.chunks_exact_mut(3) {
+                pixel.copy_from_slice(&blank);
+            }
+            self.indices.fill(0);
+            self.screen_blank = true;
+            self.line_crcs = [crc32(&self.data[.. LINE_BYTES]); SCREEN_H];
+            self.finish_frame(true);
+            self.restart_blend();
+        } else {
+            // The last frame is this blank screen already, and the blend restarted on it
+            self.finish_frame(false);
         }
+    }
+
+    fn finish_frame(&mut self, changed: bool) {
+        self.frame_crcs = self.line_crcs;
+        self.frame_hash = frame_hash(&self.frame_crcs);
         self.updated = true;
+        if changed {
+            self.frame_indices.copy_from_slice(&self.indices);
+            self.present_frame();
+        } else {
+            self.frame_count += 1;
+        }
+        if changed && self.frame_blend > 0 {
+            self.blend_frame();
+        }
     }
 
-    fn update_pal(&mut self) {
-        for i in 0 .. 4 {
-            self.palb[i] = GPU::get_monochrome_pal_val(self.palbr, i);
-            self.pal0[i] = GPU::get_monochrome_pal_val(self.pal0r, i);
-            self.pal1[i] = GPU::get_monochrome_pal_val(self.pal1r, i);
+    fn present_frame(&mut self) {
+        for (rgba, rgb) in self.frame.chunks_exact_mut(4).zip(self.data.chunks_exact(3)) {
+            rgba[.. 3].copy_from_slice(rgb);
         }
+        self.frame_count += 1;
     }
 
-    fn get_monochrome_pal_val(value: u8, index: usize) -> u8 {
-        match (value >> 2*index) & 0x03 {
-            0 => 255,
-            1 => 192,
-            2 => 96,
-            _ => 0
+    // Like the slow LCD, which hides sprites flickering every other frame
+    fn blend_frame(&mut self) {
+        let weight = self.frame_blend as u16;
+        for ((shown, &new), previous) in self.blended_frame.iter_mut().zip(self.frame.iter()).zip(self.previous_frame.iter_mut()) {
+            *shown = ((new as u16 * (100 - weight) + *previous as u16 * weight) / 100) as u8;
+            *previous = new;
         }
     }
 
-    fn renderscan(&mut self) {
+    // Shows the last frame without mixing in the ones before
+    fn restart_blend(&mut self) {
+        if self.frame_blend > 0 {
+            self.previous_frame.copy_from_slice(&self.frame);
+            self.blended_frame.copy_from_slice(&self.frame);
+        }
+    }
+
+    // The state after the boot ROM, which turned on the LCD long before
+    pub fn skip_lcd_startup(&mut self) {
+        self.first_frame = false;
+        if self.lcd_on && self.line == 0 && self.modeclock < 80 {
+            self.change_mode(2);
+        }
+    }
+
+    // Up to 100 percent of the previous frame, from the next frame on
+    pub fn set_frame_blend(&mut self, percent: u8) {
+        self.frame_blend = percent.min(100);
+        if self.frame_blend == 0 {
+            self.previous_frame = Vec::new();
+            self.blended_frame = Vec::new();
+        } else if self.previous_frame.is_empty() {
+            self.previous_frame = self.frame.clone();
+            self.blended_frame = self.frame.clone();
+        }
+    }
+
+    pub fn frame_blend(&self) -> u8 {
+        self.frame_blend
+    }
+
+    // The last completed frame as RGBA, blended with the one before when frame blending is on
+    pub fn framebuffer(&self) -> &[u8] {
+        if self.frame_blend == 0 { &self.frame } else { &self.blended_frame }
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    // Carries the count over a reset, so that it keeps changing with every frame
+    pub fn set_frame_count(&mut self, count: u64) {
+        self.frame_count = count;
+    }
+
+    pub fn frame_hash(&self) -> u64 {
+        self.frame_hash
+    }
+
+    // The color numbers of the last completed frame, one byte per pixel
+    pub fn last_frame_indices(&self) -> &[u8] {
+        &self.frame_indices
+    }
+
+    pub fn scanline_crcs(&self) -> [u32; SCREEN_H] {
+        self.frame_crcs
+    }
+
+    // Both VRAM banks, bank 1 after bank 0
+    pub fn vram(&self) -> &[u8] {
+        &self.vram
+    }
+
+    pub fn vram_mut(&mut self) -> &mut [u8] {
+        &mut self.vram
+    }
+
+    pub fn oam(&self) -> &[u8] {
+        &self.voam
+    }
+
+    pub fn oam_mut(&mut self) -> &mut [u8] {
+        &mut self.voam
+    }
+
+    pub fn debug_sprites(&self) -> Vec<SpriteDebugInfo> {
+        let height = self.sprite_size as usize;
+        (0 .. 40).map(|index| {
+            let oam = &self.voam[index * 4 .. index * 4 + 4];
+            let (y, x, tile, flags) = (oam[0], oam[1], oam[2], oam[3]);
+            let first_tile = if height == 16 { tile & 0xFE } else { tile };
+            let mut pixels = vec![0; 8 * height * 4];
+            for row in 0 .. height {
+                let tiley = if flags & 0x40 != 0 { height - 1 - row } else { row };
+                let a = 0x8000 + first_tile as u16 * 16 + tiley as u16 * 2;
+                let (b1, b2) = if flags & 0x08 != 0 && self.gbmode == GbMode::Color {
+                    (self.rbvram1(a), self.rbvram1(a + 1))
+                } else {
+                    (self.rbvram0(a), self.rbvram0(a + 1))
+                };
+                for pixelx in 0 .. 8 {
+                    let colnr = tile_colnr(b1, b2, flags & 0x20 != 0, pixelx);
+                    if colnr != 0 && self.layer_visible(Layer::Sprites) {
+                        put_rgba(&mut pixels, row * 8 + pixelx as usize, self.sprite_color(colnr, flags).0);
+                    }
+                }
+            }
+            SpriteDebugInfo {
+                index: index as u8,
+                y,
+                x,
+                tile,
+                flags,
+                behind_bg: flags & 0x80 != 0,
+                y_flip: flags & 0x40 != 0,
+                x_flip: flags & 0x20 != 0,
+                dmg_palette: (flags >> 4) & 1,
+                cgb_bank: (flags >> 3) & 1,
+                cgb_palette: flags & 0x07,
+                height: height as u8,
+                on_screen: x > 0 && (x as usize) < SCREEN_W + 8 && y as usize + height > 16 && (y as usize) < SCREEN_H + 16,
+                selected: self.sprite_selected[index],
+                pixels,
+            }
+        }).collect()
+    }
+
+    // The 384 tiles of a VRAM bank in the gray palette, as RGBA
+    pub fn render_tiles(&self, bank: usize) -> Vec<u8> {
+        let (_, gray) = DMG_PALETTES[0];
+        let tiles = &self.vram[(bank & 1) * 0x2000 .. (bank & 1) * 0x2000 + 0x1800];
+        let mut rgba = vec![0; TILE_SHEET_W * TILE_SHEET_H * 4];
+        for y in 0 .. TILE_SHEET_H {
+            for x in 0 .. TILE_SHEET_W {
+                let a = ((y / 8) * (TILE_SHEET_W / 8) + x / 8) * 16 + (y % 8) * 2;
+                let colnr = tile_colnr(tiles[a], tiles[a + 1], false, (x % 8) as u8);
+                put_rgba(&mut rgba, y * TILE_SHEET_W + x, gray[colnr]);
+            }
+        }
+        rgba
+    }
+
+    // The whole BG of the map at 9C00 or 9800 with the tile data at 8000 or 8800, like LCDC bits 3
+    // and 4, as RGBA, in color 0 while the BG is hidden. The screen at SCX and SCY is outlined.
+    pub fn render_bg_map(&self, map_select: bool, tile_data_select: bool) -> Vec<u8> {
+        let tilemapbase = if map_select { 0x9C00 } else { 0x9800 };
+        let tilebase = if tile_data_select { 0x8000 } else { 0x8800 };
+        let mut rgba = vec![0; BG_MAP_SIZE * BG_MAP_SIZE * 4];
+        for y in 0 .. BG_MAP_SIZE {
+            for x in 0 .. BG_MAP_SIZE {
+                let (tilenr, attrs) = self.map_entry(tilemapbase, y as u16 / 8, x as u16 / 8);
+                let b1 = self.tile_byte(tilebase, tilenr, attrs, y as u16 % 8, false);
+                let b2 = self.tile_byte(tilebase, tilenr, attrs, y as u16 % 8, true);
+                let colnr = self.shown_bg_colnr(false, tile_colnr(b1, b2, attrs & 0x20 != 0, (x % 8) as u8));
+                put_rgba(&mut rgba, y * BG_MAP_SIZE + x, self.bg_color(colnr, attrs).0);
+            }
+        }
+
+        let (left, top) = (self.scx as usize, self.scy as usize);
         for x in 0 .. SCREEN_W {
-            self.setcolor(x, 255);
-            self.bgprio[x] = PrioType::Normal;
+            for &y in [top, top + SCREEN_H - 1].iter() {
+                put_rgba(&mut rgba, (y % BG_MAP_SIZE) * BG_MAP_SIZE + (left + x) % BG_MAP_SIZE, VIEWPORT_COLOR);
+            }
         }
-        self.draw_bg();
-        self.draw_sprites();
+        for y in 0 .. SCREEN_H {
+            for &x in [left, left + SCREEN_W - 1].iter() {
+                put_rgba(&mut rgba, ((top + y) % BG_MAP_SIZE) * BG_MAP_SIZE + x % BG_MAP_SIZE, VIEWPORT_COLOR);
+            }
+        }
+        rgba
+    }
+
+    // Only changes how classic mode looks, from the next line drawn on
+    pub fn set_dmg_palette(&mut self, palette: [[u8; 3]; 4]) {
+        self.dmg_palette = palette;
+        self.screen_blank = false;
     }
 
-    fn setcolor(&mut self, x: usize, color: u8) {
-        self.data[self.line as usize * SCREEN_W * 3 + x * 3 + 0] = color;
-        self.data[self.line as usize * SCREEN_W * 3 + x * 3 + 1] = color;
-        self.data[self.line as usize * SCREEN_W * 3 + x * 3 + 2] = color;
+    pub fn dmg_palette(&self) -> [[u8; 3]; 4] {
+        self.dmg_palette
     }
 
-    fn setrgb(&mut self, x: usize, r: u8, g: u8, b: u8) {
-        // Gameboy Color RGB correction
-        // Taken from the Gambatte emulator
-        // assume r, g and b are between 0 and 1F
-        let baseidx = self.line as usize * SCREEN_W * 3 + x * 3;
+    // The color of the screen with the LCD off, or of the BG when it is disabled
+    fn blank_color(&self) -> [u8; 3] {
+        if self.gbmode == GbMode::Classic { self.dmg_palette[0] } else { [255; 3] }
+    }
 
-        let r = r as u32;
-        let g = g as u32;
-        let b = b as u32;
+    // The colors a DMG game gets on a Gameboy Color, as RGB555 for BGP, OBP0 and OBP1. This is
+    // what the CGB boot ROM does before it hands over, with OPRI set.
+    pub fn set_compat_palettes(&mut self, palettes: &[[u16; 4]; 3]) {
+        let rgb = |colors: &[u16; 4]| colors.map(|c| [(c & 0x1F) as u8, ((c >> 5) & 0x1F) as u8, ((c >> 10) & 0x1F) as u8]);
+        self.cbgpal[0] = rgb(&palettes[0]);
+        self.csprit[0] = rgb(&palettes[1]);
+        self.csprit[1] = rgb(&palettes[2]);
+        self.opri = true;
+    }
 
-        self.data[baseidx + 0] = ((r * 13 + g * 2 + b) >> 1) as u8;
-        self.data[baseidx + 1] = ((g * 3 + b) << 1) as u8;
-        self.data[baseidx + 2] = ((r * 3 + g * 2 + b * 11) >> 1) as u8;
+    // The shade a DMG palette register gives a color number
+    fn shade(palette: u8, colnr: usize) -> usize {
+        ((palette >> (2 * colnr)) & 0x03) as usize
     }
 
+    fn renderscan(&mut self) {
+        self.screen_blank = false;
+        if !self.fifo.line {
+            let blank = self.blank_color();
+            for x in 0 .. SCREEN_W {
+                self.setpixel(x, blank, 0);
+                self.bgprio[x] = PrioType::Normal;
+            }
+            self.draw_bg();
+            self.draw_sprites();
+        }
+
+        let start = self.line as usize * LINE_BYTES;
+        self.line_crcs[self.line as usize] = crc32(&self.data[start .. start + LINE_BYTES]);
+    }
+
+    fn setpixel(&mut self, x: usize, color: [u8; 3], index: usize) {
+        let pixel = self.line as usize * SCREEN_W + x;
+        self.data[pixel * 3 .. pixel * 3 + 3].copy_from_slice(&color);
+        self.indices[pixel] = index as u8;
+    }
+
+    // Uses the register values at the end of mode 3, so mid-frame changes of WX apply to the current line
     fn draw_bg(&mut self) {
         let drawbg = self.gbmode == GbMode::Color || self.lcdc0;
 
-        let wx_trigger = self.winx <= 166;
+        // WX 166 puts the window past the right edge, and it does not count the line
+        let wx_trigger = self.winx < 166;
         let winy = if self.win_on && self.wy_trigger && wx_trigger {
             self.wy_pos += 1;
             self.wy_pos
@@ -407,8 +880,9 @@ impl GPU {
         for x in 0 .. SCREEN_W {
             let winx = - ((self.winx as i32) - 7) + (x as i32);
             let bgx = self.scx as u32 + x as u32;
+            let window = winy >= 0 && winx >= 0;
 
-            let (tilemapbase, tiley, tilex, pixely, pixelx) = if winy >= 0 && winx >= 0 {
+            let (tilemapbase, tiley, tilex, pixely, pixelx) = if window {
                 (self.win_tilemap,
                 wintiley,
                 (winx as u16 >> 3),
@@ -424,141 +898,534 @@ impl GPU {
                 continue;
             };
 
-            let tilenr: u8 = self.rbvram0(tilemapbase + tiley * 32 + tilex);
+            let (tilenr, attrs) = self.map_entry(tilemapbase, tiley, tilex);
+            let b1 = self.tile_byte(self.tilebase, tilenr, attrs, pixely, false);
+            let b2 = self.tile_byte(self.tilebase, tilenr, attrs, pixely, true);
+            let colnr = tile_colnr(b1, b2, attrs & 0x20 != 0, pixelx);
+            let colnr = self.shown_bg_colnr(window, colnr);
+            self.set_bg_pixel(x, colnr, attrs);
+        }
+    }
 
-            let (palnr, vram1, xflip, yflip, prio) = if self.gbmode == GbMode::Color {
-                let flags = self.rbvram1(tilemapbase + tiley * 32 + tilex) as usize;
-                (flags & 0x07,
-                flags & (1 << 3) != 0,
-                flags & (1 << 5) != 0,
-                flags & (1 << 6) != 0,
-                flags & (1 << 7) != 0)
-            } else {
-                (0, false, false, false, false)
-            };
+    // The tile number and the CGB attributes of an entry of the BG or window map
+    fn map_entry(&self, tilemapbase: u16, tiley: u16, tilex: u16) -> (u8, u8) {
+        let a = tilemapbase + tiley * 32 + tilex;
+        let attrs = if self.gbmode == GbMode::Color { self.rbvram1(a) } else { 0 };
+        (self.rbvram0(a), attrs)
+    }
 
-            let tileaddress = self.tilebase
-            + (if self.tilebase == 0x8000 {
+    // The low or high byte of a line of a BG or window tile, with the tile data at 8000 or 8800
+    fn tile_byte(&self, tilebase: u16, tilenr: u8, attrs: u8, pixely: u16, high: bool) -> u8 {
+        let tileaddress = tilebase
+            + (if tilebase == 0x8000 {
                 tilenr as u16
             } else {
                 (tilenr as i8 as i16 + 128) as u16
             }) * 16;
+        let row = if attrs & 0x40 != 0 { 7 - pixely } else { pixely };
+        let a = tileaddress + row * 2 + high as u16;
+        if attrs & 0x08 != 0 { self.rbvram1(a) } else { self.rbvram0(a) }
+    }
 
-            let a0 = match yflip {
-                false => tileaddress + (pixely * 2),
-                true => tileaddress + (14 - (pixely * 2)),
-            };
+    // Color 0 for a BG or window pixel of a hidden layer, so that sprites show over it
+    fn shown_bg_colnr(&self, window: bool, colnr: usize) -> usize {
+        let layer = if window { Layer::Window } else { Layer::Background };
+        if self.layer_visible(layer) { colnr } else { 0 }
+    }
 
-            let (b1, b2) = match vram1 {
-                false => (self.rbvram0(a0), self.rbvram0(a0 + 1)),
-                true => (self.rbvram1(a0), self.rbvram1(a0 + 1)),
-            };
+    fn set_bg_pixel(&mut self, x: usize, colnr: usize, attrs: u8) {
+        self.bgprio[x] =
+            if colnr == 0 { PrioType::Color0 }
+            else if attrs & 0x80 != 0 { PrioType::PrioFlag }
+            else { PrioType::Normal };
+        let (color, index) = self.bg_color(colnr, attrs);
+        self.setpixel(x, color, index);
+    }
 
-            let xbit = match xflip {
-                true => pixelx,
-                false => 7 - pixelx,
-            } as u32;
-            let colnr = if b1 & (1 << xbit) != 0 { 1 } else { 0 }
-                | if b2 & (1 << xbit) != 0 { 2 } else { 0 };
-
-            self.bgprio[x] =
-                if colnr == 0 { PrioType::Color0 }
-                else if prio { PrioType::PrioFlag }
-                else { PrioType::Normal };
-            if self.gbmode == GbMode::Color {
-                let r = self.cbgpal[palnr][colnr][0];
-                let g = self.cbgpal[palnr][colnr][1];
-                let b = self.cbgpal[palnr][colnr][2];
-                self.setrgb(x as usize, r, g, b);
-            } else {
-                let color = self.palb[colnr];
-                self.setcolor(x, color);
-            }
-        }
+    // The RGB color of a color of the CGB palettes, with the color correction
+    fn cgb_color(&self, [r, g, b]: [u8; 3]) -> [u8; 3] {
+        self.color_table[r as usize | (g as usize) << 5 | (b as usize) << 10]
     }
 
-    fn draw_sprites(&mut self) {
-        if !self.sprite_on { return }
+    // The RGB color of a BG color number and the number that indices keeps for it
+    fn bg_color(&self, colnr: usize, attrs: u8) -> ([u8; 3], usize) {
+        if self.gbmode == GbMode::Color {
+            (self.cgb_color(self.cbgpal[attrs as usize & 0x07][colnr]), colnr)
+        } else if self.gbmode == GbMode::ColorAsClassic {
+            let shade = GPU::shade(self.palbr, colnr);
+            (self.cgb_color(self.cbgpal[0][shade]), shade)
+        } else {
+            let shade = GPU::shade(self.palbr, colnr);
+            (self.dmg_palette[shade], shade)
+        }
+    }
 
+    // The two bytes of the sprite's tile on the current line, or None when it is not on the line
+    fn sprite_tile_bytes(&self, index: u8) -> Option<(u8, u8)> {
+        let spriteaddr = index as usize * 4;
         let line = self.line as i32;
         let sprite_size = self.sprite_size as i32;
+        let spritey = self.voam[spriteaddr] as i32 - 16;
+        if line < spritey || line >= spritey + sprite_size { return None }
+
+        let tilenum = (self.voam[spriteaddr + 2] & (if self.sprite_size == 16 { 0xFE } else { 0xFF })) as u16;
+        let flags = self.voam[spriteaddr + 3];
+        let tiley = if flags & 0x40 != 0 {
+            (sprite_size - 1 - (line - spritey)) as u16
+        } else {
+            (line - spritey) as u16
+        };
+        let tileaddress = 0x8000u16 + tilenum * 16 + tiley * 2;
+        Some(if flags & 0x08 != 0 && self.gbmode == GbMode::Color {
+            (self.rbvram1(tileaddress), self.rbvram1(tileaddress + 1))
+        } else {
+            (self.rbvram0(tileaddress), self.rbvram0(tileaddress + 1))
+        })
+    }
+
+    // Draws a sprite pixel over the BG pixel at x, unless the BG has priority
+    fn set_sprite_pixel(&mut self, x: usize, colnr: usize, flags: u8) {
+        let belowbg = flags & 0x80 != 0;
+        if self.gbmode == GbMode::Color {
+            if self.lcdc0 && (self.bgprio[x] == PrioType::PrioFlag || (belowbg && self.bgprio[x] != PrioType::Color0)) {
+                return
+            }
+        } else if belowbg && self.bgprio[x] != PrioType::Color0 {
+            return
+        }
+        let (color, index) = self.sprite_color(colnr, flags);
+        self.setpixel(x, color, index);
+    }
+
+    // The RGB color of a sprite color number and the number that indices keeps for it
+    fn sprite_color(&self, colnr: usize, flags: u8) -> ([u8; 3], usize) {
+        let usepal1 = flags & 0x10 != 0;
+        if self.gbmode == GbMode::Color {
+            (self.cgb_color(self.csprit[flags as usize & 0x07][colnr]), colnr)
+        } else if self.gbmode == GbMode::ColorAsClassic {
+            let (palnr, palette) = if usepal1 { (1, self.pal1r) } else { (0, self.pal0r) };
+            let shade = GPU::shade(palette, colnr);
+            (self.cgb_color(self.csprit[palnr][shade]), shade)
+        } else {
+            let palette = if usepal1 { self.pal1r } else { self.pal0r };
+            let shade = GPU::shade(palette, colnr);
+            (self.dmg_palette[shade], shade)
+        }
+    }
+
+    fn draw_sprites(&mut self) {
+        if !self.sprite_on || !self.layer_visible(Layer::Sprites) { return }
 
         let mut sprites_to_draw = [(0, 0, 0); 10];
-        let mut sidx = 0;
-        for index in 0 .. 40 {
+        let sidx = self.line_sprite_count;
+        for (entry, &index) in sprites_to_draw.iter_mut().zip(self.line_sprites()) {
             let spriteaddr = 0xFE00 + (index as u16) * 4;
             let spritey = self.rb(spriteaddr + 0) as u16 as i32 - 16;
-            if line < spritey || line >= spritey + sprite_size { continue }
             let spritex = self.rb(spriteaddr + 1) as u16 as i32 - 8;
-            sprites_to_draw[sidx] = (spritex, spritey, index);
-            sidx += 1;
-            if sidx >= 10 {
-                break;
-            }
+            *entry = (spritex, spritey, index);
         }
-        if self.gbmode == GbMode::Color {
+        if self.gbmode == GbMode::Color && !self.opri {
             sprites_to_draw[..sidx].sort_unstable_by(cgb_sprite_order);
         }
         else {
             sprites_to_draw[..sidx].sort_unstable_by(dmg_sprite_order);
         }
 
-        for &(spritex, spritey, i) in &sprites_to_draw[..sidx] {
+        // Sprites are mixed before the BG priority applies: the first opaque sprite pixel in
+        // priority order owns its column, even when the BG then hides it
+        let mut taken = [false; SCREEN_W];
+        for &(spritex, _, i) in sprites_to_draw[..sidx].iter().rev() {
             if spritex < -7 || spritex >= (SCREEN_W as i32) { continue }
+            // Moved off the line since the scan
+            let (b1, b2) = match self.sprite_tile_bytes(i) {
+                Some(bytes) => bytes,
+                None => continue,
+            };
+            let flags = self.voam[i as usize * 4 + 3];
 
-            let spriteaddr = 0xFE00 + (i as u16) * 4;
-            let tilenum = (self.rb(spriteaddr + 2) & (if self.sprite_size == 16 { 0xFE } else { 0xFF })) as u16;
-            let flags = self.rb(spriteaddr + 3) as usize;
-            let usepal1: bool = flags & (1 << 4) != 0;
-            let xflip: bool = flags & (1 << 5) != 0;
-            let yflip: bool = flags & (1 << 6) != 0;
-            let belowbg: bool = flags & (1 << 7) != 0;
-            let c_palnr = flags & 0x07;
-            let c_vram1: bool = flags & (1 << 3) != 0;
-
-            let tiley: u16 = if yflip {
-                (sprite_size - 1 - (line - spritey)) as u16
+            for x in 0 .. 8 {
+                if spritex + x < 0 || spritex + x >= (SCREEN_W as i32) { continue }
+                let colnr = tile_colnr(b1, b2, flags & 0x20 != 0, x as u8);
+                let px = (spritex + x) as usize;
+                if colnr == 0 || taken[px] { continue }
+                taken[px] = true;
+                self.set_sprite_pixel(px, colnr, flags);
+            }
+        }
+    }
+
+    // One dot of mode 3 with the pixel FIFO: a pixel goes out unless a sprite fetch holds the
+    // FIFO, then the BG fetcher and the sprite fetch make a step
+    fn fifo_dot(&mut self) {
+        if self.fifo.sprite.is_none() && self.fifo.bg_left > 0 {
+            if self.window_starts() {
+                self.fifo.window = true;
+                self.fifo.bg_left = 0;
+                self.fifo.fetched = None;
+                self.fifo.fetch_dots = 0;
+                self.fifo.fetch_x = 0;
+                self.fifo.discard = 7u8.saturating_sub(self.winx);
+                self.wy_pos += 1;
+            } else if let Some(slot) = self.fifo_sprite_hit() {
+                self.fifo.sprite = Some((slot, 0));
             } else {
-                (line - spritey) as u16
-            };
+                self.fifo_pop();
+            }
+        }
 
-            let tileaddress = 0x8000u16 + tilenum * 16 + tiley * 2;
-            let (b1, b2) = if c_vram1 && self.gbmode == GbMode::Color {
-                (self.rbvram1(tileaddress), self.rbvram1(tileaddress + 1))
+        if self.fifo.warmup > 0 {
+            self.fifo.warmup -= 1;
+        } else if self.fifo.fetched.is_none() {
+            self.fifo_fetch();
+        }
+        if self.fifo.bg_left == 0 {
+            if let Some(row) = self.fifo.fetched.take() {
+                self.fifo.bg = row;
+                self.fifo.bg_left = 8;
+            }
+        }
+
+        // A sprite is fetched in 6 dots, once the BG fetcher has its next tile
+        if let (Some((slot, dots)), Some(_)) = (self.fifo.sprite, self.fifo.fetched) {
+            if dots == 5 {
+                self.fifo.sprite = None;
+                self.fifo_merge_sprite(slot);
             } else {
-                (self.rbvram0(tileaddress), self.rbvram0(tileaddress + 1))
-            };
+                self.fifo.sprite = Some((slot, dots + 1));
+            }
+        }
+    }
 
-            'xloop: for x in 0 .. 8 {
-                if spritex + x < 0 || spritex + x >= (SCREEN_W as i32) { continue }
+    // WX 166 never starts the window, and WX below 7 starts it with pixels left of the screen
+    fn window_starts(&self) -> bool {
+        !self.fifo.window && self.fifo.discard == 0 && self.win_on && self.wy_trigger && self.winx < 166
+            && self.fifo.lx + 7 >= self.winx as usize
+    }
 
-                let xbit = 1 << (if xflip { x } else { 7 - x } as u32);
-                let colnr = (if b1 & xbit != 0 { 1 } else { 0 }) |
-                    (if b2 & xbit != 0 { 2 } else { 0 });
-                if colnr == 0 { continue }
+    // The sprite to fetch before the next pixel, the leftmost first. Sprites left of the screen
+    // are all fetched before the first pixel, whatever SCX.
+    fn fifo_sprite_hit(&self) -> Option<usize> {
+        if !self.sprite_on { return None }
+        let lx = self.fifo.lx;
+        (0 .. self.line_sprite_count)
+            .filter(|&slot| self.fifo.sprites_done & (1 << slot) == 0)
+            .map(|slot| (self.voam[self.line_sprites[slot] as usize * 4 + 1] as usize, slot))
+            .filter(|&(x, _)| (x < 8 && lx == 0) || (self.fifo.discard == 0 && x == lx + 8))
+            .min()
+            .map(|(_, slot)| slot)
+    }
 
-                if self.gbmode == GbMode::Color {
-                    if self.lcdc0 && (self.bgprio[(spritex + x) as usize] == PrioType::PrioFlag || (belowbg && self.bgprio[(spritex + x) as usize] != PrioType::Color0)) {
-                        continue 'xloop
-                    }
-                    let r = self.csprit[c_palnr][colnr][0];
-                    let g = self.csprit[c_palnr][colnr][1];
-                    let b = self.csprit[c_palnr][colnr][2];
-                    self.setrgb((spritex + x) as usize, r, g, b);
+    fn fifo_pop(&mut self) {
+        let pixelx = 8 - self.fifo.bg_left;
+        self.fifo.bg_left -= 1;
+        if self.fifo.discard > 0 {
+            self.fifo.discard -= 1;
+            return;
+        }
+
+        let x = self.fifo.lx;
+        let row = self.fifo.bg;
+        if row.window || self.gbmode == GbMode::Color || self.lcdc0 {
+            let colnr = tile_colnr(row.lo, row.hi, row.attrs & 0x20 != 0, pixelx);
+            let colnr = self.shown_bg_colnr(row.window, colnr);
+            self.set_bg_pixel(x, colnr, row.attrs);
+        } else {
+            let blank = self.blank_color();
+            self.setpixel(x, blank, 0);
+            self.bgprio[x] = PrioType::Normal;
+        }
+
+        let obj = self.fifo.obj[0];
+        self.fifo.obj.copy_within(1 .., 0);
+        self.fifo.obj[7] = ObjPixel::default();
+        if obj.colnr != 0 && self.sprite_on && self.layer_visible(Layer::Sprites) {
+            self.set_sprite_pixel(x, obj.colnr as usize, obj.flags);
+        }
+        self.fifo.lx += 1;
+    }
+
+    // The fetcher reads the map entry, the low byte and the high byte at its dots 2, 4 and 6
+    fn fifo_fetch(&mut self) {
+        self.fifo.fetch_dots += 1;
+        let pixely = if self.fifo.window {
+            self.wy_pos as u16 & 0x07
+        } else {
+            self.scy.wrapping_add(self.line) as u16 & 0x07
+        };
+        match self.fifo.fetch_dots {
+            2 => {
+                let (tilemapbase, tiley, tilex) = if self.fifo.window {
+                    (self.win_tilemap, (self.wy_pos as u16 >> 3) & 31, self.fifo.fetch_x & 31)
                 } else {
-                    if belowbg && self.bgprio[(spritex + x) as usize] != PrioType::Color0 { continue 'xloop }
-                    let color = if usepal1 { self.pal1[colnr] } else { self.pal0[colnr] };
-                    self.setcolor((spritex + x) as usize, color);
-                }
+                    (self.bg_tilemap,
+                    (self.scy.wrapping_add(self.line) as u16 >> 3) & 31,
+                    ((self.scx as u16 >> 3) + self.fifo.fetch_x) & 31)
+                };
+                let (tilenr, attrs) = self.map_entry(tilemapbase, tiley, tilex);
+                self.fifo.tilenr = tilenr;
+                self.fifo.attrs = attrs;
+            },
+            4 => self.fifo.lo = self.tile_byte(self.tilebase, self.fifo.tilenr, self.fifo.attrs, pixely, false),
+            6 => {
+                let hi = self.tile_byte(self.tilebase, self.fifo.tilenr, self.fifo.attrs, pixely, true);
+                self.fifo.fetched = Some(BgRow { lo: self.fifo.lo, hi, attrs: self.fifo.attrs, window: self.fifo.window });
+                self.fifo.fetch_dots = 0;
+                self.fifo.fetch_x += 1;
+            },
+            _ => {},
+        }
+    }
+
+    // Fills the transparent OBJ FIFO pixels, or on a CGB those of a sprite later in OAM
+    fn fifo_merge_sprite(&mut self, slot: usize) {
+        self.fifo.sprites_done |= 1 << slot;
+        let index = self.line_sprites[slot];
+        let (lo, hi) = match self.sprite_tile_bytes(index) {
+            Some(bytes) => bytes,
+            None => return,
+        };
+        let x = self.voam[index as usize * 4 + 1] as usize;
+        let flags = self.voam[index as usize * 4 + 3];
+        // The columns left of the screen are gone
+        let skip = self.fifo.lx + 8 - x;
+        let oam_order = self.gbmode == GbMode::Color && !self.opri;
+        for (i, pixel) in self.fifo.obj[.. 8 - skip].iter_mut().enumerate() {
+            let colnr = tile_colnr(lo, hi, flags & 0x20 != 0, (i + skip) as u8) as u8;
+            if colnr != 0 && (pixel.colnr == 0 || (oam_order && index < pixel.index)) {
+                *pixel = ObjPixel { colnr, flags, index };
             }
         }
     }
 
-    pub fn may_hdma(&self) -> bool {
-        return self.hblanking;
+    // The first 10 sprites in OAM whose Y covers the line, whatever their X
+    fn scan_oam(&mut self) {
+        let line = self.line as i32;
+        let sprite_size = self.sprite_size as i32;
+        self.line_sprite_count = 0;
+        for index in 0 .. 40 {
+            let spritey = self.voam[index * 4] as i32 - 16;
+            if line < spritey || line >= spritey + sprite_size { continue }
+            // The sprites after the first 10 are only scanned for debug_sprites
+            self.sprite_selected[index] = self.line_sprite_count < 10;
+            if self.line_sprite_count < 10 {
+                self.line_sprites[self.line_sprite_count] = index as u8;
+                self.line_sprite_count += 1;
+            }
+        }
+    }
+
+    // The penalties of the Pan Docs: SCX % 8 discarded pixels, 6 dots to start the window, and
+    // 6 dots per sprite plus the wait for the background fetch of a tile not seen by an earlier sprite
+    fn mode3_length(&self) -> u32 {
+        let window = self.win_on && self.wy_trigger && self.winx < 166;
+        let mut len = 172 + (self.scx % 8) as u32;
+        if window { len += 6; }
+        if !self.sprite_on { return len; }
+
+        // BG tiles of the line in 0..32, window tiles in 32..64
+        let mut tiles_seen = [false; 64];
+        for &index in self.line_sprites() {
+            let x = self.voam[index as usize * 4 + 1] as usize;
+            if x == 0 {
+                len += 11;
+                continue;
+            }
+            if x >= 168 { continue }
+            let (tile, offset) = if window && x > self.winx as usize {
+                let winx = x - self.winx as usize - 1;
+                (32 + winx / 8, winx % 8)
+            } else {
+                ((x + (self.scx % 8) as usize) / 8, (x + self.scx as usize) % 8)
+            };
+            if !tiles_seen[tile] {
+                tiles_seen[tile] = true;
+                len += 5u32.saturating_sub(offset as u32);
+            }
+            len += 6;
+        }
+        len
+    }
+
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.renderer = renderer;
+    }
+
+    pub fn renderer(&self) -> Renderer {
+        self.renderer
+    }
+
+    // Applies to the pixels drawn from now on
+    pub fn set_color_correction(&mut self, correction: ColorCorrection) {
+        if correction != self.color_correction {
+            self.color_correction = correction;
+            self.color_table = color_table(correction);
+        }
+    }
+
+    pub fn color_correction(&self) -> ColorCorrection {
+        self.color_correction
+    }
+
+    pub fn set_layer_visible(&mut self, layer: Layer, visible: bool) {
+        self.hidden_layers[layer as usize] = !visible;
+    }
+
+    pub fn layer_visible(&self, layer: Layer) -> bool {
+        !self.hidden_layers[layer as usize]
+    }
+
+    pub fn line_sprites(&self) -> &[u8] {
+        &self.line_sprites[.. self.line_sprite_count]
+    }
+
+    pub fn take_hblank_start(&mut self) -> bool {
+        ::std::mem::replace(&mut self.hblank_start, false)
+    }
+
+    // An HDMA started in an HBlank, or with the LCD off, copies its first block right away
+    pub fn hdma_block_now(&self) -> bool {
+        !self.lcd_on || self.mode == 0
+    }
+
+    // The screen and the CRCs of the last frame are included, so it shows the same after a load
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.rb(0xFF40));
+        w.u8(self.rb(0xFF41) & 0x78);
+        w.u8(self.mode);
+        w.u32(self.modeclock);
+        w.u8(self.line);
+        w.u8(self.lyc);
+        w.u8(self.scy);
+        w.u8(self.scx);
+        w.u8(self.winy);
+        w.u8(self.winx);
+        w.bool(self.wy_trigger);
+        w.i32(self.wy_pos);
+        w.u8(self.palbr);
+        w.u8(self.pal0r);
+        w.u8(self.pal1r);
+        w.bytes(&self.vram);
+        w.bytes(&self.voam);
+        w.bool(self.cbgpal_inc);
+        w.u8(self.cbgpal_ind);
+        w.bytes(self.cbgpal.as_flattened().as_flattened());
+        w.bool(self.csprit_inc);
+        w.u8(self.csprit_ind);
+        w.bytes(self.csprit.as_flattened().as_flattened());
+        w.bool(self.opri);
+        w.usize(self.vrambank);
+        w.u8(self.interrupt);
+        self.gbmode.save_state(w);
+        w.bool(self.hblank_start);
+        w.bool(self.screen_blank);
+        w.bool(self.first_frame);
+        w.bytes(&self.data);
+        for &crc in self.line_crcs.iter().chain(self.frame_crcs.iter()) {
+            w.u32(crc);
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        let lcdc = r.u8()?;
+        self.set_lcdc(lcdc);
+        let stat = r.u8()?;
+        self.wb(0xFF41, stat);
+        self.mode = r.u8()?;
+        self.modeclock = r.u32()?;
+        self.line = r.u8()?;
+        if self.mode > 3 || self.modeclock >= 456 || self.line >= 154 {
+            return Err(StateError::Corrupt);
+        }
+        self.lyc = r.u8()?;
+        self.scy = r.u8()?;
+        self.scx = r.u8()?;
+        self.winy = r.u8()?;
+        self.winx = r.u8()?;
+        self.wy_trigger = r.bool()?;
+        self.wy_pos = r.i32()?;
+        self.palbr = r.u8()?;
+        self.pal0r = r.u8()?;
+        self.pal1r = r.u8()?;
+        r.bytes_into(&mut self.vram)?;
+        r.bytes_into(&mut self.voam)?;
+        self.cbgpal_inc = r.bool()?;
+        self.cbgpal_ind = r.u8()? & 0x3F;
+        r.bytes_into(self.cbgpal.as_flattened_mut().as_flattened_mut())?;
+        self.csprit_inc = r.bool()?;
+        self.csprit_ind = r.u8()? & 0x3F;
+        r.bytes_into(self.csprit.as_flattened_mut().as_flattened_mut())?;
+        // The color components have 5 bits
+        for palettes in [&mut self.cbgpal, &mut self.csprit] {
+            for component in palettes.as_flattened_mut().as_flattened_mut() {
+                *component &= 0x1F;
+            }
+        }
+        self.opri = r.bool()?;
+        self.vrambank = r.index(2)?;
+        self.interrupt = r.u8()?;
+        self.gbmode = GbMode::load_state(r)?;
+        self.hblank_start = r.bool()?;
+        self.screen_blank = r.bool()?;
+        self.first_frame = r.bool()?;
+        r.bytes_into(&mut self.data)?;
+        for crc in self.line_crcs.iter_mut().chain(self.frame_crcs.iter_mut()) {
+            *crc = r.u32()?;
+        }
+        self.frame_hash = frame_hash(&self.frame_crcs);
+        if self.line < SCREEN_H as u8 {
+            self.scan_oam();
+        }
+        self.mode3_len = self.mode3_length();
+        // A line loaded in mode 3 is finished by the scanline renderer
+        self.fifo = Fifo::default();
+        self.stat_line = self.stat_level();
+        // The screen of the state is shown as it is, without blending it with the one before
+        self.present_frame();
+        self.restart_blend();
+        Ok(())
     }
 }
 
+// Gameboy Color RGB correction
+// Taken from the Gambatte emulator
+// assume r, g and b are between 0 and 1F
+fn cgb_color([r, g, b]: [u8; 3]) -> [u8; 3] {
+    let r = r as u32;
+    let g = g as u32;
+    let b = b as u32;
+    [((r * 13 + g * 2 + b) >> 1) as u8, ((g * 3 + b) << 1) as u8, ((r * 3 + g * 2 + b * 11) >> 1) as u8]
+}
+
+// The formula of higan: the LCD gamma of 4, a mix of the channels, and the gamma of 2.2 of the display
+fn gba_color([r, g, b]: [u8; 3]) -> [u8; 3] {
+    let linear = |v: u8| (v as f64 / 31.0).powf(4.0);
+    let (r, g, b) = (linear(r), linear(g), linear(b));
+    let out = |v: f64| ((v / 255.0).powf(1.0 / 2.2) * 255.0 * 255.0 / 280.0).round().min(255.0) as u8;
+    [out(255.0 * r + 50.0 * g), out(10.0 * r + 230.0 * g + 30.0 * b), out(50.0 * r + 10.0 * g + 220.0 * b)]
+}
+
+// The RGB colors of the 32768 RGB555 values, so the correction costs nothing per pixel
+fn color_table(correction: ColorCorrection) -> Vec<[u8; 3]> {
+    (0 .. 0x8000u16).map(|c| {
+        let rgb = [(c & 0x1F) as u8, ((c >> 5) & 0x1F) as u8, ((c >> 10) & 0x1F) as u8];
+        match correction {
+            ColorCorrection::Raw => rgb.map(|v| (v << 3) | (v >> 2)),
+            ColorCorrection::CgbLcd => cgb_color(rgb),
+            ColorCorrection::GbaLcd => gba_color(rgb),
+        }
+    }).collect()
+}
+
+fn put_rgba(rgba: &mut [u8], pixel: usize, [r, g, b]: [u8; 3]) {
+    rgba[pixel * 4 .. pixel * 4 + 4].copy_from_slice(&[r, g, b, 0xFF]);
+}
+
+// The color number of a pixel of a tile line, counted from the left
+fn tile_colnr(b1: u8, b2: u8, xflip: bool, pixelx: u8) -> usize {
+    let xbit = if xflip { pixelx } else { 7 - pixelx };
+    (((b1 >> xbit) & 1) | (((b2 >> xbit) & 1) << 1)) as usize
+}
+
 // Functions to determine the order of sprites. Input is a tuple x-coord, OAM position
 // These function ensures that sprites with a higher priority are 'larger'
 fn dmg_sprite_order(a: &(i32, i32, u8), b: &(i32, i32, u8)) -> Ordering {
@@ -573,3 +1440,1159 @@ fn cgb_sprite_order(a: &(i32, i32, u8), b: &(i32, i32, u8)) -> Ordering {
     // CGB order: only prioritize based on OAM position.
     return b.2.cmp(&a.2);
 }
+
+// Returns the first line that differs between two sets of scanline CRCs
+pub fn first_differing_scanline(a: &[u32; SCREEN_H], b: &[u32; SCREEN_H]) -> Option<usize> {
+    a.iter().zip(b.iter()).position(|(x, y)| x != y)
+}
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for &b in data {
+        crc = CRC32_TABLE[((crc ^ b as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+// FNV-1a over the line CRCs, so the hash does not need another pass over the screen
+fn frame_hash(crcs: &[u32; SCREEN_H]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for crc in crcs.iter() {
+        for b in crc.to_le_bytes() {
+            hash = (hash ^ b as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+    hash
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BG_MAP_SIZE, COLOR_CORRECTIONS, ColorCorrection, DMG_PALETTES, GPU, Layer, Renderer, SCREEN_H, SCREEN_W, LINE_BYTES, TILE_SHEET_H, TILE_SHEET_W, cgb_color, color_table, crc32, first_differing_scanline};
+    use crate::gbmode::GbMode;
+
+    const WHITE: u8 = 255;
+    const PALETTE: [u8; 4] = [255, 192, 96, 0];
+
+    // Tile 0 is blank and used for the background, the window map uses a distinct pattern
+    fn window_gpu(wy: u8, wx: u8) -> GPU {
+        let mut gpu = GPU::new();
+        for tile in 1 .. 256u16 {
+            for row in 0 .. 8u16 {
+                gpu.wb(0x8000 + tile * 16 + row * 2, (tile * 7 + row * 13) as u8);
+                gpu.wb(0x8000 + tile * 16 + row * 2 + 1, (tile * 3 + row * 29) as u8);
+            }
+        }
+        for i in 0 .. 0x400u16 {
+            gpu.wb(0x9800 + i, 0);
+            gpu.wb(0x9C00 + i, (i % 255 + 1) as u8);
+        }
+        gpu.wb(0xFF47, 0xE4);
+        gpu.wb(0xFF4A, wy);
+        gpu.wb(0xFF4B, wx);
+        gpu.wb(0xFF40, 0xF1);
+        // The first frame is shown, as after the boot ROM
+        gpu.skip_lcd_startup();
+        gpu
+    }
+
+    // Runs one frame, writing the register values of the script at the start of the given lines
+    fn render(gpu: GPU, script: &[(u8, u16, u8)]) -> Vec<u8> {
+        render_frame(gpu, script).data
+    }
+
+    fn render_frame(mut gpu: GPU, script: &[(u8, u16, u8)]) -> GPU {
+        for line in 0 .. SCREEN_H as u8 {
+            for &(_, a, v) in script.iter().filter(|entry| entry.0 == line) {
+                gpu.wb(a, v);
+            }
+            while gpu.line == line {
+                gpu.do_cycle(4);
+            }
+        }
+        gpu
+    }
+
+    fn window_pixel(gpu: &GPU, x: u16, y: u16) -> u8 {
+        let tile = gpu.rbvram0(0x9C00 + (y / 8) * 32 + x / 8) as u16;
+        let b1 = gpu.rbvram0(0x8000 + tile * 16 + (y % 8) * 2);
+        let b2 = gpu.rbvram0(0x8000 + tile * 16 + (y % 8) * 2 + 1);
+        let bit = 7 - (x % 8);
+        let colnr = ((b1 >> bit) & 1) | (((b2 >> bit) & 1) << 1);
+        PALETTE[colnr as usize]
+    }
+
+    // The documented rules: the window starts on the first line where LY equals WY while it is
+    // enabled. From then on, each line where it is enabled and WX <= 166 shows the next line of the
+    // window, starting at WX - 7.
+    fn reference(wy: u8, wx: u8, script: &[(u8, u16, u8)]) -> Vec<u8> {
+        let gpu = window_gpu(wy, wx);
+        let (mut wy, mut wx, mut enabled) = (wy, wx, true);
+        let mut started = false;
+        let mut window_line = 0;
+        let mut image = vec![WHITE; SCREEN_W * SCREEN_H * 3];
+
+        for line in 0 .. SCREEN_H {
+            for &(_, a, v) in script.iter().filter(|entry| entry.0 as usize == line) {
+                match a {
+                    0xFF40 => enabled = v & 0x20 == 0x20,
+                    0xFF4A => wy = v,
+                    0xFF4B => wx = v,
+                    _ => unreachable!(),
+                }
+            }
+            if enabled && !started && line == wy as usize {
+                started = true;
+            }
+            if !enabled || !started || wx >= 166 {
+                continue;
+            }
+            for x in 0 .. SCREEN_W as i32 {
+                let window_x = x - (wx as i32 - 7);
+                if window_x < 0 { continue }
+                let color = window_pixel(&gpu, window_x as u16, window_line);
+                for c in 0 .. 3 {
+                    image[(line * SCREEN_W + x as usize) * 3 + c] = color;
+                }
+            }
+            window_line += 1;
+        }
+        image
+    }
+
+    fn check(wy: u8, wx: u8, script: &[(u8, u16, u8)]) {
+        let actual = render(window_gpu(wy, wx), script);
+        let expected = reference(wy, wx, script);
+        for line in 0 .. SCREEN_H {
+            let range = line * SCREEN_W * 3 .. (line + 1) * SCREEN_W * 3;
+            assert!(actual[range.clone()] == expected[range], "line {} differs", line);
+        }
+    }
+
+    #[test]
+    fn window_static() {
+        check(20, 47, &[]);
+    }
+
+    #[test]
+    fn window_wy_change_after_start() {
+        check(10, 7, &[(50, 0xFF4A, 120)]);
+        // Moving WY to an earlier line than LY does not restart the window either
+        check(10, 7, &[(50, 0xFF4A, 0), (80, 0xFF4A, 80)]);
+    }
+
+    #[test]
+    fn window_wy_change_before_start() {
+        check(100, 7, &[(50, 0xFF4A, 60)]);
+        // WY moved past LY before it matched, the window never starts
+        check(100, 7, &[(50, 0xFF4A, 20)]);
+    }
+
+    #[test]
+    fn window_disable_resumes_line_counter() {
+        check(10, 7, &[(40, 0xFF40, 0xD1), (60, 0xFF40, 0xF1)]);
+        // Disabled while LY equals WY, so it only starts on a later match
+        check(30, 7, &[(30, 0xFF40, 0xD1), (31, 0xFF40, 0xF1), (50, 0xFF4A, 70)]);
+    }
+
+    #[test]
+    fn window_wx_change_mid_frame() {
+        check(0, 7, &[(30, 0xFF4B, 87), (31, 0xFF4B, 200), (60, 0xFF4B, 3), (90, 0xFF4B, 166)]);
+    }
+
+    #[test]
+    fn window_wx_edges() {
+        // WX below 7 cuts off the left of the window
+        for wx in 0 .. 7 {
+            check(0, wx, &[]);
+        }
+        check(0, 165, &[]);
+        check(0, 166, &[]);
+        let data = render(window_gpu(0, 166), &[]);
+        assert!(data.iter().all(|&v| v == WHITE));
+        // Lines with WX 166 do not advance the window line
+        check(0, 7, &[(20, 0xFF4B, 166), (40, 0xFF4B, 7)]);
+    }
+
+    #[test]
+    fn lcdc_write_storm() {
+        let mut gpu = window_gpu(0, 7);
+        gpu.set_frame_blend(50);
+        gpu.do_cycle(456 * 10);
+
+        let start = ::std::time::Instant::now();
+        for i in 0 .. 1_000_000 {
+            gpu.wb(0xFF40, if i % 2 == 0 { 0x71 } else { 0xF1 });
+        }
+        assert!(start.elapsed() < ::std::time::Duration::from_secs(5), "took {:?}", start.elapsed());
+        assert!(gpu.data.iter().all(|&v| v == WHITE));
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+    }
+
+    #[test]
+    fn scanline_crcs_match_frame() {
+        let gpu = render_frame(window_gpu(20, 47), &[]);
+        assert!(gpu.updated);
+        let crcs = gpu.scanline_crcs();
+        for (line, data) in gpu.data.chunks(LINE_BYTES).enumerate() {
+            assert_eq!(crcs[line], crc32(data), "line {}", line);
+        }
+
+        let again = render_frame(window_gpu(20, 47), &[]);
+        assert_eq!(gpu.frame_hash(), again.frame_hash());
+        assert_eq!(first_differing_scanline(&crcs, &again.scanline_crcs()), None);
+    }
+
+    #[test]
+    fn first_differing_scanline_is_found() {
+        let gpu = render_frame(window_gpu(20, 47), &[]);
+        let changed = render_frame(window_gpu(20, 47), &[(50, 0xFF4B, 48)]);
+        assert_ne!(gpu.frame_hash(), changed.frame_hash());
+        assert_eq!(first_differing_scanline(&gpu.scanline_crcs(), &changed.scanline_crcs()), Some(50));
+    }
+
+    #[test]
+    fn lcd_off_hashes_blank_frame() {
+        let mut gpu = render_frame(window_gpu(20, 47), &[]);
+        gpu.wb(0xFF40, 0x71);
+        let blank = crc32(&[WHITE; LINE_BYTES]);
+        assert!(gpu.scanline_crcs().iter().all(|&crc| crc == blank));
+
+        let mut other = GPU::new();
+        other.wb(0xFF40, 0x80);
+        other.wb(0xFF40, 0x00);
+        assert_eq!(gpu.frame_hash(), other.frame_hash());
+    }
+
+    #[test]
+    fn vram_banks() {
+        let mut gpu = GPU::new_cgb();
+        gpu.gbmode = GbMode::Color;
+        assert_eq!(gpu.rb(0xFF4F), 0xFE);
+        gpu.wb(0xFF4F, 0xFF);
+        assert_eq!(gpu.rb(0xFF4F), 0xFF);
+
+        // Tile 0 is color 1 in bank 0 and color 2 in bank 1. The first map entry uses bank 1
+        // and palette 2.
+        for row in 0 .. 8 {
+            gpu.wb(0x8000 + row * 2 + 1, 0xFF);
+        }
+        gpu.wb(0x9800, 0x0A);
+        gpu.wb(0xFF4F, 0x00);
+        for row in 0 .. 8 {
+            gpu.wb(0x8000 + row * 2, 0xFF);
+        }
+        assert_eq!((gpu.rb(0x8000), gpu.rb(0x8001), gpu.rb(0x9800)), (0xFF, 0x00, 0x00));
+
+        // Palette 0 color 1 is blue, palette 2 color 2 is red
+        for (index, color) in [(0x02, 0x7C00u16), (0x14, 0x001F)] {
+            gpu.wb(0xFF68, 0x80 | index);
+            gpu.wb(0xFF69, color as u8);
+            gpu.wb(0xFF69, (color >> 8) as u8);
+        }
+        gpu.wb(0xFF40, 0x91);
+        let data = render(gpu, &[]);
+        assert_eq!((&data[0 .. 3], &data[8 * 3 .. 9 * 3]), (&[201, 0, 46][..], &[15, 62, 170][..]));
+    }
+
+    fn pixel(data: &[u8], x: usize, y: usize) -> [u8; 3] {
+        let i = (y * SCREEN_W + x) * 3;
+        [data[i], data[i + 1], data[i + 2]]
+    }
+
+    #[test]
+    fn cgb_palettes_and_attributes() {
+        const BLACK: [u8; 3] = [0, 0, 0];
+        const BLUE: [u8; 3] = [15, 62, 170];
+        const GREEN: [u8; 3] = [31, 186, 31];
+        const WHITE: [u8; 3] = [248, 248, 248];
+        let mut gpu = GPU::new_cgb();
+        gpu.gbmode = GbMode::Color;
+
+        // BG palette 1 is black, blue, red and white, written through the incrementing index
+        gpu.wb(0xFF68, 0x88);
+        for color in [0x0000u16, 0x7C00, 0x001F, 0x7FFF] {
+            gpu.wb(0xFF69, color as u8);
+            gpu.wb(0xFF69, (color >> 8) as u8);
+        }
+        assert_eq!(gpu.rb(0xFF68), 0xD0);
+        // Reading does not increment the index
+        gpu.wb(0xFF68, 0x0B);
+        assert_eq!((gpu.rb(0xFF69), gpu.rb(0xFF69), gpu.rb(0xFF68)), (0x7C, 0x7C, 0x4B));
+        // OBJ palette 3 color 1 is green
+        gpu.wb(0xFF6A, 0x80 | 0x1A);
+        gpu.wb(0xFF6B, 0xE0);
+        gpu.wb(0xFF6B, 0x03);
+        assert_eq!(gpu.rb(0xFF6A), 0xDC);
+
+        // Tile 1 has color 1 in its top left corner, tile 2 is all color 1 and tile 3 all color 3
+        gpu.wb(0x8010, 0x80);
+        for row in 0 .. 8 {
+            gpu.wb(0x8020 + row * 2, 0xFF);
+            gpu.wb(0x8030 + row * 2, 0xFF);
+            gpu.wb(0x8031 + row * 2, 0xFF);
+        }
+        // Tile 1 plain, flipped in X and flipped in Y, then tile 3 with and without priority, all
+        // in palette 1
+        for (i, &(tile, flags)) in [(1, 0x01), (1, 0x21), (1, 0x41), (3, 0x81), (3, 0x01)].iter().enumerate() {
+            gpu.wb(0x9800 + i as u16, tile);
+            gpu.wb(0xFF4F, 0x01);
+            gpu.wb(0x9800 + i as u16, flags);
+            gpu.wb(0xFF4F, 0x00);
+        }
+        // Sprites of tile 2 in palette 3 over the last two tiles
+        for (i, &x) in [32, 40].iter().enumerate() {
+            for (j, &v) in [16, x, 2, 0x03].iter().enumerate() {
+                gpu.wb(0xFE00 + (i * 4 + j) as u16, v);
+            }
+        }
+        gpu.wb(0xFF40, 0x93);
+        let data = render(gpu, &[]);
+
+        assert_eq!((pixel(&data, 0, 0), pixel(&data, 1, 0), pixel(&data, 0, 1)), (BLUE, BLACK, BLACK));
+        assert_eq!((pixel(&data, 15, 0), pixel(&data, 8, 0)), (BLUE, BLACK));
+        assert_eq!((pixel(&data, 16, 7), pixel(&data, 16, 0)), (BLUE, BLACK));
+        // The priority of the tile hides the sprite
+        assert_eq!((pixel(&data, 24, 3), pixel(&data, 32, 3)), (WHITE, GREEN));
+    }
+
+    // Sprites of tile 1, all color 3, on line 0 at the X positions with the attributes
+    fn sprite_gpu(mut gpu: GPU, sprites: &[(u8, u8)]) -> GPU {
+        for row in 0 .. 16 {
+            gpu.wb(0x8010 + row, 0xFF);
+        }
+        for (i, &(x, flags)) in sprites.iter().enumerate() {
+            for (j, &v) in [16, x + 8, 1, flags].iter().enumerate() {
+                gpu.wb(0xFE00 + (i * 4 + j) as u16, v);
+            }
+        }
+        gpu.wb(0xFF48, 0x40);
+        gpu.wb(0xFF49, 0x80);
+        gpu.wb(0xFF40, 0x82);
+        gpu.skip_lcd_startup();
+        gpu
+    }
+
+    #[test]
+    fn dmg_sprite_priority() {
+        // The sprite with the smaller X is in front, whatever the OAM order
+        let data = render(sprite_gpu(GPU::new(), &[(12, 0x00), (8, 0x10)]), &[]);
+        assert_eq!((data[10 * 3], data[13 * 3], data[18 * 3]), (PALETTE[2], PALETTE[2], PALETTE[1]));
+        // With the same X, the first in OAM
+        let data = render(sprite_gpu(GPU::new(), &[(8, 0x00), (8, 0x10)]), &[]);
+        assert_eq!(data[10 * 3], PALETTE[1]);
+    }
+
+    #[test]
+    fn cgb_sprite_priority() {
+        let cgb_gpu = |opri: bool| {
+            let mut gpu = GPU::new_cgb();
+            gpu.gbmode = GbMode::Color;
+            // Color 3 of OBJ palette 0 is blue, of palette 1 red
+            gpu.wb(0xFF6A, 0x86);
+            gpu.wb(0xFF6B, 0x00);
+            gpu.wb(0xFF6B, 0x7C);
+            gpu.wb(0xFF6A, 0x8E);
+            gpu.wb(0xFF6B, 0x1F);
+            gpu.wb(0xFF6B, 0x00);
+            gpu.wb(0xFF6C, opri as u8);
+            sprite_gpu(gpu, &[(12, 0x00), (8, 0x01)])
+        };
+        // The first in OAM is in front, unless OPRI asks for the DMG order
+        let data = render(cgb_gpu(false), &[]);
+        assert_eq!((data[10 * 3], data[13 * 3]), (201, 15));
+        let data = render(cgb_gpu(true), &[]);
+        assert_eq!((data[10 * 3], data[13 * 3]), (201, 201));
+    }
+
+    #[test]
+    fn cgb_bg_to_oam_priority() {
+        // Columns of tile 2 (color 3) or tile 0 (color 0), with or without the BG priority attribute,
+        // each under a sprite with or without the OAM priority flag
+        let cases: Vec<(bool, bool, bool)> = (0 .. 8).map(|i| (i & 4 != 0, i & 2 != 0, i & 1 != 0)).collect();
+        let cgb_gpu = |renderer: Renderer| {
+            let mut gpu = GPU::new_cgb();
+            gpu.gbmode = GbMode::Color;
+            gpu.set_renderer(renderer);
+            // BG palette 0 color 3 is blue, OBJ palette 0 color 3 red
+            gpu.wb(0xFF68, 0x86);
+            gpu.wb(0xFF69, 0x00);
+            gpu.wb(0xFF69, 0x7C);
+            gpu.wb(0xFF6A, 0x86);
+            gpu.wb(0xFF6B, 0x1F);
+            gpu.wb(0xFF6B, 0x00);
+            for row in 0 .. 16 {
+                gpu.wb(0x8020 + row, 0xFF);
+            }
+            for (i, &(color3, bg_prio, _)) in cases.iter().enumerate() {
+                gpu.wb(0x9800 + i as u16, if color3 { 2 } else { 0 });
+                gpu.wb(0xFF4F, 0x01);
+                gpu.wb(0x9800 + i as u16, (bg_prio as u8) << 7);
+                gpu.wb(0xFF4F, 0x00);
+            }
+            let sprites: Vec<(u8, u8)> = cases.iter().enumerate()
+                .map(|(i, &(_, _, oam_prio))| (i as u8 * 8, (oam_prio as u8) << 7))
+                .collect();
+            sprite_gpu(gpu, &sprites)
+        };
+        let red = cgb_color([31, 0, 0]);
+        let blue = cgb_color([0, 0, 31]);
+        for &renderer in [Renderer::Scanline, Renderer::Fifo].iter() {
+            for &lcdc0 in [false, true].iter() {
+                let gpu = cgb_gpu(renderer);
+                let data = render(gpu, &[(0, 0xFF40, 0x92 | lcdc0 as u8)]);
+                for (i, &(color3, bg_prio, oam_prio)) in cases.iter().enumerate() {
+                    // LCDC bit 0 off puts every sprite in front, BG color 0 is always behind
+                    let bg_wins = lcdc0 && color3 && (bg_prio || oam_prio);
+                    let expected = if bg_wins { blue } else { red };
+                    assert_eq!(pixel(&data, i * 8 + 3, 0), expected, "{:?} lcdc0 {} case {}", renderer, lcdc0, i);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn hidden_sprite_hides_lower_sprites() {
+        // The BG is color 3 from x 8. The sprite at 8 wins over the one at 12 and is behind the
+        // BG, so neither shows where they overlap
+        for &(gbmode, renderer) in [(GbMode::Classic, Renderer::Scanline), (GbMode::Classic, Renderer::Fifo),
+                                    (GbMode::Color, Renderer::Scanline), (GbMode::Color, Renderer::Fifo)].iter() {
+            let mut gpu = if gbmode == GbMode::Color { GPU::new_cgb() } else { GPU::new() };
+            gpu.gbmode = gbmode;
+            gpu.set_renderer(renderer);
+            for row in 0 .. 16 {
+                gpu.wb(0x8020 + row, 0xFF);
+            }
+            gpu.wb(0x9801, 2);
+            gpu.wb(0x9802, 2);
+            gpu.wb(0xFF47, 0xE4);
+            // OBJ palette 0 color 3 is red on the CGB
+            gpu.wb(0xFF6A, 0x86);
+            gpu.wb(0xFF6B, 0x1F);
+            gpu.wb(0xFF6B, 0x00);
+            let gpu = sprite_gpu(gpu, &[(8, 0x80), (12, 0x00)]);
+            let gpu = render_frame(gpu, &[(0, 0xFF40, 0x93)]);
+            let (bg, sprite) = (gpu.bg_color(3, 0).0, gpu.sprite_color(3, 0).0);
+            assert!(bg != sprite);
+            let colors = [10, 14, 17].iter().map(|&x| pixel(&gpu.data, x, 0)).collect::<Vec<_>>();
+            assert_eq!(colors, [bg, bg, sprite], "{:?}", renderer);
+        }
+    }
+
+    #[test]
+    fn sprite_color0_and_palettes() {
+        for &renderer in [Renderer::Scanline, Renderer::Fifo].iter() {
+            let mut gpu = GPU::new();
+            gpu.set_renderer(renderer);
+            // The left half of tile 2 is color 1 and of tile 3 color 2, their right halves color 0
+            for row in 0 .. 8 {
+                gpu.wb(0x8020 + row * 2, 0xF0);
+                gpu.wb(0x8031 + row * 2, 0xF0);
+            }
+            // 8x16 sprites of tile 3, whose low bit is ignored, with OBP0 and OBP1
+            for (i, &(x, flags)) in [(0u8, 0x00u8), (16, 0x10)].iter().enumerate() {
+                for (j, &v) in [16, x + 8, 3, flags].iter().enumerate() {
+                    gpu.wb(0xFE00 + (i * 4 + j) as u16, v);
+                }
+            }
+            gpu.wb(0xFF47, 0xE4);
+            // Both map color 0 to black, OBP0 maps 1 and 2 to shades 1 and 2, OBP1 the other way
+            gpu.wb(0xFF48, 0x27);
+            gpu.wb(0xFF49, 0x1B);
+            gpu.wb(0xFF40, 0x87);
+            gpu.skip_lcd_startup();
+            let gpu = render_frame(gpu, &[]);
+
+            let shades = |y: usize| (0 .. 24).map(|x| gpu.indices[y * SCREEN_W + x]).collect::<Vec<_>>();
+            let expected = |left: u8, right: u8| {
+                let mut line = vec![left; 4];
+                line.extend_from_slice(&[0; 12]);
+                line.extend_from_slice(&[right; 4]);
+                line.extend_from_slice(&[0; 4]);
+                line
+            };
+            assert_eq!(shades(0), expected(1, 2), "{:?}", renderer);
+            assert_eq!(shades(8), expected(2, 1), "{:?}", renderer);
+            // Color 0 shows the white background, not the black of the palettes
+            assert_eq!(pixel(&gpu.data, 6, 0), gpu.dmg_palette[0]);
+            assert_eq!(pixel(&gpu.data, 22, 8), gpu.dmg_palette[0]);
+        }
+    }
+
+    // BG tiles of color 3, a window of color 1 from 80,72 and sprites of color 2 at 0,0 and 80,72
+    fn layers_gpu(renderer: Renderer, hidden: &[Layer]) -> GPU {
+        let mut gpu = GPU::new();
+        gpu.set_renderer(renderer);
+        for row in 0 .. 8 {
+            gpu.wb(0x8010 + row * 2, 0xFF);
+            gpu.wb(0x8011 + row * 2, 0xFF);
+            gpu.wb(0x8020 + row * 2, 0xFF);
+            gpu.wb(0x8031 + row * 2, 0xFF);
+        }
+        for i in 0 .. 0x400 {
+            gpu.wb(0x9800 + i, 1);
+            gpu.wb(0x9C00 + i, 2);
+        }
+        for (i, &(y, x)) in [(16, 8), (88, 88)].iter().enumerate() {
+            for (j, &v) in [y, x, 3, 0].iter().enumerate() {
+                gpu.wb(0xFE00 + (i * 4 + j) as u16, v);
+            }
+        }
+        gpu.wb(0xFF47, 0xE4);
+        gpu.wb(0xFF48, 0xE4);
+        gpu.wb(0xFF4A, 72);
+        gpu.wb(0xFF4B, 87);
+        gpu.wb(0xFF40, 0xF3);
+        gpu.skip_lcd_startup();
+        for &layer in hidden {
+            gpu.set_layer_visible(layer, false);
+        }
+        gpu
+    }
+
+    #[test]
+    fn hidden_layers() {
+        for &renderer in [Renderer::Scanline, Renderer::Fifo].iter() {
+            let colors = |hidden: &[Layer]| {
+                let gpu = render_frame(layers_gpu(renderer, hidden), &[]);
+                [(4, 4), (20, 4), (84, 76), (100, 76)].map(|(x, y)| gpu.indices[y * SCREEN_W + x])
+            };
+            assert_eq!(colors(&[]), [2, 3, 2, 1], "{:?}", renderer);
+            // Hidden BG and window pixels are color 0, with the sprites still over them
+            assert_eq!(colors(&[Layer::Background]), [2, 0, 2, 1], "{:?}", renderer);
+            assert_eq!(colors(&[Layer::Window]), [2, 3, 2, 0], "{:?}", renderer);
+            assert_eq!(colors(&[Layer::Sprites]), [3, 3, 1, 1], "{:?}", renderer);
+
+            // The timing does not change
+            let mode0_dots = |hidden: &[Layer]| {
+                let mut gpu = layers_gpu(renderer, hidden);
+                gpu.wb(0xFF41, 0x08);
+                interrupt_dots(gpu, 0x02)
+            };
+            assert_eq!(mode0_dots(&[Layer::Background, Layer::Window, Layer::Sprites]), mode0_dots(&[]), "{:?}", renderer);
+        }
+
+        // The BG map and sprite viewers show the hidden layers the same way
+        let bg_map = |hidden: &[Layer]| layers_gpu(Renderer::Scanline, hidden).render_bg_map(false, true)[(4 * BG_MAP_SIZE + 4) * 4 ..][.. 3].to_vec();
+        assert_eq!(bg_map(&[]), [PALETTE[3]; 3]);
+        assert_eq!(bg_map(&[Layer::Window, Layer::Sprites]), [PALETTE[3]; 3]);
+        assert_eq!(bg_map(&[Layer::Background]), [PALETTE[0]; 3]);
+        let sprite = |hidden: &[Layer]| layers_gpu(Renderer::Scanline, hidden).debug_sprites().swap_remove(0).pixels;
+        assert_eq!(sprite(&[])[.. 4], [PALETTE[2], PALETTE[2], PALETTE[2], 0xFF]);
+        assert_eq!(sprite(&[Layer::Background, Layer::Window])[.. 4], [PALETTE[2], PALETTE[2], PALETTE[2], 0xFF]);
+        assert!(sprite(&[Layer::Sprites]).iter().all(|&v| v == 0));
+    }
+
+    #[test]
+    fn ten_sprites_per_line() {
+        // 12 sprites on line 0, the first of them off the screen in X, and one on line 8
+        let mut sprites = vec![(0xA0, 0x00)];
+        sprites.extend((0 .. 11).map(|i| (i * 8, 0x00)));
+        let mut gpu = sprite_gpu(GPU::new(), &sprites);
+        gpu.wb(0xFE00 + 12 * 4, 24);
+        gpu.wb(0xFE00 + 12 * 4 + 1, 8);
+        gpu.wb(0xFE00 + 12 * 4 + 2, 1);
+        assert_eq!(gpu.line_sprites(), &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+
+        let gpu = render_frame(gpu, &[]);
+        // The off screen sprite takes a place, so the ninth and tenth on screen are not drawn
+        for i in 0 .. 11 {
+            let expected = if i < 9 { PALETTE[1] } else { WHITE };
+            assert_eq!(gpu.data[i * 8 * 3], expected, "{}", i);
+        }
+        assert_eq!(gpu.data[8 * SCREEN_W * 3], PALETTE[1]);
+    }
+
+    fn stat_gpu(gbmode: GbMode, stat: u8, lyc: u8) -> GPU {
+        let mut gpu = GPU::new();
+        gpu.gbmode = gbmode;
+        gpu.wb(0xFF45, lyc);
+        gpu.wb(0xFF40, 0x91);
+        gpu.wb(0xFF41, stat);
+        gpu.interrupt = 0;
+        gpu
+    }
+
+    fn count_stat_interrupts(gpu: &mut GPU, lines: u32) -> u32 {
+        let mut count = 0;
+        for _ in 0 .. lines * 456 / 4 {
+            gpu.do_cycle(4);
+            if gpu.interrupt & 0x02 != 0 { count += 1; }
+            gpu.interrupt = 0;
+        }
+        count
+    }
+
+    #[test]
+    fn stat_irq_blocking() {
+        // Separate sources only fire when the line goes from low to high
+        let mut gpu = stat_gpu(GbMode::Color, 0x20, 100);
+        assert_eq!(count_stat_interrupts(&mut gpu, 10), 10);
+        let mut gpu = stat_gpu(GbMode::Color, 0x08, 100);
+        assert_eq!(count_stat_interrupts(&mut gpu, 10), 10);
+
+        // The mode 0 level carries into mode 2 of the next line
+        let mut gpu = stat_gpu(GbMode::Color, 0x28, 100);
+        assert_eq!(count_stat_interrupts(&mut gpu, 10), 10);
+
+        // LY=LYC starts during the HBlank of line 4 and stays high until the HBlank of line 5
+        let mut gpu = stat_gpu(GbMode::Color, 0x48, 5);
+        assert_eq!(count_stat_interrupts(&mut gpu, 10), 9);
+
+        // VBlank keeps the line high, so the mode 0 and LY=LYC sources do not fire again
+        let mut gpu = stat_gpu(GbMode::Color, 0x58, 150);
+        assert_eq!(count_stat_interrupts(&mut gpu, 154), 144);
+    }
+
+    #[test]
+    fn stat_irq_on_lyc_write() {
+        let mut gpu = stat_gpu(GbMode::Color, 0x40, 100);
+        gpu.do_cycle(456 * 3);
+        assert_eq!(gpu.interrupt & 0x02, 0);
+        gpu.wb(0xFF45, 3);
+        assert_eq!(gpu.interrupt & 0x02, 0x02);
+        gpu.interrupt = 0;
+        gpu.wb(0xFF45, 3);
+        assert_eq!(gpu.interrupt & 0x02, 0);
+    }
+
+    #[test]
+    fn dmg_stat_write_quirk() {
+        for &(gbmode, expected) in [(GbMode::Classic, 0x02), (GbMode::Color, 0)].iter() {
+            let mut gpu = stat_gpu(gbmode, 0x00, 100);
+            // Mode 3 does not fire
+            gpu.do_cycle(100);
+            gpu.wb(0xFF41, 0x00);
+            assert_eq!(gpu.interrupt & 0x02, 0);
+            // HBlank
+            gpu.do_cycle(200);
+            gpu.wb(0xFF41, 0x00);
+            assert_eq!(gpu.interrupt & 0x02, expected);
+            gpu.interrupt = 0;
+            // VBlank
+            gpu.do_cycle(456 * 144);
+            gpu.interrupt = 0;
+            gpu.wb(0xFF41, 0x00);
+            assert_eq!(gpu.interrupt & 0x02, expected);
+        }
+    }
+
+    fn mode3_gpu(scx: u8, lcdc: u8, sprite_xs: &[u8]) -> GPU {
+        mode3_gpu_with(Renderer::Scanline, scx, lcdc, sprite_xs)
+    }
+
+    fn mode3_gpu_with(renderer: Renderer, scx: u8, lcdc: u8, sprite_xs: &[u8]) -> GPU {
+        let mut gpu = GPU::new();
+        gpu.set_renderer(renderer);
+        for (i, &x) in sprite_xs.iter().enumerate() {
+            gpu.wb(0xFE00 + i as u16 * 4, 16);
+            gpu.wb(0xFE00 + i as u16 * 4 + 1, x);
+        }
+        gpu.wb(0xFF43, scx);
+        gpu.wb(0xFF4A, 0);
+        gpu.wb(0xFF4B, 7);
+        gpu.wb(0xFF40, lcdc);
+        gpu.do_cycle(100);
+        assert_eq!(gpu.mode, 3);
+        gpu
+    }
+
+    #[test]
+    fn mode3_penalties() {
+        assert_eq!(mode3_gpu(0, 0x82, &[]).mode3_len, 172);
+        assert_eq!(mode3_gpu(3, 0x82, &[]).mode3_len, 175);
+        assert_eq!(mode3_gpu(0, 0xA2, &[]).mode3_len, 178);
+        // Sprites wait for the BG fetch of their first pixel's tile
+        assert_eq!(mode3_gpu(0, 0x82, &[0]).mode3_len, 183);
+        assert_eq!(mode3_gpu(0, 0x82, &[8]).mode3_len, 183);
+        assert_eq!(mode3_gpu(0, 0x82, &[13]).mode3_len, 178);
+        assert_eq!(mode3_gpu(3, 0x82, &[13]).mode3_len, 186);
+        assert_eq!(mode3_gpu(0, 0x82, &[8, 9]).mode3_len, 189);
+        assert_eq!(mode3_gpu(0, 0x82, &[8, 16]).mode3_len, 194);
+        assert_eq!(mode3_gpu(0, 0x82, &[168]).mode3_len, 172);
+        // Over the window, the tiles are aligned on WX
+        assert_eq!(mode3_gpu(0, 0xA2, &[20]).mode3_len, 185);
+        // No penalty with the sprites off
+        assert_eq!(mode3_gpu(0, 0x80, &[8]).mode3_len, 172);
+        // Only the 10 sprites of the line count
+        assert_eq!(mode3_gpu(0, 0x82, &[0; 12]).mode3_len, 172 + 110);
+    }
+
+    #[test]
+    fn hblank_follows_mode3() {
+        // The dots from the start of line 1 to HBlank, and to line 2
+        fn timing(mut gpu: GPU) -> (u32, u32) {
+            while gpu.line != 1 { gpu.do_cycle(1); }
+            let mut dots = 0;
+            while gpu.mode != 0 { gpu.do_cycle(1); dots += 1; }
+            assert!(gpu.take_hblank_start());
+            assert_eq!(gpu.rb(0xFF41) & 0x03, 0);
+            let hblank = dots;
+            while gpu.line != 2 { gpu.do_cycle(1); dots += 1; }
+            (hblank, dots)
+        }
+        let (plain, line) = timing(mode3_gpu(0, 0x82, &[]));
+        assert_eq!(line, 456);
+        let (longer, line) = timing(mode3_gpu(5, 0x82, &[0, 8]));
+        assert_eq!(longer - plain, 5 + 11 + 6);
+        assert_eq!(line, 456);
+    }
+
+    fn run_to_line(gpu: &mut GPU, line: u8) {
+        while gpu.line != line { gpu.do_cycle(1); }
+    }
+
+    #[test]
+    fn stat_mode_dots() {
+        let mut gpu = stat_gpu(GbMode::Color, 0x00, 100);
+        run_to_line(&mut gpu, 1);
+        let mut modes = Vec::new();
+        for _ in 0 .. 456 {
+            modes.push(gpu.rb(0xFF41) & 0x03);
+            gpu.do_cycle(1);
+        }
+        assert!(modes[.. 80].iter().all(|&m| m == 2));
+        assert!(modes[80 .. 252].iter().all(|&m| m == 3));
+        assert!(modes[252 ..].iter().all(|&m| m == 0));
+
+        // VBlank starts at dot 0 of line 144, with its interrupt
+        run_to_line(&mut gpu, 143);
+        gpu.do_cycle(455);
+        gpu.interrupt = 0;
+        assert_eq!(gpu.rb(0xFF41) & 0x03, 0);
+        gpu.do_cycle(1);
+        assert_eq!(gpu.rb(0xFF44), 144);
+        assert_eq!(gpu.rb(0xFF41) & 0x03, 1);
+        assert_eq!(gpu.interrupt & 0x01, 0x01);
+    }
+
+    // The dots of a frame, counted from line 0, at which the GPU requests the interrupts
+    fn interrupt_dots(mut gpu: GPU, mask: u8) -> Vec<u32> {
+        run_to_line(&mut gpu, 153);
+        run_to_line(&mut gpu, 0);
+        gpu.interrupt = 0;
+        let mut dots = Vec::new();
+        for dot in 1 ..= 154 * 456 {
+            gpu.do_cycle(1);
+            if gpu.interrupt & mask != 0 { dots.push(dot); }
+            gpu.interrupt = 0;
+        }
+        dots
+    }
+
+    #[test]
+    fn interrupt_dots_per_source() {
+        // VBlank and the mode 1 source at dot 0 of line 144
+        assert_eq!(interrupt_dots(stat_gpu(GbMode::Classic, 0x00, 200), 0x01), [144 * 456]);
+        assert_eq!(interrupt_dots(stat_gpu(GbMode::Classic, 0x10, 200), 0x02), [144 * 456]);
+        // Mode 2 at the start of every visible line, and of line 144
+        let expected: Vec<u32> = (1 ..= 144).map(|line| line * 456).chain(Some(154 * 456)).collect();
+        assert_eq!(interrupt_dots(stat_gpu(GbMode::Color, 0x20, 200), 0x02), expected);
+        // Mode 0 when HBlank starts, later with SCX
+        for &scx in [0u8, 5].iter() {
+            let mut gpu = stat_gpu(GbMode::Color, 0x08, 200);
+            gpu.wb(0xFF43, scx);
+            let expected: Vec<u32> = (0 .. 144).map(|line| line * 456 + 80 + 172 + scx as u32).collect();
+            assert_eq!(interrupt_dots(gpu, 0x02), expected);
+        }
+        // LY=LYC when LY changes to LYC
+        assert_eq!(interrupt_dots(stat_gpu(GbMode::Color, 0x40, 50), 0x02), [50 * 456]);
+    }
+
+    #[test]
+    fn line_153_reads_ly_0() {
+        let mut gpu = stat_gpu(GbMode::Color, 0x00, 153);
+        run_to_line(&mut gpu, 153);
+        let mut lys = Vec::new();
+        let mut coincidence = Vec::new();
+        for _ in 0 .. 456 {
+            lys.push(gpu.rb(0xFF44));
+            coincidence.push(gpu.rb(0xFF41) & 0x04 != 0);
+            gpu.do_cycle(1);
+        }
+        assert_eq!(&lys[.. 4], &[153; 4]);
+        assert!(lys[4 ..].iter().all(|&ly| ly == 0));
+        assert_eq!(&coincidence[.. 4], &[true; 4]);
+        assert!(coincidence[4 ..].iter().all(|&c| !c));
+        assert_eq!(gpu.line, 0);
+    }
+
+    #[test]
+    fn lyc_0_fires_in_line_153() {
+        let mut gpu = stat_gpu(GbMode::Color, 0x40, 0);
+        run_to_line(&mut gpu, 153);
+        gpu.interrupt = 0;
+        gpu.do_cycle(3);
+        assert_eq!(gpu.interrupt & 0x02, 0);
+        gpu.do_cycle(1);
+        assert_eq!(gpu.interrupt & 0x02, 0x02);
+        gpu.interrupt = 0;
+
+        // The line stays high into line 0, which does not fire again
+        run_to_line(&mut gpu, 1);
+        assert_eq!(gpu.interrupt & 0x02, 0);
+        assert_eq!(count_stat_interrupts(&mut gpu, 154), 1);
+    }
+
+    #[test]
+    fn fifo_matches_scanline() {
+        fn sprites(mut gpu: GPU) -> GPU {
+            for (i, &(x, flags)) in [(0u8, 0x00u8), (5, 0x10), (30, 0x20), (60, 0x80), (64, 0x00), (100, 0x40), (165, 0x00)].iter().enumerate() {
+                for (j, &v) in [16 + i as u8 * 9, x, (i % 4) as u8 + 1, flags].iter().enumerate() {
+                    gpu.wb(0xFE00 + (i * 4 + j) as u16, v);
+                }
+            }
+            gpu.wb(0xFF48, 0x1B);
+            gpu.wb(0xFF49, 0xE4);
+            gpu
+        }
+        fn cgb(mut gpu: GPU) -> GPU {
+            gpu.gbmode = GbMode::Color;
+            gpu.wb(0xFF68, 0x80);
+            gpu.wb(0xFF6A, 0x80);
+            for i in 0 .. 64u8 {
+                gpu.wb(0xFF69, i.wrapping_mul(37));
+                gpu.wb(0xFF6B, i.wrapping_mul(91));
+            }
+            gpu.wb(0xFF4F, 1);
+            for i in 0 .. 0x800u16 {
+                gpu.wb(0x9800 + i, (i * 13 % 256) as u8 & 0xEF);
+            }
+            gpu.wb(0xFF4F, 0);
+            gpu
+        }
+        fn scene(name: &str) -> GPU {
+            match name {
+                "window" => window_gpu(0, 7),
+                "window part" => window_gpu(20, 50),
+                "window left" => window_gpu(5, 3),
+                "window off" => window_gpu(0, 166),
+                "scroll" => { let mut gpu = window_gpu(50, 90); gpu.wb(0xFF42, 17); gpu.wb(0xFF43, 3); gpu },
+                "sprites" => { let mut gpu = sprites(window_gpu(70, 7)); gpu.wb(0xFF40, 0xF3); gpu },
+                "tall sprites" => { let mut gpu = sprites(window_gpu(70, 7)); gpu.wb(0xFF43, 5); gpu.wb(0xFF40, 0xF7); gpu },
+                _ => { let mut gpu = sprites(cgb(window_gpu(40, 30))); gpu.wb(0xFF40, 0xF3); gpu },
+            }
+        }
+        for &name in ["window", "window part", "window left", "window off", "scroll", "sprites", "tall sprites", "cgb"].iter() {
+            let scanline = render_frame(render_frame(scene(name), &[]), &[]);
+            let mut gpu = scene(name);
+            gpu.set_renderer(Renderer::Fifo);
+            let fifo = render_frame(render_frame(gpu, &[]), &[]);
+            assert!(scanline.data == fifo.data, "{}", name);
+            assert_eq!(first_differing_scanline(&scanline.scanline_crcs(), &fifo.scanline_crcs()), None, "{}", name);
+            assert_eq!(scanline.frame_hash(), fifo.frame_hash(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn fifo_mode3_lengths() {
+        fn fifo_len(scx: u8, lcdc: u8, sprite_xs: &[u8]) -> u32 {
+            let mut gpu = mode3_gpu_with(Renderer::Fifo, scx, lcdc, sprite_xs);
+            while gpu.mode == 3 { gpu.do_cycle(1); }
+            gpu.mode3_len
+        }
+        let sprite_sets: &[&[u8]] = &[&[], &[0], &[8], &[9], &[12], &[13], &[16], &[20], &[100], &[167], &[168],
+            &[8, 9], &[8, 16], &[8, 8], &[20, 60, 61, 100], &[30, 31, 32], &[150, 160], &[8, 30, 31, 32, 44, 60, 90, 120, 150, 160]];
+        for scx in 0 .. 8 {
+            for &lcdc in [0x82, 0xA2, 0x80].iter() {
+                for &xs in sprite_sets {
+                    let expected = mode3_gpu(scx, lcdc, xs).mode3_len;
+                    assert_eq!(fifo_len(scx, lcdc, xs), expected, "SCX {} LCDC {:02X} sprites {:?}", scx, lcdc, xs);
+                }
+            }
+        }
+        // Unlike in the formula, a sprite at X 0 waits for the first tile with those at X 8
+        assert_eq!(fifo_len(0, 0x82, &[0, 8]), 172 + 11 + 6);
+    }
+
+    #[test]
+    fn fifo_mid_line_writes() {
+        // A BGP write in mode 3 changes the rest of the line with the FIFO, and all of it without
+        let mut lines = Vec::new();
+        for &renderer in [Renderer::Scanline, Renderer::Fifo].iter() {
+            let mut gpu = mode3_gpu_with(renderer, 0, 0x91, &[]);
+            gpu.wb(0xFF47, 0x00);
+            run_to_line(&mut gpu, 1);
+            gpu.do_cycle(80 + 12 + 59);
+            gpu.wb(0xFF47, 0xFF);
+            run_to_line(&mut gpu, 2);
+            lines.push(gpu.data[SCREEN_W * 3 .. SCREEN_W * 6].to_vec());
+        }
+        assert!(lines[0].iter().all(|&v| v == 0));
+        assert!(lines[1][.. 60 * 3].iter().all(|&v| v == 255));
+        assert!(lines[1][60 * 3 ..].iter().all(|&v| v == 0));
+
+        // SCX is read by every tile fetch, and its low bits only at the start of the line
+        let mut gpu = window_gpu(200, 7);
+        gpu.set_renderer(Renderer::Fifo);
+        run_to_line(&mut gpu, 1);
+        gpu.do_cycle(80 + 12 + 40);
+        gpu.wb(0xFF43, 0x13);
+        run_to_line(&mut gpu, 2);
+        let mut expected = window_gpu(200, 7);
+        expected.wb(0xFF43, 0x10);
+        let expected = render_frame(expected, &[]);
+        let line = |data: &[u8]| data[SCREEN_W * 3 .. SCREEN_W * 6].to_vec();
+        // The fetcher is a tile ahead of the pixels
+        assert!(line(&gpu.data)[56 * 3 ..] == line(&expected.data)[56 * 3 ..]);
+    }
+
+    #[test]
+    fn dmg_palette_colors() {
+        let green = DMG_PALETTES[1].1;
+        let mut gpu = sprite_gpu(GPU::new(), &[(8, 0x00), (16, 0x10)]);
+        gpu.wb(0xFF47, 0xE4);
+        gpu.set_dmg_palette(green);
+        let mut gpu = render_frame(gpu, &[]);
+        assert_eq!(pixel(&gpu.data, 0, 0), green[0]);
+        assert_eq!(pixel(&gpu.data, 8, 0), green[1]);
+        assert_eq!(pixel(&gpu.data, 16, 0), green[2]);
+
+        // The LCD off shows the lightest shade
+        gpu.wb(0xFF40, 0x00);
+        assert!(gpu.data.chunks(3).all(|color| color == green[0]));
+
+        // Color games keep their colors
+        let render_cgb = |palette| {
+            let mut gpu = sprite_gpu(GPU::new(), &[(8, 0x01)]);
+            gpu.gbmode = GbMode::Color;
+            gpu.wb(0xFF6A, 0x80);
+            for i in 0 .. 64 { gpu.wb(0xFF6B, i * 3); }
+            gpu.set_dmg_palette(palette);
+            render_frame(gpu, &[]).data
+        };
+        assert!(render_cgb(green) == render_cgb(DMG_PALETTES[0].1));
+    }
+
+    #[test]
+    fn color_correction() {
+        let red = |correction| color_table(correction)[0x001F];
+        assert_eq!(red(ColorCorrection::Raw), [255, 0, 0]);
+        assert_eq!(red(ColorCorrection::CgbLcd), cgb_color([0x1F, 0, 0]));
+        let [r, g, b] = red(ColorCorrection::GbaLcd);
+        assert!(r < 255 && g > 0 && b > 0, "{:?}", [r, g, b]);
+        assert_eq!(color_table(ColorCorrection::GbaLcd)[0], [0, 0, 0]);
+
+        // Color 0 of BG palette 0 as pure red
+        let render_red = |correction| {
+            let mut gpu = sprite_gpu(GPU::new(), &[]);
+            gpu.gbmode = GbMode::Color;
+            gpu.wb(0xFF68, 0x80);
+            gpu.wb(0xFF69, 0x1F);
+            gpu.wb(0xFF69, 0x00);
+            gpu.set_color_correction(correction);
+            pixel(&render(gpu, &[]), 0, 0)
+        };
+        for &(_, correction) in COLOR_CORRECTIONS.iter() {
+            assert_eq!(render_red(correction), red(correction), "{:?}", correction);
+        }
+    }
+
+    #[test]
+    fn frame_blending() {
+        fn frame(gpu: &mut GPU, lcdc: u8) {
+            run_to_line(gpu, 0);
+            gpu.wb(0xFF40, lcdc);
+            run_to_line(gpu, 144);
+        }
+        // A sprite of shade 1 on every other frame
+        let mut gpu = sprite_gpu(GPU::new(), &[(0, 0x00)]);
+        gpu.set_frame_blend(50);
+        frame(&mut gpu, 0x82);
+        frame(&mut gpu, 0x80);
+        let hash = gpu.frame_hash();
+        assert_eq!(gpu.data[0], WHITE);
+        assert_eq!(gpu.framebuffer()[0], ((WHITE as u16 + PALETTE[1] as u16) / 2) as u8);
+        assert_eq!(gpu.framebuffer()[8 * 4], WHITE);
+        frame(&mut gpu, 0x82);
+        assert_eq!(gpu.framebuffer()[0], ((WHITE as u16 + PALETTE[1] as u16) / 2) as u8);
+        frame(&mut gpu, 0x82);
+        assert_eq!(gpu.framebuffer()[0], PALETTE[1]);
+
+        // The hash is of the frame drawn, and the screen is that frame without blending
+        frame(&mut gpu, 0x80);
+        assert_eq!(gpu.frame_hash(), hash);
+        gpu.set_frame_blend(0);
+        assert_eq!(gpu.framebuffer()[0], WHITE);
+    }
+
+    #[test]
+    fn vram_dumps() {
+        let rgba = |image: &[u8], width: usize, x: usize, y: usize| {
+            let i = (y * width + x) * 4;
+            [image[i], image[i + 1], image[i + 2], image[i + 3]]
+        };
+        let gray = |shade: usize| [PALETTE[shade], PALETTE[shade], PALETTE[shade], 0xFF];
+        let red = [255, 0, 0, 0xFF];
+
+        let mut gpu = GPU::new();
+        gpu.wb(0x8010, 0x80); // Tile 1, color 3 at the top left
+        gpu.wb(0x8011, 0x80);
+        gpu.wb(0x97FE, 0x01); // Tile 383, color 1 at the bottom right
+        gpu.wb(0x9010, 0x80); // Tile 1 from 8800, color 1 at the top left
+        let tiles = gpu.render_tiles(0);
+        assert_eq!(tiles.len(), TILE_SHEET_W * TILE_SHEET_H * 4);
+        assert_eq!(rgba(&tiles, TILE_SHEET_W, 8, 0), gray(3));
+        assert_eq!(rgba(&tiles, TILE_SHEET_W, 9, 0), gray(0));
+        assert_eq!(rgba(&tiles, TILE_SHEET_W, 127, 191), gray(1));
+        assert!(gpu.render_tiles(1).chunks(4).all(|pixel| pixel == gray(0)));
+
+        // BGP swaps colors 1 and 2
+        gpu.wb(0xFF47, 0xD8);
+        gpu.wb(0x9801, 1);
+        gpu.wb(0xFF42, 100);
+        gpu.wb(0xFF43, 250);
+        let map = gpu.render_bg_map(false, true);
+        assert_eq!(map.len(), BG_MAP_SIZE * BG_MAP_SIZE * 4);
+        assert_eq!(rgba(&map, BG_MAP_SIZE, 8, 0), gray(3));
+        assert_eq!(rgba(&map, BG_MAP_SIZE, 9, 0), gray(0));
+        let map = gpu.render_bg_map(false, false);
+        assert_eq!(rgba(&map, BG_MAP_SIZE, 8, 0), gray(2));
+        assert!(gpu.render_bg_map(true, true).chunks(4).enumerate()
+            .all(|(i, pixel)| pixel == red || pixel == gray(0) || i % BG_MAP_SIZE == 8));
+
+        // The viewport from (250, 100) to (153, 243), wrapping around
+        for &(x, y) in [(250, 100), (255, 100), (0, 100), (153, 100), (250, 243), (0, 243), (153, 200), (250, 150)].iter() {
+            assert_eq!(rgba(&map, BG_MAP_SIZE, x, y), red, "({}, {})", x, y);
+        }
+        for &(x, y) in [(249, 100), (154, 100), (10, 150), (250, 99), (250, 244)].iter() {
+            assert_eq!(rgba(&map, BG_MAP_SIZE, x, y), gray(0), "({}, {})", x, y);
+        }
+
+        // Flipped horizontally, from bank 1, in palette 3
+        gpu.gbmode = GbMode::Color;
+        gpu.wb(0xFF68, 0x80 | (3 * 8 + 2));
+        gpu.wb(0xFF69, 0x1F);
+        gpu.wb(0xFF69, 0x00);
+        gpu.wb(0xFF4F, 1);
+        gpu.wb(0x8000, 0x80);
+        gpu.wb(0x9800, 0x2B);
+        gpu.wb(0xFF4F, 0);
+        let map = gpu.render_bg_map(false, true);
+        let [r, g, b] = cgb_color([0x1F, 0, 0]);
+        assert_eq!(rgba(&map, BG_MAP_SIZE, 7, 0), [r, g, b, 0xFF]);
+        assert!(rgba(&map, BG_MAP_SIZE, 0, 0) != [r, g, b, 0xFF]);
+    }
+
+    #[test]
+    fn debug_sprites() {
+        let mut gpu = mode3_gpu(0, 0x82, &[8; 12]);
+        gpu.wb(0xFF48, 0xE4);
+        gpu.wb(0x8000, 0x80); // Color 1 at the top left of tile 0
+        gpu.wb(0xFE07, 0x20);
+        let sprites = gpu.debug_sprites();
+        assert_eq!(sprites.len(), 40);
+        assert!(sprites[.. 10].iter().all(|sprite| sprite.selected && sprite.on_screen));
+        assert!(sprites[10 .. 12].iter().all(|sprite| !sprite.selected && sprite.on_screen));
+        assert!(!sprites[12].selected && !sprites[12].on_screen);
+        assert_eq!((sprites[1].index, sprites[1].y, sprites[1].x, sprites[1].x_flip, sprites[1].height), (1, 16, 8, true, 8));
+
+        let pixel = |sprite: &super::SpriteDebugInfo, x: usize, y: usize| sprite.pixels[(y * 8 + x) * 4 .. (y * 8 + x) * 4 + 4].to_vec();
+        let shade1 = vec![PALETTE[1], PALETTE[1], PALETTE[1], 0xFF];
+        assert_eq!(sprites[0].pixels.len(), 8 * 8 * 4);
+        assert_eq!(pixel(&sprites[0], 0, 0), shade1);
+        assert_eq!(pixel(&sprites[0], 1, 0), [0; 4]);
+        assert_eq!(pixel(&sprites[1], 7, 0), shade1);
+
+        // 8x16 sprites start at an even tile, here flipped vertically
+        gpu.wb(0xFF40, 0x86);
+        gpu.wb(0xFE0A, 1);
+        gpu.wb(0xFE0B, 0x40);
+        let sprite = &gpu.debug_sprites()[2];
+        assert_eq!((sprite.tile, sprite.height, sprite.y_flip), (1, 16, true));
+        assert_eq!(sprite.pixels.len(), 8 * 16 * 4);
+        assert_eq!(pixel(sprite, 0, 15), shade1);
+        assert_eq!(pixel(sprite, 0, 0), [0; 4]);
+    }
+
+    #[test]
+    fn lcd_off_and_on() {
+        let blank = |gpu: &GPU| gpu.framebuffer().chunks(4).all(|pixel| pixel == [WHITE, WHITE, WHITE, 0xFF]);
+        let mut gpu = window_gpu(0, 7);
+        gpu.set_frame_blend(50);
+        run_to_line(&mut gpu, 144);
+        run_to_line(&mut gpu, 50);
+        assert!(!blank(&gpu));
+
+        // Off in the middle of a frame: blank without blending, LY and the mode 0, no interrupts
+        gpu.wb(0xFF40, 0x71);
+        assert!(gpu.lcd_off_warned);
+        assert!(blank(&gpu));
+        gpu.interrupt = 0;
+        gpu.wb(0xFF41, 0x78);
+        gpu.do_cycle(456 * 200);
+        assert_eq!((gpu.rb(0xFF44), gpu.rb(0xFF41) & 0x03, gpu.interrupt), (0, 0, 0));
+        let count = gpu.frame_count();
+
+        // On again: line 0 has no mode 2, and the first frame is not shown
+        gpu.wb(0xFF41, 0x00);
+        gpu.wb(0xFF40, 0xF1);
+        gpu.do_cycle(72);
+        assert_eq!(gpu.rb(0xFF41) & 0x03, 0);
+        gpu.do_cycle(4);
+        assert_eq!(gpu.mode, 3);
+        run_to_line(&mut gpu, 1);
+        assert_eq!(gpu.mode, 2);
+        run_to_line(&mut gpu, 0);
+        assert_eq!(gpu.frame_count(), count);
+        assert!(blank(&gpu));
+        run_to_line(&mut gpu, 144);
+        assert_eq!(gpu.frame_count(), count + 1);
+        assert!(!blank(&gpu));
+
+        // Turning it off in VBlank is what games should do
+        let mut gpu = window_gpu(0, 7);
+        run_to_line(&mut gpu, 144);
+        gpu.wb(0xFF40, 0x71);
+        assert!(!gpu.lcd_off_warned);
+    }
+
+    #[test]
+    fn first_frame_state() {
+        use crate::state::{StateReader, StateWriter};
+
+        // Saved while the first frame after turning the LCD on is still hidden
+        let mut gpu = window_gpu(0, 7);
+        run_to_line(&mut gpu, 144);
+        gpu.wb(0xFF40, 0x71);
+        gpu.wb(0xFF40, 0xF1);
+        run_to_line(&mut gpu, 50);
+        let mut w = StateWriter::new();
+        gpu.save_state(&mut w);
+        let state = w.into_vec();
+
+        let mut loaded = window_gpu(0, 7);
+        let mut r = StateReader::new(&state);
+        loaded.load_state(&mut r).unwrap();
+        r.finish().unwrap();
+        let mut w = StateWriter::new();
+        loaded.save_state(&mut w);
+        assert!(w.into_vec() == state);
+        assert!(loaded.first_frame);
+        let count = loaded.frame_count();
+        run_to_line(&mut loaded, 144);
+        assert_eq!(loaded.frame_count(), count);
+        run_to_line(&mut loaded, 0);
+        run_to_line(&mut loaded, 144);
+        assert_eq!(loaded.frame_count(), count + 1);
+    }
+}
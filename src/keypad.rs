@@ -1,3 +1,5 @@
+use crate::state::{StateReader, StateResult, StateWriter};
+
 pub struct Keypad {
     row0: u8,
     row1: u8,
@@ -81,6 +83,19 @@ impl Keypad {
         }
         self.update();
     }
+
+    // The keys stay as the frontend reported them, only the selected rows are restored
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.data);
+        w.u8(self.interrupt);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.data = r.u8()?;
+        self.interrupt = r.u8()?;
+        self.update();
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -1,22 +1,56 @@
 #![crate_name = "rboy"]
 #![crate_type = "lib" ]
 
+pub use crate::access::{Access, AccessCallback, AccessKind, AccessSource};
+pub use crate::cheats::CheatError;
+pub use crate::colorize::{COMPAT_PALETTES, Colorization};
 pub use crate::keypad::KeypadKey;
-pub use crate::gpu::{SCREEN_W, SCREEN_H};
-pub use crate::sound::AudioPlayer;
+pub use crate::gpu::{BG_MAP_SIZE, COLOR_CORRECTIONS, ColorCorrection, DMG_PALETTES, Layer, Renderer, SCREEN_W, SCREEN_H, SpriteDebugInfo, TILE_SHEET_H, TILE_SHEET_W, first_differing_scanline};
+pub use crate::sound::{ApuDebugState, AudioPlayer, ChannelId, ClipStats, INTERNAL_SAMPLE_RATE, MixTap, SampleTap, SoundOptions, SquareDebugState, SweepDebugState, VinSource};
+pub use crate::trap::{SpeedSwitchConditions, SpeedSwitchOutcome, SpeedSwitchRule, SPEED_SWITCH_MATRIX, Trap, TrapOptions, TrapReport, speed_switch_rule};
+pub use crate::cpu::CpuState;
+pub use crate::debugger::{DebugStop, MooneyeOutcome};
+pub use crate::disasm::{disassemble, instruction_cycles};
+pub use crate::png::{PngColor, encode_png};
+pub use crate::profiler::ProfileEntry;
+pub use crate::mmu::MemoryRegion;
+pub use crate::mbc::{CameraSource, CartridgeHeader, CartridgeType, Destination, Mapper, CAMERA_H, CAMERA_W};
+pub use crate::register::Registers;
+pub use crate::rewind::{Rewind, RewindOptions};
+pub use crate::sgb::{SGB_GAME_X, SGB_GAME_Y, SGB_SCREEN_H, SGB_SCREEN_W};
+pub use crate::snapshot::{BudgetEvent, SnapshotBudget, SnapshotHandle, SnapshotKind};
+pub use crate::state::StateError;
 
 pub mod device;
 
+mod access;
+mod apu;
+mod cheats;
+mod colorize;
 mod cpu;
+mod debugger;
+mod disasm;
 mod gbmode;
 mod gpu;
 mod keypad;
+#[cfg(feature = "libretro")]
+mod libretro;
 mod mbc;
 mod mmu;
+mod png;
 mod printer;
+mod profiler;
+mod samplebuffer;
 mod register;
+mod resampler;
+mod rewind;
 mod serial;
+mod sgb;
+mod snapshot;
 mod sound;
+mod state;
 mod timer;
+mod trace;
+mod trap;
 
 pub type StrResult<T> = Result<T, &'static str>;
@@ -0,0 +1,611 @@
+// A libretro core on top of Device, built when the libretro feature is enabled.
+// See libretro.h in the RetroArch repository for the meaning of the constants and structs.
+
+use crate::device::Device;
+use crate::gpu::{SCREEN_H, SCREEN_W};
+use crate::keypad::KeypadKey;
+use crate::sound::{AudioPlayer, SoundOptions};
+use std::os::raw::{c_char, c_uint, c_void};
+use std::sync::{Arc, Mutex};
+
+const RETRO_API_VERSION: c_uint = 1;
+const RETRO_REGION_NTSC: c_uint = 0;
+const RETRO_MEMORY_SAVE_RAM: c_uint = 0;
+const RETRO_DEVICE_JOYPAD: c_uint = 1;
+const RETRO_ENVIRONMENT_SET_PIXEL_FORMAT: c_uint = 10;
+const RETRO_ENVIRONMENT_GET_RUMBLE_INTERFACE: c_uint = 23;
+const RETRO_PIXEL_FORMAT_XRGB8888: c_uint = 1;
+const RETRO_RUMBLE_STRONG: c_uint = 0;
+
+const SAMPLE_RATE: u32 = 44100;
+const CLOCKS_PER_FRAME: u32 = 70224;
+// The frontend allocates the size it is told once, while a state grows a little when optional
+// parts like a running OAM DMA are in it
+const STATE_SLACK: usize = 1024;
+const FRAMES_PER_SECOND: f64 = 4194304.0 / CLOCKS_PER_FRAME as f64;
+
+// Indexed by the RETRO_DEVICE_ID_JOYPAD_* values
+const JOYPAD_KEYS: [Option<KeypadKey>; 9] = [
+    Some(KeypadKey::B),
+    None,
+    Some(KeypadKey::Select),
+    Some(KeypadKey::Start),
+    Some(KeypadKey::Up),
+    Some(KeypadKey::Down),
+    Some(KeypadKey::Left),
+    Some(KeypadKey::Right),
+    Some(KeypadKey::A),
+];
+
+type EnvironmentFn = extern "C" fn(cmd: c_uint, data: *mut c_void) -> bool;
+type VideoRefreshFn = extern "C" fn(data: *const c_void, width: c_uint, height: c_uint, pitch: usize);
+type AudioSampleFn = extern "C" fn(left: i16, right: i16);
+type AudioSampleBatchFn = extern "C" fn(data: *const i16, frames: usize) -> usize;
+type InputPollFn = extern "C" fn();
+type InputStateFn = extern "C" fn(port: c_uint, device: c_uint, index: c_uint, id: c_uint) -> i16;
+type SetRumbleStateFn = extern "C" fn(port: c_uint, effect: c_uint, strength: u16) -> bool;
+
+#[repr(C)]
+pub struct RetroSystemInfo {
+    library_name: *const c_char,
+    library_version: *const c_char,
+    valid_extensions: *const c_char,
+    need_fullpath: bool,
+    block_extract: bool,
+}
+
+#[repr(C)]
+pub struct RetroGameGeometry {
+    base_width: c_uint,
+    base_height: c_uint,
+    max_width: c_uint,
+    max_height: c_uint,
+    aspect_ratio: f32,
+}
+
+#[repr(C)]
+pub struct RetroSystemTiming {
+    fps: f64,
+    sample_rate: f64,
+}
+
+#[repr(C)]
+pub struct RetroSystemAvInfo {
+    geometry: RetroGameGeometry,
+    timing: RetroSystemTiming,
+}
+
+#[repr(C)]
+pub struct RetroRumbleInterface {
+    set_rumble_state: Option<SetRumbleStateFn>,
+}
+
+#[repr(C)]
+pub struct RetroGameInfo {
+    path: *const c_char,
+    data: *const c_void,
+    size: usize,
+    meta: *const c_char,
+}
+
+struct Callbacks {
+    environment: Option<EnvironmentFn>,
+    video_refresh: Option<VideoRefreshFn>,
+    audio_sample_batch: Option<AudioSampleBatchFn>,
+    input_poll: Option<InputPollFn>,
+    input_state: Option<InputStateFn>,
+}
+
+struct Core {
+    device: Device,
+    audio: Arc<Mutex<Vec<i16>>>,
+    frame: Vec<u32>,
+    pressed: [bool; 9],
+    // Mirror of the cartridge RAM that the frontend reads from and writes into
+    sram: Vec<u8>,
+    sram_loaded: bool,
+    set_rumble_state: Option<SetRumbleStateFn>,
+    rumble: bool,
+    // The codes by the index of the frontend, empty when disabled
+    cheats: Vec<String>,
+}
+
+static CALLBACKS: Mutex<Callbacks> = Mutex::new(Callbacks {
+    environment: None,
+    video_refresh: None,
+    audio_sample_batch: None,
+    input_poll: None,
+    input_state: None,
+});
+
+static CORE: Mutex<Option<Core>> = Mutex::new(None);
+
+// Collects the mixed audio until the end of the frame, when it is handed to the frontend
+struct RetroPlayer {
+    buffer: Arc<Mutex<Vec<i16>>>,
+}
+
+impl AudioPlayer for RetroPlayer {
+    fn play(&mut self, left_channel: &[f32], right_channel: &[f32]) {
+        let mut buffer = self.buffer.lock().unwrap();
+        for (l, r) in left_channel.iter().zip(right_channel) {
+            buffer.push((l.clamp(-1.0, 1.0) * i16::MAX as f32) as i16);
+            buffer.push((r.clamp(-1.0, 1.0) * i16::MAX as f32) as i16);
+        }
+    }
+
+    fn samples_rate(&self) -> u32 {
+        SAMPLE_RATE
+    }
+
+    fn underflowed(&self) -> bool {
+        false
+    }
+}
+
+impl Core {
+    fn new(romdata: Vec<u8>) -> Option<Core> {
+        let mut device = Device::new_cgb_from_buffer(romdata, false).ok()?;
+        let audio = Arc::new(Mutex::new(Vec::new()));
+        // Use the smallest chunks, the frontend does its own buffering
+        device.enable_audio_with_options(Box::new(RetroPlayer { buffer: audio.clone() }), SoundOptions { latency_ms: 0, ..SoundOptions::default() });
+
+        let sram = match device.ram_is_battery_backed() {
+            true => device.dumpram(),
+            false => Vec::new(),
+        };
+
+        Some(Core {
+            device,
+            audio,
+            frame: vec![0; SCREEN_W * SCREEN_H],
+            pressed: [false; 9],
+            sram,
+            sram_loaded: false,
+            set_rumble_state: None,
+            rumble: false,
+            cheats: Vec::new(),
+        })
+    }
+
+    fn update_keys(&mut self, pressed: [bool; 9]) {
+        for (id, key) in JOYPAD_KEYS.iter().enumerate() {
+            let key = match *key {
+                Some(key) => key,
+                None => continue,
+            };
+            let pressed = pressed[id];
+            if pressed != self.pressed[id] {
+                match pressed {
+                    true => self.device.keydown(key),
+                    false => self.device.keyup(key),
+                }
+                self.pressed[id] = pressed;
+            }
+        }
+    }
+
+    // Runs until the next frame is drawn, or for one frame worth of time when the LCD is off
+    fn run_frame(&mut self) {
+        let mut ticks = 0;
+        while ticks < CLOCKS_PER_FRAME * 2 {
+            ticks += self.device.do_cycle();
+            if self.device.check_and_reset_gpu_updated() {
+                break;
+            }
+        }
+
+        for (pixel, rgb) in self.frame.iter_mut().zip(self.device.framebuffer().chunks(4)) {
+            *pixel = ((rgb[0] as u32) << 16) | ((rgb[1] as u32) << 8) | rgb[2] as u32;
+        }
+    }
+
+    // The rumble motor of the cartridge to forward to the first controller, when it changed
+    fn update_rumble(&mut self) -> Option<(SetRumbleStateFn, bool)> {
+        let rumble = self.device.rumble_active();
+        if rumble == self.rumble {
+            return None;
+        }
+        self.rumble = rumble;
+        self.set_rumble_state.map(|set_rumble_state| (set_rumble_state, rumble))
+    }
+
+    // A cheat of the frontend can hold several codes, joined by '+'
+    fn apply_cheats(&mut self) {
+        self.device.clear_cheats();
+        for code in self.cheats.iter().flat_map(|cheat| cheat.split('+')).map(str::trim).filter(|code| !code.is_empty()) {
+            if let Err(e) = self.device.add_cheat(code) {
+                eprintln!("Warning: ignoring cheat {}: {}", code, e);
+            }
+        }
+    }
+
+    // The frontend fills the save RAM after loading the game, so it is only applied on the first frame
+    fn sync_sram(&mut self) {
+        if self.sram.is_empty() {
+            return;
+        }
+        if !self.sram_loaded {
+            let _ = self.device.loadram(&self.sram);
+            self.sram_loaded = true;
+        }
+        if self.device.check_and_reset_ram_updated() {
+            let ram = self.device.dumpram();
+            let len = self.sram.len();
+            self.sram.copy_from_slice(&ram[.. len]);
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_api_version() -> c_uint {
+    RETRO_API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn retro_init() {}
+
+#[no_mangle]
+pub extern "C" fn retro_deinit() {
+    *CORE.lock().unwrap() = None;
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_environment(cb: EnvironmentFn) {
+    CALLBACKS.lock().unwrap().environment = Some(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_video_refresh(cb: VideoRefreshFn) {
+    CALLBACKS.lock().unwrap().video_refresh = Some(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample(_cb: AudioSampleFn) {}
+
+#[no_mangle]
+pub extern "C" fn retro_set_audio_sample_batch(cb: AudioSampleBatchFn) {
+    CALLBACKS.lock().unwrap().audio_sample_batch = Some(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_poll(cb: InputPollFn) {
+    CALLBACKS.lock().unwrap().input_poll = Some(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_input_state(cb: InputStateFn) {
+    CALLBACKS.lock().unwrap().input_state = Some(cb);
+}
+
+#[no_mangle]
+pub extern "C" fn retro_set_controller_port_device(_port: c_uint, _device: c_uint) {}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_info(info: *mut RetroSystemInfo) {
+    *info = RetroSystemInfo {
+        library_name: "rboy\0".as_ptr() as *const c_char,
+        library_version: concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char,
+        valid_extensions: "gb|gbc\0".as_ptr() as *const c_char,
+        need_fullpath: false,
+        block_extract: false,
+    };
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_get_system_av_info(info: *mut RetroSystemAvInfo) {
+    *info = RetroSystemAvInfo {
+        geometry: RetroGameGeometry {
+            base_width: SCREEN_W as c_uint,
+            base_height: SCREEN_H as c_uint,
+            max_width: SCREEN_W as c_uint,
+            max_height: SCREEN_H as c_uint,
+            aspect_ratio: SCREEN_W as f32 / SCREEN_H as f32,
+        },
+        timing: RetroSystemTiming {
+            fps: FRAMES_PER_SECOND,
+            sample_rate: SAMPLE_RATE as f64,
+        },
+    };
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_region() -> c_uint {
+    RETRO_REGION_NTSC
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_load_game(game: *const RetroGameInfo) -> bool {
+    if game.is_null() || (*game).data.is_null() {
+        return false;
+    }
+
+    let mut rumble = RetroRumbleInterface { set_rumble_state: None };
+    let environment = CALLBACKS.lock().unwrap().environment;
+    if let Some(environment) = environment {
+        let mut format = RETRO_PIXEL_FORMAT_XRGB8888;
+        if !environment(RETRO_ENVIRONMENT_SET_PIXEL_FORMAT, &mut format as *mut c_uint as *mut c_void) {
+            return false;
+        }
+        if !environment(RETRO_ENVIRONMENT_GET_RUMBLE_INTERFACE, &mut rumble as *mut RetroRumbleInterface as *mut c_void) {
+            rumble.set_rumble_state = None;
+        }
+    }
+
+    let romdata = ::std::slice::from_raw_parts((*game).data as *const u8, (*game).size).to_vec();
+    let core = Core::new(romdata).map(|core| Core { set_rumble_state: rumble.set_rumble_state, ..core });
+    let loaded = core.is_some();
+    *CORE.lock().unwrap() = core;
+    loaded
+}
+
+#[no_mangle]
+pub extern "C" fn retro_load_game_special(_game_type: c_uint, _info: *const RetroGameInfo, _num_info: usize) -> bool {
+    false
+}
+
+#[no_mangle]
+pub extern "C" fn retro_unload_game() {
+    *CORE.lock().unwrap() = None;
+}
+
+#[no_mangle]
+pub extern "C" fn retro_reset() {
+    if let Some(ref mut core) = *CORE.lock().unwrap() {
+        let classic = core.device.is_classic();
+        let _ = core.device.switch_model(classic);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_run() {
+    // No lock is held while the frontend is called, as it may call back into the core
+    let (input_poll, input_state, video_refresh, audio_sample_batch) = {
+        let callbacks = CALLBACKS.lock().unwrap();
+        (callbacks.input_poll, callbacks.input_state, callbacks.video_refresh, callbacks.audio_sample_batch)
+    };
+    if CORE.lock().unwrap().is_none() {
+        return;
+    }
+
+    if let Some(input_poll) = input_poll {
+        input_poll();
+    }
+    let pressed = input_state.map(|input_state| {
+        let mut pressed = [false; 9];
+        for (id, pressed) in pressed.iter_mut().enumerate() {
+            *pressed = input_state(0, RETRO_DEVICE_JOYPAD, 0, id as c_uint) != 0;
+        }
+        pressed
+    });
+
+    let (frame, audio, rumble) = {
+        let mut guard = CORE.lock().unwrap();
+        let core = match *guard {
+            Some(ref mut core) => core,
+            None => return,
+        };
+        if let Some(pressed) = pressed {
+            core.update_keys(pressed);
+        }
+
+        core.sync_sram();
+        core.run_frame();
+        core.sync_sram();
+        let audio = std::mem::take(&mut *core.audio.lock().unwrap());
+        (core.frame.clone(), audio, core.update_rumble())
+    };
+
+    if let Some((set_rumble_state, rumble)) = rumble {
+        set_rumble_state(0, RETRO_RUMBLE_STRONG, if rumble { u16::MAX } else { 0 });
+    }
+
+    if let Some(video_refresh) = video_refresh {
+        video_refresh(frame.as_ptr() as *const c_void, SCREEN_W as c_uint, SCREEN_H as c_uint, SCREEN_W * 4);
+    }
+
+    if let Some(audio_sample_batch) = audio_sample_batch {
+        let mut written = 0;
+        while written < audio.len() {
+            let frames = audio_sample_batch(audio[written ..].as_ptr(), (audio.len() - written) / 2);
+            if frames == 0 {
+                break;
+            }
+            written += frames * 2;
+        }
+    }
+}
+
+// The buffer holds the length of the state as 4 bytes little endian, then the state of
+// Device::save_state, padded with zeroes
+#[no_mangle]
+pub extern "C" fn retro_serialize_size() -> usize {
+    match *CORE.lock().unwrap() {
+        Some(ref core) => 4 + core.device.save_state().len() + STATE_SLACK,
+        None => 0,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_serialize(data: *mut c_void, size: usize) -> bool {
+    let state = match *CORE.lock().unwrap() {
+        Some(ref core) => core.device.save_state(),
+        None => return false,
+    };
+    if data.is_null() || size < 4 + state.len() {
+        return false;
+    }
+
+    let buffer = ::std::slice::from_raw_parts_mut(data as *mut u8, size);
+    buffer[.. 4].copy_from_slice(&(state.len() as u32).to_le_bytes());
+    buffer[4 .. 4 + state.len()].copy_from_slice(&state);
+    for byte in buffer[4 + state.len() ..].iter_mut() {
+        *byte = 0;
+    }
+    true
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_unserialize(data: *const c_void, size: usize) -> bool {
+    if data.is_null() || size < 4 {
+        return false;
+    }
+    let buffer = ::std::slice::from_raw_parts(data as *const u8, size);
+    let len = u32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize;
+    let state = match buffer[4 ..].get(.. len) {
+        Some(state) => state,
+        None => return false,
+    };
+
+    match *CORE.lock().unwrap() {
+        Some(ref mut core) => match core.device.load_state(state) {
+            Ok(()) => {
+                // The loaded cartridge RAM is what the frontend saves from now on
+                core.sram_loaded = true;
+                if !core.sram.is_empty() {
+                    let ram = core.device.dumpram();
+                    let len = core.sram.len();
+                    core.sram.copy_from_slice(&ram[.. len]);
+                }
+                true
+            },
+            Err(e) => {
+                eprintln!("Warning: could not load the state: {}", e);
+                false
+            },
+        },
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_cheat_reset() {
+    if let Some(ref mut core) = *CORE.lock().unwrap() {
+        core.cheats.clear();
+        core.device.clear_cheats();
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn retro_cheat_set(index: c_uint, enabled: bool, code: *const c_char) {
+    let mut guard = CORE.lock().unwrap();
+    let core = match *guard {
+        Some(ref mut core) => core,
+        None => return,
+    };
+
+    let index = index as usize;
+    if core.cheats.len() <= index {
+        core.cheats.resize(index + 1, String::new());
+    }
+    core.cheats[index] = match (enabled, code.is_null()) {
+        (true, false) => ::std::ffi::CStr::from_ptr(code).to_string_lossy().into_owned(),
+        _ => String::new(),
+    };
+    core.apply_cheats();
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_data(id: c_uint) -> *mut c_void {
+    match (id, &mut *CORE.lock().unwrap()) {
+        (RETRO_MEMORY_SAVE_RAM, Some(core)) if !core.sram.is_empty() => core.sram.as_mut_ptr() as *mut c_void,
+        _ => ::std::ptr::null_mut(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn retro_get_memory_size(id: c_uint) -> usize {
+    match (id, &*CORE.lock().unwrap()) {
+        (RETRO_MEMORY_SAVE_RAM, Some(core)) => core.sram.len(),
+        _ => 0,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    static FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+    extern "C" fn environment(cmd: c_uint, data: *mut c_void) -> bool {
+        cmd == RETRO_ENVIRONMENT_SET_PIXEL_FORMAT && unsafe { *(data as *const c_uint) } == RETRO_PIXEL_FORMAT_XRGB8888
+    }
+
+    // Calls back into the core, as frontends may
+    extern "C" fn video_refresh(data: *const c_void, width: c_uint, height: c_uint, pitch: usize) {
+        assert!(!data.is_null());
+        assert_eq!((width, height, pitch), (SCREEN_W as c_uint, SCREEN_H as c_uint, SCREEN_W * 4));
+        assert_eq!(retro_get_memory_size(RETRO_MEMORY_SAVE_RAM), 0);
+        FRAMES.fetch_add(1, Ordering::SeqCst);
+    }
+
+    extern "C" fn input_poll() {
+        assert_eq!(retro_get_memory_size(RETRO_MEMORY_SAVE_RAM), 0);
+    }
+
+    extern "C" fn input_state(_port: c_uint, _device: c_uint, _index: c_uint, _id: c_uint) -> i16 {
+        assert_eq!(retro_get_memory_size(RETRO_MEMORY_SAVE_RAM), 0);
+        0
+    }
+
+    extern "C" fn audio_sample_batch(_data: *const i16, frames: usize) -> usize {
+        frames
+    }
+
+    #[test]
+    fn runs_frames() {
+        let romdata = ::std::fs::read("roms/cpu_instrs.gb").unwrap();
+        let game = RetroGameInfo {
+            path: ::std::ptr::null(),
+            data: romdata.as_ptr() as *const c_void,
+            size: romdata.len(),
+            meta: ::std::ptr::null(),
+        };
+
+        retro_set_environment(environment);
+        retro_set_video_refresh(video_refresh);
+        retro_set_audio_sample_batch(audio_sample_batch);
+        retro_set_input_poll(input_poll);
+        retro_set_input_state(input_state);
+        retro_init();
+        assert!(unsafe { retro_load_game(&game) });
+
+        for _ in 0 .. 60 {
+            retro_run();
+        }
+        assert_eq!(FRAMES.load(Ordering::SeqCst), 60);
+        assert_eq!(retro_get_memory_size(RETRO_MEMORY_SAVE_RAM), 0);
+
+        // A state brings back the same frames
+        let frame = || CORE.lock().unwrap().as_ref().unwrap().frame.clone();
+        let mut state = vec![0xFF; retro_serialize_size()];
+        assert!(unsafe { retro_serialize(state.as_mut_ptr() as *mut c_void, state.len()) });
+        for _ in 0 .. 10 {
+            retro_run();
+        }
+        let after = frame();
+        assert!(unsafe { retro_unserialize(state.as_ptr() as *const c_void, state.len()) });
+        for _ in 0 .. 10 {
+            retro_run();
+        }
+        assert!(frame() == after);
+        assert!(!unsafe { retro_serialize(state.as_mut_ptr() as *mut c_void, 4) });
+        assert!(!unsafe { retro_unserialize(state.as_ptr() as *const c_void, 100) });
+
+        // Cheats are set by index, and several codes can be joined with '+'
+        let read = |address| CORE.lock().unwrap().as_mut().unwrap().device.read_memory(address);
+        let (original, other) = (read(0x0D58), read(0x0D59));
+        unsafe { retro_cheat_set(3, true, "3ED-58F + 12D-59F\0".as_ptr() as *const c_char) };
+        assert_eq!((read(0x0D58), read(0x0D59)), (0x3E, 0x12));
+        unsafe { retro_cheat_set(3, false, "3ED-58F + 12D-59F\0".as_ptr() as *const c_char) };
+        assert_eq!((read(0x0D58), read(0x0D59)), (original, other));
+        unsafe { retro_cheat_set(0, true, "3ED-58F\0".as_ptr() as *const c_char) };
+        assert_eq!(read(0x0D58), 0x3E);
+        retro_cheat_reset();
+        assert_eq!(read(0x0D58), original);
+
+        retro_unload_game();
+        retro_deinit();
+    }
+}
@@ -1,9 +1,12 @@
 #![crate_name = "rboy"]
 
-use rboy::device::Device;
+use rboy::device::{Device, LoadOptions};
+use std::collections::VecDeque;
 use std::io::{self, Read};
-use std::sync::mpsc::{self, Receiver, SyncSender, TryRecvError, TrySendError};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TryRecvError, TrySendError};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use std::thread;
 use cpal::traits::{HostTrait, DeviceTrait, StreamTrait};
 use cpal::{Sample, FromSample};
@@ -11,6 +14,57 @@ use winit::platform::pump_events::{EventLoopExtPumpEvents, PumpStatus};
 
 const EXITCODE_SUCCESS : i32 = 0;
 const EXITCODE_CPULOADFAILS : i32 = 2;
+const AUDIO_RETRY_INTERVAL : std::time::Duration = std::time::Duration::from_secs(2);
+const UNDERRUN_FADE_FRAMES : usize = 64;
+const SPEED_MEASURE_INTERVAL : std::time::Duration = std::time::Duration::from_millis(250);
+// How long a message stays in the title of the window
+const MESSAGE_DURATION : std::time::Duration = std::time::Duration::from_secs(2);
+// The percentage of frame blending that the G key turns on without --frame-blend
+const DEFAULT_FRAME_BLEND : u8 = 50;
+
+// Which samples to drop when the emulator produces audio faster than the device plays it
+#[derive(Copy, Clone, PartialEq)]
+enum AudioDropPolicy {
+    Oldest,
+    Newest,
+}
+
+// A command of the --debug prompt
+#[derive(Debug, PartialEq)]
+enum DebugCommand {
+    Break(u16),
+    DeleteBreak(u16),
+    Watch { address: u16, on_read: bool, on_write: bool },
+    DeleteWatch(u16),
+    Step(u32),
+    StepOver,
+    StepOut,
+    RunTo(u16),
+    FollowInterrupts(bool),
+    Continue,
+    Registers,
+    Memory { address: u16, len: u16 },
+    Oam,
+    Help,
+    Quit,
+}
+
+const DEBUG_HELP: &str = "\
+b ADDR            set a breakpoint
+db ADDR           delete a breakpoint
+w ADDR [r|w|rw]   watch an address for reads and/or writes. Default: w
+dw ADDR           delete a watchpoint
+s [N]             step N instructions, N in decimal. Default: 1
+n                 step over a CALL or RST, or else step one instruction
+o                 step out, until a RET returns from the current function
+g ADDR            run until ADDR is reached
+i f|s             follow interrupts into their handler, or skip them, during n and o. Default: s
+c                 continue until a breakpoint, watchpoint or trap
+r                 print the registers and the next instruction
+m ADDR [LEN]      print LEN bytes of memory. Default: 16
+oam               print the sprites, with a * on the ones drawn on the current line
+q                 quit
+Addresses and lengths are in hex";
 
 #[derive(Default)]
 struct RenderOptions {
@@ -22,6 +76,30 @@ enum GBEvent {
     KeyDown(rboy::KeypadKey),
     SpeedUp,
     SpeedDown,
+    SwitchModel,
+    Reset,
+    Resume,
+    DumpApu,
+    DumpMemory,
+    CycleDmgPalette,
+    CycleColorCorrection,
+    // Turns frame blending off, or on with the given percentage
+    ToggleFrameBlend(u8),
+    // Saves the last frame as a PNG file, in gray with the color numbers when raw
+    Screenshot(PathBuf, bool),
+    // Saves the tiles and BG maps in VRAM as PNG files in the directory
+    DumpVram(PathBuf),
+    ToggleSpriteBoxes,
+    // Shows or hides a layer of the picture
+    ToggleLayer(rboy::Layer),
+    // Save and load the state in a slot, numbered from 1
+    SaveState(u8),
+    LoadState(u8),
+    // Rewinding starts and stops
+    Rewind(bool),
+    // The tilt of an MBC7 cartridge in g, to the right and towards the player
+    Tilt(f32, f32),
+    AudioPlayer(Box<CpalPlayer>),
 }
 
 #[cfg(target_os = "windows")]
@@ -68,6 +146,162 @@ fn parse_scale_var(arg: &str) -> Result<u32, ArgParseError> {
     }
 }
 
+fn parse_latency_var(arg: &str) -> Result<u32, ArgParseError> {
+    match arg.parse::<u32>() {
+        Err(e) => Err(ArgParseError::new(format!("Could not parse audio latency: {}", e))),
+        Ok(s) if s < 1 => Err(ArgParseError::new("Audio latency must be at least 1 ms")),
+        Ok(s) if s > 1000 => Err(ArgParseError::new("Audio latency may be at most 1000 ms")),
+        Ok(s) => Ok(s),
+    }
+}
+
+fn parse_rewind_length(arg: &str) -> Result<u32, ArgParseError> {
+    match arg.parse::<u32>() {
+        Err(e) => Err(ArgParseError::new(format!("Could not parse rewind length: {}", e))),
+        Ok(s) if s > 600 => Err(ArgParseError::new("Rewind length may be at most 600 seconds")),
+        Ok(s) => Ok(s),
+    }
+}
+
+fn parse_rewind_interval(arg: &str) -> Result<u32, ArgParseError> {
+    match arg.parse::<u32>() {
+        Err(e) => Err(ArgParseError::new(format!("Could not parse rewind interval: {}", e))),
+        Ok(s) if s < 1 => Err(ArgParseError::new("Rewind interval must be at least 1 frame")),
+        Ok(s) if s > 60 => Err(ArgParseError::new("Rewind interval may be at most 60 frames")),
+        Ok(s) => Ok(s),
+    }
+}
+
+fn parse_frame_blend(arg: &str) -> Result<u8, ArgParseError> {
+    match arg.parse::<u8>() {
+        Err(e) => Err(ArgParseError::new(format!("Could not parse frame blend: {}", e))),
+        Ok(s) if s > 100 => Err(ArgParseError::new("Frame blend may be at most 100 percent")),
+        Ok(s) => Ok(s),
+    }
+}
+
+fn parse_headroom_var(arg: &str) -> Result<f32, ArgParseError> {
+    match arg.parse::<f32>() {
+        Err(e) => Err(ArgParseError::new(format!("Could not parse audio headroom: {}", e))),
+        Ok(s) if !(0.0 ..= 24.0).contains(&s) => Err(ArgParseError::new("Audio headroom must be between 0 and 24 dB")),
+        Ok(s) => Ok(s),
+    }
+}
+
+// Parses a range of ROM offsets in hex, such as 0100:0150
+fn parse_disasm_range(arg: &str) -> Result<(usize, usize), ArgParseError> {
+    let parse = |v: &str| {
+        let v = v.trim_start_matches("0x").trim_start_matches('$');
+        usize::from_str_radix(v, 16).map_err(|e| ArgParseError::new(format!("Could not parse disassembly address: {}", e)))
+    };
+    match arg.split_once(':') {
+        None => Err(ArgParseError::new("Disassembly range must be given as start:end")),
+        Some((start, end)) => match (parse(start)?, parse(end)?) {
+            (start, end) if start >= end => Err(ArgParseError::new("Disassembly range must end after its start")),
+            range => Ok(range),
+        },
+    }
+}
+
+// A palette name of rboy::DMG_PALETTES, or four colors as RRGGBB from light to dark
+fn parse_dmg_palette(arg: &str) -> Result<[[u8; 3]; 4], ArgParseError> {
+    if let Some(&(_, palette)) = rboy::DMG_PALETTES.iter().find(|(name, _)| *name == arg) {
+        return Ok(palette);
+    }
+    let colors: Vec<&str> = arg.split(',').map(|c| c.trim().trim_start_matches('#')).collect();
+    if colors.len() != 4 || colors.iter().any(|c| c.len() != 6) {
+        let names: Vec<&str> = rboy::DMG_PALETTES.iter().map(|&(name, _)| name).collect();
+        return Err(ArgParseError::new(format!("DMG palette must be one of {} or four RRGGBB colors separated by commas", names.join(", "))));
+    }
+    let mut palette = [[0; 3]; 4];
+    for (color, text) in palette.iter_mut().zip(colors) {
+        let rgb = u32::from_str_radix(text, 16).map_err(|e| ArgParseError::new(format!("Could not parse DMG palette color {}: {}", text, e)))?;
+        *color = [(rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8];
+    }
+    Ok(palette)
+}
+
+// auto, off or a set name of rboy::COMPAT_PALETTES
+fn parse_colorize(arg: &str) -> Result<rboy::Colorization, ArgParseError> {
+    match arg {
+        "auto" => Ok(rboy::Colorization::Auto),
+        "off" => Ok(rboy::Colorization::Off),
+        _ => match rboy::COMPAT_PALETTES.iter().position(|(name, _)| *name == arg) {
+            Some(set) => Ok(rboy::Colorization::Palette(set)),
+            None => {
+                let names: Vec<&str> = rboy::COMPAT_PALETTES.iter().map(|&(name, _)| name).collect();
+                Err(ArgParseError::new(format!("Colorization must be auto, off or one of {}", names.join(", "))))
+            },
+        },
+    }
+}
+
+// The preset after the current palette, or the first one after a custom palette
+fn next_dmg_palette(current: [[u8; 3]; 4]) -> (&'static str, [[u8; 3]; 4]) {
+    let next = rboy::DMG_PALETTES.iter().position(|&(_, palette)| palette == current).map_or(0, |i| i + 1);
+    rboy::DMG_PALETTES[next % rboy::DMG_PALETTES.len()]
+}
+
+fn next_color_correction(current: rboy::ColorCorrection) -> (&'static str, rboy::ColorCorrection) {
+    let next = rboy::COLOR_CORRECTIONS.iter().position(|&(_, correction)| correction == current).map_or(0, |i| i + 1);
+    rboy::COLOR_CORRECTIONS[next % rboy::COLOR_CORRECTIONS.len()]
+}
+
+fn parse_trace_limit(arg: &str) -> Result<u64, ArgParseError> {
+    match arg.parse::<u64>() {
+        Err(e) => Err(ArgParseError::new(format!("Could not parse trace limit: {}", e))),
+        Ok(s) if s < 1 => Err(ArgParseError::new("Trace limit must be at least 1 million instructions")),
+        Ok(s) => Ok(s),
+    }
+}
+
+fn parse_debug_command(line: &str) -> Result<DebugCommand, String> {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let hex = |i: usize| -> Result<u16, String> {
+        let word = words.get(i).ok_or_else(|| "Missing argument, see h".to_string())?;
+        let word = word.trim_start_matches("0x").trim_start_matches('$');
+        u16::from_str_radix(word, 16).map_err(|e| format!("Could not parse {}: {}", word, e))
+    };
+    let optional_hex = |i: usize, default: u16| match words.get(i) {
+        None => Ok(default),
+        Some(_) => hex(i),
+    };
+    match words.first().copied() {
+        Some("b") => Ok(DebugCommand::Break(hex(1)?)),
+        Some("db") => Ok(DebugCommand::DeleteBreak(hex(1)?)),
+        Some("w") => {
+            let (on_read, on_write) = match words.get(2).copied() {
+                None | Some("w") => (false, true),
+                Some("r") => (true, false),
+                Some("rw") => (true, true),
+                Some(other) => return Err(format!("Unknown watchpoint kind {}", other)),
+            };
+            Ok(DebugCommand::Watch { address: hex(1)?, on_read, on_write })
+        },
+        Some("dw") => Ok(DebugCommand::DeleteWatch(hex(1)?)),
+        Some("s") => match words.get(1) {
+            None => Ok(DebugCommand::Step(1)),
+            Some(n) => n.parse().map(DebugCommand::Step).map_err(|e| format!("Could not parse {}: {}", n, e)),
+        },
+        Some("n") => Ok(DebugCommand::StepOver),
+        Some("o") => Ok(DebugCommand::StepOut),
+        Some("g") => Ok(DebugCommand::RunTo(hex(1)?)),
+        Some("i") => match words.get(1).copied() {
+            Some("f") => Ok(DebugCommand::FollowInterrupts(true)),
+            Some("s") => Ok(DebugCommand::FollowInterrupts(false)),
+            _ => Err("Expected i f or i s".into()),
+        },
+        Some("c") => Ok(DebugCommand::Continue),
+        Some("r") => Ok(DebugCommand::Registers),
+        Some("m") => Ok(DebugCommand::Memory { address: hex(1)?, len: optional_hex(2, 16)? }),
+        Some("oam") => Ok(DebugCommand::Oam),
+        Some("h") => Ok(DebugCommand::Help),
+        Some("q") => Ok(DebugCommand::Quit),
+        Some(other) => Err(format!("Unknown command {}, see h", other)),
+        None => Err("No command, see h".into()),
+    }
+}
+
 fn main() {
     let exit_status = real_main();
     if exit_status != EXITCODE_SUCCESS {
@@ -82,7 +316,7 @@ fn real_main() -> i32 {
         .about("A Gameboy Colour emulator written in Rust")
         .arg(clap::Arg::new("filename")
              .help("Sets the ROM file to load")
-             .required(true))
+             .required_unless_present("list-audio-devices"))
         .arg(clap::Arg::new("serial")
              .help("Prints the data from the serial port to stdout")
              .short('s')
@@ -98,6 +332,10 @@ fn real_main() -> i32 {
              .short('c')
              .long("classic")
              .action(clap::ArgAction::SetTrue))
+        .arg(clap::Arg::new("sgb")
+             .help("Runs games that support it as on a Super Game Boy, with its border and colors. Implies --classic")
+             .long("sgb")
+             .action(clap::ArgAction::SetTrue))
         .arg(clap::Arg::new("scale")
              .help("Sets the scale of the interface. Default: 2")
              .short('x')
@@ -108,39 +346,239 @@ fn real_main() -> i32 {
              .short('a')
              .long("audio")
              .action(clap::ArgAction::SetTrue))
+        .arg(clap::Arg::new("audio-latency")
+             .help("Sets the requested audio latency in milliseconds. Default: 45")
+             .long("audio-latency")
+             .value_parser(parse_latency_var))
+        .arg(clap::Arg::new("audio-headroom")
+             .help("Sets the attenuation of the audio output in dB, to prevent clipping. Default: 3")
+             .long("audio-headroom")
+             .value_parser(parse_headroom_var))
+        .arg(clap::Arg::new("audio-soft-clip")
+             .help("Saturates loud audio gradually instead of clipping it")
+             .long("audio-soft-clip")
+             .action(clap::ArgAction::SetTrue))
+        .arg(clap::Arg::new("audio-drop")
+             .help("Sets which audio samples are dropped when the output queue is full. Default: newest")
+             .long("audio-drop")
+             .value_parser(["oldest", "newest"]))
+        .arg(clap::Arg::new("audio-device")
+             .help("Sets the name of the audio output device. Default: the system default")
+             .long("audio-device"))
+        .arg(clap::Arg::new("list-audio-devices")
+             .help("Lists the available audio output devices")
+             .long("list-audio-devices")
+             .action(clap::ArgAction::SetTrue))
+        .arg(clap::Arg::new("audio-peaks")
+             .help("Prints the peak level of each audio channel and the clipped and dropped samples and underruns every second")
+             .long("audio-peaks")
+             .action(clap::ArgAction::SetTrue))
+        .arg(clap::Arg::new("rewind-length")
+             .help("Sets how many seconds can be rewound by holding B, or 0 to turn rewinding off. Default: 10")
+             .long("rewind-length")
+             .value_parser(parse_rewind_length))
+        .arg(clap::Arg::new("rewind-interval")
+             .help("Sets after how many frames the state is kept for rewinding. Default: 6")
+             .long("rewind-interval")
+             .value_parser(parse_rewind_interval))
+        .arg(clap::Arg::new("cheat")
+             .help("Activates a Game Genie code, as ABC-DEF or ABC-DEF-GHI, or a GameShark code of 8 hex digits. Can be given more than once")
+             .long("cheat")
+             .action(clap::ArgAction::Append))
+        .arg(clap::Arg::new("cheat-file")
+             .help("Activates the cheat codes in a text file, one per line with an optional description after it. Lines starting with # are skipped")
+             .long("cheat-file"))
+        .arg(clap::Arg::new("traps")
+             .help("Pauses the emulation when the game appears to crash, e.g. on a jump to 0000, or does a CGB speed switch that fails on hardware. Always on in debug builds")
+             .long("traps")
+             .action(clap::ArgAction::SetTrue))
         .arg(clap::Arg::new("skip-checksum")
-             .help("Skips verification of the cartridge checksum")
+             .help("Does not warn about invalid cartridge checksums")
              .long("skip-checksum")
              .action(clap::ArgAction::SetTrue))
+        .arg(clap::Arg::new("mbc1-multicart")
+             .help("Maps an MBC1 cartridge as a multicart (MBC1M) or not. Default: detected from the ROM")
+             .long("mbc1-multicart")
+             .value_parser(["on", "off"]))
+        .arg(clap::Arg::new("dmg-palette")
+             .help("Sets the colors of classic games, as gray, green, pocket or contrast, or four RRGGBB colors from light to dark separated by commas. Default: gray")
+             .long("dmg-palette")
+             .value_parser(parse_dmg_palette))
+        .arg(clap::Arg::new("colorize")
+             .help("Sets the colors of classic games on a Gameboy Color without a boot ROM: auto for the ones the CGB boot ROM picks from the title, off for gray or a set of colors by name. Default: auto")
+             .long("colorize")
+             .value_parser(parse_colorize))
+        .arg(clap::Arg::new("color-correction")
+             .help("Shows the colors of color games as they are, or mixed and darkened like on the LCD of a Gameboy Color or Advance. Shift+C cycles them. Default: cgb")
+             .long("color-correction")
+             .value_name("profile")
+             .value_parser(["raw", "cgb", "gba"]))
+        .arg(clap::Arg::new("frame-blend")
+             .help("Mixes this percentage of the previous frame into each frame, like the slow LCD of the Gameboy, or 0 to turn it off. G toggles it. Default: 0, 50 when toggled on")
+             .long("frame-blend")
+             .value_parser(parse_frame_blend))
+        .arg(clap::Arg::new("renderer")
+             .help("Draws the screen line by line, or pixel by pixel with the slower pixel FIFO that shows register writes in the middle of a line. Default: scanline")
+             .long("renderer")
+             .value_parser(["scanline", "fifo"]))
+        .arg(clap::Arg::new("screenshot-dir")
+             .help("Sets the directory that F12 saves screenshots in, Shift+F12 screenshots of the color numbers 0 to 3 in gray, and V the tiles and BG maps in VRAM. Default: the current directory")
+             .long("screenshot-dir"))
+        .arg(clap::Arg::new("boot-rom")
+             .help("Runs a 256 byte DMG boot ROM from this file before the game, in classic mode")
+             .long("boot-rom"))
+        .arg(clap::Arg::new("cgb-boot-rom")
+             .help("Runs a 2304 byte CGB boot ROM from this file before the game, in Gameboy Color mode")
+             .long("cgb-boot-rom"))
         .arg(clap::Arg::new("test-mode")
              .help("Starts the emulator in a special test mode")
              .long("test-mode")
              .action(clap::ArgAction::SetTrue))
+        .arg(clap::Arg::new("disasm")
+             .help("Prints the disassembly of the ROM from start up to end, in hex as start:end, and exits")
+             .long("disasm")
+             .value_parser(parse_disasm_range))
+        .arg(clap::Arg::new("debug")
+             .help("Starts a debugger prompt on the terminal instead of the window")
+             .long("debug")
+             .action(clap::ArgAction::SetTrue))
+        .arg(clap::Arg::new("trace")
+             .help("Logs the CPU state before every instruction to a file in the Gameboy Doctor format. LY always reads as 90 while tracing")
+             .long("trace"))
+        .arg(clap::Arg::new("trace-limit")
+             .help("Stops the trace after this many million instructions")
+             .long("trace-limit")
+             .requires("trace")
+             .value_parser(parse_trace_limit))
+        .arg(clap::Arg::new("trace-disasm")
+             .help("Adds the disassembled instruction to each line of the trace")
+             .long("trace-disasm")
+             .requires("trace")
+             .action(clap::ArgAction::SetTrue))
+        .arg(clap::Arg::new("profile")
+             .help("Writes the instructions and cycles executed per ROM bank and address to a CSV file on exit")
+             .long("profile"))
         .get_matches();
 
     let test_mode = matches.get_one::<bool>("test-mode").copied().unwrap();
+    let debug_mode = matches.get_one::<bool>("debug").copied().unwrap();
     let opt_serial = matches.get_one::<bool>("serial").copied().unwrap();
     let opt_printer = matches.get_one::<bool>("printer").copied().unwrap();
-    let opt_classic = matches.get_one::<bool>("classic").copied().unwrap();
+    let opt_sgb = matches.get_one::<bool>("sgb").copied().unwrap();
+    let opt_classic = matches.get_one::<bool>("classic").copied().unwrap() || opt_sgb;
     let opt_audio = matches.get_one::<bool>("audio").copied().unwrap();
+    let opt_audio_peaks = matches.get_one::<bool>("audio-peaks").copied().unwrap();
     let opt_skip_checksum = matches.get_one::<bool>("skip-checksum").copied().unwrap();
-    let filename = matches.get_one::<String>("filename").unwrap();
+    let opt_traps = matches.get_one::<bool>("traps").copied().unwrap();
+    let opt_audio_soft_clip = matches.get_one::<bool>("audio-soft-clip").copied().unwrap();
+    let audio_headroom = matches.get_one::<f32>("audio-headroom").copied().unwrap_or(rboy::SoundOptions::default().headroom_db);
+    let audio_drop_policy = match matches.get_one::<String>("audio-drop").map(|s| s.as_str()) {
+        Some("oldest") => AudioDropPolicy::Oldest,
+        _ => AudioDropPolicy::Newest,
+    };
+    let audio_dropped = Arc::new(AtomicU64::new(0));
+    let audio_underruns = Arc::new(AtomicU64::new(0));
+    let opt_list_audio_devices = matches.get_one::<bool>("list-audio-devices").copied().unwrap();
+    let audio_device = matches.get_one::<String>("audio-device");
     let scale = matches.get_one::<u32>("scale").copied().unwrap_or(2);
+    let audio_latency = matches.get_one::<u32>("audio-latency").copied().unwrap_or(rboy::SoundOptions::default().latency_ms);
+    let trace = matches.get_one::<String>("trace").map(|file| Trace {
+        file,
+        limit: matches.get_one::<u64>("trace-limit").map(|n| n * 1_000_000),
+        disasm: matches.get_one::<bool>("trace-disasm").copied().unwrap(),
+    });
+    let profile_file = matches.get_one::<String>("profile");
+    let mbc1_multicart = matches.get_one::<String>("mbc1-multicart").map(|s| s == "on");
+    let frame_blend = matches.get_one::<u8>("frame-blend").copied().unwrap_or(0);
+    let dmg_palette = matches.get_one::<[[u8; 3]; 4]>("dmg-palette").copied();
+    let colorization = matches.get_one::<rboy::Colorization>("colorize").copied().unwrap_or_default();
+    let color_correction = matches.get_one::<String>("color-correction")
+        .and_then(|arg| rboy::COLOR_CORRECTIONS.iter().find(|(name, _)| name == arg))
+        .map_or_else(rboy::ColorCorrection::default, |&(_, correction)| correction);
+    let screenshot_dir = PathBuf::from(matches.get_one::<String>("screenshot-dir").map_or(".", |s| s.as_str()));
+    let opt_fifo = matches.get_one::<String>("renderer").is_some_and(|s| s == "fifo");
+    let cheats: Vec<&String> = matches.get_many::<String>("cheat").map_or(Vec::new(), |codes| codes.collect());
+    let cheat_file = matches.get_one::<String>("cheat-file");
+    let rewind_options = rboy::RewindOptions {
+        seconds: matches.get_one::<u32>("rewind-length").copied().unwrap_or(rboy::RewindOptions::default().seconds),
+        interval: matches.get_one::<u32>("rewind-interval").copied().unwrap_or(rboy::RewindOptions::default().interval),
+        ..Default::default()
+    };
+    let boot_roms = BootRoms {
+        dmg: matches.get_one::<String>("boot-rom").map(|s| s.as_str()),
+        cgb: matches.get_one::<String>("cgb-boot-rom").map(|s| s.as_str()),
+    };
+
+    if opt_list_audio_devices {
+        for (id, name) in audio_output_devices() {
+            println!("{}: {}", id, name);
+        }
+        return EXITCODE_SUCCESS;
+    }
+
+    let filename = matches.get_one::<String>("filename").unwrap();
+
+    if let Some(&(start, end)) = matches.get_one::<(usize, usize)>("disasm") {
+        return run_disasm(filename, start, end);
+    }
+
+    if debug_mode {
+        return run_debug_mode(filename, opt_classic, opt_skip_checksum, &boot_roms);
+    }
 
     if test_mode {
-        return run_test_mode(filename, opt_classic, opt_skip_checksum);
+        return run_test_mode(filename, opt_classic, opt_skip_checksum, trace.as_ref());
     }
 
-    let cpu = construct_cpu(filename, opt_classic, opt_serial, opt_printer, opt_skip_checksum);
+    let options = match load_options(opt_classic, opt_skip_checksum, &boot_roms) {
+        Some(options) => options,
+        None => return EXITCODE_CPULOADFAILS,
+    };
+    let cpu = construct_cpu(filename, LoadOptions { sgb: opt_sgb, colorization, ..options }, opt_serial, opt_printer);
     if cpu.is_none() { return EXITCODE_CPULOADFAILS; }
     let mut cpu = cpu.unwrap();
+    if opt_traps {
+        cpu.set_traps(rboy::TrapOptions::enabled());
+    }
+    if let Some(ref trace) = trace {
+        if !start_trace(&mut cpu, trace) { return EXITCODE_CPULOADFAILS; }
+    }
+    if profile_file.is_some() {
+        cpu.start_profile();
+    }
+    if let Some(multicart) = mbc1_multicart {
+        cpu.set_mbc1_multicart(multicart);
+    }
+    if opt_fifo {
+        cpu.set_renderer(rboy::Renderer::Fifo);
+    }
+    if let Some(palette) = dmg_palette {
+        cpu.set_dmg_palette(palette);
+    }
+    cpu.set_color_correction(color_correction);
+    cpu.set_frame_blend(frame_blend);
+    // The G key toggles between no blending and this
+    let frame_blend_toggle = if frame_blend == 0 { DEFAULT_FRAME_BLEND } else { frame_blend };
+    if !add_cheats(&mut cpu, &cheats, cheat_file.map(|f| f.as_str())) { return EXITCODE_CPULOADFAILS; }
 
     let mut cpal_audio_stream = None;
+    let mut audio_lost = None;
     if opt_audio {
-        let player = CpalPlayer::get();
+        let player = CpalPlayer::get(audio_device.map(|s| s.as_str()), audio_latency, audio_drop_policy, audio_dropped.clone(), audio_underruns.clone());
         match player {
             Some((v, s)) => {
-                cpu.enable_audio(Box::new(v) as Box<dyn rboy::AudioPlayer>);
+                warn(&format!("Audio output: {}", v.description()));
+                let options = rboy::SoundOptions {
+                    latency_ms: v.latency_ms().unwrap_or(audio_latency),
+                    headroom_db: audio_headroom,
+                    soft_clip: opt_audio_soft_clip,
+                };
+                audio_lost = Some(v.lost.clone());
+                cpu.enable_audio_with_options(Box::new(v) as Box<dyn rboy::AudioPlayer>, options);
+                if opt_audio_peaks {
+                    let clip_stats = cpu.audio_clip_stats().unwrap();
+                    cpu.set_audio_sample_tap(Some(audio_peak_printer(rboy::INTERNAL_SAMPLE_RATE, clip_stats, audio_dropped.clone(), audio_underruns.clone())));
+                }
                 cpal_audio_stream = Some(s);
             },
             None => {
@@ -149,27 +587,33 @@ fn real_main() -> i32 {
             },
         }
     }
-    let romname = cpu.romname();
+    let romname = cpu.rom_info().title.clone();
 
     let (sender1, receiver1) = mpsc::channel();
     let (sender2, receiver2) = mpsc::sync_channel(1);
+    let (message_sender, message_receiver) = mpsc::channel();
 
     let mut event_loop = winit::event_loop::EventLoop::new().unwrap();
     let window_builder = create_window_builder(&romname);
     let (window, display) = glium::backend::glutin::SimpleWindowBuilder::new().set_window_builder(window_builder).build(&event_loop);
-    set_window_size(&window, scale);
-
-    let mut texture = glium::texture::texture2d::Texture2d::empty_with_format(
-            &display,
-            glium::texture::UncompressedFloatFormat::U8U8U8,
-            glium::texture::MipmapsOption::NoMipmap,
-            rboy::SCREEN_W as u32,
-            rboy::SCREEN_H as u32)
-        .unwrap();
+    // Larger with the border of a Super Game Boy, which can come and go with a model switch
+    let mut screen_size = cpu.screen_size();
+    set_window_size(&window, screen_size, scale);
+    let mut texture = screen_texture(&display, screen_size);
 
     let mut renderoptions = <RenderOptions as Default>::default();
+    // Held tilt keys, left, right, up and down
+    let mut tilt = [false; 4];
+
+    let mut modifiers = winit::keyboard::ModifiersState::empty();
+    let mut message_end = None;
 
-    let cputhread = thread::spawn(move|| run_cpu(cpu, sender2, receiver1));
+    let statename = filename.clone();
+    let rewind = Some(rewind_options).filter(|options| options.seconds > 0);
+    let cputhread = thread::spawn(move|| run_cpu(cpu, &statename, rewind, sender2, receiver1, message_sender));
+
+    let mut audio_lost_reported = false;
+    let mut audio_retry_time = std::time::Instant::now();
 
     event_loop.set_control_flow(winit::event_loop::ControlFlow::Poll);
     'evloop: loop {
@@ -183,28 +627,80 @@ fn real_main() -> i32 {
                 Event::WindowEvent { event, .. } => match event {
                     WindowEvent::CloseRequested
                         => elwt.exit(),
+                    WindowEvent::ModifiersChanged(new)
+                        => modifiers = new.state(),
                     WindowEvent::KeyboardInput { event: keyevent, .. } => match (keyevent.state, keyevent.logical_key.as_ref()) {
                         (Pressed, Key::Named(NamedKey::Escape))
                             => elwt.exit(),
                         (Pressed, Key::Character("1"))
-                            => set_window_size(&window, 1),
+                            => set_window_size(&window, screen_size, 1),
                         (Pressed, Key::Character("r" | "R"))
-                            => set_window_size(&window, scale),
+                            => set_window_size(&window, screen_size, scale),
                         (Pressed, Key::Named(NamedKey::Shift))
                             => { let _ = sender1.send(GBEvent::SpeedUp); },
                         (Released, Key::Named(NamedKey::Shift))
                             => { let _ = sender1.send(GBEvent::SpeedDown); },
                         (Pressed, Key::Character("t" | "T"))
                             => { renderoptions.linear_interpolation = !renderoptions.linear_interpolation; }
+                        (Pressed, Key::Character("m" | "M"))
+                            => { let _ = sender1.send(GBEvent::SwitchModel); },
+                        (Pressed, Key::Character("p" | "P"))
+                            => { let _ = sender1.send(GBEvent::Resume); },
+                        (Pressed, Key::Named(NamedKey::Backspace))
+                            => { let _ = sender1.send(GBEvent::Reset); },
+                        (Pressed, Key::Character("a" | "A"))
+                            => { let _ = sender1.send(GBEvent::DumpApu); },
+                        (Pressed, Key::Character("d" | "D"))
+                            => { let _ = sender1.send(GBEvent::DumpMemory); },
+                        (Pressed, Key::Character("c" | "C")) if modifiers.shift_key()
+                            => { let _ = sender1.send(GBEvent::CycleColorCorrection); },
+                        (Pressed, Key::Character("c" | "C"))
+                            => { let _ = sender1.send(GBEvent::CycleDmgPalette); },
+                        (Pressed, Key::Character("o" | "O"))
+                            => { let _ = sender1.send(GBEvent::ToggleSpriteBoxes); },
+                        (Pressed, Key::Character("v" | "V"))
+                            => { let _ = sender1.send(GBEvent::DumpVram(screenshot_dir.clone())); },
+                        (Pressed, Key::Character("g" | "G"))
+                            => { let _ = sender1.send(GBEvent::ToggleFrameBlend(frame_blend_toggle)); },
+                        (Pressed, Key::Character(key @ ("b" | "B" | "w" | "W" | "s" | "S"))) if modifiers.control_key() => {
+                            let layer = match key {
+                                "b" | "B" => rboy::Layer::Background,
+                                "w" | "W" => rboy::Layer::Window,
+                                _ => rboy::Layer::Sprites,
+                            };
+                            let _ = sender1.send(GBEvent::ToggleLayer(layer));
+                        },
+                        (Pressed, Key::Character("b" | "B"))
+                            => { let _ = sender1.send(GBEvent::Rewind(true)); },
+                        (Released, Key::Character("b" | "B"))
+                            => { let _ = sender1.send(GBEvent::Rewind(false)); },
+                        (Pressed, Key::Named(NamedKey::F12)) => {
+                            let raw = modifiers.shift_key();
+                            let path = screenshot_path(&screenshot_dir, &romname, if raw { Some("raw") } else { None }, std::time::SystemTime::now());
+                            let _ = sender1.send(GBEvent::Screenshot(path, raw));
+                        },
+                        (Pressed, Key::Named(key @ (NamedKey::F1 | NamedKey::F2 | NamedKey::F3 | NamedKey::F4))) => {
+                            let slot = match key { NamedKey::F1 => 1, NamedKey::F2 => 2, NamedKey::F3 => 3, _ => 4 };
+                            let event = if modifiers.shift_key() { GBEvent::SaveState(slot) } else { GBEvent::LoadState(slot) };
+                            let _ = sender1.send(event);
+                        },
                         (Pressed, winitkey) => {
-                            if let Some(key) = winit_to_keypad(winitkey) {
+                            if let Some(key) = winit_to_keypad(winitkey.clone()) {
                                 let _ = sender1.send(GBEvent::KeyDown(key));
                             }
+                            else if let Some(direction) = winit_to_tilt(winitkey) {
+                                tilt[direction] = true;
+                                let _ = sender1.send(tilt_event(&tilt));
+                            }
                         },
                         (Released, winitkey) => {
-                            if let Some(key) = winit_to_keypad(winitkey) {
+                            if let Some(key) = winit_to_keypad(winitkey.clone()) {
                                 let _ = sender1.send(GBEvent::KeyUp(key));
                             }
+                            else if let Some(direction) = winit_to_tilt(winitkey) {
+                                tilt[direction] = false;
+                                let _ = sender1.send(tilt_event(&tilt));
+                            }
                         },
                     },
                     _ => (),
@@ -216,19 +712,77 @@ fn real_main() -> i32 {
         if let PumpStatus::Exit(_) = status {
             break 'evloop;
         }
+
+        if let Ok(message) = message_receiver.try_recv() {
+            window.set_title(&format!("RBoy - {} - {}", romname, message));
+            message_end = Some(std::time::Instant::now() + MESSAGE_DURATION);
+        }
+        if message_end.is_some_and(|end| std::time::Instant::now() >= end) {
+            window.set_title(&format!("RBoy - {}", romname));
+            message_end = None;
+        }
+
+        // The emulation continues without sound until the default device can be opened again
+        if audio_lost.as_ref().is_some_and(|lost: &Arc<AtomicBool>| lost.load(Ordering::Relaxed)) {
+            if !audio_lost_reported {
+                warn("Audio device lost, continuing without sound");
+                audio_lost_reported = true;
+                audio_retry_time = std::time::Instant::now() + AUDIO_RETRY_INTERVAL;
+            }
+            if std::time::Instant::now() >= audio_retry_time {
+                audio_retry_time = std::time::Instant::now() + AUDIO_RETRY_INTERVAL;
+                if let Some((player, stream)) = CpalPlayer::get(None, audio_latency, audio_drop_policy, audio_dropped.clone(), audio_underruns.clone()) {
+                    warn(&format!("Audio device restored: {}", player.description()));
+                    audio_lost = Some(player.lost.clone());
+                    audio_lost_reported = false;
+                    let _ = sender1.send(GBEvent::AudioPlayer(Box::new(player)));
+                    cpal_audio_stream = Some(stream);
+                }
+            }
+        }
         match receiver2.recv() {
-            Ok(data) => recalculate_screen(&display, &mut texture, &*data, &renderoptions),
+            Ok((width, height, data)) => {
+                if (width, height) != screen_size {
+                    screen_size = (width, height);
+                    set_window_size(&window, screen_size, scale);
+                    texture = screen_texture(&display, screen_size);
+                }
+                recalculate_screen(&display, &mut texture, screen_size, &data, &renderoptions)
+            },
             Err(..) => break 'evloop, // Remote end has hung-up
         }
     }
 
     drop(cpal_audio_stream);
     drop(receiver2); // Stop CPU thread by disconnecting
-    let _ = cputhread.join();
+    let cpu = cputhread.join();
+    if let (Some(profile_file), Ok(cpu)) = (profile_file, cpu) {
+        if let Err(e) = write_profile(&cpu, profile_file) {
+            warn(&format!("Could not write profile {}: {}", profile_file, e));
+        }
+    }
 
     EXITCODE_SUCCESS
 }
 
+// The index into the held tilt keys
+fn winit_to_tilt(key: winit::keyboard::Key<&str>) -> Option<usize> {
+    use winit::keyboard::Key;
+    match key {
+        Key::Character("J" | "j") => Some(0),
+        Key::Character("L" | "l") => Some(1),
+        Key::Character("I" | "i") => Some(2),
+        Key::Character("K" | "k") => Some(3),
+        _ => None,
+    }
+}
+
+// A held key tilts the cartridge by 1 g
+fn tilt_event(tilt: &[bool; 4]) -> GBEvent {
+    let axis = |negative: bool, positive: bool| positive as i8 as f32 - negative as i8 as f32;
+    GBEvent::Tilt(axis(tilt[0], tilt[1]), axis(tilt[2], tilt[3]))
+}
+
 fn winit_to_keypad(key: winit::keyboard::Key<&str>) -> Option<rboy::KeypadKey> {
     use winit::keyboard::{Key, NamedKey};
     match key {
@@ -244,8 +798,21 @@ fn winit_to_keypad(key: winit::keyboard::Key<&str>) -> Option<rboy::KeypadKey> {
     }
 }
 
+fn screen_texture<T: glium::glutin::surface::SurfaceTypeTrait + glium::glutin::surface::ResizeableSurface + 'static>(display: &glium::Display<T>,
+                  (width, height): (usize, usize)) -> glium::texture::texture2d::Texture2d
+{
+    glium::texture::texture2d::Texture2d::empty_with_format(
+            display,
+            glium::texture::UncompressedFloatFormat::U8U8U8,
+            glium::texture::MipmapsOption::NoMipmap,
+            width as u32,
+            height as u32)
+        .unwrap()
+}
+
 fn recalculate_screen<T: glium::glutin::surface::SurfaceTypeTrait + glium::glutin::surface::ResizeableSurface + 'static>(display: &glium::Display<T>,
                       texture: &mut glium::texture::texture2d::Texture2d,
+                      (width, height): (usize, usize),
                       datavec: &[u8],
                       renderoptions: &RenderOptions)
 {
@@ -260,16 +827,16 @@ fn recalculate_screen<T: glium::glutin::surface::SurfaceTypeTrait + glium::gluti
 
     let rawimage2d = glium::texture::RawImage2d {
         data: std::borrow::Cow::Borrowed(datavec),
-        width: rboy::SCREEN_W as u32,
-        height: rboy::SCREEN_H as u32,
-        format: glium::texture::ClientFormat::U8U8U8,
+        width: width as u32,
+        height: height as u32,
+        format: glium::texture::ClientFormat::U8U8U8U8,
     };
     texture.write(
         glium::Rect {
             left: 0,
             bottom: 0,
-            width: rboy::SCREEN_W as u32,
-            height: rboy::SCREEN_H as u32
+            width: width as u32,
+            height: height as u32
         },
         rawimage2d);
 
@@ -292,16 +859,16 @@ fn warn(message: &str) {
     eprintln!("{}", message);
 }
 
-fn construct_cpu(filename: &str, classic_mode: bool, output_serial: bool, output_printer: bool, skip_checksum: bool) -> Option<Box<Device>> {
-    let opt_c = match classic_mode {
-        true => Device::new(filename, skip_checksum),
-        false => Device::new_cgb(filename, skip_checksum),
-    };
-    let mut c = match opt_c
+fn construct_cpu(filename: &str, options: LoadOptions, output_serial: bool, output_printer: bool) -> Option<Box<Device>> {
+    let sgb = options.sgb;
+    let mut c = match Device::new_from_file(filename, options)
     {
         Ok(cpu) => { cpu },
-        Err(message) => { warn(message); return None; },
+        Err(e) => { warn(e.message()); return None; },
     };
+    if sgb && !c.rom_info().supports_sgb() {
+        warn("The game does not support the Super Game Boy, running it as on a classic Gameboy");
+    }
 
     if output_printer {
         c.attach_printer();
@@ -313,25 +880,240 @@ fn construct_cpu(filename: &str, classic_mode: bool, output_serial: bool, output
     Some(Box::new(c))
 }
 
-fn run_cpu(mut cpu: Box<Device>, sender: SyncSender<Vec<u8>>, receiver: Receiver<GBEvent>) {
+// The boot ROM files for each model
+struct BootRoms<'a> {
+    dmg: Option<&'a str>,
+    cgb: Option<&'a str>,
+}
+
+// The options to start the machine with, in the boot ROM for its model when there is one
+fn load_options(classic: bool, skip_checksum: bool, boot_roms: &BootRoms) -> Option<LoadOptions> {
+    let read = |filename: Option<&str>| match filename {
+        Some(filename) => std::fs::read(filename).map(Some).map_err(|_| "Could not read the boot ROM"),
+        None => Ok(None),
+    };
+    let result = read(boot_roms.dmg).and_then(|dmg| read(boot_roms.cgb).map(|cgb| (dmg, cgb)));
+    match result {
+        Ok((boot_rom, cgb_boot_rom)) => Some(LoadOptions { classic, skip_checksum, boot_rom, cgb_boot_rom, ..LoadOptions::default() }),
+        Err(message) => { warn(message); None },
+    }
+}
+
+// The codes of a cheat file, with their line numbers
+fn parse_cheat_file(text: &str) -> Vec<(usize, &str)> {
+    text.lines().enumerate()
+        .map(|(i, line)| (i + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|(i, line)| line.split_whitespace().next().map(|code| (i, code)))
+        .collect()
+}
+
+// Codes of a GameShark type that is not supported are skipped
+fn add_cheat(cpu: &mut Device, code: &str, location: &str) -> bool {
+    match cpu.add_cheat(code) {
+        Ok(()) => true,
+        Err(e @ rboy::CheatError::UnsupportedGameSharkType(_)) => {
+            warn(&format!("Skipping cheat {}{}: {}", code, location, e));
+            true
+        },
+        Err(e) => {
+            warn(&format!("Invalid cheat {}{}: {}", code, location, e));
+            false
+        },
+    }
+}
+
+fn add_cheats(cpu: &mut Device, codes: &[&String], file: Option<&str>) -> bool {
+    for code in codes {
+        if !add_cheat(cpu, code, "") {
+            return false;
+        }
+    }
+    if let Some(file) = file {
+        let text = match std::fs::read_to_string(file) {
+            Ok(text) => text,
+            Err(e) => { warn(&format!("Could not read the cheat file {}: {}", file, e)); return false; },
+        };
+        for (line, code) in parse_cheat_file(&text) {
+            if !add_cheat(cpu, code, &format!(" on line {} of {}", line, file)) {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+// The file of a save state slot next to the ROM, e.g. game.ss1
+fn state_slot_path(filename: &str, slot: u8) -> PathBuf {
+    Path::new(filename).with_extension(format!("ss{}", slot))
+}
+
+// A dump of the memory next to the ROM, named after the time in milliseconds so that two dumps
+// can be compared, e.g. game.1700000000000.bus
+fn memory_dump_path(filename: &str, time: std::time::SystemTime) -> PathBuf {
+    let millis = time.duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_millis());
+    Path::new(filename).with_extension(format!("{}.bus", millis))
+}
+
+// A screenshot named after the game title and the time in milliseconds, e.g.
+// TETRIS.1700000000000.png, or TETRIS.1700000000000.raw.png for a kind of image
+fn screenshot_path(dir: &Path, title: &str, kind: Option<&str>, time: std::time::SystemTime) -> PathBuf {
+    let millis = time.duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_millis());
+    let mut name: String = title.chars().map(|c| if c.is_ascii_alphanumeric() || c == '-' { c } else { '_' }).collect();
+    if name.is_empty() {
+        name = "rboy".to_string();
+    }
+    match kind {
+        Some(kind) => dir.join(format!("{}.{}.{}.png", name, millis, kind)),
+        None => dir.join(format!("{}.{}.png", name, millis)),
+    }
+}
+
+// The width, height and pixels of a frame for the window
+type Frame = (usize, usize, Vec<u8>);
+
+// The frame to show, with a box around every sprite when sprite_boxes is on and the sprites are
+// not hidden
+fn screen_frame(cpu: &Device, sprite_boxes: bool) -> Frame {
+    let (width, height) = cpu.screen_size();
+    let mut frame = cpu.framebuffer().to_vec();
+    if sprite_boxes && cpu.layer_visible(rboy::Layer::Sprites) {
+        let origin = if cpu.is_sgb() { (rboy::SGB_GAME_X, rboy::SGB_GAME_Y) } else { (0, 0) };
+        draw_sprite_boxes(&mut frame, width, origin, &cpu.debug_sprites());
+    }
+    (width, height, frame)
+}
+
+fn layer_name(layer: rboy::Layer) -> &'static str {
+    match layer {
+        rboy::Layer::Background => "Background",
+        rboy::Layer::Window => "Window",
+        rboy::Layer::Sprites => "Sprites",
+    }
+}
+
+// Green around the sprites on the screen, red around those dropped from a line with more than 10.
+// The game screen starts at origin in a frame of width pixels.
+fn draw_sprite_boxes(frame: &mut [u8], width: usize, origin: (usize, usize), sprites: &[rboy::SpriteDebugInfo]) {
+    for sprite in sprites.iter().filter(|sprite| sprite.on_screen) {
+        let color = if sprite.selected { [0, 255, 0, 255] } else { [255, 0, 0, 255] };
+        let (left, top) = (sprite.x as i32 - 8, sprite.y as i32 - 16);
+        let (right, bottom) = (left + 7, top + sprite.height as i32 - 1);
+        for y in top ..= bottom {
+            for x in left ..= right {
+                let edge = x == left || x == right || y == top || y == bottom;
+                if edge && x >= 0 && x < rboy::SCREEN_W as i32 && y >= 0 && y < rboy::SCREEN_H as i32 {
+                    let i = ((origin.1 + y as usize) * width + origin.0 + x as usize) * 4;
+                    frame[i .. i + 4].copy_from_slice(&color);
+                }
+            }
+        }
+    }
+}
+
+// Encodes and writes the images on another thread, so the game does not stall, and tells the user
+// when they are saved
+fn save_pngs(images: Vec<(PathBuf, usize, usize, rboy::PngColor, Vec<u8>)>, what: &'static str, messages: Sender<String>) {
+    thread::spawn(move || {
+        let mut paths = Vec::new();
+        for (path, width, height, color, pixels) in images {
+            if let Err(e) = std::fs::write(&path, rboy::encode_png(width, height, color, &pixels)) {
+                let message = format!("Could not save {}: {}", what, e);
+                warn(&message);
+                let _ = messages.send(message);
+                return;
+            }
+            paths.push(path.display().to_string());
+        }
+        let message = format!("Saved {} to {}", what, paths.join(", "));
+        warn(&message);
+        let _ = messages.send(message);
+    });
+}
+
+// A failed load leaves the game running as it was
+fn load_state_slot(cpu: &mut Device, filename: &str, slot: u8) -> String {
+    match cpu.load_state_from(state_slot_path(filename, slot)) {
+        Ok(()) => format!("Loaded state {}", slot),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => format!("No state in slot {}", slot),
+        Err(e) => format!("Could not load state {}: {}", slot, e),
+    }
+}
+
+// Returns the device when the window is closed. Messages for the user are sent to messages.
+fn run_cpu(mut cpu: Box<Device>, filename: &str, rewind_options: Option<rboy::RewindOptions>, sender: SyncSender<Frame>, receiver: Receiver<GBEvent>, messages: Sender<String>) -> Box<Device> {
     let periodic = timer_periodic(16);
     let mut limit_speed = true;
+    let mut skip_video = false;
+    let mut seen_underruns = 0;
 
     let waitticks = (4194304f64 / 1000.0 * 16.0).round() as u32;
     let mut ticks = 0;
 
+    // While running unrestricted, the audio is told how fast the emulation actually runs
+    let mut speed_measure_start = std::time::Instant::now();
+    let mut speed_measure_frames = 0u32;
+    let mut lock_reported = false;
+    // A state is loaded at the end of a frame, with the frame and audio buffers complete. When no
+    // frame ends during the next slice, as while the LCD is off, it is loaded at the end of the slice.
+    let mut pending_load = None;
+    let notify = |message: String| {
+        warn(&message);
+        let _ = messages.send(message);
+    };
+    // While rewinding, a snapshot is popped and shown every slice instead of running the game
+    let mut budget = rboy::SnapshotBudget::with_default_cap();
+    let mut rewind = rewind_options.map(|options| rboy::Rewind::new(options, &mut budget));
+    let mut rewinding = false;
+    let mut sprite_boxes = false;
+
     'outer: loop {
-        while ticks < waitticks {
+        if rewinding {
+            if let Some(ref mut rewind) = rewind {
+                if rewind.rewind(&mut cpu, &mut budget) && cpu.check_and_reset_gpu_updated() {
+                    let data = screen_frame(&cpu, sprite_boxes);
+                    if let Err(TrySendError::Disconnected(..)) = sender.try_send(data) {
+                        break 'outer;
+                    }
+                }
+            }
+        }
+        while ticks < waitticks && !rewinding {
             ticks += cpu.do_cycle();
+            if let Some(report) = cpu.take_trap() {
+                warn(&format!("{}Emulation paused, press P to continue", report));
+            }
+            if let (Some(pc), false) = (cpu.cpu_locked(), lock_reported) {
+                warn(&format!("CPU locked at ${:04X} by illegal opcode ${:02X}, press Backspace to reset", pc, cpu.read_memory(pc)));
+                lock_reported = true;
+            }
             if cpu.check_and_reset_gpu_updated() {
-                let data = cpu.get_gpu_data().to_vec();
-                if let Err(TrySendError::Disconnected(..)) = sender.try_send(data) {
-                    break 'outer;
+                if !skip_video {
+                    let data = screen_frame(&cpu, sprite_boxes);
+                    if let Err(TrySendError::Disconnected(..)) = sender.try_send(data) {
+                        break 'outer;
+                    }
+                }
+                if let Some(ref mut rewind) = rewind {
+                    rewind.frame(&cpu, &mut budget);
+                    for event in budget.take_events() {
+                        if let rboy::BudgetEvent::RewindIntervalIncreased(frames) = event {
+                            notify(format!("Rewinding is short of memory, keeping a state every {} frames", frames));
+                        }
+                    }
+                }
+                if let Some(slot) = pending_load.take() {
+                    notify(load_state_slot(&mut cpu, filename, slot));
+                    lock_reported = false;
                 }
             }
         }
+        if let Some(slot) = pending_load.take() {
+            notify(load_state_slot(&mut cpu, filename, slot));
+            lock_reported = false;
+        }
 
-        ticks -= waitticks;
+        ticks = ticks.saturating_sub(waitticks);
 
         'recv: loop {
             match receiver.try_recv() {
@@ -339,8 +1121,109 @@ fn run_cpu(mut cpu: Box<Device>, sender: SyncSender<Vec<u8>>, receiver: Receiver
                     match event {
                         GBEvent::KeyUp(key) => cpu.keyup(key),
                         GBEvent::KeyDown(key) => cpu.keydown(key),
-                        GBEvent::SpeedUp => limit_speed = false,
-                        GBEvent::SpeedDown => { limit_speed = true; cpu.sync_audio(); }
+                        GBEvent::SpeedUp => {
+                            limit_speed = false;
+                            speed_measure_start = std::time::Instant::now();
+                            speed_measure_frames = 0;
+                        },
+                        GBEvent::SpeedDown => {
+                            limit_speed = true;
+                            cpu.set_audio_speed_multiplier(1.0);
+                            cpu.sync_audio();
+                        },
+                        GBEvent::Resume => cpu.resume(),
+                        GBEvent::Tilt(x, y) => cpu.set_accelerometer(x, y),
+                        GBEvent::DumpApu => match cpu.audio_debug_state() {
+                            Some(state) => eprint!("{}", state),
+                            None => warn("Audio is not enabled"),
+                        },
+                        GBEvent::DumpMemory => {
+                            let path = memory_dump_path(filename, std::time::SystemTime::now());
+                            match std::fs::write(&path, cpu.dump_memory(rboy::MemoryRegion::FullBus)) {
+                                Ok(()) => notify(format!("Wrote the memory to {}", path.display())),
+                                Err(e) => notify(format!("Could not write the memory: {}", e)),
+                            }
+                        },
+                        GBEvent::CycleDmgPalette => {
+                            let (name, palette) = next_dmg_palette(cpu.dmg_palette());
+                            cpu.set_dmg_palette(palette);
+                            notify(format!("DMG palette: {}", name));
+                        },
+                        GBEvent::CycleColorCorrection => {
+                            let (name, correction) = next_color_correction(cpu.color_correction());
+                            cpu.set_color_correction(correction);
+                            notify(format!("Color correction: {}", name));
+                        },
+                        GBEvent::ToggleFrameBlend(percent) => {
+                            let percent = if cpu.frame_blend() == 0 { percent } else { 0 };
+                            cpu.set_frame_blend(percent);
+                            notify(match percent {
+                                0 => "Frame blending off".to_string(),
+                                _ => format!("Frame blending {}%", percent),
+                            });
+                        },
+                        GBEvent::Screenshot(path, raw) => {
+                            let ((width, height), color, pixels) = if raw {
+                                ((rboy::SCREEN_W, rboy::SCREEN_H), rboy::PngColor::Gray, cpu.screenshot_indices().iter().map(|&i| 255 - i * 85).collect())
+                            } else {
+                                (cpu.screen_size(), rboy::PngColor::Rgba, cpu.screenshot())
+                            };
+                            save_pngs(vec![(path, width, height, color, pixels)], "a screenshot", messages.clone());
+                        },
+                        GBEvent::ToggleSpriteBoxes => {
+                            sprite_boxes = !sprite_boxes;
+                            notify(format!("Sprite boxes {}", if sprite_boxes { "on" } else { "off" }));
+                        },
+                        GBEvent::ToggleLayer(layer) => {
+                            let visible = !cpu.layer_visible(layer);
+                            cpu.set_layer_visible(layer, visible);
+                            notify(format!("{} {}", layer_name(layer), if visible { "shown" } else { "hidden" }));
+                        },
+                        GBEvent::DumpVram(dir) => {
+                            let time = std::time::SystemTime::now();
+                            let title = cpu.rom_info().title.clone();
+                            let tile_data_select = cpu.read_range(0xFF40, 1)[0] & 0x10 != 0;
+                            let mut images = Vec::new();
+                            for bank in 0 .. if cpu.is_classic() { 1 } else { 2 } {
+                                let path = screenshot_path(&dir, &title, Some(&format!("tiles{}", bank)), time);
+                                images.push((path, rboy::TILE_SHEET_W, rboy::TILE_SHEET_H, rboy::PngColor::Rgba, cpu.render_tiles(bank)));
+                            }
+                            for &(map, map_select) in [("9800", false), ("9C00", true)].iter() {
+                                let path = screenshot_path(&dir, &title, Some(&format!("map{}", map)), time);
+                                images.push((path, rboy::BG_MAP_SIZE, rboy::BG_MAP_SIZE, rboy::PngColor::Rgba, cpu.render_bg_map(map_select, tile_data_select)));
+                            }
+                            save_pngs(images, "the VRAM", messages.clone());
+                        },
+                        GBEvent::AudioPlayer(player) => cpu.set_audio_player(player),
+                        GBEvent::SaveState(slot) => match cpu.save_state_to(state_slot_path(filename, slot)) {
+                            Ok(()) => notify(format!("Saved state {}", slot)),
+                            Err(e) => notify(format!("Could not save state {}: {}", slot, e)),
+                        },
+                        // Nothing runs while paused by a trap, so there is no frame to wait for
+                        GBEvent::LoadState(slot) if cpu.is_paused() => {
+                            notify(load_state_slot(&mut cpu, filename, slot));
+                            lock_reported = false;
+                        },
+                        GBEvent::LoadState(slot) => pending_load = Some(slot),
+                        // The game goes on from the last snapshot that was shown
+                        GBEvent::Rewind(false) if rewinding => {
+                            rewinding = false;
+                            lock_reported = false;
+                            cpu.sync_audio();
+                        },
+                        GBEvent::Rewind(start) => rewinding = start && rewind.is_some(),
+                        GBEvent::Reset => match cpu.reset() {
+                            Ok(()) => lock_reported = false,
+                            Err(message) => warn(message),
+                        },
+                        GBEvent::SwitchModel => {
+                            lock_reported = false;
+                            let classic = !cpu.is_classic();
+                            match cpu.switch_model(classic) {
+                                Ok(()) => warn(if classic { "Switched to classic Gameboy mode" } else { "Switched to Gameboy Color mode" }),
+                                Err(message) => warn(message),
+                            }
+                        },
                     }
                 },
                 Err(TryRecvError::Empty) => break 'recv,
@@ -348,8 +1231,52 @@ fn run_cpu(mut cpu: Box<Device>, sender: SyncSender<Vec<u8>>, receiver: Receiver
             }
         }
 
-        if limit_speed { let _ = periodic.recv(); }
+        if limit_speed {
+            // After the audio ran dry, run the next slice without waiting and without video to catch up
+            let underruns = cpu.audio_underrun_count().unwrap_or(0);
+            skip_video = underruns != seen_underruns && !cpu.is_paused() && !rewinding;
+            seen_underruns = underruns;
+            if !skip_video { let _ = periodic.recv(); }
+        }
+        else {
+            skip_video = false;
+            speed_measure_frames += 1;
+            let elapsed = speed_measure_start.elapsed();
+            if elapsed >= SPEED_MEASURE_INTERVAL {
+                let emulated = std::time::Duration::from_millis(16) * speed_measure_frames;
+                cpu.set_audio_speed_multiplier(emulated.as_secs_f32() / elapsed.as_secs_f32());
+                speed_measure_start = std::time::Instant::now();
+                speed_measure_frames = 0;
+            }
+        }
     }
+    cpu
+}
+
+fn audio_peak_printer(sample_rate: u32, clip_stats: Arc<rboy::ClipStats>, dropped: Arc<AtomicU64>, underruns: Arc<AtomicU64>) -> rboy::SampleTap {
+    let mut peaks = [0u16; 4];
+    let mut samples = 0;
+    let mut reported_underruns = underruns.load(Ordering::Relaxed);
+
+    Box::new(move |channel: rboy::ChannelId, buf: &[i16]| {
+        let peak = buf.iter().map(|v| v.unsigned_abs()).max().unwrap_or(0);
+        let idx = channel as usize;
+        peaks[idx] = std::cmp::max(peaks[idx], peak);
+
+        if channel == rboy::ChannelId::Noise {
+            samples += buf.len() as u32;
+            if samples >= sample_rate {
+                let (clipped_left, clipped_right) = clip_stats.take();
+                let dropped_samples = dropped.swap(0, Ordering::Relaxed);
+                let total_underruns = underruns.load(Ordering::Relaxed);
+                println!("Audio peaks: square1 {:5} square2 {:5} wave {:5} noise {:5}, clipped samples: left {:5} right {:5}, dropped samples: {:5}, underruns: {:3}",
+                    peaks[0], peaks[1], peaks[2], peaks[3], clipped_left, clipped_right, dropped_samples, total_underruns - reported_underruns);
+                reported_underruns = total_underruns;
+                peaks = [0; 4];
+                samples = 0;
+            }
+        }
+    })
 }
 
 fn timer_periodic(ms: u64) -> Receiver<()> {
@@ -365,108 +1292,239 @@ fn timer_periodic(ms: u64) -> Receiver<()> {
     rx
 }
 
-fn set_window_size(window: &winit::window::Window, scale: u32) {
+fn set_window_size(window: &winit::window::Window, (width, height): (usize, usize), scale: u32) {
     let _ = window.request_inner_size(winit::dpi::LogicalSize::<u32>::from((
-            rboy::SCREEN_W as u32 * scale,
-            rboy::SCREEN_H as u32 * scale,
+            width as u32 * scale,
+            height as u32 * scale,
         )));
 }
 
+// Returns the id and name of every audio output device
+fn audio_output_devices() -> Vec<(usize, String)> {
+    let devices = match cpal::default_host().output_devices() {
+        Ok(devices) => devices,
+        Err(_) => return Vec::new(),
+    };
+    devices.enumerate()
+        .map(|(id, device)| (id, device.name().unwrap_or_else(|_| "<unknown>".to_owned())))
+        .collect()
+}
+
+fn find_audio_device(name: Option<&str>) -> Option<cpal::Device> {
+    let host = cpal::default_host();
+    if let Some(name) = name {
+        if let Ok(mut devices) = host.output_devices() {
+            if let Some(device) = devices.find(|d| d.name().is_ok_and(|n| n == name)) {
+                return Some(device);
+            }
+        }
+        warn(&format!("Audio device '{}' not found, using the default device", name));
+    }
+    host.default_output_device()
+}
+
+// Picks a stereo config, preferring f32 over i16 over anything else,
+// and 44.1 kHz over 48 kHz over the rate closest to 44.1 kHz.
+fn select_audio_config(device: &cpal::Device) -> Option<cpal::SupportedStreamConfig> {
+    let preferred_rates = [cpal::SampleRate(44100), cpal::SampleRate(48000)];
+
+    let supported_configs = device.supported_output_configs().ok()?;
+    supported_configs
+        .filter(|f| f.channels() == 2)
+        .map(|f| {
+            let format_rank = match f.sample_format() {
+                cpal::SampleFormat::F32 => 0,
+                cpal::SampleFormat::I16 => 1,
+                _ => 2,
+            };
+            let (rate_rank, rate) = match preferred_rates.iter().position(|&r| f.min_sample_rate() <= r && r <= f.max_sample_rate()) {
+                Some(idx) => (idx as u32, preferred_rates[idx]),
+                None => {
+                    let rate = preferred_rates[0].0.clamp(f.min_sample_rate().0, f.max_sample_rate().0);
+                    (preferred_rates.len() as u32 + rate.abs_diff(preferred_rates[0].0), cpal::SampleRate(rate))
+                },
+            };
+            ((format_rank, rate_rank), f.with_sample_rate(rate))
+        })
+        .min_by_key(|&(rank, _)| rank)
+        .map(|(_, config)| config)
+}
+
 struct CpalPlayer {
-    buffer: Arc<Mutex<Vec<(f32, f32)>>>,
+    buffer: Arc<Mutex<VecDeque<(f32, f32)>>>,
+    drop_policy: AudioDropPolicy,
+    dropped: Arc<AtomicU64>,
+    // Counted by the stream, each time it runs dry after it had samples
+    underruns: Arc<AtomicU64>,
     sample_rate: u32,
+    // None when the device did not take a fixed size and uses its own, which cpal does not tell
+    buffer_frames: Option<u32>,
+    device_name: String,
+    sample_format: cpal::SampleFormat,
+    // Set by the stream when the device fails, after which the samples are dropped
+    lost: Arc<AtomicBool>,
 }
 
 impl CpalPlayer {
-    fn get() -> Option<(CpalPlayer, cpal::Stream)> {
-        let device = match cpal::default_host().default_output_device() {
-            Some(e) => e,
-            None => return None,
-        };
-
-        // We want a config with:
-        // chanels = 2
-        // SampleFormat F32
-        // Rate at around 44100
-
-        let wanted_samplerate = cpal::SampleRate(44100);
-        let supported_configs = match device.supported_output_configs() {
-            Ok(e) => e,
-            Err(_) => return None,
-        };
-        let mut supported_config = None;
-        for f in supported_configs {
-            if f.channels() == 2 && f.sample_format() == cpal::SampleFormat::F32 {
-                if f.min_sample_rate() <= wanted_samplerate && wanted_samplerate <= f.max_sample_rate() {
-                    supported_config = Some(f.with_sample_rate(wanted_samplerate));
-                }
-                else {
-                    supported_config = Some(f.with_max_sample_rate());
-                }
-                break;
-            }
-        }
-        if supported_config.is_none() {
-            return None;
-        }
+    fn get(device_name: Option<&str>, latency_ms: u32, drop_policy: AudioDropPolicy, dropped: Arc<AtomicU64>, underruns: Arc<AtomicU64>) -> Option<(CpalPlayer, cpal::Stream)> {
+        let device = find_audio_device(device_name)?;
+        let selected_config = select_audio_config(&device)?;
 
-        let selected_config = supported_config.unwrap();
+        let buffer_frames = fixed_buffer_frames(selected_config.sample_rate().0, latency_ms, selected_config.buffer_size());
 
         let sample_format = selected_config.sample_format();
-        let config : cpal::StreamConfig = selected_config.into();
-
-        let err_fn = |err| eprintln!("An error occurred on the output audio stream: {}", err);
+        let mut config : cpal::StreamConfig = selected_config.into();
+        config.buffer_size = cpal::BufferSize::Fixed(buffer_frames);
 
-        let shared_buffer = Arc::new(Mutex::new(Vec::new()));
-        let stream_buffer = shared_buffer.clone();
+        let shared_buffer = Arc::new(Mutex::new(VecDeque::new()));
 
-        let player = CpalPlayer {
-            buffer: shared_buffer,
+        let mut player = CpalPlayer {
+            buffer: shared_buffer.clone(),
+            drop_policy,
+            dropped,
+            underruns,
             sample_rate: config.sample_rate.0,
+            buffer_frames: Some(buffer_frames),
+            device_name: device.name().unwrap_or_else(|_| "<unknown>".to_owned()),
+            sample_format,
+            lost: Arc::new(AtomicBool::new(false)),
         };
 
-        let stream = match sample_format {
-            cpal::SampleFormat::I8 => device.build_output_stream(&config, move|data: &mut [i8], _callback_info: &cpal::OutputCallbackInfo| cpal_thread(data, &stream_buffer), err_fn, None),
-            cpal::SampleFormat::I16 => device.build_output_stream(&config, move|data: &mut [i16], _callback_info: &cpal::OutputCallbackInfo| cpal_thread(data, &stream_buffer), err_fn, None),
-            cpal::SampleFormat::I32 => device.build_output_stream(&config, move|data: &mut [i32], _callback_info: &cpal::OutputCallbackInfo| cpal_thread(data, &stream_buffer), err_fn, None),
-            cpal::SampleFormat::I64 => device.build_output_stream(&config, move|data: &mut [i64], _callback_info: &cpal::OutputCallbackInfo| cpal_thread(data, &stream_buffer), err_fn, None),
-            cpal::SampleFormat::U8 => device.build_output_stream(&config, move|data: &mut [u8], _callback_info: &cpal::OutputCallbackInfo| cpal_thread(data, &stream_buffer), err_fn, None),
-            cpal::SampleFormat::U16 => device.build_output_stream(&config, move|data: &mut [u16], _callback_info: &cpal::OutputCallbackInfo| cpal_thread(data, &stream_buffer), err_fn, None),
-            cpal::SampleFormat::U32 => device.build_output_stream(&config, move|data: &mut [u32], _callback_info: &cpal::OutputCallbackInfo| cpal_thread(data, &stream_buffer), err_fn, None),
-            cpal::SampleFormat::U64 => device.build_output_stream(&config, move|data: &mut [u64], _callback_info: &cpal::OutputCallbackInfo| cpal_thread(data, &stream_buffer), err_fn, None),
-            cpal::SampleFormat::F32 => device.build_output_stream(&config, move|data: &mut [f32], _callback_info: &cpal::OutputCallbackInfo| cpal_thread(data, &stream_buffer), err_fn, None),
-            cpal::SampleFormat::F64 => device.build_output_stream(&config, move|data: &mut [f64], _callback_info: &cpal::OutputCallbackInfo| cpal_thread(data, &stream_buffer), err_fn, None),
-            sf => panic!("Unsupported sample format {}", sf),
-        }.unwrap();
+        let stream = match build_cpal_stream(&device, &config, sample_format, &shared_buffer, &player.lost, &player.underruns) {
+            Ok(stream) => stream,
+            Err(_) => {
+                // Not every backend accepts a fixed buffer size, fall back to the default one
+                config.buffer_size = cpal::BufferSize::Default;
+                player.buffer_frames = None;
+                match build_cpal_stream(&device, &config, sample_format, &shared_buffer, &player.lost, &player.underruns) {
+                    Ok(stream) => stream,
+                    Err(_) => return None,
+                }
+            },
+        };
 
         stream.play().unwrap();
 
         Some((player, stream))
     }
+
+    fn latency_ms(&self) -> Option<u32> {
+        self.buffer_frames.map(|frames| frames * 1000 / self.sample_rate)
+    }
+
+    fn description(&self) -> String {
+        let latency = match self.latency_ms() {
+            Some(ms) => format!("{} ms buffer", ms),
+            None => "default buffer".to_owned(),
+        };
+        format!("{} ({} Hz, {}, {})", self.device_name, self.sample_rate, self.sample_format, latency)
+    }
 }
 
-fn cpal_thread<T: Sample + FromSample<f32>>(outbuffer: &mut[T], audio_buffer: &Arc<Mutex<Vec<(f32, f32)>>>) {
+// The requested latency in frames, clamped to the buffer sizes the device supports
+fn fixed_buffer_frames(sample_rate: u32, latency_ms: u32, supported: &cpal::SupportedBufferSize) -> u32 {
+    let wanted_frames = sample_rate * latency_ms / 1000;
+    let frames = match *supported {
+        cpal::SupportedBufferSize::Range { min, max } => wanted_frames.clamp(min, max),
+        cpal::SupportedBufferSize::Unknown => wanted_frames,
+    };
+    frames.max(1)
+}
+
+fn build_cpal_stream(device: &cpal::Device, config: &cpal::StreamConfig, sample_format: cpal::SampleFormat, shared_buffer: &Arc<Mutex<VecDeque<(f32, f32)>>>, lost: &Arc<AtomicBool>, underruns: &Arc<AtomicU64>) -> Result<cpal::Stream, cpal::BuildStreamError> {
+    let stream_lost = lost.clone();
+    let err_fn = move |err: cpal::StreamError| {
+        if !stream_lost.swap(true, Ordering::Relaxed) {
+            eprintln!("An error occurred on the output audio stream: {}", err);
+        }
+    };
+    let stream_buffer = shared_buffer.clone();
+    let mut state = CallbackState { last: (0.0, 0.0), starved: true, underruns: underruns.clone() };
+
+    match sample_format {
+        cpal::SampleFormat::I8 => device.build_output_stream(config, move|data: &mut [i8], _callback_info: &cpal::OutputCallbackInfo| cpal_thread(data, &stream_buffer, &mut state), err_fn, None),
+        cpal::SampleFormat::I16 => device.build_output_stream(config, move|data: &mut [i16], _callback_info: &cpal::OutputCallbackInfo| cpal_thread(data, &stream_buffer, &mut state), err_fn, None),
+        cpal::SampleFormat::I32 => device.build_output_stream(config, move|data: &mut [i32], _callback_info: &cpal::OutputCallbackInfo| cpal_thread(data, &stream_buffer, &mut state), err_fn, None),
+        cpal::SampleFormat::I64 => device.build_output_stream(config, move|data: &mut [i64], _callback_info: &cpal::OutputCallbackInfo| cpal_thread(data, &stream_buffer, &mut state), err_fn, None),
+        cpal::SampleFormat::U8 => device.build_output_stream(config, move|data: &mut [u8], _callback_info: &cpal::OutputCallbackInfo| cpal_thread(data, &stream_buffer, &mut state), err_fn, None),
+        cpal::SampleFormat::U16 => device.build_output_stream(config, move|data: &mut [u16], _callback_info: &cpal::OutputCallbackInfo| cpal_thread(data, &stream_buffer, &mut state), err_fn, None),
+        cpal::SampleFormat::U32 => device.build_output_stream(config, move|data: &mut [u32], _callback_info: &cpal::OutputCallbackInfo| cpal_thread(data, &stream_buffer, &mut state), err_fn, None),
+        cpal::SampleFormat::U64 => device.build_output_stream(config, move|data: &mut [u64], _callback_info: &cpal::OutputCallbackInfo| cpal_thread(data, &stream_buffer, &mut state), err_fn, None),
+        cpal::SampleFormat::F32 => device.build_output_stream(config, move|data: &mut [f32], _callback_info: &cpal::OutputCallbackInfo| cpal_thread(data, &stream_buffer, &mut state), err_fn, None),
+        cpal::SampleFormat::F64 => device.build_output_stream(config, move|data: &mut [f64], _callback_info: &cpal::OutputCallbackInfo| cpal_thread(data, &stream_buffer, &mut state), err_fn, None),
+        sf => panic!("Unsupported sample format {}", sf),
+    }
+}
+
+struct CallbackState {
+    // The last sample that was played
+    last: (f32, f32),
+    starved: bool,
+    underruns: Arc<AtomicU64>,
+}
+
+fn cpal_thread<T: Sample + FromSample<f32>>(outbuffer: &mut[T], audio_buffer: &Arc<Mutex<VecDeque<(f32, f32)>>>, state: &mut CallbackState) {
     let mut inbuffer = audio_buffer.lock().unwrap();
     let outlen =  ::std::cmp::min(outbuffer.len() / 2, inbuffer.len());
     for (i, (in_l, in_r)) in inbuffer.drain(..outlen).enumerate() {
-        outbuffer[i*2] = T::from_sample(in_l);
-        outbuffer[i*2+1] = T::from_sample(in_r);
+        outbuffer[i*2] = output_sample(in_l);
+        outbuffer[i*2+1] = output_sample(in_r);
+        state.last = (in_l, in_r);
+    }
+    drop(inbuffer);
+
+    // On an underrun, fade the last sample out instead of leaving stale data in the device buffer
+    let starved = outlen < outbuffer.len() / 2;
+    if starved {
+        if !state.starved {
+            state.underruns.fetch_add(1, Ordering::Relaxed);
+        }
+        let (last_l, last_r) = state.last;
+        for (i, frame) in outbuffer[outlen * 2 ..].chunks_mut(2).enumerate() {
+            let factor = 1.0 - (i as f32 / UNDERRUN_FADE_FRAMES as f32).min(1.0);
+            for (v, last) in frame.iter_mut().zip([last_l, last_r]) {
+                *v = output_sample(last * factor);
+            }
+        }
+        state.last = (0.0, 0.0);
     }
+    state.starved = starved;
+}
+
+// Converts to the sample format of the device. Unsigned formats are centered on their midpoint.
+// The input is clamped first, so out of range samples saturate instead of wrapping around.
+fn output_sample<T: Sample + FromSample<f32>>(v: f32) -> T {
+    T::from_sample(v.clamp(-1.0, 1.0))
 }
 
 impl rboy::AudioPlayer for CpalPlayer {
     fn play(&mut self, buf_left: &[f32], buf_right: &[f32]) {
         debug_assert!(buf_left.len() == buf_right.len());
 
+        if self.lost.load(Ordering::Relaxed) {
+            return;
+        }
+
         let mut buffer = self.buffer.lock().unwrap();
 
+        // Do not buffer much more than the requested latency.
+        // This speeds up the resync after the turning on and off the speed limiter
+        let max_buffered = std::cmp::max(self.buffer_frames.unwrap_or(0) as usize * 4, self.sample_rate as usize / 10);
+
+        // Never wait for the device, drop samples instead
+        let mut dropped = 0;
         for (l, r) in buf_left.iter().zip(buf_right) {
-            if buffer.len() > self.sample_rate as usize {
-                // Do not fill the buffer with more than 1 second of data
-                // This speeds up the resync after the turning on and off the speed limiter
-                return
+            if buffer.len() > max_buffered {
+                dropped += 1;
+                match self.drop_policy {
+                    AudioDropPolicy::Newest => continue,
+                    AudioDropPolicy::Oldest => { buffer.pop_front(); },
+                }
             }
-            buffer.push((*l, *r));
+            buffer.push_back((*l, *r));
+        }
+        if dropped > 0 {
+            self.dropped.fetch_add(dropped, Ordering::Relaxed);
         }
     }
 
@@ -475,7 +1533,11 @@ impl rboy::AudioPlayer for CpalPlayer {
     }
 
     fn underflowed(&self) -> bool {
-        (*self.buffer.lock().unwrap()).len() == 0
+        !self.lost.load(Ordering::Relaxed) && (*self.buffer.lock().unwrap()).len() == 0
+    }
+
+    fn underruns(&self) -> u64 {
+        self.underruns.load(Ordering::Relaxed)
     }
 }
 
@@ -495,18 +1557,22 @@ impl rboy::AudioPlayer for NullAudioPlayer {
     }
 }
 
-fn run_test_mode(filename: &str, classic_mode: bool, skip_checksum: bool) -> i32 {
+fn run_test_mode(filename: &str, classic_mode: bool, skip_checksum: bool, trace: Option<&Trace>) -> i32 {
     let opt_cpu = match classic_mode {
         true => Device::new(filename, skip_checksum),
         false => Device::new_cgb(filename, skip_checksum),
     };
     let mut cpu = match opt_cpu {
-        Err(errmsg) => { warn(errmsg); return EXITCODE_CPULOADFAILS; },
+        Err(e) => { warn(e.message()); return EXITCODE_CPULOADFAILS; },
         Ok(cpu) => cpu,
     };
 
     cpu.set_stdout(true);
+    cpu.set_traps(rboy::TrapOptions::disabled());
     cpu.enable_audio(Box::new(NullAudioPlayer {}));
+    if let Some(trace) = trace {
+        if !start_trace(&mut cpu, trace) { return EXITCODE_CPULOADFAILS; }
+    }
 
     // from masonforest, https://stackoverflow.com/a/55201400 (CC BY-SA 4.0)
     let stdin_channel = spawn_stdin_channel();
@@ -519,6 +1585,9 @@ fn run_test_mode(filename: &str, classic_mode: bool, skip_checksum: bool) -> i32
                         let data = cpu.get_gpu_data().to_vec();
                         print_screenshot(data);
                     },
+                    b'h' => {
+                        print_frame_hash(cpu.frame_hash(), &cpu.scanline_crcs());
+                    },
                     v => {
                         eprintln!("MSG:Unknown stdinvalue {}", v);
                     },
@@ -534,6 +1603,176 @@ fn run_test_mode(filename: &str, classic_mode: bool, skip_checksum: bool) -> i32
     EXITCODE_SUCCESS
 }
 
+// The trace file, the number of instructions to log and whether to add the disassembly
+struct Trace<'a> {
+    file: &'a str,
+    limit: Option<u64>,
+    disasm: bool,
+}
+
+// The trace is flushed when the device is dropped
+fn start_trace(cpu: &mut Device, trace: &Trace) -> bool {
+    match std::fs::File::create(trace.file) {
+        Ok(file) => {
+            cpu.start_trace(Box::new(file), trace.limit, true, trace.disasm);
+            true
+        },
+        Err(e) => {
+            warn(&format!("Could not create trace file {}: {}", trace.file, e));
+            false
+        },
+    }
+}
+
+fn write_profile(cpu: &Device, filename: &str) -> io::Result<()> {
+    use std::io::Write;
+
+    let mut out = io::BufWriter::new(std::fs::File::create(filename)?);
+    writeln!(out, "bank,pc,instructions,cycles")?;
+    for entry in cpu.profile_report(usize::MAX) {
+        writeln!(out, "{:02X},{:04X},{},{}", entry.bank, entry.pc, entry.instructions, entry.cycles)?;
+    }
+    out.flush()
+}
+
+fn run_disasm(filename: &str, start: usize, end: usize) -> i32 {
+    let data = match std::fs::read(filename) {
+        Err(e) => { warn(&format!("Could not read {}: {}", filename, e)); return EXITCODE_CPULOADFAILS; },
+        Ok(data) => data,
+    };
+
+    let end = end.min(data.len());
+    let mut offset = start;
+    while offset < end {
+        // Banks after the first are mapped at 4000
+        let pc = match offset {
+            0 ..= 0x3FFF => offset as u16,
+            _ => 0x4000 | (offset & 0x3FFF) as u16,
+        };
+        let (text, len) = rboy::disassemble(&data[offset .. end], pc);
+        let bytes: Vec<String> = data[offset .. offset + len as usize].iter().map(|b| format!("{:02X}", b)).collect();
+        println!("{:06X}  {:04X}  {:<8}  {}", offset, pc, bytes.join(" "), text);
+        offset += len as usize;
+    }
+    EXITCODE_SUCCESS
+}
+
+fn run_debug_mode(filename: &str, classic_mode: bool, skip_checksum: bool, boot_roms: &BootRoms) -> i32 {
+    let options = match load_options(classic_mode, skip_checksum, boot_roms) {
+        Some(options) => options,
+        None => return EXITCODE_CPULOADFAILS,
+    };
+    let mut cpu = match Device::new_from_file(filename, options) {
+        Err(e) => { warn(e.message()); return EXITCODE_CPULOADFAILS; },
+        Ok(cpu) => cpu,
+    };
+
+    print_debug_location(&mut cpu);
+    let mut follow_interrupts = false;
+    let mut lines = io::stdin().lines();
+    loop {
+        print!("> ");
+        let _ = io::Write::flush(&mut io::stdout());
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            _ => break,
+        };
+        if line.trim().is_empty() { continue; }
+        let command = match parse_debug_command(&line) {
+            Ok(command) => command,
+            Err(message) => { println!("{}", message); continue; },
+        };
+        match command {
+            DebugCommand::Break(address) => cpu.add_breakpoint(address),
+            DebugCommand::DeleteBreak(address) => cpu.remove_breakpoint(address),
+            DebugCommand::Watch { address, on_read, on_write } => cpu.add_watchpoint(address, on_read, on_write),
+            DebugCommand::DeleteWatch(address) => cpu.remove_watchpoint(address),
+            DebugCommand::Step(count) => {
+                for _ in 0 .. count {
+                    cpu.step();
+                    // Stop stepping on a watchpoint or trap
+                    if let Some(stop) = cpu.take_debug_stop() {
+                        println!("{}", stop);
+                        break;
+                    }
+                    if let Some(report) = cpu.take_trap() {
+                        print!("{}", report);
+                        break;
+                    }
+                }
+                print_debug_location(&mut cpu);
+            },
+            DebugCommand::StepOver => {
+                cpu.step_over(follow_interrupts);
+                run_until_debug_stop(&mut cpu);
+            },
+            DebugCommand::StepOut => {
+                cpu.step_out(follow_interrupts);
+                run_until_debug_stop(&mut cpu);
+            },
+            DebugCommand::RunTo(address) => {
+                cpu.run_to(address);
+                run_until_debug_stop(&mut cpu);
+            },
+            DebugCommand::FollowInterrupts(follow) => follow_interrupts = follow,
+            DebugCommand::Continue => {
+                cpu.resume();
+                run_until_debug_stop(&mut cpu);
+            },
+            DebugCommand::Registers => print_debug_location(&mut cpu),
+            DebugCommand::Memory { address, len } => {
+                for row in (0 .. len as u32).step_by(16) {
+                    let start = address.wrapping_add(row as u16);
+                    let bytes: Vec<String> = (0 .. (len as u32 - row).min(16)).map(|i| format!("{:02X}", cpu.read_memory(start.wrapping_add(i as u16)))).collect();
+                    println!("{:04X}  {}", start, bytes.join(" "));
+                }
+            },
+            DebugCommand::Oam => {
+                let oam = cpu.dump_memory(rboy::MemoryRegion::Oam);
+                let selected = cpu.line_sprites();
+                println!("LY {:02X}, {} sprites on the line", cpu.read_range(0xFF44, 1)[0], selected.len());
+                println!("     Y  X  tile flags");
+                for (index, sprite) in oam.chunks(4).enumerate() {
+                    let mark = if selected.contains(&(index as u8)) { '*' } else { ' ' };
+                    println!("{}{:02}  {:02X} {:02X} {:02X}   {:02X}", mark, index, sprite[0], sprite[1], sprite[2], sprite[3]);
+                }
+            },
+            DebugCommand::Help => println!("{}", DEBUG_HELP),
+            DebugCommand::Quit => break,
+        }
+    }
+    EXITCODE_SUCCESS
+}
+
+// Runs until the CPU pauses or locks up, and prints why
+fn run_until_debug_stop(cpu: &mut Device) {
+    while !cpu.is_paused() && cpu.cpu_locked().is_none() {
+        cpu.do_cycle();
+    }
+    if let Some(stop) = cpu.take_debug_stop() {
+        println!("{}", stop);
+    }
+    if let Some(pc) = cpu.cpu_locked() {
+        println!("CPU locked at {:04X} by illegal opcode {:02X}", pc, cpu.read_memory(pc));
+    }
+    if let Some(report) = cpu.take_trap() {
+        print!("{}", report);
+    }
+    print_debug_location(cpu);
+}
+
+fn print_debug_location(cpu: &mut Device) {
+    let registers = cpu.registers();
+    let pc = registers.pc;
+    let bytes = [cpu.read_memory(pc), cpu.read_memory(pc.wrapping_add(1)), cpu.read_memory(pc.wrapping_add(2))];
+    println!("{}", registers);
+    let text = rboy::disassemble(&bytes, pc).0;
+    match rboy::instruction_cycles(&bytes) {
+        (cycles, taken) if cycles != taken => println!("{:04X}  {:<20}{}/{} cycles", pc, text, cycles, taken),
+        (cycles, _) => println!("{:04X}  {:<20}{} cycles", pc, text, cycles),
+    }
+}
+
 fn spawn_stdin_channel() -> Receiver<u8> {
     let (tx, rx) = mpsc::channel::<u8>();
     thread::spawn(move || loop {
@@ -547,6 +1786,16 @@ fn spawn_stdin_channel() -> Receiver<u8> {
     rx
 }
 
+// The scanline CRCs let the caller find the first line that differs from a reference
+fn print_frame_hash(hash: u64, crcs: &[u32]) {
+    eprintln!("HASH:{:016x}", hash);
+    eprint!("SCANLINES:");
+    for crc in crcs {
+        eprint!("{:08x}", crc);
+    };
+    eprintln!();
+}
+
 fn print_screenshot(data: Vec<u8>) {
     eprint!("SCREENSHOT:");
     for b in data {
@@ -554,3 +1803,169 @@ fn print_screenshot(data: Vec<u8>) {
     };
     eprintln!();
 }
+
+#[cfg(test)]
+mod test {
+    use super::{draw_sprite_boxes, fixed_buffer_frames, memory_dump_path, next_color_correction, next_dmg_palette, output_sample, parse_colorize, parse_cheat_file, parse_debug_command, parse_disasm_range, parse_dmg_palette, screenshot_path, state_slot_path, DebugCommand};
+
+    #[test]
+    fn buffer_frames_clamp() {
+        let range = |min, max| cpal::SupportedBufferSize::Range { min, max };
+        assert_eq!(fixed_buffer_frames(48000, 40, &range(64, 4096)), 1920);
+        assert_eq!(fixed_buffer_frames(48000, 1, &range(64, 4096)), 64);
+        assert_eq!(fixed_buffer_frames(48000, 200, &range(64, 4096)), 4096);
+        assert_eq!(fixed_buffer_frames(44100, 40, &cpal::SupportedBufferSize::Unknown), 1764);
+        assert_eq!(fixed_buffer_frames(44100, 0, &cpal::SupportedBufferSize::Unknown), 1);
+    }
+
+    #[test]
+    fn output_sample_formats() {
+        assert_eq!(output_sample::<u8>(1.0), u8::MAX);
+        assert_eq!(output_sample::<u8>(-1.0), 0);
+        assert_eq!(output_sample::<u8>(0.0), 128);
+        assert_eq!(output_sample::<i8>(1.0), i8::MAX);
+        assert_eq!(output_sample::<i8>(-1.0), i8::MIN);
+        assert_eq!(output_sample::<i8>(0.0), 0);
+        assert_eq!(output_sample::<u16>(1.0), u16::MAX);
+        assert_eq!(output_sample::<u16>(-1.0), 0);
+        assert_eq!(output_sample::<u16>(0.0), 32768);
+        assert_eq!(output_sample::<i16>(1.0), i16::MAX);
+        assert_eq!(output_sample::<i16>(-1.0), i16::MIN);
+        assert_eq!(output_sample::<i16>(0.0), 0);
+        assert_eq!(output_sample::<u32>(1.0), u32::MAX);
+        assert_eq!(output_sample::<u32>(-1.0), 0);
+        assert_eq!(output_sample::<u32>(0.0), 1 << 31);
+        assert_eq!(output_sample::<i32>(1.0), i32::MAX);
+        assert_eq!(output_sample::<i32>(-1.0), i32::MIN);
+        assert_eq!(output_sample::<i32>(0.0), 0);
+        assert_eq!(output_sample::<f32>(1.0), 1.0);
+        assert_eq!(output_sample::<f32>(-1.0), -1.0);
+        assert_eq!(output_sample::<f32>(0.0), 0.0);
+        assert_eq!(output_sample::<f64>(1.0), 1.0);
+        assert_eq!(output_sample::<f64>(-1.0), -1.0);
+        assert_eq!(output_sample::<f64>(0.0), 0.0);
+    }
+
+    #[test]
+    fn output_sample_clamps() {
+        assert_eq!(output_sample::<u16>(1.5), u16::MAX);
+        assert_eq!(output_sample::<i16>(-3.0), i16::MIN);
+        assert_eq!(output_sample::<u8>(2.0), u8::MAX);
+        assert_eq!(output_sample::<f32>(1.25), 1.0);
+    }
+
+    #[test]
+    fn disasm_range() {
+        assert_eq!(parse_disasm_range("0100:0150").unwrap(), (0x100, 0x150));
+        assert_eq!(parse_disasm_range("$4000:0x4100").unwrap(), (0x4000, 0x4100));
+        assert!(parse_disasm_range("0150:0100").is_err());
+        assert!(parse_disasm_range("0100").is_err());
+        assert!(parse_disasm_range("01G0:0150").is_err());
+    }
+
+    #[test]
+    fn state_slot_paths() {
+        assert_eq!(state_slot_path("roms/game.gb", 1), std::path::Path::new("roms/game.ss1"));
+        assert_eq!(state_slot_path("game.gbc", 4), std::path::Path::new("game.ss4"));
+        assert_eq!(state_slot_path("game", 2), std::path::Path::new("game.ss2"));
+    }
+
+    #[test]
+    fn colorize_names() {
+        assert_eq!(parse_colorize("auto").unwrap(), rboy::Colorization::Auto);
+        assert_eq!(parse_colorize("off").unwrap(), rboy::Colorization::Off);
+        assert_eq!(parse_colorize("brown").unwrap(), rboy::Colorization::Palette(5));
+        assert_eq!(parse_colorize("0x16").unwrap(), rboy::Colorization::Palette(0x16));
+        assert!(parse_colorize("purple").is_err());
+    }
+
+    #[test]
+    fn dmg_palettes() {
+        assert_eq!(parse_dmg_palette("green").unwrap(), rboy::DMG_PALETTES[1].1);
+        assert_eq!(parse_dmg_palette("FFFFFF,#aa5500, 808080,000000").unwrap(), [[255, 255, 255], [170, 85, 0], [128, 128, 128], [0, 0, 0]]);
+        assert!(parse_dmg_palette("purple").is_err());
+        assert!(parse_dmg_palette("FFFFFF,AAAAAA,555555").is_err());
+        assert!(parse_dmg_palette("FFFFFF,AAAAAA,555555,00000G").is_err());
+
+        assert_eq!(next_dmg_palette(rboy::DMG_PALETTES[0].1), rboy::DMG_PALETTES[1]);
+        assert_eq!(next_dmg_palette(rboy::DMG_PALETTES[3].1), rboy::DMG_PALETTES[0]);
+        assert_eq!(next_dmg_palette([[1; 3]; 4]), rboy::DMG_PALETTES[0]);
+    }
+
+    #[test]
+    fn color_corrections() {
+        assert_eq!(next_color_correction(rboy::ColorCorrection::Raw), ("cgb", rboy::ColorCorrection::CgbLcd));
+        assert_eq!(next_color_correction(rboy::ColorCorrection::GbaLcd), ("raw", rboy::ColorCorrection::Raw));
+    }
+
+    #[test]
+    fn memory_dump_paths() {
+        let time = std::time::UNIX_EPOCH + std::time::Duration::from_millis(1700000000123);
+        assert_eq!(memory_dump_path("roms/game.gb", time), std::path::Path::new("roms/game.1700000000123.bus"));
+        assert_eq!(memory_dump_path("game", time), std::path::Path::new("game.1700000000123.bus"));
+    }
+
+    #[test]
+    fn screenshot_paths() {
+        let time = std::time::UNIX_EPOCH + std::time::Duration::from_millis(1700000000123);
+        let dir = std::path::Path::new("shots");
+        assert_eq!(screenshot_path(dir, "TETRIS", None, time), dir.join("TETRIS.1700000000123.png"));
+        assert_eq!(screenshot_path(dir, "POKEMON RED", Some("raw"), time), dir.join("POKEMON_RED.1700000000123.raw.png"));
+        assert_eq!(screenshot_path(dir, "TETRIS", Some("map9C00"), time), dir.join("TETRIS.1700000000123.map9C00.png"));
+        assert_eq!(screenshot_path(dir, "", None, time), dir.join("rboy.1700000000123.png"));
+        assert_eq!(screenshot_path(dir, "A/B", None, time), dir.join("A_B.1700000000123.png"));
+    }
+
+    #[test]
+    fn sprite_boxes() {
+        let sprite = |index: u8, y: u8, x: u8, height: u8, on_screen: bool, selected: bool| rboy::SpriteDebugInfo {
+            index, y, x, tile: 0, flags: 0, behind_bg: false, y_flip: false, x_flip: false, dmg_palette: 0,
+            cgb_bank: 0, cgb_palette: 0, height, on_screen, selected, pixels: Vec::new(),
+        };
+        let mut frame = vec![0; rboy::SCREEN_W * rboy::SCREEN_H * 4];
+        // At (2, 4), cut off at the left, and dropped from its lines
+        let sprites = [sprite(0, 20, 10, 8, true, true), sprite(1, 16, 4, 16, true, false), sprite(2, 0, 0, 8, false, false)];
+        draw_sprite_boxes(&mut frame, rboy::SCREEN_W, (0, 0), &sprites);
+        let pixel = |x: usize, y: usize| frame[(y * rboy::SCREEN_W + x) * 4 .. (y * rboy::SCREEN_W + x) * 4 + 4].to_vec();
+        for &(x, y) in [(2, 4), (9, 4), (2, 11), (9, 11), (5, 4), (2, 7)].iter() {
+            assert_eq!(pixel(x, y), [0, 255, 0, 255], "({}, {})", x, y);
+        }
+        assert_eq!(pixel(5, 7), [0; 4]);
+        assert_eq!(pixel(0, 0), [255, 0, 0, 255]);
+        assert_eq!(pixel(3, 15), [255, 0, 0, 255]);
+        assert_eq!(pixel(0, 8), [0; 4]);
+        assert_eq!(pixel(3, 8), [255, 0, 0, 255]);
+        assert_eq!(pixel(1, 8), [0; 4]);
+
+        // Inside the border of a Super Game Boy, still cut off at the game screen
+        let mut frame = vec![0; rboy::SGB_SCREEN_W * rboy::SGB_SCREEN_H * 4];
+        draw_sprite_boxes(&mut frame, rboy::SGB_SCREEN_W, (rboy::SGB_GAME_X, rboy::SGB_GAME_Y), &sprites);
+        let pixel = |x: usize, y: usize| frame[(y * rboy::SGB_SCREEN_W + x) * 4 .. (y * rboy::SGB_SCREEN_W + x) * 4 + 4].to_vec();
+        assert_eq!(pixel(rboy::SGB_GAME_X + 2, rboy::SGB_GAME_Y + 4), [0, 255, 0, 255]);
+        assert_eq!(pixel(rboy::SGB_GAME_X, rboy::SGB_GAME_Y), [255, 0, 0, 255]);
+        assert_eq!(pixel(rboy::SGB_GAME_X - 1, rboy::SGB_GAME_Y), [0; 4]);
+    }
+
+    #[test]
+    fn cheat_files() {
+        let text = "# Zelda\n00A-17B-C49 Infinite hearts\n\n  3ED-58F\n#01F-FFF\n010238CD\tMoney\n";
+        assert_eq!(parse_cheat_file(text), vec![(2, "00A-17B-C49"), (4, "3ED-58F"), (6, "010238CD")]);
+    }
+
+    #[test]
+    fn debug_commands() {
+        assert_eq!(parse_debug_command("b 0150"), Ok(DebugCommand::Break(0x150)));
+        assert_eq!(parse_debug_command("w $C000 rw"), Ok(DebugCommand::Watch { address: 0xC000, on_read: true, on_write: true }));
+        assert_eq!(parse_debug_command("w FF40"), Ok(DebugCommand::Watch { address: 0xFF40, on_read: false, on_write: true }));
+        assert_eq!(parse_debug_command("s"), Ok(DebugCommand::Step(1)));
+        assert_eq!(parse_debug_command("s 10"), Ok(DebugCommand::Step(10)));
+        assert_eq!(parse_debug_command("m 0x8000 20"), Ok(DebugCommand::Memory { address: 0x8000, len: 0x20 }));
+        assert_eq!(parse_debug_command("g 4000"), Ok(DebugCommand::RunTo(0x4000)));
+        assert_eq!(parse_debug_command("oam"), Ok(DebugCommand::Oam));
+        assert_eq!(parse_debug_command("i f"), Ok(DebugCommand::FollowInterrupts(true)));
+        assert!(parse_debug_command("i").is_err());
+        assert!(parse_debug_command("b").is_err());
+        assert!(parse_debug_command("w C000 x").is_err());
+        assert!(parse_debug_command("x").is_err());
+    }
+}
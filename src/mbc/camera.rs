@@ -0,0 +1,378 @@
+use crate::mbc::{MBC, poke, rom_banks};
+use crate::StrResult;
+use crate::state::{StateReader, StateResult, StateWriter};
+
+pub const CAMERA_W: usize = 128;
+pub const CAMERA_H: usize = 112;
+
+// Fills in an 8-bit grayscale frame, 0 being black, when the game takes a picture
+pub type CameraSource = Box<dyn FnMut(&mut [u8; CAMERA_W * CAMERA_H]) + Send>;
+
+const REGISTER_COUNT: usize = 0x36;
+// The pictures need all of it, so it does not depend on the RAM size in the header
+const RAM_BANKS: usize = 16;
+// The 4x4 matrix of 3 thresholds per pixel that the picture is dithered with
+const DITHER_MATRIX: usize = 0x06;
+// The exposure time at which the source frame is used as is
+const EXPOSURE_NEUTRAL: i32 = 0x1000;
+// The picture is written to RAM bank 0 as 16x14 tiles
+const PICTURE_ADDRESS: usize = 0x0100;
+const EDGE_RATIOS: [f32; 8] = [0.5, 0.75, 1.0, 1.25, 2.0, 3.0, 4.0, 5.0];
+
+// A diagonal gradient, for when there is no camera on the host
+fn test_pattern(frame: &mut [u8; CAMERA_W * CAMERA_H]) {
+    for (i, pixel) in frame.iter_mut().enumerate() {
+        let (x, y) = (i % CAMERA_W, i / CAMERA_W);
+        *pixel = ((x + y) * 255 / (CAMERA_W + CAMERA_H - 2)) as u8;
+    }
+}
+
+// The Game Boy Camera, an MBC with 128 KiB of RAM and the registers of the image sensor, which
+// are mapped at A000-BFFF instead of the RAM when bit 4 of the RAM bank is set
+pub struct PocketCamera {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rombank: usize,
+    rambank: usize,
+    rombanks: usize,
+    camera_mapped: bool,
+    // Only writes to the RAM are disabled
+    ram_on: bool,
+    ram_updated: bool,
+    registers: [u8; REGISTER_COUNT],
+    // The CPU ticks until the picture being taken is done, 0 when idle
+    capture_ticks: u32,
+    frame: Box<[u8; CAMERA_W * CAMERA_H]>,
+    source: CameraSource,
+}
+
+impl PocketCamera {
+    pub fn new(data: Vec<u8>) -> StrResult<PocketCamera> {
+        let rombanks = rom_banks(data[0x148]);
+
+        let res = PocketCamera {
+            rom: data,
+            ram: vec![0; RAM_BANKS * 0x2000],
+            rombank: 1,
+            rambank: 0,
+            rombanks,
+            camera_mapped: false,
+            ram_on: false,
+            ram_updated: false,
+            registers: [0; REGISTER_COUNT],
+            capture_ticks: 0,
+            frame: Box::new([0; CAMERA_W * CAMERA_H]),
+            source: Box::new(test_pattern),
+        };
+
+        Ok(res)
+    }
+
+    fn ram_address(&self, a: u16) -> usize {
+        ((self.rambank & (RAM_BANKS - 1)) * 0x2000) | ((a & 0x1FFF) as usize)
+    }
+
+    fn exposure(&self) -> u32 {
+        ((self.registers[2] as u32) << 8) | self.registers[3] as u32
+    }
+
+    // Takes the frame from the source, which is processed when the capture is done
+    fn start_capture(&mut self) {
+        (self.source)(&mut self.frame);
+        let n = self.registers[1] & 0x80 != 0;
+        self.capture_ticks = 129792 + if n { 0 } else { 2048 } + self.exposure() * 64;
+    }
+
+    // Exposes, edge enhances and dithers the frame into the tiles in RAM
+    fn finish_capture(&mut self) {
+        let exposure = self.exposure() as i32;
+        let invert = self.registers[4] & 0x08 != 0;
+        let exposed: Vec<i32> = self.frame.iter().map(|&pixel| {
+            let v = (pixel as i32 * exposure / EXPOSURE_NEUTRAL).min(255);
+            if invert { 255 - v } else { v }
+        }).collect();
+
+        let at = |x: usize, y: usize| exposed[y * CAMERA_W + x];
+        // Mode 2D, with the N bit set and both VH bits
+        let edge_ratio = match self.registers[1] & 0xE0 {
+            0xE0 => Some(EDGE_RATIOS[((self.registers[4] >> 4) & 0x07) as usize]),
+            _ => None,
+        };
+
+        for y in 0 .. CAMERA_H {
+            for x in 0 .. CAMERA_W {
+                let mut v = at(x, y);
+                if let Some(ratio) = edge_ratio {
+                    let neighbours = at(x.saturating_sub(1), y) + at((x + 1).min(CAMERA_W - 1), y)
+                        + at(x, y.saturating_sub(1)) + at(x, (y + 1).min(CAMERA_H - 1));
+                    v += ((4 * v - neighbours) as f32 * ratio) as i32;
+                }
+
+                let thresholds = DITHER_MATRIX + ((y & 3) * 4 + (x & 3)) * 3;
+                let color = match self.registers[thresholds .. thresholds + 3].iter().position(|&t| v < t as i32) {
+                    Some(i) => 3 - i as u8,
+                    None => 0,
+                };
+
+                let tile = (y / 8) * (CAMERA_W / 8) + x / 8;
+                let address = PICTURE_ADDRESS + tile * 16 + (y & 7) * 2;
+                let bit = 0x80 >> (x & 7);
+                for (plane, byte) in self.ram[address .. address + 2].iter_mut().enumerate() {
+                    match color & (1 << plane) {
+                        0 => *byte &= !bit,
+                        _ => *byte |= bit,
+                    }
+                }
+            }
+        }
+        self.ram_updated = true;
+    }
+}
+
+impl MBC for PocketCamera {
+    fn readrom(&self, a: u16) -> u8 {
+        let idx = (self.rombank_at(a) * 0x4000) | ((a as usize) & 0x3FFF);
+        *self.rom.get(idx).unwrap_or(&0xFF)
+    }
+
+    // Of the sensor registers, only the one that starts a capture can be read
+    fn readram(&self, a: u16) -> u8 {
+        if self.camera_mapped {
+            return match a & 0x7F {
+                0 => self.registers[0] & 0x07,
+                _ => 0x00,
+            };
+        }
+        *self.ram.get(self.ram_address(a)).unwrap_or(&0xFF)
+    }
+
+    fn writerom(&mut self, a: u16, v: u8) {
+        match a {
+            0x0000 ..= 0x1FFF => { self.ram_on = v & 0x0F == 0x0A; },
+            0x2000 ..= 0x3FFF => { self.rombank = (v as usize) & 0x3F; },
+            0x4000 ..= 0x5FFF => {
+                self.camera_mapped = v & 0x10 != 0;
+                self.rambank = (v as usize) & 0x0F;
+            },
+            0x6000 ..= 0x7FFF => {},
+            _ => panic!("Could not write to {:04X} (Pocket Camera)", a),
+        }
+    }
+
+    fn writeram(&mut self, a: u16, v: u8) {
+        if self.camera_mapped {
+            match (a & 0x7F) as usize {
+                0 => {
+                    let busy = self.registers[0] & 0x01 != 0;
+                    self.registers[0] = v & 0x07;
+                    match (busy, v & 0x01 != 0) {
+                        (false, true) => self.start_capture(),
+                        (true, false) => self.capture_ticks = 0,
+                        _ => {},
+                    }
+                },
+                register if register < REGISTER_COUNT => self.registers[register] = v,
+                _ => {},
+            }
+            return;
+        }
+        if !self.ram_on { return }
+        let address = self.ram_address(a);
+        if address < self.ram.len() {
+            self.ram[address] = v;
+            self.ram_updated = true;
+        }
+    }
+
+    fn do_cycle(&mut self, ticks: u32) {
+        if self.capture_ticks == 0 { return }
+        self.capture_ticks = self.capture_ticks.saturating_sub(ticks);
+        if self.capture_ticks == 0 {
+            self.finish_capture();
+            self.registers[0] &= !0x01;
+        }
+    }
+
+    fn poke_ram(&mut self, offset: usize, v: u8) {
+        self.ram_updated |= poke(&mut self.ram, offset, v);
+    }
+
+    fn peek_ram(&self, offset: usize) -> Option<u8> {
+        self.ram.get(offset).copied()
+    }
+
+    fn is_battery_backed(&self) -> bool {
+        true
+    }
+
+    fn loadram(&mut self, ramdata: &[u8]) -> StrResult<()> {
+        if ramdata.len() != self.ram.len() {
+            return Err("Loaded RAM has incorrect length");
+        }
+
+        self.ram = ramdata.to_vec();
+
+        Ok(())
+    }
+
+    fn dumpram(&self) -> Vec<u8> {
+        self.ram.to_vec()
+    }
+
+    fn rombank_at(&self, a: u16) -> usize {
+        match a {
+            0x0000 ..= 0x3FFF => 0,
+            _ => self.rombank & self.rombanks.saturating_sub(1),
+        }
+    }
+
+    fn set_camera_source(&mut self, source: CameraSource) {
+        self.source = source;
+    }
+
+    // A picture being taken is finished from the frame it was started with
+    fn save_state(&self, w: &mut StateWriter) {
+        w.vec(&self.ram);
+        w.u8(self.rombank as u8);
+        w.u8(self.rambank as u8);
+        w.bool(self.camera_mapped);
+        w.bool(self.ram_on);
+        w.bytes(&self.registers);
+        w.u32(self.capture_ticks);
+        w.bytes(&self.frame[..]);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        r.vec_into(&mut self.ram)?;
+        self.rombank = (r.u8()? & 0x3F) as usize;
+        self.rambank = (r.u8()? & 0x0F) as usize;
+        self.camera_mapped = r.bool()?;
+        self.ram_on = r.bool()?;
+        r.bytes_into(&mut self.registers)?;
+        self.capture_ticks = r.u32()?;
+        r.bytes_into(&mut self.frame[..])?;
+        Ok(())
+    }
+
+    fn take_camera_source(&mut self) -> Option<CameraSource> {
+        Some(std::mem::replace(&mut self.source, Box::new(test_pattern)))
+    }
+
+    fn check_and_reset_ram_updated(&mut self) -> bool {
+        let result = self.ram_updated;
+        self.ram_updated = false;
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{PocketCamera, CAMERA_W};
+    use crate::mbc::MBC;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn camera_with_ram_size(ram_size: u8) -> PocketCamera {
+        let mut data = vec![0; 64 * 0x4000];
+        for bank in 0 .. 64 {
+            data[bank * 0x4000] = bank as u8;
+        }
+        data[0x147] = 0xFC;
+        data[0x148] = 0x05;
+        data[0x149] = ram_size;
+        PocketCamera::new(data).unwrap()
+    }
+
+    fn camera() -> PocketCamera {
+        camera_with_ram_size(0x04)
+    }
+
+    #[test]
+    fn banks() {
+        let mut m = camera();
+        m.writerom(0x2000, 0x3F);
+        assert_eq!(m.readrom(0x4000), 0x3F);
+        m.writerom(0x2000, 0x00);
+        assert_eq!(m.readrom(0x4000), 0);
+
+        // The RAM can always be read, but only written when enabled
+        m.writerom(0x4000, 0x0F);
+        m.writeram(0xA000, 0x11);
+        assert_eq!(m.readram(0xA000), 0x00);
+        m.writerom(0x0000, 0x0A);
+        m.writeram(0xA000, 0x11);
+        m.writerom(0x0000, 0x00);
+        assert_eq!(m.readram(0xA000), 0x11);
+        assert_eq!(m.dumpram().len(), 0x20000);
+
+        m.writerom(0x4000, 0x10);
+        m.writeram(0xA001, 0x80);
+        assert_eq!((m.readram(0xA000), m.readram(0xA001), m.readram(0xA080)), (0x00, 0x00, 0x00));
+        m.writerom(0x4000, 0x0F);
+        assert_eq!(m.readram(0xA000), 0x11);
+    }
+
+    #[test]
+    fn capture() {
+        let mut m = camera();
+        let captures = Arc::new(AtomicUsize::new(0));
+        let counter = captures.clone();
+        // Black on the left, white on the right
+        m.set_camera_source(Box::new(move |frame| {
+            counter.fetch_add(1, Ordering::SeqCst);
+            for (i, pixel) in frame.iter_mut().enumerate() {
+                *pixel = if i % CAMERA_W < CAMERA_W / 2 { 0x00 } else { 0xFF };
+            }
+        }));
+
+        m.writerom(0x4000, 0x10);
+        m.writeram(0xA001, 0x80);
+        m.writeram(0xA002, 0x10);
+        m.writeram(0xA003, 0x00);
+        for i in 0 .. 16 {
+            m.writeram(0xA006 + i * 3, 0x40);
+            m.writeram(0xA007 + i * 3, 0x80);
+            m.writeram(0xA008 + i * 3, 0xC0);
+        }
+        m.writeram(0xA000, 0x03);
+        assert_eq!(m.readram(0xA000), 0x03);
+        assert_eq!(captures.load(Ordering::SeqCst), 1);
+
+        // 129792 ticks, plus 64 per exposure step
+        m.do_cycle(129792 + 0x1000 * 64 - 1);
+        assert_eq!(m.readram(0xA000), 0x03);
+        m.do_cycle(4);
+        assert_eq!(m.readram(0xA000), 0x02);
+        assert!(m.check_and_reset_ram_updated());
+
+        // The first and last tile of the top row
+        let ram = m.dumpram();
+        assert_eq!(&ram[0x100 .. 0x102], &[0xFF, 0xFF]);
+        assert_eq!(&ram[0x100 + 15 * 16 .. 0x102 + 15 * 16], &[0x00, 0x00]);
+
+        // Inverted, with a middle threshold for the white half
+        m.writeram(0xA004, 0x08);
+        m.writeram(0xA003, 0x00);
+        m.writeram(0xA002, 0x08);
+        m.writeram(0xA000, 0x01);
+        m.do_cycle(u32::MAX);
+        let ram = m.dumpram();
+        assert_eq!(&ram[0x100 .. 0x102], &[0x00, 0x00]);
+        assert_eq!(&ram[0x100 + 15 * 16 .. 0x102 + 15 * 16], &[0xFF, 0x00]);
+        assert_eq!(captures.load(Ordering::SeqCst), 2);
+    }
+    #[test]
+    fn ram_size_in_header_ignored() {
+        // A header without RAM still gets the 128 KiB the pictures are written to
+        let mut m = camera_with_ram_size(0x00);
+        assert_eq!(m.dumpram().len(), 0x20000);
+        m.writerom(0x4000, 0x10);
+        m.writeram(0xA000, 0x01);
+        m.do_cycle(u32::MAX);
+        assert_eq!(m.readram(0xA000), 0x00);
+        m.writerom(0x4000, 0x0F);
+        m.writerom(0x0000, 0x0A);
+        m.writeram(0xBFFF, 0x22);
+        assert_eq!(m.dumpram()[0x1FFFF], 0x22);
+    }
+}
@@ -0,0 +1,271 @@
+use crate::mbc::{ram_banks, rom_banks};
+use crate::StrResult;
+use std::convert::TryInto;
+
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+const HEADER_START: usize = 0x100;
+const HEADER_END: usize = 0x150;
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Mapper {
+    // ROM only, possibly with RAM
+    None,
+    MBC1,
+    MBC2,
+    MBC3,
+    MBC5,
+    MBC6,
+    MBC7,
+    MMM01,
+    PocketCamera,
+    TAMA5,
+    HuC1,
+    HuC3,
+    Unknown,
+}
+
+// The cartridge type byte at 0147 and the hardware it stands for. MBC2 counts as having RAM, as
+// it is built into the controller.
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub struct CartridgeType {
+    pub code: u8,
+    pub mapper: Mapper,
+    pub ram: bool,
+    pub battery: bool,
+    pub rtc: bool,
+    pub rumble: bool,
+}
+
+impl CartridgeType {
+    pub fn from_code(code: u8) -> CartridgeType {
+        let (mapper, ram, battery, rtc, rumble) = match code {
+            0x00 => (Mapper::None, false, false, false, false),
+            0x01 => (Mapper::MBC1, false, false, false, false),
+            0x02 => (Mapper::MBC1, true, false, false, false),
+            0x03 => (Mapper::MBC1, true, true, false, false),
+            0x05 => (Mapper::MBC2, true, false, false, false),
+            0x06 => (Mapper::MBC2, true, true, false, false),
+            0x08 => (Mapper::None, true, false, false, false),
+            0x09 => (Mapper::None, true, true, false, false),
+            0x0B => (Mapper::MMM01, false, false, false, false),
+            0x0C => (Mapper::MMM01, true, false, false, false),
+            0x0D => (Mapper::MMM01, true, true, false, false),
+            0x0F => (Mapper::MBC3, false, true, true, false),
+            0x10 => (Mapper::MBC3, true, true, true, false),
+            0x11 => (Mapper::MBC3, false, false, false, false),
+            0x12 => (Mapper::MBC3, true, false, false, false),
+            0x13 => (Mapper::MBC3, true, true, false, false),
+            0x19 => (Mapper::MBC5, false, false, false, false),
+            0x1A => (Mapper::MBC5, true, false, false, false),
+            0x1B => (Mapper::MBC5, true, true, false, false),
+            0x1C => (Mapper::MBC5, false, false, false, true),
+            0x1D => (Mapper::MBC5, true, false, false, true),
+            0x1E => (Mapper::MBC5, true, true, false, true),
+            0x20 => (Mapper::MBC6, true, true, false, false),
+            0x22 => (Mapper::MBC7, true, true, false, true),
+            0xFC => (Mapper::PocketCamera, true, true, false, false),
+            0xFD => (Mapper::TAMA5, true, true, true, false),
+            0xFE => (Mapper::HuC3, true, true, true, false),
+            0xFF => (Mapper::HuC1, true, true, false, false),
+            _ => (Mapper::Unknown, false, false, false, false),
+        };
+        CartridgeType { code, mapper, ram, battery, rtc, rumble }
+    }
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug)]
+pub enum Destination {
+    Japan,
+    Overseas,
+}
+
+// The cartridge header at 0100-014F
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct CartridgeHeader {
+    pub title: String,
+    // Only in the newer CGB games
+    pub manufacturer: Option<String>,
+    pub cgb_flag: u8,
+    pub sgb_flag: u8,
+    pub cartridge_type: CartridgeType,
+    // In bytes, 0 for an unknown size
+    pub rom_size: usize,
+    pub ram_size: usize,
+    pub destination: Destination,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+    bytes: [u8; HEADER_END - HEADER_START],
+}
+
+impl CartridgeHeader {
+    pub fn parse(rom: &[u8]) -> StrResult<CartridgeHeader> {
+        if rom.len() < HEADER_END { return Err("Rom size to small"); }
+        let bytes: [u8; HEADER_END - HEADER_START] = rom[HEADER_START .. HEADER_END].try_into().unwrap();
+        let at = |a: usize| bytes[a - HEADER_START];
+
+        // The title is shorter when the last bytes hold the manufacturer code and CGB flag
+        let cgb_flag = at(0x143);
+        let title_size = match cgb_flag & 0x80 {
+            0x80 => 11,
+            _ => 16,
+        };
+        let title = bytes[0x34 .. 0x34 + title_size].iter()
+            .take_while(|&&v| v != 0)
+            .map(|&v| v as char)
+            .collect();
+        let manufacturer = &bytes[0x3F .. 0x43];
+        let manufacturer = match cgb_flag & 0x80 != 0 && manufacturer.iter().all(|v| v.is_ascii_alphanumeric()) {
+            true => Some(manufacturer.iter().map(|&v| v as char).collect()),
+            false => None,
+        };
+
+        Ok(CartridgeHeader {
+            title,
+            manufacturer,
+            cgb_flag,
+            sgb_flag: at(0x146),
+            cartridge_type: CartridgeType::from_code(at(0x147)),
+            rom_size: rom_banks(at(0x148)) * 0x4000,
+            ram_size: ram_banks(at(0x149)) * 0x2000,
+            destination: match at(0x14A) {
+                0x00 => Destination::Japan,
+                _ => Destination::Overseas,
+            },
+            header_checksum: at(0x14D),
+            global_checksum: ((at(0x14E) as u16) << 8) | at(0x14F) as u16,
+            bytes,
+        })
+    }
+
+    pub fn is_cgb_only(&self) -> bool {
+        self.cgb_flag == 0xC0
+    }
+
+    // The SGB only runs its commands for games with the flag and the new licensee code
+    pub fn supports_sgb(&self) -> bool {
+        self.sgb_flag == 0x03 && self.bytes[0x4B] == 0x33
+    }
+
+    // The boot ROM locks up unless the logo matches
+    pub fn verify_logo(&self) -> bool {
+        self.bytes[0x04 .. 0x34] == NINTENDO_LOGO
+    }
+
+    // The checksum of 0134-014C, which the boot ROM verifies
+    pub fn verify_header_checksum(&self) -> bool {
+        let value = self.bytes[0x34 .. 0x4D].iter().fold(0u8, |value, &v| value.wrapping_sub(v).wrapping_sub(1));
+        value == self.header_checksum
+    }
+
+    // The sum of all bytes of the ROM except the checksum itself, which nothing verifies
+    pub fn verify_global_checksum(&self, rom: &[u8]) -> bool {
+        let sum = rom.iter().enumerate()
+            .filter(|&(a, _)| a != 0x14E && a != 0x14F)
+            .fold(0u16, |sum, (_, &v)| sum.wrapping_add(v as u16));
+        sum == self.global_checksum
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{CartridgeHeader, CartridgeType, Destination, Mapper, NINTENDO_LOGO};
+
+    // A 32 KiB ROM with valid checksums
+    fn header_rom(title: &[u8], cgb_flag: u8, cartridge_type: u8) -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x104 .. 0x134].copy_from_slice(&NINTENDO_LOGO);
+        rom[0x134 .. 0x134 + title.len()].copy_from_slice(title);
+        rom[0x143] = cgb_flag;
+        rom[0x147] = cartridge_type;
+        fix_checksums(&mut rom);
+        rom
+    }
+
+    fn fix_checksums(rom: &mut [u8]) {
+        rom[0x14D] = rom[0x134 .. 0x14D].iter().fold(0u8, |value, &v| value.wrapping_sub(v).wrapping_sub(1));
+        rom[0x14E] = 0;
+        rom[0x14F] = 0;
+        let sum = rom.iter().fold(0u16, |sum, &v| sum.wrapping_add(v as u16));
+        rom[0x14E .. 0x150].copy_from_slice(&sum.to_be_bytes());
+    }
+
+    #[test]
+    fn dmg_header() {
+        let mut rom = header_rom(b"TETRIS", 0x00, 0x00);
+        rom[0x146] = 0x03;
+        rom[0x14A] = 0x01;
+        fix_checksums(&mut rom);
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.title, "TETRIS");
+        assert_eq!(header.manufacturer, None);
+        assert_eq!((header.cgb_flag, header.sgb_flag), (0x00, 0x03));
+        assert!(!header.is_cgb_only());
+        assert!(!header.supports_sgb());
+        assert_eq!(header.cartridge_type, CartridgeType { code: 0x00, mapper: Mapper::None, ram: false, battery: false, rtc: false, rumble: false });
+        assert_eq!((header.rom_size, header.ram_size), (0x8000, 0));
+        assert_eq!(header.destination, Destination::Overseas);
+        assert!(header.verify_logo());
+        assert!(header.verify_header_checksum());
+        assert!(header.verify_global_checksum(&rom));
+
+        rom[0x14B] = 0x33;
+        fix_checksums(&mut rom);
+        assert!(CartridgeHeader::parse(&rom).unwrap().supports_sgb());
+    }
+
+    #[test]
+    fn cgb_header() {
+        let mut rom = header_rom(b"POKEMON_SLVAAXE", 0x80, 0x10);
+        rom[0x148] = 0x06;
+        rom[0x149] = 0x03;
+        fix_checksums(&mut rom);
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!(header.title, "POKEMON_SLV");
+        assert_eq!(header.manufacturer.as_deref(), Some("AAXE"));
+        assert_eq!(header.cartridge_type, CartridgeType { code: 0x10, mapper: Mapper::MBC3, ram: true, battery: true, rtc: true, rumble: false });
+        assert_eq!((header.rom_size, header.ram_size), (0x200000, 0x8000));
+        assert_eq!(header.destination, Destination::Japan);
+
+        // A title that fills the space has no manufacturer code
+        let rom = header_rom(b"ZELDA\0\0\0\0\0\0\0\0\0\0", 0xC0, 0x1E);
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!((header.title.as_str(), header.manufacturer.as_deref()), ("ZELDA", None));
+        assert!(header.is_cgb_only());
+        assert!(header.cartridge_type.rumble && header.cartridge_type.battery);
+        assert_eq!(header.cartridge_type.mapper, Mapper::MBC5);
+    }
+
+    #[test]
+    fn bad_header() {
+        let mut rom = header_rom(b"HOMEBREW", 0x00, 0x03);
+        rom[0x104] = 0;
+        rom[0x147] = 0xEE;
+        rom[0x148] = 0x20;
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert!(!header.verify_logo());
+        assert!(!header.verify_header_checksum());
+        assert!(!header.verify_global_checksum(&rom));
+        assert_eq!(header.cartridge_type.mapper, Mapper::Unknown);
+        assert_eq!(header.rom_size, 0);
+        assert!(CartridgeHeader::parse(&rom[.. 0x14F]).is_err());
+    }
+
+    #[test]
+    fn checksum_zero() {
+        let mut data = vec![0; 0x150];
+        data[0x14D] = -(0x14D_i32 - 0x134_i32) as u8;
+        assert!(CartridgeHeader::parse(&data).unwrap().verify_header_checksum());
+    }
+
+    #[test]
+    fn checksum_ones() {
+        let mut data = vec![1; 0x150];
+        data[0x14D] = (-(0x14D_i32 - 0x134_i32) * 2) as u8;
+        assert!(CartridgeHeader::parse(&data).unwrap().verify_header_checksum());
+    }
+}
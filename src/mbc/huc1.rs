@@ -0,0 +1,193 @@
+use crate::mbc::{MBC, poke, ram_banks, rom_banks};
+use crate::StrResult;
+use crate::state::{StateReader, StateResult, StateWriter};
+
+// What the IR receiver reads as when it sees no light
+const IR_NO_LIGHT: u8 = 0xC0;
+
+// Hudson's MBC1 variant, which has an infrared port in place of the RAM enable and no banking mode
+pub struct HuC1 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    // Writing 0E to 0000-1FFF maps the IR port at A000-BFFF, anything else maps the RAM
+    ir_mode: bool,
+    ir_led: bool,
+    ram_updated: bool,
+    rombank: usize,
+    rambank: usize,
+    rombanks: usize,
+    rambanks: usize,
+}
+
+impl HuC1 {
+    pub fn new(data: Vec<u8>) -> StrResult<HuC1> {
+        let rombanks = rom_banks(data[0x148]);
+        let rambanks = ram_banks(data[0x149]);
+
+        let res = HuC1 {
+            rom: data,
+            ram: vec![0; rambanks * 0x2000],
+            ir_mode: false,
+            ir_led: false,
+            ram_updated: false,
+            rombank: 1,
+            rambank: 0,
+            rombanks,
+            rambanks,
+        };
+
+        Ok(res)
+    }
+
+    fn ram_address(&self, a: u16) -> usize {
+        ((self.rambank & self.rambanks.saturating_sub(1)) * 0x2000) | ((a & 0x1FFF) as usize)
+    }
+}
+
+impl MBC for HuC1 {
+    fn readrom(&self, a: u16) -> u8 {
+        let idx = (self.rombank_at(a) * 0x4000) | ((a as usize) & 0x3FFF);
+        *self.rom.get(idx).unwrap_or(&0xFF)
+    }
+    fn readram(&self, a: u16) -> u8 {
+        if self.ir_mode { return IR_NO_LIGHT }
+        *self.ram.get(self.ram_address(a)).unwrap_or(&0xFF)
+    }
+
+    fn writerom(&mut self, a: u16, v: u8) {
+        match a {
+            0x0000 ..= 0x1FFF => { self.ir_mode = v & 0x0F == 0x0E; },
+            0x2000 ..= 0x3FFF => {
+                self.rombank = match (v as usize) & 0x3F {
+                    0 => 1,
+                    n => n,
+                };
+            },
+            0x4000 ..= 0x5FFF => { self.rambank = (v as usize) & 0x03; },
+            0x6000 ..= 0x7FFF => {},
+            _ => panic!("Could not write to {:04X} (HuC1)", a),
+        }
+    }
+
+    fn writeram(&mut self, a: u16, v: u8) {
+        if self.ir_mode {
+            // Nothing receives the light yet
+            self.ir_led = v & 0x01 != 0;
+            return;
+        }
+        let address = self.ram_address(a);
+        if address < self.ram.len() {
+            self.ram[address] = v;
+            self.ram_updated = true;
+        }
+    }
+
+    fn poke_ram(&mut self, offset: usize, v: u8) {
+        self.ram_updated |= poke(&mut self.ram, offset, v);
+    }
+
+    fn peek_ram(&self, offset: usize) -> Option<u8> {
+        self.ram.get(offset).copied()
+    }
+
+    fn is_battery_backed(&self) -> bool {
+        true
+    }
+
+    fn loadram(&mut self, ramdata: &[u8]) -> StrResult<()> {
+        if ramdata.len() != self.ram.len() {
+            return Err("Loaded RAM has incorrect length");
+        }
+
+        self.ram = ramdata.to_vec();
+
+        Ok(())
+    }
+
+    fn dumpram(&self) -> Vec<u8> {
+        self.ram.to_vec()
+    }
+
+    fn rombank_at(&self, a: u16) -> usize {
+        match a {
+            0x0000 ..= 0x3FFF => 0,
+            _ => self.rombank & self.rombanks.saturating_sub(1),
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.vec(&self.ram);
+        w.bool(self.ir_mode);
+        w.bool(self.ir_led);
+        w.u8(self.rombank as u8);
+        w.u8(self.rambank as u8);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        r.vec_into(&mut self.ram)?;
+        self.ir_mode = r.bool()?;
+        self.ir_led = r.bool()?;
+        self.rombank = match r.u8()? & 0x3F { 0 => 1, n => n as usize };
+        self.rambank = (r.u8()? & 0x03) as usize;
+        Ok(())
+    }
+
+    fn check_and_reset_ram_updated(&mut self) -> bool {
+        let result = self.ram_updated;
+        self.ram_updated = false;
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::HuC1;
+    use crate::mbc::{self, MBC};
+
+    // 1 MiB with 32 KiB of RAM, every bank starts with its number
+    fn huc1() -> Vec<u8> {
+        let mut data = vec![0; 64 * 0x4000];
+        for bank in 0 .. 64 {
+            data[bank * 0x4000] = bank as u8;
+        }
+        data[0x147] = 0xFF;
+        data[0x148] = 0x05;
+        data[0x149] = 0x03;
+        data
+    }
+
+    #[test]
+    fn rom_banks() {
+        let mut m = HuC1::new(huc1()).unwrap();
+        assert_eq!(m.readrom(0x4000), 1);
+        m.writerom(0x2000, 0x3F);
+        assert_eq!(m.readrom(0x4000), 0x3F);
+        m.writerom(0x2000, 0x00);
+        assert_eq!(m.readrom(0x4000), 1);
+        // There is no banking mode
+        m.writerom(0x2000, 0x05);
+        m.writerom(0x4000, 0x03);
+        m.writerom(0x6000, 0x01);
+        assert_eq!((m.readrom(0x0000), m.readrom(0x4000)), (0, 5));
+    }
+
+    #[test]
+    fn ram_and_ir() {
+        let mut m = mbc::get_mbc(huc1(), true).unwrap();
+        assert!(m.is_battery_backed());
+        for bank in 0 .. 4 {
+            m.writerom(0x4000, bank);
+            m.writeram(0xA000, bank + 1);
+        }
+        m.writerom(0x4000, 0x02);
+        assert_eq!(m.readram(0xA000), 3);
+
+        m.writerom(0x0000, 0x0E);
+        assert_eq!(m.readram(0xA000), 0xC0);
+        m.writeram(0xA000, 0x01);
+        m.writerom(0x0000, 0x00);
+        assert_eq!(m.readram(0xA000), 3);
+        assert_eq!(m.dumpram()[2 * 0x2000], 3);
+        assert!(m.check_and_reset_ram_updated());
+    }
+}
@@ -1,5 +1,6 @@
 use crate::StrResult;
 use crate::mbc::MBC;
+use crate::state::{StateReader, StateResult, StateWriter};
 
 pub struct MBC0 {
     rom: Vec<u8>,
@@ -21,4 +22,7 @@ impl MBC for MBC0 {
     fn loadram(&mut self, _ramdata: &[u8]) -> StrResult<()> { Ok(()) }
     fn dumpram(&self) -> Vec<u8> { Vec::new() }
     fn check_and_reset_ram_updated(&mut self) -> bool { false }
+    fn rombank_at(&self, a: u16) -> usize { a as usize >> 14 }
+    fn save_state(&self, _w: &mut StateWriter) {}
+    fn load_state(&mut self, _r: &mut StateReader) -> StateResult<()> { Ok(()) }
 }
@@ -1,5 +1,7 @@
-use crate::mbc::{MBC, ram_banks, rom_banks};
+use crate::mbc::{MBC, poke, ram_banks, rom_banks};
+use crate::mbc::header::NINTENDO_LOGO;
 use crate::StrResult;
+use crate::state::{StateReader, StateResult, StateWriter};
 
 pub struct MBC1 {
     rom: Vec<u8>,
@@ -7,11 +9,23 @@ pub struct MBC1 {
     ram_on: bool,
     ram_updated:bool,
     banking_mode: u8,
-    rombank: usize,
-    rambank: usize,
+    // The 5-bit ROM bank register, which never holds 0
+    bank1: usize,
+    // The 2-bit register for the upper ROM bank bits or the RAM bank
+    bank2: usize,
     has_battery: bool,
     rombanks: usize,
     rambanks: usize,
+    // MBC1M, which only uses 4 bits of bank1, with bank2 as the upper 2 bits above them
+    multicart: bool,
+}
+
+// A multicart has a game with its own header at banks 10, 20 and 30
+fn is_multicart(data: &[u8]) -> bool {
+    data.len() == 0x100000 && [0x10, 0x20, 0x30].iter().all(|bank| {
+        let logo = bank * 0x4000 + 0x104;
+        data[logo .. logo + NINTENDO_LOGO.len()] == NINTENDO_LOGO
+    })
 }
 
 impl MBC1 {
@@ -23,65 +37,57 @@ impl MBC1 {
         };
         let rombanks = rom_banks(data[0x148]);
         let ramsize = rambanks * 0x2000;
+        let multicart = is_multicart(&data);
 
         let res = MBC1 {
             rom: data,
             ram: ::std::iter::repeat(0u8).take(ramsize).collect(),
             ram_on: false,
             banking_mode: 0,
-            rombank: 1,
-            rambank: 0,
+            bank1: 1,
+            bank2: 0,
             ram_updated: false,
             has_battery: has_battery,
             rombanks: rombanks,
             rambanks: rambanks,
+            multicart,
         };
 
         Ok(res)
     }
+
+    // Only mode 1 switches the RAM bank. Banks past the end of the RAM mirror the ones before.
+    fn ram_address(&self, a: u16) -> usize {
+        let rambank = match self.banking_mode {
+            1 => self.bank2 & self.rambanks.saturating_sub(1),
+            _ => 0,
+        };
+        (rambank * 0x2000) | ((a & 0x1FFF) as usize)
+    }
 }
 
 impl MBC for MBC1 {
     fn readrom(&self, a: u16) -> u8 {
-        let bank = if a < 0x4000 {
-            if self.banking_mode == 0 {
-                0
-            }
-            else {
-                self.rombank & 0xE0
-            }
-        }
-        else {
-            self.rombank
-        };
-        let idx = bank * 0x4000 | ((a as usize) & 0x3FFF);
+        let idx = (self.rombank_at(a) * 0x4000) | ((a as usize) & 0x3FFF);
         *self.rom.get(idx).unwrap_or(&0xFF)
     }
     fn readram(&self, a: u16) -> u8 {
         if !self.ram_on { return 0xFF }
-        let rambank = if self.banking_mode == 1 { self.rambank } else { 0 };
-        self.ram[(rambank * 0x2000) | ((a & 0x1FFF) as usize)]
+        *self.ram.get(self.ram_address(a)).unwrap_or(&0xFF)
     }
 
     fn writerom(&mut self, a: u16, v: u8) {
         match a {
             0x0000 ..= 0x1FFF => { self.ram_on = v & 0xF == 0xA; },
+            // Bank 0 is turned into 1 before the bank is limited to the ROM size, so 20 selects 1
+            // but on a small ROM 04 can select bank 0
             0x2000 ..= 0x3FFF => {
-                let lower_bits = match (v as usize) & 0x1F {
+                self.bank1 = match (v as usize) & 0x1F {
                     0 => 1,
                     n => n,
                 };
-                self.rombank = ((self.rombank & 0x60) | lower_bits) % self.rombanks;
-            },
-            0x4000 ..= 0x5FFF => {
-                if self.rombanks > 0x20 {
-                    let upper_bits = (v as usize & 0x03) % (self.rombanks >> 5);
-                    self.rombank = self.rombank & 0x1F | (upper_bits << 5)
-                }
-                if self.rambanks > 1 {
-                    self.rambank = (v as usize) & 0x03;
-                }
             },
+            0x4000 ..= 0x5FFF => { self.bank2 = (v as usize) & 0x03; },
             0x6000 ..= 0x7FFF => { self.banking_mode = v & 0x01; },
             _ => panic!("Could not write to {:04X} (MBC1)", a),
         }
@@ -89,14 +95,21 @@ impl MBC for MBC1 {
 
     fn writeram(&mut self, a: u16, v: u8) {
         if !self.ram_on { return }
-        let rambank = if self.banking_mode == 1 { self.rambank } else { 0 };
-        let address = (rambank * 0x2000) | ((a & 0x1FFF) as usize);
+        let address = self.ram_address(a);
         if address < self.ram.len() {
             self.ram[address] = v;
             self.ram_updated = true;
         }
     }
 
+    fn poke_ram(&mut self, offset: usize, v: u8) {
+        self.ram_updated |= poke(&mut self.ram, offset, v);
+    }
+
+    fn peek_ram(&self, offset: usize) -> Option<u8> {
+        self.ram.get(offset).copied()
+    }
+
     fn is_battery_backed(&self) -> bool {
         self.has_battery
     }
@@ -115,9 +128,175 @@ impl MBC for MBC1 {
         self.ram.to_vec()
     }
 
+    // In mode 1 the upper bits also switch the bank at 0000-3FFF. Banks past the end of the ROM
+    // mirror the ones before.
+    fn rombank_at(&self, a: u16) -> usize {
+        let (bank2_shift, bank1_mask) = match self.multicart {
+            true => (4, 0x0F),
+            false => (5, 0x1F),
+        };
+        let bank = if a < 0x4000 {
+            if self.banking_mode == 0 {
+                0
+            }
+            else {
+                self.bank2 << bank2_shift
+            }
+        }
+        else {
+            (self.bank2 << bank2_shift) | (self.bank1 & bank1_mask)
+        };
+        bank & self.rombanks.saturating_sub(1)
+    }
+
+    fn set_multicart(&mut self, multicart: bool) {
+        self.multicart = multicart;
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.vec(&self.ram);
+        w.bool(self.ram_on);
+        w.u8(self.banking_mode);
+        w.u8(self.bank1 as u8);
+        w.u8(self.bank2 as u8);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        r.vec_into(&mut self.ram)?;
+        self.ram_on = r.bool()?;
+        self.banking_mode = r.u8()? & 0x01;
+        self.bank1 = match r.u8()? & 0x1F { 0 => 1, n => n as usize };
+        self.bank2 = (r.u8()? & 0x03) as usize;
+        Ok(())
+    }
+
     fn check_and_reset_ram_updated(&mut self) -> bool {
         let result = self.ram_updated;
         self.ram_updated = false;
         result
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::MBC1;
+    use crate::mbc::MBC;
+    use crate::mbc::header::NINTENDO_LOGO;
+
+    // Every bank starts with its number
+    fn mbc1(rom_size: u8, ram_size: u8) -> MBC1 {
+        let banks = 2 << rom_size;
+        let mut data = vec![0; banks * 0x4000];
+        for bank in 0 .. banks {
+            data[bank * 0x4000] = bank as u8;
+        }
+        data[0x147] = 0x03;
+        data[0x148] = rom_size;
+        data[0x149] = ram_size;
+        MBC1::new(data).unwrap()
+    }
+
+    #[test]
+    fn rom_banks() {
+        // 1 MiB
+        let mut m = mbc1(0x05, 0x00);
+        assert_eq!(m.readrom(0x4000), 1);
+        m.writerom(0x2000, 0x00);
+        assert_eq!(m.readrom(0x4000), 1);
+        m.writerom(0x2000, 0x1F);
+        assert_eq!(m.readrom(0x4000), 0x1F);
+        m.writerom(0x2000, 0x20);
+        assert_eq!(m.readrom(0x4000), 1);
+
+        m.writerom(0x4000, 0x01);
+        assert_eq!(m.readrom(0x4000), 0x21);
+        assert_eq!(m.readrom(0x0000), 0);
+        m.writerom(0x6000, 0x01);
+        assert_eq!(m.readrom(0x0000), 0x20);
+        // The 0 to 1 translation only looks at the lower bits
+        m.writerom(0x2000, 0x00);
+        assert_eq!(m.readrom(0x4000), 0x21);
+    }
+
+    #[test]
+    fn multicart() {
+        let mut data = mbc1(0x05, 0x00).rom;
+        let mut plain = MBC1::new(data.clone()).unwrap();
+        for bank in [0x10, 0x20, 0x30] {
+            let logo = bank * 0x4000 + 0x104;
+            data[logo .. logo + NINTENDO_LOGO.len()].copy_from_slice(&NINTENDO_LOGO);
+        }
+        let mut multicart = MBC1::new(data).unwrap();
+        assert!(!plain.multicart);
+        assert!(multicart.multicart);
+
+        for m in [&mut plain, &mut multicart] {
+            m.writerom(0x2000, 0x12);
+            m.writerom(0x4000, 0x01);
+            m.writerom(0x6000, 0x01);
+        }
+        assert_eq!((plain.readrom(0x0000), plain.readrom(0x4000)), (0x20, 0x32));
+        assert_eq!((multicart.readrom(0x0000), multicart.readrom(0x4000)), (0x10, 0x12));
+
+        // Bank 10 is selectable as the lower bits are not 0
+        multicart.writerom(0x2000, 0x10);
+        assert_eq!(multicart.readrom(0x4000), 0x10);
+
+        multicart.set_multicart(false);
+        assert_eq!(multicart.readrom(0x4000), 0x30);
+    }
+
+    #[test]
+    fn rom_mirroring() {
+        // 64 KiB
+        let mut m = mbc1(0x01, 0x00);
+        m.writerom(0x2000, 0x05);
+        assert_eq!(m.readrom(0x4000), 1);
+        m.writerom(0x2000, 0x04);
+        assert_eq!(m.readrom(0x4000), 0);
+        m.writerom(0x2000, 0x7F);
+        assert_eq!(m.readrom(0x4000), 3);
+        // The upper bits select nothing on a small ROM
+        m.writerom(0x2000, 0x02);
+        m.writerom(0x4000, 0x03);
+        m.writerom(0x6000, 0x01);
+        assert_eq!(m.readrom(0x4000), 2);
+        assert_eq!(m.readrom(0x0000), 0);
+    }
+
+    #[test]
+    fn ram_banks() {
+        // 32 KiB
+        let mut m = mbc1(0x00, 0x03);
+        m.writeram(0xA000, 0x11);
+        assert_eq!(m.readram(0xA000), 0xFF);
+
+        m.writerom(0x0000, 0x0A);
+        m.writeram(0xA000, 0x11);
+        m.writerom(0x4000, 0x02);
+        // Mode 0 always uses bank 0
+        assert_eq!(m.readram(0xA000), 0x11);
+        m.writerom(0x6000, 0x01);
+        assert_eq!(m.readram(0xA000), 0x00);
+        m.writeram(0xBFFF, 0x22);
+        assert_eq!(m.dumpram()[2 * 0x2000 + 0x1FFF], 0x22);
+
+        m.writerom(0x0000, 0x00);
+        assert_eq!(m.readram(0xBFFF), 0xFF);
+    }
+
+    #[test]
+    fn no_ram() {
+        let mut m = mbc1(0x00, 0x00);
+        m.writerom(0x0000, 0x0A);
+        m.writeram(0xA000, 0x11);
+        assert_eq!(m.readram(0xA000), 0xFF);
+        for bank in 0 .. 4 {
+            m.writerom(0x4000, bank);
+            m.writeram(0xBFFF, 0x22);
+            assert_eq!(m.readram(0xBFFF), 0xFF);
+        }
+        assert!(m.dumpram().is_empty());
+        assert!(!m.check_and_reset_ram_updated());
+    }
+}
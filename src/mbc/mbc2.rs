@@ -1,5 +1,6 @@
-use crate::mbc::{MBC, rom_banks};
+use crate::mbc::{MBC, poke, rom_banks};
 use crate::StrResult;
+use crate::state::{StateReader, StateResult, StateWriter};
 
 pub struct MBC2 {
     rom: Vec<u8>,
@@ -35,13 +36,7 @@ impl MBC2 {
 
 impl MBC for MBC2 {
     fn readrom(&self, a: u16) -> u8 {
-        let bank = if a < 0x4000 {
-            0
-        }
-        else {
-            self.rombank
-        };
-        let idx = bank * 0x4000 | ((a as usize) & 0x3FFF);
+        let idx = (self.rombank_at(a) * 0x4000) | ((a as usize) & 0x3FFF);
         *self.rom.get(idx).unwrap_or(&0xFF)
     }
     fn readram(&self, a: u16) -> u8 {
@@ -59,7 +54,7 @@ impl MBC for MBC2 {
                     self.rombank = match (v as usize) & 0x0F {
                         0 => 1,
                         n => n,
-                    } % self.rombanks;
+                    };
                 }
             },
             _ => {},
@@ -72,6 +67,14 @@ impl MBC for MBC2 {
         self.ram_updated = true;
     }
 
+    fn poke_ram(&mut self, offset: usize, v: u8) {
+        self.ram_updated |= poke(&mut self.ram, offset, v | 0xF0);
+    }
+
+    fn peek_ram(&self, offset: usize) -> Option<u8> {
+        self.ram.get(offset).copied()
+    }
+
     fn is_battery_backed(&self) -> bool {
         self.has_battery
     }
@@ -90,9 +93,96 @@ impl MBC for MBC2 {
         self.ram.to_vec()
     }
 
+    fn save_state(&self, w: &mut StateWriter) {
+        w.vec(&self.ram);
+        w.bool(self.ram_on);
+        w.u8(self.rombank as u8);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        r.vec_into(&mut self.ram)?;
+        self.ram_on = r.bool()?;
+        self.rombank = (r.u8()? & 0x0F) as usize;
+        Ok(())
+    }
+
+    fn rombank_at(&self, a: u16) -> usize {
+        if a < 0x4000 {
+            0
+        }
+        else {
+            self.rombank & self.rombanks.saturating_sub(1)
+        }
+    }
+
     fn check_and_reset_ram_updated(&mut self) -> bool {
         let result = self.ram_updated;
         self.ram_updated = false;
         result
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::MBC2;
+    use crate::mbc::MBC;
+
+    // 256 KiB, every bank starts with its number
+    fn mbc2() -> MBC2 {
+        let mut data = vec![0; 16 * 0x4000];
+        for bank in 0 .. 16 {
+            data[bank * 0x4000] = bank as u8;
+        }
+        data[0x147] = 0x06;
+        data[0x148] = 0x03;
+        MBC2::new(data).unwrap()
+    }
+
+    #[test]
+    fn registers() {
+        let mut m = mbc2();
+        // Address bit 8 selects the ROM bank register, anywhere in 0000-3FFF
+        m.writerom(0x0100, 0x05);
+        assert_eq!(m.readrom(0x4000), 5);
+        m.writerom(0x3FFF, 0x1F);
+        assert_eq!(m.readrom(0x4000), 0x0F);
+        m.writerom(0x2100, 0x00);
+        assert_eq!(m.readrom(0x4000), 1);
+        assert_eq!(m.readrom(0x0000), 0);
+
+        m.writerom(0x3EFF, 0x0A);
+        assert_eq!(m.readrom(0x4000), 1);
+        m.writeram(0xA000, 0x00);
+        assert_eq!(m.readram(0xA000), 0xF0);
+        m.writerom(0x0000, 0x00);
+        assert_eq!(m.readram(0xA000), 0xFF);
+
+        // The bank wraps around on a smaller ROM
+        let mut data = m.rom[.. 4 * 0x4000].to_vec();
+        data[0x148] = 0x01;
+        let mut m = MBC2::new(data).unwrap();
+        m.writerom(0x0100, 0x0F);
+        assert_eq!(m.readrom(0x4000), 3);
+    }
+
+    #[test]
+    fn ram() {
+        let mut m = mbc2();
+        m.writeram(0xA000, 0x01);
+        assert_eq!(m.readram(0xA000), 0xFF);
+
+        m.writerom(0x0000, 0x0A);
+        m.writeram(0xA000, 0x12);
+        m.writeram(0xA1FF, 0x34);
+        // Only the lower nibble is stored, and the 512 half-bytes repeat through A000-BFFF
+        assert_eq!(m.readram(0xA000), 0xF2);
+        assert_eq!(m.readram(0xA200), 0xF2);
+        assert_eq!(m.readram(0xBFFF), 0xF4);
+        m.writeram(0xBE00, 0x05);
+        assert_eq!(m.readram(0xA000), 0xF5);
+
+        assert!(m.is_battery_backed());
+        assert_eq!(m.dumpram().len(), 512);
+        assert!(m.check_and_reset_ram_updated());
+    }
+}
@@ -1,15 +1,23 @@
-use crate::mbc::{MBC, ram_banks};
+use crate::mbc::{MBC, poke, ram_banks, rom_banks};
 use crate::StrResult;
+use crate::state::{StateError, StateReader, StateResult, StateWriter};
 
-use std::io::prelude::*;
 use std::time;
 use std::convert::TryInto;
 
+// Save files from BGB and SameBoy append the clock to the RAM: the 5 registers and the 5 latched
+// registers as 32-bit values, then the UNIX time of the save. Older files have a 32-bit time.
+const RTC_BLOCK_SIZE: usize = 48;
+const RTC_BLOCK_SIZE_32BIT: usize = 44;
+// Older saves of this emulator start with the 64-bit rtc_zero instead
+const LEGACY_RTC_SIZE: usize = 8;
+
 pub struct MBC3 {
     rom: Vec<u8>,
     ram: Vec<u8>,
     rombank: usize,
     rambank: usize,
+    rombanks: usize,
     rambanks: usize,
     selectrtc: bool,
     ram_on: bool,
@@ -17,6 +25,8 @@ pub struct MBC3 {
     has_battery: bool,
     rtc_ram: [u8; 5],
     rtc_ram_latch: [u8; 5],
+    // The last value written to 6000-7FFF. Writing 00 then 01 latches the clock.
+    rtc_latch_write: u8,
     rtc_zero: Option<u64>,
 }
 
@@ -27,11 +37,13 @@ impl MBC3 {
             0x0F | 0x10 | 0x13 => true,
             _ => false,
         };
+        // The MBC30 of the Japanese Pokemon Crystal has 64 KiB, which is not supported
         let rambanks = match subtype {
-            0x10 | 0x12 | 0x13 => ram_banks(data[0x149]),
+            0x10 | 0x12 | 0x13 => ram_banks(data[0x149]).min(4),
             _ => 0,
         };
         let ramsize = rambanks * 0x2000;
+        let rombanks = rom_banks(data[0x148]);
         let rtc = match subtype {
             0x0F | 0x10 => Some(0),
             _ => None,
@@ -42,6 +54,7 @@ impl MBC3 {
             ram: ::std::iter::repeat(0u8).take(ramsize).collect(),
             rombank: 1,
             rambank: 0,
+            rombanks,
             rambanks: rambanks,
             selectrtc: false,
             ram_on: false,
@@ -49,6 +62,7 @@ impl MBC3 {
             has_battery: has_battery,
             rtc_ram: [0u8; 5],
             rtc_ram_latch: [0u8; 5],
+            rtc_latch_write: 0xFF,
             rtc_zero: rtc,
         };
 
@@ -61,47 +75,76 @@ impl MBC3 {
     }
 
     fn calc_rtc_reg(&mut self) {
+        let (rtc_ram, overflow) = self.rtc_now();
+        self.rtc_ram = rtc_ram;
+        // Count on from the wrapped day counter
+        if overflow {
+            self.calc_rtc_zero();
+        }
+    }
+
+    // The registers at the current time, and whether the day counter wrapped since rtc_zero
+    fn rtc_now(&self) -> ([u8; 5], bool) {
+        let mut rtc_ram = self.rtc_ram;
+
         // Do not modify regs when halted
-        if self.rtc_ram[4] & 0x40 == 0x40 { return }
+        if rtc_ram[4] & 0x40 == 0x40 { return (rtc_ram, false) }
 
         let tzero = match self.rtc_zero {
             Some(t) => time::UNIX_EPOCH + time::Duration::from_secs(t),
-            None => return,
+            None => return (rtc_ram, false),
         };
 
         if self.compute_difftime() == self.rtc_zero {
             // No time has passed. Do not alter registers
-            return;
+            return (rtc_ram, false);
         }
 
         let difftime = match time::SystemTime::now().duration_since(tzero) {
             Ok(n) => { n.as_secs() },
             _ => { 0 },
         };
-        self.rtc_ram[0] = (difftime % 60) as u8;
-        self.rtc_ram[1] = ((difftime / 60) % 60) as u8;
-        self.rtc_ram[2] = ((difftime / 3600) % 24) as u8;
+        rtc_ram[0] = (difftime % 60) as u8;
+        rtc_ram[1] = ((difftime / 60) % 60) as u8;
+        rtc_ram[2] = ((difftime / 3600) % 24) as u8;
         let days = difftime / (3600*24);
-        self.rtc_ram[3] = days as u8;
-        self.rtc_ram[4] = (self.rtc_ram[4] & 0xFE) | (((days >> 8) & 0x01) as u8);
+        rtc_ram[3] = days as u8;
+        rtc_ram[4] = (rtc_ram[4] & 0xFE) | (((days >> 8) & 0x01) as u8);
         if days >= 512 {
-            self.rtc_ram[4] |= 0x80;
-            self.calc_rtc_zero();
+            rtc_ram[4] |= 0x80;
         }
+        (rtc_ram, days >= 512)
     }
 
     fn compute_difftime(&self) -> Option<u64> {
         if self.rtc_zero.is_none() { return None; }
-        let mut difftime = match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
-            Ok(t) => t.as_secs(),
-            Err(_) => panic!("System clock is set to a time before the unix epoch (1970-01-01)"),
-        };
-        difftime -= self.rtc_ram[0] as u64;
-        difftime -= (self.rtc_ram[1] as u64) * 60;
-        difftime -= (self.rtc_ram[2] as u64) * 3600;
+        Some(unix_time() - self.rtc_seconds())
+    }
+
+    // The time the registers count, in seconds
+    fn rtc_seconds(&self) -> u64 {
         let days = ((self.rtc_ram[4] as u64 & 0x1) << 8) | (self.rtc_ram[3] as u64);
-        difftime -= days * 3600 * 24;
-        Some(difftime)
+        self.rtc_ram[0] as u64 + (self.rtc_ram[1] as u64) * 60 + (self.rtc_ram[2] as u64) * 3600 + days * 3600 * 24
+    }
+
+    // Restores the clock saved at the given UNIX time, and runs it for the time since then unless
+    // it was halted
+    fn load_rtc(&mut self, block: &[u8]) {
+        let register = |i: usize| u32::from_le_bytes(block[i * 4 .. i * 4 + 4].try_into().unwrap()) as u8;
+        for i in 0 .. 5 {
+            self.rtc_ram[i] = register(i) & rtc_mask(i);
+            self.rtc_ram_latch[i] = register(5 + i) & rtc_mask(i);
+        }
+        let saved_at = match block.len() {
+            RTC_BLOCK_SIZE => u64::from_le_bytes(block[40 .. 48].try_into().unwrap()),
+            _ => u32::from_le_bytes(block[40 .. 44].try_into().unwrap()) as u64,
+        };
+
+        self.calc_rtc_zero();
+        if self.rtc_ram[4] & 0x40 == 0 {
+            self.rtc_zero = Some(saved_at.min(unix_time()).saturating_sub(self.rtc_seconds()));
+            self.calc_rtc_reg();
+        }
     }
 
     fn calc_rtc_zero(&mut self) {
@@ -109,17 +152,33 @@ impl MBC3 {
     }
 }
 
+fn unix_time() -> u64 {
+    match time::SystemTime::now().duration_since(time::UNIX_EPOCH) {
+        Ok(t) => t.as_secs(),
+        Err(_) => panic!("System clock is set to a time before the unix epoch (1970-01-01)"),
+    }
+}
+
+// The bits that exist in each clock register
+fn rtc_mask(register: usize) -> u8 {
+    match register {
+        0 | 1 => 0x3F,
+        2 => 0x1F,
+        4 => 0xC1,
+        _ => 0xFF,
+    }
+}
+
 impl MBC for MBC3 {
     fn readrom(&self, a: u16) -> u8 {
-        let idx = if a < 0x4000 { a as usize }
-        else { self.rombank * 0x4000 | ((a as usize) & 0x3FFF) };
+        let idx = (self.rombank_at(a) * 0x4000) | ((a as usize) & 0x3FFF);
         *self.rom.get(idx).unwrap_or(&0xFF)
     }
     fn readram(&self, a: u16) -> u8 {
         if !self.ram_on { return 0xFF }
         if !self.selectrtc && self.rambank < self.rambanks {
             self.ram[self.rambank * 0x2000 | ((a as usize) & 0x1FFF)]
-        } else if self.selectrtc && self.rambank < 5 {
+        } else if self.selectrtc && self.rambank < 5 && self.rtc_zero.is_some() {
             self.rtc_ram_latch[self.rambank]
         } else {
             0xFF
@@ -135,7 +194,12 @@ impl MBC for MBC3 {
                 self.selectrtc = v & 0x8 == 0x8;
                 self.rambank = (v & 0x7) as usize;
             },
-            0x6000 ..= 0x7FFF => self.latch_rtc_reg(),
+            0x6000 ..= 0x7FFF => {
+                if self.rtc_latch_write == 0x00 && v == 0x01 {
+                    self.latch_rtc_reg();
+                }
+                self.rtc_latch_write = v;
+            },
             _ => panic!("Could not write to {:04X} (MBC3)", a),
         }
     }
@@ -144,51 +208,106 @@ impl MBC for MBC3 {
         if !self.selectrtc && self.rambank < self.rambanks {
             self.ram[self.rambank * 0x2000 | ((a as usize) & 0x1FFF)] = v;
             self.ram_updated = true;
-        } else if self.selectrtc && self.rambank < 5 {
+        } else if self.selectrtc && self.rambank < 5 && self.rtc_zero.is_some() {
             self.calc_rtc_reg();
-            let vmask = match self.rambank {
-                0 | 1 => 0x3F,
-                2 => 0x1F,
-                4 => 0xC1,
-                _ => 0xFF,
-            };
-            self.rtc_ram[self.rambank] = v & vmask;
+            self.rtc_ram[self.rambank] = v & rtc_mask(self.rambank);
             self.calc_rtc_zero();
             self.ram_updated = true;
         }
     }
 
+    fn poke_ram(&mut self, offset: usize, v: u8) {
+        self.ram_updated |= poke(&mut self.ram, offset, v);
+    }
+
+    fn peek_ram(&self, offset: usize) -> Option<u8> {
+        self.ram.get(offset).copied()
+    }
+
     fn is_battery_backed(&self) -> bool {
         self.has_battery
     }
 
+    // Loads saves with or without the clock block, and the older saves of this emulator
     fn loadram(&mut self, ramdata: &[u8]) -> StrResult<()> {
-        if ramdata.len() != 8 + self.ram.len() {
-            return Err("Loaded ram is too small");
-        }
-
-        let (int_bytes, rest) = ramdata.split_at(8);
-        let rtc = u64::from_be_bytes(int_bytes.try_into().unwrap());
-        if self.rtc_zero.is_some() {
-            self.rtc_zero = Some(rtc);
+        let ramsize = self.ram.len();
+        match ramdata.len().checked_sub(ramsize) {
+            Some(0) => {
+                self.ram = ramdata.to_vec();
+            },
+            Some(LEGACY_RTC_SIZE) => {
+                let (int_bytes, rest) = ramdata.split_at(LEGACY_RTC_SIZE);
+                let rtc = u64::from_be_bytes(int_bytes.try_into().unwrap());
+                if self.rtc_zero.is_some() {
+                    self.rtc_zero = Some(rtc);
+                }
+                self.ram = rest.to_vec();
+            },
+            Some(RTC_BLOCK_SIZE) | Some(RTC_BLOCK_SIZE_32BIT) => {
+                let (ram, block) = ramdata.split_at(ramsize);
+                self.ram = ram.to_vec();
+                if self.rtc_zero.is_some() {
+                    self.load_rtc(block);
+                }
+            },
+            _ => return Err("Loaded RAM has incorrect length"),
         }
-        self.ram = rest.to_vec();
         Ok(())
     }
 
     fn dumpram(&self) -> Vec<u8> {
-        let rtc = match self.rtc_zero {
-            Some(t) => t,
-            None => 0,
-        };
+        let mut file = self.ram.to_vec();
+        if self.rtc_zero.is_some() {
+            let (rtc_ram, _) = self.rtc_now();
+            for v in rtc_ram.iter().chain(self.rtc_ram_latch.iter()) {
+                file.extend_from_slice(&(*v as u32).to_le_bytes());
+            }
+            file.extend_from_slice(&unix_time().to_le_bytes());
+        }
+        file
+    }
 
-        let mut file = vec![];
+    fn rombank_at(&self, a: u16) -> usize {
+        if a < 0x4000 { 0 } else { self.rombank & self.rombanks.saturating_sub(1) }
+    }
 
-        let mut ok = true;
-        if ok { let rtc_bytes = rtc.to_be_bytes(); ok = file.write_all(&rtc_bytes).is_ok(); };
-        if ok { let _ = file.write_all(&*self.ram); };
+    // The clock keeps counting from the UNIX time in rtc_zero, so it shows the real time after a
+    // load as it does after loading a save file
+    fn save_state(&self, w: &mut StateWriter) {
+        w.vec(&self.ram);
+        w.u8(self.rombank as u8);
+        w.u8(self.rambank as u8);
+        w.bool(self.selectrtc);
+        w.bool(self.ram_on);
+        w.bytes(&self.rtc_ram);
+        w.bytes(&self.rtc_ram_latch);
+        w.u8(self.rtc_latch_write);
+        w.bool(self.rtc_zero.is_some());
+        if let Some(rtc_zero) = self.rtc_zero {
+            w.u64(rtc_zero);
+        }
+    }
 
-        file
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        r.vec_into(&mut self.ram)?;
+        self.rombank = (r.u8()? & 0x7F) as usize;
+        self.rambank = (r.u8()? & 0x07) as usize;
+        self.selectrtc = r.bool()?;
+        self.ram_on = r.bool()?;
+        r.bytes_into(&mut self.rtc_ram)?;
+        r.bytes_into(&mut self.rtc_ram_latch)?;
+        for i in 0 .. 5 {
+            self.rtc_ram[i] &= rtc_mask(i);
+            self.rtc_ram_latch[i] &= rtc_mask(i);
+        }
+        self.rtc_latch_write = r.u8()?;
+        // Only a cartridge with a clock has one in its states
+        self.rtc_zero = match (r.bool()?, self.rtc_zero.is_some()) {
+            (true, true) => Some(r.u64()?),
+            (false, false) => None,
+            _ => return Err(StateError::Corrupt),
+        };
+        Ok(())
     }
 
     fn check_and_reset_ram_updated(&mut self) -> bool {
@@ -197,3 +316,234 @@ impl MBC for MBC3 {
         result
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::MBC3;
+    use crate::mbc::MBC;
+    use std::time;
+
+    const DAY: u64 = 24 * 3600;
+
+    fn mbc3_rtc() -> MBC3 {
+        let mut data = vec![0; 0x8000];
+        data[0x147] = 0x10;
+        data[0x149] = 0x03;
+        let mut m = MBC3::new(data).unwrap();
+        m.writerom(0x0000, 0x0A);
+        m
+    }
+
+    fn now() -> u64 {
+        time::SystemTime::now().duration_since(time::UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    // The RAM followed by the clock registers, the latched registers and the time of the save
+    fn save(rtc_ram: [u8; 5], rtc_ram_latch: [u8; 5], saved_at: u64) -> Vec<u8> {
+        let mut file = vec![0x55; 4 * 0x2000];
+        for v in rtc_ram.iter().chain(rtc_ram_latch.iter()) {
+            file.extend_from_slice(&(*v as u32).to_le_bytes());
+        }
+        file.extend_from_slice(&saved_at.to_le_bytes());
+        file
+    }
+
+    fn latch(m: &mut MBC3) {
+        m.writerom(0x6000, 0x00);
+        m.writerom(0x6000, 0x01);
+    }
+
+    fn read_rtc(m: &mut MBC3, register: u8) -> u8 {
+        m.writerom(0x4000, register);
+        m.readram(0xA000)
+    }
+
+    #[test]
+    fn latch_sequence() {
+        let mut m = mbc3_rtc();
+        m.rtc_zero = Some(now() - (2 * 3600 + 3 * 60 + 4));
+        assert_eq!(read_rtc(&mut m, 0x08), 0);
+
+        m.writerom(0x6000, 0x01);
+        assert_eq!(read_rtc(&mut m, 0x08), 0);
+        latch(&mut m);
+        assert_eq!(read_rtc(&mut m, 0x0A), 2);
+        assert_eq!(read_rtc(&mut m, 0x09), 3);
+        assert!((4 ..= 5).contains(&read_rtc(&mut m, 0x08)));
+
+        // The latched values stay until the next latch
+        m.rtc_zero = Some(now() - 10 * 3600);
+        assert_eq!(read_rtc(&mut m, 0x0A), 2);
+        m.writerom(0x6000, 0x00);
+        m.writerom(0x6000, 0x02);
+        m.writerom(0x6000, 0x01);
+        assert_eq!(read_rtc(&mut m, 0x0A), 2);
+        latch(&mut m);
+        assert_eq!(read_rtc(&mut m, 0x0A), 10);
+    }
+
+    #[test]
+    fn day_carry() {
+        let mut m = mbc3_rtc();
+        m.rtc_zero = Some(now() - 300 * DAY);
+        latch(&mut m);
+        assert_eq!(read_rtc(&mut m, 0x0B), (300 & 0xFF) as u8);
+        assert_eq!(read_rtc(&mut m, 0x0C), 0x01);
+
+        m.rtc_zero = Some(now() - 513 * DAY);
+        latch(&mut m);
+        assert_eq!(read_rtc(&mut m, 0x0B), 1);
+        assert_eq!(read_rtc(&mut m, 0x0C), 0x80);
+
+        // The carry stays set until it is written
+        latch(&mut m);
+        assert_eq!(read_rtc(&mut m, 0x0C), 0x80);
+        m.writeram(0xA000, 0x00);
+        latch(&mut m);
+        assert_eq!(read_rtc(&mut m, 0x0C), 0x00);
+    }
+
+    #[test]
+    fn halt() {
+        let mut m = mbc3_rtc();
+        m.writerom(0x4000, 0x0A);
+        m.writeram(0xA000, 0x05);
+        m.writerom(0x4000, 0x0C);
+        m.writeram(0xA000, 0x40);
+
+        // Time passing while halted is not counted
+        m.rtc_zero = m.rtc_zero.map(|t| t - 3600);
+        latch(&mut m);
+        assert_eq!(read_rtc(&mut m, 0x0A), 5);
+        assert_eq!(read_rtc(&mut m, 0x0C), 0x40);
+
+        m.writeram(0xA000, 0x00);
+        latch(&mut m);
+        assert_eq!(read_rtc(&mut m, 0x0A), 5);
+        m.rtc_zero = m.rtc_zero.map(|t| t - 3600);
+        latch(&mut m);
+        assert_eq!(read_rtc(&mut m, 0x0A), 6);
+    }
+
+    #[test]
+    fn ram_and_rom_banks() {
+        let mut data = vec![0; 128 * 0x4000];
+        for bank in 0 .. 128 {
+            data[bank * 0x4000] = bank as u8;
+        }
+        data[0x147] = 0x13;
+        data[0x148] = 0x06;
+        data[0x149] = 0x03;
+        let mut m = MBC3::new(data).unwrap();
+        m.writerom(0x2000, 0x00);
+        assert_eq!(m.readrom(0x4000), 1);
+        m.writerom(0x2000, 0xFF);
+        assert_eq!(m.readrom(0x4000), 0x7F);
+
+        m.writeram(0xA000, 0x11);
+        assert_eq!(m.readram(0xA000), 0xFF);
+        m.writerom(0x0000, 0x0A);
+        for bank in 0 .. 4 {
+            m.writerom(0x4000, bank);
+            m.writeram(0xA000, bank + 1);
+        }
+        for bank in 0 .. 4 {
+            m.writerom(0x4000, bank);
+            assert_eq!(m.readram(0xA000), bank + 1);
+        }
+        // No clock on this cartridge
+        assert_eq!(read_rtc(&mut m, 0x08), 0xFF);
+    }
+
+    #[test]
+    fn bank_masking() {
+        // 64 KiB without RAM
+        let mut data = vec![0; 4 * 0x4000];
+        for bank in 0 .. 4 {
+            data[bank * 0x4000] = bank as u8;
+        }
+        data[0x147] = 0x11;
+        data[0x148] = 0x01;
+        let mut m = MBC3::new(data).unwrap();
+        m.writerom(0x2000, 0x7F);
+        assert_eq!(m.readrom(0x4000), 3);
+        m.writerom(0x2000, 0x06);
+        assert_eq!(m.readrom(0x4000), 2);
+
+        m.writerom(0x0000, 0x0A);
+        m.writerom(0x4000, 0x03);
+        m.writeram(0xA000, 0x11);
+        assert_eq!(m.readram(0xA000), 0xFF);
+        assert!(m.dumpram().is_empty());
+        assert!(!m.check_and_reset_ram_updated());
+    }
+
+    #[test]
+    fn load_rtc_block() {
+        let mut m = mbc3_rtc();
+        m.loadram(&save([0, 0, 0, 0, 0], [1, 2, 3, 4, 0], now() - 90000)).unwrap();
+        assert_eq!(m.dumpram()[0], 0x55);
+        // The latched registers are kept until the next latch
+        assert_eq!(read_rtc(&mut m, 0x09), 2);
+        latch(&mut m);
+        assert_eq!(read_rtc(&mut m, 0x0B), 1);
+        assert_eq!(read_rtc(&mut m, 0x0A), 1);
+        assert_eq!(read_rtc(&mut m, 0x09), 0);
+
+        // The day counter wraps into the carry while the emulator is closed
+        let mut m = mbc3_rtc();
+        m.loadram(&save([0, 0, 12, 0xFF, 0x01], [0; 5], now() - 86400)).unwrap();
+        latch(&mut m);
+        assert_eq!(read_rtc(&mut m, 0x0B), 0);
+        assert_eq!(read_rtc(&mut m, 0x0C), 0x80);
+        assert_eq!(read_rtc(&mut m, 0x0A), 12);
+
+        // A halted clock does not run
+        let mut m = mbc3_rtc();
+        m.loadram(&save([5, 6, 7, 8, 0x41], [0; 5], now() - 90000)).unwrap();
+        latch(&mut m);
+        assert_eq!(read_rtc(&mut m, 0x08), 5);
+        assert_eq!(read_rtc(&mut m, 0x0B), 8);
+        assert_eq!(read_rtc(&mut m, 0x0C), 0x41);
+
+        // 32-bit timestamp
+        let mut file = save([0; 5], [0; 5], 0);
+        file.truncate(file.len() - 8);
+        file.extend_from_slice(&((now() - 3600) as u32).to_le_bytes());
+        let mut m = mbc3_rtc();
+        m.loadram(&file).unwrap();
+        latch(&mut m);
+        assert_eq!(read_rtc(&mut m, 0x0A), 1);
+    }
+
+    #[test]
+    fn save_round_trip() {
+        let mut m = mbc3_rtc();
+        m.rtc_zero = Some(now() - (3 * DAY + 4 * 3600));
+        latch(&mut m);
+        m.writerom(0x4000, 0x00);
+        m.writeram(0xA000, 0x12);
+        let file = m.dumpram();
+        assert_eq!(file.len(), 4 * 0x2000 + 48);
+
+        let mut loaded = mbc3_rtc();
+        loaded.loadram(&file).unwrap();
+        assert_eq!(loaded.rtc_ram_latch, m.rtc_ram_latch);
+        latch(&mut loaded);
+        assert_eq!(read_rtc(&mut loaded, 0x0B), 3);
+        assert_eq!(read_rtc(&mut loaded, 0x0A), 4);
+        loaded.writerom(0x4000, 0x00);
+        assert_eq!(loaded.readram(0xA000), m.readram(0xA000));
+
+        // Saves without a clock, and the older saves of this emulator
+        let mut plain = mbc3_rtc();
+        plain.loadram(&file[.. 4 * 0x2000]).unwrap();
+        let mut legacy = (now() - 2 * DAY).to_be_bytes().to_vec();
+        legacy.extend_from_slice(&file[.. 4 * 0x2000]);
+        let mut m = mbc3_rtc();
+        m.loadram(&legacy).unwrap();
+        latch(&mut m);
+        assert_eq!(read_rtc(&mut m, 0x0B), 2);
+        assert!(m.loadram(&file[1 ..]).is_err());
+    }
+}
@@ -1,14 +1,19 @@
-use crate::mbc::{MBC, ram_banks, rom_banks};
+use crate::mbc::{MBC, poke, ram_banks, rom_banks};
 use crate::StrResult;
+use crate::state::{StateReader, StateResult, StateWriter};
 
 pub struct MBC5 {
     rom: Vec<u8>,
     ram: Vec<u8>,
+    // The 9-bit ROM bank register, where 0 selects bank 0
     rombank: usize,
+    // The 4-bit RAM bank register
     rambank: usize,
     ram_on: bool,
     ram_updated:bool,
     has_battery: bool,
+    // On a rumble cartridge bit 3 of the RAM bank register drives the motor
+    has_rumble: bool,
     rombanks: usize,
     rambanks: usize,
 }
@@ -26,6 +31,13 @@ impl MBC5 {
         };
         let ramsize = 0x2000 * rambanks;
         let rombanks = rom_banks(data[0x148]);
+        if rombanks == 0 {
+            return Err("Unsupported ROM size in the cartridge header");
+        }
+        if data.len() != rombanks * 0x4000 {
+            return Err("ROM file size does not match the size in the cartridge header");
+        }
+        let has_rumble = matches!(subtype, 0x1C ..= 0x1E);
 
         let res = MBC5 {
             rom: data,
@@ -35,38 +47,58 @@ impl MBC5 {
             ram_updated: false,
             ram_on: false,
             has_battery: has_battery,
+            has_rumble,
             rombanks: rombanks,
             rambanks: rambanks,
         };
 
         Ok(res)
     }
+
+    // Banks past the end of the RAM mirror the ones before
+    fn ram_address(&self, a: u16) -> usize {
+        let rambank = match self.has_rumble {
+            true => self.rambank & 0x07,
+            false => self.rambank,
+        };
+        ((rambank & self.rambanks.saturating_sub(1)) * 0x2000) | ((a & 0x1FFF) as usize)
+    }
 }
 
 impl MBC for MBC5 {
     fn readrom(&self, a: u16) -> u8 {
-        let idx = if a < 0x4000 { a as usize }
-        else { self.rombank * 0x4000 | ((a as usize) & 0x3FFF) };
-        *self.rom.get(idx).unwrap_or(&0)
+        let idx = (self.rombank_at(a) * 0x4000) | ((a as usize) & 0x3FFF);
+        *self.rom.get(idx).unwrap_or(&0xFF)
     }
     fn readram(&self, a: u16) -> u8 {
-        if !self.ram_on { return 0 }
-        self.ram[self.rambank * 0x2000 | ((a as usize) & 0x1FFF)]
+        if !self.ram_on { return 0xFF }
+        *self.ram.get(self.ram_address(a)).unwrap_or(&0xFF)
     }
     fn writerom(&mut self, a: u16, v: u8) {
         match a {
             0x0000 ..= 0x1FFF => self.ram_on = v & 0x0F == 0x0A,
-            0x2000 ..= 0x2FFF => self.rombank = ((self.rombank & 0x100) | (v as usize)) % self.rombanks,
-            0x3000 ..= 0x3FFF => self.rombank = ((self.rombank & 0x0FF) | (((v & 0x1) as usize) << 8)) % self.rombanks,
-            0x4000 ..= 0x5FFF => self.rambank = ((v & 0x0F) as usize) % self.rambanks,
-            0x6000 ..= 0x7FFF => { /* ? */ },
+            0x2000 ..= 0x2FFF => self.rombank = (self.rombank & 0x100) | (v as usize),
+            0x3000 ..= 0x3FFF => self.rombank = (self.rombank & 0x0FF) | (((v & 0x1) as usize) << 8),
+            0x4000 ..= 0x5FFF => self.rambank = (v & 0x0F) as usize,
+            0x6000 ..= 0x7FFF => {},
             _ => panic!("Could not write to {:04X} (MBC5)", a),
         }
     }
     fn writeram(&mut self, a: u16, v: u8) {
         if self.ram_on == false { return }
-        self.ram[self.rambank * 0x2000 | ((a as usize) & 0x1FFF)] = v;
-        self.ram_updated = true;
+        let address = self.ram_address(a);
+        if address < self.ram.len() {
+            self.ram[address] = v;
+            self.ram_updated = true;
+        }
+    }
+
+    fn poke_ram(&mut self, offset: usize, v: u8) {
+        self.ram_updated |= poke(&mut self.ram, offset, v);
+    }
+
+    fn peek_ram(&self, offset: usize) -> Option<u8> {
+        self.ram.get(offset).copied()
     }
 
     fn is_battery_backed(&self) -> bool {
@@ -87,9 +119,149 @@ impl MBC for MBC5 {
         self.ram.to_vec()
     }
 
+    // Only the address lines of a power of two sized ROM are connected, so the banks past its end
+    // mirror the ones before
+    fn rombank_at(&self, a: u16) -> usize {
+        if a < 0x4000 { 0 }
+        else { self.rombank & (self.rombanks - 1) }
+    }
+
+    fn rumble_active(&self) -> bool {
+        self.has_rumble && self.rambank & 0x08 != 0
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.vec(&self.ram);
+        w.u16(self.rombank as u16);
+        w.u8(self.rambank as u8);
+        w.bool(self.ram_on);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        r.vec_into(&mut self.ram)?;
+        self.rombank = (r.u16()? & 0x1FF) as usize;
+        self.rambank = (r.u8()? & 0x0F) as usize;
+        self.ram_on = r.bool()?;
+        Ok(())
+    }
+
     fn check_and_reset_ram_updated(&mut self) -> bool {
         let result = self.ram_updated;
         self.ram_updated = false;
         result
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::MBC5;
+    use crate::mbc::MBC;
+
+    // Every bank starts with its number, the upper bit in the second byte
+    fn mbc5(subtype: u8, rom_size: u8, ram_size: u8) -> MBC5 {
+        let banks = 2 << rom_size;
+        let mut data = vec![0; banks * 0x4000];
+        for bank in 0 .. banks {
+            data[bank * 0x4000] = bank as u8;
+            data[bank * 0x4000 + 1] = (bank >> 8) as u8;
+        }
+        data[0x147] = subtype;
+        data[0x148] = rom_size;
+        data[0x149] = ram_size;
+        MBC5::new(data).unwrap()
+    }
+
+    #[test]
+    fn rom_banks() {
+        // 8 MiB
+        let mut m = mbc5(0x1B, 0x08, 0x03);
+        assert_eq!(m.readrom(0x4000), 1);
+        m.writerom(0x2000, 0x00);
+        assert_eq!((m.readrom(0x4000), m.readrom(0x4001)), (0, 0));
+        m.writerom(0x3000, 0x01);
+        assert_eq!((m.readrom(0x4000), m.readrom(0x4001)), (0, 1));
+        m.writerom(0x2FFF, 0xAB);
+        assert_eq!((m.readrom(0x4000), m.readrom(0x4001)), (0xAB, 1));
+        assert_eq!(m.readrom(0x0000), 0);
+
+        // The ninth bit is kept, and mirrors on a 1 MiB ROM
+        let mut m = mbc5(0x19, 0x05, 0x00);
+        m.writerom(0x3000, 0x01);
+        m.writerom(0x2000, 0x45);
+        assert_eq!((m.readrom(0x4000), m.readrom(0x4001)), (0x05, 0));
+    }
+
+    #[test]
+    fn bank_300() {
+        let mut data = vec![0; 512 * 0x4000];
+        data[0x147] = 0x19;
+        data[0x148] = 0x08;
+        data[300 * 0x4000] = 0x12;
+        data[300 * 0x4000 + 0x3FFF] = 0x34;
+        data[44 * 0x4000] = 0x56;
+        let mut m = MBC5::new(data).unwrap();
+        m.writerom(0x2000, (300 & 0xFF) as u8);
+        assert_eq!(m.readrom(0x4000), 0x56);
+        m.writerom(0x3000, (300 >> 8) as u8);
+        assert_eq!(m.readrom(0x4000), 0x12);
+        assert_eq!(m.readrom(0x7FFF), 0x34);
+    }
+
+    #[test]
+    fn rom_size_mismatch() {
+        let mut data = vec![0; 0x8000];
+        data[0x147] = 0x19;
+        data[0x148] = 0x01;
+        assert!(MBC5::new(data.clone()).is_err());
+        data[0x148] = 0x0A;
+        assert!(MBC5::new(data.clone()).is_err());
+        data[0x148] = 0x00;
+        assert!(MBC5::new(data).is_ok());
+    }
+
+    #[test]
+    fn ram_banks() {
+        let mut m = mbc5(0x1B, 0x00, 0x04);
+        m.writeram(0xA000, 0x11);
+        assert_eq!(m.readram(0xA000), 0xFF);
+
+        m.writerom(0x0000, 0x0A);
+        for bank in 0 .. 16 {
+            m.writerom(0x4000, bank);
+            m.writeram(0xBFFF, bank + 1);
+        }
+        assert_eq!(m.dumpram()[15 * 0x2000 + 0x1FFF], 16);
+        m.writerom(0x4000, 0x03);
+        assert_eq!(m.readram(0xBFFF), 4);
+        assert!(!m.rumble_active());
+
+        m.writerom(0x0000, 0x00);
+        assert_eq!(m.readram(0xBFFF), 0xFF);
+
+        let mut m = mbc5(0x19, 0x00, 0x00);
+        m.writerom(0x0000, 0x0A);
+        m.writerom(0x4000, 0x01);
+        m.writeram(0xA000, 0x11);
+        assert_eq!(m.readram(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn rumble() {
+        let mut m = mbc5(0x1E, 0x00, 0x03);
+        m.writerom(0x0000, 0x0A);
+        m.writerom(0x4000, 0x00);
+        m.writeram(0xA000, 0x11);
+        m.writerom(0x4000, 0x01);
+        m.writeram(0xA000, 0x22);
+        assert!(!m.rumble_active());
+
+        // The motor bit does not select a RAM bank
+        m.writerom(0x4000, 0x08);
+        assert!(m.rumble_active());
+        assert_eq!(m.readram(0xA000), 0x11);
+        m.writerom(0x4000, 0x09);
+        assert_eq!(m.readram(0xA000), 0x22);
+        m.writerom(0x4000, 0x01);
+        assert!(!m.rumble_active());
+    }
+}
@@ -0,0 +1,464 @@
+use crate::mbc::{MBC, rom_banks};
+use crate::StrResult;
+use crate::state::{StateError, StateReader, StateResult, StateWriter};
+
+// The accelerometer reads this when level, and about this much more per g
+const ACCELEROMETER_CENTER: f32 = 0x81D0 as f32;
+const ACCELEROMETER_PER_G: f32 = 0x70 as f32;
+// An erased accelerometer latch
+const ACCELEROMETER_ERASED: u16 = 0x8000;
+
+// A 93LC56 EEPROM of 128 16-bit words, clocked one bit at a time through A080
+enum EepromState {
+    // Waiting for a start bit, then shifting in the 2-bit opcode and 8-bit address
+    Command,
+    // Shifting out the words from the address on
+    Read,
+    // Shifting in the word to write to the address, or to all words
+    Write { all: bool },
+    // Ignoring the clock until the chip is deselected
+    Done,
+}
+
+struct Eeprom {
+    data: Vec<u8>,
+    state: EepromState,
+    write_enabled: bool,
+    shift: u16,
+    bits: u8,
+    address: usize,
+    cs: bool,
+    clk: bool,
+    di: bool,
+    dout: bool,
+    updated: bool,
+}
+
+impl Eeprom {
+    fn new() -> Eeprom {
+        Eeprom {
+            data: vec![0xFF; 256],
+            state: EepromState::Command,
+            write_enabled: false,
+            shift: 0,
+            bits: 0,
+            address: 0,
+            cs: false,
+            clk: false,
+            di: false,
+            dout: true,
+            updated: false,
+        }
+    }
+
+    fn read(&self) -> u8 {
+        (self.cs as u8) << 7 | (self.clk as u8) << 6 | (self.di as u8) << 1 | self.dout as u8
+    }
+
+    // Bits 7, 6 and 1 are the chip select, clock and data in pins
+    fn write(&mut self, v: u8) {
+        let (cs, clk, di) = (v & 0x80 != 0, v & 0x40 != 0, v & 0x02 != 0);
+        if !cs {
+            self.state = EepromState::Command;
+            self.bits = 0;
+            self.dout = true;
+        }
+        else if self.cs && clk && !self.clk {
+            self.clock(di);
+        }
+        self.cs = cs;
+        self.clk = clk;
+        self.di = di;
+    }
+
+    fn word(&self, address: usize) -> u16 {
+        u16::from_le_bytes([self.data[address * 2], self.data[address * 2 + 1]])
+    }
+
+    fn set_word(&mut self, address: usize, v: u16) {
+        self.data[address * 2 .. address * 2 + 2].copy_from_slice(&v.to_le_bytes());
+        self.updated = true;
+    }
+
+    fn clock(&mut self, di: bool) {
+        match self.state {
+            EepromState::Command => {
+                if self.bits == 0 && !di {
+                    return;
+                }
+                self.shift = (self.shift << 1) | di as u16;
+                self.bits += 1;
+                if self.bits == 11 {
+                    self.bits = 0;
+                    self.command(self.shift & 0x3FF);
+                }
+            },
+            EepromState::Read => {
+                self.dout = self.shift & 0x8000 != 0;
+                self.shift <<= 1;
+                self.bits += 1;
+                if self.bits == 16 {
+                    self.bits = 0;
+                    self.address = (self.address + 1) & 0x7F;
+                    self.shift = self.word(self.address);
+                }
+            },
+            EepromState::Write { all } => {
+                self.shift = (self.shift << 1) | di as u16;
+                self.bits += 1;
+                if self.bits == 16 {
+                    if self.write_enabled {
+                        match all {
+                            true => for address in 0 .. 128 { self.set_word(address, self.shift) },
+                            false => self.set_word(self.address, self.shift),
+                        }
+                    }
+                    // Writes finish immediately, so the chip is never busy
+                    self.dout = true;
+                    self.state = EepromState::Done;
+                }
+            },
+            EepromState::Done => {},
+        }
+    }
+
+    fn command(&mut self, command: u16) {
+        self.address = (command & 0x7F) as usize;
+        self.state = EepromState::Done;
+        self.shift = 0;
+        match (command >> 8, (command >> 6) & 0x03) {
+            // READ, starting with a dummy 0
+            (0b10, _) => {
+                self.dout = false;
+                self.shift = self.word(self.address);
+                self.state = EepromState::Read;
+            },
+            // WRITE
+            (0b01, _) => self.state = EepromState::Write { all: false },
+            // ERASE
+            (0b11, _) => {
+                if self.write_enabled {
+                    self.set_word(self.address, 0xFFFF);
+                }
+            },
+            // EWEN
+            (0b00, 0b11) => self.write_enabled = true,
+            // EWDS
+            (0b00, 0b00) => self.write_enabled = false,
+            // ERAL
+            (0b00, 0b10) => {
+                if self.write_enabled {
+                    for address in 0 .. 128 {
+                        self.set_word(address, 0xFFFF);
+                    }
+                }
+            },
+            // WRAL
+            _ => self.state = EepromState::Write { all: true },
+        }
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.vec(&self.data);
+        w.u8(match self.state {
+            EepromState::Command => 0,
+            EepromState::Read => 1,
+            EepromState::Write { all: false } => 2,
+            EepromState::Write { all: true } => 3,
+            EepromState::Done => 4,
+        });
+        w.bool(self.write_enabled);
+        w.u16(self.shift);
+        w.u8(self.bits);
+        w.u8(self.address as u8);
+        w.u8(self.read());
+        w.bool(self.dout);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        r.vec_into(&mut self.data)?;
+        self.state = match r.u8()? {
+            0 => EepromState::Command,
+            1 => EepromState::Read,
+            2 => EepromState::Write { all: false },
+            3 => EepromState::Write { all: true },
+            4 => EepromState::Done,
+            _ => return Err(StateError::Corrupt),
+        };
+        self.write_enabled = r.bool()?;
+        self.shift = r.u16()?;
+        self.bits = r.u8()? % 16;
+        self.address = (r.u8()? & 0x7F) as usize;
+        let pins = r.u8()?;
+        self.cs = pins & 0x80 != 0;
+        self.clk = pins & 0x40 != 0;
+        self.di = pins & 0x02 != 0;
+        self.dout = r.bool()?;
+        Ok(())
+    }
+}
+
+// The MBC7 has a 2-axis accelerometer and an EEPROM instead of RAM, both mapped as registers at
+// A000-AFFF once they are enabled by writes to 0000-1FFF and 4000-5FFF
+pub struct MBC7 {
+    rom: Vec<u8>,
+    rombank: usize,
+    rombanks: usize,
+    ram_on: bool,
+    ram_on2: bool,
+    accelerometer: (f32, f32),
+    latch: (u16, u16),
+    latch_erased: bool,
+    eeprom: Eeprom,
+}
+
+impl MBC7 {
+    pub fn new(data: Vec<u8>) -> StrResult<MBC7> {
+        let rombanks = rom_banks(data[0x148]);
+
+        let res = MBC7 {
+            rom: data,
+            rombank: 1,
+            rombanks,
+            ram_on: false,
+            ram_on2: false,
+            accelerometer: (0.0, 0.0),
+            latch: (ACCELEROMETER_ERASED, ACCELEROMETER_ERASED),
+            latch_erased: false,
+            eeprom: Eeprom::new(),
+        };
+
+        Ok(res)
+    }
+}
+
+fn accelerometer_value(g: f32) -> u16 {
+    (ACCELEROMETER_CENTER + ACCELEROMETER_PER_G * g).round().clamp(0.0, u16::MAX as f32) as u16
+}
+
+impl MBC for MBC7 {
+    fn readrom(&self, a: u16) -> u8 {
+        let idx = (self.rombank_at(a) * 0x4000) | ((a as usize) & 0x3FFF);
+        *self.rom.get(idx).unwrap_or(&0xFF)
+    }
+
+    fn readram(&self, a: u16) -> u8 {
+        if !self.ram_on || !self.ram_on2 || a >= 0xB000 { return 0xFF }
+        match (a >> 4) & 0x0F {
+            0x2 => self.latch.0 as u8,
+            0x3 => (self.latch.0 >> 8) as u8,
+            0x4 => self.latch.1 as u8,
+            0x5 => (self.latch.1 >> 8) as u8,
+            0x6 => 0x00,
+            0x8 => self.eeprom.read(),
+            _ => 0xFF,
+        }
+    }
+
+    fn writerom(&mut self, a: u16, v: u8) {
+        match a {
+            0x0000 ..= 0x1FFF => { self.ram_on = v == 0x0A; },
+            0x2000 ..= 0x3FFF => { self.rombank = v as usize; },
+            0x4000 ..= 0x5FFF => { self.ram_on2 = v == 0x40; },
+            0x6000 ..= 0x7FFF => {},
+            _ => panic!("Could not write to {:04X} (MBC7)", a),
+        }
+    }
+
+    fn writeram(&mut self, a: u16, v: u8) {
+        if !self.ram_on || !self.ram_on2 || a >= 0xB000 { return }
+        match (a >> 4) & 0x0F {
+            0x0 if v == 0x55 => {
+                self.latch = (ACCELEROMETER_ERASED, ACCELEROMETER_ERASED);
+                self.latch_erased = true;
+            },
+            0x1 if v == 0xAA && self.latch_erased => {
+                self.latch = (accelerometer_value(self.accelerometer.0), accelerometer_value(self.accelerometer.1));
+                self.latch_erased = false;
+            },
+            0x8 => self.eeprom.write(v),
+            _ => {},
+        }
+    }
+
+    fn is_battery_backed(&self) -> bool {
+        true
+    }
+
+    fn loadram(&mut self, ramdata: &[u8]) -> StrResult<()> {
+        if ramdata.len() != self.eeprom.data.len() {
+            return Err("Loaded EEPROM has incorrect length");
+        }
+
+        self.eeprom.data = ramdata.to_vec();
+
+        Ok(())
+    }
+
+    fn dumpram(&self) -> Vec<u8> {
+        self.eeprom.data.to_vec()
+    }
+
+    fn rombank_at(&self, a: u16) -> usize {
+        match a {
+            0x0000 ..= 0x3FFF => 0,
+            _ => self.rombank & self.rombanks.saturating_sub(1),
+        }
+    }
+
+    fn set_accelerometer(&mut self, x: f32, y: f32) {
+        self.accelerometer = (x, y);
+    }
+
+    // The tilt is left as the frontend set it
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.rombank as u8);
+        w.bool(self.ram_on);
+        w.bool(self.ram_on2);
+        w.u16(self.latch.0);
+        w.u16(self.latch.1);
+        w.bool(self.latch_erased);
+        self.eeprom.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.rombank = r.u8()? as usize;
+        self.ram_on = r.bool()?;
+        self.ram_on2 = r.bool()?;
+        self.latch = (r.u16()?, r.u16()?);
+        self.latch_erased = r.bool()?;
+        self.eeprom.load_state(r)
+    }
+
+    fn check_and_reset_ram_updated(&mut self) -> bool {
+        let result = self.eeprom.updated;
+        self.eeprom.updated = false;
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::MBC7;
+    use crate::mbc::MBC;
+
+    fn mbc7() -> MBC7 {
+        let mut data = vec![0; 0x10 * 0x4000];
+        for bank in 0 .. 0x10 {
+            data[bank * 0x4000] = bank as u8;
+        }
+        data[0x147] = 0x22;
+        data[0x148] = 0x03;
+        let mut m = MBC7::new(data).unwrap();
+        m.writerom(0x0000, 0x0A);
+        m.writerom(0x4000, 0x40);
+        m
+    }
+
+    fn latch(m: &mut MBC7) -> (u16, u16) {
+        m.writeram(0xA000, 0x55);
+        m.writeram(0xA010, 0xAA);
+        let byte = |m: &MBC7, a| m.readram(a) as u16;
+        (byte(m, 0xA020) | byte(m, 0xA030) << 8, byte(m, 0xA040) | byte(m, 0xA050) << 8)
+    }
+
+    // Clocks the bits in with chip select held, and returns what DO reads after each
+    fn send(m: &mut MBC7, bits: &[u8]) -> Vec<u8> {
+        bits.iter().map(|&bit| {
+            m.writeram(0xA080, 0x80 | (bit << 1));
+            m.writeram(0xA080, 0xC0 | (bit << 1));
+            m.readram(0xA080) & 0x01
+        }).collect()
+    }
+
+    fn bits(v: u32, n: usize) -> Vec<u8> {
+        (0 .. n).rev().map(|i| ((v >> i) & 1) as u8).collect()
+    }
+
+    fn deselect(m: &mut MBC7) {
+        m.writeram(0xA080, 0x00);
+    }
+
+    fn read_word(m: &mut MBC7, address: u32) -> u16 {
+        send(m, &bits(0b110 << 8 | address, 11));
+        let out = send(m, &[0; 16]);
+        deselect(m);
+        out.iter().fold(0, |word, &bit| (word << 1) | bit as u16)
+    }
+
+    #[test]
+    fn rom_banks() {
+        let mut m = mbc7();
+        assert_eq!(m.readrom(0x4000), 1);
+        m.writerom(0x2000, 0x0F);
+        assert_eq!(m.readrom(0x4000), 0x0F);
+        m.writerom(0x2000, 0x00);
+        assert_eq!(m.readrom(0x4000), 0);
+    }
+
+    #[test]
+    fn accelerometer() {
+        let mut m = mbc7();
+        assert_eq!(latch(&mut m), (0x81D0, 0x81D0));
+        m.set_accelerometer(1.0, -0.5);
+        assert_eq!(latch(&mut m), (0x8240, 0x8198));
+
+        // Latching again needs an erase first
+        m.set_accelerometer(0.0, 0.0);
+        m.writeram(0xA010, 0xAA);
+        assert_eq!(m.readram(0xA020), 0x40);
+        m.writeram(0xA000, 0x55);
+        assert_eq!((m.readram(0xA020), m.readram(0xA030)), (0x00, 0x80));
+
+        m.writerom(0x4000, 0x00);
+        assert_eq!(m.readram(0xA020), 0xFF);
+    }
+
+    #[test]
+    fn eeprom() {
+        let mut m = mbc7();
+        assert_eq!(read_word(&mut m, 5), 0xFFFF);
+
+        // Writes are ignored until enabled
+        send(&mut m, &bits(0b101 << 8 | 5, 11));
+        send(&mut m, &bits(0x1234, 16));
+        deselect(&mut m);
+        assert_eq!(read_word(&mut m, 5), 0xFFFF);
+        assert!(!m.check_and_reset_ram_updated());
+
+        send(&mut m, &bits(0b100_1100_0000, 11));
+        deselect(&mut m);
+        // Leading zeros before the start bit are skipped
+        send(&mut m, &[0, 0]);
+        send(&mut m, &bits(0b101 << 8 | 5, 11));
+        assert_eq!(send(&mut m, &bits(0x1234, 16))[15], 1);
+        deselect(&mut m);
+        assert_eq!(read_word(&mut m, 5), 0x1234);
+        assert_eq!(&m.dumpram()[10 .. 12], &[0x34, 0x12]);
+        assert!(m.check_and_reset_ram_updated());
+
+        // A read continues with the next words, after a dummy 0
+        send(&mut m, &bits(0b110 << 8 | 4, 11));
+        assert_eq!(m.readram(0xA080) & 0x01, 0);
+        let out = send(&mut m, &[0; 32]);
+        deselect(&mut m);
+        assert_eq!(out.iter().fold(0u32, |word, &bit| (word << 1) | bit as u32), 0xFFFF1234);
+
+        send(&mut m, &bits(0b111 << 8 | 5, 11));
+        deselect(&mut m);
+        assert_eq!(read_word(&mut m, 5), 0xFFFF);
+
+        send(&mut m, &bits(0b100_0100_0000, 11));
+        send(&mut m, &bits(0xABCD, 16));
+        deselect(&mut m);
+        assert_eq!(read_word(&mut m, 0x7F), 0xABCD);
+        send(&mut m, &bits(0b100_1000_0000, 11));
+        deselect(&mut m);
+        assert_eq!(read_word(&mut m, 0x00), 0xFFFF);
+
+        let saved = m.dumpram();
+        let mut loaded = mbc7();
+        loaded.loadram(&saved).unwrap();
+        assert_eq!(loaded.dumpram(), saved);
+    }
+}
@@ -1,7 +1,6 @@
 use crate::StrResult;
-use std::io;
-use std::io::prelude::*;
-use std::fs::{self, File};
+use crate::state::{StateReader, StateResult, StateWriter};
+use std::fs;
 use std::path;
 
 mod mbc0;
@@ -9,6 +8,13 @@ mod mbc1;
 mod mbc2;
 mod mbc3;
 mod mbc5;
+mod mbc7;
+mod huc1;
+mod camera;
+mod header;
+
+pub use self::camera::{CameraSource, CAMERA_H, CAMERA_W};
+pub use self::header::{CartridgeHeader, CartridgeType, Destination, Mapper};
 
 pub trait MBC : Send {
     fn readrom(&self, a: u16) -> u8;
@@ -16,11 +22,40 @@ pub trait MBC : Send {
     fn writerom(&mut self, a: u16, v: u8);
     fn writeram(&mut self, a: u16, v: u8);
     fn check_and_reset_ram_updated(&mut self) -> bool;
+    // The ROM bank that is mapped at a, which is below 8000
+    fn rombank_at(&self, a: u16) -> usize;
+
+    // Overrides the detection of an MBC1 multicart. Other cartridges ignore it.
+    fn set_multicart(&mut self, _multicart: bool) {}
+
+    // Whether the rumble motor of an MBC5 rumble cartridge is on
+    fn rumble_active(&self) -> bool { false }
+
+    // Sets the tilt that the accelerometer of an MBC7 cartridge reads, in g
+    fn set_accelerometer(&mut self, _x: f32, _y: f32) {}
+
+    // Sets where a Pocket Camera takes its pictures from. Other cartridges ignore it.
+    fn set_camera_source(&mut self, _source: CameraSource) {}
+    fn take_camera_source(&mut self) -> Option<CameraSource> { None }
+
+    // Runs the hardware on the cartridge for the given CPU ticks
+    fn do_cycle(&mut self, _ticks: u32) {}
+
+    // Writes the RAM at an offset into all of its banks, whatever is mapped and whether it is
+    // enabled, for a cheat. Cartridges without banked RAM ignore it.
+    fn poke_ram(&mut self, _offset: usize, _v: u8) {}
+    // Reads the RAM at an offset into all of its banks, or None past its end
+    fn peek_ram(&self, _offset: usize) -> Option<u8> { None }
 
     fn is_battery_backed(&self) -> bool;
     fn loadram(&mut self, ramdata: &[u8]) -> StrResult<()>;
     fn dumpram(&self) -> Vec<u8>;
 
+    // The RAM, the banking registers and the hardware on the cartridge, for a save state. What
+    // the frontend sets, like the camera source or the tilt, is not included.
+    fn save_state(&self, w: &mut StateWriter);
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()>;
+
     fn romname(&self) -> String {
         const TITLE_START : u16 = 0x134;
         const CGB_FLAG : u16 = 0x143;
@@ -43,52 +78,112 @@ pub trait MBC : Send {
     }
 }
 
+// Bad checksums only give a warning, as the hardware does not check the global one and many
+// homebrew ROMs get the header one wrong
 pub fn get_mbc(data: Vec<u8>, skip_checksum: bool) -> StrResult<Box<dyn MBC+'static>> {
-    if data.len() < 0x150 { return Err("Rom size to small"); }
+    let header = CartridgeHeader::parse(&data)?;
     if !skip_checksum {
-        check_checksum(&data)?;
+        if !header.verify_header_checksum() {
+            eprintln!("Warning: the cartridge header checksum is invalid");
+        }
+        if !header.verify_global_checksum(&data) {
+            eprintln!("Warning: the cartridge global checksum is invalid");
+        }
     }
-    match data[0x147] {
-        0x00 => mbc0::MBC0::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
-        0x01 ..= 0x03 => mbc1::MBC1::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
-        0x05 ..= 0x06 => mbc2::MBC2::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
-        0x0F ..= 0x13 => mbc3::MBC3::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
-        0x19 ..= 0x1E => mbc5::MBC5::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
-        _ => { Err("Unsupported MBC type") },
+    match header.cartridge_type.mapper {
+        Mapper::None => mbc0::MBC0::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
+        Mapper::MBC1 => mbc1::MBC1::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
+        Mapper::MBC2 => mbc2::MBC2::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
+        Mapper::MBC3 => mbc3::MBC3::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
+        Mapper::MBC5 => mbc5::MBC5::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
+        Mapper::MBC7 => mbc7::MBC7::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
+        Mapper::PocketCamera => camera::PocketCamera::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
+        Mapper::HuC1 => huc1::HuC1::new(data).map(|v| Box::new(v) as Box<dyn MBC>),
+        _ => { Err("Unsupported MBC type. Supported are ROM only, MBC1, MBC2, MBC3, MBC5, MBC7, HuC1 and the Pocket Camera") },
     }
 }
 
+// How often RAM written since the last save is flushed to the save file, in CPU ticks
+const SAVE_INTERVAL: u32 = 3 * 4194304;
+
+// Keeps the RAM of a battery backed cartridge in a .sav file next to the ROM, which has the raw
+// RAM like the saves of other emulators. It is written when the game disables the RAM after
+// writing to it, every few seconds while there are unsaved writes, and when the cartridge is
+// dropped.
 pub struct FileBackedMBC {
     rampath: path::PathBuf,
     mbc: Box<dyn MBC>,
+    // RAM written since the last save
+    unsaved: bool,
+    // RAM written since the last check_and_reset_ram_updated
+    ram_updated: bool,
+    save_ticks: u32,
 }
 
 impl FileBackedMBC {
-    pub fn new(rompath: path::PathBuf, skip_checksum: bool) -> StrResult<FileBackedMBC> {
-        let mut data = vec![];
-        File::open(&rompath).and_then(|mut f| f.read_to_end(&mut data)).map_err(|_| "Could not read ROM")?;
-        let mut mbc = get_mbc(data, skip_checksum)?;
-
-        let rampath = rompath.with_extension("gbsave");
-
-        if mbc.is_battery_backed() {
-            match fs::File::open(&rampath) {
-                Ok(mut file) => {
-                    let mut ramdata: Vec<u8> = vec![];
-                    match file.read_to_end(&mut ramdata) {
-                        Err(..) => return Err("Error while reading existing save file"),
-                        Ok(..) => { mbc.loadram(&ramdata)?; },
-                    }
-                },
-                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
-                Err(_) => return Err("Error loading existing save file"),
+    // Saves the battery backed RAM of an MBC next to the ROM at rompath, after loading it from
+    // there. Only fails on errors of the save file.
+    pub fn with_save_file(rompath: path::PathBuf, mut mbc: Box<dyn MBC+'static>) -> StrResult<FileBackedMBC> {
+        // game.sav, or else game.gb.sav, or the game.gbsave of older versions of the emulator
+        let mut appended = rompath.clone().into_os_string();
+        appended.push(".sav");
+        let candidates = [rompath.with_extension("sav"), appended.into(), rompath.with_extension("gbsave")];
+        let existing = match mbc.is_battery_backed() {
+            true => candidates.iter().position(|p| p.exists()),
+            false => None,
+        };
+        let rampath = match existing {
+            Some(1) => candidates[1].clone(),
+            _ => candidates[0].clone(),
+        };
+
+        if let Some(i) = existing {
+            let ramdata = fs::read(&candidates[i]).map_err(|_| "Error while reading existing save file")?;
+            let name = candidates[i].display().to_string();
+            // The game would overwrite a save that was not loaded with its empty RAM
+            if !load_save(&mut *mbc, &ramdata, &name) && candidates[i] == rampath {
+                let mut backup = rampath.clone().into_os_string();
+                backup.push(".bak");
+                fs::rename(&rampath, &backup).map_err(|_| "Could not move away the save file of the wrong size")?;
+                eprintln!("Warning: moved {} to {}", name, path::Path::new(&backup).display());
             }
         }
 
-        Ok(FileBackedMBC { rampath, mbc })
+        Ok(FileBackedMBC { rampath, mbc, unsaved: false, ram_updated: false, save_ticks: 0 })
+    }
+
+    fn collect_ram_updates(&mut self) {
+        if self.mbc.check_and_reset_ram_updated() {
+            self.unsaved = true;
+            self.ram_updated = true;
+        }
+    }
+
+    fn save(&mut self) {
+        self.unsaved = false;
+        if let Err(e) = fs::write(&self.rampath, self.mbc.dumpram()) {
+            eprintln!("Could not write the save file {}: {}", self.rampath.display(), e);
+        }
+    }
+
+    fn save_if_unsaved(&mut self) {
+        self.collect_ram_updates();
+        if self.unsaved {
+            self.save();
+        }
     }
 }
 
+// A save of the wrong size is not loaded, as it is of another cartridge or would put its banks in
+// the wrong places. The game starts with empty RAM instead.
+fn load_save(mbc: &mut dyn MBC, ramdata: &[u8], name: &str) -> bool {
+    if mbc.loadram(ramdata).is_ok() {
+        return true;
+    }
+    eprintln!("Warning: {} has {} bytes instead of {}, not loading it", name, ramdata.len(), mbc.dumpram().len());
+    false
+}
+
 // Implement MBC for FileBackedMBC such that the MMU can use this transparently
 impl MBC for FileBackedMBC {
     fn readrom(&self, a: u16) -> u8 {
@@ -99,14 +194,61 @@ impl MBC for FileBackedMBC {
         self.mbc.readram(a)
     }
 
+    fn rombank_at(&self, a: u16) -> usize {
+        self.mbc.rombank_at(a)
+    }
+
+    fn set_multicart(&mut self, multicart: bool) {
+        self.mbc.set_multicart(multicart)
+    }
+
+    fn rumble_active(&self) -> bool {
+        self.mbc.rumble_active()
+    }
+
+    fn set_accelerometer(&mut self, x: f32, y: f32) {
+        self.mbc.set_accelerometer(x, y)
+    }
+
+    fn set_camera_source(&mut self, source: CameraSource) {
+        self.mbc.set_camera_source(source)
+    }
+
+    fn take_camera_source(&mut self) -> Option<CameraSource> {
+        self.mbc.take_camera_source()
+    }
+
+    fn do_cycle(&mut self, ticks: u32) {
+        self.mbc.do_cycle(ticks);
+        self.save_ticks += ticks;
+        if self.save_ticks >= SAVE_INTERVAL {
+            self.save_ticks = 0;
+            if self.mbc.is_battery_backed() {
+                self.save_if_unsaved();
+            }
+        }
+    }
+
+    // Games disable the RAM when they are done saving
     fn writerom(&mut self, a: u16, v: u8) {
-        self.mbc.writerom(a, v)
+        self.mbc.writerom(a, v);
+        if a < 0x2000 && v & 0x0F != 0x0A && self.mbc.is_battery_backed() {
+            self.save_if_unsaved();
+        }
     }
 
     fn writeram(&mut self, a: u16, v: u8) {
         self.mbc.writeram(a, v)
     }
 
+    fn poke_ram(&mut self, offset: usize, v: u8) {
+        self.mbc.poke_ram(offset, v)
+    }
+
+    fn peek_ram(&self, offset: usize) -> Option<u8> {
+        self.mbc.peek_ram(offset)
+    }
+
     fn is_battery_backed(&self) -> bool {
         self.mbc.is_battery_backed()
     }
@@ -119,8 +261,23 @@ impl MBC for FileBackedMBC {
         self.mbc.dumpram()
     }
 
+    fn save_state(&self, w: &mut StateWriter) {
+        self.mbc.save_state(w)
+    }
+
+    // The loaded RAM replaces the save file
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.mbc.load_state(r)?;
+        self.unsaved = true;
+        self.ram_updated = true;
+        Ok(())
+    }
+
     fn check_and_reset_ram_updated(&mut self) -> bool {
-        self.mbc.check_and_reset_ram_updated()
+        self.collect_ram_updates();
+        let result = self.ram_updated;
+        self.ram_updated = false;
+        result
     }
 }
 
@@ -128,16 +285,19 @@ impl MBC for FileBackedMBC {
 impl Drop for FileBackedMBC {
     fn drop(&mut self) {
         if self.mbc.is_battery_backed() {
-            // TODO: error handling
-            let mut file = match fs::File::create(&self.rampath) {
-                Ok(f) => f,
-                Err(..) => return,
-            };
-            let _ = file.write_all(&self.mbc.dumpram());
+            self.save();
         }
     }
 }
 
+// Returns whether the byte changed, for ram_updated
+fn poke(ram: &mut [u8], offset: usize, v: u8) -> bool {
+    match ram.get_mut(offset) {
+        Some(b) if *b != v => { *b = v; true },
+        _ => false,
+    }
+}
+
 fn ram_banks(v: u8) -> usize {
     match v {
         1 =>
@@ -162,31 +322,95 @@ fn rom_banks(v: u8) -> usize {
     }
 }
 
-fn check_checksum(data: &[u8]) -> StrResult<()> {
-    let mut value: u8 = 0;
-    for i in 0x134 .. 0x14D {
-        value = value.wrapping_sub(data[i]).wrapping_sub(1);
-    }
-    match data[0x14D] == value
-    {
-        true => Ok(()),
-        false => Err("Cartridge checksum is invalid"),
-    }
-}
-
 #[cfg(test)]
 mod test {
+    use super::{get_mbc, FileBackedMBC, MBC, SAVE_INTERVAL};
+    use std::fs;
+
+    // A cartridge of a type with a RAM size code, and the register that selects its RAM bank
+    fn cartridge(cartridge_type: u8, ram_size: u8) -> Box<dyn MBC> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = cartridge_type;
+        rom[0x149] = ram_size;
+        get_mbc(rom, true).unwrap()
+    }
+
     #[test]
-    fn checksum_zero() {
-        let mut data = vec![0; 0x150];
-        data[0x14D] = -(0x14D_i32 - 0x134_i32) as u8;
-        super::check_checksum(&data).unwrap();
+    fn ram_sizes() {
+        // MBC1, MBC3, MBC5 and HuC1 with 32 KiB
+        for cartridge_type in [0x03, 0x13, 0x1B, 0xFF] {
+            let mut m = cartridge(cartridge_type, 0x03);
+            assert_eq!(m.dumpram().len(), 0x8000, "{:02X}", cartridge_type);
+            m.writerom(0x0000, 0x0A);
+            // The RAM banking mode of an MBC1
+            m.writerom(0x6000, 0x01);
+            for bank in 0 .. 4 {
+                m.writerom(0x4000, bank);
+                m.writeram(0xA000, 0x10 + bank);
+                m.writeram(0xBFFF, 0x20 + bank);
+            }
+            for bank in [2, 0, 3, 1] {
+                m.writerom(0x4000, bank);
+                assert_eq!((m.readram(0xA000), m.readram(0xBFFF)), (0x10 + bank, 0x20 + bank), "{:02X}", cartridge_type);
+            }
+            let ram = m.dumpram();
+            assert_eq!((ram[0x4000], ram[0x7FFF]), (0x12, 0x23));
+        }
+
+        assert_eq!(cartridge(0x1B, 0x04).dumpram().len(), 0x20000);
+        assert_eq!(cartridge(0x1B, 0x05).dumpram().len(), 0x10000);
+        assert_eq!(cartridge(0x13, 0x04).dumpram().len(), 0x8000);
+        // The RAM of an MBC2 is in the MBC, whatever the header says
+        assert_eq!(cartridge(0x06, 0x03).dumpram().len(), 512);
+        // Without RAM, or a type without RAM
+        assert!(cartridge(0x03, 0x00).dumpram().is_empty());
+        assert!(cartridge(0x01, 0x03).dumpram().is_empty());
+        assert!(cartridge(0x19, 0x03).dumpram().is_empty());
     }
 
     #[test]
-    fn checksum_ones() {
-        let mut data = vec![1; 0x150];
-        data[0x14D] = (-(0x14D_i32 - 0x134_i32) * 2) as u8;
-        super::check_checksum(&data).unwrap();
+    fn save_files() {
+        let dir = std::env::temp_dir().join(format!("rboy_save_files_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        // MBC1 with 8 KiB of battery backed RAM
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x03;
+        rom[0x149] = 0x02;
+        let rompath = dir.join("game.gb");
+        fs::write(&rompath, &rom).unwrap();
+
+        // A save that is too short is not loaded, and moved away before the game overwrites it
+        fs::write(dir.join("game.gb.sav"), [1, 2, 3, 4]).unwrap();
+        let mut m = FileBackedMBC::with_save_file(rompath.clone(), get_mbc(fs::read(&rompath).unwrap(), true).unwrap()).unwrap();
+        m.writerom(0x0000, 0x0A);
+        assert_eq!((m.readram(0xA000), m.readram(0xA004)), (0, 0));
+        assert_eq!(fs::read(dir.join("game.gb.sav.bak")).unwrap(), [1, 2, 3, 4]);
+        assert!(!dir.join("game.gb.sav").exists());
+
+        // Disabling the RAM after writing saves it
+        m.writeram(0xA001, 0x55);
+        assert!(!dir.join("game.gb.sav").exists());
+        m.writerom(0x0000, 0x00);
+        let saved = fs::read(dir.join("game.gb.sav")).unwrap();
+        assert_eq!((saved.len(), &saved[.. 3]), (0x2000, &[0, 0x55, 0][..]));
+        assert!(m.check_and_reset_ram_updated());
+
+        // Unsaved writes are flushed every few seconds
+        m.writerom(0x0000, 0x0A);
+        m.writeram(0xA002, 0x66);
+        m.do_cycle(SAVE_INTERVAL - 4);
+        assert_eq!(fs::read(dir.join("game.gb.sav")).unwrap()[2], 0);
+        m.do_cycle(4);
+        assert_eq!(fs::read(dir.join("game.gb.sav")).unwrap()[2], 0x66);
+        drop(m);
+
+        // The saves of older versions are loaded and written back as game.sav
+        fs::rename(dir.join("game.gb.sav"), dir.join("game.gbsave")).unwrap();
+        let m = FileBackedMBC::with_save_file(rompath.clone(), get_mbc(fs::read(&rompath).unwrap(), true).unwrap()).unwrap();
+        assert_eq!(m.dumpram()[1], 0x55);
+        drop(m);
+        assert_eq!(fs::read(dir.join("game.sav")).unwrap()[1], 0x55);
+
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -1,14 +1,32 @@
+use crate::access::{Access, AccessHook, AccessKind, AccessSource};
+use crate::cheats::{Cheats, GameSharkTarget};
+use crate::colorize::{compat_palettes, Colorization};
 use crate::serial::{Serial, SerialCallback};
 use crate::timer::Timer;
 use crate::keypad::Keypad;
 use crate::gpu::GPU;
 use crate::sound::Sound;
+use crate::sgb::Sgb;
 use crate::gbmode::{GbMode, GbSpeed};
+use crate::state::{StateError, StateReader, StateResult, StateWriter};
 use crate::StrResult;
 use crate::mbc;
 
 const WRAM_SIZE: usize = 0x8000;
 const ZRAM_SIZE: usize = 0x7F;
+// A block of 16 bytes takes 8 us, in either speed
+const VRAMDMA_BLOCK_TICKS: u32 = 32;
+// An OAM DMA copies one byte per M-cycle, after an M-cycle to start up
+const OAMDMA_LEN: u16 = 0xA0;
+const OAMDMA_STARTUP: u8 = 2;
+pub const BOOT_ROM_SIZE: usize = 0x100;
+// 0000-00FF and 0200-08FF, with the cartridge header visible in between
+pub const CGB_BOOT_ROM_SIZE: usize = 0x900;
+
+struct OamDma {
+    source: u16,
+    index: u16,
+}
 
 #[derive(PartialEq)]
 enum DMAType {
@@ -17,10 +35,24 @@ enum DMAType {
     HDMA,
 }
 
+// A memory of the machine, for dumps
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum MemoryRegion {
+    Vram(usize),
+    Wram(usize),
+    Oam,
+    // FF80-FFFE
+    Hram,
+    CartRam(usize),
+    // FF00-FF7F
+    Io,
+    // The 64 KiB as the CPU sees them, with the banks that are mapped
+    FullBus,
+}
+
 pub struct MMU<'a> {
     wram: [u8; WRAM_SIZE],
     zram: [u8; ZRAM_SIZE],
-    hdma: [u8; 4],
     pub inte: u8,
     pub intf: u8,
     pub serial: Serial<'a>,
@@ -28,16 +60,35 @@ pub struct MMU<'a> {
     pub keypad: Keypad,
     pub gpu: GPU,
     pub sound: Option<Sound>,
+    // Present while running as a Super Game Boy
+    pub sgb: Option<Sgb>,
     hdma_status: DMAType,
+    // Written directly by FF51-FF54, so a new transfer continues where the last one ended. The
+    // destination is an offset into VRAM.
     hdma_src: u16,
     hdma_dst: u16,
     hdma_len: u8,
+    // An HBlank started, or the HDMA did in one, and the next block is copied
+    hdma_block_due: bool,
     wrambank: usize,
     pub mbc: Box<dyn mbc::MBC+'static>,
     pub gbmode: GbMode,
     gbspeed: GbSpeed,
     speed_switch_req: bool,
+    ly_stub: bool,
     undocumented_cgb_regs: [u8; 3],  // 0xFF72, 0xFF73, 0xFF75
+    // The running OAM DMA, and the one that was started and replaces it after the start up
+    // delay. The CPU can only reach FF00-FFFF while one runs.
+    oamdma: Option<OamDma>,
+    oamdma_start: Option<(u16, u8)>,
+    // Mapped over 0000-00FF, or 0000-08FF without 0100-01FF for a CGB boot ROM, until the
+    // game writes to FF50
+    boot_rom: Option<Vec<u8>>,
+    // Only written by the CGB boot ROM, bit 2 selects the DMG compatibility mode on FF50
+    key0: u8,
+    // Not part of a save state, as the frontend gives them
+    pub cheats: Cheats,
+    access_hook: Option<Box<AccessHook>>,
 }
 
 fn fill_random(slice: &mut [u8], start: u32) {
@@ -62,7 +113,6 @@ impl<'a> MMU<'a> {
         let mut res = MMU {
             wram: [0; WRAM_SIZE],
             zram: [0; ZRAM_SIZE],
-            hdma: [0; 4],
             wrambank: 1,
             inte: 0,
             intf: 0,
@@ -71,15 +121,24 @@ impl<'a> MMU<'a> {
             keypad: Keypad::new(),
             gpu: GPU::new(),
             sound: None,
+            sgb: None,
             mbc: cart,
             gbmode: GbMode::Classic,
             gbspeed: GbSpeed::Single,
             speed_switch_req: false,
+            ly_stub: false,
             hdma_src: 0,
             hdma_dst: 0,
             hdma_status: DMAType::NoDMA,
             hdma_len: 0xFF,
+            hdma_block_due: false,
             undocumented_cgb_regs: [0; 3],
+            oamdma: None,
+            oamdma_start: None,
+            boot_rom: None,
+            key0: 0,
+            cheats: Cheats::default(),
+            access_hook: None,
         };
         fill_random(&mut res.wram, 42);
         if res.rb(0x0143) == 0xC0 {
@@ -98,7 +157,6 @@ impl<'a> MMU<'a> {
             wram: [0; WRAM_SIZE],
             zram: [0; ZRAM_SIZE],
             wrambank: 1,
-            hdma: [0; 4],
             inte: 0,
             intf: 0,
             serial: serial,
@@ -106,18 +164,28 @@ impl<'a> MMU<'a> {
             keypad: Keypad::new(),
             gpu: GPU::new_cgb(),
             sound: None,
+            sgb: None,
             mbc: cart,
             gbmode: GbMode::Color,
             gbspeed: GbSpeed::Single,
             speed_switch_req: false,
+            ly_stub: false,
             hdma_src: 0,
             hdma_dst: 0,
             hdma_status: DMAType::NoDMA,
             hdma_len: 0xFF,
+            hdma_block_due: false,
             undocumented_cgb_regs: [0; 3],
+            oamdma: None,
+            oamdma_start: None,
+            boot_rom: None,
+            key0: 0,
+            cheats: Cheats::default(),
+            access_hook: None,
         };
         fill_random(&mut res.wram, 42);
         res.determine_mode();
+        res.set_colorization(Colorization::Auto);
         res.set_initial();
         Ok(res)
     }
@@ -146,6 +214,7 @@ impl<'a> MMU<'a> {
         self.wb(0xFF25, 0xF3);
         self.wb(0xFF26, 0xF1);
         self.wb(0xFF40, 0x91);
+        self.gpu.skip_lcd_startup();
         self.wb(0xFF42, 0);
         self.wb(0xFF43, 0);
         self.wb(0xFF45, 0);
@@ -156,21 +225,71 @@ impl<'a> MMU<'a> {
         self.wb(0xFF4B, 0);
     }
 
+    // Maps a DMG boot ROM, or a CGB boot ROM on a Gameboy Color, and turns the registers it sets
+    // up back to their power on values. A CGB boot ROM runs in color mode and picks the mode of
+    // the game with KEY0.
+    pub fn map_boot_rom(&mut self, boot_rom: Vec<u8>) {
+        self.boot_rom = Some(boot_rom);
+        if self.gbmode != GbMode::Classic {
+            self.set_mode(GbMode::Color);
+            self.key0 = 0;
+        }
+        self.wb(0xFF40, 0);
+        self.wb(0xFF47, 0);
+        self.wb(0xFF48, 0);
+        self.wb(0xFF49, 0);
+    }
+
+    // Colors a DMG game on a Gameboy Color as the CGB boot ROM would, or with another set. A
+    // boot ROM sets the colors itself.
+    pub fn set_colorization(&mut self, colorization: Colorization) {
+        if self.gbmode != GbMode::ColorAsClassic { return }
+        let rom: Vec<u8> = (0 .. 0x150).map(|a| self.mbc.readrom(a)).collect();
+        self.gpu.set_compat_palettes(&compat_palettes(&rom, colorization));
+    }
+
+    pub fn boot_rom_mapped(&self) -> bool {
+        self.boot_rom.is_some()
+    }
+
+    fn unmap_boot_rom(&mut self) {
+        if self.boot_rom.take().is_some() && self.gbmode == GbMode::Color && self.key0 & 0x04 != 0 {
+            self.set_mode(GbMode::ColorAsClassic);
+        }
+    }
+
+    fn boot_rom_byte(&self, address: u16) -> Option<u8> {
+        match (&self.boot_rom, address) {
+            (_, 0x0100 ..= 0x01FF) => None,
+            (Some(boot_rom), _) => boot_rom.get(address as usize).copied(),
+            (None, _) => None,
+        }
+    }
+
     fn determine_mode(&mut self) {
         let mode = match self.rb(0x0143) & 0x80 {
             0x80 => GbMode::Color,
             _ => GbMode::ColorAsClassic,
         };
+        self.set_mode(mode);
+    }
+
+    fn set_mode(&mut self, mode: GbMode) {
         self.gbmode = mode;
         self.gpu.gbmode = mode;
     }
 
     pub fn do_cycle(&mut self, ticks: u32) -> u32 {
-        let cpudivider = self.gbspeed as u32;
+        // The timer and serial port run at the CPU clock, the GPU and APU keep their rate in double speed
+        let cpudivider = self.gbspeed() as u32;
         let vramticks = self.perform_vramdma();
         let gputicks = ticks / cpudivider + vramticks;
         let cputicks = ticks + vramticks * cpudivider;
 
+        for _ in 0 .. cputicks / 4 {
+            self.oamdma_step();
+        }
+
         self.timer.do_cycle(cputicks);
         self.intf |= self.timer.interrupt;
         self.timer.interrupt = 0;
@@ -179,37 +298,115 @@ impl<'a> MMU<'a> {
         self.keypad.interrupt = 0;
 
         self.gpu.do_cycle(gputicks);
+        if self.gpu.take_hblank_start() && self.hdma_status == DMAType::HDMA {
+            self.hdma_block_due = true;
+        }
+        if self.gpu.interrupt & 0x01 != 0 {
+            self.apply_gameshark();
+        }
         self.intf |= self.gpu.interrupt;
         self.gpu.interrupt = 0;
+        if let Some(sgb) = &mut self.sgb {
+            sgb.frame_done(self.gpu.frame_count(), self.gpu.last_frame_indices());
+        }
 
         let _ = self.sound.as_mut().map_or((), |s| s.do_cycle(gputicks));
 
+        self.serial.do_cycle(cputicks);
         self.intf |= self.serial.interrupt;
         self.serial.interrupt = 0;
 
+        self.mbc.do_cycle(cputicks);
+
         return gputicks;
     }
 
+    // The GameShark writes its codes when the VBlank interrupt is raised
+    fn apply_gameshark(&mut self) {
+        for i in 0 .. self.cheats.gameshark().len() {
+            let code = self.cheats.gameshark()[i];
+            match code.target {
+                GameSharkTarget::CartridgeRam(bank) => self.mbc.poke_ram((bank * 0x2000) | (code.address as usize & 0x1FFF), code.value),
+                GameSharkTarget::WorkRam(bank) if self.gbmode == GbMode::Color => self.wram[(bank * 0x1000) | (code.address as usize & 0x0FFF)] = code.value,
+                GameSharkTarget::WorkRam(_) | GameSharkTarget::Mapped => self.wb(code.address, code.value),
+            }
+        }
+    }
+
+    // Reads as the CPU, which only gets FF from the bus while an OAM DMA runs
+    pub fn cpu_rb(&mut self, address: u16) -> u8 {
+        let value = match self.oamdma {
+            Some(_) if address < 0xFF00 => 0xFF,
+            _ => self.rb(address),
+        };
+        self.report_access(address, value, AccessKind::Read, AccessSource::Cpu);
+        value
+    }
+
+    pub fn cpu_wb(&mut self, address: u16, value: u8) {
+        match self.oamdma {
+            Some(_) if address < 0xFF00 => {},
+            _ => {
+                self.wb(address, value);
+                self.report_access(address, value, AccessKind::Write, AccessSource::Cpu);
+            },
+        }
+    }
+
+    pub fn set_access_hook(&mut self, hook: Option<AccessHook>) {
+        self.access_hook = hook.filter(|hook| !hook.is_empty()).map(Box::new);
+    }
+
+    pub fn take_access_hook(&mut self) -> Option<AccessHook> {
+        self.access_hook.take().map(|hook| *hook)
+    }
+
+    #[inline]
+    pub fn report_access(&mut self, address: u16, value: u8, kind: AccessKind, source: AccessSource) {
+        if let Some(ref mut hook) = self.access_hook {
+            hook.report(Access { address, value, kind, source });
+        }
+    }
+
+    // The APU catches up to the CPU before its registers are read
     pub fn rb(&mut self, address: u16) -> u8 {
         match address {
-            0x0000 ..= 0x7FFF => self.mbc.readrom(address),
+            0xFF10 ..= 0xFF3F => self.sound.as_mut().map_or(0xFF, |s| s.rb(address)),
+            _ => self.peek(address),
+        }
+    }
+
+    // Reads memory without any side effects
+    pub fn peek(&self, address: u16) -> u8 {
+        if let Some(value) = self.boot_rom_byte(address) {
+            return value;
+        }
+        match address {
+            0x0000 ..= 0x7FFF => self.cheats.patch_rom(address, self.mbc.readrom(address)),
             0x8000 ..= 0x9FFF => self.gpu.rb(address),
             0xA000 ..= 0xBFFF => self.mbc.readram(address),
             0xC000 ..= 0xCFFF | 0xE000 ..= 0xEFFF => self.wram[address as usize & 0x0FFF],
             0xD000 ..= 0xDFFF | 0xF000 ..= 0xFDFF => self.wram[(self.wrambank * 0x1000) | address as usize & 0x0FFF],
             0xFE00 ..= 0xFE9F => self.gpu.rb(address),
-            0xFF00 => self.keypad.rb(),
+            // Unusable, a Gameboy Color repeats the upper nibble of the lower address byte
+            0xFEA0 ..= 0xFEFF if self.gbmode == GbMode::Classic => 0x00,
+            0xFEA0 ..= 0xFEFF => (address as u8 & 0xF0) | (address as u8 >> 4),
+            0xFF00 => self.sgb.as_ref().map_or(self.keypad.rb(), |sgb| sgb.read_p1(self.keypad.rb())),
+            // The fast serial clock only exists in CGB mode
+            0xFF02 if self.gbmode != GbMode::Color => self.serial.rb(address) | 0x02,
             0xFF01 ..= 0xFF02 => self.serial.rb(address),
             0xFF04 ..= 0xFF07 => self.timer.rb(address),
             0xFF0F => self.intf | 0b11100000,
-            0xFF10 ..= 0xFF3F => self.sound.as_mut().map_or(0xFF, |s| s.rb(address)),
+            0xFF10 ..= 0xFF3F => self.sound.as_ref().map_or(0xFF, |s| s.peek(address)),
             0xFF4D | 0xFF4F | 0xFF51 ..= 0xFF55 | 0xFF6C | 0xFF70 if self.gbmode != GbMode::Color => { 0xFF },
             0xFF72 ..= 0xFF73 | 0xFF75 ..= 0xFF77 if self.gbmode == GbMode::Classic => { 0xFF },
+            0xFF44 if self.ly_stub => 0x90,
             0xFF4D => 0b01111110 | (if self.gbspeed == GbSpeed::Double { 0x80 } else { 0 }) | (if self.speed_switch_req { 1 } else { 0 }),
             0xFF40 ..= 0xFF4F => self.gpu.rb(address),
+            0xFF50 => 0xFE | (self.boot_rom.is_none() as u8),
             0xFF51 ..= 0xFF55 => self.hdma_read(address),
-            0xFF68 ..= 0xFF6B => self.gpu.rb(address),
-            0xFF70 => self.wrambank as u8,
+            0xFF68 ..= 0xFF6C => self.gpu.rb(address),
+            0xFF70 => self.wrambank as u8 | 0xF8,
             0xFF72 ..= 0xFF73 => self.undocumented_cgb_regs[address as usize - 0xFF72],
             0xFF75 => self.undocumented_cgb_regs[2] | 0b10001111,
             0xFF76 ..= 0xFF77 => 0x00,  // CGB PCM registers. Not yet implemented.
@@ -219,10 +416,6 @@ impl<'a> MMU<'a> {
         }
     }
 
-    pub fn rw(&mut self, address: u16) -> u16 {
-        (self.rb(address) as u16) | ((self.rb(address + 1) as u16) << 8)
-    }
-
     pub fn wb(&mut self, address: u16, value: u8) {
         match address {
             0x0000 ..= 0x7FFF => self.mbc.writerom(address, value),
@@ -231,7 +424,14 @@ impl<'a> MMU<'a> {
             0xC000 ..= 0xCFFF | 0xE000 ..= 0xEFFF => self.wram[address as usize & 0x0FFF] = value,
             0xD000 ..= 0xDFFF | 0xF000 ..= 0xFDFF => self.wram[(self.wrambank * 0x1000) | (address as usize & 0x0FFF)] = value,
             0xFE00 ..= 0xFE9F => self.gpu.wb(address, value),
-            0xFF00 => self.keypad.wb(value),
+            0xFEA0 ..= 0xFEFF => {},
+            0xFF00 => {
+                self.keypad.wb(value);
+                if let Some(sgb) = &mut self.sgb {
+                    sgb.write_p1(value);
+                }
+            },
+            0xFF02 if self.gbmode != GbMode::Color => self.serial.wb(address, value & !0x02),
             0xFF01 ..= 0xFF02 => self.serial.wb(address, value),
             0xFF04 ..= 0xFF07 => self.timer.wb(address, value),
             0xFF10 ..= 0xFF3F => self.sound.as_mut().map_or((), |s| s.wb(address, value)),
@@ -239,9 +439,12 @@ impl<'a> MMU<'a> {
             0xFF4D | 0xFF4F | 0xFF51 ..= 0xFF55 | 0xFF6C | 0xFF70 | 0xFF76 ..= 0xFF77 if self.gbmode != GbMode::Color => {},
             0xFF72 ..= 0xFF73 | 0xFF75 ..= 0xFF77 if self.gbmode == GbMode::Classic => {},
             0xFF4D => if value & 0x1 == 0x1 { self.speed_switch_req = true; },
+            0xFF4C if self.boot_rom.is_some() => self.key0 = value,
             0xFF40 ..= 0xFF4F => self.gpu.wb(address, value),
+            // Once unmapped the boot ROM stays unmapped until a reset
+            0xFF50 if value != 0 => self.unmap_boot_rom(),
             0xFF51 ..= 0xFF55 => self.hdma_write(address, value),
-            0xFF68 ..= 0xFF6B => self.gpu.wb(address, value),
+            0xFF68 ..= 0xFF6C => self.gpu.wb(address, value),
             0xFF0F => self.intf = value,
             0xFF70 => { self.wrambank = match value & 0x7 { 0 => 1, n => n as usize }; },
             0xFF72 ..= 0xFF73 => self.undocumented_cgb_regs[address as usize - 0xFF72] = value,
@@ -252,9 +455,83 @@ impl<'a> MMU<'a> {
         };
     }
 
-    pub fn ww(&mut self, address: u16, value: u16) {
-        self.wb(address, (value & 0xFF) as u8);
-        self.wb(address + 1, (value >> 8) as u8);
+    // A bank that this Gameboy does not have is empty
+    pub fn dump_region(&self, region: MemoryRegion) -> Vec<u8> {
+        let len = self.region_len(region);
+        match region {
+            MemoryRegion::Vram(bank) => self.gpu.vram()[bank * 0x2000 ..][.. len].to_vec(),
+            MemoryRegion::Wram(bank) => self.wram[bank * 0x1000 ..][.. len].to_vec(),
+            MemoryRegion::Oam => self.gpu.oam().to_vec(),
+            MemoryRegion::Hram => self.zram.to_vec(),
+            MemoryRegion::CartRam(bank) => (0 .. len).filter_map(|i| self.mbc.peek_ram(bank * 0x2000 + i)).collect(),
+            MemoryRegion::Io => (0xFF00 ..= 0xFF7F).map(|a| self.peek(a)).collect(),
+            MemoryRegion::FullBus => (0x0000 ..= 0xFFFF).map(|a| self.peek(a)).collect(),
+        }
+    }
+
+    // The I/O registers are written as the CPU writes them, except that no OAM DMA starts and the
+    // boot ROM stays mapped. Of the full bus only what is memory is written, without the ROM, the
+    // I/O registers and the echo of WRAM.
+    pub fn load_region(&mut self, region: MemoryRegion, data: &[u8]) -> StrResult<()> {
+        let len = self.region_len(region);
+        if len == 0 {
+            return Err("This Gameboy does not have the memory region");
+        }
+        if data.len() != len {
+            return Err("The data does not have the size of the memory region");
+        }
+        match region {
+            MemoryRegion::Vram(bank) => self.gpu.vram_mut()[bank * 0x2000 ..][.. len].copy_from_slice(data),
+            MemoryRegion::Wram(bank) => self.wram[bank * 0x1000 ..][.. len].copy_from_slice(data),
+            MemoryRegion::Oam => self.gpu.oam_mut().copy_from_slice(data),
+            MemoryRegion::Hram => self.zram.copy_from_slice(data),
+            MemoryRegion::CartRam(bank) => {
+                for (i, &v) in data.iter().enumerate() {
+                    self.mbc.poke_ram(bank * 0x2000 + i, v);
+                }
+            },
+            MemoryRegion::Io => {
+                for (address, &v) in (0xFF00 ..= 0xFF7F).zip(data) {
+                    if address != 0xFF46 && address != 0xFF50 {
+                        self.wb(address, v);
+                    }
+                }
+            },
+            MemoryRegion::FullBus => {
+                for (address, &v) in (0x0000 ..= 0xFFFF).zip(data) {
+                    if let 0x8000 ..= 0xDFFF | 0xFE00 ..= 0xFE9F | 0xFF80 ..= 0xFFFF = address {
+                        self.wb(address, v);
+                    }
+                }
+            },
+        }
+        Ok(())
+    }
+
+    fn region_len(&self, region: MemoryRegion) -> usize {
+        let cgb = self.gbmode == GbMode::Color;
+        match region {
+            MemoryRegion::Vram(bank) if bank < if cgb { 2 } else { 1 } => 0x2000,
+            MemoryRegion::Wram(bank) if bank < if cgb { 8 } else { 2 } => 0x1000,
+            MemoryRegion::Vram(_) | MemoryRegion::Wram(_) => 0,
+            MemoryRegion::Oam => self.gpu.oam().len(),
+            MemoryRegion::Hram => ZRAM_SIZE,
+            MemoryRegion::CartRam(bank) => (0 .. 0x2000).take_while(|i| self.mbc.peek_ram(bank * 0x2000 + i).is_some()).count(),
+            MemoryRegion::Io => 0x80,
+            MemoryRegion::FullBus => 0x10000,
+        }
+    }
+
+    pub fn set_ly_stub(&mut self, enabled: bool) {
+        self.ly_stub = enabled;
+    }
+
+    pub fn speed_switch_requested(&self) -> bool {
+        self.speed_switch_req
+    }
+
+    pub fn gbspeed(&self) -> GbSpeed {
+        self.gbspeed
     }
 
     pub fn switch_speed(&mut self) {
@@ -266,19 +543,42 @@ impl<'a> MMU<'a> {
             }
         }
         self.speed_switch_req = false;
+        self.timer.wb(0xFF04, 0);
     }
 
+    // A DMA that is restarted keeps running until the new one starts
     fn oamdma(&mut self, value: u8) {
-        let base = (value as u16) << 8;
-        for i in 0 .. 0xA0 {
-            let b = self.rb(base + i);
-            self.wb(0xFE00 + i, b);
+        self.oamdma_start = Some(((value as u16) << 8, OAMDMA_STARTUP));
+    }
+
+    fn oamdma_step(&mut self) {
+        if let Some(OamDma { source, index }) = self.oamdma {
+            // E000-FFFF is read from the echo of WRAM
+            let address = source + index;
+            let address = if address >= 0xE000 { address - 0x2000 } else { address };
+            let b = self.rb(address);
+            self.gpu.wb(0xFE00 + index, b);
+            if self.access_hook.is_some() {
+                self.report_access(address, b, AccessKind::Read, AccessSource::Dma);
+                self.report_access(0xFE00 + index, b, AccessKind::Write, AccessSource::Dma);
+            }
+            self.oamdma = match index + 1 {
+                OAMDMA_LEN => None,
+                index => Some(OamDma { source, index }),
+            };
+        }
+        if let Some((source, delay)) = self.oamdma_start {
+            self.oamdma_start = match delay - 1 {
+                0 => { self.oamdma = Some(OamDma { source, index: 0 }); None },
+                delay => Some((source, delay)),
+            };
         }
     }
 
     fn hdma_read(&self, a: u16) -> u8 {
         match a {
-            0xFF51 ..= 0xFF54 => { self.hdma[(a - 0xFF51) as usize] },
+            // The addresses are write only
+            0xFF51 ..= 0xFF54 => 0xFF,
             0xFF55 => self.hdma_len | if self.hdma_status == DMAType::NoDMA { 0x80 } else { 0 },
             _ => panic!("The address {:04X} should not be handled by hdma_read", a),
         }
@@ -286,26 +586,22 @@ impl<'a> MMU<'a> {
 
     fn hdma_write(&mut self, a: u16, v: u8) {
         match a {
-            0xFF51 => self.hdma[0] = v,
-            0xFF52 => self.hdma[1] = v & 0xF0,
-            0xFF53 => self.hdma[2] = v & 0x1F,
-            0xFF54 => self.hdma[3] = v & 0xF0,
+            0xFF51 => self.hdma_src = ((v as u16) << 8) | (self.hdma_src & 0x00F0),
+            0xFF52 => self.hdma_src = (self.hdma_src & 0xFF00) | (v as u16 & 0xF0),
+            0xFF53 => self.hdma_dst = ((v as u16 & 0x1F) << 8) | (self.hdma_dst & 0x00F0),
+            0xFF54 => self.hdma_dst = (self.hdma_dst & 0x1F00) | (v as u16 & 0xF0),
             0xFF55 => {
-                if self.hdma_status == DMAType::HDMA {
-                    if v & 0x80 == 0 { self.hdma_status = DMAType::NoDMA; };
+                // Clearing bit 7 stops an HDMA, which keeps the remaining length. Setting it
+                // restarts it.
+                if self.hdma_status == DMAType::HDMA && v & 0x80 == 0 {
+                    self.hdma_status = DMAType::NoDMA;
                     return;
                 }
-                let src = ((self.hdma[0] as u16) << 8) | (self.hdma[1] as u16);
-                let dst = ((self.hdma[2] as u16) << 8) | (self.hdma[3] as u16) | 0x8000;
-                if !(src <= 0x7FF0 || (src >= 0xA000 && src <= 0xDFF0)) { panic!("HDMA transfer with illegal start address {:04X}", src); }
-
-                self.hdma_src = src;
-                self.hdma_dst = dst;
                 self.hdma_len = v & 0x7F;
-
                 self.hdma_status =
                     if v & 0x80 == 0x80 { DMAType::HDMA }
                     else { DMAType::GDMA };
+                self.hdma_block_due = self.gpu.hdma_block_now();
             },
             _ => panic!("The address {:04X} should not be handled by hdma_write", a),
         };
@@ -320,14 +616,15 @@ impl<'a> MMU<'a> {
     }
 
     fn perform_hdma(&mut self) -> u32 {
-        if self.gpu.may_hdma() == false {
+        if !self.hdma_block_due {
             return 0;
         }
+        self.hdma_block_due = false;
 
         self.perform_vramdma_row();
         if self.hdma_len == 0x7F { self.hdma_status = DMAType::NoDMA; }
 
-        return 8;
+        VRAMDMA_BLOCK_TICKS
     }
 
     fn perform_gdma(&mut self) -> u32 {
@@ -337,17 +634,30 @@ impl<'a> MMU<'a> {
         }
 
         self.hdma_status = DMAType::NoDMA;
-        return len * 8;
+        len * VRAMDMA_BLOCK_TICKS
     }
 
+    // The destination wraps around in VRAM. VRAM can not be a source, and E000-FFFF is read
+    // from A000-BFFF.
     fn perform_vramdma_row(&mut self) {
-        let mmu_src = self.hdma_src;
         for j in 0 .. 0x10 {
-            let b: u8 = self.rb(mmu_src + j);
-            self.gpu.wb(self.hdma_dst + j, b);
+            let src = match self.hdma_src.wrapping_add(j) {
+                0x8000 ..= 0x9FFF => None,
+                src @ 0xE000 ..= 0xFFFF => Some(src - 0x4000),
+                src => Some(src),
+            };
+            let b = src.map_or(0xFF, |src| self.rb(src));
+            let dst = 0x8000 | ((self.hdma_dst + j) & 0x1FFF);
+            self.gpu.wb(dst, b);
+            if self.access_hook.is_some() {
+                if let Some(src) = src {
+                    self.report_access(src, b, AccessKind::Read, AccessSource::Dma);
+                }
+                self.report_access(dst, b, AccessKind::Write, AccessSource::Dma);
+            }
         }
-        self.hdma_src += 0x10;
-        self.hdma_dst += 0x10;
+        self.hdma_src = self.hdma_src.wrapping_add(0x10);
+        self.hdma_dst = (self.hdma_dst + 0x10) & 0x1FFF;
 
         if self.hdma_len == 0 {
             self.hdma_len = 0x7F;
@@ -356,4 +666,418 @@ impl<'a> MMU<'a> {
             self.hdma_len -= 1;
         }
     }
+
+    // Everything but the serial callback and the audio player. The APU is written as a block of
+    // its own, which is skipped when only one side has audio.
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bytes(&self.wram);
+        w.bytes(&self.zram);
+        w.u8(self.inte);
+        w.u8(self.intf);
+        self.serial.save_state(w);
+        self.timer.save_state(w);
+        self.keypad.save_state(w);
+        self.gpu.save_state(w);
+        w.bool(self.sound.is_some());
+        if let Some(sound) = &self.sound {
+            let mut block = StateWriter::new();
+            sound.save_state(&mut block);
+            w.vec(&block.into_vec());
+        }
+        w.u8(match self.hdma_status { DMAType::NoDMA => 0, DMAType::GDMA => 1, DMAType::HDMA => 2 });
+        w.u16(self.hdma_src);
+        w.u16(self.hdma_dst);
+        w.u8(self.hdma_len);
+        w.bool(self.hdma_block_due);
+        w.usize(self.wrambank);
+        self.mbc.save_state(w);
+        self.gbmode.save_state(w);
+        self.gbspeed.save_state(w);
+        w.bool(self.speed_switch_req);
+        w.bytes(&self.undocumented_cgb_regs);
+        w.bool(self.oamdma.is_some());
+        if let Some(OamDma { source, index }) = self.oamdma {
+            w.u16(source);
+            w.u16(index);
+        }
+        w.bool(self.oamdma_start.is_some());
+        if let Some((source, delay)) = self.oamdma_start {
+            w.u16(source);
+            w.u8(delay);
+        }
+        w.bool(self.boot_rom.is_some());
+        if let Some(boot_rom) = &self.boot_rom {
+            w.vec(boot_rom);
+        }
+        w.u8(self.key0);
+        w.bool(self.sgb.is_some());
+        if let Some(sgb) = &self.sgb {
+            let mut block = StateWriter::new();
+            sgb.save_state(&mut block);
+            w.vec(&block.into_vec());
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        r.bytes_into(&mut self.wram)?;
+        r.bytes_into(&mut self.zram)?;
+        self.inte = r.u8()?;
+        self.intf = r.u8()?;
+        self.serial.load_state(r)?;
+        self.timer.load_state(r)?;
+        self.keypad.load_state(r)?;
+        self.gpu.load_state(r)?;
+        if r.bool()? {
+            let block = r.vec()?;
+            if let Some(sound) = &mut self.sound {
+                let mut block = StateReader::new(block);
+                sound.load_state(&mut block)?;
+                block.finish()?;
+            }
+        }
+        self.hdma_status = match r.u8()? {
+            0 => DMAType::NoDMA,
+            1 => DMAType::GDMA,
+            2 => DMAType::HDMA,
+            _ => return Err(StateError::Corrupt),
+        };
+        self.hdma_src = r.u16()? & 0xFFF0;
+        self.hdma_dst = r.u16()? & 0x1FF0;
+        self.hdma_len = r.u8()?;
+        self.hdma_block_due = r.bool()?;
+        self.wrambank = match r.index(8)? {
+            0 => return Err(StateError::Corrupt),
+            bank => bank,
+        };
+        self.mbc.load_state(r)?;
+        self.gbmode = GbMode::load_state(r)?;
+        self.gbspeed = GbSpeed::load_state(r)?;
+        self.speed_switch_req = r.bool()?;
+        r.bytes_into(&mut self.undocumented_cgb_regs)?;
+        self.oamdma = match r.bool()? {
+            true => Some(OamDma { source: r.u16()?, index: r.u16()? }),
+            false => None,
+        };
+        if matches!(self.oamdma, Some(OamDma { index, .. }) if index >= OAMDMA_LEN) {
+            return Err(StateError::Corrupt);
+        }
+        self.oamdma_start = match r.bool()? {
+            true => Some((r.u16()?, r.u8()?)),
+            false => None,
+        };
+        if matches!(self.oamdma_start, Some((_, delay)) if delay == 0 || delay > OAMDMA_STARTUP) {
+            return Err(StateError::Corrupt);
+        }
+        self.boot_rom = match r.bool()? {
+            true => match r.vec()? {
+                boot_rom if boot_rom.len() == BOOT_ROM_SIZE || boot_rom.len() == CGB_BOOT_ROM_SIZE => Some(boot_rom.to_vec()),
+                _ => return Err(StateError::Corrupt),
+            },
+            false => None,
+        };
+        self.key0 = r.u8()?;
+        if r.bool()? {
+            let block = r.vec()?;
+            if let Some(sgb) = &mut self.sgb {
+                let mut block = StateReader::new(block);
+                sgb.load_state(&mut block)?;
+                block.finish()?;
+                sgb.recompose(self.gpu.frame_count(), self.gpu.last_frame_indices());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MemoryRegion, MMU};
+    use crate::mbc;
+    use crate::sound::{AudioPlayer, Sound, SoundOptions};
+
+    struct NullPlayer;
+
+    impl AudioPlayer for NullPlayer {
+        fn play(&mut self, _left_channel: &[f32], _right_channel: &[f32]) {}
+        fn samples_rate(&self) -> u32 { 44100 }
+        fn underflowed(&self) -> bool { false }
+    }
+
+    fn rom(cgb_flag: u8) -> Box<dyn mbc::MBC> {
+        let mut romdata = vec![0; 0x8000];
+        romdata[0x0143] = cgb_flag;
+        mbc::get_mbc(romdata, true).unwrap()
+    }
+
+    #[test]
+    fn wram_banks() {
+        let mut m = MMU::new_cgb(rom(0x80), None).unwrap();
+        assert_eq!(m.rb(0xFF70), 0xF9);
+        for bank in 1 .. 8 {
+            m.wb(0xFF70, bank);
+            m.wb(0xD000, bank * 0x10);
+        }
+        m.wb(0xC000, 0x01);
+
+        // Bank 0 selects bank 1, and the upper bits are ignored
+        m.wb(0xFF70, 0xF8);
+        assert_eq!((m.rb(0xFF70), m.rb(0xD000)), (0xF9, 0x10));
+        for bank in 2 .. 8 {
+            m.wb(0xFF70, bank);
+            assert_eq!((m.rb(0xD000), m.rb(0xF000), m.rb(0xC000)), (bank * 0x10, bank * 0x10, 0x01));
+        }
+        // The echo writes to the selected bank as well
+        m.wb(0xF001, 0x77);
+        assert_eq!(m.rb(0xD001), 0x77);
+        assert_eq!(m.wram[7 * 0x1000 + 1], 0x77);
+    }
+
+    #[test]
+    fn vramdma_to_bank_1() {
+        let mut m = MMU::new_cgb(rom(0x80), None).unwrap();
+        m.wb(0xC000, 0x42);
+        m.wb(0xFF4F, 0x01);
+        for (address, value) in [(0xFF51, 0xC0), (0xFF52, 0x00), (0xFF53, 0x00), (0xFF54, 0x00), (0xFF55, 0x00)] {
+            m.wb(address, value);
+        }
+        m.do_cycle(4);
+        assert_eq!(m.rb(0x8000), 0x42);
+        m.wb(0xFF4F, 0x00);
+        assert_eq!(m.rb(0x8000), 0x00);
+    }
+
+    #[test]
+    fn echo_ram() {
+        for mut m in [MMU::new(rom(0x00), None).unwrap(), MMU::new_cgb(rom(0x80), None).unwrap()] {
+            m.wb(0xC123, 0x11);
+            m.wb(0xE456, 0x22);
+            m.wb(0xDDFF, 0x33);
+            m.wb(0xFDFE, 0x44);
+            assert_eq!((m.rb(0xE123), m.rb(0xC456), m.rb(0xFDFF), m.rb(0xDDFE)), (0x11, 0x22, 0x33, 0x44));
+        }
+
+        let mut m = MMU::new_cgb(rom(0x80), None).unwrap();
+        m.wb(0xFF70, 0x03);
+        m.wb(0xD123, 0x55);
+        m.wb(0xF456, 0x66);
+        m.wb(0xFF70, 0x04);
+        assert_eq!((m.rb(0xF123), m.rb(0xD456)), (m.wram[0x4123], m.wram[0x4456]));
+        m.wb(0xFF70, 0x03);
+        assert_eq!((m.rb(0xF123), m.rb(0xD456)), (0x55, 0x66));
+
+        // OAM DMA reads from the echo as well
+        m.wb(0xFF46, 0xF1);
+        m.do_cycle(4 * 162);
+        assert_eq!(m.rb(0xFE23), 0x55);
+    }
+
+    #[test]
+    fn unusable_region() {
+        let machines = [
+            (MMU::new(rom(0x00), None).unwrap(), false),
+            (MMU::new_cgb(rom(0x00), None).unwrap(), true),
+            (MMU::new_cgb(rom(0x80), None).unwrap(), true),
+        ];
+        for (mut m, cgb) in machines {
+            let oam: Vec<u8> = (0xFE00 ..= 0xFE9F).map(|a| m.rb(a)).collect();
+            for address in 0xFEA0 ..= 0xFEFF {
+                m.wb(address, 0x12);
+                let expected = match cgb {
+                    true => ((address >> 4) as u8 & 0x0F) * 0x11,
+                    false => 0x00,
+                };
+                assert_eq!(m.rb(address), expected, "{:04X}", address);
+            }
+            assert_eq!(m.rb(0xFEA0), if cgb { 0xAA } else { 0x00 });
+            assert_eq!((0xFE00 ..= 0xFE9F).map(|a| m.rb(a)).collect::<Vec<u8>>(), oam);
+        }
+    }
+
+    // Runs a one byte transfer and returns its length in GPU ticks
+    fn serial_transfer(m: &mut MMU, control: u8) -> u32 {
+        let mut ticks = 0;
+        m.wb(0xFF0F, 0x00);
+        m.wb(0xFF02, control);
+        while m.rb(0xFF02) & 0x80 != 0 {
+            ticks += m.do_cycle(4);
+        }
+        ticks
+    }
+
+    #[test]
+    fn serial_clock_speeds() {
+        let mut m = MMU::new_cgb(rom(0x80), None).unwrap();
+        assert_eq!((serial_transfer(&mut m, 0x81), serial_transfer(&mut m, 0x83)), (4096, 128));
+        assert_eq!(m.rb(0xFF02), 0x7F);
+
+        m.wb(0xFF4D, 0x01);
+        m.switch_speed();
+        assert_eq!((serial_transfer(&mut m, 0x81), serial_transfer(&mut m, 0x83)), (2048, 64));
+
+        // The fast clock bit is ignored and reads as set outside of CGB mode
+        for mut m in [MMU::new(rom(0x00), None).unwrap(), MMU::new_cgb(rom(0x00), None).unwrap()] {
+            assert_eq!(serial_transfer(&mut m, 0x83), 4096);
+            m.wb(0xFF02, 0x00);
+            assert_eq!(m.rb(0xFF02), 0x7E);
+        }
+    }
+
+    #[test]
+    fn io_write_storm() {
+        let mut m = MMU::new_cgb(rom(0x80), None).unwrap();
+        m.gpu.set_frame_blend(50);
+        m.do_cycle(456 * 4 * 10);
+
+        // Timer, serial, LCD, KEY1 and HDMA writes, with HDMA started and stopped again
+        let writes = [
+            (0xFF05, 0x00, 0x80), (0xFF06, 0x00, 0x80), (0xFF07, 0x04, 0x07), (0xFF01, 0x00, 0xFF),
+            (0xFF02, 0x00, 0x01), (0xFF40, 0x11, 0x91), (0xFF4D, 0x00, 0x01), (0xFF51, 0xC0, 0xD0),
+            (0xFF53, 0x00, 0x10), (0xFF55, 0x80, 0x00),
+        ];
+        let start = ::std::time::Instant::now();
+        for i in 0 .. 1_000_000 {
+            let (address, a, b) = writes[i % writes.len()];
+            m.wb(address, if (i / writes.len()) % 2 == 0 { a } else { b });
+        }
+        assert!(start.elapsed() < ::std::time::Duration::from_secs(5), "took {:?}", start.elapsed());
+    }
+
+    #[test]
+    fn wram_banks_dmg() {
+        for mut m in [MMU::new(rom(0x00), None).unwrap(), MMU::new_cgb(rom(0x00), None).unwrap()] {
+            m.wb(0xD000, 0x11);
+            m.wb(0xFF70, 0x02);
+            assert_eq!((m.rb(0xFF70), m.rb(0xD000)), (0xFF, 0x11));
+        }
+    }
+
+    // Registers that no model implements, and the ones only a Gameboy Color has
+    const UNUSED_IO: [std::ops::RangeInclusive<u16>; 11] = [
+        0xFF03 ..= 0xFF03, 0xFF08 ..= 0xFF0E, 0xFF15 ..= 0xFF15, 0xFF1F ..= 0xFF1F,
+        0xFF27 ..= 0xFF2F, 0xFF4C ..= 0xFF4C, 0xFF4E ..= 0xFF4E, 0xFF56 ..= 0xFF67,
+        0xFF6D ..= 0xFF6F, 0xFF71 ..= 0xFF71, 0xFF78 ..= 0xFF7F,
+    ];
+    const CGB_IO: [std::ops::RangeInclusive<u16>; 6] = [
+        0xFF4D ..= 0xFF4D, 0xFF4F ..= 0xFF4F, 0xFF51 ..= 0xFF55, 0xFF68 ..= 0xFF6C,
+        0xFF70 ..= 0xFF70, 0xFF72 ..= 0xFF77,
+    ];
+
+    #[test]
+    fn address_sweep() {
+        for cgb in [false, true] {
+            for sound in [false, true] {
+                let mut m = match cgb {
+                    false => MMU::new(rom(0x00), None).unwrap(),
+                    true => MMU::new_cgb(rom(0x80), None).unwrap(),
+                };
+                if sound {
+                    let options = SoundOptions::default();
+                    m.sound = Some(match cgb {
+                        false => Sound::new_dmg(Box::new(NullPlayer), options),
+                        true => Sound::new_cgb(Box::new(NullPlayer), options),
+                    });
+                }
+                for address in 0x0000 ..= 0xFFFF {
+                    m.rb(address);
+                }
+                for address in 0x0000 ..= 0xFFFF {
+                    m.wb(address, 0x00);
+                }
+                m.do_cycle(4);
+
+                let unused = UNUSED_IO.iter().chain(CGB_IO.iter().filter(|_| !cgb)).cloned().flatten();
+                for address in unused {
+                    assert_eq!(m.rb(address), 0xFF, "{:04X} cgb {} sound {}", address, cgb, sound);
+                }
+                // Only the bits that exist are cleared
+                assert_eq!(m.rb(0xFF0F), 0xE0);
+                assert_eq!(m.rb(0xFF41) & 0x80, 0x80);
+                assert_eq!(m.rb(0xFFFF), 0x00);
+                assert_eq!(m.rb(0xC000), 0x00);
+                assert_eq!(m.rb(0xFF80), 0x00);
+            }
+        }
+    }
+
+    // Loads a pattern into a region and checks that the dump returns it
+    fn round_trip(m: &mut MMU, region: MemoryRegion, seed: u8) {
+        let len = m.dump_region(region).len();
+        assert!(len > 0, "{:?}", region);
+        let data: Vec<u8> = (0 .. len).map(|i| (i as u8).wrapping_mul(7) ^ seed).collect();
+        m.load_region(region, &data).unwrap();
+        assert_eq!(m.dump_region(region), data, "{:?}", region);
+    }
+
+    #[test]
+    fn memory_regions() {
+        let mut romdata = vec![0; 0x8000];
+        romdata[0x0143] = 0x80;
+        // MBC1 with 32 KiB of RAM
+        romdata[0x0147] = 0x03;
+        romdata[0x0149] = 0x03;
+        let mut m = MMU::new_cgb(mbc::get_mbc(romdata, true).unwrap(), None).unwrap();
+        let regions = (0 .. 2).map(MemoryRegion::Vram)
+            .chain((0 .. 8).map(MemoryRegion::Wram))
+            .chain((0 .. 4).map(MemoryRegion::CartRam))
+            .chain([MemoryRegion::Oam, MemoryRegion::Hram]);
+        for (seed, region) in regions.enumerate() {
+            round_trip(&mut m, region, seed as u8);
+        }
+        assert_eq!(m.dump_region(MemoryRegion::Wram(3))[0x123], m.wram[0x3123]);
+        assert_eq!(m.dump_region(MemoryRegion::Vram(1)).len(), 0x2000);
+        assert!(m.dump_region(MemoryRegion::Vram(2)).is_empty());
+        assert!(m.dump_region(MemoryRegion::CartRam(4)).is_empty());
+        assert!(m.load_region(MemoryRegion::CartRam(4), &[0; 0x2000]).is_err());
+        assert!(m.load_region(MemoryRegion::Oam, &[0; 0x9F]).is_err());
+
+        let mut m = MMU::new(rom(0x00), None).unwrap();
+        round_trip(&mut m, MemoryRegion::Wram(1), 0x55);
+        assert!(m.dump_region(MemoryRegion::Vram(1)).is_empty());
+        assert!(m.dump_region(MemoryRegion::Wram(2)).is_empty());
+        assert!(m.dump_region(MemoryRegion::CartRam(0)).is_empty());
+    }
+
+    #[test]
+    fn io_region() {
+        let mut m = MMU::new(rom(0x00), None).unwrap();
+        let mut io = m.dump_region(MemoryRegion::Io);
+        assert_eq!(io.len(), 0x80);
+        let registers = [(0xFF42, 0x12), (0xFF43, 0x34), (0xFF47, 0xE4), (0xFF4A, 0x56), (0xFF4B, 0x78)];
+        for (address, value) in registers {
+            io[address - 0xFF00] = value;
+        }
+        io[0xFF46 - 0xFF00] = 0xC0;
+        m.load_region(MemoryRegion::Io, &io).unwrap();
+        for (address, value) in registers {
+            assert_eq!(m.peek(address as u16), value);
+        }
+        // No OAM DMA was started
+        assert!(m.oamdma_start.is_none());
+    }
+
+    #[test]
+    fn full_bus_region() {
+        let mut m = MMU::new_cgb(rom(0x80), None).unwrap();
+        m.wb(0xFF70, 0x03);
+        m.wb(0xFF4F, 0x01);
+        m.intf = 0x1F;
+        let mut bus = m.dump_region(MemoryRegion::FullBus);
+        assert_eq!(bus.len(), 0x10000);
+        // Reading has no side effects
+        assert!(m.dump_region(MemoryRegion::FullBus) == bus);
+        assert_eq!(m.intf, 0x1F);
+
+        for address in [0x8000, 0x9FFF, 0xC123, 0xD456, 0xFE00, 0xFF80, 0xFFFF] {
+            bus[address] = address as u8 ^ 0xA5;
+        }
+        m.load_region(MemoryRegion::FullBus, &bus).unwrap();
+        // The echo shows what was written to WRAM
+        bus[0xE123] = bus[0xC123];
+        bus[0xF456] = bus[0xD456];
+        assert!(m.dump_region(MemoryRegion::FullBus) == bus);
+        // The banks that are mapped are written
+        assert_eq!(m.dump_region(MemoryRegion::Vram(1))[0], 0x8000u16 as u8 ^ 0xA5);
+        assert_eq!(m.dump_region(MemoryRegion::Wram(3))[0x456], 0x56 ^ 0xA5);
+        assert_eq!(m.inte, 0xFF ^ 0xA5);
+    }
 }
@@ -0,0 +1,167 @@
+use crate::gpu::crc32;
+
+// Deflate stored blocks hold at most this many bytes
+const STORED_BLOCK: usize = 0xFFFF;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PngColor {
+    // One byte per pixel
+    Gray,
+    // Four bytes per pixel
+    Rgba,
+}
+
+impl PngColor {
+    fn channels(self) -> usize {
+        match self {
+            PngColor::Gray => 1,
+            PngColor::Rgba => 4,
+        }
+    }
+
+    fn color_type(self) -> u8 {
+        match self {
+            PngColor::Gray => 0,
+            PngColor::Rgba => 6,
+        }
+    }
+}
+
+// Encodes 8 bit pixels as a PNG file. The image data is not compressed, which keeps this small
+// and fast; a screenshot is about 90 KiB.
+pub fn encode_png(width: usize, height: usize, color: PngColor, pixels: &[u8]) -> Vec<u8> {
+    let stride = width * color.channels();
+    assert!(width > 0 && height > 0, "empty image");
+    assert_eq!(pixels.len(), stride * height, "pixel data does not match the image size");
+
+    // Every row starts with filter type 0, none
+    let mut raw = Vec::with_capacity((stride + 1) * height);
+    for row in pixels.chunks_exact(stride) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(STORED_BLOCK).peekable();
+    while let Some(block) = blocks.next() {
+        let len = block.len() as u16;
+        zlib.push(blocks.peek().is_none() as u8);
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&(width as u32).to_be_bytes());
+    ihdr.extend_from_slice(&(height as u32).to_be_bytes());
+    ihdr.extend_from_slice(&[8, color.color_type(), 0, 0, 0]);
+
+    let mut png = vec![0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+    write_chunk(&mut png, b"IHDR", &ihdr);
+    write_chunk(&mut png, b"IDAT", &zlib);
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = png.len();
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    let crc = crc32(&png[start ..]);
+    png.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    // Sums of 5552 bytes cannot overflow before the modulo
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= 65521;
+        b %= 65521;
+    }
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod test {
+    use std::convert::TryInto;
+    use super::{adler32, encode_png, PngColor};
+
+    // The chunks of a PNG file as type and data, checking their CRCs
+    fn read_chunks(png: &[u8]) -> Vec<(String, Vec<u8>)> {
+        assert_eq!(&png[.. 8], b"\x89PNG\r\n\x1a\n");
+        let mut chunks = Vec::new();
+        let mut pos = 8;
+        while pos < png.len() {
+            let len = u32::from_be_bytes(png[pos .. pos + 4].try_into().unwrap()) as usize;
+            let body = &png[pos + 4 .. pos + 8 + len];
+            let crc = u32::from_be_bytes(png[pos + 8 + len .. pos + 12 + len].try_into().unwrap());
+            assert_eq!(crate::gpu::crc32(body), crc);
+            chunks.push((String::from_utf8(body[.. 4].to_vec()).unwrap(), body[4 ..].to_vec()));
+            pos += 12 + len;
+        }
+        chunks
+    }
+
+    // Reads back zlib data made of stored blocks
+    fn inflate_stored(zlib: &[u8]) -> Vec<u8> {
+        assert_eq!(&zlib[.. 2], &[0x78, 0x01]);
+        let mut out = Vec::new();
+        let mut pos = 2;
+        loop {
+            let last = zlib[pos] & 1 == 1;
+            assert_eq!(zlib[pos] & 0x06, 0, "not a stored block");
+            let len = u16::from_le_bytes([zlib[pos + 1], zlib[pos + 2]]);
+            assert_eq!(!len, u16::from_le_bytes([zlib[pos + 3], zlib[pos + 4]]));
+            out.extend_from_slice(&zlib[pos + 5 .. pos + 5 + len as usize]);
+            pos += 5 + len as usize;
+            if last { break }
+        }
+        assert_eq!(&zlib[pos ..], &adler32(&out).to_be_bytes());
+        out
+    }
+
+    #[test]
+    fn adler32_check_value() {
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+        assert_eq!(adler32(&[0xFF; 100000]), 0x149A302C);
+    }
+
+    #[test]
+    fn png_layout() {
+        let pixels: Vec<u8> = (0 .. 3 * 2 * 4).map(|i| i as u8).collect();
+        let png = encode_png(3, 2, PngColor::Rgba, &pixels);
+        let chunks = read_chunks(&png);
+        let kinds: Vec<&str> = chunks.iter().map(|(kind, _)| kind.as_str()).collect();
+        assert_eq!(kinds, ["IHDR", "IDAT", "IEND"]);
+        assert_eq!(chunks[0].1, [0, 0, 0, 3, 0, 0, 0, 2, 8, 6, 0, 0, 0]);
+
+        let mut rows = vec![0];
+        rows.extend_from_slice(&pixels[.. 12]);
+        rows.push(0);
+        rows.extend_from_slice(&pixels[12 ..]);
+        assert_eq!(inflate_stored(&chunks[1].1), rows);
+        assert!(chunks[2].1.is_empty());
+    }
+
+    #[test]
+    fn png_spans_stored_blocks() {
+        let pixels: Vec<u8> = (0 .. 160 * 144 * 4).map(|i| (i * 7) as u8).collect();
+        let chunks = read_chunks(&encode_png(160, 144, PngColor::Rgba, &pixels));
+        let raw = inflate_stored(&chunks[1].1);
+        assert_eq!(raw.len(), 144 * (1 + 160 * 4));
+        for (row, pixels) in raw.chunks(1 + 160 * 4).zip(pixels.chunks(160 * 4)) {
+            assert_eq!(row[0], 0);
+            assert!(&row[1 ..] == pixels);
+        }
+
+        let gray = read_chunks(&encode_png(160, 144, PngColor::Gray, &[0x55; 160 * 144]));
+        assert_eq!(gray[0].1[9], 0);
+        assert_eq!(inflate_stored(&gray[1].1).len(), 144 * 161);
+    }
+}
@@ -0,0 +1,131 @@
+// Each 16 KiB ROM region that runs code gets an array of counters, allocated on first use
+const REGION_SIZE: usize = 0x4000;
+
+#[derive(Default, Copy, Clone)]
+struct Counter {
+    instructions: u64,
+    cycles: u64,
+}
+
+// The time spent on an address. The bank is the ROM bank mapped at the address, and 0 for code
+// running from RAM.
+#[derive(PartialEq, Clone, Debug)]
+pub struct ProfileEntry {
+    pub bank: usize,
+    pub pc: u16,
+    pub instructions: u64,
+    // In M-cycles
+    pub cycles: u64,
+}
+
+// Counts the executed instructions and their cycles per ROM bank and address
+pub struct Profiler {
+    // Indexed by the bank times 2, plus 1 for a bank mapped at 4000-7FFF
+    rom: Vec<Option<Box<[Counter]>>>,
+    // 8000-FFFF
+    ram: Box<[Counter]>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            rom: Vec::new(),
+            ram: vec![Counter::default(); 0x8000].into_boxed_slice(),
+        }
+    }
+
+    pub fn record(&mut self, bank: usize, pc: u16, cycles: u32) {
+        let counter = match pc {
+            0x0000 ..= 0x7FFF => {
+                let region = bank * 2 + (pc as usize >> 14);
+                if region >= self.rom.len() {
+                    self.rom.resize_with(region + 1, || None);
+                }
+                let counters = self.rom[region].get_or_insert_with(|| vec![Counter::default(); REGION_SIZE].into_boxed_slice());
+                &mut counters[pc as usize & (REGION_SIZE - 1)]
+            },
+            _ => &mut self.ram[pc as usize - 0x8000],
+        };
+        counter.instructions += 1;
+        counter.cycles += cycles as u64;
+    }
+
+    // The top_n addresses with the most cycles, the most first
+    pub fn report(&self, top_n: usize) -> Vec<ProfileEntry> {
+        let rom = self.rom.iter().enumerate().filter_map(|(region, counters)| counters.as_ref().map(|c| (region, c)))
+            .flat_map(|(region, counters)| {
+                let base = (region & 1) * REGION_SIZE;
+                counters.iter().enumerate().map(move |(offset, c)| (region / 2, (base + offset) as u16, c))
+            });
+        let ram = self.ram.iter().enumerate().map(|(offset, c)| (0, (0x8000 + offset) as u16, c));
+        let mut entries: Vec<ProfileEntry> = rom.chain(ram)
+            .filter(|(_, _, c)| c.instructions > 0)
+            .map(|(bank, pc, c)| ProfileEntry { bank, pc, instructions: c.instructions, cycles: c.cycles })
+            .collect();
+        entries.sort_by(|a, b| b.cycles.cmp(&a.cycles).then(a.bank.cmp(&b.bank)).then(a.pc.cmp(&b.pc)));
+        entries.truncate(top_n);
+        entries
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ProfileEntry, Profiler};
+    use crate::cpu::CPU;
+    use crate::mbc;
+
+    fn entry(bank: usize, pc: u16, instructions: u64, cycles: u64) -> ProfileEntry {
+        ProfileEntry { bank, pc, instructions, cycles }
+    }
+
+    #[test]
+    fn report_order() {
+        let mut p = Profiler::new();
+        p.record(0, 0x0150, 1);
+        p.record(3, 0x4000, 2);
+        p.record(3, 0x4000, 2);
+        p.record(0, 0xFF80, 3);
+        p.record(5, 0x4000, 1);
+        assert_eq!(p.report(10), [
+            entry(3, 0x4000, 2, 4),
+            entry(0, 0xFF80, 1, 3),
+            entry(0, 0x0150, 1, 1),
+            entry(5, 0x4000, 1, 1),
+        ]);
+        assert_eq!(p.report(1), [entry(3, 0x4000, 2, 4)]);
+    }
+
+    #[test]
+    fn banked_code() {
+        // MBC1 with 4 banks. Bank 0 calls 4000 in bank 2, then in bank 3.
+        let mut romdata = vec![0; 0x10000];
+        romdata[0x0147] = 0x01;
+        romdata[0x0148] = 0x01;
+        let code = [
+            0x3E, 0x02,       // 0100 LD A,02
+            0xEA, 0x00, 0x20, // 0102 LD (2000),A
+            0xCD, 0x00, 0x40, // 0105 CALL 4000
+            0x3C,             // 0108 INC A
+            0xEA, 0x00, 0x20, // 0109 LD (2000),A
+            0xCD, 0x00, 0x40, // 010C CALL 4000
+            0x18, 0xFE,       // 010F JR 010F
+        ];
+        romdata[0x0100 .. 0x0100 + code.len()].copy_from_slice(&code);
+        romdata[0x8000] = 0xC9;                                   // Bank 2: RET
+        romdata[0xC000 .. 0xC002].copy_from_slice(&[0x00, 0xC9]); // Bank 3: NOP; RET
+
+        let mut c = CPU::new(mbc::get_mbc(romdata, true).unwrap(), None).unwrap();
+        c.start_profile();
+        for _ in 0 .. 10 {
+            c.do_cycle();
+        }
+        let report = c.profile_report(usize::MAX);
+        assert_eq!(report.iter().filter(|e| e.pc >= 0x4000).cloned().collect::<Vec<_>>(), [
+            entry(2, 0x4000, 1, 4),
+            entry(3, 0x4001, 1, 4),
+            entry(3, 0x4000, 1, 1),
+        ]);
+        assert!(report.contains(&entry(0, 0x010F, 1, 3)));
+        assert!(report.contains(&entry(0, 0x0105, 1, 6)));
+    }
+}
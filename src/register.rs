@@ -1,8 +1,10 @@
 use crate::gbmode::GbMode;
+use std::fmt;
 
 #[derive(Copy, Clone)]
 pub struct Registers {
     pub a: u8,
+    // Only the upper nibble exists, so every write masks the lower one
     f: u8,
     pub b: u8,
     pub c: u8,
@@ -72,8 +74,18 @@ impl Registers {
         }
     }
 
+    // Before the boot ROM has run
+    pub fn power_on() -> Registers {
+        Registers { a: 0, f: 0, b: 0, c: 0, d: 0, e: 0, h: 0, l: 0, pc: 0x0000, sp: 0x0000 }
+    }
+
+    // After the SGB boot ROM has run
+    pub fn sgb() -> Registers {
+        Registers { a: 0x01, f: 0, b: 0x00, c: 0x14, d: 0x00, e: 0x00, h: 0xC0, l: 0x60, pc: 0x0100, sp: 0xFFFE }
+    }
+
     pub fn af(&self) -> u16 {
-        ((self.a as u16) << 8) | ((self.f & 0xF0) as u16)
+        ((self.a as u16) << 8) | (self.f as u16)
     }
     pub fn bc(&self) -> u16 {
         ((self.b as u16) << 8) | (self.c as u16)
@@ -86,12 +98,12 @@ impl Registers {
     }
     pub fn hld(&mut self) -> u16 {
         let res = self.hl();
-        self.sethl(res - 1);
+        self.sethl(res.wrapping_sub(1));
         res
     }
     pub fn hli(&mut self) -> u16 {
         let res = self.hl();
-        self.sethl(res + 1);
+        self.sethl(res.wrapping_add(1));
         res
     }
 
@@ -133,6 +145,16 @@ impl Registers {
     }
 }
 
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use CpuFlag::*;
+        let flag = |flag: CpuFlag, name: char| if self.getflag(flag) { name } else { '-' };
+        write!(f, "AF={:04X} BC={:04X} DE={:04X} HL={:04X} SP={:04X} PC={:04X} {}{}{}{}",
+            self.af(), self.bc(), self.de(), self.hl(), self.sp, self.pc,
+            flag(Z, 'Z'), flag(N, 'N'), flag(H, 'H'), flag(C, 'C'))
+    }
+}
+
 #[cfg(test)]
 mod test
 {
@@ -0,0 +1,88 @@
+// Converts a continuous stereo stream from one sample rate to another by linear interpolation.
+// The state carries over between calls, so the stream can be fed in chunks of any size.
+pub struct Resampler {
+    input_rate: u64,
+    output_rate: u64,
+    // Position of the next output sample in units of 1 / output_rate input samples, where 0 is
+    // the last sample of the previous chunk. Keeping it exact makes the result independent of
+    // the chunk sizes.
+    pos: u64,
+    prev: (f32, f32),
+    out_left: Vec<f32>,
+    out_right: Vec<f32>,
+}
+
+impl Resampler {
+    pub fn new(input_rate: u32, output_rate: u32) -> Resampler {
+        Resampler {
+            input_rate: input_rate as u64,
+            output_rate: output_rate as u64,
+            pos: output_rate as u64,
+            prev: (0.0, 0.0),
+            out_left: Vec::new(),
+            out_right: Vec::new(),
+        }
+    }
+
+    // Returns the resampled chunk, which is valid until the next call
+    pub fn process(&mut self, left: &[f32], right: &[f32]) -> (&[f32], &[f32]) {
+        debug_assert!(left.len() == right.len());
+        self.out_left.clear();
+        self.out_right.clear();
+
+        let len = left.len();
+        let sample = |i: usize, prev: f32, buf: &[f32]| if i == 0 { prev } else { buf[i - 1] };
+        while self.pos / self.output_rate < len as u64 {
+            let i = (self.pos / self.output_rate) as usize;
+            let frac = (self.pos % self.output_rate) as f32 / self.output_rate as f32;
+            let l0 = sample(i, self.prev.0, left);
+            let r0 = sample(i, self.prev.1, right);
+            self.out_left.push(l0 + (left[i] - l0) * frac);
+            self.out_right.push(r0 + (right[i] - r0) * frac);
+            self.pos += self.input_rate;
+        }
+
+        if len > 0 {
+            self.pos -= len as u64 * self.output_rate;
+            self.prev = (left[len - 1], right[len - 1]);
+        }
+        (&self.out_left, &self.out_right)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Resampler;
+
+    fn resample_in_chunks(resampler: &mut Resampler, input: &[f32], chunk: usize) -> Vec<f32> {
+        let mut output = Vec::new();
+        for part in input.chunks(chunk) {
+            output.extend_from_slice(resampler.process(part, part).0);
+        }
+        output
+    }
+
+    #[test]
+    fn ratios() {
+        let input: Vec<f32> = (0 .. 44100).map(|i| (i as f32 * 0.01).sin()).collect();
+        for &rate in [11025u32, 22050, 32000, 44100, 48000, 96000, 192000].iter() {
+            let output = resample_in_chunks(&mut Resampler::new(44100, rate), &input, 1000);
+            // One second in gives one second out, give or take a step of the input
+            assert!(output.len().abs_diff(rate as usize) <= (rate / 44100) as usize + 1, "{} Hz gives {} samples", rate, output.len());
+            for (j, v) in output.iter().enumerate() {
+                let t = j as f64 * 44100.0 / rate as f64;
+                let expected = (t * 0.01).sin() as f32;
+                assert!((v - expected).abs() < 1e-3, "{} Hz sample {}: {} vs {}", rate, j, v, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn chunk_size_does_not_matter() {
+        let input: Vec<f32> = (0 .. 5000).map(|i| ((i * 7919) % 200) as f32 / 100.0 - 1.0).collect();
+        let whole = resample_in_chunks(&mut Resampler::new(44100, 48000), &input, input.len());
+        for &chunk in [1usize, 7, 64, 999].iter() {
+            assert_eq!(resample_in_chunks(&mut Resampler::new(44100, 48000), &input, chunk), whole);
+        }
+    }
+}
@@ -0,0 +1,289 @@
+use crate::device::Device;
+use crate::snapshot::{SnapshotBudget, SnapshotHandle, SnapshotKind};
+use std::collections::VecDeque;
+
+// The Gameboy shows about 59.7 frames per second
+const FRAMES_PER_SECOND: u32 = 60;
+
+#[derive(Clone, Copy, Debug)]
+pub struct RewindOptions {
+    // The frames between two snapshots. Every snapshot that is popped steps back this far.
+    pub interval: u32,
+    // How far back the rewind goes, which sets the number of snapshots that are kept
+    pub seconds: u32,
+    // Keeps the snapshots except the newest as the bytes that differ from the one after them
+    pub compress: bool,
+}
+
+impl Default for RewindOptions {
+    fn default() -> RewindOptions {
+        RewindOptions {
+            interval: 6,
+            seconds: 10,
+            compress: true,
+        }
+    }
+}
+
+// A ring buffer of save states of the last seconds, kept in the rewind part of a snapshot budget.
+// A save state is about 117 KiB and takes about 10 us, so 10 seconds at the default interval take
+// 12 MiB uncompressed. Compressing takes about 80 us more and keeps only the bytes that changed,
+// from a few hundred bytes for a still screen up to the 68 KiB of the screen plus the memory that
+// changed when everything moves.
+pub struct Rewind {
+    options: RewindOptions,
+    capacity: usize,
+    frames: u32,
+    // Oldest first. When compressed, only the newest is a full state.
+    snapshots: VecDeque<SnapshotHandle>,
+}
+
+impl Rewind {
+    pub fn new(options: RewindOptions, budget: &mut SnapshotBudget) -> Rewind {
+        let interval = options.interval.max(1);
+        budget.set_rewind_interval(interval);
+        Rewind {
+            options,
+            capacity: ((options.seconds * FRAMES_PER_SECOND / interval) as usize).max(1),
+            frames: 0,
+            snapshots: VecDeque::new(),
+        }
+    }
+
+    // The number of snapshots that can be popped, unless the budget evicted some of them
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    // Called after every frame. Takes a snapshot at the interval of the budget, which grows when
+    // the budget runs out of memory.
+    pub fn frame(&mut self, device: &Device, budget: &mut SnapshotBudget) {
+        self.frames += 1;
+        if self.frames >= budget.rewind_interval() {
+            self.frames = 0;
+            self.push(device.save_state(), budget);
+        }
+    }
+
+    // Loads the newest snapshot and removes it. Returns false when there is none left.
+    pub fn rewind(&mut self, device: &mut Device, budget: &mut SnapshotBudget) -> bool {
+        self.frames = 0;
+        let state = match self.take_newest(budget) {
+            Some(state) => state,
+            None => return false,
+        };
+        if self.options.compress {
+            match self.take_newest(budget) {
+                Some(delta) => {
+                    let older = apply_delta(&state, &delta);
+                    self.store(older, budget);
+                },
+                None => self.clear(budget),
+            }
+        }
+        device.load_state(&state).is_ok()
+    }
+
+    pub fn clear(&mut self, budget: &mut SnapshotBudget) {
+        for handle in self.snapshots.drain(..) {
+            budget.release(handle);
+        }
+    }
+
+    fn push(&mut self, state: Vec<u8>, budget: &mut SnapshotBudget) {
+        if self.options.compress {
+            // The newest snapshot is replaced by what differs from the new state
+            match self.take_newest(budget) {
+                Some(newest) if newest.len() == state.len() => {
+                    let delta = encode_delta(&state, &newest);
+                    self.store(delta, budget);
+                },
+                _ => self.clear(budget),
+            }
+        }
+        while self.snapshots.len() >= self.capacity {
+            if let Some(handle) = self.snapshots.pop_front() {
+                budget.release(handle);
+            }
+        }
+        self.store(state, budget);
+    }
+
+    // When the budget evicted the newest snapshot, it evicted all the older ones before it
+    fn take_newest(&mut self, budget: &mut SnapshotBudget) -> Option<Vec<u8>> {
+        let newest = self.snapshots.pop_back().and_then(|handle| budget.release(handle));
+        if newest.is_none() {
+            self.clear(budget);
+        }
+        newest
+    }
+
+    fn store(&mut self, data: Vec<u8>, budget: &mut SnapshotBudget) {
+        match budget.store(SnapshotKind::Rewind, data) {
+            Some(handle) => self.snapshots.push_back(handle),
+            None => self.clear(budget),
+        }
+    }
+}
+
+// The bytes of target that differ from base, as runs of the number of equal bytes, the number
+// of differing bytes and the differing bytes. Both have to be of the same length.
+fn encode_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut delta = Vec::new();
+    let mut i = 0;
+    while i < target.len() {
+        let start = i;
+        while i < target.len() && target[i] == base[i] {
+            i += 1;
+        }
+        write_count(&mut delta, i - start);
+        let start = i;
+        while i < target.len() && target[i] != base[i] {
+            i += 1;
+        }
+        write_count(&mut delta, i - start);
+        delta.extend_from_slice(&target[start .. i]);
+    }
+    delta
+}
+
+fn apply_delta(base: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut target = base.to_vec();
+    let mut pos = 0;
+    let mut i = 0;
+    while i < delta.len() {
+        pos += read_count(delta, &mut i);
+        let n = read_count(delta, &mut i);
+        target[pos .. pos + n].copy_from_slice(&delta[i .. i + n]);
+        pos += n;
+        i += n;
+    }
+    target
+}
+
+// 7 bits per byte, with the top bit set on all but the last
+fn write_count(out: &mut Vec<u8>, mut n: usize) {
+    while n >= 0x80 {
+        out.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+fn read_count(data: &[u8], i: &mut usize) -> usize {
+    let mut n = 0;
+    let mut shift = 0;
+    loop {
+        let v = data[*i];
+        *i += 1;
+        n |= ((v & 0x7F) as usize) << shift;
+        if v & 0x80 == 0 {
+            return n;
+        }
+        shift += 7;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{apply_delta, encode_delta, Rewind, RewindOptions};
+    use crate::device::Device;
+    use crate::snapshot::SnapshotBudget;
+
+    const CPUINSTRS: &str = "roms/cpu_instrs.gb";
+
+    #[test]
+    fn delta() {
+        let base: Vec<u8> = (0 .. 1000).map(|i| i as u8).collect();
+        let mut target = base.clone();
+        target[0] = 0xFF;
+        target[500 .. 700].iter_mut().for_each(|v| *v = !*v);
+        target[999] = 0;
+        let delta = encode_delta(&base, &target);
+        assert!(delta.len() < 220);
+        assert_eq!(apply_delta(&base, &delta), target);
+        assert_eq!(apply_delta(&base, &encode_delta(&base, &base)), base);
+    }
+
+    // Captures a snapshot every 2 frames for 10 frames and returns the states that were captured
+    fn run(rewind: &mut Rewind, device: &mut Device, budget: &mut SnapshotBudget) -> Vec<Vec<u8>> {
+        let mut states = Vec::new();
+        for frame in 1 ..= 10 {
+            device.run_cycles(70224);
+            rewind.frame(device, budget);
+            if frame % 2 == 0 {
+                states.push(device.save_state());
+            }
+        }
+        states
+    }
+
+    #[test]
+    fn rewind_order() {
+        for compress in [true, false] {
+            let mut budget = SnapshotBudget::new(64 * 1024 * 1024);
+            let options = RewindOptions { interval: 2, seconds: 1, compress };
+            let mut rewind = Rewind::new(options, &mut budget);
+            let mut device = Device::new(CPUINSTRS, false).unwrap();
+            let states = run(&mut rewind, &mut device, &mut budget);
+            assert_eq!(rewind.len(), 5);
+
+            for state in states.iter().rev() {
+                assert!(rewind.rewind(&mut device, &mut budget));
+                assert!(device.save_state() == *state);
+            }
+            assert!(!rewind.rewind(&mut device, &mut budget));
+            assert_eq!(budget.used(), 0);
+
+            // Emulation goes on from the last state that was popped
+            let states = run(&mut rewind, &mut device, &mut budget);
+            assert!(rewind.rewind(&mut device, &mut budget));
+            assert!(device.save_state() == states[4]);
+        }
+    }
+
+    #[test]
+    fn ring_buffer() {
+        let mut budget = SnapshotBudget::new(64 * 1024 * 1024);
+        // A second holds 3 snapshots
+        let mut rewind = Rewind::new(RewindOptions { interval: 20, seconds: 1, compress: true }, &mut budget);
+        let mut device = Device::new(CPUINSTRS, false).unwrap();
+        let mut states = Vec::new();
+        for _ in 0 .. 5 {
+            for _ in 0 .. 20 {
+                device.run_cycles(70224);
+                rewind.frame(&device, &mut budget);
+            }
+            states.push(device.save_state());
+        }
+        assert_eq!(rewind.len(), 3);
+        for state in states[2 ..].iter().rev() {
+            assert!(rewind.rewind(&mut device, &mut budget));
+            assert!(device.save_state() == *state);
+        }
+        assert!(!rewind.rewind(&mut device, &mut budget));
+    }
+
+    #[test]
+    fn budget_evicts_oldest() {
+        let mut device = Device::new(CPUINSTRS, false).unwrap();
+        let size = device.save_state().len();
+        let mut budget = SnapshotBudget::new(size * 5 / 2);
+        budget.set_min_rewind_entries(1);
+        let options = RewindOptions { interval: 2, seconds: 1, compress: false };
+        let mut rewind = Rewind::new(options, &mut budget);
+        let states = run(&mut rewind, &mut device, &mut budget);
+
+        assert_eq!(rewind.len(), 5);
+        assert!(rewind.rewind(&mut device, &mut budget));
+        assert!(device.save_state() == states[4]);
+        assert!(rewind.rewind(&mut device, &mut budget));
+        assert!(device.save_state() == states[3]);
+        assert!(!rewind.rewind(&mut device, &mut budget));
+        assert!(rewind.is_empty());
+    }
+}
@@ -0,0 +1,169 @@
+// The interface the sound channels need from a band-limited synthesis buffer.
+// Time is measured in clocks since the start of the current frame, see blip_buf for the details.
+pub trait SampleBuffer : Send {
+    fn set_rates(&mut self, clock_rate: f64, sample_rate: f64);
+    fn clear(&mut self);
+    fn add_delta(&mut self, clock_time: u32, delta: i32);
+    fn end_frame(&mut self, clock_duration: u32);
+    fn samples_avail(&self) -> u32;
+    fn read_samples(&mut self, buf: &mut [i16], stereo: bool) -> usize;
+}
+
+#[cfg(feature = "blip_buf")]
+pub fn new_sample_buffer(sample_count: u32) -> Box<dyn SampleBuffer> {
+    Box::new(blip_buf::BlipBuf::new(sample_count))
+}
+
+#[cfg(not(feature = "blip_buf"))]
+pub fn new_sample_buffer(sample_count: u32) -> Box<dyn SampleBuffer> {
+    Box::new(LinearBuffer::new(sample_count))
+}
+
+#[cfg(feature = "blip_buf")]
+impl SampleBuffer for blip_buf::BlipBuf {
+    fn set_rates(&mut self, clock_rate: f64, sample_rate: f64) {
+        blip_buf::BlipBuf::set_rates(self, clock_rate, sample_rate)
+    }
+
+    fn clear(&mut self) {
+        blip_buf::BlipBuf::clear(self)
+    }
+
+    fn add_delta(&mut self, clock_time: u32, delta: i32) {
+        blip_buf::BlipBuf::add_delta(self, clock_time, delta)
+    }
+
+    fn end_frame(&mut self, clock_duration: u32) {
+        blip_buf::BlipBuf::end_frame(self, clock_duration)
+    }
+
+    fn samples_avail(&self) -> u32 {
+        blip_buf::BlipBuf::samples_avail(self)
+    }
+
+    fn read_samples(&mut self, buf: &mut [i16], stereo: bool) -> usize {
+        blip_buf::BlipBuf::read_samples(self, buf, stereo)
+    }
+}
+
+#[cfg(any(test, not(feature = "blip_buf")))]
+use std::collections::VecDeque;
+
+// Same high-pass filter as blip_buf, which removes the DC offset over time
+#[cfg(any(test, not(feature = "blip_buf")))]
+const BASS_SHIFT : u32 = 9;
+
+// A pure Rust fallback for blip_buf. Every step is spread linearly over the two output
+// samples surrounding it, which is a lot simpler but less accurate than blip_buf.
+#[cfg(any(test, not(feature = "blip_buf")))]
+pub struct LinearBuffer {
+    factor: f64,
+    offset: f64,
+    avail: usize,
+    integrator: f64,
+    // Read from the front, so the samples that are left do not have to be moved
+    deltas: VecDeque<f64>,
+}
+
+#[cfg(any(test, not(feature = "blip_buf")))]
+impl LinearBuffer {
+    pub fn new(sample_count: u32) -> LinearBuffer {
+        LinearBuffer {
+            factor: 1.0,
+            offset: 0.0,
+            avail: 0,
+            integrator: 0.0,
+            deltas: VecDeque::from(vec![0.0; sample_count as usize + 2]),
+        }
+    }
+}
+
+#[cfg(any(test, not(feature = "blip_buf")))]
+impl SampleBuffer for LinearBuffer {
+    fn set_rates(&mut self, clock_rate: f64, sample_rate: f64) {
+        self.factor = sample_rate / clock_rate;
+    }
+
+    fn clear(&mut self) {
+        self.offset = 0.0;
+        self.avail = 0;
+        self.integrator = 0.0;
+        for v in self.deltas.iter_mut() {
+            *v = 0.0;
+        }
+    }
+
+    fn add_delta(&mut self, clock_time: u32, delta: i32) {
+        let position = self.offset + clock_time as f64 * self.factor;
+        let index = position as usize;
+        let fraction = position - index as f64;
+
+        debug_assert!(index + 1 < self.deltas.len(), "LinearBuffer overflow");
+        if index + 1 < self.deltas.len() {
+            self.deltas[index] += delta as f64 * (1.0 - fraction);
+            self.deltas[index + 1] += delta as f64 * fraction;
+        }
+    }
+
+    fn end_frame(&mut self, clock_duration: u32) {
+        self.offset += clock_duration as f64 * self.factor;
+        self.avail = self.offset as usize;
+        debug_assert!(self.avail < self.deltas.len(), "LinearBuffer overflow");
+    }
+
+    fn samples_avail(&self) -> u32 {
+        self.avail as u32
+    }
+
+    fn read_samples(&mut self, buf: &mut [i16], stereo: bool) -> usize {
+        let step = if stereo { 2 } else { 1 };
+        let count = ::std::cmp::min(self.avail, buf.len() / step);
+
+        for i in 0 .. count {
+            self.integrator += self.deltas.pop_front().unwrap_or(0.0);
+            self.deltas.push_back(0.0);
+            let sample = self.integrator.round().clamp(i16::MIN as f64, i16::MAX as f64);
+            buf[i * step] = sample as i16;
+            self.integrator -= self.integrator / (1 << BASS_SHIFT) as f64;
+        }
+
+        self.offset -= count as f64;
+        self.avail -= count;
+        count
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{LinearBuffer, SampleBuffer};
+
+    #[test]
+    fn linear_timing() {
+        let mut buffer = LinearBuffer::new(1000);
+        buffer.set_rates(1000.0, 100.0);
+
+        buffer.end_frame(1005);
+        assert_eq!(buffer.samples_avail(), 100);
+        buffer.end_frame(5);
+        assert_eq!(buffer.samples_avail(), 101);
+
+        let mut out = [0i16; 200];
+        assert_eq!(buffer.read_samples(&mut out, false), 101);
+        assert_eq!(buffer.samples_avail(), 0);
+    }
+
+    #[test]
+    fn linear_step() {
+        let mut buffer = LinearBuffer::new(100);
+        buffer.set_rates(100.0, 100.0);
+
+        buffer.add_delta(10, 1000);
+        buffer.end_frame(20);
+
+        let mut out = [0i16; 20];
+        assert_eq!(buffer.read_samples(&mut out, false), 20);
+        assert!(out[.. 10].iter().all(|&v| v == 0));
+        assert_eq!(out[10], 1000);
+        assert!(out[11 ..].iter().all(|&v| v > 950 && v < 1000));
+    }
+}
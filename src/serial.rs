@@ -1,11 +1,20 @@
+use crate::state::{StateReader, StateResult, StateWriter};
+
 pub type SerialCallback<'a> = Box<dyn FnMut(u8) -> Option<u8> + Send + 'a>;
 
 fn noop(_: u8) -> Option<u8> { None }
 
+// 8 bits at 8192 Hz, in CPU clocks, so a transfer is twice as fast in double speed
+const TRANSFER_TICKS: u32 = 8 * 512;
+// 8 bits at 262144 Hz, selected by bit 1 of SC on a Gameboy Color
+const FAST_TRANSFER_TICKS: u32 = 8 * 16;
+
 pub struct Serial<'a> {
     data: u8,
     control: u8,
     callback: SerialCallback<'a>,
+    transfer_ticks: u32,
+    reply: Option<u8>,
     pub interrupt: u8,
 }
 
@@ -13,7 +22,7 @@ impl<'a> Serial<'a>
 {
     pub fn new_with_callback(cb: SerialCallback<'a>) -> Serial<'a>
     {
-        Serial { data: 0, control: 0, callback: cb, interrupt: 0 }
+        Serial { data: 0, control: 0, callback: cb, transfer_ticks: 0, reply: None, interrupt: 0 }
     }
 
     pub fn wb(&mut self, a: u16, v: u8) {
@@ -22,23 +31,33 @@ impl<'a> Serial<'a>
             0xFF02 => {
                 self.control = v;
                 if v & 0x81 == 0x81 {
-                    match (self.callback)(self.data) {
-                        Some(v) => {
-                            self.data = v;
-                            self.interrupt = 0x8
-                        },
-                        None => {},
-                    }
+                    self.reply = (self.callback)(self.data);
+                    self.transfer_ticks = if v & 0x02 == 0x02 { FAST_TRANSFER_TICKS } else { TRANSFER_TICKS };
                 }
             },
             _ => panic!("Serial does not handle address {:4X} (write)", a),
         };
     }
 
+    pub fn do_cycle(&mut self, ticks: u32) {
+        if self.transfer_ticks == 0 { return; }
+        if ticks < self.transfer_ticks {
+            self.transfer_ticks -= ticks;
+            return;
+        }
+
+        self.transfer_ticks = 0;
+        self.control &= 0x7F;
+        if let Some(v) = self.reply.take() {
+            self.data = v;
+            self.interrupt = 0x8;
+        }
+    }
+
     pub fn rb(&self, a: u16) -> u8 {
         match a {
             0xFF01 => self.data,
-            0xFF02 => self.control | 0b01111110,
+            0xFF02 => self.control | 0b01111100,
             _ => panic!("Serial does not handle address {:4X} (read)", a),
         }
     }
@@ -50,10 +69,68 @@ impl<'a> Serial<'a>
     pub fn unset_callback(&mut self) {
         self.callback = Box::new(noop);
     }
+
+    pub fn take_callback(&mut self) -> SerialCallback<'a> {
+        ::std::mem::replace(&mut self.callback, Box::new(noop))
+    }
+
+    // The callback is not part of the state, a transfer in progress completes with the reply it
+    // got when it started
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.data);
+        w.u8(self.control);
+        w.u32(self.transfer_ticks);
+        w.bool(self.reply.is_some());
+        if let Some(reply) = self.reply {
+            w.u8(reply);
+        }
+        w.u8(self.interrupt);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.data = r.u8()?;
+        self.control = r.u8()?;
+        self.transfer_ticks = r.u32()?;
+        self.reply = match r.bool()? {
+            true => Some(r.u8()?),
+            false => None,
+        };
+        self.interrupt = r.u8()?;
+        Ok(())
+    }
 }
 
 impl Serial<'static> {
     pub fn new() -> Serial<'static> {
-        Serial { data: 0, control: 0, callback: Box::new(noop), interrupt: 0 }
+        Serial { data: 0, control: 0, callback: Box::new(noop), transfer_ticks: 0, reply: None, interrupt: 0 }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Serial, FAST_TRANSFER_TICKS, TRANSFER_TICKS};
+
+    #[test]
+    fn transfer_completes_after_8_bits() {
+        let mut serial = Serial::new_with_callback(Box::new(|v| Some(!v)));
+        serial.wb(0xFF01, 0x0F);
+        serial.wb(0xFF02, 0x81);
+        serial.do_cycle(TRANSFER_TICKS - 4);
+        assert_eq!((serial.rb(0xFF01), serial.rb(0xFF02), serial.interrupt), (0x0F, 0xFD, 0));
+
+        serial.do_cycle(4);
+        assert_eq!((serial.rb(0xFF01), serial.rb(0xFF02), serial.interrupt), (0xF0, 0x7D, 0x8));
+    }
+
+    #[test]
+    fn fast_clock() {
+        let mut serial = Serial::new_with_callback(Box::new(|v| Some(!v)));
+        serial.wb(0xFF01, 0x0F);
+        serial.wb(0xFF02, 0x83);
+        serial.do_cycle(FAST_TRANSFER_TICKS - 4);
+        assert_eq!((serial.rb(0xFF01), serial.rb(0xFF02), serial.interrupt), (0x0F, 0xFF, 0));
+
+        serial.do_cycle(4);
+        assert_eq!((serial.rb(0xFF01), serial.rb(0xFF02), serial.interrupt), (0xF0, 0x7F, 0x8));
     }
 }
@@ -0,0 +1,723 @@
+use crate::gpu::{SCREEN_H, SCREEN_W};
+use crate::state::{StateError, StateReader, StateResult, StateWriter};
+
+// The picture of a Super Game Boy, with the game screen inside the border
+pub const SGB_SCREEN_W: usize = 256;
+pub const SGB_SCREEN_H: usize = 224;
+// The top left corner of the game screen in it
+pub const SGB_GAME_X: usize = 48;
+pub const SGB_GAME_Y: usize = 40;
+
+const PACKET_SIZE: usize = 16;
+const PACKET_BITS: usize = PACKET_SIZE * 8;
+// Palettes are given for each tile of the game screen
+const ATTR_W: usize = SCREEN_W / 8;
+const ATTR_H: usize = SCREEN_H / 8;
+// An attribute file has 2 bits for each tile, ATTR_TRN sends 45 of them
+const ATTR_FILE_SIZE: usize = ATTR_W * ATTR_H / 4;
+const ATTR_FILES: usize = 45;
+// A VRAM transfer sends 4 KiB as the first 256 tiles of the screen
+const TRANSFER_SIZE: usize = 0x1000;
+// The map of 32 by 32 tiles and the 4 palettes of 16 colors that PCT_TRN sends
+const BORDER_SIZE: usize = 0x880;
+const BORDER_PALETTES: usize = 0x800;
+// A VRAM transfer reads the screen of the second frame finished after the command
+const TRANSFER_DELAY: u8 = 2;
+// Palette 1-A, which is used until a game sets its own
+const DEFAULT_PALETTE: [u16; 4] = [0x67BF, 0x265B, 0x10B5, 0x2866];
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Transfer {
+    Palettes,
+    Attributes,
+    // The second half of the 256 border tiles
+    Tiles(bool),
+    Border,
+}
+
+impl Transfer {
+    fn code(self) -> u8 {
+        match self {
+            Transfer::Palettes => 0,
+            Transfer::Attributes => 1,
+            Transfer::Tiles(false) => 2,
+            Transfer::Tiles(true) => 3,
+            Transfer::Border => 4,
+        }
+    }
+
+    fn from_code(code: u8) -> StateResult<Transfer> {
+        Ok(match code {
+            0 => Transfer::Palettes,
+            1 => Transfer::Attributes,
+            2 => Transfer::Tiles(false),
+            3 => Transfer::Tiles(true),
+            4 => Transfer::Border,
+            _ => return Err(StateError::Corrupt),
+        })
+    }
+}
+
+// The border and palettes of a Super Game Boy. A game sends commands as packets of 16 bytes,
+// one bit at a time through P14 and P15 of P1, and larger data as the screen of a frame.
+pub struct Sgb {
+    // P14 and P15 as last written
+    lines: u8,
+    // Both lines go high before each bit
+    ready: bool,
+    // The number of bits of the packet so far, the stop bit comes after 128. None until a reset
+    // pulse starts a packet.
+    bit: Option<usize>,
+    packet: [u8; PACKET_SIZE],
+    // The packets of the command so far, which takes up to 7
+    command: Vec<u8>,
+    // MLT_REQ: the number of controllers, and the one that P1 reads
+    players: u8,
+    player: u8,
+    // Colors as RGB555, color 0 is the same in all four
+    palettes: [[u16; 4]; 4],
+    // From PAL_TRN, 512 palettes of 4 colors
+    system_palettes: Vec<u8>,
+    // The palette of each tile of the game screen
+    attributes: [u8; ATTR_W * ATTR_H],
+    attribute_files: Vec<u8>,
+    // 256 tiles of 4 bits per pixel, in the SNES layout
+    border_tiles: Vec<u8>,
+    // The map and palettes of the border, empty until PCT_TRN
+    border: Vec<u8>,
+    // MASK_EN: 0 shows the game, 1 keeps the last picture, 2 shows black and 3 color 0
+    mask: u8,
+    transfer: Option<(Transfer, u8)>,
+    // The frame of the GPU that frame was made from
+    frame_count: u64,
+    frame: Vec<u8>,
+}
+
+impl Sgb {
+    pub fn new() -> Sgb {
+        let mut sgb = Sgb {
+            lines: 0x30,
+            ready: false,
+            bit: None,
+            packet: [0; PACKET_SIZE],
+            command: Vec::new(),
+            players: 1,
+            player: 0,
+            palettes: [DEFAULT_PALETTE; 4],
+            system_palettes: vec![0; TRANSFER_SIZE],
+            attributes: [0; ATTR_W * ATTR_H],
+            attribute_files: vec![0; ATTR_FILES * ATTR_FILE_SIZE],
+            border_tiles: vec![0; 2 * TRANSFER_SIZE],
+            border: Vec::new(),
+            mask: 0,
+            transfer: None,
+            frame_count: 0,
+            frame: vec![0; SGB_SCREEN_W * SGB_SCREEN_H * 4],
+        };
+        sgb.compose(&[0; SCREEN_W * SCREEN_H]);
+        sgb
+    }
+
+    pub fn write_p1(&mut self, value: u8) {
+        let lines = value & 0x30;
+        match lines {
+            // A reset pulse starts a packet
+            0x00 => {
+                self.bit = Some(0);
+                self.packet = [0; PACKET_SIZE];
+                self.ready = false;
+            },
+            0x30 => if self.lines != 0x30 {
+                self.ready = true;
+                if self.players > 1 {
+                    self.player = (self.player + 1) % self.players;
+                }
+            },
+            // P15 low sends a 1, P14 low a 0
+            _ => if let (true, Some(bit)) = (self.ready, self.bit) {
+                self.ready = false;
+                let one = lines == 0x10;
+                if bit < PACKET_BITS {
+                    self.packet[bit / 8] |= (one as u8) << (bit % 8);
+                    self.bit = Some(bit + 1);
+                } else {
+                    self.bit = None;
+                    if !one { self.packet_done(); }
+                }
+            },
+        }
+        self.lines = lines;
+    }
+
+    // P1 as the keypad gives it: with more than one controller, the number of the one that is
+    // read shows while no row is selected. The other controllers have no keys held.
+    pub fn read_p1(&self, value: u8) -> u8 {
+        if self.players > 1 && self.lines == 0x30 {
+            (value & 0xF0) | (0x0F - self.player)
+        } else if self.player != 0 {
+            value | 0x0F
+        } else {
+            value
+        }
+    }
+
+    fn packet_done(&mut self) {
+        // The first packet gives the command and its number of packets
+        if self.command.is_empty() && self.packet[0] & 0x07 == 0 { return }
+        self.command.extend_from_slice(&self.packet);
+        if self.command.len() == (self.command[0] & 0x07) as usize * PACKET_SIZE {
+            let command = std::mem::take(&mut self.command);
+            self.run(&command);
+        }
+    }
+
+    fn run(&mut self, command: &[u8]) {
+        match command[0] >> 3 {
+            0x00 => self.set_palette_pair(command, 0, 1),
+            0x01 => self.set_palette_pair(command, 2, 3),
+            0x02 => self.set_palette_pair(command, 0, 3),
+            0x03 => self.set_palette_pair(command, 1, 2),
+            0x04 => self.attr_blk(command),
+            0x05 => self.attr_lin(command),
+            0x06 => self.attr_div(command),
+            0x07 => self.attr_chr(command),
+            0x0A => self.pal_set(command),
+            0x0B => self.transfer = Some((Transfer::Palettes, TRANSFER_DELAY)),
+            0x11 => {
+                self.players = match command[1] & 0x03 { 1 => 2, 3 => 4, _ => 1 };
+                self.player = 0;
+            },
+            0x13 => self.transfer = Some((Transfer::Tiles(command[1] & 0x01 != 0), TRANSFER_DELAY)),
+            0x14 => self.transfer = Some((Transfer::Border, TRANSFER_DELAY)),
+            0x15 => self.transfer = Some((Transfer::Attributes, TRANSFER_DELAY)),
+            0x16 => self.attr_set(command[1]),
+            0x17 => self.mask = command[1] & 0x03,
+            // Sound, multiplayer data and SNES code are not supported
+            _ => {},
+        }
+    }
+
+    // PAL01, PAL23, PAL03 and PAL12: color 0 for all palettes, then colors 1 to 3 of both
+    fn set_palette_pair(&mut self, command: &[u8], a: usize, b: usize) {
+        let color = |i: usize| u16::from_le_bytes([command[1 + i * 2], command[2 + i * 2]]) & 0x7FFF;
+        for palette in self.palettes.iter_mut() {
+            palette[0] = color(0);
+        }
+        for i in 1 .. 4 {
+            self.palettes[a][i] = color(i);
+            self.palettes[b][i] = color(i + 3);
+        }
+    }
+
+    // Rectangles of tiles, with a palette inside, on the edge and outside of each
+    fn attr_blk(&mut self, command: &[u8]) {
+        let sets = command[1] as usize;
+        for set in command[2 ..].chunks_exact(6).take(sets) {
+            let (inside, mut edge, outside) = (set[1] & 0x03, (set[1] >> 2) & 0x03, (set[1] >> 4) & 0x03);
+            // Changing only the inside or the outside changes the edge with it
+            let areas = match set[0] & 0x07 {
+                0x01 => { edge = inside; 0x03 },
+                0x04 => { edge = outside; 0x06 },
+                areas => areas,
+            };
+            let (x1, y1, x2, y2) = (set[2] as usize & 0x1F, set[3] as usize & 0x1F, set[4] as usize & 0x1F, set[5] as usize & 0x1F);
+            for (i, attribute) in self.attributes.iter_mut().enumerate() {
+                let (x, y) = (i % ATTR_W, i / ATTR_W);
+                let within = x >= x1 && x <= x2 && y >= y1 && y <= y2;
+                let (area, palette) = if within && (x == x1 || x == x2 || y == y1 || y == y2) {
+                    (0x02, edge)
+                } else if within {
+                    (0x01, inside)
+                } else {
+                    (0x04, outside)
+                };
+                if areas & area != 0 {
+                    *attribute = palette;
+                }
+            }
+        }
+    }
+
+    // Whole rows or columns of tiles
+    fn attr_lin(&mut self, command: &[u8]) {
+        for &line in command[2 ..].iter().take(command[1] as usize) {
+            let (n, palette) = (line as usize & 0x1F, (line >> 5) & 0x03);
+            for (i, attribute) in self.attributes.iter_mut().enumerate() {
+                let (x, y) = (i % ATTR_W, i / ATTR_W);
+                if (line & 0x80 != 0 && y == n) || (line & 0x80 == 0 && x == n) {
+                    *attribute = palette;
+                }
+            }
+        }
+    }
+
+    // The screen split at a column or row, with a palette on each side and on the line
+    fn attr_div(&mut self, command: &[u8]) {
+        let (after, before, on) = (command[1] & 0x03, (command[1] >> 2) & 0x03, (command[1] >> 4) & 0x03);
+        let by_row = command[1] & 0x40 != 0;
+        let at = command[2] as usize & 0x1F;
+        for (i, attribute) in self.attributes.iter_mut().enumerate() {
+            let n = if by_row { i / ATTR_W } else { i % ATTR_W };
+            *attribute = match n.cmp(&at) {
+                std::cmp::Ordering::Less => before,
+                std::cmp::Ordering::Equal => on,
+                std::cmp::Ordering::Greater => after,
+            };
+        }
+    }
+
+    // The palettes of single tiles from a start tile on, 4 to a byte, along rows or columns
+    fn attr_chr(&mut self, command: &[u8]) {
+        let (mut x, mut y) = (command[1] as usize, command[2] as usize);
+        if x >= ATTR_W || y >= ATTR_H { return }
+        let count = (u16::from_le_bytes([command[3], command[4]]) as usize).min(ATTR_W * ATTR_H);
+        let by_column = command[5] & 0x01 != 0;
+        for i in 0 .. count {
+            let byte = match command.get(6 + i / 4) {
+                Some(&byte) => byte,
+                None => break,
+            };
+            self.attributes[y * ATTR_W + x] = (byte >> (6 - 2 * (i % 4))) & 0x03;
+            if by_column {
+                y += 1;
+                if y == ATTR_H { y = 0; x = (x + 1) % ATTR_W; }
+            } else {
+                x += 1;
+                if x == ATTR_W { x = 0; y = (y + 1) % ATTR_H; }
+            }
+        }
+    }
+
+    // Four of the palettes from PAL_TRN, with color 0 of the first for all, and an attribute file
+    fn pal_set(&mut self, command: &[u8]) {
+        for i in 0 .. 4 {
+            let n = (u16::from_le_bytes([command[1 + i * 2], command[2 + i * 2]]) & 0x1FF) as usize;
+            for (c, color) in self.palettes[i].iter_mut().enumerate() {
+                let at = n * 8 + c * 2;
+                *color = u16::from_le_bytes([self.system_palettes[at], self.system_palettes[at + 1]]) & 0x7FFF;
+            }
+        }
+        for i in 1 .. 4 {
+            self.palettes[i][0] = self.palettes[0][0];
+        }
+        if command[9] & 0x80 != 0 {
+            self.attr_set(command[9] & 0x3F);
+        }
+        if command[9] & 0x40 != 0 {
+            self.mask = 0;
+        }
+    }
+
+    // Applies an attribute file from ATTR_TRN, and cancels the mask with bit 6
+    fn attr_set(&mut self, value: u8) {
+        let n = (value & 0x3F) as usize;
+        if n < ATTR_FILES {
+            let file = &self.attribute_files[n * ATTR_FILE_SIZE .. (n + 1) * ATTR_FILE_SIZE];
+            for (i, attribute) in self.attributes.iter_mut().enumerate() {
+                *attribute = (file[i / 4] >> (6 - 2 * (i % 4))) & 0x03;
+            }
+        }
+        if value & 0x40 != 0 {
+            self.mask = 0;
+        }
+    }
+
+    // Takes a frame of the GPU as the shade of every pixel, once for each frame_count
+    pub fn frame_done(&mut self, frame_count: u64, indices: &[u8]) {
+        if frame_count == self.frame_count { return }
+        self.frame_count = frame_count;
+        match self.transfer {
+            Some((kind, frames)) if frames > 1 => self.transfer = Some((kind, frames - 1)),
+            Some((kind, _)) => {
+                self.transfer = None;
+                self.receive(kind, &screen_data(indices));
+            },
+            None => {},
+        }
+        self.compose(indices);
+    }
+
+    fn receive(&mut self, kind: Transfer, data: &[u8]) {
+        match kind {
+            Transfer::Palettes => self.system_palettes.copy_from_slice(data),
+            Transfer::Attributes => self.attribute_files.copy_from_slice(&data[.. ATTR_FILES * ATTR_FILE_SIZE]),
+            Transfer::Tiles(high) => {
+                let start = high as usize * TRANSFER_SIZE;
+                self.border_tiles[start .. start + TRANSFER_SIZE].copy_from_slice(data);
+            },
+            Transfer::Border => self.border = data[.. BORDER_SIZE].to_vec(),
+        }
+    }
+
+    // The color of the border at a pixel, None where it is transparent
+    fn border_color(&self, x: usize, y: usize) -> Option<u16> {
+        if self.border.is_empty() { return None }
+        let at = ((y / 8) * 32 + x / 8) * 2;
+        let entry = u16::from_le_bytes([self.border[at], self.border[at + 1]]);
+        let tile = &self.border_tiles[(entry & 0xFF) as usize * 32 ..];
+        let row = if entry & 0x8000 != 0 { 7 - y % 8 } else { y % 8 };
+        let bit = if entry & 0x4000 != 0 { x % 8 } else { 7 - x % 8 };
+        let plane = |offset: usize, shift: usize| ((tile[offset + row * 2] >> bit) & 1) << shift;
+        let color = plane(0, 0) | plane(1, 1) | plane(16, 2) | plane(17, 3);
+        if color == 0 { return None }
+        // Palettes 4 to 7
+        let at = BORDER_PALETTES + (((entry >> 10) & 0x03) as usize * 16 + color as usize) * 2;
+        Some(u16::from_le_bytes([self.border[at], self.border[at + 1]]))
+    }
+
+    fn compose(&mut self, indices: &[u8]) {
+        let backdrop = rgba(self.palettes[0][0]);
+        for y in 0 .. SGB_SCREEN_H {
+            for x in 0 .. SGB_SCREEN_W {
+                let game = (SGB_GAME_X .. SGB_GAME_X + SCREEN_W).contains(&x) && (SGB_GAME_Y .. SGB_GAME_Y + SCREEN_H).contains(&y);
+                if game && self.mask == 1 { continue }
+                let color = match (game, self.mask) {
+                    (true, 0) => {
+                        let (gx, gy) = (x - SGB_GAME_X, y - SGB_GAME_Y);
+                        let palette = self.attributes[(gy / 8) * ATTR_W + gx / 8] as usize;
+                        rgba(self.palettes[palette][indices[gy * SCREEN_W + gx] as usize & 0x03])
+                    },
+                    (true, 2) => [0, 0, 0, 0xFF],
+                    (true, _) => backdrop,
+                    (false, _) => self.border_color(x, y).map_or(backdrop, rgba),
+                };
+                let i = (y * SGB_SCREEN_W + x) * 4;
+                self.frame[i .. i + 4].copy_from_slice(&color);
+            }
+        }
+    }
+
+    // The border with the game screen inside, RGBA
+    pub fn frame(&self) -> &[u8] {
+        &self.frame
+    }
+
+    // Makes the picture again, e.g. after a loaded state
+    pub fn recompose(&mut self, frame_count: u64, indices: &[u8]) {
+        self.frame_count = frame_count;
+        self.compose(indices);
+    }
+
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.lines);
+        w.bool(self.ready);
+        w.bool(self.bit.is_some());
+        w.usize(self.bit.unwrap_or(0));
+        w.bytes(&self.packet);
+        w.vec(&self.command);
+        w.u8(self.players);
+        w.u8(self.player);
+        for &color in self.palettes.iter().flatten() {
+            w.u16(color);
+        }
+        w.bytes(&self.system_palettes);
+        w.bytes(&self.attributes);
+        w.bytes(&self.attribute_files);
+        w.bytes(&self.border_tiles);
+        w.vec(&self.border);
+        w.u8(self.mask);
+        w.bool(self.transfer.is_some());
+        if let Some((kind, frames)) = self.transfer {
+            w.u8(kind.code());
+            w.u8(frames);
+        }
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.lines = r.u8()? & 0x30;
+        self.ready = r.bool()?;
+        let started = r.bool()?;
+        let bit = r.index(PACKET_BITS + 1)?;
+        self.bit = if started { Some(bit) } else { None };
+        r.bytes_into(&mut self.packet)?;
+        self.command = r.vec()?.to_vec();
+        if self.command.len() >= 7 * PACKET_SIZE || self.command.len() & (PACKET_SIZE - 1) != 0 {
+            return Err(StateError::Corrupt);
+        }
+        self.players = r.u8()?;
+        self.player = r.u8()?;
+        if ![1, 2, 4].contains(&self.players) || self.player >= self.players {
+            return Err(StateError::Corrupt);
+        }
+        for color in self.palettes.iter_mut().flatten() {
+            *color = r.u16()? & 0x7FFF;
+        }
+        r.bytes_into(&mut self.system_palettes)?;
+        r.bytes_into(&mut self.attributes)?;
+        if self.attributes.iter().any(|&a| a > 3) {
+            return Err(StateError::Corrupt);
+        }
+        r.bytes_into(&mut self.attribute_files)?;
+        r.bytes_into(&mut self.border_tiles)?;
+        self.border = r.vec()?.to_vec();
+        if !self.border.is_empty() && self.border.len() != BORDER_SIZE {
+            return Err(StateError::Corrupt);
+        }
+        self.mask = r.u8()?;
+        if self.mask > 3 {
+            return Err(StateError::Corrupt);
+        }
+        self.transfer = match r.bool()? {
+            true => match (Transfer::from_code(r.u8()?)?, r.u8()?) {
+                (kind, frames) if (1 ..= TRANSFER_DELAY).contains(&frames) => Some((kind, frames)),
+                _ => return Err(StateError::Corrupt),
+            },
+            false => None,
+        };
+        Ok(())
+    }
+}
+
+// The first 256 tiles of the screen, row by row, as 2 bit tiles
+fn screen_data(indices: &[u8]) -> Vec<u8> {
+    let mut data = vec![0; TRANSFER_SIZE];
+    for (tile, bytes) in data.chunks_exact_mut(16).enumerate() {
+        let (tx, ty) = (tile % ATTR_W, tile / ATTR_W);
+        for row in 0 .. 8 {
+            let start = (ty * 8 + row) * SCREEN_W + tx * 8;
+            for (i, &shade) in indices[start .. start + 8].iter().enumerate() {
+                bytes[row * 2] |= (shade & 1) << (7 - i);
+                bytes[row * 2 + 1] |= ((shade >> 1) & 1) << (7 - i);
+            }
+        }
+    }
+    data
+}
+
+fn rgba(color: u16) -> [u8; 4] {
+    let channel = |shift: u16| {
+        let v = ((color >> shift) & 0x1F) as u8;
+        (v << 3) | (v >> 2)
+    };
+    [channel(0), channel(5), channel(10), 0xFF]
+}
+
+#[cfg(test)]
+mod test {
+    use super::{rgba, Sgb, ATTR_W, SGB_GAME_X, SGB_GAME_Y, SGB_SCREEN_W, TRANSFER_SIZE};
+    use crate::gpu::{SCREEN_H, SCREEN_W};
+    use std::convert::TryInto;
+
+    // Sends a packet as a game does: a reset pulse, 128 bits each followed by both lines high, and
+    // the stop bit
+    fn send_packet(sgb: &mut Sgb, packet: &[u8], stop: u8) {
+        sgb.write_p1(0x00);
+        sgb.write_p1(0x30);
+        for bit in 0 .. 128 {
+            sgb.write_p1(if packet[bit / 8] & (1 << (bit % 8)) != 0 { 0x10 } else { 0x20 });
+            sgb.write_p1(0x30);
+        }
+        sgb.write_p1(stop);
+        sgb.write_p1(0x30);
+    }
+
+    fn send(sgb: &mut Sgb, command: u8, data: &[u8]) {
+        let packets = data.len() / 16 + 1;
+        let mut bytes = vec![0; packets * 16];
+        bytes[0] = (command << 3) | packets as u8;
+        bytes[1 .. 1 + data.len()].copy_from_slice(data);
+        for packet in bytes.chunks(16) {
+            send_packet(sgb, packet, 0x20);
+        }
+    }
+
+    // The screen that a VRAM transfer of data reads
+    fn screen_of(data: &[u8]) -> Vec<u8> {
+        let mut indices = vec![0; SCREEN_W * SCREEN_H];
+        for (tile, bytes) in data.chunks(16).enumerate() {
+            for row in 0 .. 8 {
+                for i in 0 .. 8 {
+                    let shade = ((bytes[row * 2] >> (7 - i)) & 1) | (((bytes[row * 2 + 1] >> (7 - i)) & 1) << 1);
+                    indices[((tile / ATTR_W) * 8 + row) * SCREEN_W + (tile % ATTR_W) * 8 + i] = shade;
+                }
+            }
+        }
+        indices
+    }
+
+    // Runs frames until the transfer has read data
+    fn transfer(sgb: &mut Sgb, frame_count: &mut u64, command: u8, flags: u8, data: &[u8]) {
+        send(sgb, command, &[flags]);
+        let screen = screen_of(data);
+        for _ in 0 .. 2 {
+            *frame_count += 1;
+            sgb.frame_done(*frame_count, &screen);
+        }
+        assert_eq!(sgb.transfer, None);
+    }
+
+    fn pixel(sgb: &Sgb, x: usize, y: usize) -> [u8; 4] {
+        let i = (y * SGB_SCREEN_W + x) * 4;
+        sgb.frame()[i .. i + 4].try_into().unwrap()
+    }
+
+    #[test]
+    fn palette_packets() {
+        let mut sgb = Sgb::new();
+        // PAL01: color 0, colors 1-3 of palette 0 and of palette 1
+        send(&mut sgb, 0x00, &[0x1F, 0x00, 0xE0, 0x03, 0x00, 0x7C, 0xFF, 0x7F, 0x01, 0x00, 0x02, 0x00, 0x03, 0x00]);
+        assert_eq!(sgb.palettes[0], [0x001F, 0x03E0, 0x7C00, 0x7FFF]);
+        assert_eq!(sgb.palettes[1], [0x001F, 0x0001, 0x0002, 0x0003]);
+        assert_eq!(sgb.palettes[2][0], 0x001F);
+
+        // A packet that ends with a 1 instead of the stop bit is dropped, as is one of no packets
+        let mut packet = [0; 16];
+        packet[0] = (0x01 << 3) | 1;
+        packet[1] = 0x42;
+        send_packet(&mut sgb, &packet, 0x10);
+        packet[0] = 0x01 << 3;
+        send_packet(&mut sgb, &packet, 0x20);
+        assert_eq!(sgb.palettes[0][0], 0x001F);
+
+        // The game screen gets the palettes of its tiles, the backdrop color 0
+        sgb.frame_done(1, &[1; SCREEN_W * SCREEN_H]);
+        assert_eq!(pixel(&sgb, SGB_GAME_X, SGB_GAME_Y), rgba(0x03E0));
+        assert_eq!(pixel(&sgb, 0, 0), [0xFF, 0, 0, 0xFF]);
+        assert_eq!(pixel(&sgb, SGB_GAME_X, SGB_GAME_Y), [0, 0xFF, 0, 0xFF]);
+
+        // MASK_EN: black, color 0, and the last picture kept
+        send(&mut sgb, 0x17, &[0x02]);
+        sgb.frame_done(2, &[1; SCREEN_W * SCREEN_H]);
+        assert_eq!(pixel(&sgb, SGB_GAME_X, SGB_GAME_Y), [0, 0, 0, 0xFF]);
+        send(&mut sgb, 0x17, &[0x03]);
+        sgb.frame_done(3, &[1; SCREEN_W * SCREEN_H]);
+        assert_eq!(pixel(&sgb, SGB_GAME_X, SGB_GAME_Y), [0xFF, 0, 0, 0xFF]);
+        send(&mut sgb, 0x17, &[0x01]);
+        sgb.frame_done(4, &[2; SCREEN_W * SCREEN_H]);
+        assert_eq!(pixel(&sgb, SGB_GAME_X, SGB_GAME_Y), [0xFF, 0, 0, 0xFF]);
+    }
+
+    #[test]
+    fn multiplayer() {
+        let mut sgb = Sgb::new();
+        assert_eq!(sgb.read_p1(0xCF), 0xCF);
+
+        // The controller number changes each time both lines go high again
+        for (request, players) in [(0x01, 2), (0x03, 4)] {
+            send(&mut sgb, 0x11, &[request]);
+            let mut ids = Vec::new();
+            for _ in 0 .. players {
+                ids.push(sgb.read_p1(0xCF) & 0x0F);
+                sgb.write_p1(0x20);
+                sgb.write_p1(0x30);
+            }
+            ids.sort_unstable();
+            assert_eq!(ids, (0x10 - players .. 0x10).collect::<Vec<u8>>(), "{} players", players);
+        }
+
+        // Only the first controller has keys
+        while sgb.player != 1 {
+            sgb.write_p1(0x20);
+            sgb.write_p1(0x30);
+        }
+        sgb.write_p1(0x20);
+        assert_eq!(sgb.read_p1(0xE6), 0xEF);
+        send(&mut sgb, 0x11, &[0x00]);
+        sgb.write_p1(0x20);
+        assert_eq!(sgb.read_p1(0xE6), 0xE6);
+    }
+
+    #[test]
+    fn attribute_commands() {
+        let at = |sgb: &Sgb, x: usize, y: usize| sgb.attributes[y * ATTR_W + x];
+        let mut sgb = Sgb::new();
+        // ATTR_BLK: only the inside of 1,1-3,3 as palette 2 changes the edge too
+        send(&mut sgb, 0x04, &[1, 0x01, 0x02, 1, 1, 3, 3]);
+        assert_eq!((at(&sgb, 1, 1), at(&sgb, 2, 2), at(&sgb, 3, 3), at(&sgb, 4, 4), at(&sgb, 0, 0)), (2, 2, 2, 0, 0));
+        // Then the outside as 1 and the edge as 3
+        send(&mut sgb, 0x04, &[1, 0x06, 0x1C, 1, 1, 3, 3]);
+        assert_eq!((at(&sgb, 1, 1), at(&sgb, 2, 2), at(&sgb, 4, 4), at(&sgb, 0, 0)), (3, 2, 1, 1));
+
+        // ATTR_LIN: column 5 as palette 1 and row 2 as palette 3
+        send(&mut sgb, 0x05, &[2, 0x20 | 5, 0x80 | 0x60 | 2]);
+        assert_eq!((at(&sgb, 5, 0), at(&sgb, 5, 17), at(&sgb, 6, 2), at(&sgb, 2, 1)), (1, 1, 3, 3));
+
+        // ATTR_DIV at row 9: above, on and below
+        send(&mut sgb, 0x06, &[0x40 | 0x20 | 0x04 | 0x03, 9]);
+        assert_eq!((at(&sgb, 0, 8), at(&sgb, 0, 9), at(&sgb, 0, 10)), (1, 2, 3));
+
+        // ATTR_CHR: five tiles from 18,0 along the rows, wrapping to the next
+        send(&mut sgb, 0x07, &[18, 0, 5, 0, 0, 0b0001_1011, 0b1100_0000]);
+        assert_eq!((at(&sgb, 18, 0), at(&sgb, 19, 0), at(&sgb, 0, 1), at(&sgb, 1, 1), at(&sgb, 2, 1)), (0, 1, 2, 3, 3));
+    }
+
+    #[test]
+    fn palette_and_attribute_transfers() {
+        let mut sgb = Sgb::new();
+        let mut frame_count = 0;
+        // PAL_TRN: system palette 5 and 300
+        let mut data = vec![0; TRANSFER_SIZE];
+        data[5 * 8 .. 6 * 8].copy_from_slice(&[0x11, 0x00, 0x22, 0x00, 0x33, 0x00, 0x44, 0x00]);
+        data[300 * 8 .. 301 * 8].copy_from_slice(&[0x55, 0x00, 0x66, 0x00, 0x77, 0x00, 0x88, 0x00]);
+        transfer(&mut sgb, &mut frame_count, 0x0B, 0, &data);
+
+        // ATTR_TRN: file 2 has palette 3 for the first tile
+        let mut data = vec![0; TRANSFER_SIZE];
+        data[2 * 90] = 0xC0;
+        transfer(&mut sgb, &mut frame_count, 0x15, 0, &data);
+
+        // PAL_SET of palettes 5, 300 (012C), 5 and 300 with file 2 applied, color 0 of the first palette for all
+        send(&mut sgb, 0x0A, &[5, 0, 0x2C, 0x01, 5, 0, 0x2C, 0x01, 0x80 | 2]);
+        assert_eq!(sgb.palettes[0], [0x11, 0x22, 0x33, 0x44]);
+        assert_eq!(sgb.palettes[1], [0x11, 0x66, 0x77, 0x88]);
+        assert_eq!(sgb.palettes[3], [0x11, 0x66, 0x77, 0x88]);
+        assert_eq!((sgb.attributes[0], sgb.attributes[1]), (3, 0));
+
+        // ATTR_SET of another file
+        send(&mut sgb, 0x16, &[0]);
+        assert_eq!(sgb.attributes[0], 0);
+    }
+
+    #[test]
+    fn border() {
+        let mut sgb = Sgb::new();
+        let mut frame_count = 0;
+        // No border before PCT_TRN
+        assert_eq!(pixel(&sgb, 0, 0), rgba(0x67BF));
+
+        // CHR_TRN of the upper tiles: tile 0x81, the second, has color 1 at its top left pixel and 15 next to it
+        let mut tiles = vec![0; TRANSFER_SIZE];
+        let tile = 32;
+        tiles[tile] = 0xC0;
+        tiles[tile + 1] = 0x40;
+        tiles[tile + 16] = 0x40;
+        tiles[tile + 17] = 0x40;
+        transfer(&mut sgb, &mut frame_count, 0x13, 0x01, &tiles);
+
+        // PCT_TRN: tile 0x81 at 0,0 in palette 5, and flipped both ways at 1,0 in palette 4
+        let mut data = vec![0; TRANSFER_SIZE];
+        data[0 .. 2].copy_from_slice(&(0x81 | (5 << 10) as u16).to_le_bytes());
+        data[2 .. 4].copy_from_slice(&(0x81 | (4 << 10) | 0xC000u16).to_le_bytes());
+        data[0x800 + (16 + 1) * 2] = 0x1F;
+        data[0x800 + (16 + 15) * 2 + 1] = 0x7C;
+        data[0x800 + 2] = 0xE0;
+        data[0x800 + 3] = 0x03;
+        transfer(&mut sgb, &mut frame_count, 0x14, 0, &data);
+
+        assert_eq!(pixel(&sgb, 0, 0), [0xFF, 0, 0, 0xFF]);
+        assert_eq!(pixel(&sgb, 1, 0), [0, 0, 0xFF, 0xFF]);
+        assert_eq!(pixel(&sgb, 2, 0), rgba(0x67BF));
+        assert_eq!(pixel(&sgb, 15, 7), [0, 0xFF, 0, 0xFF]);
+        assert_eq!(pixel(&sgb, 15, 0), rgba(0x67BF));
+    }
+
+    #[test]
+    fn state() {
+        use crate::state::{StateReader, StateWriter};
+
+        let mut sgb = Sgb::new();
+        send(&mut sgb, 0x11, &[0x01]);
+        send(&mut sgb, 0x04, &[1, 0x01, 0x02, 1, 1, 3, 3]);
+        send(&mut sgb, 0x14, &[0]);
+        let mut w = StateWriter::new();
+        sgb.save_state(&mut w);
+        let data = w.into_vec();
+
+        let mut loaded = Sgb::new();
+        let mut r = StateReader::new(&data);
+        loaded.load_state(&mut r).unwrap();
+        r.finish().unwrap();
+        assert_eq!((loaded.players, loaded.player, loaded.transfer), (sgb.players, sgb.player, sgb.transfer));
+        assert_eq!(loaded.attributes, sgb.attributes);
+    }
+}
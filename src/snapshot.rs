@@ -0,0 +1,275 @@
+use std::collections::VecDeque;
+
+const MIN_DEFAULT_CAP: usize = 8 * 1024 * 1024;
+const MAX_DEFAULT_CAP: usize = 512 * 1024 * 1024;
+const FALLBACK_DEFAULT_CAP: usize = 64 * 1024 * 1024;
+const DEFAULT_MIN_REWIND_ENTRIES: usize = 16;
+const MAX_REWIND_INTERVAL: u32 = 64;
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum SnapshotKind {
+    Rewind,
+    RunAhead,
+    MovieAnchor,
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct SnapshotHandle {
+    kind: SnapshotKind,
+    id: u64,
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum BudgetEvent {
+    RewindEvicted,
+    RewindIntervalIncreased(u32),
+    RunAheadDisabled,
+}
+
+// Shared memory budget for all systems that keep snapshots of the machine around.
+// When the budget runs out the service degrades in a fixed order:
+//   1. the oldest rewind snapshots are evicted
+//   2. when fewer than min_rewind_entries remain, the rewind capture interval is doubled
+//   3. when no rewind snapshots are left, run-ahead is disabled
+pub struct SnapshotBudget {
+    cap: usize,
+    used: usize,
+    next_id: u64,
+    rewind: VecDeque<(u64, Vec<u8>)>,
+    runahead: VecDeque<(u64, Vec<u8>)>,
+    anchors: VecDeque<(u64, Vec<u8>)>,
+    min_rewind_entries: usize,
+    rewind_interval: u32,
+    runahead_enabled: bool,
+    events: Vec<BudgetEvent>,
+}
+
+impl SnapshotBudget {
+    pub fn new(cap: usize) -> SnapshotBudget {
+        SnapshotBudget {
+            cap,
+            used: 0,
+            next_id: 0,
+            rewind: VecDeque::new(),
+            runahead: VecDeque::new(),
+            anchors: VecDeque::new(),
+            min_rewind_entries: DEFAULT_MIN_REWIND_ENTRIES,
+            rewind_interval: 1,
+            runahead_enabled: true,
+            events: Vec::new(),
+        }
+    }
+
+    pub fn with_default_cap() -> SnapshotBudget {
+        SnapshotBudget::new(default_cap())
+    }
+
+    pub fn set_min_rewind_entries(&mut self, entries: usize) {
+        self.min_rewind_entries = entries;
+    }
+
+    // Sets the interval to start from, before it grows when the budget runs out
+    pub fn set_rewind_interval(&mut self, frames: u32) {
+        self.rewind_interval = frames;
+    }
+
+    pub fn cap(&self) -> usize {
+        self.cap
+    }
+
+    pub fn used(&self) -> usize {
+        self.used
+    }
+
+    // Number of frames the rewind system should wait between two snapshots
+    pub fn rewind_interval(&self) -> u32 {
+        self.rewind_interval
+    }
+
+    pub fn runahead_enabled(&self) -> bool {
+        self.runahead_enabled
+    }
+
+    pub fn entries(&self, kind: SnapshotKind) -> usize {
+        self.queue(kind).len()
+    }
+
+    // Returns the notices about degraded service since the last call, e.g. to show them on screen
+    pub fn take_events(&mut self) -> Vec<BudgetEvent> {
+        ::std::mem::take(&mut self.events)
+    }
+
+    pub fn store(&mut self, kind: SnapshotKind, data: Vec<u8>) -> Option<SnapshotHandle> {
+        if kind == SnapshotKind::RunAhead && !self.runahead_enabled {
+            return None;
+        }
+        if data.len() > self.cap {
+            return None;
+        }
+
+        while self.used + data.len() > self.cap {
+            if !self.rewind.is_empty() {
+                self.evict_rewind();
+            }
+            else if self.runahead_enabled && kind != SnapshotKind::Rewind {
+                self.disable_runahead();
+                if kind == SnapshotKind::RunAhead {
+                    return None;
+                }
+            }
+            else {
+                return None;
+            }
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.used += data.len();
+        self.queue_mut(kind).push_back((id, data));
+        Some(SnapshotHandle { kind, id })
+    }
+
+    pub fn get(&self, handle: SnapshotHandle) -> Option<&[u8]> {
+        self.queue(handle.kind).iter()
+            .find(|entry| entry.0 == handle.id)
+            .map(|entry| &entry.1[..])
+    }
+
+    pub fn release(&mut self, handle: SnapshotHandle) -> Option<Vec<u8>> {
+        let queue = self.queue_mut(handle.kind);
+        let position = queue.iter().position(|entry| entry.0 == handle.id)?;
+        let (_, data) = queue.remove(position)?;
+        self.used -= data.len();
+        Some(data)
+    }
+
+    fn evict_rewind(&mut self) {
+        if let Some((_, data)) = self.rewind.pop_front() {
+            self.used -= data.len();
+            self.events.push(BudgetEvent::RewindEvicted);
+        }
+        if self.rewind.len() < self.min_rewind_entries && self.rewind_interval < MAX_REWIND_INTERVAL {
+            self.rewind_interval *= 2;
+            self.events.push(BudgetEvent::RewindIntervalIncreased(self.rewind_interval));
+        }
+    }
+
+    fn disable_runahead(&mut self) {
+        for (_, data) in self.runahead.drain(..) {
+            self.used -= data.len();
+        }
+        self.runahead_enabled = false;
+        self.events.push(BudgetEvent::RunAheadDisabled);
+    }
+
+    fn queue(&self, kind: SnapshotKind) -> &VecDeque<(u64, Vec<u8>)> {
+        match kind {
+            SnapshotKind::Rewind => &self.rewind,
+            SnapshotKind::RunAhead => &self.runahead,
+            SnapshotKind::MovieAnchor => &self.anchors,
+        }
+    }
+
+    fn queue_mut(&mut self, kind: SnapshotKind) -> &mut VecDeque<(u64, Vec<u8>)> {
+        match kind {
+            SnapshotKind::Rewind => &mut self.rewind,
+            SnapshotKind::RunAhead => &mut self.runahead,
+            SnapshotKind::MovieAnchor => &mut self.anchors,
+        }
+    }
+}
+
+// A sixteenth of the physical memory, within sane bounds
+pub fn default_cap() -> usize {
+    match system_memory() {
+        Some(total) => (total / 16).clamp(MIN_DEFAULT_CAP, MAX_DEFAULT_CAP),
+        None => FALLBACK_DEFAULT_CAP,
+    }
+}
+
+fn system_memory() -> Option<usize> {
+    let meminfo = ::std::fs::read_to_string("/proc/meminfo").ok()?;
+    let line = meminfo.lines().find(|l| l.starts_with("MemTotal:"))?;
+    let kib = line.split_whitespace().nth(1)?.parse::<usize>().ok()?;
+    Some(kib * 1024)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BudgetEvent, SnapshotBudget, SnapshotKind};
+
+    #[test]
+    fn store_and_release() {
+        let mut budget = SnapshotBudget::new(100);
+        let handle = budget.store(SnapshotKind::MovieAnchor, vec![1; 40]).unwrap();
+        assert_eq!(budget.used(), 40);
+        assert_eq!(budget.get(handle), Some(&[1u8; 40][..]));
+        assert_eq!(budget.release(handle), Some(vec![1; 40]));
+        assert_eq!(budget.used(), 0);
+        assert_eq!(budget.get(handle), None);
+        assert!(budget.take_events().is_empty());
+    }
+
+    #[test]
+    fn rewind_evicts_oldest_first() {
+        let mut budget = SnapshotBudget::new(100);
+        budget.set_min_rewind_entries(2);
+        let handles: Vec<_> = (0 .. 10).map(|i| budget.store(SnapshotKind::Rewind, vec![i; 10]).unwrap()).collect();
+        assert_eq!(budget.used(), 100);
+
+        let newest = budget.store(SnapshotKind::Rewind, vec![10; 10]).unwrap();
+        assert_eq!(budget.get(handles[0]), None);
+        assert!(budget.get(handles[1]).is_some());
+        assert!(budget.get(newest).is_some());
+        assert_eq!(budget.entries(SnapshotKind::Rewind), 10);
+        assert_eq!(budget.rewind_interval(), 1);
+        assert_eq!(budget.take_events(), vec![BudgetEvent::RewindEvicted]);
+    }
+
+    #[test]
+    fn degradation_order() {
+        let mut budget = SnapshotBudget::new(100);
+        budget.set_min_rewind_entries(4);
+
+        let runahead = budget.store(SnapshotKind::RunAhead, vec![0; 20]).unwrap();
+        for _ in 0 .. 8 {
+            budget.store(SnapshotKind::Rewind, vec![0; 10]).unwrap();
+        }
+        assert_eq!(budget.used(), 100);
+
+        // Step 1: evict rewind entries while enough of them remain
+        budget.store(SnapshotKind::MovieAnchor, vec![0; 30]).unwrap();
+        assert_eq!(budget.entries(SnapshotKind::Rewind), 5);
+        assert_eq!(budget.rewind_interval(), 1);
+        assert_eq!(budget.take_events(), vec![BudgetEvent::RewindEvicted; 3]);
+
+        // Step 2: increase the rewind interval once the history becomes too short
+        budget.store(SnapshotKind::MovieAnchor, vec![0; 20]).unwrap();
+        assert_eq!(budget.entries(SnapshotKind::Rewind), 3);
+        assert_eq!(budget.rewind_interval(), 2);
+        assert_eq!(budget.take_events(), vec![
+            BudgetEvent::RewindEvicted,
+            BudgetEvent::RewindEvicted,
+            BudgetEvent::RewindIntervalIncreased(2),
+        ]);
+        assert!(budget.runahead_enabled());
+
+        // Step 3: disable run-ahead once the rewind history is gone
+        budget.store(SnapshotKind::MovieAnchor, vec![0; 40]).unwrap();
+        assert_eq!(budget.entries(SnapshotKind::Rewind), 0);
+        assert!(!budget.runahead_enabled());
+        assert_eq!(budget.get(runahead), None);
+        assert_eq!(budget.take_events().last(), Some(&BudgetEvent::RunAheadDisabled));
+        assert_eq!(budget.used(), 90);
+        assert!(budget.store(SnapshotKind::RunAhead, vec![0; 1]).is_none());
+    }
+
+    #[test]
+    fn rewind_does_not_disable_runahead() {
+        let mut budget = SnapshotBudget::new(50);
+        budget.store(SnapshotKind::RunAhead, vec![0; 40]).unwrap();
+        assert!(budget.store(SnapshotKind::Rewind, vec![0; 20]).is_none());
+        assert!(budget.runahead_enabled());
+        assert!(budget.store(SnapshotKind::Rewind, vec![0; 10]).is_some());
+    }
+}
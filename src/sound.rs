@@ -1,9 +1,25 @@
-use blip_buf::BlipBuf;
+use crate::apu::length::LengthCounter;
+use crate::apu::sequencer::{self, FrameSequencer};
+use crate::resampler::Resampler;
+use crate::samplebuffer::{self, SampleBuffer};
+use crate::state::{StateError, StateReader, StateResult, StateWriter};
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 const WAVE_PATTERN : [[i32; 8]; 4] = [[-1,-1,-1,-1,1,-1,-1,-1],[-1,-1,-1,-1,1,1,-1,-1],[-1,-1,1,1,1,1,-1,-1],[1,1,1,1,-1,-1,1,1]];
 const CLOCKS_PER_SECOND : u32 = 1 << 22;
+// The rate the APU is synthesized at, so the output does not depend on the audio device.
+// The output is resampled when the player uses another rate.
+pub const INTERNAL_SAMPLE_RATE : u32 = 44100;
 const CLOCKS_PER_FRAME : u32 = CLOCKS_PER_SECOND / 512;
-const OUTPUT_SAMPLE_COUNT : usize = 2000; // this should be less than blip_buf::MAX_FRAME
+const DEFAULT_LATENCY_MS : u32 = 45;
+const DEFAULT_HEADROOM_DB : f32 = 3.0;
+// Samples below this level pass the soft clipper unchanged
+const SOFT_CLIP_KNEE : f32 = 0.75;
+const SPEED_FADE_SAMPLE_COUNT : usize = 32;
+const MIN_OUTPUT_SAMPLE_COUNT : u32 = 64;
+const MAX_OUTPUT_SAMPLE_COUNT : u32 = 3800; // this should be less than blip_buf::MAX_FRAME
 const SWEEP_DELAY_ZERO_PERIOD : u8 = 8;
 
 // Additional delay on trigger of the wave channel (channel 3). In other emulators it is 6, but we
@@ -14,6 +30,139 @@ pub trait AudioPlayer : Send {
     fn play(&mut self, left_channel: &[f32], right_channel: &[f32]);
     fn samples_rate(&self) -> u32;
     fn underflowed(&self) -> bool;
+    // How often the device ran out of samples so far, for players that can tell
+    fn underruns(&self) -> u64 {
+        0
+    }
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum ChannelId {
+    Square1,
+    Square2,
+    Wave,
+    Noise,
+}
+
+// Taps are called from within the mixer, so a slow tap delays the emulation thread.
+// The slices are only valid for the duration of the call. They are at INTERNAL_SAMPLE_RATE,
+// before the output is resampled for the player.
+pub type SampleTap = Box<dyn FnMut(ChannelId, &[i16]) + Send>;
+pub type MixTap = Box<dyn FnMut(&[f32], &[f32]) + Send>;
+
+// Supplies the audio on the cartridge's VIN pin. It is asked to fill the whole slice with
+// samples in the range -1.0 to 1.0 at INTERNAL_SAMPLE_RATE.
+pub type VinSource = Box<dyn FnMut(&mut [f32]) + Send>;
+
+#[derive(Copy, Clone)]
+pub struct SoundOptions {
+    // Requested amount of audio that is generated before it is handed to the player.
+    // Values outside of what the emulator supports are clamped, see Sound::latency_ms.
+    pub latency_ms: u32,
+    // Attenuation of the final mix in dB, so loud content does not clip
+    pub headroom_db: f32,
+    // Gradually saturate samples near full scale instead of clamping them
+    pub soft_clip: bool,
+}
+
+impl Default for SoundOptions {
+    fn default() -> SoundOptions {
+        SoundOptions {
+            latency_ms: DEFAULT_LATENCY_MS,
+            headroom_db: DEFAULT_HEADROOM_DB,
+            soft_clip: false,
+        }
+    }
+}
+
+// Counts the output samples that exceeded full scale. These are updated by the
+// emulation thread and can be read from anywhere.
+#[derive(Default)]
+pub struct ClipStats {
+    left: AtomicU64,
+    right: AtomicU64,
+}
+
+impl ClipStats {
+    pub fn left(&self) -> u64 {
+        self.left.load(Ordering::Relaxed)
+    }
+
+    pub fn right(&self) -> u64 {
+        self.right.load(Ordering::Relaxed)
+    }
+
+    // Returns the left and right counts since the last call, e.g. to report them per second
+    pub fn take(&self) -> (u64, u64) {
+        (self.left.swap(0, Ordering::Relaxed), self.right.swap(0, Ordering::Relaxed))
+    }
+}
+
+// A snapshot of the APU registers and internal state, for debugging
+#[non_exhaustive]
+#[derive(PartialEq, Clone, Debug)]
+pub struct ApuDebugState {
+    pub nr50: u8,
+    pub nr51: u8,
+    pub nr52: u8,
+    // The step the frame sequencer takes next, 0 to 7
+    pub frame_step: u8,
+    // Channel 1 to 4, as reported in NR52
+    pub channels_enabled: [bool; 4],
+    pub square1: SquareDebugState,
+    pub square2: SquareDebugState,
+}
+
+#[non_exhaustive]
+#[derive(PartialEq, Clone, Debug)]
+pub struct SquareDebugState {
+    pub enabled: bool,
+    pub dac_enabled: bool,
+    // The 11 bit frequency register, and the resulting period in clocks per duty step
+    pub frequency: u16,
+    pub period: u32,
+    pub duty: u8,
+    pub phase: u8,
+    pub length: u16,
+    pub length_enabled: bool,
+    pub volume: u8,
+    pub envelope_period: u8,
+    pub envelope_goes_up: bool,
+    // Only channel 1 has a sweep unit
+    pub sweep: Option<SweepDebugState>,
+}
+
+#[non_exhaustive]
+#[derive(PartialEq, Clone, Debug)]
+pub struct SweepDebugState {
+    pub enabled: bool,
+    // The shadow frequency the sweep calculates with
+    pub frequency: u16,
+    pub period: u8,
+    pub shift: u8,
+    pub negate: bool,
+}
+
+impl fmt::Display for ApuDebugState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let on_off = |v: bool| if v { "on" } else { "off" };
+        let channels: String = self.channels_enabled.iter().zip("1234".chars())
+            .map(|(&on, name)| if on { name } else { '-' })
+            .collect();
+        writeln!(f, "NR50={:02X} NR51={:02X} NR52={:02X} frame step {} channels {}", self.nr50, self.nr51, self.nr52, self.frame_step, channels)?;
+        for (name, square) in [("CH1", &self.square1), ("CH2", &self.square2)] {
+            write!(f, "{} {:3} dac {:3} freq {:03X} period {:4} duty {} phase {} length {:2} ({:3}) volume {:2} envelope {} {}",
+                name, on_off(square.enabled), on_off(square.dac_enabled), square.frequency, square.period, square.duty, square.phase,
+                square.length, on_off(square.length_enabled), square.volume, square.envelope_period,
+                if square.envelope_goes_up { "up" } else { "down" })?;
+            if let Some(ref sweep) = square.sweep {
+                write!(f, " sweep {} freq {:03X} period {} shift {} {}", on_off(sweep.enabled), sweep.frequency, sweep.period, sweep.shift,
+                    if sweep.negate { "down" } else { "up" })?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
 }
 
 struct VolumeEnvelope {
@@ -76,59 +225,22 @@ impl VolumeEnvelope {
             }
         }
     }
-}
-
-struct LengthCounter {
-    enabled: bool,
-    value: u16,
-    max: u16,
-}
-
-impl LengthCounter {
-    fn new(max: u16) -> Self {
-        LengthCounter {
-            enabled: false,
-            value: 0,
-            max: max
-        }
-    }
-
-    fn is_active(&self) -> bool {
-        self.value > 0
-    }
-
-    fn extra_step(frame_step: u8) -> bool {
-        // if the last 'step' was a length step return true
-        // this is equivalent to (next) frame_step is not a length step
-        // which is true for odd steps
-        frame_step % 2 == 1
-    }
-
-    fn enable(&mut self, enable: bool, frame_step: u8) {
-        let was_enabled = self.enabled;
-        self.enabled = enable; // self.step takes the new value of enabled into account
-        if !was_enabled && LengthCounter::extra_step(frame_step) {
-            self.step();
-        }
-    }
 
-    fn set(&mut self, minus_value: u8) {
-        self.value = self.max - minus_value as u16;
+    fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.period);
+        w.bool(self.goes_up);
+        w.u8(self.delay);
+        w.u8(self.initial_volume);
+        w.u8(self.volume);
     }
 
-    fn trigger(&mut self, frame_step: u8) {
-        if self.value == 0 {
-            self.value = self.max;
-            if LengthCounter::extra_step(frame_step) {
-                self.step();
-            }
-        }
-    }
-
-    fn step(&mut self) {
-        if self.enabled && self.value > 0 {
-            self.value -= 1;
-        }
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.period = r.u8()? & 0x7;
+        self.goes_up = r.bool()?;
+        self.delay = r.u8()? & 0x7;
+        self.initial_volume = r.u8()? & 0xF;
+        self.volume = r.u8()? & 0xF;
+        Ok(())
     }
 }
 
@@ -150,12 +262,13 @@ struct SquareChannel {
     sweep_shift: u8,
     sweep_negate: bool,
     sweep_did_negate: bool,
+    triggered: bool,
     volume_envelope: VolumeEnvelope,
-    blip: BlipBuf,
+    blip: Box<dyn SampleBuffer>,
 }
 
 impl SquareChannel {
-    fn new(blip: BlipBuf, with_sweep: bool) -> SquareChannel {
+    fn new(blip: Box<dyn SampleBuffer>, with_sweep: bool) -> SquareChannel {
         SquareChannel {
             active: false,
             dac_enabled: false,
@@ -174,6 +287,7 @@ impl SquareChannel {
             sweep_shift: 0,
             sweep_negate: false,
             sweep_did_negate: false,
+            triggered: false,
             volume_envelope: VolumeEnvelope::new(),
             blip: blip,
         }
@@ -183,6 +297,32 @@ impl SquareChannel {
         self.active
     }
 
+    fn debug_state(&self) -> SquareDebugState {
+        SquareDebugState {
+            enabled: self.active,
+            dac_enabled: self.dac_enabled,
+            frequency: self.frequency,
+            period: self.period,
+            duty: self.duty,
+            phase: self.phase,
+            length: self.length.value(),
+            length_enabled: self.length.enabled(),
+            volume: self.volume_envelope.volume,
+            envelope_period: self.volume_envelope.period,
+            envelope_goes_up: self.volume_envelope.goes_up,
+            sweep: match self.has_sweep {
+                true => Some(SweepDebugState {
+                    enabled: self.sweep_enabled,
+                    frequency: self.sweep_frequency,
+                    period: self.sweep_period,
+                    shift: self.sweep_shift,
+                    negate: self.sweep_negate,
+                }),
+                false => None,
+            },
+        }
+    }
+
     fn rb(&self, a: u16) -> u8 {
         match a {
             0xFF10 => {
@@ -203,14 +343,14 @@ impl SquareChannel {
             }
             0xFF14 | 0xFF19 => {
                 0x80 |
-                if self.length.enabled { 0x40 } else { 0 } |
+                if self.length.enabled() { 0x40 } else { 0 } |
                 0x3F
             },
             _ => unimplemented!(),
         }
     }
 
-    fn wb(&mut self, a: u16, v: u8, frame_step: u8) {
+    fn wb(&mut self, a: u16, v: u8, current_step: u8) {
         match a {
             0xFF10 => {
                 self.sweep_period = (v >> 4) & 0x7;
@@ -224,7 +364,7 @@ impl SquareChannel {
             },
             0xFF11 | 0xFF16 => {
                 self.duty = v >> 6;
-                self.length.set(v & 0x3F);
+                self.length.load(v & 0x3F);
             },
             0xFF12 | 0xFF17 => {
                 self.dac_enabled = v & 0xF8 != 0;
@@ -238,15 +378,19 @@ impl SquareChannel {
                 self.frequency = (self.frequency & 0x00FF) | (((v & 0b0000_0111) as u16) << 8);
                 self.calculate_period();
 
-                self.length.enable(v & 0x40 == 0x40, frame_step);
-                self.active &= self.length.is_active();
+                self.length.set_enable(v & 0x40 == 0x40, current_step);
+                self.active &= !self.length.expired();
 
                 if v & 0x80 == 0x80 {
                     if self.dac_enabled {
                         self.active = true;
                     }
 
-                    self.length.trigger(frame_step);
+                    // The frequency timer is reloaded, but the duty position is kept
+                    self.delay = self.period;
+                    self.triggered = true;
+
+                    self.length.trigger(current_step);
 
                     if self.has_sweep {
                         self.sweep_frequency = self.frequency;
@@ -283,6 +427,16 @@ impl SquareChannel {
             let pattern = WAVE_PATTERN[self.duty as usize];
             let vol = self.volume_envelope.volume as i32;
 
+            if self.triggered {
+                // A trigger happened at start_time. Output the current duty step right away,
+                // otherwise rapid retriggers would not be heard until the next step.
+                let amp = vol * pattern[((self.phase + 7) % 8) as usize];
+                if amp != self.last_amp {
+                    self.blip.add_delta(start_time, amp - self.last_amp);
+                    self.last_amp = amp;
+                }
+            }
+
             while time < end_time {
                 let amp = vol * pattern[self.phase as usize];
                 if amp != self.last_amp {
@@ -296,11 +450,12 @@ impl SquareChannel {
             // next time, we have to wait an additional delay timesteps
             self.delay = time - end_time;
         }
+        self.triggered = false;
     }
 
-    fn step_length(&mut self) {
-        self.length.step();
-        self.active &= self.length.is_active();
+    fn step_length(&mut self, step: u8) {
+        self.length.tick(step);
+        self.active &= !self.length.expired();
     }
 
     fn sweep_calculate_frequency(&mut self) -> u16 {
@@ -346,6 +501,48 @@ impl SquareChannel {
             }
         }
     }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.active);
+        w.bool(self.dac_enabled);
+        w.u8(self.duty);
+        w.u8(self.phase);
+        self.length.save_state(w);
+        w.u16(self.frequency);
+        w.u32(self.period);
+        w.i32(self.last_amp);
+        w.u32(self.delay);
+        w.bool(self.sweep_enabled);
+        w.u16(self.sweep_frequency);
+        w.u8(self.sweep_delay);
+        w.u8(self.sweep_period);
+        w.u8(self.sweep_shift);
+        w.bool(self.sweep_negate);
+        w.bool(self.sweep_did_negate);
+        w.bool(self.triggered);
+        self.volume_envelope.save_state(w);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.active = r.bool()?;
+        self.dac_enabled = r.bool()?;
+        self.duty = r.u8()? & 0x3;
+        self.phase = r.u8()? % 8;
+        self.length.load_state(r)?;
+        self.frequency = r.u16()?;
+        self.period = r.u32()?;
+        self.last_amp = r.i32()?;
+        self.delay = r.u32()?;
+        self.sweep_enabled = r.bool()?;
+        self.sweep_frequency = r.u16()?;
+        self.sweep_delay = r.u8()?;
+        self.sweep_period = r.u8()?;
+        self.sweep_shift = r.u8()? & 0x7;
+        self.sweep_negate = r.bool()?;
+        self.sweep_did_negate = r.bool()?;
+        self.triggered = r.bool()?;
+        self.volume_envelope.load_state(r)
+    }
 }
 
 struct WaveChannel {
@@ -361,11 +558,11 @@ struct WaveChannel {
     current_wave: u8,
     dmg_mode: bool,
     sample_recently_accessed: bool,
-    blip: BlipBuf,
+    blip: Box<dyn SampleBuffer>,
 }
 
 impl WaveChannel {
-    fn new(blip: BlipBuf, dmg_mode: bool) -> WaveChannel {
+    fn new(blip: Box<dyn SampleBuffer>, dmg_mode: bool) -> WaveChannel {
         WaveChannel {
             active: false,
             dac_enabled: false,
@@ -398,7 +595,7 @@ impl WaveChannel {
             0xFF1D => 0xFF,
             0xFF1E => {
                 0x80 |
-                if self.length.enabled { 0x40 } else { 0 } |
+                if self.length.enabled() { 0x40 } else { 0 } |
                 0x3F
             },
             0xFF30 ..= 0xFF3F => {
@@ -418,13 +615,13 @@ impl WaveChannel {
         }
     }
 
-    fn wb(&mut self, a: u16, v: u8, frame_step: u8) {
+    fn wb(&mut self, a: u16, v: u8, current_step: u8) {
         match a {
             0xFF1A => {
                 self.dac_enabled = (v & 0x80) == 0x80;
                 self.active = self.active && self.dac_enabled;
             }
-            0xFF1B => self.length.set(v),
+            0xFF1B => self.length.load(v),
             0xFF1C => self.volume_shift = (v >> 5) & 0b11,
             0xFF1D => {
                 self.frequency = (self.frequency & 0x0700) | (v as u16);
@@ -434,13 +631,13 @@ impl WaveChannel {
                 self.frequency = (self.frequency & 0x00FF) | (((v & 0b111) as u16) << 8);
                 self.calculate_period();
 
-                self.length.enable(v & 0x40 == 0x40, frame_step);
-                self.active &= self.length.is_active();
+                self.length.set_enable(v & 0x40 == 0x40, current_step);
+                self.active &= !self.length.expired();
 
                 if v & 0x80 == 0x80 {
                     self.dmg_maybe_corrupt_waveram();
 
-                    self.length.trigger(frame_step);
+                    self.length.trigger(current_step);
 
                     self.current_wave = 0;
                     self.delay = self.period + WAVE_INITIAL_DELAY;
@@ -523,9 +720,9 @@ impl WaveChannel {
         }
     }
 
-    fn step_length(&mut self) {
-        self.length.step();
-        self.active &= self.length.is_active();
+    fn step_length(&mut self, step: u8) {
+        self.length.tick(step);
+        self.active &= !self.length.expired();
     }
 
     fn dmg_maybe_corrupt_waveram(&mut self) {
@@ -551,6 +748,35 @@ impl WaveChannel {
             self.waveram[3] = self.waveram[blockstart + 3];
         }
     }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.active);
+        w.bool(self.dac_enabled);
+        self.length.save_state(w);
+        w.u16(self.frequency);
+        w.u32(self.period);
+        w.i32(self.last_amp);
+        w.u32(self.delay);
+        w.u8(self.volume_shift);
+        w.bytes(&self.waveram);
+        w.u8(self.current_wave);
+        w.bool(self.sample_recently_accessed);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.active = r.bool()?;
+        self.dac_enabled = r.bool()?;
+        self.length.load_state(r)?;
+        self.frequency = r.u16()?;
+        self.period = r.u32()?;
+        self.last_amp = r.i32()?;
+        self.delay = r.u32()?;
+        self.volume_shift = r.u8()? & 0x3;
+        r.bytes_into(&mut self.waveram)?;
+        self.current_wave = r.u8()? % 32;
+        self.sample_recently_accessed = r.bool()?;
+        Ok(())
+    }
 }
 
 struct NoiseChannel {
@@ -564,11 +790,11 @@ struct NoiseChannel {
     state: u16,
     delay: u32,
     last_amp: i32,
-    blip: BlipBuf,
+    blip: Box<dyn SampleBuffer>,
 }
 
 impl NoiseChannel {
-    fn new(blip: BlipBuf) -> NoiseChannel {
+    fn new(blip: Box<dyn SampleBuffer>) -> NoiseChannel {
         NoiseChannel {
             active: false,
             dac_enabled: false,
@@ -593,16 +819,16 @@ impl NoiseChannel {
             },
             0xFF23 => {
                 0x80 |
-                if self.length.enabled { 0x40 } else { 0 } |
+                if self.length.enabled() { 0x40 } else { 0 } |
                 0x3F
             },
             _ => unimplemented!(),
         }
     }
 
-    fn wb(&mut self, a: u16, v: u8, frame_step: u8) {
+    fn wb(&mut self, a: u16, v: u8, current_step: u8) {
         match a {
-            0xFF20 => self.length.set(v & 0x3F),
+            0xFF20 => self.length.load(v & 0x3F),
             0xFF21 => {
                 self.dac_enabled = v & 0xF8 != 0;
                 self.active = self.active && self.dac_enabled;
@@ -617,11 +843,11 @@ impl NoiseChannel {
                 self.period = freq_div << (v >> 4);
             },
             0xFF23 => {
-                self.length.enable(v & 0x40 == 0x40, frame_step);
-                self.active &= self.length.is_active();
+                self.length.set_enable(v & 0x40 == 0x40, current_step);
+                self.active &= !self.length.expired();
 
                 if v & 0x80 == 0x80 {
-                    self.length.trigger(frame_step);
+                    self.length.trigger(current_step);
 
                     self.state = 0xFF;
                     self.delay = 0;
@@ -672,9 +898,36 @@ impl NoiseChannel {
         }
     }
 
-    fn step_length(&mut self) {
-        self.length.step();
-        self.active &= self.length.is_active();
+    fn step_length(&mut self, step: u8) {
+        self.length.tick(step);
+        self.active &= !self.length.expired();
+    }
+
+    fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.active);
+        w.bool(self.dac_enabled);
+        w.u8(self.reg_ff22);
+        self.length.save_state(w);
+        self.volume_envelope.save_state(w);
+        w.u32(self.period);
+        w.u8(self.shift_width);
+        w.u16(self.state);
+        w.u32(self.delay);
+        w.i32(self.last_amp);
+    }
+
+    fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.active = r.bool()?;
+        self.dac_enabled = r.bool()?;
+        self.reg_ff22 = r.u8()?;
+        self.length.load_state(r)?;
+        self.volume_envelope.load_state(r)?;
+        self.period = r.u32()?;
+        self.shift_width = r.u8()? & 0xF;
+        self.state = r.u16()?;
+        self.delay = r.u32()?;
+        self.last_amp = r.i32()?;
+        Ok(())
     }
 }
 
@@ -683,79 +936,201 @@ pub struct Sound {
     time: u32,
     prev_time: u32,
     next_time: u32,
-    frame_step: u8,
+    sequencer: FrameSequencer,
     output_period: u32,
     channel1: SquareChannel,
     channel2: SquareChannel,
     channel3: WaveChannel,
     channel4: NoiseChannel,
-    volume_left: u8,
-    volume_right: u8,
-    reg_vin_to_so: u8,
+    reg_ff24: u8,
     reg_ff25: u8,
     need_sync: bool,
     dmg_mode: bool,
+    options: SoundOptions,
+    output_sample_count: usize,
+    buf_left: Vec<f32>,
+    buf_right: Vec<f32>,
+    buf: Vec<i16>,
+    buf_vin: Vec<f32>,
+    sample_tap: Option<SampleTap>,
+    mix_tap: Option<MixTap>,
+    vin_source: Option<VinSource>,
+    clip_stats: Arc<ClipStats>,
+    speed_multiplier: f32,
+    speed_credit: f32,
+    skipped_buffer: bool,
+    underrun_rate: u64,
+    underrun_rate_base: u64,
+    underrun_rate_time: u32,
+    resampler: Option<Resampler>,
     player: Box<dyn AudioPlayer>,
 }
 
 impl Sound {
-    pub fn new_dmg(player: Box<dyn AudioPlayer>) -> Sound {
-        Sound::new_internal(player, true)
+    pub fn new_dmg(player: Box<dyn AudioPlayer>, options: SoundOptions) -> Sound {
+        Sound::new_internal(player, options, true)
     }
 
-    pub fn new_cgb(player: Box<dyn AudioPlayer>) -> Sound {
-        Sound::new_internal(player, false)
+    pub fn new_cgb(player: Box<dyn AudioPlayer>, options: SoundOptions) -> Sound {
+        Sound::new_internal(player, options, false)
     }
 
-    fn new_internal(player: Box<dyn AudioPlayer>, dmg_mode: bool) -> Sound {
-        let blipbuf1 = create_blipbuf(player.samples_rate());
-        let blipbuf2 = create_blipbuf(player.samples_rate());
-        let blipbuf3 = create_blipbuf(player.samples_rate());
-        let blipbuf4 = create_blipbuf(player.samples_rate());
+    fn new_internal(player: Box<dyn AudioPlayer>, options: SoundOptions, dmg_mode: bool) -> Sound {
+        let output_sample_count = output_sample_count(options.latency_ms, INTERNAL_SAMPLE_RATE);
 
-        let output_period = (OUTPUT_SAMPLE_COUNT as u64 * CLOCKS_PER_SECOND as u64) / player.samples_rate() as u64;
+        let blipbuf1 = create_blipbuf(INTERNAL_SAMPLE_RATE);
+        let blipbuf2 = create_blipbuf(INTERNAL_SAMPLE_RATE);
+        let blipbuf3 = create_blipbuf(INTERNAL_SAMPLE_RATE);
+        let blipbuf4 = create_blipbuf(INTERNAL_SAMPLE_RATE);
+
+        let output_period = (output_sample_count as u64 * CLOCKS_PER_SECOND as u64) / INTERNAL_SAMPLE_RATE as u64;
 
         Sound {
             on: false,
             time: 0,
             prev_time: 0,
             next_time: CLOCKS_PER_FRAME,
-            frame_step: 0,
+            sequencer: FrameSequencer::new(),
             output_period: output_period as u32,
             channel1: SquareChannel::new(blipbuf1, true),
             channel2: SquareChannel::new(blipbuf2, false),
             channel3: WaveChannel::new(blipbuf3, dmg_mode),
             channel4: NoiseChannel::new(blipbuf4),
-            volume_left: 7,
-            volume_right: 7,
-            reg_vin_to_so: 0x00,
+            reg_ff24: 0x77,
             reg_ff25: 0x00,
             need_sync: false,
             dmg_mode: dmg_mode,
+            options,
+            output_sample_count: output_sample_count as usize,
+            buf_left: vec![0f32; output_sample_count as usize + 10],
+            buf_right: vec![0f32; output_sample_count as usize + 10],
+            buf: vec![0i16; output_sample_count as usize + 10],
+            buf_vin: vec![0f32; output_sample_count as usize + 10],
+            sample_tap: None,
+            mix_tap: None,
+            vin_source: None,
+            clip_stats: Arc::new(ClipStats::default()),
+            speed_multiplier: 1.0,
+            speed_credit: 0.0,
+            skipped_buffer: false,
+            underrun_rate: 0,
+            underrun_rate_base: 0,
+            underrun_rate_time: 0,
+            resampler: resampler_for(player.samples_rate()),
             player: player,
         }
     }
 
+    pub fn set_sample_tap(&mut self, tap: Option<SampleTap>) {
+        self.sample_tap = tap;
+    }
+
+    pub fn set_mix_tap(&mut self, tap: Option<MixTap>) {
+        self.mix_tap = tap;
+    }
+
+    // The source is only heard when NR50 routes VIN to the left or right output
+    pub fn set_vin_source(&mut self, source: Option<VinSource>) {
+        self.vin_source = source;
+    }
+
+    // Creates a freshly powered APU, keeping the player, options, taps and VIN source of this one
+    pub fn reset(self, dmg_mode: bool) -> Sound {
+        let mut sound = Sound::new_internal(self.player, self.options, dmg_mode);
+        sound.sample_tap = self.sample_tap;
+        sound.mix_tap = self.mix_tap;
+        sound.vin_source = self.vin_source;
+        sound.clip_stats = self.clip_stats;
+        sound.speed_multiplier = self.speed_multiplier;
+        sound
+    }
+
+    // Tells the APU how much faster than real time the emulation runs. Above 1.0, only a matching
+    // share of the mixed buffers is played, so the pitch is kept and the player is not flooded.
+    // The buffers around a skipped one are faded to avoid clicks.
+    pub fn set_speed_multiplier(&mut self, multiplier: f32) {
+        let multiplier = if multiplier.is_finite() { multiplier.max(1.0) } else { 1.0 };
+        if multiplier == 1.0 {
+            self.speed_credit = 0.0;
+            self.skipped_buffer = false;
+        }
+        self.speed_multiplier = multiplier;
+    }
+
+    // Switches to another player, e.g. after the audio device was lost. The state of the APU is
+    // kept, but the samples that were not yet handed to the old player are dropped.
+    pub fn set_player(&mut self, player: Box<dyn AudioPlayer>) {
+        self.resampler = resampler_for(player.samples_rate());
+        self.player = player;
+        self.need_sync = false;
+        self.underrun_rate_base = self.player.underruns();
+        self.clear_buffers();
+    }
+
+    pub fn clip_stats(&self) -> Arc<ClipStats> {
+        self.clip_stats.clone()
+    }
+
+    // The frame limiter can watch this to skip a frame of video after an underrun, so the
+    // emulation catches up before the audio glitches again
+    pub fn underrun_count(&self) -> u64 {
+        self.player.underruns()
+    }
+
+    // The underruns during the last second of emulated time
+    pub fn underrun_rate(&self) -> u64 {
+        self.underrun_rate
+    }
+
+    pub fn latency_ms(&self) -> u32 {
+        (self.output_sample_count as u64 * 1000 / INTERNAL_SAMPLE_RATE as u64) as u32
+    }
+
    pub fn rb(&mut self, a: u16) -> u8 {
         self.run();
-        let v = match a {
+        self.peek(a)
+    }
+
+    // The registers as they were when the APU last caught up to the CPU
+    pub fn peek(&self, a: u16) -> u8 {
+        match a {
             0xFF10 ..= 0xFF14 => self.channel1.rb(a),
             0xFF16 ..= 0xFF19 => self.channel2.rb(a),
             0xFF1A ..= 0xFF1E => self.channel3.rb(a),
             0xFF20 ..= 0xFF23 => self.channel4.rb(a),
-            0xFF24 => ((self.volume_right & 7) << 4) | (self.volume_left & 7) | self.reg_vin_to_so,
+            0xFF24 => self.reg_ff24,
             0xFF25 => self.reg_ff25,
-            0xFF26 => (
-                if self.on { 0x80 } else { 0x00 } |
-                0x70 |
-                if self.channel4.on() { 0x8 } else { 0x0 } |
-                if self.channel3.on() { 0x4 } else { 0x0 } |
-                if self.channel2.on() { 0x2 } else { 0x0 } |
-                if self.channel1.on() { 0x1 } else { 0x0 }),
+            0xFF26 => self.reg_ff26(),
             0xFF30 ..= 0xFF3F => self.channel3.rb(a),
             _ => 0xFF,
-        };
-        return v;
+        }
+    }
+
+    fn reg_ff26(&self) -> u8 {
+        (if self.on { 0x80 } else { 0x00 }) |
+        0x70 |
+        if self.channel4.on() { 0x8 } else { 0x0 } |
+        if self.channel3.on() { 0x4 } else { 0x0 } |
+        if self.channel2.on() { 0x2 } else { 0x0 } |
+        if self.channel1.on() { 0x1 } else { 0x0 }
+    }
+
+    // The state as of the last register access or output, see catch_up
+    pub fn debug_state(&self) -> ApuDebugState {
+        ApuDebugState {
+            nr50: self.reg_ff24,
+            nr51: self.reg_ff25,
+            nr52: self.reg_ff26(),
+            frame_step: self.sequencer.current_step(),
+            channels_enabled: [self.channel1.on(), self.channel2.on(), self.channel3.on(), self.channel4.on()],
+            square1: self.channel1.debug_state(),
+            square2: self.channel2.debug_state(),
+        }
+    }
+
+    // Runs the channels up to the current time
+    pub fn catch_up(&mut self) {
+        self.run();
     }
 
     pub fn wb(&mut self, a: u16, v: u8) {
@@ -763,10 +1138,10 @@ impl Sound {
             // Allow writes to the length register when in DMG mode
             if self.dmg_mode {
                 match a {
-                    0xFF11 => self.channel1.wb(a, v & 0x3F, self.frame_step),
-                    0xFF16 => self.channel2.wb(a, v & 0x3F, self.frame_step),
-                    0xFF1B => self.channel3.wb(a, v, self.frame_step),
-                    0xFF20 => self.channel4.wb(a, v & 0x3F, self.frame_step),
+                    0xFF11 => self.channel1.wb(a, v & 0x3F, self.sequencer.current_step()),
+                    0xFF16 => self.channel2.wb(a, v & 0x3F, self.sequencer.current_step()),
+                    0xFF1B => self.channel3.wb(a, v, self.sequencer.current_step()),
+                    0xFF20 => self.channel4.wb(a, v & 0x3F, self.sequencer.current_step()),
                     _ => (),
                 }
             }
@@ -777,15 +1152,11 @@ impl Sound {
         }
         self.run();
         match a {
-            0xFF10 ..= 0xFF14 => self.channel1.wb(a, v, self.frame_step),
-            0xFF16 ..= 0xFF19 => self.channel2.wb(a, v, self.frame_step),
-            0xFF1A ..= 0xFF1E => self.channel3.wb(a, v, self.frame_step),
-            0xFF20 ..= 0xFF23 => self.channel4.wb(a, v, self.frame_step),
-            0xFF24 => {
-                self.volume_left = v & 0x7;
-                self.volume_right = (v >> 4) & 0x7;
-                self.reg_vin_to_so = v & 0x88;
-            }
+            0xFF10 ..= 0xFF14 => self.channel1.wb(a, v, self.sequencer.current_step()),
+            0xFF16 ..= 0xFF19 => self.channel2.wb(a, v, self.sequencer.current_step()),
+            0xFF1A ..= 0xFF1E => self.channel3.wb(a, v, self.sequencer.current_step()),
+            0xFF20 ..= 0xFF23 => self.channel4.wb(a, v, self.sequencer.current_step()),
+            0xFF24 => self.reg_ff24 = v,
             0xFF25 => self.reg_ff25 = v,
             0xFF26 => {
                 let turn_on = v & 0x80 == 0x80;
@@ -797,11 +1168,11 @@ impl Sound {
                 }
                 if !self.on && turn_on {
                     // Reset frame step when turning on
-                    self.frame_step = 0;
+                    self.sequencer.reset();
                 }
                 self.on = turn_on;
             }
-            0xFF30 ..= 0xFF3F => self.channel3.wb(a, v, self.frame_step),
+            0xFF30 ..= 0xFF3F => self.channel3.wb(a, v, self.sequencer.current_step()),
             _ => (),
         }
     }
@@ -828,6 +1199,7 @@ impl Sound {
         self.channel2.blip.end_frame(self.time);
         self.channel3.blip.end_frame(self.time);
         self.channel4.blip.end_frame(self.time);
+        self.update_underrun_rate(self.time);
         self.next_time -= self.time;
         self.time = 0;
         self.prev_time = 0;
@@ -842,30 +1214,40 @@ impl Sound {
         }
     }
 
+    fn update_underrun_rate(&mut self, elapsed: u32) {
+        self.underrun_rate_time += elapsed;
+        if self.underrun_rate_time >= CLOCKS_PER_SECOND {
+            self.underrun_rate_time -= CLOCKS_PER_SECOND;
+            let count = self.player.underruns();
+            self.underrun_rate = count.saturating_sub(self.underrun_rate_base);
+            self.underrun_rate_base = count;
+        }
+    }
+
     fn run(&mut self) {
+        // Every register access runs the APU first, so this has to be cheap when no time has passed
+        if self.prev_time == self.time { return; }
+
         while self.next_time <= self.time {
             self.channel1.run(self.prev_time, self.next_time);
             self.channel2.run(self.prev_time, self.next_time);
             self.channel3.run(self.prev_time, self.next_time);
             self.channel4.run(self.prev_time, self.next_time);
 
-            if self.frame_step % 2 == 0 {
-                self.channel1.step_length();
-                self.channel2.step_length();
-                self.channel3.step_length();
-                self.channel4.step_length();
-            }
-            if self.frame_step % 4 == 2 {
+            let step = self.sequencer.advance();
+            self.channel1.step_length(step);
+            self.channel2.step_length(step);
+            self.channel3.step_length(step);
+            self.channel4.step_length(step);
+            if sequencer::clocks_sweep(step) {
                 self.channel1.step_sweep();
             }
-            if self.frame_step == 7 {
+            if sequencer::clocks_envelope(step) {
                 self.channel1.volume_envelope.step();
                 self.channel2.volume_envelope.step();
                 self.channel4.volume_envelope.step();
             }
 
-            self.frame_step = (self.frame_step + 1) % 8;
-
             self.prev_time = self.next_time;
             self.next_time += CLOCKS_PER_FRAME;
         }
@@ -888,15 +1270,24 @@ impl Sound {
 
         let mut outputted = 0;
 
-        let left_vol = (self.volume_left as f32 / 7.0) * (1.0 / 15.0) * 0.25;
-        let right_vol = (self.volume_right as f32 / 7.0) * (1.0 / 15.0) * 0.25;
+        let left_vol = ((self.reg_ff24 & 0x7) as f32 / 7.0) * (1.0 / 15.0) * 0.25;
+        let right_vol = (((self.reg_ff24 >> 4) & 0x7) as f32 / 7.0) * (1.0 / 15.0) * 0.25;
+        let vin_left = self.reg_ff24 & 0x08 == 0x08;
+        let vin_right = self.reg_ff24 & 0x80 == 0x80;
+        let gain = 10f32.powf(-self.options.headroom_db / 20.0);
+        let soft_clip = self.options.soft_clip;
 
         while outputted < sample_count {
-            let buf_left = &mut [0f32; OUTPUT_SAMPLE_COUNT + 10];
-            let buf_right = &mut [0f32; OUTPUT_SAMPLE_COUNT + 10];
-            let buf = &mut [0i16; OUTPUT_SAMPLE_COUNT + 10];
+            let buf_left = &mut self.buf_left[..];
+            let buf_right = &mut self.buf_right[..];
+            let buf = &mut self.buf[..];
+            for v in buf_left.iter_mut() { *v = 0.0; }
+            for v in buf_right.iter_mut() { *v = 0.0; }
 
             let count1 = self.channel1.blip.read_samples(buf, false);
+            if let Some(ref mut tap) = self.sample_tap {
+                tap(ChannelId::Square1, &buf[..count1]);
+            }
             for (i, v) in buf[..count1].iter().enumerate() {
                 if self.reg_ff25 & 0x10 == 0x10 {
                     buf_left[i] += *v as f32 * left_vol;
@@ -907,6 +1298,9 @@ impl Sound {
             }
 
             let count2 = self.channel2.blip.read_samples(buf, false);
+            if let Some(ref mut tap) = self.sample_tap {
+                tap(ChannelId::Square2, &buf[..count2]);
+            }
             for (i, v) in buf[..count2].iter().enumerate() {
                 if self.reg_ff25 & 0x20 == 0x20 {
                     buf_left[i] += *v as f32 * left_vol;
@@ -919,6 +1313,9 @@ impl Sound {
             // channel3 is the WaveChannel, that outputs samples with a 4x
             // increase in amplitude in order to avoid a loss of precision.
             let count3 = self.channel3.blip.read_samples(buf, false);
+            if let Some(ref mut tap) = self.sample_tap {
+                tap(ChannelId::Wave, &buf[..count3]);
+            }
             for (i, v) in buf[..count3].iter().enumerate() {
                 if self.reg_ff25 & 0x40 == 0x40 {
                     buf_left[i] += ((*v as f32) / 4.0) * left_vol;
@@ -929,6 +1326,9 @@ impl Sound {
             }
 
             let count4 = self.channel4.blip.read_samples(buf, false);
+            if let Some(ref mut tap) = self.sample_tap {
+                tap(ChannelId::Noise, &buf[..count4]);
+            }
             for (i, v) in buf[..count4].iter().enumerate() {
                 if self.reg_ff25 & 0x80 == 0x80 {
                     buf_left[i] += *v as f32 * left_vol;
@@ -942,7 +1342,57 @@ impl Sound {
             debug_assert!(count1 == count3);
             debug_assert!(count1 == count4);
 
-            self.player.play(&buf_left[..count1], &buf_right[..count1]);
+            // The VIN input is as loud as a channel at full volume
+            if let Some(ref mut source) = self.vin_source {
+                if vin_left || vin_right {
+                    let buf_vin = &mut self.buf_vin[..count1];
+                    for v in buf_vin.iter_mut() { *v = 0.0; }
+                    source(buf_vin);
+                    for (i, v) in buf_vin.iter().enumerate() {
+                        if vin_left {
+                            buf_left[i] += *v * 15.0 * left_vol;
+                        }
+                        if vin_right {
+                            buf_right[i] += *v * 15.0 * right_vol;
+                        }
+                    }
+                }
+            }
+
+            let clipped_left = output_stage(&mut buf_left[..count1], gain, soft_clip);
+            let clipped_right = output_stage(&mut buf_right[..count1], gain, soft_clip);
+            self.clip_stats.left.fetch_add(clipped_left, Ordering::Relaxed);
+            self.clip_stats.right.fetch_add(clipped_right, Ordering::Relaxed);
+
+            if self.speed_multiplier > 1.0 {
+                // The credit is counted in samples, as the buffers differ in length
+                let count = count1 as f32;
+                self.speed_credit += count / self.speed_multiplier;
+                if self.speed_credit < count {
+                    self.skipped_buffer = true;
+                    outputted += count1;
+                    continue;
+                }
+                self.speed_credit -= count;
+
+                let fade_in = self.skipped_buffer;
+                let fade_out = self.speed_credit + count / self.speed_multiplier < count;
+                fade_edges(&mut buf_left[..count1], fade_in, fade_out);
+                fade_edges(&mut buf_right[..count1], fade_in, fade_out);
+                self.skipped_buffer = false;
+            }
+
+            if let Some(ref mut tap) = self.mix_tap {
+                tap(&buf_left[..count1], &buf_right[..count1]);
+            }
+
+            match self.resampler {
+                Some(ref mut resampler) => {
+                    let (left, right) = resampler.process(&buf_left[..count1], &buf_right[..count1]);
+                    self.player.play(left, right);
+                },
+                None => self.player.play(&buf_left[..count1], &buf_right[..count1]),
+            }
 
             outputted += count1;
         }
@@ -954,10 +1404,729 @@ impl Sound {
         self.channel3.blip.clear();
         self.channel4.blip.clear();
     }
+
+    // The state of the APU, without the samples that were not output yet. The player, options and
+    // taps stay as they are on a load.
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.bool(self.on);
+        w.bool(self.dmg_mode);
+        // Relative to prev_time, as the output frame starts over on a load
+        w.u32(self.time - self.prev_time);
+        w.u32(self.next_time - self.prev_time);
+        self.sequencer.save_state(w);
+        self.channel1.save_state(w);
+        self.channel2.save_state(w);
+        self.channel3.save_state(w);
+        self.channel4.save_state(w);
+        w.u8(self.reg_ff24);
+        w.u8(self.reg_ff25);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.on = r.bool()?;
+        self.dmg_mode = r.bool()?;
+        self.channel3.dmg_mode = self.dmg_mode;
+        self.prev_time = 0;
+        self.time = r.u32()?;
+        self.next_time = r.u32()?;
+        let max_time = (MAX_OUTPUT_SAMPLE_COUNT as u64 * CLOCKS_PER_SECOND as u64 / INTERNAL_SAMPLE_RATE as u64) as u32;
+        if self.time > max_time || self.next_time == 0 || self.next_time > CLOCKS_PER_FRAME {
+            return Err(StateError::Corrupt);
+        }
+        self.sequencer.load_state(r)?;
+        self.channel1.load_state(r)?;
+        self.channel2.load_state(r)?;
+        self.channel3.load_state(r)?;
+        self.channel4.load_state(r)?;
+        self.reg_ff24 = r.u8()?;
+        self.reg_ff25 = r.u8()?;
+        self.clear_buffers();
+        Ok(())
+    }
+}
+
+// Applies the final gain and limits the samples to full scale. Returns the number of samples
+// that exceeded full scale.
+fn output_stage(buf: &mut [f32], gain: f32, soft_clip: bool) -> u64 {
+    let mut clipped = 0;
+    for v in buf.iter_mut() {
+        let sample = *v * gain;
+        if sample.abs() > 1.0 {
+            clipped += 1;
+        }
+        *v = match soft_clip {
+            true => soft_clip_sample(sample),
+            false => sample.clamp(-1.0, 1.0),
+        };
+    }
+    clipped
+}
+
+// Linear up to the knee, and then a tanh curve that approaches full scale without reaching it
+fn soft_clip_sample(v: f32) -> f32 {
+    let magnitude = v.abs();
+    if magnitude <= SOFT_CLIP_KNEE {
+        return v;
+    }
+    let range = 1.0 - SOFT_CLIP_KNEE;
+    let clipped = SOFT_CLIP_KNEE + range * ((magnitude - SOFT_CLIP_KNEE) / range).tanh();
+    clipped.copysign(v)
+}
+
+fn fade_edges(buf: &mut [f32], fade_in: bool, fade_out: bool) {
+    let len = std::cmp::min(SPEED_FADE_SAMPLE_COUNT, buf.len() / 2);
+    for i in 0 .. len {
+        let factor = i as f32 / len as f32;
+        if fade_in {
+            buf[i] *= factor;
+        }
+        if fade_out {
+            let end = buf.len() - 1;
+            buf[end - i] *= factor;
+        }
+    }
+}
+
+fn output_sample_count(latency_ms: u32, samples_rate: u32) -> u32 {
+    let wanted = (latency_ms as u64 * samples_rate as u64 / 1000) as u32;
+    wanted.clamp(MIN_OUTPUT_SAMPLE_COUNT, MAX_OUTPUT_SAMPLE_COUNT)
+}
+
+fn resampler_for(samples_rate: u32) -> Option<Resampler> {
+    match samples_rate {
+        INTERNAL_SAMPLE_RATE => None,
+        rate => Some(Resampler::new(INTERNAL_SAMPLE_RATE, rate)),
+    }
 }
 
-fn create_blipbuf(samples_rate: u32) -> BlipBuf {
-    let mut blipbuf = BlipBuf::new(samples_rate);
+fn create_blipbuf(samples_rate: u32) -> Box<dyn SampleBuffer> {
+    let mut blipbuf = samplebuffer::new_sample_buffer(samples_rate);
     blipbuf.set_rates(CLOCKS_PER_SECOND as f64, samples_rate as f64);
     blipbuf
 }
+
+// The pure Rust buffers of a build without blip_buf, to compare the two in one test run
+#[cfg(test)]
+fn create_linear_buffer(samples_rate: u32) -> Box<dyn SampleBuffer> {
+    let mut buffer = samplebuffer::LinearBuffer::new(samples_rate);
+    buffer.set_rates(CLOCKS_PER_SECOND as f64, samples_rate as f64);
+    Box::new(buffer)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AudioPlayer, ChannelId, Sound, SoundOptions, CLOCKS_PER_SECOND, INTERNAL_SAMPLE_RATE, create_linear_buffer};
+    use super::soft_clip_sample;
+    use crate::apu::TestRng;
+    use crate::state::{StateReader, StateWriter};
+    use std::sync::{Arc, Mutex};
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    const SAMPLE_RATE: u32 = 44100;
+
+    type Output = Arc<Mutex<Vec<f32>>>;
+
+    struct TestPlayer {
+        left: Output,
+        right: Output,
+        rate: u32,
+    }
+
+    impl AudioPlayer for TestPlayer {
+        fn play(&mut self, left_channel: &[f32], right_channel: &[f32]) {
+            self.left.lock().unwrap().extend_from_slice(left_channel);
+            self.right.lock().unwrap().extend_from_slice(right_channel);
+        }
+
+        fn samples_rate(&self) -> u32 {
+            self.rate
+        }
+
+        fn underflowed(&self) -> bool {
+            false
+        }
+    }
+
+    fn test_player(rate: u32) -> (TestPlayer, Output, Output) {
+        let left = Arc::new(Mutex::new(Vec::new()));
+        let right = Arc::new(Mutex::new(Vec::new()));
+        (TestPlayer { left: left.clone(), right: right.clone(), rate }, left, right)
+    }
+
+    // Without headroom, so the tests see the raw mix
+    fn test_sound() -> (Sound, Output) {
+        let (sound, left, _) = test_sound_with(SoundOptions { headroom_db: 0.0, ..SoundOptions::default() });
+        (sound, left)
+    }
+
+    fn test_sound_with(options: SoundOptions) -> (Sound, Output, Output) {
+        let (player, left, right) = test_player(SAMPLE_RATE);
+        (Sound::new_dmg(Box::new(player), options), left, right)
+    }
+
+    // All channels at full volume, in phase and routed to the left output only, plus VIN
+    fn play_loud_script(sound: &mut Sound) {
+        sound.set_vin_source(Some(Box::new(|buf: &mut [f32]| {
+            for v in buf.iter_mut() { *v = 1.0; }
+        })));
+        sound.wb(0xFF26, 0x80);
+        sound.wb(0xFF24, 0x7F);
+        sound.wb(0xFF25, 0xF0);
+        for a in 0xFF30 ..= 0xFF3F {
+            sound.wb(a, 0xFF);
+        }
+        sound.wb(0xFF1A, 0x80);
+        sound.wb(0xFF1C, 0x20);
+        sound.wb(0xFF11, 0xC0);
+        sound.wb(0xFF12, 0xF0);
+        sound.wb(0xFF16, 0xC0);
+        sound.wb(0xFF17, 0xF0);
+        sound.wb(0xFF21, 0xF0);
+        sound.wb(0xFF22, 0x00);
+        sound.wb(0xFF14, 0x86);
+        sound.wb(0xFF19, 0x86);
+        sound.wb(0xFF1E, 0x86);
+        sound.wb(0xFF23, 0x80);
+        sound.do_cycle(CLOCKS_PER_SECOND / 10);
+    }
+
+    fn sample_index(cycles: u32) -> usize {
+        (cycles as u64 * SAMPLE_RATE as u64 / CLOCKS_PER_SECOND as u64) as usize
+    }
+
+    #[test]
+    fn rapid_retrigger_outputs_immediately() {
+        let (mut sound, left) = test_sound();
+        sound.wb(0xFF26, 0x80);
+        sound.wb(0xFF24, 0x77);
+        sound.wb(0xFF25, 0x11);
+        sound.wb(0xFF11, 0x80);
+        sound.wb(0xFF12, 0x08);
+        sound.wb(0xFF13, 0x00);
+        sound.wb(0xFF14, 0x80);
+
+        let start = 1024;
+        sound.do_cycle(start);
+
+        // Retrigger at full volume every 256 cycles during one millisecond. The frequency is
+        // low enough that the duty position never advances in between.
+        sound.wb(0xFF12, 0xF0);
+        let mut cycles = 0;
+        while cycles < CLOCKS_PER_SECOND / 1000 {
+            sound.wb(0xFF14, 0x80);
+            sound.do_cycle(256);
+            cycles += 256;
+        }
+        sound.do_cycle(CLOCKS_PER_SECOND / 10);
+
+        let samples = left.lock().unwrap();
+        let window = &samples[sample_index(start) + 1 .. sample_index(start + cycles)];
+        let rms = (window.iter().map(|v| v * v).sum::<f32>() / window.len() as f32).sqrt();
+
+        // Each retrigger outputs the full volume of the current duty step: 15 out of 15,
+        // scaled by the master volume of 7/7 and the mixing factor of 0.25.
+        let expected = 15.0 / 15.0 * (7.0 / 7.0) * 0.25;
+        assert!((rms - expected).abs() < expected * 0.1, "RMS was {}, expected {}", rms, expected);
+    }
+
+    #[test]
+    fn nr50_reads_back() {
+        let (mut sound, _) = test_sound();
+        sound.wb(0xFF26, 0x80);
+        for &v in [0x00, 0x88, 0x5A, 0xFF].iter() {
+            sound.wb(0xFF24, v);
+            assert_eq!(sound.rb(0xFF24), v);
+        }
+    }
+
+    #[test]
+    fn vin_is_gated_by_nr50() {
+        let (mut sound, left) = test_sound();
+        sound.set_vin_source(Some(Box::new(|buf: &mut [f32]| {
+            for v in buf.iter_mut() { *v = 1.0; }
+        })));
+        sound.wb(0xFF26, 0x80);
+        sound.wb(0xFF25, 0x00);
+
+        sound.wb(0xFF24, 0x77);
+        sound.do_cycle(CLOCKS_PER_SECOND / 10);
+        assert!(left.lock().unwrap().iter().all(|&v| v == 0.0));
+
+        sound.wb(0xFF24, 0x7F);
+        sound.do_cycle(CLOCKS_PER_SECOND / 10);
+        let samples = left.lock().unwrap();
+        assert!(!samples.is_empty());
+        assert_eq!(*samples.last().unwrap(), 0.25);
+    }
+
+    #[test]
+    fn loud_script_clips_without_headroom() {
+        let (mut sound, left, right) = test_sound_with(SoundOptions { headroom_db: 0.0, ..SoundOptions::default() });
+        let stats = sound.clip_stats();
+        play_loud_script(&mut sound);
+
+        assert!(stats.left() > 0);
+        assert_eq!(stats.right(), 0);
+        assert!(left.lock().unwrap().iter().all(|v| v.abs() <= 1.0));
+        assert!(right.lock().unwrap().iter().all(|&v| v == 0.0));
+
+        let (clipped_left, _) = stats.take();
+        assert!(clipped_left > 0);
+        assert_eq!(stats.left(), 0);
+    }
+
+    #[test]
+    fn default_headroom_avoids_clipping() {
+        let (mut sound, left, _) = test_sound_with(SoundOptions::default());
+        let stats = sound.clip_stats();
+        play_loud_script(&mut sound);
+
+        assert_eq!((stats.left(), stats.right()), (0, 0));
+        assert!(left.lock().unwrap().iter().any(|v| v.abs() > 0.5));
+    }
+
+    #[test]
+    fn soft_clip() {
+        let (mut sound, left, _) = test_sound_with(SoundOptions { headroom_db: 0.0, soft_clip: true, ..SoundOptions::default() });
+        let stats = sound.clip_stats();
+        play_loud_script(&mut sound);
+
+        assert!(stats.left() > 0);
+        assert!(left.lock().unwrap().iter().all(|v| v.abs() < 1.0));
+
+        assert_eq!(soft_clip_sample(0.5), 0.5);
+        assert_eq!(soft_clip_sample(-0.75), -0.75);
+        assert!(soft_clip_sample(0.9) > 0.8 && soft_clip_sample(0.9) < 0.9);
+        assert!(soft_clip_sample(-2.0) > -1.0);
+    }
+
+    #[test]
+    fn set_player_keeps_state() {
+        let (mut sound, left) = test_sound();
+        sound.wb(0xFF26, 0x80);
+        sound.wb(0xFF24, 0x77);
+        sound.wb(0xFF25, 0x11);
+        sound.wb(0xFF11, 0x80);
+        sound.wb(0xFF12, 0xF0);
+        sound.wb(0xFF13, 0x00);
+        sound.wb(0xFF14, 0x87);
+        sound.do_cycle(CLOCKS_PER_SECOND / 20);
+        assert!(!left.lock().unwrap().is_empty());
+
+        let registers: Vec<u8> = (0xFF10 ..= 0xFF3F).map(|a| sound.rb(a)).collect();
+        let (player, new_left, _) = test_player(22050);
+        sound.set_player(Box::new(player));
+        assert_eq!((0xFF10 ..= 0xFF3F).map(|a| sound.rb(a)).collect::<Vec<u8>>(), registers);
+
+        run_frames(&mut sound, 6);
+        let samples = new_left.lock().unwrap();
+        assert!(samples.len() > 2000 && samples.len() <= 2205, "got {} samples", samples.len());
+        assert!(samples.iter().any(|v| v.abs() > 0.1));
+    }
+
+    fn save(sound: &Sound) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        sound.save_state(&mut w);
+        w.into_vec()
+    }
+
+    #[test]
+    fn save_state_round_trip() {
+        let (mut sound, _) = test_sound();
+        sound.wb(0xFF26, 0x80);
+        sound.wb(0xFF25, 0xFF);
+        sound.wb(0xFF10, 0x16);
+        sound.wb(0xFF11, 0x50);
+        sound.wb(0xFF12, 0xF3);
+        sound.wb(0xFF14, 0xC7);
+        sound.wb(0xFF1A, 0x80);
+        sound.wb(0xFF1B, 0x40);
+        sound.wb(0xFF1C, 0x20);
+        sound.wb(0xFF1E, 0xC3);
+        sound.wb(0xFF21, 0x1F);
+        sound.wb(0xFF22, 0x4A);
+        sound.wb(0xFF23, 0xC0);
+        sound.do_cycle(12345);
+        let state = save(&sound);
+
+        let run = |sound: &mut Sound| {
+            let mut registers = Vec::new();
+            for _ in 0 .. 40 {
+                sound.do_cycle(CLOCKS_PER_SECOND / 100);
+                registers.extend((0xFF10 ..= 0xFF3F).map(|a| sound.rb(a)));
+            }
+            registers
+        };
+        let registers = run(&mut sound);
+        let after = save(&sound);
+
+        // The other APU has a different latency and has been running on its own
+        let (mut other, _, _) = test_sound_with(SoundOptions { latency_ms: 100, ..SoundOptions::default() });
+        other.wb(0xFF26, 0x80);
+        other.do_cycle(CLOCKS_PER_SECOND / 7);
+        let mut r = StateReader::new(&state);
+        other.load_state(&mut r).unwrap();
+        r.finish().unwrap();
+        assert_eq!(save(&other), state);
+        assert_eq!(run(&mut other), registers);
+        assert_eq!(save(&other), after);
+    }
+
+    #[test]
+    fn nr52_write_storm() {
+        let (mut sound, _) = test_sound();
+        sound.wb(0xFF26, 0x80);
+        sound.wb(0xFF12, 0xF0);
+        sound.wb(0xFF14, 0x80);
+
+        let start = ::std::time::Instant::now();
+        for i in 0 .. 1_000_000 {
+            sound.wb(0xFF26, if i % 2 == 0 { 0x00 } else { 0x80 });
+            if i % 16 == 0 {
+                sound.do_cycle(1);
+            }
+        }
+        assert!(start.elapsed() < ::std::time::Duration::from_secs(5), "took {:?}", start.elapsed());
+        assert_eq!(sound.rb(0xFF26) & 0x80, 0x80);
+    }
+
+    // Channel 1 playing a 512 Hz square wave for two seconds, in steps of a frame
+    fn play_square_script(sound: &mut Sound) {
+        sound.wb(0xFF26, 0x80);
+        sound.wb(0xFF25, 0x11);
+        sound.wb(0xFF11, 0x80);
+        sound.wb(0xFF12, 0xF0);
+        sound.wb(0xFF13, 0x00);
+        sound.wb(0xFF14, 0x87);
+        for _ in 0 .. 120 {
+            sound.do_cycle(CLOCKS_PER_SECOND / 60);
+        }
+    }
+
+    // The cycles per sample, measured between the first and the last rise of the wave. A rise goes
+    // from below -0.1 to above 0.1, so the quiet start of the channel and the faded edges around
+    // skipped buffers are not counted.
+    fn wave_rate(samples: &[f32]) -> f32 {
+        let mut rises = Vec::new();
+        let mut low = false;
+        for (i, &v) in samples.iter().enumerate() {
+            if v < -0.1 {
+                low = true;
+            } else if v > 0.1 && low {
+                low = false;
+                rises.push(i);
+            }
+        }
+        assert!(rises.len() > 2);
+        (rises.len() - 1) as f32 / (rises[rises.len() - 1] - rises[0]) as f32
+    }
+
+    #[test]
+    fn speed_multiplier_one_is_identical() {
+        let (mut sound, left) = test_sound();
+        play_loud_script(&mut sound);
+
+        let (mut fast_forwarded, fast_forwarded_left) = test_sound();
+        fast_forwarded.set_speed_multiplier(4.0);
+        fast_forwarded.set_speed_multiplier(1.0);
+        play_loud_script(&mut fast_forwarded);
+
+        assert_eq!(*left.lock().unwrap(), *fast_forwarded_left.lock().unwrap());
+    }
+
+    #[test]
+    fn fast_forward_keeps_pitch() {
+        let (mut sound, left) = test_sound();
+        play_square_script(&mut sound);
+
+        let (mut fast_forwarded, fast_forwarded_left) = test_sound();
+        fast_forwarded.set_speed_multiplier(4.0);
+        play_square_script(&mut fast_forwarded);
+
+        let normal = left.lock().unwrap();
+        let fast = fast_forwarded_left.lock().unwrap();
+        assert!(fast.len() * 4 <= normal.len() + 2 * sound.output_sample_count, "{} vs {}", fast.len(), normal.len());
+        assert!(fast.len() * 4 + 2 * sound.output_sample_count >= normal.len(), "{} vs {}", fast.len(), normal.len());
+
+        let normal_rate = wave_rate(&normal);
+        let fast_rate = wave_rate(&fast);
+        assert!((normal_rate / fast_rate - 1.0).abs() < 0.05, "{} vs {}", normal_rate, fast_rate);
+        assert!(fast.iter().all(|v| v.abs() <= 1.0));
+    }
+
+    #[test]
+    fn debug_state() {
+        let (mut sound, _) = test_sound();
+        sound.wb(0xFF26, 0x80);
+        sound.wb(0xFF24, 0x57);
+        sound.wb(0xFF25, 0xF3);
+        sound.wb(0xFF10, 0x23);
+        sound.wb(0xFF11, 0x80);
+        sound.wb(0xFF12, 0xF3);
+        sound.wb(0xFF13, 0xD6);
+        sound.wb(0xFF14, 0xC6);
+        sound.do_cycle(CLOCKS_PER_SECOND / 512 * 3);
+        sound.catch_up();
+
+        let state = sound.debug_state();
+        assert_eq!((state.nr50, state.nr51, state.nr52), (0x57, 0xF3, 0xF1));
+        assert_eq!(state.frame_step, 3);
+        assert_eq!(state.channels_enabled, [true, false, false, false]);
+
+        let square = &state.square1;
+        assert!(square.enabled && square.dac_enabled && square.length_enabled);
+        assert_eq!((square.frequency, square.period, square.duty), (0x6D6, (2048 - 0x6D6) * 4, 2));
+        assert_eq!((square.length, square.volume, square.envelope_period, square.envelope_goes_up), (62, 15, 3, false));
+        let sweep = square.sweep.as_ref().unwrap();
+        assert!(sweep.enabled);
+        assert_eq!((sweep.frequency, sweep.period, sweep.shift, sweep.negate), (0x6D6, 2, 3, false));
+
+        assert!(!state.square2.enabled);
+        assert!(state.square2.sweep.is_none());
+
+        let text = state.to_string();
+        assert_eq!(text.lines().count(), 3);
+        assert!(text.starts_with("NR50=57 NR51=F3 NR52=F1 frame step 3 channels 1---\n"), "{}", text);
+        assert!(text.contains("CH1 on  dac on  freq 6D6"), "{}", text);
+        assert!(text.contains("sweep on freq 6D6 period 2 shift 3 up"), "{}", text);
+    }
+
+    // Channel 2 as described in the Pan Docs, limited to what decides whether it is enabled
+    struct Channel2Reference {
+        enabled: bool,
+        dac: bool,
+        length: u16,
+        length_enabled: bool,
+    }
+
+    impl Channel2Reference {
+        fn write(&mut self, a: u16, v: u8, next_step: u8) {
+            let first_half = next_step % 2 == 1;
+            match a {
+                0xFF16 => self.length = 64 - (v & 0x3F) as u16,
+                0xFF17 => {
+                    self.dac = v & 0xF8 != 0;
+                    self.enabled &= self.dac;
+                },
+                0xFF19 => {
+                    let enable = v & 0x40 != 0;
+                    if enable && !self.length_enabled && first_half && self.length != 0 {
+                        self.length -= 1;
+                        if self.length == 0 {
+                            self.enabled = false;
+                        }
+                    }
+                    self.length_enabled = enable;
+                    if v & 0x80 != 0 {
+                        if self.length == 0 {
+                            self.length = if enable && first_half { 63 } else { 64 };
+                        }
+                        self.enabled = self.dac;
+                    }
+                },
+                _ => unreachable!(),
+            }
+        }
+
+        fn step(&mut self, step: u8) {
+            if step % 2 == 0 && self.length_enabled && self.length != 0 {
+                self.length -= 1;
+                if self.length == 0 {
+                    self.enabled = false;
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn length_counter_matches_reference() {
+        let mut rng = TestRng::new(287);
+        for _ in 0 .. 100 {
+            let (mut sound, _) = test_sound();
+            sound.wb(0xFF26, 0x80);
+            let mut reference = Channel2Reference { enabled: false, dac: false, length: 0, length_enabled: false };
+
+            for _ in 0 .. 300 {
+                if rng.below(2) == 0 {
+                    let a = [0xFF16, 0xFF17, 0xFF19][rng.below(3) as usize];
+                    let v = rng.next() as u8;
+                    let next_step = sound.debug_state().frame_step;
+                    sound.wb(a, v);
+                    reference.write(a, v, next_step);
+                }
+                else {
+                    let step = sound.debug_state().frame_step;
+                    sound.do_cycle(CLOCKS_PER_SECOND / 512);
+                    sound.catch_up();
+                    reference.step(step);
+                }
+
+                let state = sound.debug_state().square2;
+                assert!(state.length <= 64);
+                assert!(!state.enabled || (state.dac_enabled && state.length != 0));
+                assert_eq!((state.enabled, state.length, state.length_enabled),
+                    (reference.enabled, reference.length, reference.length_enabled));
+            }
+        }
+    }
+
+    struct UnderrunPlayer {
+        underruns: Arc<AtomicU64>,
+    }
+
+    impl AudioPlayer for UnderrunPlayer {
+        fn play(&mut self, _left_channel: &[f32], _right_channel: &[f32]) {}
+
+        fn samples_rate(&self) -> u32 {
+            SAMPLE_RATE
+        }
+
+        fn underflowed(&self) -> bool {
+            false
+        }
+
+        fn underruns(&self) -> u64 {
+            self.underruns.load(Ordering::Relaxed)
+        }
+    }
+
+    fn run_frames(sound: &mut Sound, frames: u32) {
+        for _ in 0 .. frames {
+            sound.do_cycle(CLOCKS_PER_SECOND / 60);
+        }
+    }
+
+    #[test]
+    fn underrun_rate() {
+        let underruns = Arc::new(AtomicU64::new(0));
+        let mut sound = Sound::new_dmg(Box::new(UnderrunPlayer { underruns: underruns.clone() }), SoundOptions::default());
+        sound.wb(0xFF26, 0x80);
+
+        underruns.store(5, Ordering::Relaxed);
+        assert_eq!(sound.underrun_count(), 5);
+        assert_eq!(sound.underrun_rate(), 0);
+        run_frames(&mut sound, 64);
+        assert_eq!(sound.underrun_rate(), 5);
+
+        underruns.store(7, Ordering::Relaxed);
+        run_frames(&mut sound, 64);
+        assert_eq!(sound.underrun_rate(), 2);
+
+        // A new player starts counting from its own total
+        let new_underruns = Arc::new(AtomicU64::new(100));
+        sound.set_player(Box::new(UnderrunPlayer { underruns: new_underruns.clone() }));
+        new_underruns.store(101, Ordering::Relaxed);
+        run_frames(&mut sound, 64);
+        assert_eq!((sound.underrun_count(), sound.underrun_rate()), (101, 1));
+    }
+
+    struct NullPlayer {
+        rate: u32,
+    }
+
+    impl AudioPlayer for NullPlayer {
+        fn play(&mut self, _left_channel: &[f32], _right_channel: &[f32]) {
+        }
+
+        fn samples_rate(&self) -> u32 {
+            self.rate
+        }
+
+        fn underflowed(&self) -> bool {
+            false
+        }
+    }
+
+    // FNV-1a over the channel samples seen by the sample tap, for one second of a fixed script
+    fn script_checksum(player_rate: u32) -> u64 {
+        let mut sound = Sound::new_dmg(Box::new(NullPlayer { rate: player_rate }), SoundOptions::default());
+        let hash = Arc::new(Mutex::new(0xcbf29ce484222325u64));
+        let tap_hash = hash.clone();
+        sound.set_sample_tap(Some(Box::new(move |channel: ChannelId, samples: &[i16]| {
+            let mut hash = tap_hash.lock().unwrap();
+            for &v in samples {
+                for &b in [channel as u8, v as u8, (v >> 8) as u8].iter() {
+                    *hash = (*hash ^ b as u64).wrapping_mul(0x100000001b3);
+                }
+            }
+        })));
+
+        sound.wb(0xFF26, 0x80);
+        sound.wb(0xFF24, 0x77);
+        sound.wb(0xFF25, 0xFF);
+        for a in 0xFF30 ..= 0xFF3F {
+            sound.wb(a, ((a & 0xF) as u8) * 0x11);
+        }
+        sound.wb(0xFF10, 0x24);
+        sound.wb(0xFF11, 0x80);
+        sound.wb(0xFF12, 0xF3);
+        sound.wb(0xFF13, 0x00);
+        sound.wb(0xFF14, 0x86);
+        sound.wb(0xFF16, 0x50);
+        sound.wb(0xFF17, 0xA2);
+        sound.wb(0xFF18, 0x40);
+        sound.wb(0xFF19, 0xC5);
+        sound.wb(0xFF1A, 0x80);
+        sound.wb(0xFF1C, 0x40);
+        sound.wb(0xFF1D, 0x20);
+        sound.wb(0xFF1E, 0x85);
+        sound.wb(0xFF21, 0xF1);
+        sound.wb(0xFF22, 0x45);
+        sound.wb(0xFF23, 0x80);
+        run_frames(&mut sound, 30);
+        sound.wb(0xFF22, 0x2C);
+        sound.wb(0xFF23, 0x80);
+        sound.wb(0xFF13, 0x80);
+        sound.wb(0xFF14, 0x87);
+        run_frames(&mut sound, 30);
+
+        let hash = *hash.lock().unwrap();
+        hash
+    }
+
+    #[test]
+    fn output_is_independent_of_player_rate() {
+        #[cfg(feature = "blip_buf")]
+        const EXPECTED: u64 = 0xd25270a9a2127579;
+        #[cfg(not(feature = "blip_buf"))]
+        const EXPECTED: u64 = 0x7c0258a5ab1be450;
+
+        for &rate in [11025, 22050, 44100, 48000, 192000].iter() {
+            assert_eq!(script_checksum(rate), EXPECTED, "checksum differs at {} Hz", rate);
+        }
+    }
+    #[test]
+    fn sample_buffers_agree() {
+        // The registers do not depend on the buffer, and both give the same number of samples
+        let sounds = || {
+            let (blip, blip_left, _) = test_sound_with(SoundOptions::default());
+            let (mut linear, linear_left, _) = test_sound_with(SoundOptions::default());
+            linear.channel1.blip = create_linear_buffer(INTERNAL_SAMPLE_RATE);
+            linear.channel2.blip = create_linear_buffer(INTERNAL_SAMPLE_RATE);
+            linear.channel3.blip = create_linear_buffer(INTERNAL_SAMPLE_RATE);
+            linear.channel4.blip = create_linear_buffer(INTERNAL_SAMPLE_RATE);
+            [(blip, blip_left), (linear, linear_left)]
+        };
+        let script: &[(u16, u8, u32)] = &[
+            (0xFF26, 0x80, 0), (0xFF24, 0x77, 0), (0xFF25, 0xFF, 0),
+            (0xFF10, 0x16, 0), (0xFF11, 0x80, 0), (0xFF12, 0xF3, 0), (0xFF13, 0x00, 0), (0xFF14, 0x87, 10),
+            (0xFF16, 0x40, 0), (0xFF17, 0x71, 0), (0xFF18, 0x50, 0), (0xFF19, 0xC5, 5),
+            (0xFF1A, 0x80, 0), (0xFF1C, 0x40, 0), (0xFF1D, 0x20, 0), (0xFF1E, 0x85, 20),
+            (0xFF21, 0xF1, 0), (0xFF22, 0x45, 0), (0xFF23, 0x80, 30),
+            (0xFF25, 0x0F, 5), (0xFF26, 0x00, 5), (0xFF26, 0x80, 10),
+        ];
+        let mut sounds = sounds();
+        for &(a, v, frames) in script {
+            for (sound, _) in sounds.iter_mut() {
+                sound.wb(a, v);
+                run_frames(sound, frames);
+            }
+            let [(blip, blip_left), (linear, linear_left)] = &mut sounds;
+            for r in 0xFF10 ..= 0xFF3F {
+                assert_eq!(blip.rb(r), linear.rb(r), "register {:04X} after writing {:02X} to {:04X}", r, v, a);
+            }
+            assert_eq!(blip_left.lock().unwrap().len(), linear_left.lock().unwrap().len(), "after writing {:02X} to {:04X}", v, a);
+        }
+        assert!(!sounds[0].1.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,220 @@
+use std::convert::TryInto;
+use std::fmt;
+
+// Every state starts with the magic, the format version, and the checksums from the header of
+// the game it was saved from
+pub const STATE_MAGIC: &[u8; 8] = b"RBOYSTAT";
+// Has to be increased on any change to what the components write
+pub const STATE_VERSION: u16 = 2;
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum StateError {
+    // Not a save state of this emulator
+    NotAState,
+    // Written by a version of the emulator with another format
+    UnsupportedVersion(u16),
+    // Saved while another game was loaded
+    WrongGame,
+    // Cut off or damaged
+    Corrupt,
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::NotAState => write!(f, "Not a save state"),
+            StateError::UnsupportedVersion(v) => write!(f, "Unsupported save state version {}, expected {}", v, STATE_VERSION),
+            StateError::WrongGame => write!(f, "The save state is for another game"),
+            StateError::Corrupt => write!(f, "The save state is corrupt"),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+pub type StateResult<T> = Result<T, StateError>;
+
+// Writes the values in little endian, without any framing. The reader has to read them back in
+// the same order.
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { data: Vec::new() }
+    }
+
+    pub fn into_vec(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn u8(&mut self, v: u8) {
+        self.data.push(v);
+    }
+
+    pub fn bool(&mut self, v: bool) {
+        self.data.push(v as u8);
+    }
+
+    pub fn u16(&mut self, v: u16) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, v: u64) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn i32(&mut self, v: i32) {
+        self.data.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn usize(&mut self, v: usize) {
+        self.u64(v as u64);
+    }
+
+    // A block whose size the reader knows
+    pub fn bytes(&mut self, v: &[u8]) {
+        self.data.extend_from_slice(v);
+    }
+
+    // A block with its length in front, such as the cartridge RAM
+    pub fn vec(&mut self, v: &[u8]) {
+        self.u32(v.len() as u32);
+        self.bytes(v);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data }
+    }
+
+    fn take(&mut self, n: usize) -> StateResult<&'a [u8]> {
+        if n > self.data.len() {
+            return Err(StateError::Corrupt);
+        }
+        let (head, tail) = self.data.split_at(n);
+        self.data = tail;
+        Ok(head)
+    }
+
+    pub fn u8(&mut self) -> StateResult<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn bool(&mut self) -> StateResult<bool> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Corrupt),
+        }
+    }
+
+    pub fn u16(&mut self) -> StateResult<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn u32(&mut self) -> StateResult<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> StateResult<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn i32(&mut self) -> StateResult<i32> {
+        Ok(i32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn usize(&mut self) -> StateResult<usize> {
+        self.u64()?.try_into().map_err(|_| StateError::Corrupt)
+    }
+
+    // A usize that has to be below max, e.g. a bank number that indexes into memory
+    pub fn index(&mut self, max: usize) -> StateResult<usize> {
+        match self.usize()? {
+            v if v < max => Ok(v),
+            _ => Err(StateError::Corrupt),
+        }
+    }
+
+    pub fn bytes(&mut self, n: usize) -> StateResult<&'a [u8]> {
+        self.take(n)
+    }
+
+    pub fn bytes_into(&mut self, dest: &mut [u8]) -> StateResult<()> {
+        dest.copy_from_slice(self.take(dest.len())?);
+        Ok(())
+    }
+
+    // Reads a block written by StateWriter::vec, which has to fit dest exactly
+    pub fn vec_into(&mut self, dest: &mut [u8]) -> StateResult<()> {
+        if self.u32()? as usize != dest.len() {
+            return Err(StateError::Corrupt);
+        }
+        self.bytes_into(dest)
+    }
+
+    pub fn vec(&mut self) -> StateResult<&'a [u8]> {
+        let len = self.u32()? as usize;
+        self.take(len)
+    }
+
+    // Fails when there is data left over, which a state of the same version never has
+    pub fn finish(self) -> StateResult<()> {
+        match self.data.is_empty() {
+            true => Ok(()),
+            false => Err(StateError::Corrupt),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{StateError, StateReader, StateWriter};
+
+    #[test]
+    fn round_trip() {
+        let mut w = StateWriter::new();
+        w.u8(0x12);
+        w.bool(true);
+        w.u16(0x3456);
+        w.u32(0x789A_BCDE);
+        w.u64(u64::MAX - 1);
+        w.i32(-5);
+        w.usize(7);
+        w.vec(&[1, 2, 3]);
+        let data = w.into_vec();
+
+        let mut r = StateReader::new(&data);
+        assert_eq!(r.u8(), Ok(0x12));
+        assert_eq!(r.bool(), Ok(true));
+        assert_eq!(r.u16(), Ok(0x3456));
+        assert_eq!(r.u32(), Ok(0x789A_BCDE));
+        assert_eq!(r.u64(), Ok(u64::MAX - 1));
+        assert_eq!(r.i32(), Ok(-5));
+        assert_eq!(r.index(8), Ok(7));
+        let mut v = [0; 3];
+        r.vec_into(&mut v).unwrap();
+        assert_eq!(v, [1, 2, 3]);
+        assert_eq!(r.finish(), Ok(()));
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(StateReader::new(&[1, 2]).u32(), Err(StateError::Corrupt));
+        assert_eq!(StateReader::new(&[2]).bool(), Err(StateError::Corrupt));
+        assert_eq!(StateReader::new(&[9, 0, 0, 0, 0, 0, 0, 0]).index(8), Err(StateError::Corrupt));
+        assert_eq!(StateReader::new(&[3, 0, 0, 0, 1, 2, 3]).vec_into(&mut [0; 2]), Err(StateError::Corrupt));
+        assert_eq!(StateReader::new(&[0]).finish(), Err(StateError::Corrupt));
+    }
+}
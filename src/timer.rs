@@ -1,3 +1,5 @@
+use crate::state::{StateError, StateReader, StateResult, StateWriter};
+
 pub struct Timer {
     divider: u8,
     counter: u8,
@@ -70,5 +72,30 @@ impl Timer {
             }
         }
     }
-}
 
+    pub fn save_state(&self, w: &mut StateWriter) {
+        w.u8(self.divider);
+        w.u8(self.counter);
+        w.u8(self.modulo);
+        w.bool(self.enabled);
+        w.u32(self.step);
+        w.u32(self.internalcnt);
+        w.u32(self.internaldiv);
+        w.u8(self.interrupt);
+    }
+
+    pub fn load_state(&mut self, r: &mut StateReader) -> StateResult<()> {
+        self.divider = r.u8()?;
+        self.counter = r.u8()?;
+        self.modulo = r.u8()?;
+        self.enabled = r.bool()?;
+        self.step = match r.u32()? {
+            step @ (16 | 64 | 256 | 1024) => step,
+            _ => return Err(StateError::Corrupt),
+        };
+        self.internalcnt = r.u32()?;
+        self.internaldiv = r.u32()?;
+        self.interrupt = r.u8()?;
+        Ok(())
+    }
+}
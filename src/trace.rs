@@ -0,0 +1,44 @@
+use crate::disasm::disassemble;
+use crate::register::Registers;
+use std::io::{BufWriter, Write};
+
+// Logs the CPU state before every instruction, in the format of Gameboy Doctor
+pub struct CpuTrace {
+    out: BufWriter<Box<dyn Write + Send>>,
+    remaining: Option<u64>,
+    disasm: bool,
+}
+
+impl CpuTrace {
+    // The limit is the number of instructions to log. With disasm, each line ends with the
+    // instruction, which the reference logs of Gameboy Doctor do not have.
+    pub fn new(out: Box<dyn Write + Send>, limit: Option<u64>, disasm: bool) -> CpuTrace {
+        CpuTrace {
+            out: BufWriter::with_capacity(1 << 16, out),
+            remaining: limit,
+            disasm,
+        }
+    }
+
+    // Returns false once the trace is finished, because the limit was reached or writing failed
+    pub fn log(&mut self, reg: &Registers, pcmem: [u8; 4]) -> bool {
+        if self.remaining == Some(0) {
+            return false;
+        }
+        let mut result = write!(self.out, "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{:02X},{:02X},{:02X},{:02X}",
+            reg.a, reg.af() & 0xFF, reg.b, reg.c, reg.d, reg.e, reg.h, reg.l, reg.sp, reg.pc,
+            pcmem[0], pcmem[1], pcmem[2], pcmem[3]);
+        if self.disasm {
+            result = result.and_then(|_| write!(self.out, " {}", disassemble(&pcmem, reg.pc).0));
+        }
+        let result = result.and_then(|_| writeln!(self.out));
+        if let Some(ref mut n) = self.remaining {
+            *n -= 1;
+        }
+        result.is_ok() && self.remaining != Some(0)
+    }
+
+    pub fn flush(&mut self) {
+        let _ = self.out.flush();
+    }
+}
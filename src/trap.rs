@@ -0,0 +1,366 @@
+use std::collections::VecDeque;
+use std::fmt;
+
+const HISTORY_LEN: usize = 32;
+const DEFAULT_RST38_LIMIT: u32 = 4;
+
+// First-chance traps, which pause the emulation on control flow that usually means the
+// game has crashed, often because of an emulation bug.
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct TrapOptions {
+    // Jump, call or return to 0x0000 by anything else than RST 00
+    pub jump_to_zero: bool,
+    // Executing from 0xFEA0-0xFEFF or 0xFFFF
+    pub invalid_pc: bool,
+    // Executing more than this many RST 38 (opcode 0xFF) instructions in a row
+    pub rst38_limit: Option<u32>,
+    // A CGB speed switch that would not work on hardware, see SPEED_SWITCH_MATRIX
+    pub speed_switch: bool,
+}
+
+impl TrapOptions {
+    pub fn enabled() -> TrapOptions {
+        TrapOptions {
+            jump_to_zero: true,
+            invalid_pc: true,
+            rst38_limit: Some(DEFAULT_RST38_LIMIT),
+            speed_switch: true,
+        }
+    }
+
+    pub fn disabled() -> TrapOptions {
+        TrapOptions {
+            jump_to_zero: false,
+            invalid_pc: false,
+            rst38_limit: None,
+            speed_switch: false,
+        }
+    }
+
+    pub fn any_enabled(&self) -> bool {
+        self.jump_to_zero || self.invalid_pc || self.rst38_limit.is_some() || self.speed_switch
+    }
+}
+
+// On in debug builds, opt-in otherwise
+impl Default for TrapOptions {
+    fn default() -> TrapOptions {
+        match cfg!(debug_assertions) {
+            true => TrapOptions::enabled(),
+            false => TrapOptions::disabled(),
+        }
+    }
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum Trap {
+    JumpToZero { from: u16 },
+    InvalidPc(u16),
+    Rst38Chain(u32),
+    SpeedSwitch { outcome: SpeedSwitchOutcome, explanation: &'static str },
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Trap::JumpToZero { from } => write!(f, "Jump to 0000 from {:04X}", from),
+            Trap::InvalidPc(pc) => write!(f, "Execution from invalid address {:04X}", pc),
+            Trap::Rst38Chain(count) => write!(f, "{} consecutive RST 38 instructions", count),
+            Trap::SpeedSwitch { explanation, .. } => write!(f, "Speed switch that fails on hardware, {}", explanation),
+        }
+    }
+}
+
+// The state of the machine when STOP is executed with a speed switch requested in KEY1
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub struct SpeedSwitchConditions {
+    // P1 selects the buttons, the direction keys or both
+    pub joypad_selected: bool,
+    // One of the selected keys is held
+    pub key_held: bool,
+    // IE & IF is not zero
+    pub interrupt_pending: bool,
+    pub ime: bool,
+}
+
+#[derive(PartialEq, Copy, Clone, Debug)]
+pub enum SpeedSwitchOutcome {
+    // The speed switches, as emulated
+    Switches,
+    // STOP halts the CPU or is skipped, and the speed does not change
+    NoSwitch,
+    // The CPU behaves unpredictably and usually hangs
+    Hang,
+    // The speed switches, but a key press during the switch would stop it
+    Unreliable,
+}
+
+// A row of the decision matrix. None matches either value.
+pub struct SpeedSwitchRule {
+    pub key_held: Option<bool>,
+    pub joypad_selected: Option<bool>,
+    pub interrupt_pending: Option<bool>,
+    pub ime: Option<bool>,
+    pub outcome: SpeedSwitchOutcome,
+    pub explanation: &'static str,
+}
+
+impl SpeedSwitchRule {
+    fn matches(&self, conditions: &SpeedSwitchConditions) -> bool {
+        let matches = |rule: Option<bool>, value: bool| rule.unwrap_or(value) == value;
+        matches(self.key_held, conditions.key_held) &&
+            matches(self.joypad_selected, conditions.joypad_selected) &&
+            matches(self.interrupt_pending, conditions.interrupt_pending) &&
+            matches(self.ime, conditions.ime)
+    }
+}
+
+// What the hardware does on STOP with a speed switch requested, after the STOP flowchart in the
+// Pan Docs. The first matching row applies.
+pub static SPEED_SWITCH_MATRIX: [SpeedSwitchRule; 5] = [
+    SpeedSwitchRule {
+        key_held: Some(true), joypad_selected: None, interrupt_pending: Some(true), ime: None,
+        outcome: SpeedSwitchOutcome::NoSwitch,
+        explanation: "a selected key is held and an interrupt is pending, so STOP is skipped and the speed does not change",
+    },
+    SpeedSwitchRule {
+        key_held: Some(true), joypad_selected: None, interrupt_pending: Some(false), ime: None,
+        outcome: SpeedSwitchOutcome::NoSwitch,
+        explanation: "a selected key is held, so STOP only halts until an interrupt and the speed does not change",
+    },
+    SpeedSwitchRule {
+        key_held: Some(false), joypad_selected: None, interrupt_pending: Some(true), ime: Some(true),
+        outcome: SpeedSwitchOutcome::Hang,
+        explanation: "an enabled interrupt is pending while IME is set, so the CPU glitches and usually hangs",
+    },
+    SpeedSwitchRule {
+        key_held: Some(false), joypad_selected: Some(true), interrupt_pending: None, ime: None,
+        outcome: SpeedSwitchOutcome::Unreliable,
+        explanation: "P1 still selects the keys, so a key press during the switch stops it. Write $30 to P1 first",
+    },
+    SpeedSwitchRule {
+        key_held: None, joypad_selected: None, interrupt_pending: None, ime: None,
+        outcome: SpeedSwitchOutcome::Switches,
+        explanation: "the speed switches",
+    },
+];
+
+pub fn speed_switch_rule(conditions: &SpeedSwitchConditions) -> &'static SpeedSwitchRule {
+    SPEED_SWITCH_MATRIX.iter().find(|rule| rule.matches(conditions)).unwrap()
+}
+
+pub struct TrapReport {
+    pub trap: Trap,
+    // The most recently executed instructions, oldest first
+    pub history: Vec<(u16, String)>,
+}
+
+impl fmt::Display for TrapReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Trap: {}", self.trap)?;
+        writeln!(f, "Recently executed instructions:")?;
+        for &(pc, ref instruction) in self.history.iter() {
+            writeln!(f, "  {:04X}  {}", pc, instruction)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct TrapMonitor {
+    options: TrapOptions,
+    history: VecDeque<u16>,
+    rst38_count: u32,
+}
+
+impl TrapMonitor {
+    pub fn new(options: TrapOptions) -> TrapMonitor {
+        TrapMonitor {
+            options,
+            history: VecDeque::with_capacity(HISTORY_LEN),
+            rst38_count: 0,
+        }
+    }
+
+    // Called before the instruction at pc is executed
+    pub fn before(&mut self, pc: u16, opcode: u8) -> Option<Trap> {
+        if self.history.len() == HISTORY_LEN {
+            self.history.pop_front();
+        }
+        self.history.push_back(pc);
+
+        if self.options.invalid_pc && ((0xFEA0 ..= 0xFEFF).contains(&pc) || pc == 0xFFFF) {
+            return Some(Trap::InvalidPc(pc));
+        }
+
+        self.rst38_count = match opcode {
+            0xFF => self.rst38_count + 1,
+            _ => 0,
+        };
+        match self.options.rst38_limit {
+            Some(limit) if self.rst38_count > limit => Some(Trap::Rst38Chain(self.rst38_count)),
+            _ => None,
+        }
+    }
+
+    // Called after the instruction at pc has been executed
+    pub fn after(&mut self, pc: u16, opcode: u8, newpc: u16) -> Option<Trap> {
+        if self.options.jump_to_zero && newpc == 0x0000 && opcode != 0xC7 {
+            return Some(Trap::JumpToZero { from: pc });
+        }
+        None
+    }
+
+    // Called when STOP is executed with a speed switch requested
+    pub fn speed_switch(&self, conditions: &SpeedSwitchConditions) -> Option<Trap> {
+        if !self.options.speed_switch {
+            return None;
+        }
+        let rule = speed_switch_rule(conditions);
+        match rule.outcome {
+            SpeedSwitchOutcome::Switches => None,
+            outcome => Some(Trap::SpeedSwitch { outcome, explanation: rule.explanation }),
+        }
+    }
+
+    pub fn options(&self) -> TrapOptions {
+        self.options
+    }
+
+    pub fn history(&self) -> impl Iterator<Item=u16> + '_ {
+        self.history.iter().copied()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{speed_switch_rule, SpeedSwitchConditions, SpeedSwitchOutcome, Trap, TrapOptions, SPEED_SWITCH_MATRIX};
+    use crate::cpu::CPU;
+    use crate::mbc;
+
+    const CPUINSTRS: &str = "roms/cpu_instrs.gb";
+
+    fn rom_with_code(code: &[u8]) -> Vec<u8> {
+        let mut romdata = vec![0; 0x8000];
+        romdata[0x0100 .. 0x0100 + code.len()].copy_from_slice(code);
+        romdata
+    }
+
+    fn run_until_trap(romdata: Vec<u8>, steps: usize) -> Option<Trap> {
+        let cart = mbc::get_mbc(romdata, true).unwrap();
+        run_cpu_until_trap(CPU::new(cart, None).unwrap(), steps)
+    }
+
+    fn run_cgb_until_trap(code: &[u8], steps: usize) -> Option<Trap> {
+        let mut romdata = rom_with_code(code);
+        romdata[0x0143] = 0x80;
+        let cart = mbc::get_mbc(romdata, true).unwrap();
+        run_cpu_until_trap(CPU::new_cgb(cart, None).unwrap(), steps)
+    }
+
+    fn run_cpu_until_trap(mut c: CPU<'static>, steps: usize) -> Option<Trap> {
+        c.set_traps(TrapOptions::enabled());
+        for _ in 0 .. steps {
+            c.do_cycle();
+            if let Some(report) = c.take_trap() {
+                return Some(report.trap);
+            }
+        }
+        None
+    }
+
+    #[test]
+    fn jump_to_zero() {
+        // JP 0000
+        assert_eq!(run_until_trap(rom_with_code(&[0xC3, 0x00, 0x00]), 10), Some(Trap::JumpToZero { from: 0x0100 }));
+        // RST 00 is allowed
+        assert_eq!(run_until_trap(rom_with_code(&[0xC7]), 10), None);
+    }
+
+    #[test]
+    fn invalid_pc() {
+        // JP FEA0
+        assert_eq!(run_until_trap(rom_with_code(&[0xC3, 0xA0, 0xFE]), 10), Some(Trap::InvalidPc(0xFEA0)));
+    }
+
+    #[test]
+    fn rst38_chain() {
+        // A single RST 38 that returns is fine
+        let mut romdata = rom_with_code(&[0xFF, 0x18, 0xFE]);
+        romdata[0x0038] = 0xC9;
+        assert_eq!(run_until_trap(romdata, 100), None);
+
+        // RST 38 into RST 38 keeps going
+        let mut romdata = rom_with_code(&[0xFF]);
+        romdata[0x0038] = 0xFF;
+        assert_eq!(run_until_trap(romdata, 100), Some(Trap::Rst38Chain(5)));
+    }
+
+    #[test]
+    fn no_false_positives_during_cpu_instrs() {
+        for &classic in [true, false].iter() {
+            let cart = mbc::get_mbc(std::fs::read(CPUINSTRS).unwrap(), false).unwrap();
+            let mut c = match classic {
+                true => CPU::new(cart, None).unwrap(),
+                false => CPU::new_cgb(cart, None).unwrap(),
+            };
+            c.set_traps(TrapOptions::enabled());
+            let mut ticks = 0;
+            while ticks < 4194304 * 10 {
+                ticks += c.do_cycle();
+                if let Some(report) = c.take_trap() {
+                    panic!("{}", report);
+                }
+            }
+        }
+    }
+
+    fn conditions(key_held: bool, joypad_selected: bool, interrupt_pending: bool, ime: bool) -> SpeedSwitchConditions {
+        SpeedSwitchConditions { key_held, joypad_selected, interrupt_pending, ime }
+    }
+
+    #[test]
+    fn speed_switch_matrix() {
+        use super::SpeedSwitchOutcome::*;
+        let outcome = |c| speed_switch_rule(&c).outcome;
+
+        // A held key implies that it is selected
+        assert_eq!(outcome(conditions(true, true, true, false)), NoSwitch);
+        assert_eq!(outcome(conditions(true, true, true, true)), NoSwitch);
+        assert_eq!(outcome(conditions(true, true, false, false)), NoSwitch);
+        assert_eq!(outcome(conditions(true, true, false, true)), NoSwitch);
+        assert_eq!(outcome(conditions(false, false, true, true)), Hang);
+        assert_eq!(outcome(conditions(false, true, true, true)), Hang);
+        assert_eq!(outcome(conditions(false, true, false, false)), Unreliable);
+        assert_eq!(outcome(conditions(false, true, true, false)), Unreliable);
+        assert_eq!(outcome(conditions(false, false, false, false)), Switches);
+        assert_eq!(outcome(conditions(false, false, false, true)), Switches);
+        assert_eq!(outcome(conditions(false, false, true, false)), Switches);
+
+        // Every row is reachable
+        for rule in SPEED_SWITCH_MATRIX.iter() {
+            let c = conditions(rule.key_held.unwrap_or(false), rule.joypad_selected.unwrap_or(rule.key_held.unwrap_or(false)),
+                rule.interrupt_pending.unwrap_or(false), rule.ime.unwrap_or(false));
+            assert!(std::ptr::eq(speed_switch_rule(&c), rule), "{}", rule.explanation);
+        }
+    }
+
+    #[test]
+    fn speed_switch() {
+        // DI, LD A,$10, LDH ($00),A, LD A,$01, LDH ($4D),A, STOP, JR -2
+        let selected = [0xF3, 0x3E, 0x10, 0xE0, 0x00, 0x3E, 0x01, 0xE0, 0x4D, 0x10, 0x00, 0x18, 0xFE];
+        match run_cgb_until_trap(&selected, 20) {
+            Some(Trap::SpeedSwitch { outcome, .. }) => assert_eq!(outcome, SpeedSwitchOutcome::Unreliable),
+            other => panic!("{:?}", other),
+        }
+
+        // The same with both key groups deselected
+        let mut deselected = selected;
+        deselected[2] = 0x30;
+        assert_eq!(run_cgb_until_trap(&deselected, 20), None);
+
+        // Without a request in KEY1 STOP does not switch, so there is nothing to check
+        let mut not_requested = selected;
+        not_requested[6] = 0x00;
+        assert_eq!(run_cgb_until_trap(&not_requested, 20), None);
+    }
+}